@@ -72,5 +72,21 @@ fn main() -> Result<()> {
         config.vm0_image_path
     );
     println!("cargo:rustc-env=PLATFORM={}", config.platform.to_uppercase());
+    if var("CARGO_FEATURE_RAMDISK").is_ok() {
+        // the ramdisk a static config may load with `ramdisk_filename: option_env!("RAMDISK_PATH")`
+        let ramdisk =
+            var("RAMDISK_PATH").unwrap_or_else(|_| format!("{}/image/net_rootfs.cpio", env!("CARGO_MANIFEST_DIR")));
+        println!("cargo:rerun-if-env-changed=RAMDISK_PATH");
+        println!("cargo:rerun-if-changed={}", ramdisk);
+        println!("cargo:rustc-env=RAMDISK_PATH={}", ramdisk);
+    }
+    if var("CARGO_FEATURE_DTB_CONFIG").is_ok() {
+        // e.g. `dtc -I dts -O dtb -o image/vm_config.dtb dts/vm_config.dts`
+        let vm_config_dtb =
+            var("VM_CONFIG_DTB").unwrap_or_else(|_| format!("{}/image/vm_config.dtb", env!("CARGO_MANIFEST_DIR")));
+        println!("cargo:rerun-if-env-changed=VM_CONFIG_DTB");
+        println!("cargo:rerun-if-changed={}", vm_config_dtb);
+        println!("cargo:rustc-env=VM_CONFIG_DTB={}", vm_config_dtb);
+    }
     Ok(())
 }
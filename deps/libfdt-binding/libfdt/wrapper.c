@@ -133,9 +133,10 @@ void fdt_set_memory(void *fdt, uint64_t region_num, const struct region *regions
     if (region_num > FDT_MEMORY_REGION_MAX) {
         region_num = FDT_MEMORY_REGION_MAX;
     }
-    int existed =
-        fdt_node_offset_by_prop_value(fdt, 0, "device_type", "memory", (int)strlen("memory") + 1);
-    if (existed > 0) {
+    // the platform fdt may describe its ram with several memory nodes
+    int existed;
+    while ((existed = fdt_node_offset_by_prop_value(fdt, 0, "device_type", "memory",
+                                                    (int)strlen("memory") + 1)) > 0) {
         fdt_del_node(fdt, existed);
     }
 
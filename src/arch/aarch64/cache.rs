@@ -3,7 +3,7 @@ use core::fmt::Display;
 use crate::{
     arch::{cache, CacheIndexed, CacheInfoTrait, CacheInvalidate, CacheType},
     device::{emu_register_reg, EmuContext, EmuRegType},
-    kernel::current_cpu,
+    kernel::{active_vm, current_cpu},
 };
 use aarch64_cpu::registers::{CCSIDR_EL1, CLIDR_EL1, CSSELR_EL1, ID_AA64MMFR2_EL1};
 use alloc::vec::Vec;
@@ -69,9 +69,11 @@ impl Aarch64CacheInfo {
         CSSELR_EL1.write(CSSELR_EL1::Level.val(level - 1));
     }
 
-    #[inline]
-    fn get_cache_level() -> u64 {
-        CSSELR_EL1.read(CSSELR_EL1::Level) + 1
+    // replace the NumSets field of a CCSIDR_EL1 value, which moves with FEAT_CCIDX
+    fn ccsidr_with_num_sets(ccsidr: u64, num_sets: usize, has_ccidx: bool) -> u64 {
+        let (off, len) = if has_ccidx { (32, 24) } else { (13, 15) };
+        let mask = ((1 << len) - 1) << off;
+        (ccsidr & !mask) | (((num_sets as u64 - 1) << off) & mask)
     }
 }
 
@@ -247,13 +249,22 @@ pub fn vcache_ccsidr_el1_handler(_id: usize, emu_ctx: &EmuContext) -> bool {
             false
         }
         false => {
-            let last_level = CPU_CACHE.get().unwrap().min_share_level as u64;
-
-            let val = if Aarch64CacheInfo::get_cache_level() != last_level {
-                CCSIDR_EL1.get()
-            } else {
-                todo!("need to give L{} cache info of VM", last_level);
-            };
+            let csselr = current_cpu().active_vcpu.as_ref().unwrap().csselr();
+            // the physical CSSELR_EL1 only picks the register read here, the guest never runs with it
+            CSSELR_EL1.set(csselr);
+            isb!();
+            let mut val = CCSIDR_EL1.get();
+
+            // a VM with part of the LLC colors sees an LLC with as many sets as its share,
+            // the line size and the associativity are kept
+            let cpu_cache = CPU_CACHE.get().unwrap();
+            let llc = &cpu_cache.info_list[cpu_cache.min_share_level - 1];
+            // CSSELR_EL1.{Level, InD} select the unified LLC
+            if csselr & 0xf == ((llc.level - 1) << 1) as u64 {
+                if let Some(num_sets) = active_vm().and_then(|vm| vm.llc_num_sets()) {
+                    val = Aarch64CacheInfo::ccsidr_with_num_sets(val, num_sets, llc.has_ccidx);
+                }
+            }
             current_cpu().set_gpr(emu_ctx.reg, val as usize);
 
             debug!(
@@ -293,12 +304,13 @@ pub fn vcache_clidr_el1_handler(_id: usize, emu_ctx: &EmuContext) -> bool {
 }
 
 /// Cache Size Selection Register
-/// no more operation
+/// kept per vcpu, read back by the CCSIDR_EL1 emulation
 pub fn vcache_csselr_el1_handler(_id: usize, emu_ctx: &EmuContext) -> bool {
+    let vcpu = current_cpu().active_vcpu.clone().unwrap();
     match emu_ctx.write {
         true => {
             let val = current_cpu().get_gpr(emu_ctx.reg);
-            CSSELR_EL1.set(val as u64);
+            vcpu.set_csselr(val as u64);
             debug!(
                 "Core{} {} CSSELR_EL1 with x{}={:#x}",
                 current_cpu().id,
@@ -308,7 +320,7 @@ pub fn vcache_csselr_el1_handler(_id: usize, emu_ctx: &EmuContext) -> bool {
             );
         }
         false => {
-            let val = CSSELR_EL1.get();
+            let val = vcpu.csselr();
             current_cpu().set_gpr(emu_ctx.reg, val as usize);
 
             debug!(
@@ -3,6 +3,7 @@ use core::arch::global_asm;
 use aarch64_cpu::registers::*;
 
 use super::timer::GenericTimerContext;
+use super::VirtPmu;
 
 global_asm!(include_str!("fpsimd.S"));
 
@@ -148,13 +149,14 @@ pub struct VmContext {
     tpidr_el0: u64,
     tpidr_el1: u64,
     tpidrro_el0: u64,
+    // never loaded on the core, the accesses trap with HCR_EL2.TID2 and select the CCSIDR_EL1 read
+    pub csselr_el1: u64,
 
     // hypervisor context
     pub hcr_el2: u64,
     // cptr_el2: u64,
     // hstr_el2: u64,
-    #[cfg(not(feature = "memory-reservation"))]
-    pub pmcr_el0: u64,
+    pub vpmu: VirtPmu,
     // pub vtcr_el2: u64,
 
     // exception
@@ -200,8 +202,7 @@ impl VmContext {
         mrs!(self.tpidr_el1, TPIDR_EL1);
         mrs!(self.tpidrro_el0, TPIDRRO_EL0);
 
-        #[cfg(not(feature = "memory-reservation"))]
-        mrs!(self.pmcr_el0, PMCR_EL0);
+        self.vpmu.save();
         // mrs!(self.vtcr_el2, VTCR_EL2);
         mrs!(self.hcr_el2, HCR_EL2);
         // MRS!(self.cptr_el2, CPTR_EL2);
@@ -215,7 +216,7 @@ impl VmContext {
         }
     }
 
-    pub fn ext_regs_restore(&self) {
+    pub fn ext_regs_restore(&mut self) {
         self.generic_timer.restore();
 
         // MSR!(VPIDR_EL2, self.vpidr_el2, "x");
@@ -241,8 +242,7 @@ impl VmContext {
         msr!(TPIDR_EL1, self.tpidr_el1);
         msr!(TPIDRRO_EL0, self.tpidrro_el0);
 
-        #[cfg(not(feature = "memory-reservation"))]
-        msr!(PMCR_EL0, self.pmcr_el0);
+        self.vpmu.restore();
         // msr!(VTCR_EL2, self.vtcr_el2);
         msr!(HCR_EL2, self.hcr_el2);
         // MSR!(CPTR_EL2, self.cptr_el2);
@@ -9,9 +9,11 @@ use ffi_interface::c_interface;
 
 use crate::arch::{ContextFrame, ContextFrameTrait, InterruptController};
 use crate::kernel::interrupt_handler;
-use crate::kernel::{active_vm, current_cpu};
+use crate::kernel::{current_cpu, exit_stat_enter, exit_stat_leave, ExitClass};
 
-use super::sync::{data_abort_handler, hvc_handler, smc_handler, sysreg_handler};
+use super::sync::{
+    data_abort_handler, guest_fault_handler, hvc_handler, instruction_abort_handler, smc_handler, sysreg_handler,
+};
 use super::{interrupt_arch_deactive_irq, IntCtrl};
 
 global_asm!(
@@ -36,6 +38,11 @@ fn exception_hpfar() -> usize {
     hpfar as usize
 }
 
+// ESR, FAR and HPFAR of the last exception taken to EL2, for the panic dump
+pub fn exception_syndrome_regs() -> (usize, usize, usize) {
+    (exception_esr(), exception_far(), exception_hpfar())
+}
+
 #[allow(non_upper_case_globals)]
 const ESR_ELx_S1PTW_SHIFT: usize = 7;
 #[allow(non_upper_case_globals)]
@@ -142,6 +149,17 @@ pub fn exception_data_abort_access_is_sign_ext() -> bool {
     ((exception_iss() >> 21) & 1) != 0
 }
 
+/// ISV: whether the access fields (width, register, sign extension...) of the syndrome are valid
+#[inline(always)]
+pub fn exception_data_abort_syndrome_valid() -> bool {
+    (exception_iss() & (1 << 24)) != 0
+}
+
+/// translate a guest virtual address to ipa through the guest stage-1 page table
+pub fn exception_guest_va_to_ipa(va: usize) -> Result<usize, ()> {
+    translate_far_to_hpfar(va).map(|hpfar| (va & 0xfff) | (hpfar << 8))
+}
+
 #[c_interface]
 pub fn current_el_sp0_synchronous(ctx: *mut ContextFrame) {
     current_cpu().set_ctx(ctx);
@@ -176,7 +194,7 @@ pub fn current_el_spx_synchronous(ctx: *mut ContextFrame) {
 #[c_interface]
 pub fn current_el_spx_irq(ctx: *mut ContextFrame) {
     trace!(">>> core {} current_el_spx_irq", current_cpu().id);
-    lower_aarch64_irq(ctx);
+    irq_handler(ctx);
 }
 
 #[c_interface]
@@ -190,11 +208,15 @@ pub fn lower_aarch64_synchronous(ctx: *mut ContextFrame) {
     trace!("lower_aarch64_synchronous");
     let prev_ctx = current_cpu().set_ctx(ctx);
     let esr = ESR_EL2.extract();
+    let exit = exit_stat_enter(exit_class(esr.read_as_enum(ESR_EL2::EC)));
     match esr.read_as_enum(ESR_EL2::EC) {
         Some(ESR_EL2::EC::Value::DataAbortLowerEL) => {
             trace!("Core[{}] data_abort_handler", current_cpu().id);
             data_abort_handler();
         }
+        Some(ESR_EL2::EC::Value::InstrAbortLowerEL) => {
+            instruction_abort_handler();
+        }
         Some(ESR_EL2::EC::Value::SMC64) => {
             smc_handler();
         }
@@ -211,19 +233,26 @@ pub fn lower_aarch64_synchronous(ctx: *mut ContextFrame) {
                 (*ctx).gpr(1),
                 (*ctx).gpr(29)
             );
-            panic!(
-                "core {} vm {}: handler not presents for EC_{} @ipa {:#x}, @pc {:#x}",
-                current_cpu().id,
-                active_vm().unwrap().id(),
-                esr.read(ESR_EL2::EC),
-                exception_fault_addr(),
-                (*ctx).exception_pc()
-            );
+            guest_fault_handler(format_args!("handler not presents for EC_{}", esr.read(ESR_EL2::EC)));
         },
     }
+    current_cpu().vcpu_array.resched_pending();
+    exit_stat_leave(exit);
     current_cpu().set_ctx(prev_ctx);
 }
 
+// the exit class of a trap from the guest, None for one it is stopped by
+fn exit_class(ec: Option<ESR_EL2::EC::Value>) -> Option<ExitClass> {
+    match ec? {
+        ESR_EL2::EC::Value::DataAbortLowerEL | ESR_EL2::EC::Value::InstrAbortLowerEL => Some(ExitClass::DataAbort),
+        ESR_EL2::EC::Value::SMC64 => Some(ExitClass::Smc),
+        ESR_EL2::EC::Value::HVC64 => Some(ExitClass::Hvc),
+        ESR_EL2::EC::Value::TrappedMsrMrs => Some(ExitClass::Sysreg),
+        ESR_EL2::EC::Value::TrappedWFIorWFE => Some(ExitClass::Wfx),
+        _ => None,
+    }
+}
+
 #[cfg(feature = "preempt")]
 fn interrupt_enter() {
     use super::cpu::{cpu_interrupt_disable, cpu_interrupt_enable};
@@ -296,6 +325,12 @@ fn interrupt_leave() {
 
 #[c_interface]
 pub fn lower_aarch64_irq(ctx: *mut ContextFrame) {
+    let exit = exit_stat_enter(Some(ExitClass::Irq));
+    irq_handler(ctx);
+    exit_stat_leave(exit);
+}
+
+fn irq_handler(ctx: *mut ContextFrame) {
     let prev_ctx = current_cpu().set_ctx(ctx);
     if let Some((int_id, _sender)) = IntCtrl::fetch() {
         #[cfg(feature = "preempt")]
@@ -310,6 +345,7 @@ pub fn lower_aarch64_irq(ctx: *mut ContextFrame) {
         interrupt_leave();
         interrupt_arch_deactive_irq(handled_by_hypervisor);
     }
+    current_cpu().vcpu_array.resched_pending();
     current_cpu().set_ctx(prev_ctx);
 }
 
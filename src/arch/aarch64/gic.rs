@@ -18,7 +18,13 @@ pub const GICC_CTLR_EN_BIT: usize = 0x1;
 pub const GICC_CTLR_EOIMODENS_BIT: usize = 1 << 9;
 
 // GICH BITS
-const GICH_HCR_LRENPIE_BIT: usize = 1 << 2;
+pub(super) const GICH_HCR_LRENPIE_BIT: usize = 1 << 2;
+pub const GICH_HCR_UIE_BIT: usize = 1 << 1;
+pub const GICH_HCR_NPIE_BIT: usize = 1 << 3;
+pub const GICH_MISR_EOI_BIT: usize = 1;
+pub const GICH_MISR_U_BIT: usize = 1 << 1;
+pub const GICH_MISR_LRENP_BIT: usize = 1 << 2;
+pub const GICH_MISR_NP_BIT: usize = 1 << 3;
 
 pub const GIC_SGIS_NUM: usize = 16;
 const GIC_PPIS_NUM: usize = 16;
@@ -108,6 +114,32 @@ impl IrqState {
     }
 }
 
+/* What interrupt.rs and the vgic need from a GIC version besides the distributor and the list registers.
+ * GicV2 and GicV3 implement it, `Gic` is the one selected by the `gicv3` feature.
+ */
+pub trait GicDriver {
+    // number of list registers of the virtual cpu interface
+    fn lrs_num() -> usize;
+    fn glb_init();
+    fn cpu_init();
+    fn cpu_reset();
+    // acknowledge the highest priority pending interrupt, returns the raw IAR value
+    fn ack() -> usize;
+    // (int_id, src cpu) of an IAR value
+    fn irq_id(iar: usize) -> (usize, usize);
+    fn eoi(iar: usize);
+    fn deactivate(iar: usize);
+    fn send_sgi(cpu_id: usize, sgi_num: usize);
+}
+
+#[cfg(not(feature = "gicv3"))]
+pub type Gic = GicV2;
+#[cfg(feature = "gicv3")]
+pub type Gic = super::gicv3::GicV3;
+
+#[cfg(feature = "gicv3")]
+pub(super) use super::gicv3::{GicState, GICD, GICH};
+
 pub struct GicDesc {
     pub gicd_addr: usize,
     pub gicc_addr: usize,
@@ -116,6 +148,7 @@ pub struct GicDesc {
     pub maintenance_int_id: usize,
 }
 
+#[cfg(not(feature = "gicv3"))]
 register_structs! {
     #[allow(non_snake_case)]
     pub GicDistributor {
@@ -144,8 +177,10 @@ register_structs! {
     }
 }
 
+#[cfg(not(feature = "gicv3"))]
 unsafe impl Sync for GicDistributor {}
 
+#[cfg(not(feature = "gicv3"))]
 impl GicDistributor {
     pub fn is_enabler(&self, idx: usize) -> u32 {
         self.ISENABLER[idx].get()
@@ -355,6 +390,7 @@ impl GicDistributor {
     }
 }
 
+#[cfg(not(feature = "gicv3"))]
 register_structs! {
   #[allow(non_snake_case)]
   pub GicCpuInterface {
@@ -382,13 +418,16 @@ register_structs! {
 }
 
 // SAFETY: GicCpuInterface is private to each core
+#[cfg(not(feature = "gicv3"))]
 unsafe impl Send for GicCpuInterface {}
+#[cfg(not(feature = "gicv3"))]
 unsafe impl Sync for GicCpuInterface {}
 
+#[cfg(not(feature = "gicv3"))]
 impl GicCpuInterface {
     fn init(&self) {
         for i in 0..gic_lrs() {
-            GICH.LR[i].set(0);
+            GICH.set_lr(i, 0);
         }
 
         self.PMR.set(u32::MAX);
@@ -396,8 +435,8 @@ impl GicCpuInterface {
         self.CTLR
             .set(ctlr_prev | GICC_CTLR_EN_BIT as u32 | GICC_CTLR_EOIMODENS_BIT as u32);
 
-        let hcr_prev = GICH.HCR.get();
-        GICH.HCR.set(hcr_prev | GICH_HCR_LRENPIE_BIT as u32);
+        let hcr_prev = GICH.hcr();
+        GICH.set_hcr(hcr_prev | GICH_HCR_LRENPIE_BIT as u32);
     }
 
     pub fn hppir(&self) -> u32 {
@@ -425,6 +464,7 @@ impl GicCpuInterface {
     }
 }
 
+#[cfg(not(feature = "gicv3"))]
 register_structs! {
     #[allow(non_snake_case)]
     pub GicHypervisorInterface {
@@ -446,10 +486,13 @@ register_structs! {
     }
 }
 
-// SAFETY: GicCpuInterface is private to each core
+// SAFETY: GicHypervisorInterface is private to each core
+#[cfg(not(feature = "gicv3"))]
 unsafe impl Send for GicHypervisorInterface {}
+#[cfg(not(feature = "gicv3"))]
 unsafe impl Sync for GicHypervisorInterface {}
 
+#[cfg(not(feature = "gicv3"))]
 impl GicHypervisorInterface {
     pub fn hcr(&self) -> u32 {
         self.HCR.get()
@@ -484,6 +527,7 @@ impl GicHypervisorInterface {
     }
 }
 
+#[cfg(not(feature = "gicv3"))]
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct GicState {
@@ -495,6 +539,7 @@ pub struct GicState {
     pub ctlr: u32,
 }
 
+#[cfg(not(feature = "gicv3"))]
 impl Default for GicState {
     fn default() -> Self {
         Self {
@@ -508,6 +553,7 @@ impl Default for GicState {
     }
 }
 
+#[cfg(not(feature = "gicv3"))]
 impl crate::arch::InterruptContextTriat for GicState {
     fn save_state(&mut self) {
         self.hcr = GICH.hcr();
@@ -556,34 +602,77 @@ impl crate::arch::InterruptContextTriat for GicState {
 }
 
 // SAFETY: they are GICv2 mmio device regions
+#[cfg(not(feature = "gicv3"))]
 pub(super) static GICD: DeviceRef<GicDistributor> = unsafe { DeviceRef::new(Platform::GICD_BASE as *const _) };
+#[cfg(not(feature = "gicv3"))]
 pub(super) static GICC: DeviceRef<GicCpuInterface> = unsafe { DeviceRef::new(Platform::GICC_BASE as *const _) };
+#[cfg(not(feature = "gicv3"))]
 pub(super) static GICH: DeviceRef<GicHypervisorInterface> = unsafe { DeviceRef::new(Platform::GICH_BASE as *const _) };
 
+#[cfg(not(feature = "gicv3"))]
 #[inline(always)]
 fn gic_max_spi() -> usize {
-    let typer = GICD.TYPER.get();
+    let typer = GICD.typer();
     let value = typer & 0b11111;
     (32 * (value + 1)) as usize
 }
 
-pub fn gic_glb_init() {
-    let gich_lrs_num = {
+// GICv2: the GICC and GICH mmio regions
+#[cfg(not(feature = "gicv3"))]
+pub struct GicV2;
+
+#[cfg(not(feature = "gicv3"))]
+impl GicDriver for GicV2 {
+    fn lrs_num() -> usize {
         let vtr = GICH.VTR.get();
         ((vtr & 0b111111) + 1) as usize
-    };
+    }
+
+    fn glb_init() {
+        GICD.global_init();
+    }
+
+    fn cpu_init() {
+        GICD.cpu_init();
+        GICC.init();
+    }
+
+    fn cpu_reset() {
+        GICC.init();
+    }
+
+    fn ack() -> usize {
+        GICC.IAR.get() as usize
+    }
+
+    fn irq_id(iar: usize) -> (usize, usize) {
+        (bit_extract(iar, 0, 10), bit_extract(iar, 10, 3))
+    }
+
+    fn eoi(iar: usize) {
+        GICC.EOIR.set(iar as u32);
+    }
+
+    fn deactivate(iar: usize) {
+        GICC.DIR.set(iar as u32);
+    }
 
-    GIC_LRS_NUM.store(gich_lrs_num, Ordering::Relaxed);
-    GICD.global_init();
+    fn send_sgi(cpu_id: usize, sgi_num: usize) {
+        GICD.send_sgi(Platform::cpuid_to_cpuif(cpu_id), sgi_num);
+    }
+}
+
+pub fn gic_glb_init() {
+    GIC_LRS_NUM.store(Gic::lrs_num(), Ordering::Relaxed);
+    Gic::glb_init();
 }
 
 pub fn gic_cpu_init() {
-    GICD.cpu_init();
-    GICC.init();
+    Gic::cpu_init();
 }
 
 pub fn gic_cpu_reset() {
-    GICC.init();
+    Gic::cpu_reset();
 }
 
 pub fn gic_is_priv(int_id: usize) -> bool {
@@ -595,23 +684,21 @@ fn gic_is_sgi(int_id: usize) -> bool {
 }
 
 pub(super) fn gicc_clear_current_irq(for_hypervisor: bool) {
-    let irq = current_cpu().current_irq as u32;
+    let irq = current_cpu().current_irq;
     if irq == 0 {
         return;
     }
-    GICC.EOIR.set(irq);
+    Gic::eoi(irq);
     if for_hypervisor {
-        GICC.DIR.set(irq);
+        Gic::deactivate(irq);
     }
     current_cpu().current_irq = 0;
 }
 
 pub(super) fn gicc_get_current_irq() -> Option<(usize, usize)> {
-    let iar = GICC.IAR.get();
-    let irq = iar as usize;
-    current_cpu().current_irq = irq;
-    let id = bit_extract(iar as usize, 0, 10);
-    let src = bit_extract(iar as usize, 10, 3);
+    let iar = Gic::ack();
+    current_cpu().current_irq = iar;
+    let (id, src) = Gic::irq_id(iar);
     if id >= 1022 {
         None
     } else {
@@ -619,6 +706,16 @@ pub(super) fn gicc_get_current_irq() -> Option<(usize, usize)> {
     }
 }
 
+pub fn gic_send_sgi(cpu_id: usize, sgi_num: usize) {
+    Gic::send_sgi(cpu_id, sgi_num);
+}
+
 pub fn gic_lrs() -> usize {
     GIC_LRS_NUM.load(Ordering::Relaxed)
 }
+
+// whether any list register holds a pending virtual interrupt
+#[cfg(feature = "trap-wfi")]
+pub fn gich_lrs_pending() -> bool {
+    (0..gic_lrs()).any(|i| IrqState::from(GICH.lr(i) >> 28 & 0b11).is_pend())
+}
@@ -0,0 +1,645 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+use tock_registers::interfaces::*;
+use tock_registers::registers::*;
+use tock_registers::*;
+
+use crate::board::{PlatOperation, Platform, PLAT_DESC};
+use crate::kernel::current_cpu;
+use crate::util::{bit_extract, device_ref::DeviceRef};
+
+use super::gic::*;
+
+// GICD BITS
+const GICD_CTLR_ENABLE_G1_BIT: u32 = 1;
+const GICD_CTLR_ENABLE_G1A_BIT: u32 = 1 << 1;
+const GICD_CTLR_ARE_NS_BIT: u32 = 1 << 4;
+const GICD_CTLR_RWP_BIT: u32 = 1 << 31;
+
+// GICR BITS
+const GICR_WAKER_PROCESSOR_SLEEP_BIT: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP_BIT: u32 = 1 << 2;
+const GICR_TYPER_VLPIS_BIT: u64 = 1 << 1;
+const GICR_TYPER_LAST_BIT: u64 = 1 << 4;
+// RD_base and SGI_base, GICv4 adds VLPI_base and a reserved frame
+const GICR_FRAME_SIZE: usize = 0x20000;
+const GICR_FRAME_SIZE_VLPIS: usize = 0x40000;
+const GICR_CPU_NUM_MAX: usize = 64;
+
+// ICC BITS
+const ICC_SRE_SRE_BIT: u64 = 1;
+const ICC_CTLR_EOIMODE_BIT: u64 = 1 << 1;
+
+// ICH LR BITS
+const ICH_LR_EOI_BIT: u64 = 1 << 41;
+const ICH_LR_GROUP_BIT: u64 = 1 << 60;
+const ICH_LR_HW_BIT: u64 = 1 << 61;
+const GICV3_LIST_REGS_NUM: usize = 16;
+
+const GIC_INT_REGS_NUM: usize = GIC_INTS_MAX / 32;
+const GIC_PRIO_REGS_NUM: usize = GIC_INTS_MAX * 8 / 32;
+const GIC_CONFIG_REGS_NUM: usize = GIC_INTS_MAX * 2 / 32;
+
+static GICD_LOCK: Mutex<()> = Mutex::new(());
+
+// the redistributor frame of each core, found by its affinity in `cpu_init`
+static GICR_OF_CPU: [AtomicUsize; GICR_CPU_NUM_MAX] = [const { AtomicUsize::new(0) }; GICR_CPU_NUM_MAX];
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub GicDistributor {
+        (0x0000 => CTLR: ReadWrite<u32>),   // Distributor Control Register
+        (0x0004 => TYPER: ReadOnly<u32>),   // Interrupt Controller Type Register
+        (0x0008 => IIDR: ReadOnly<u32>),    // Distributor Implementer Identification Register
+        (0x000c => reserve0),
+        (0x0080 => IGROUPR: [ReadWrite<u32>; GIC_INT_REGS_NUM]),    // Interrupt Group Registers
+        (0x0100 => ISENABLER: [ReadWrite<u32>; GIC_INT_REGS_NUM]),  // Interrupt Set-Enable Registers
+        (0x0180 => ICENABLER: [ReadWrite<u32>; GIC_INT_REGS_NUM]),  // Interrupt Clear-Enable Registers
+        (0x0200 => ISPENDR: [ReadWrite<u32>; GIC_INT_REGS_NUM]),    // Interrupt Set-Pending Registers
+        (0x0280 => ICPENDR: [ReadWrite<u32>; GIC_INT_REGS_NUM]),    // Interrupt Clear-Pending Registers
+        (0x0300 => ISACTIVER: [ReadWrite<u32>; GIC_INT_REGS_NUM]),  // Interrupt Set-Active Registers
+        (0x0380 => ICACTIVER: [ReadWrite<u32>; GIC_INT_REGS_NUM]),  // Interrupt Clear-Active Registers
+        (0x0400 => IPRIORITYR: [ReadWrite<u32>; GIC_PRIO_REGS_NUM]),    // Interrupt Priority Registers
+        (0x0800 => reserve1),   // ITARGETSR is RES0 with affinity routing
+        (0x0c00 => ICFGR: [ReadWrite<u32>; GIC_CONFIG_REGS_NUM]),   // Interrupt Configuration Registers
+        (0x0d00 => reserve2),
+        (0x6000 => IROUTER: [ReadWrite<u64>; GIC_INTS_MAX]),    // Interrupt Routing Registers, from SPI 32
+        (0x8000 => reserve3),
+        (0x10000 => @END),
+    }
+}
+
+unsafe impl Sync for GicDistributor {}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub GicRedistributor {
+        // RD_base
+        (0x0000 => CTLR: ReadWrite<u32>),   // Redistributor Control Register
+        (0x0004 => IIDR: ReadOnly<u32>),    // Implementer Identification Register
+        (0x0008 => TYPER: ReadOnly<u64>),   // Redistributor Type Register
+        (0x0010 => STATUSR: ReadWrite<u32>),    // Error Reporting Status Register
+        (0x0014 => WAKER: ReadWrite<u32>),  // Redistributor Wake Register
+        (0x0018 => reserve0),
+        // SGI_base
+        (0x10080 => IGROUPR0: ReadWrite<u32>),  // Interrupt Group Register 0
+        (0x10084 => reserve1),
+        (0x10100 => ISENABLER0: ReadWrite<u32>),    // Interrupt Set-Enable Register 0
+        (0x10104 => reserve2),
+        (0x10180 => ICENABLER0: ReadWrite<u32>),    // Interrupt Clear-Enable Register 0
+        (0x10184 => reserve3),
+        (0x10200 => ISPENDR0: ReadWrite<u32>),  // Interrupt Set-Pending Register 0
+        (0x10204 => reserve4),
+        (0x10280 => ICPENDR0: ReadWrite<u32>),  // Interrupt Clear-Pending Register 0
+        (0x10284 => reserve5),
+        (0x10300 => ISACTIVER0: ReadWrite<u32>),    // Interrupt Set-Active Register 0
+        (0x10304 => reserve6),
+        (0x10380 => ICACTIVER0: ReadWrite<u32>),    // Interrupt Clear-Active Register 0
+        (0x10384 => reserve7),
+        (0x10400 => IPRIORITYR: [ReadWrite<u32>; GIC_PRIVINT_NUM * 8 / 32]),   // Interrupt Priority Registers
+        (0x10420 => reserve8),
+        (0x10c00 => ICFGR: [ReadWrite<u32>; GIC_PRIVINT_NUM * 2 / 32]),    // Interrupt Configuration Registers
+        (0x10c08 => reserve9),
+        (0x20000 => @END),
+    }
+}
+
+// SAFETY: each core only touches its own redistributor
+unsafe impl Send for GicRedistributor {}
+unsafe impl Sync for GicRedistributor {}
+
+// SAFETY: they are GICv3 mmio device regions
+static GICD_REGS: DeviceRef<GicDistributor> = unsafe { DeviceRef::new(Platform::GICD_BASE as *const _) };
+
+// Aff3.Aff2.Aff1.Aff0 of a MPIDR, as GICR_TYPER and GICD_IROUTER lay it out
+fn mpidr_affinity(mpidr: usize) -> u64 {
+    ((mpidr as u64 >> 32 & 0xff) << 24) | (mpidr as u64 & 0xff_ffff)
+}
+
+fn irouter_from_mpidr(mpidr: usize) -> u64 {
+    ((mpidr as u64 >> 32 & 0xff) << 32) | (mpidr as u64 & 0xff_ffff)
+}
+
+fn gicr() -> DeviceRef<'static, GicRedistributor> {
+    let base = GICR_OF_CPU[current_cpu().id].load(Ordering::Relaxed);
+    // SAFETY: the frame was found in the GICR region of the platform by `gicr_probe`
+    unsafe { DeviceRef::new(base as *const _) }
+}
+
+// walk the redistributor frames until the one of the current core
+fn gicr_probe() -> Option<usize> {
+    let mpidr = mrs!(MPIDR_EL1) as usize;
+    let affinity = mpidr_affinity(mpidr);
+    let mut base = Platform::GICR_BASE;
+    loop {
+        // SAFETY: the frames are contiguous from GICR_BASE until one says it is the last
+        let gicr: DeviceRef<GicRedistributor> = unsafe { DeviceRef::new(base as *const _) };
+        let typer = gicr.TYPER.get();
+        if typer >> 32 == affinity {
+            return Some(base);
+        }
+        if typer & GICR_TYPER_LAST_BIT != 0 {
+            return None;
+        }
+        base += if typer & GICR_TYPER_VLPIS_BIT != 0 {
+            GICR_FRAME_SIZE_VLPIS
+        } else {
+            GICR_FRAME_SIZE
+        };
+    }
+}
+
+fn gicd_wait_rwp() {
+    while GICD_REGS.CTLR.get() & GICD_CTLR_RWP_BIT != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/* The distributor with affinity routing, private interrupts are forwarded to the
+ * redistributor of the current core, so that the callers keep the GICv2 interface.
+ */
+pub struct GicDistributorV3;
+
+impl GicDistributorV3 {
+    fn global_init(&self) {
+        let int_num = gic_max_spi();
+
+        GICD_REGS.CTLR.set(0);
+        gicd_wait_rwp();
+
+        for i in GIC_PRIVINT_NUM / 32..int_num / 32 {
+            GICD_REGS.IGROUPR[i].set(u32::MAX);
+            GICD_REGS.ICENABLER[i].set(u32::MAX);
+            GICD_REGS.ICPENDR[i].set(u32::MAX);
+            GICD_REGS.ICACTIVER[i].set(u32::MAX);
+        }
+
+        for i in GIC_PRIVINT_NUM / 4..int_num * 8 / 32 {
+            GICD_REGS.IPRIORITYR[i].set(u32::MAX);
+        }
+
+        // everything goes to the master core until it is enabled somewhere else
+        let route = irouter_from_mpidr(PLAT_DESC.cpu_desc.core_list[0].mpidr);
+        for int_id in GIC_PRIVINT_NUM..int_num {
+            GICD_REGS.IROUTER[int_id].set(route);
+        }
+
+        GICD_REGS
+            .CTLR
+            .set(GICD_CTLR_ARE_NS_BIT | GICD_CTLR_ENABLE_G1A_BIT | GICD_CTLR_ENABLE_G1_BIT);
+        gicd_wait_rwp();
+    }
+
+    fn cpu_init(&self) {
+        let cpu_id = current_cpu().id;
+        assert!(cpu_id < GICR_CPU_NUM_MAX);
+        let base = match gicr_probe() {
+            Some(base) => base,
+            None => panic!("gicv3: Core {} has no redistributor", cpu_id),
+        };
+        GICR_OF_CPU[cpu_id].store(base, Ordering::Relaxed);
+
+        let gicr = gicr();
+        let waker = gicr.WAKER.get();
+        gicr.WAKER.set(waker & !GICR_WAKER_PROCESSOR_SLEEP_BIT);
+        while gicr.WAKER.get() & GICR_WAKER_CHILDREN_ASLEEP_BIT != 0 {
+            core::hint::spin_loop();
+        }
+
+        /*
+         * Make sure all private interrupts are not enabled, non pending,
+         * non active.
+         */
+        gicr.IGROUPR0.set(u32::MAX);
+        gicr.ICENABLER0.set(u32::MAX);
+        gicr.ICPENDR0.set(u32::MAX);
+        gicr.ICACTIVER0.set(u32::MAX);
+
+        /* All interrupts have lowest priority possible by default */
+        for i in 0..(GIC_PRIVINT_NUM * 8) / 32 {
+            gicr.IPRIORITYR[i].set(u32::MAX);
+        }
+    }
+
+    pub fn prio(&self, int_id: usize) -> usize {
+        let idx = (int_id * 8) / 32;
+        let off = (int_id * 8) % 32;
+        let reg = if gic_is_priv(int_id) {
+            gicr().IPRIORITYR[idx].get()
+        } else {
+            GICD_REGS.IPRIORITYR[idx].get()
+        };
+        ((reg >> off) & 0xff) as usize
+    }
+
+    pub fn set_prio(&self, int_id: usize, prio: u8) {
+        let idx = (int_id * 8) / 32;
+        let off = (int_id * 8) % 32;
+        let mask: u32 = 0b11111111 << off;
+
+        if gic_is_priv(int_id) {
+            let gicr = gicr();
+            let prev = gicr.IPRIORITYR[idx].get();
+            gicr.IPRIORITYR[idx].set((prev & !mask) | (((prio as u32) << off) & mask));
+        } else {
+            let lock = GICD_LOCK.lock();
+            let prev = GICD_REGS.IPRIORITYR[idx].get();
+            GICD_REGS.IPRIORITYR[idx].set((prev & !mask) | (((prio as u32) << off) & mask));
+            drop(lock);
+        }
+    }
+
+    // the GICv2 cpu interface mask of the core an SPI is routed to
+    pub fn trgt(&self, int_id: usize) -> usize {
+        if gic_is_priv(int_id) {
+            return 1 << Platform::cpuid_to_cpuif(current_cpu().id);
+        }
+        let route = GICD_REGS.IROUTER[int_id].get();
+        match (0..PLAT_DESC.cpu_desc.num).find(|&i| irouter_from_mpidr(PLAT_DESC.cpu_desc.core_list[i].mpidr) == route)
+        {
+            Some(cpu_id) => 1 << Platform::cpuid_to_cpuif(cpu_id),
+            None => 0,
+        }
+    }
+
+    /* An SPI is routed to one core with affinity routing, the lowest cpu interface in `trgt` is taken.
+     * Private interrupts always belong to their own redistributor.
+     */
+    pub fn set_trgt(&self, int_id: usize, trgt: u8) {
+        if gic_is_priv(int_id) || trgt == 0 {
+            return;
+        }
+        let cpu_id = Platform::cpuif_to_cpuid(trgt.trailing_zeros() as usize);
+        if cpu_id >= PLAT_DESC.cpu_desc.num {
+            warn!(
+                "gicv3: set_trgt of int {} to an unknown cpu interface {:#x}",
+                int_id, trgt
+            );
+            return;
+        }
+        let lock = GICD_LOCK.lock();
+        GICD_REGS.IROUTER[int_id].set(irouter_from_mpidr(PLAT_DESC.cpu_desc.core_list[cpu_id].mpidr));
+        drop(lock);
+    }
+
+    pub fn set_enable(&self, int_id: usize, en: bool) {
+        let idx = int_id / 32;
+        let bit = 1 << (int_id % 32);
+
+        if gic_is_priv(int_id) {
+            let gicr = gicr();
+            if en {
+                gicr.ISENABLER0.set(bit);
+            } else {
+                gicr.ICENABLER0.set(bit);
+            }
+        } else {
+            let lock = GICD_LOCK.lock();
+            if en {
+                GICD_REGS.ISENABLER[idx].set(bit);
+            } else {
+                GICD_REGS.ICENABLER[idx].set(bit);
+            }
+            drop(lock);
+        }
+    }
+
+    pub fn set_pend(&self, int_id: usize, pend: bool) {
+        let reg_ind = int_id / 32;
+        let mask = 1 << (int_id % 32);
+
+        if gic_is_priv(int_id) {
+            let gicr = gicr();
+            if pend {
+                gicr.ISPENDR0.set(mask);
+            } else {
+                gicr.ICPENDR0.set(mask);
+            }
+        } else {
+            let lock = GICD_LOCK.lock();
+            if pend {
+                GICD_REGS.ISPENDR[reg_ind].set(mask);
+            } else {
+                GICD_REGS.ICPENDR[reg_ind].set(mask);
+            }
+            drop(lock);
+        }
+    }
+
+    pub fn set_act(&self, int_id: usize, act: bool) {
+        let reg_ind = int_id / 32;
+        let mask = 1 << (int_id % 32);
+
+        if gic_is_priv(int_id) {
+            let gicr = gicr();
+            if act {
+                gicr.ISACTIVER0.set(mask);
+            } else {
+                gicr.ICACTIVER0.set(mask);
+            }
+        } else {
+            let lock = GICD_LOCK.lock();
+            if act {
+                GICD_REGS.ISACTIVER[reg_ind].set(mask);
+            } else {
+                GICD_REGS.ICACTIVER[reg_ind].set(mask);
+            }
+            drop(lock);
+        }
+    }
+
+    pub fn set_state(&self, int_id: usize, state: IrqState) {
+        self.set_act(int_id, state.is_active());
+        self.set_pend(int_id, state.is_pend());
+    }
+
+    pub fn set_icfgr(&self, int_id: usize, cfg: u8) {
+        let reg_ind = (int_id * GIC_CONFIG_BITS) / 32;
+        let off = (int_id * GIC_CONFIG_BITS) % 32;
+        let mask = 0b11 << off;
+
+        if gic_is_priv(int_id) {
+            let gicr = gicr();
+            let icfgr = gicr.ICFGR[reg_ind].get();
+            gicr.ICFGR[reg_ind].set((icfgr & !mask) | (((cfg as u32) << off) & mask));
+        } else {
+            let lock = GICD_LOCK.lock();
+            let icfgr = GICD_REGS.ICFGR[reg_ind].get();
+            GICD_REGS.ICFGR[reg_ind].set((icfgr & !mask) | (((cfg as u32) << off) & mask));
+            drop(lock);
+        }
+    }
+
+    pub fn typer(&self) -> u32 {
+        GICD_REGS.TYPER.get()
+    }
+
+    pub fn iidr(&self) -> u32 {
+        GICD_REGS.IIDR.get()
+    }
+
+    pub fn state(&self, int_id: usize) -> usize {
+        let reg_ind = int_id / 32;
+        let mask = 1 << (int_id % 32);
+
+        let (pend, act) = if gic_is_priv(int_id) {
+            let gicr = gicr();
+            (gicr.ISPENDR0.get(), gicr.ISACTIVER0.get())
+        } else {
+            let lock = GICD_LOCK.lock();
+            let state = (GICD_REGS.ISPENDR[reg_ind].get(), GICD_REGS.ISACTIVER[reg_ind].get());
+            drop(lock);
+            state
+        };
+        usize::from(pend & mask != 0) | usize::from(act & mask != 0) << 1
+    }
+}
+
+fn gic_max_spi() -> usize {
+    let typer = GICD_REGS.TYPER.get();
+    let value = typer & 0b11111;
+    (32 * (value + 1)) as usize
+}
+
+macro_rules! ich_lr_access {
+    ($($idx:literal => $reg:ident),* $(,)?) => {
+        fn ich_lr_read(lr_idx: usize) -> u64 {
+            match lr_idx {
+                $($idx => mrs!($reg),)*
+                _ => panic!("gicv3: no list register {}", lr_idx),
+            }
+        }
+
+        fn ich_lr_write(lr_idx: usize, val: u64) {
+            match lr_idx {
+                $($idx => msr!($reg, val),)*
+                _ => panic!("gicv3: no list register {}", lr_idx),
+            }
+        }
+    };
+}
+
+ich_lr_access!(
+    0 => ICH_LR0_EL2, 1 => ICH_LR1_EL2, 2 => ICH_LR2_EL2, 3 => ICH_LR3_EL2,
+    4 => ICH_LR4_EL2, 5 => ICH_LR5_EL2, 6 => ICH_LR6_EL2, 7 => ICH_LR7_EL2,
+    8 => ICH_LR8_EL2, 9 => ICH_LR9_EL2, 10 => ICH_LR10_EL2, 11 => ICH_LR11_EL2,
+    12 => ICH_LR12_EL2, 13 => ICH_LR13_EL2, 14 => ICH_LR14_EL2, 15 => ICH_LR15_EL2,
+);
+
+/* A list register in the GICv2 GICH_LR layout, as the vgic writes them, to the GICv3 ICH_LR layout.
+ * The SGI source cpu stays in bits [12:10] of the vINTID, which is where a GICv2 compatible
+ * virtual cpu interface expects it.
+ */
+fn lr_v2_to_v3(lr: u32) -> u64 {
+    let lr = lr as u64;
+    let hw = lr & (1 << 31) != 0;
+    let mut val = (lr & 0x3ff)                  // vINTID
+        | (((lr >> 23) & 0b11111) << 3) << 48   // priority
+        | ((lr >> 28) & 0b11) << 62; // state
+    if lr & (1 << 30) != 0 {
+        val |= ICH_LR_GROUP_BIT;
+    }
+    if hw {
+        val |= ICH_LR_HW_BIT | ((lr >> 10) & 0x3ff) << 32;
+    } else {
+        if lr & 0x3ff < GIC_SGIS_NUM as u64 {
+            val |= lr & (0b111 << 10);
+        }
+        if lr & (1 << 19) != 0 {
+            val |= ICH_LR_EOI_BIT;
+        }
+    }
+    val
+}
+
+fn lr_v3_to_v2(val: u64) -> u32 {
+    let mut lr = (val & 0x3ff)                      // vINTID
+        | (((val >> 48) & 0xff) >> 3) << 23         // priority
+        | ((val >> 62) & 0b11) << 28; // state
+    if val & ICH_LR_GROUP_BIT != 0 {
+        lr |= 1 << 30;
+    }
+    if val & ICH_LR_HW_BIT != 0 {
+        lr |= 1 << 31 | ((val >> 32) & 0x3ff) << 10;
+    } else {
+        lr |= val & (0b111 << 10);
+        if val & ICH_LR_EOI_BIT != 0 {
+            lr |= 1 << 19;
+        }
+    }
+    lr as u32
+}
+
+/* The ICH_* system registers behind the GICH interface the vgic uses. The HCR, MISR, EISR and
+ * ELRSR bits are laid out as in GICv2, the list registers are translated.
+ */
+pub struct GicHypervisorInterfaceV3;
+
+impl GicHypervisorInterfaceV3 {
+    pub fn hcr(&self) -> u32 {
+        mrs!(ICH_HCR_EL2) as u32
+    }
+
+    pub fn set_hcr(&self, hcr: u32) {
+        msr!(ICH_HCR_EL2, hcr as u64);
+    }
+
+    pub fn elrsr(&self, elsr_idx: usize) -> u32 {
+        match elsr_idx {
+            0 => mrs!(ICH_ELRSR_EL2) as u32,
+            _ => 0,
+        }
+    }
+
+    pub fn eisr(&self, eisr_idx: usize) -> u32 {
+        match eisr_idx {
+            0 => mrs!(ICH_EISR_EL2) as u32,
+            _ => 0,
+        }
+    }
+
+    pub fn lr(&self, lr_idx: usize) -> u32 {
+        lr_v3_to_v2(ich_lr_read(lr_idx))
+    }
+
+    pub fn misr(&self) -> u32 {
+        mrs!(ICH_MISR_EL2) as u32
+    }
+
+    pub fn set_lr(&self, lr_idx: usize, val: u32) {
+        ich_lr_write(lr_idx, lr_v2_to_v3(val));
+    }
+}
+
+pub(super) static GICD: GicDistributorV3 = GicDistributorV3;
+pub(super) static GICH: GicHypervisorInterfaceV3 = GicHypervisorInterfaceV3;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct GicState {
+    hcr: u32,
+    ap0r: u32,
+    ap1r: u32,
+    lr: [u64; GICV3_LIST_REGS_NUM],
+    // ICH_VMCR_EL2, VENG0 and VEOIM are where GICC_CTLR has EnableGrp0 and EOImodeNS
+    pub ctlr: u32,
+}
+
+impl Default for GicState {
+    fn default() -> Self {
+        Self {
+            hcr: 1 << 2, // List Register Entry Not Present Interrupt Enable.
+            ap0r: 0,
+            ap1r: 0,
+            lr: [0; GICV3_LIST_REGS_NUM],
+            ctlr: 0,
+        }
+    }
+}
+
+impl crate::arch::InterruptContextTriat for GicState {
+    fn save_state(&mut self) {
+        self.hcr = GICH.hcr();
+        // only 5 priority bits are implemented, AP0R1..3 and AP1R1..3 are not
+        self.ap0r = mrs!(ICH_AP0R0_EL2) as u32;
+        self.ap1r = mrs!(ICH_AP1R0_EL2) as u32;
+        let elrsr = GICH.elrsr(0);
+        for i in 0..gic_lrs() {
+            if elrsr & 1 << i == 0 {
+                self.lr[i] = ich_lr_read(i);
+            } else {
+                self.lr[i] = 0;
+            }
+        }
+        self.ctlr = mrs!(ICH_VMCR_EL2) as u32;
+    }
+
+    fn restore_state(&self) {
+        // the guests see the GICv2 memory mapped cpu interface
+        msr!(ICC_SRE_EL1, 0u64);
+        isb!();
+        GICH.set_hcr(self.hcr);
+        msr!(ICH_AP0R0_EL2, self.ap0r as u64);
+        msr!(ICH_AP1R0_EL2, self.ap1r as u64);
+        for i in 0..gic_lrs() {
+            ich_lr_write(i, self.lr[i]);
+        }
+        msr!(ICH_VMCR_EL2, self.ctlr as u64);
+    }
+}
+
+/* GICv3: a distributor with affinity routing, a redistributor frame per core and
+ * the ICC_* / ICH_* system registers in place of the GICC and GICH mmio regions.
+ */
+pub struct GicV3;
+
+impl GicDriver for GicV3 {
+    fn lrs_num() -> usize {
+        let vtr = mrs!(ICH_VTR_EL2) as usize;
+        (vtr & 0b11111) + 1
+    }
+
+    fn glb_init() {
+        GICD.global_init();
+    }
+
+    fn cpu_init() {
+        let sre = mrs!(ICC_SRE_EL2);
+        msr!(ICC_SRE_EL2, sre | ICC_SRE_SRE_BIT);
+        isb!();
+        GICD.cpu_init();
+        Self::cpu_reset();
+    }
+
+    fn cpu_reset() {
+        for i in 0..gic_lrs() {
+            ich_lr_write(i, 0);
+        }
+
+        msr!(ICC_PMR_EL1, 0xffu64);
+        msr!(ICC_BPR1_EL1, 0u64);
+        let ctlr = mrs!(ICC_CTLR_EL1);
+        msr!(ICC_CTLR_EL1, ctlr | ICC_CTLR_EOIMODE_BIT);
+        msr!(ICC_IGRPEN1_EL1, 1u64);
+        isb!();
+
+        let hcr = GICH.hcr();
+        GICH.set_hcr(hcr | GICH_HCR_LRENPIE_BIT as u32);
+    }
+
+    fn ack() -> usize {
+        mrs!(ICC_IAR1_EL1) as usize
+    }
+
+    fn irq_id(iar: usize) -> (usize, usize) {
+        // there is no source cpu in ICC_IAR1_EL1
+        (bit_extract(iar, 0, 24), 0)
+    }
+
+    fn eoi(iar: usize) {
+        msr!(ICC_EOIR1_EL1, iar as u64);
+    }
+
+    fn deactivate(iar: usize) {
+        msr!(ICC_DIR_EL1, iar as u64);
+    }
+
+    fn send_sgi(cpu_id: usize, sgi_num: usize) {
+        let mpidr = PLAT_DESC.cpu_desc.core_list[cpu_id].mpidr;
+        let aff0 = mpidr & 0xff;
+        assert!(
+            aff0 < 16,
+            "gicv3: Core {} aff0 {} can not be in an SGI target list",
+            cpu_id,
+            aff0
+        );
+        let sgi1r = ((mpidr >> 32 & 0xff) << 48)    // Aff3
+            | ((mpidr >> 16 & 0xff) << 32)          // Aff2
+            | ((sgi_num & 0b1111) << 24)            // INTID
+            | ((mpidr >> 8 & 0xff) << 16)           // Aff1
+            | (1 << aff0); // TargetList
+        msr!(ICC_SGI1R_EL1, sgi1r as u64);
+        isb!();
+    }
+}
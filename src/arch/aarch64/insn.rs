@@ -0,0 +1,106 @@
+use crate::util::bit_extract;
+
+/// A decoded A64 load/store instruction, used to emulate data aborts without a valid syndrome (ISV=0)
+#[derive(Debug)]
+pub struct MemInsn {
+    /// access width in bytes of each register
+    pub width: usize,
+    pub write: bool,
+    pub sign_ext: bool,
+    /// width in bytes of the transfer register(s)
+    pub reg_width: usize,
+    pub rt: usize,
+    /// the second register of LDP/STP
+    pub rt2: Option<usize>,
+    pub rn: usize,
+    /// the offset to add to the base register after the access (pre/post-indexed)
+    pub writeback: Option<isize>,
+}
+
+#[inline]
+fn sign_extend(val: usize, bits: usize) -> isize {
+    let shift = usize::BITS as usize - bits;
+    ((val << shift) as isize) >> shift
+}
+
+// LDR/STR/LDRB/LDRH/LDRSB/LDRSH/LDRSW (immediate, unscaled, unprivileged and register offset)
+fn decode_single(insn: usize) -> Option<MemInsn> {
+    let size = bit_extract(insn, 30, 2);
+    let opc = bit_extract(insn, 22, 2);
+    let (write, sign_ext, reg_width) = match (size, opc) {
+        (_, 0b00) => (true, false, if size == 0b11 { 8 } else { 4 }),
+        (_, 0b01) => (false, false, if size == 0b11 { 8 } else { 4 }),
+        // size 0b11 is PRFM
+        (0b00..=0b10, 0b10) => (false, true, 8),
+        (0b00..=0b01, 0b11) => (false, true, 4),
+        _ => return None,
+    };
+    let writeback = if bit_extract(insn, 25, 1) != 0 {
+        return None;
+    } else if bit_extract(insn, 24, 1) != 0 {
+        // unsigned offset
+        None
+    } else if bit_extract(insn, 21, 1) == 0 {
+        match bit_extract(insn, 10, 2) {
+            // unscaled or unprivileged
+            0b00 | 0b10 => None,
+            // post-indexed or pre-indexed
+            _ => Some(sign_extend(bit_extract(insn, 12, 9), 9)),
+        }
+    } else if bit_extract(insn, 10, 2) == 0b10 {
+        // register offset
+        None
+    } else {
+        // atomic memory operations
+        return None;
+    };
+    Some(MemInsn {
+        width: 1 << size,
+        write,
+        sign_ext,
+        reg_width,
+        rt: bit_extract(insn, 0, 5),
+        rt2: None,
+        rn: bit_extract(insn, 5, 5),
+        writeback,
+    })
+}
+
+// LDP/STP/LDNP/STNP/LDPSW
+fn decode_pair(insn: usize) -> Option<MemInsn> {
+    let load = bit_extract(insn, 22, 1) != 0;
+    let (width, sign_ext, reg_width) = match bit_extract(insn, 30, 2) {
+        0b00 => (4, false, 4),
+        0b01 if load => (4, true, 8),
+        0b10 => (8, false, 8),
+        _ => return None,
+    };
+    let writeback = match bit_extract(insn, 23, 3) {
+        // no-allocate or signed offset
+        0b000 | 0b010 => None,
+        // post-indexed or pre-indexed
+        0b001 | 0b011 => Some(sign_extend(bit_extract(insn, 15, 7), 7) * width as isize),
+        _ => return None,
+    };
+    Some(MemInsn {
+        width,
+        write: !load,
+        sign_ext,
+        reg_width,
+        rt: bit_extract(insn, 0, 5),
+        rt2: Some(bit_extract(insn, 10, 5)),
+        rn: bit_extract(insn, 5, 5),
+        writeback,
+    })
+}
+
+/// Decode the general purpose register load/store forms, SIMD&FP, exclusive and atomic forms are not supported
+pub fn decode_mem_insn(insn: u32) -> Option<MemInsn> {
+    let insn = insn as usize;
+    // op0[29:27], V[26]
+    match (bit_extract(insn, 27, 3), bit_extract(insn, 26, 1)) {
+        (0b111, 0) => decode_single(insn),
+        (0b101, 0) => decode_pair(insn),
+        _ => None,
+    }
+}
@@ -2,11 +2,13 @@ use crate::arch::{gic_cpu_init, gic_cpu_reset, gic_glb_init, gic_maintenance_han
 use crate::board::{PlatOperation, Platform, PLAT_DESC};
 use crate::kernel::{current_cpu, interrupt_reserve_int, Vcpu, Vm};
 
-use super::{gicc_clear_current_irq, gicc_get_current_irq, GICD, GIC_SGIS_NUM};
+use super::{gic_send_sgi, gicc_clear_current_irq, gicc_get_current_irq, GICD, GIC_SGIS_NUM};
 
 pub const INTERRUPT_NUM_MAX: usize = 1024;
 pub const INTERRUPT_IRQ_HYPERVISOR_TIMER: usize = 26;
 pub const INTERRUPT_IRQ_IPI: usize = 1;
+// the diagnostic SGI the watchdog sends to a stuck core
+pub const INTERRUPT_IRQ_WATCHDOG: usize = 2;
 pub const INTERRUPT_IRQ_GUEST_TIMER: usize = 27;
 
 pub fn interrupt_arch_init() {
@@ -37,7 +39,7 @@ pub fn interrupt_arch_enable(int_id: usize, en: bool) {
 
 pub fn interrupt_arch_ipi_send(cpu_id: usize, ipi_id: usize) {
     if ipi_id < GIC_SGIS_NUM {
-        GICD.send_sgi(Platform::cpuid_to_cpuif(cpu_id), ipi_id);
+        gic_send_sgi(cpu_id, ipi_id);
     }
 }
 
@@ -45,17 +47,51 @@ pub fn interrupt_arch_vm_register(vm: &Vm, id: usize) {
     super::vgic_set_hw_int(vm, id);
 }
 
+// false if the interrupt is in flight, it is released on the core of its vcpu later
+pub fn interrupt_arch_vm_release(vm: &Vm, id: usize) -> bool {
+    super::vgic_release_hw_int(vm, id)
+}
+
+pub fn interrupt_arch_vm_transfer(src: &Vm, dst: &Vm, id: usize) {
+    super::vgic_transfer_hw_int(src, dst, id);
+}
+
 pub fn interrupt_arch_vm_inject(vm: &Vm, vcpu: &Vcpu, int_id: usize) {
-    let vgic = vm.vgic();
     // trace!("int {}, cur vcpu vm {}, trgt vcpu vm {}", int_id, active_vm().unwrap().id(), vcpu.vm_id());
-    if let Some(cur_vcpu) = current_cpu().active_vcpu.as_ref() {
-        if cur_vcpu == vcpu {
-            vgic.inject(vcpu, int_id);
-            return;
+    // a vcpu that is not running gets the int queued in its context by the vgic
+    vm.vgic().inject(vcpu, int_id);
+}
+
+pub fn interrupt_arch_vcpu_retarget(vcpu: &Vcpu, old_phys_id: usize) {
+    if let Some(vm) = vcpu.vm() {
+        if vm.has_vgic() {
+            vm.vgic().vcpu_retarget_ints(vcpu, old_phys_id);
         }
     }
+}
+
+pub fn interrupt_arch_vcpu_bind(vcpu: &Vcpu, bind: bool) {
+    if let Some(vm) = vcpu.vm() {
+        if vm.has_vgic() {
+            vm.vgic().vcpu_bind_hw_ppis(vcpu, bind);
+        }
+    }
+}
+
+pub fn interrupt_arch_vm_int_target(vm: &Vm, int_id: usize) -> Option<usize> {
+    if vm.has_vgic() {
+        vm.vgic().spi_target(vm, int_id)
+    } else {
+        None
+    }
+}
 
-    vcpu.push_int(int_id);
+// whether a physical irq (timer tick, ipi, ...) waits for this core while irqs are masked at EL2
+pub fn interrupt_arch_irq_pending() -> bool {
+    use aarch64_cpu::registers::ISR_EL1;
+    use tock_registers::interfaces::Readable;
+    // ISR_EL1.I
+    ISR_EL1.get() & (1 << 7) != 0
 }
 
 pub fn interrupt_arch_clear() {
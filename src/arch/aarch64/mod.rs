@@ -1,17 +1,19 @@
 pub use self::cache::*;
 pub use self::context_frame::*;
+pub use self::exception::exception_syndrome_regs;
 pub use self::gic::*;
 pub use self::interface::*;
 pub use self::interrupt::*;
 pub use self::mmu::PLATFORM_PHYSICAL_LIMIT_GB;
 pub use self::page_table::*;
 pub use self::psci::*;
+pub use self::smc::{smc_guest_unknown_handler, smc_log_read, smc_log_reset, SmcLogEntry, SmcLogList};
 #[cfg(feature = "smmuv2")]
 pub use self::smmu::*;
 pub use self::vgic::*;
-pub use pmuv3::arch_pmu_init;
+pub use pmuv3::{arch_pmu_init, VirtPmu};
 #[cfg(feature = "memory-reservation")]
-pub use pmuv3::{vcpu_start_pmu, vcpu_stop_pmu, PmuTimerEvent};
+pub use pmuv3::{vcpu_charge_mem_access, vcpu_start_pmu, vcpu_stop_pmu, PmuTimerEvent};
 
 #[macro_use]
 mod regs;
@@ -24,6 +26,10 @@ mod cpu;
 mod exception;
 #[allow(dead_code)]
 mod gic;
+#[allow(dead_code)]
+#[cfg(feature = "gicv3")]
+mod gicv3;
+mod insn;
 mod interface;
 mod interrupt;
 mod mmu;
@@ -8,15 +8,17 @@ use crate::arch::ArchTrait;
 use crate::arch::TlbInvalidate;
 use crate::kernel::mem_page_alloc;
 use crate::kernel::Cpu;
-use crate::mm::PageFrame;
+use crate::mm::{PageFrame, PageUsage};
 use crate::util::memcpy_safe;
-use crate::util::round_up;
+use crate::util::{round_down, round_up};
 
 use super::{Arch, PAGE_SIZE, PTE_PER_PAGE};
 
 const PTE_TABLE: usize = 0b11;
 const PTE_PAGE: usize = 0b11;
 const PTE_BLOCK: usize = 0b01;
+// the output address of a block or page, the other bits are its attributes
+const PTE_OUTPUT_ADDR_MASK: usize = 0x0000_FFFF_FFFF_F000;
 
 const PTE_S1_FIELD_AP_RW_EL0_NONE: usize = 0b00 << 6;
 const PTE_S1_FIELD_AP_RW_EL0_RW: usize = 0b01 << 6;
@@ -177,7 +179,7 @@ impl ArchPageTableEntryTrait for Aarch64PageTableEntry {
 
     #[inline]
     fn to_pa(&self) -> usize {
-        self.0 & 0x0000_FFFF_FFFF_F000
+        self.0 & PTE_OUTPUT_ADDR_MASK
     }
 
     #[inline]
@@ -207,6 +209,14 @@ impl Aarch64PageTableEntry {
     }
 }
 
+// why `range_leaf` found no leaf inside the range, with the size to skip
+enum LeafMiss {
+    // nothing is mapped
+    Hole(usize),
+    // a block crossing the range could not be split, it is left mapped
+    Unsplit(usize),
+}
+
 #[derive(PartialEq, Eq)]
 enum MmuStage {
     S1,
@@ -216,7 +226,11 @@ enum MmuStage {
 pub struct PageTable {
     directory_pa: usize,
     stage: MmuStage,
+    // VMID of a stage 2 table, to invalidate its TLB entries from any VM
+    vmid: usize,
     pages: Mutex<BTreeMap<usize, PageFrame>>,
+    // serializes the modifications and their TLB invalidation
+    map_lock: Mutex<()>,
 }
 
 const SIZE_2MB: usize = 1 << LVL2_SHIFT;
@@ -227,18 +241,23 @@ impl PageTable {
         Self {
             directory_pa: directory,
             stage: if is_stage2 { MmuStage::S2 } else { MmuStage::S1 },
+            vmid: 0,
             pages: Mutex::new(BTreeMap::new()),
+            map_lock: Mutex::new(()),
         }
     }
 
-    pub fn new(directory: PageFrame, is_stage2: bool) -> Self {
+    // a stage 2 table of VM `vmid`
+    pub fn new(directory: PageFrame, vmid: usize) -> Self {
         let directory_pa = directory.pa();
         let mut map = BTreeMap::new();
         map.insert(directory.pa(), directory);
         Self {
             directory_pa,
-            stage: if is_stage2 { MmuStage::S2 } else { MmuStage::S1 },
+            stage: MmuStage::S2,
+            vmid,
             pages: Mutex::new(map),
+            map_lock: Mutex::new(()),
         }
     }
 
@@ -276,7 +295,7 @@ impl PageTable {
         let directory = Aarch64PageTableEntry::from_pa(self.directory_pa);
         let mut l1e = directory.entry(pt_lvl1_idx(ipa));
         if !l1e.valid() {
-            if let Ok(frame) = mem_page_alloc() {
+            if let Ok(frame) = mem_page_alloc(PageUsage::PageTable) {
                 l1e = Aarch64PageTableEntry::make_table(frame.pa());
                 let mut pages = self.pages.lock();
                 let pf = pages.insert(frame.pa(), frame);
@@ -296,23 +315,11 @@ impl PageTable {
         }
     }
 
-    fn unmap_2mb(&self, ipa: usize) {
-        let directory = Aarch64PageTableEntry::from_pa(self.directory_pa);
-        let l1e = directory.entry(pt_lvl1_idx(ipa));
-        if l1e.valid() {
-            let l2e = l1e.entry(pt_lvl2_idx(ipa));
-            if l2e.valid() {
-                l1e.set_entry(pt_lvl2_idx(ipa), Aarch64PageTableEntry(0));
-                self.tlb_invalidate(ipa);
-            }
-        }
-    }
-
     fn map(&self, ipa: usize, pa: usize, pte: usize) {
         let directory = Aarch64PageTableEntry::from_pa(self.directory_pa);
         let mut l1e = directory.entry(pt_lvl1_idx(ipa));
         if !l1e.valid() {
-            if let Ok(frame) = mem_page_alloc() {
+            if let Ok(frame) = mem_page_alloc(PageUsage::PageTable) {
                 l1e = Aarch64PageTableEntry::make_table(frame.pa());
                 let mut pages = self.pages.lock();
                 let pf = pages.insert(frame.pa(), frame);
@@ -325,7 +332,7 @@ impl PageTable {
 
         let mut l2e = l1e.entry(pt_lvl2_idx(ipa));
         if !l2e.valid() {
-            if let Ok(frame) = mem_page_alloc() {
+            if let Ok(frame) = mem_page_alloc(PageUsage::PageTable) {
                 l2e = Aarch64PageTableEntry::make_table(frame.pa());
                 let mut pages = self.pages.lock();
                 let pf = pages.insert(frame.pa(), frame);
@@ -346,22 +353,6 @@ impl PageTable {
         }
     }
 
-    fn unmap(&self, ipa: usize) {
-        let directory = Aarch64PageTableEntry::from_pa(self.directory_pa);
-        let l1e = directory.entry(pt_lvl1_idx(ipa));
-        if l1e.valid() {
-            let l2e = l1e.entry(pt_lvl2_idx(ipa));
-            if l2e.valid() {
-                let l3e = l2e.entry(pt_lvl3_idx(ipa));
-                if l3e.valid() {
-                    l2e.set_entry(pt_lvl3_idx(ipa), Aarch64PageTableEntry::from_pa(0));
-                    // invalidate tlbs
-                    self.tlb_invalidate(ipa);
-                }
-            }
-        }
-    }
-
     fn map_range_2mb(&self, ipa: usize, len: usize, pa: usize, pte: usize) {
         let page_num = round_up(len, SIZE_2MB) / SIZE_2MB;
 
@@ -386,14 +377,6 @@ impl PageTable {
         }
     }
 
-    fn unmap_range_2mb(&self, ipa: usize, len: usize) {
-        let page_num = round_up(len, SIZE_2MB) / SIZE_2MB;
-
-        for i in 0..page_num {
-            self.unmap_2mb(ipa + i * SIZE_2MB);
-        }
-    }
-
     fn map_range(&self, ipa: usize, len: usize, pa: usize, pte: usize) {
         let page_num = round_up(len, PAGE_SIZE) / PAGE_SIZE;
         for i in 0..page_num {
@@ -401,11 +384,145 @@ impl PageTable {
         }
     }
 
-    fn unmap_range(&self, ipa: usize, len: usize) {
-        let page_num = round_up(len, PAGE_SIZE) / PAGE_SIZE;
-        for i in 0..page_num {
-            self.unmap(ipa + i * PAGE_SIZE);
+    /* Replace the block `table[idx]` of `size` bytes at `block_start` by a table of the next level
+     * with the same translation and attributes, so a part of the block can change alone.
+     * Break-before-make: the block is invalidated and its TLB entries flushed before the table is written.
+     * The block is left mapped if no page is left for the table.
+     */
+    fn split_block(
+        &self,
+        table: Aarch64PageTableEntry,
+        idx: usize,
+        block_start: usize,
+        size: usize,
+    ) -> Result<Aarch64PageTableEntry, ()> {
+        let block = table.entry(idx);
+        let frame = match mem_page_alloc(PageUsage::PageTable) {
+            Ok(frame) => frame,
+            Err(_) => {
+                error!(
+                    "split_block: alloc page table failed, {:#x} block at {:#x} kept",
+                    size, block_start
+                );
+                return Err(());
+            }
+        };
+        let sub_size = size / PTE_PER_PAGE;
+        let attrs = block.to_pte() & !PTE_OUTPUT_ADDR_MASK & !0b11;
+        let desc = if sub_size == PAGE_SIZE { PTE_PAGE } else { PTE_BLOCK };
+        let next = Aarch64PageTableEntry::make_table(frame.pa());
+        for i in 0..PTE_PER_PAGE {
+            next.set_entry(
+                i,
+                Aarch64PageTableEntry::from_pa((block.to_pa() + i * sub_size) | attrs | desc),
+            );
         }
+        let pf = self.pages.lock().insert(frame.pa(), frame);
+        debug_assert!(pf.is_none());
+
+        table.set_entry(idx, Aarch64PageTableEntry(0));
+        self.tlb_invalidate_range(block_start, size);
+        table.set_entry(idx, next);
+        debug!("split {:#x} block at {:#x}", size, block_start);
+        Ok(next)
+    }
+
+    /* Find the leaf entry mapping `addr` as (table, index, size). A block crossing the bounds of
+     * [start, end) is split on the way, so the leaf returned lies inside the range.
+     */
+    fn range_leaf(
+        &self,
+        addr: usize,
+        start: usize,
+        end: usize,
+    ) -> Result<(Aarch64PageTableEntry, usize, usize), LeafMiss> {
+        let directory = Aarch64PageTableEntry::from_pa(self.directory_pa);
+        let mut l1e = directory.entry(pt_lvl1_idx(addr));
+        if !l1e.valid() {
+            return Err(LeafMiss::Hole(SIZE_1GB));
+        } else if l1e.to_pte() & 0b11 == PTE_BLOCK {
+            let block_start = round_down(addr, SIZE_1GB);
+            if block_start >= start && block_start + SIZE_1GB <= end {
+                return Ok((directory, pt_lvl1_idx(addr), SIZE_1GB));
+            }
+            l1e = self
+                .split_block(directory, pt_lvl1_idx(addr), block_start, SIZE_1GB)
+                .map_err(|_| LeafMiss::Unsplit(SIZE_1GB))?;
+        }
+        let mut l2e = l1e.entry(pt_lvl2_idx(addr));
+        if !l2e.valid() {
+            return Err(LeafMiss::Hole(SIZE_2MB));
+        } else if l2e.to_pte() & 0b11 == PTE_BLOCK {
+            let block_start = round_down(addr, SIZE_2MB);
+            if block_start >= start && block_start + SIZE_2MB <= end {
+                return Ok((l1e, pt_lvl2_idx(addr), SIZE_2MB));
+            }
+            l2e = self
+                .split_block(l1e, pt_lvl2_idx(addr), block_start, SIZE_2MB)
+                .map_err(|_| LeafMiss::Unsplit(SIZE_2MB))?;
+        }
+        if !l2e.entry(pt_lvl3_idx(addr)).valid() {
+            return Err(LeafMiss::Hole(PAGE_SIZE));
+        }
+        Ok((l2e, pt_lvl3_idx(addr), PAGE_SIZE))
+    }
+
+    /* The blocks and pages of the range are cleared, a block crossing its bounds is split first.
+     * Return false if a block could not be split, it is left mapped.
+     */
+    fn unmap_range(&self, ipa: usize, len: usize) -> bool {
+        let end = ipa + round_up(len, PAGE_SIZE);
+        let mut addr = ipa;
+        let mut ok = true;
+        while addr < end {
+            match self.range_leaf(addr, ipa, end) {
+                Ok((table, idx, size)) => {
+                    table.set_entry(idx, Aarch64PageTableEntry(0));
+                    addr = round_down(addr, size) + size;
+                }
+                Err(LeafMiss::Hole(size)) => addr = round_down(addr, size) + size,
+                Err(LeafMiss::Unsplit(size)) => {
+                    ok = false;
+                    addr = round_down(addr, size) + size;
+                }
+            }
+        }
+        ok
+    }
+
+    /* Merge a table of 4K pages back into a 2MB block, if its pages map one aligned 2MB frame
+     * with the same attributes. The block is written after the table is invalidated and its
+     * TLB entries flushed, the table is freed last.
+     */
+    fn collapse_2mb(&self, ipa: usize) -> bool {
+        let directory = Aarch64PageTableEntry::from_pa(self.directory_pa);
+        let l1e = directory.entry(pt_lvl1_idx(ipa));
+        if !l1e.valid() || l1e.to_pte() & 0b11 == PTE_BLOCK {
+            return false;
+        }
+        let l2e = l1e.entry(pt_lvl2_idx(ipa));
+        if !l2e.valid() || l2e.to_pte() & 0b11 == PTE_BLOCK {
+            return false;
+        }
+        let first = l2e.entry(0);
+        let attrs = first.to_pte() & !PTE_OUTPUT_ADDR_MASK;
+        if !first.valid() || first.to_pa() % SIZE_2MB != 0 {
+            return false;
+        }
+        for i in 1..PTE_PER_PAGE {
+            let l3e = l2e.entry(i);
+            if l3e.to_pte() != (first.to_pa() + i * PAGE_SIZE) | attrs {
+                return false;
+            }
+        }
+        let block = (first.to_pa() | attrs) & !0b11 | PTE_BLOCK;
+
+        l1e.set_entry(pt_lvl2_idx(ipa), Aarch64PageTableEntry(0));
+        self.tlb_invalidate_range(ipa, SIZE_2MB);
+        l1e.set_entry(pt_lvl2_idx(ipa), Aarch64PageTableEntry(block));
+        // no walk can reach the table any more
+        self.pages.lock().remove(&l2e.to_pa());
+        true
     }
 
     pub fn show_pt(&self, ipa: usize) {
@@ -426,6 +543,7 @@ impl PageTable {
     }
 
     pub fn pt_map_range(&self, ipa: usize, len: usize, pa: usize, pte: usize, map_block: bool) {
+        let _lock = self.map_lock.lock();
         if map_block && ipa % SIZE_1GB == 0 && len % SIZE_1GB == 0 && pa % SIZE_1GB == 0 {
             self.map_range_1gb(ipa, len, pa, pte);
         } else if map_block && ipa % SIZE_2MB == 0 && len % SIZE_2MB == 0 && pa % SIZE_2MB == 0 {
@@ -435,48 +553,98 @@ impl PageTable {
         }
     }
 
-    fn tlb_invalidate(&self, va: usize) {
+    // invalidate the entries of the range on all cores, after the ptes are written
+    fn tlb_invalidate_range(&self, va: usize, len: usize) {
         match self.stage {
-            MmuStage::S1 => crate::arch::Arch::invalid_hypervisor_va(va),
-            MmuStage::S2 => crate::arch::Arch::invalid_guest_ipa(va),
+            MmuStage::S1 => Arch::invalid_hypervisor_va_range(va, len),
+            MmuStage::S2 => Arch::invalid_guest_ipa_range(self.vmid, va, len),
         }
     }
 
-    pub fn pt_unmap_range(&self, ipa: usize, len: usize, map_block: bool) {
-        if ipa % SIZE_2MB == 0 && len % SIZE_2MB == 0 && map_block {
-            self.unmap_range_2mb(ipa, len);
-        } else {
-            self.unmap_range(ipa, len);
+    // false if a block crossing the range could not be split, it is still mapped
+    pub fn pt_unmap_range(&self, ipa: usize, len: usize) -> bool {
+        let _lock = self.map_lock.lock();
+        let ok = self.unmap_range(ipa, len);
+        self.tlb_invalidate_range(ipa, round_up(len, PAGE_SIZE));
+        ok
+    }
+
+    /* Merge the runs of 4K pages of the range which map whole 2MB frames back into 2MB blocks,
+     * e.g. once the dirty log which split them stops. Return the number of blocks made.
+     */
+    pub fn pt_collapse_range(&self, ipa: usize, len: usize) -> usize {
+        let _lock = self.map_lock.lock();
+        let mut blocks = 0;
+        let mut addr = round_up(ipa, SIZE_2MB);
+        while addr + SIZE_2MB <= ipa + len {
+            if self.collapse_2mb(addr) {
+                blocks += 1;
+            }
+            addr += SIZE_2MB;
         }
-        if self.stage == MmuStage::S1 {
-            Arch::invalid_hypervisor_all();
+        blocks
+    }
+
+    /* Replace the access permission bits of the valid ptes in the range, e.g. PTE_S2_FIELD_AP_RO to
+     * write protect guest memory. A block crossing the bounds of the range is split into pages first,
+     * return false if one could not be split, it keeps its permission.
+     */
+    pub fn pt_set_access_permission(&self, ipa: usize, len: usize, ap: usize) -> bool {
+        const PTE_AP_MASK: usize = 0b11 << 6;
+        let _lock = self.map_lock.lock();
+        let end = ipa + round_up(len, PAGE_SIZE);
+        let mut addr = ipa;
+        let mut ok = true;
+        while addr < end {
+            match self.range_leaf(addr, ipa, end) {
+                Ok((table, idx, size)) => {
+                    let pte = (table.entry(idx).to_pte() & !PTE_AP_MASK) | ap;
+                    table.set_entry(idx, Aarch64PageTableEntry(pte));
+                    addr = round_down(addr, size) + size;
+                }
+                Err(LeafMiss::Hole(size)) => addr = round_down(addr, size) + size,
+                Err(LeafMiss::Unsplit(size)) => {
+                    ok = false;
+                    addr = round_down(addr, size) + size;
+                }
+            }
         }
+        self.tlb_invalidate_range(ipa, round_up(len, PAGE_SIZE));
+        ok
     }
 
+    // the pte of level `lvl` (1 to 3) on the walk of `va`, None if the walk ends above it
     pub fn get_pte(&self, va: usize, lvl: usize) -> Option<usize> {
-        if lvl == 1 {
-            let directory = Aarch64PageTableEntry::from_pa(self.directory_pa);
-            let l1e = directory.entry(pt_lvl1_idx(va));
-            if l1e.valid() {
-                Some(l1e.to_pte())
-            } else {
-                None
+        if !(1..=3).contains(&lvl) {
+            return None;
+        }
+        let mut entry = Aarch64PageTableEntry::from_pa(self.directory_pa);
+        for (level, idx) in [pt_lvl1_idx(va), pt_lvl2_idx(va), pt_lvl3_idx(va)]
+            .into_iter()
+            .enumerate()
+            .take(lvl)
+        {
+            if level > 0 && (!entry.valid() || entry.to_pte() & 0b11 == PTE_BLOCK) {
+                return None;
             }
-        } else {
-            None
+            entry = entry.entry(idx);
         }
+        entry.valid().then_some(entry.to_pte())
     }
 
     pub fn set_pte(&self, va: usize, lvl: usize, pte: usize) {
         if lvl == 1 {
+            let _lock = self.map_lock.lock();
             let directory = Aarch64PageTableEntry::from_pa(self.directory_pa);
             let l1e = directory.entry(pt_lvl1_idx(va));
-            if l1e.valid() {
-                warn!("set_pte: va {va:#x} is already mapped with {:#x}!", l1e.to_pte());
-            }
             let table = Aarch64PageTableEntry(pte);
             assert!(table.valid());
             directory.set_entry(pt_lvl1_idx(va), table);
+            if l1e.valid() {
+                warn!("set_pte: va {va:#x} is already mapped with {:#x}!", l1e.to_pte());
+                // the walk caches may hold the old table
+                self.tlb_invalidate_range(va & !(SIZE_1GB - 1), SIZE_1GB);
+            }
         } else {
             panic!("set_pte: not support lvl {lvl}");
         }
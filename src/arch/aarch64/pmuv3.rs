@@ -2,8 +2,10 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
+use crate::device::{emu_register_reg, EmuContext, EmuRegType};
+use crate::kernel::current_cpu;
 #[cfg(feature = "memory-reservation")]
-use crate::kernel::{current_cpu, Vcpu, VcpuState, WeakVcpu};
+use crate::kernel::{Vcpu, VcpuState, WeakVcpu};
 
 use super::regs::{PMCCFILTR_EL0, PMCR_EL0, PMUSERENR_EL0};
 
@@ -90,8 +92,8 @@ static GLOBAL_PMU: PmuEventCounterList = PmuEventCounterList {
 };
 
 pub fn arch_pmu_init() {
-    // EL2 Performance Monitors enabled
-    let mdcr = mrs!(MDCR_EL2) | (0b1 << 7);
+    // EL2 Performance Monitors enabled, trap the PMU accesses of guests (TPM)
+    let mdcr = mrs!(MDCR_EL2) | (0b1 << 7) | (0b1 << 6);
     msr!(MDCR_EL2, mdcr);
 
     // disables the cycle counter and all PMEVCNTR<x>
@@ -132,6 +134,10 @@ pub fn arch_pmu_init() {
     // software can access PMCCNTR_EL0
     PMUSERENR_EL0.write(PMUSERENR_EL0::EN::Trap + PMUSERENR_EL0::CR::Trap);
 
+    if current_cpu().id == 0 {
+        vpmu_register_regs();
+    }
+
     #[cfg(feature = "memory-reservation")]
     {
         use crate::{
@@ -179,11 +185,305 @@ fn pmu_mem_access_handler() {
     current_cpu().vcpu_array.block_current();
 }
 
-#[allow(dead_code)]
 pub fn cpu_cycle_count() -> u64 {
     mrs!(PMCCNTR_EL0)
 }
 
+const PMCR_E: u64 = 1 << 0;
+const PMCR_P: u64 = 1 << 1;
+const PMCR_C: u64 = 1 << 2;
+const PMCR_LC: u64 = 1 << 6;
+const PMCR_N_SHIFT: u64 = 11;
+// E, D, X, DP, LC, LP
+const PMCR_WRITABLE_MASK: u64 = 0xf9;
+// IMP, IDCODE
+const PMCR_ID_MASK: u64 = 0xffff_0000;
+const PMCNTEN_CYCLE: u64 = 1 << 31;
+const PMCNTEN_EVENT: u64 = 1 << 0;
+// P, U and evtCount of PMEVTYPER<n>_EL0, the guest never counts at EL2
+const PMEVTYPER_MASK: u64 = 0xc000_ffff;
+// PMSELR_EL0.SEL of the cycle counter
+const PMSELR_CYCLE: u64 = 31;
+// the event counters kept by the hypervisor, from index 0
+const PMU_HOST_COUNTERS: usize = if cfg!(feature = "memory-reservation") { 1 } else { 0 };
+
+// the hardware event counter behind the one of the vcpus, the last one the hypervisor leaves
+fn vpmu_hw_counter() -> Option<u64> {
+    let num = GLOBAL_PMU.event_counters_num.load(Ordering::Relaxed);
+    (num > PMU_HOST_COUNTERS).then_some(num as u64 - 1)
+}
+
+/// The virtual PMU of a vcpu.
+/// The cycle counter counts the cycles the vcpu really runs at EL0/EL1.
+/// One event counter is exposed (PMCR_EL0.N = 1) if the hypervisor has one to spare, it is loaded
+/// into the last hardware counter while the vcpu runs. The counters of the hypervisor are never seen.
+/// Overflows are not reported, PMOVS and PMINTEN read as zero.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct VirtPmu {
+    pmcr: u64,
+    cnten: u64,
+    // accumulated cycles of the virtual cycle counter
+    ccntr: u64,
+    // host cycle counter when the virtual one started to count
+    start: u64,
+    running: bool,
+    selr: u64,
+    evtyper: u64,
+    // the event counter, only up to date while it is not loaded
+    evcntr: u32,
+    // the event counter is loaded into the hardware one
+    ev_loaded: bool,
+}
+
+impl VirtPmu {
+    fn counting(&self) -> bool {
+        self.pmcr & PMCR_E != 0 && self.cnten & PMCNTEN_CYCLE != 0
+    }
+
+    fn ev_counting(&self) -> bool {
+        self.pmcr & PMCR_E != 0 && self.cnten & PMCNTEN_EVENT != 0
+    }
+
+    fn sync(&mut self) {
+        if self.running {
+            let now = cpu_cycle_count();
+            self.ccntr = self.ccntr.wrapping_add(now.wrapping_sub(self.start));
+            self.start = now;
+        }
+    }
+
+    // take the event counter back from the hardware one
+    fn ev_unload(&mut self) {
+        if let (true, Some(idx)) = (self.ev_loaded, vpmu_hw_counter()) {
+            msr!(PMCNTENCLR_EL0, 1u64 << idx);
+            msr!(PMSELR_EL0, idx);
+            self.evcntr = mrs!(PMXEVCNTR_EL0) as u32;
+            msr!(PMOVSCLR_EL0, 1u64 << idx);
+            self.ev_loaded = false;
+        }
+    }
+
+    // load the event counter into the hardware one, if it counts
+    fn ev_load(&mut self) {
+        match vpmu_hw_counter() {
+            Some(idx) if self.ev_counting() => {
+                msr!(PMSELR_EL0, idx);
+                msr!(PMXEVTYPER_EL0, self.evtyper);
+                msr!(PMXEVCNTR_EL0, self.evcntr as u64);
+                msr!(PMCNTENSET_EL0, 1u64 << idx);
+                isb!();
+                self.ev_loaded = true;
+            }
+            _ => {}
+        }
+    }
+
+    // update the running state after the configuration changed
+    fn update(&mut self) {
+        self.running = self.counting();
+        if self.running {
+            self.start = cpu_cycle_count();
+        }
+        self.ev_load();
+    }
+
+    /// Called when the vcpu is scheduled out
+    pub fn save(&mut self) {
+        self.sync();
+        self.running = false;
+        self.ev_unload();
+    }
+
+    /// Called when the vcpu is scheduled in
+    pub fn restore(&mut self) {
+        self.update();
+    }
+
+    fn pmcr(&self) -> u64 {
+        let num = if vpmu_hw_counter().is_some() { 1 } else { 0 };
+        (PMCR_EL0.get() & PMCR_ID_MASK) | (num << PMCR_N_SHIFT) | self.pmcr
+    }
+
+    fn set_pmcr(&mut self, val: u64) {
+        self.sync();
+        self.ev_unload();
+        if val & PMCR_C != 0 {
+            self.ccntr = 0;
+        }
+        if val & PMCR_P != 0 {
+            self.evcntr = 0;
+        }
+        self.pmcr = val & PMCR_WRITABLE_MASK;
+        self.update();
+    }
+
+    fn cnten_mask() -> u64 {
+        if vpmu_hw_counter().is_some() {
+            PMCNTEN_CYCLE | PMCNTEN_EVENT
+        } else {
+            PMCNTEN_CYCLE
+        }
+    }
+
+    fn set_cnten(&mut self, val: u64, set: bool) {
+        self.sync();
+        self.ev_unload();
+        if set {
+            self.cnten |= val & Self::cnten_mask();
+        } else {
+            self.cnten &= !(val & Self::cnten_mask());
+        }
+        self.update();
+    }
+
+    fn ccntr(&mut self) -> u64 {
+        self.sync();
+        if self.pmcr & PMCR_LC != 0 {
+            self.ccntr
+        } else {
+            self.ccntr & u32::MAX as u64
+        }
+    }
+
+    fn set_ccntr(&mut self, val: u64) {
+        self.sync();
+        self.ccntr = val;
+    }
+
+    fn evcntr(&mut self) -> u64 {
+        self.ev_unload();
+        let val = self.evcntr;
+        self.ev_load();
+        val as u64
+    }
+
+    fn set_evcntr(&mut self, val: u64) {
+        self.ev_unload();
+        self.evcntr = val as u32;
+        self.ev_load();
+    }
+
+    fn set_evtyper(&mut self, val: u64) {
+        self.ev_unload();
+        self.evtyper = val & PMEVTYPER_MASK;
+        self.ev_load();
+    }
+
+    // PMXEVTYPER_EL0 and PMXEVCNTR_EL0 reach the counter selected by PMSELR_EL0, only 0 and the cycle filter exist
+    fn selected(&self) -> Option<u64> {
+        match self.selr {
+            0 if vpmu_hw_counter().is_some() => Some(0),
+            PMSELR_CYCLE => Some(PMSELR_CYCLE),
+            _ => None,
+        }
+    }
+}
+
+const PMCR_EL0_ADDR: usize = sysreg_encode_addr!(0b11, 0b011, 0b1001, 0b1100, 0b000);
+const PMCNTENSET_EL0_ADDR: usize = sysreg_encode_addr!(0b11, 0b011, 0b1001, 0b1100, 0b001);
+const PMCNTENCLR_EL0_ADDR: usize = sysreg_encode_addr!(0b11, 0b011, 0b1001, 0b1100, 0b010);
+const PMSELR_EL0_ADDR: usize = sysreg_encode_addr!(0b11, 0b011, 0b1001, 0b1100, 0b101);
+const PMCEID0_EL0_ADDR: usize = sysreg_encode_addr!(0b11, 0b011, 0b1001, 0b1100, 0b110);
+const PMCEID1_EL0_ADDR: usize = sysreg_encode_addr!(0b11, 0b011, 0b1001, 0b1100, 0b111);
+const PMCCNTR_EL0_ADDR: usize = sysreg_encode_addr!(0b11, 0b011, 0b1001, 0b1101, 0b000);
+const PMXEVTYPER_EL0_ADDR: usize = sysreg_encode_addr!(0b11, 0b011, 0b1001, 0b1101, 0b001);
+const PMXEVCNTR_EL0_ADDR: usize = sysreg_encode_addr!(0b11, 0b011, 0b1001, 0b1101, 0b010);
+const PMEVCNTR0_EL0_ADDR: usize = sysreg_encode_addr!(0b11, 0b011, 0b1110, 0b1000, 0b000);
+const PMEVTYPER0_EL0_ADDR: usize = sysreg_encode_addr!(0b11, 0b011, 0b1110, 0b1100, 0b000);
+
+// PMU registers which read as zero and ignore writes
+const VPMU_RAZ_WI_REGS: [usize; 7] = [
+    sysreg_encode_addr!(0b11, 0b011, 0b1001, 0b1100, 0b011), // PMOVSCLR_EL0
+    sysreg_encode_addr!(0b11, 0b011, 0b1001, 0b1100, 0b100), // PMSWINC_EL0
+    sysreg_encode_addr!(0b11, 0b011, 0b1001, 0b1110, 0b000), // PMUSERENR_EL0
+    sysreg_encode_addr!(0b11, 0b000, 0b1001, 0b1110, 0b001), // PMINTENSET_EL1
+    sysreg_encode_addr!(0b11, 0b000, 0b1001, 0b1110, 0b010), // PMINTENCLR_EL1
+    sysreg_encode_addr!(0b11, 0b011, 0b1001, 0b1110, 0b011), // PMOVSSET_EL0
+    sysreg_encode_addr!(0b11, 0b000, 0b1001, 0b1110, 0b110), // PMMIR_EL1
+];
+
+fn vpmu_register_regs() {
+    for addr in [
+        PMCR_EL0_ADDR,
+        PMCNTENSET_EL0_ADDR,
+        PMCNTENCLR_EL0_ADDR,
+        PMSELR_EL0_ADDR,
+        PMCEID0_EL0_ADDR,
+        PMCEID1_EL0_ADDR,
+        PMCCNTR_EL0_ADDR,
+        PMXEVTYPER_EL0_ADDR,
+        PMXEVCNTR_EL0_ADDR,
+        PMEVCNTR0_EL0_ADDR,
+        PMEVTYPER0_EL0_ADDR,
+    ] {
+        emu_register_reg(EmuRegType::SysReg, addr, vpmu_handler);
+    }
+    for addr in VPMU_RAZ_WI_REGS {
+        emu_register_reg(EmuRegType::SysReg, addr, vpmu_raz_wi_handler);
+    }
+    // PMEVCNTR<n>_EL0 and PMEVTYPER<n>_EL0 beyond the one of the vcpu, n = 31 is PMCCFILTR_EL0
+    for n in 1..32 {
+        let (crm, op2) = (n >> 3, n & 0b111);
+        if n < 31 {
+            emu_register_reg(
+                EmuRegType::SysReg,
+                sysreg_encode_addr!(0b11, 0b011, 0b1110, 0b1000 | crm, op2),
+                vpmu_raz_wi_handler,
+            );
+        }
+        emu_register_reg(
+            EmuRegType::SysReg,
+            sysreg_encode_addr!(0b11, 0b011, 0b1110, 0b1100 | crm, op2),
+            vpmu_raz_wi_handler,
+        );
+    }
+}
+
+fn vpmu_handler(_id: usize, emu_ctx: &EmuContext) -> bool {
+    let vcpu = current_cpu().active_vcpu.as_ref().unwrap();
+    let has_event = vpmu_hw_counter().is_some();
+    if emu_ctx.write {
+        let val = current_cpu().get_gpr(emu_ctx.reg) as u64;
+        vcpu.vpmu_access(|vpmu| match emu_ctx.address {
+            PMCR_EL0_ADDR => vpmu.set_pmcr(val),
+            PMCNTENSET_EL0_ADDR => vpmu.set_cnten(val, true),
+            PMCNTENCLR_EL0_ADDR => vpmu.set_cnten(val, false),
+            PMSELR_EL0_ADDR => vpmu.selr = val & 0x1f,
+            PMCCNTR_EL0_ADDR => vpmu.set_ccntr(val),
+            PMXEVTYPER_EL0_ADDR if vpmu.selected() == Some(0) => vpmu.set_evtyper(val),
+            PMXEVCNTR_EL0_ADDR if vpmu.selected() == Some(0) => vpmu.set_evcntr(val),
+            PMEVTYPER0_EL0_ADDR if has_event => vpmu.set_evtyper(val),
+            PMEVCNTR0_EL0_ADDR if has_event => vpmu.set_evcntr(val),
+            // PMCEID, PMCCFILTR and the counters the vcpu does not have
+            _ => {}
+        });
+    } else {
+        let val = vcpu.vpmu_access(|vpmu| match emu_ctx.address {
+            PMCR_EL0_ADDR => vpmu.pmcr(),
+            PMCNTENSET_EL0_ADDR | PMCNTENCLR_EL0_ADDR => vpmu.cnten,
+            PMSELR_EL0_ADDR => vpmu.selr,
+            // the guest may program any common event the core implements
+            PMCEID0_EL0_ADDR if has_event => mrs!(PMCEID0_EL0),
+            PMCEID1_EL0_ADDR if has_event => mrs!(PMCEID1_EL0),
+            PMCCNTR_EL0_ADDR => vpmu.ccntr(),
+            PMXEVTYPER_EL0_ADDR if vpmu.selected() == Some(0) => vpmu.evtyper,
+            PMXEVCNTR_EL0_ADDR if vpmu.selected() == Some(0) => vpmu.evcntr(),
+            PMEVTYPER0_EL0_ADDR if has_event => vpmu.evtyper,
+            PMEVCNTR0_EL0_ADDR if has_event => vpmu.evcntr(),
+            _ => 0,
+        });
+        current_cpu().set_gpr(emu_ctx.reg, val as usize);
+    }
+    true
+}
+
+fn vpmu_raz_wi_handler(_id: usize, emu_ctx: &EmuContext) -> bool {
+    if !emu_ctx.write {
+        current_cpu().set_gpr(emu_ctx.reg, 0);
+    }
+    true
+}
+
 #[cfg(feature = "memory-reservation")]
 pub fn vcpu_start_pmu(vcpu: &Vcpu) {
     let remaining_budget = vcpu.bw_info().remaining_budget();
@@ -218,6 +518,25 @@ pub fn vcpu_stop_pmu(vcpu: &Vcpu) {
     vcpu.bw_info().update_remaining_budget(remaining_budget);
 }
 
+/* Take `accesses` done by the hypervisor on behalf of the running `vcpu` from its budget, they are
+ * not counted at EL2. A budget run out overflows the counter on its next access.
+ */
+#[cfg(feature = "memory-reservation")]
+pub fn vcpu_charge_mem_access(vcpu: &Vcpu, accesses: u32) {
+    vcpu_stop_pmu(vcpu);
+    let remaining_budget = vcpu.bw_info().remaining_budget().saturating_sub(accesses);
+    vcpu.bw_info().update_remaining_budget(remaining_budget);
+    vcpu_start_pmu(vcpu);
+}
+
+// the budget used by `vcpu` in the period ending at `now`, before it is replenished
+#[cfg(feature = "memory-reservation")]
+fn vcpu_report_mem_bw(vcpu: &Vcpu, now: core::time::Duration) {
+    if let Some(vm) = vcpu.vm() {
+        vm.mem_bw_report(vcpu.id(), vcpu.bw_info().used_budget(), now);
+    }
+}
+
 #[cfg(feature = "memory-reservation")]
 pub struct PmuTimerEvent(pub WeakVcpu);
 
@@ -235,6 +554,7 @@ impl crate::util::timer_list::TimerEvent for PmuTimerEvent {
             match vcpu.state() {
                 VcpuState::Running => {
                     vcpu_stop_pmu(&vcpu);
+                    vcpu_report_mem_bw(&vcpu, now);
                     #[cfg(feature = "trace-memory")]
                     {
                         let prev_bw =
@@ -250,6 +570,7 @@ impl crate::util::timer_list::TimerEvent for PmuTimerEvent {
                     vcpu_start_pmu(&vcpu);
                 }
                 VcpuState::Blocked => {
+                    vcpu_report_mem_bw(&vcpu, now);
                     #[cfg(feature = "trace-memory")]
                     {
                         let prev_bw =
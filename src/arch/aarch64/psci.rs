@@ -1,7 +1,7 @@
 use crate::arch::{gic_cpu_init, interrupt_arch_deactive_irq};
 use crate::board::PlatOperation;
 use crate::kernel::IpiMessage;
-use crate::kernel::{active_vm, ipi_send_msg, IpiInnerMsg, IpiPowerMessage, IpiType, PowerEvent};
+use crate::kernel::{active_vm, ipi_send_msg_retry, IpiInnerMsg, IpiPowerMessage, IpiType, PowerEvent};
 use crate::kernel::{current_cpu, ipi_intra_broadcast_msg, Vcpu, VcpuState, Vm};
 use crate::vmm::vmm_reboot;
 
@@ -14,6 +14,7 @@ const TEGRA_SIP_GET_ACTMON_CLK_COUNTERS: u32 = 0xC2FFFE02;
 pub fn power_arch_vm_shutdown_secondary_cores(vm: &Vm) {
     let m = IpiPowerMessage {
         src: vm.id(),
+        vcpu_id: 0,
         event: PowerEvent::Reset,
         entry: 0,
         context: 0,
@@ -72,15 +73,24 @@ pub fn smc_guest_handler(fid: usize, x1: usize, x2: usize, x3: usize) -> bool {
     );
     let r = match fid as u32 {
         PSCI_FEATURES => match x1 as u32 {
-            PSCI_VERSION | PSCI_CPU_ON_64 | PSCI_FEATURES => smccc::error::SUCCESS as usize,
+            PSCI_VERSION | PSCI_CPU_ON_64 | PSCI_CPU_OFF | PSCI_AFFINITY_INFO_64 | PSCI_FEATURES => {
+                smccc::error::SUCCESS as usize
+            }
             _ => error::NOT_SUPPORTED as usize,
         },
         PSCI_VERSION => smc_call(PSCI_VERSION, 0, 0, 0).0,
         PSCI_CPU_ON_64 => psci_guest_cpu_on(x1, x2, x3),
+        PSCI_CPU_OFF => {
+            // CPU_OFF does not return on success, current context now belongs to another vcpu
+            if psci_guest_cpu_off() {
+                return true;
+            }
+            error::DENIED as usize
+        }
         PSCI_SYSTEM_RESET => psci_guest_sys_reset(),
         PSCI_SYSTEM_OFF => psci_guest_sys_off(),
         PSCI_MIGRATE_INFO_TYPE => MigrateType::MigrationNotRequired as usize,
-        PSCI_AFFINITY_INFO_64 => 0,
+        PSCI_AFFINITY_INFO_64 => psci_guest_affinity_info(x1, x2),
         #[cfg(feature = "tx2")]
         TEGRA_SIP_GET_ACTMON_CLK_COUNTERS => {
             let result = smc_call(TEGRA_SIP_GET_ACTMON_CLK_COUNTERS, x1, x2, x3);
@@ -113,21 +123,59 @@ fn psci_vcpu_on(vcpu: &Vcpu, entry: usize, ctx: usize) {
             current_cpu().id
         );
     }
-    vcpu.set_gpr(0, ctx);
-    vcpu.set_exception_pc(entry);
+    vcpu.reset_power_on(entry, ctx);
     // Just wake up the vcpu
     current_cpu().vcpu_array.wakeup_vcpu(vcpu);
 }
 
-// Todo: need to support more vcpu in one Core
+fn psci_vcpu_off(vcpu: &Vcpu) -> bool {
+    let vm = match vcpu.vm() {
+        Some(vm) => vm,
+        None => return false,
+    };
+    info!(
+        "Core {} (vm {}, vcpu {}) is powered off",
+        current_cpu().id,
+        vm.id(),
+        vcpu.id()
+    );
+    vm.vgic().vcpu_drop_priv_ints(vcpu);
+    current_cpu().vcpu_array.power_off_vcpu(vcpu)
+}
+
+fn psci_guest_cpu_off() -> bool {
+    match current_cpu().active_vcpu.clone() {
+        Some(vcpu) => psci_vcpu_off(&vcpu),
+        None => false,
+    }
+}
+
+fn psci_guest_affinity_info(target_affinity: usize, lowest_affinity_level: usize) -> usize {
+    // only affinity level 0 (a single vcpu) is supported
+    if lowest_affinity_level != 0 {
+        return error::INVALID_PARAMETERS as usize;
+    }
+    let vm = active_vm().unwrap();
+    match vm.vcpu(target_affinity & 0xff) {
+        Some(vcpu) if vcpu.state() == VcpuState::Inv => AffinityState::Off as usize,
+        Some(_) => AffinityState::On as usize,
+        None => error::INVALID_PARAMETERS as usize,
+    }
+}
+
 pub fn psci_ipi_handler(msg: IpiMessage) {
     match msg.ipi_message {
         IpiInnerMsg::Power(power_msg) => {
-            let trgt_vcpu = match current_cpu().vcpu_array.pop_vcpu_through_vmid(power_msg.src) {
+            if let PowerEvent::Reset = power_msg.event {
+                psci_vm_reset_percore(power_msg.src);
+                return;
+            }
+            let trgt_vcpu = match current_cpu().vcpu_array.vcpu_by_id(power_msg.src, power_msg.vcpu_id) {
                 None => {
                     warn!(
-                        "Core {} failed to find target vcpu, source vmid {}",
+                        "Core {} failed to find target vcpu {}, source vmid {}",
                         current_cpu().id,
+                        power_msg.vcpu_id,
                         power_msg.src
                     );
                     return;
@@ -153,15 +201,11 @@ pub fn psci_ipi_handler(msg: IpiMessage) {
                     psci_vcpu_on(trgt_vcpu, power_msg.entry, power_msg.context);
                 }
                 PowerEvent::CpuOff => {
-                    // TODO: 为什么ipi cpu off是当前vcpu shutdown，而vcpu shutdown 最后是把平台的物理核心shutdown
-                    // 没有用到。不用管
-                    // current_cpu().active_vcpu.clone().unwrap().shutdown();
-                    unimplemented!("PowerEvent::PsciIpiCpuOff")
-                }
-                PowerEvent::Reset => {
-                    let vcpu = current_cpu().active_vcpu.as_ref().unwrap();
-                    vcpu.init_boot_info(active_vm().unwrap().config());
+                    let trgt_vcpu = trgt_vcpu.clone();
+                    psci_vcpu_off(&trgt_vcpu);
                 }
+                // handled above, it is for all the vcpus of the VM
+                PowerEvent::Reset => {}
             }
         }
         _ => {
@@ -170,35 +214,66 @@ pub fn psci_ipi_handler(msg: IpiMessage) {
     }
 }
 
+// the vcpus of VM `vm_id` on this core start from the kernel entry again, without the interrupts of the old guest
+fn psci_vm_reset_percore(vm_id: usize) {
+    for vcpu in current_cpu().vcpu_array.iter() {
+        if vcpu.vm_id() == vm_id {
+            if let Some(vm) = vcpu.vm() {
+                if vm.has_vgic() {
+                    vm.vgic().vcpu_reset_ints(vcpu);
+                }
+                vcpu.init_boot_info(&vm);
+            }
+        }
+    }
+}
+
 fn psci_guest_cpu_on(mpidr: usize, entry: usize, ctx: usize) -> usize {
+    vcpu_power_on(&active_vm().unwrap(), mpidr, entry, ctx)
+}
+
+/* Start the vcpu `mpidr` of `vm` at `entry` with `ctx` in x0, on the core it is placed on.
+ * PSCI CPU_ON and the spin-table both come here, so the secondaries are set up the same way.
+ * Return 0 or the PSCI error.
+ */
+pub fn vcpu_power_on(vm: &Vm, mpidr: usize, entry: usize, ctx: usize) -> usize {
     let vcpu_id = mpidr & 0xff;
-    let vm = active_vm().unwrap();
 
     if let Some(phys_id) = vm.vcpuid_to_pcpuid(vcpu_id) {
+        if matches!(vm.vcpu(vcpu_id), Some(vcpu) if vcpu.state() != VcpuState::Inv) {
+            return error::ALREADY_ON as usize;
+        }
         #[cfg(feature = "tx2")]
         {
             let cluster = (mpidr >> 8) & 0xff;
             if vm.id() == 0 && cluster != 1 {
-                warn!("psci_guest_cpu_on: L4T only support cluster #1");
+                warn!("vcpu_power_on: L4T only support cluster #1");
                 return error::NOT_PRESENT as usize;
             }
         }
 
         let m = IpiPowerMessage {
             src: vm.id(),
+            vcpu_id,
             event: PowerEvent::CpuOn,
             entry,
             context: ctx,
         };
 
-        if !ipi_send_msg(phys_id, IpiType::Power, IpiInnerMsg::Power(m)) {
-            warn!("psci_guest_cpu_on: fail to send msg");
+        if phys_id == current_cpu().id {
+            // the target vcpu shares this core with the caller
+            psci_ipi_handler(IpiMessage {
+                ipi_type: IpiType::Power,
+                ipi_message: IpiInnerMsg::Power(m),
+            });
+        } else if let Err(err) = ipi_send_msg_retry(phys_id, IpiType::Power, IpiInnerMsg::Power(m)) {
+            warn!("vcpu_power_on: fail to send msg: {:?}", err);
             return error::NOT_PRESENT as usize;
         }
 
         0
     } else {
-        warn!("psci_guest_cpu_on: VM {} target vcpu {} not exist", vm.id(), vcpu_id);
+        warn!("vcpu_power_on: VM {} target vcpu {} not exist", vm.id(), vcpu_id);
         error::NOT_PRESENT as usize
     }
 }
@@ -1,4 +1,11 @@
 use core::arch::asm;
+use core::mem::size_of;
+
+use spin::Mutex;
+
+use crate::config::SmcPolicy;
+use crate::kernel::access::vm_ipa2hva;
+use crate::kernel::{active_vcpu_id, active_vm, current_cpu, CONFIG_VM_NUM_MAX};
 
 #[inline(never)]
 pub fn smc_call(x0: u32, x1: usize, x2: usize, x3: usize) -> (usize, usize, usize, usize) {
@@ -22,3 +29,175 @@ pub fn smc_call(x0: u32, x1: usize, x2: usize, x3: usize) -> (usize, usize, usiz
     #[cfg(not(target_arch = "aarch64"))]
     compile_error!("smc not supported");
 }
+
+// forward a guest call with x1-x6, x4-x17 may be corrupted by the callee (SMCCC v1.0)
+#[inline(never)]
+fn smc_call_args(x0: u32, args: &[usize; 6]) -> (usize, usize, usize, usize) {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        let r0;
+        let r1;
+        let r2;
+        let r3;
+        asm!(
+            "smc #0",
+            inout("x0") x0 as usize => r0,
+            inout("x1") args[0] => r1,
+            inout("x2") args[1] => r2,
+            inout("x3") args[2] => r3,
+            inout("x4") args[3] => _,
+            inout("x5") args[4] => _,
+            inout("x6") args[5] => _,
+            lateout("x7") _,
+            lateout("x8") _,
+            lateout("x9") _,
+            lateout("x10") _,
+            lateout("x11") _,
+            lateout("x12") _,
+            lateout("x13") _,
+            lateout("x14") _,
+            lateout("x15") _,
+            lateout("x16") _,
+            lateout("x17") _,
+            options(nomem, nostack)
+        );
+        (r0, r1, r2, r3)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    compile_error!("smc not supported");
+}
+
+/* An unknown SMC of a VM, as read back by VM0 with HVC_SYS_SMC_LOG.
+ * Keep the layout in sync with shyper-cli.
+ */
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SmcLogEntry {
+    pub fid: u64,
+    pub args: [u64; 6],
+    pub vcpu_id: u32,
+    // 1 if it was forwarded to EL3
+    pub forwarded: u32,
+}
+
+const SMC_LOG_RING_LEN: usize = 16;
+
+#[repr(C)]
+pub struct SmcLogList {
+    // unknown calls since the VM was created
+    pub total: u64,
+    // calls overwritten since the last read
+    pub lost: u64,
+    pub num: u64,
+    pub entries: [SmcLogEntry; SMC_LOG_RING_LEN],
+}
+
+struct SmcLogRing {
+    entries: [SmcLogEntry; SMC_LOG_RING_LEN],
+    head: usize,
+    num: usize,
+    total: usize,
+    lost: usize,
+}
+
+impl SmcLogRing {
+    const fn new() -> Self {
+        Self {
+            entries: [SmcLogEntry {
+                fid: 0,
+                args: [0; 6],
+                vcpu_id: 0,
+                forwarded: 0,
+            }; SMC_LOG_RING_LEN],
+            head: 0,
+            num: 0,
+            total: 0,
+            lost: 0,
+        }
+    }
+
+    fn push(&mut self, entry: SmcLogEntry) {
+        if self.num == SMC_LOG_RING_LEN {
+            self.head = (self.head + 1) % SMC_LOG_RING_LEN;
+            self.num -= 1;
+            self.lost += 1;
+        }
+        self.entries[(self.head + self.num) % SMC_LOG_RING_LEN] = entry;
+        self.num += 1;
+        self.total += 1;
+    }
+}
+
+static SMC_LOG: [Mutex<SmcLogRing>; CONFIG_VM_NUM_MAX] = [const { Mutex::new(SmcLogRing::new()) }; CONFIG_VM_NUM_MAX];
+
+/* Apply the smc policy of the active VM to a call `smc_guest_handler` does not know.
+ * Return false if the call is denied without a trace (SmcPolicy::Deny).
+ */
+pub fn smc_guest_unknown_handler(fid: usize) -> bool {
+    let vm = active_vm().unwrap();
+    let smc = vm.config().smc_config();
+    if smc.policy == SmcPolicy::Deny {
+        return false;
+    }
+    let mut args = [0; 6];
+    for (i, arg) in args.iter_mut().enumerate() {
+        *arg = current_cpu().get_gpr(i + 1);
+    }
+    let forwarded = smc.allows(fid as u32);
+    if let Some(ring) = SMC_LOG.get(vm.id()) {
+        ring.lock().push(SmcLogEntry {
+            fid: fid as u64,
+            args: args.map(|arg| arg as u64),
+            vcpu_id: active_vcpu_id() as u32,
+            forwarded: forwarded as u32,
+        });
+    }
+    if forwarded {
+        let (r0, r1, r2, r3) = smc_call_args(fid as u32, &args);
+        current_cpu().set_gpr(0, r0);
+        current_cpu().set_gpr(1, r1);
+        current_cpu().set_gpr(2, r2);
+        current_cpu().set_gpr(3, r3);
+    } else {
+        debug!("VM[{}] smc {:#x} is logged and denied", vm.id(), fid);
+        current_cpu().set_gpr(0, smccc::error::NOT_SUPPORTED as usize);
+    }
+    true
+}
+
+/* HVC_SYS_SMC_LOG: VM0 moves the logged smc calls of VM `vm_id` to an `SmcLogList` at `list_ipa`,
+ * return the number of entries.
+ */
+pub fn smc_log_read(list_ipa: usize, vm_id: usize) -> Result<usize, ()> {
+    let vm = active_vm().unwrap();
+    if vm.id() != 0 {
+        error!("smc_log_read: VM[{}] is not allowed to read the smc log", vm.id());
+        return Err(());
+    }
+    let ring = match SMC_LOG.get(vm_id) {
+        Some(ring) => ring,
+        None => return Err(()),
+    };
+    let list_hva = vm_ipa2hva(&vm, list_ipa, size_of::<SmcLogList>()).map_err(|_| ())?;
+    let list = unsafe { &mut *(list_hva as *mut SmcLogList) };
+
+    let mut ring = ring.lock();
+    list.total = ring.total as u64;
+    list.lost = ring.lost as u64;
+    list.num = ring.num as u64;
+    for i in 0..ring.num {
+        list.entries[i] = ring.entries[(ring.head + i) % SMC_LOG_RING_LEN];
+    }
+    let num = ring.num;
+    ring.head = 0;
+    ring.num = 0;
+    ring.lost = 0;
+    Ok(num)
+}
+
+pub fn smc_log_reset(vm_id: usize) {
+    if let Some(ring) = SMC_LOG.get(vm_id) {
+        *ring.lock() = SmcLogRing::new();
+    }
+}
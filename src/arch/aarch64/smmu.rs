@@ -18,7 +18,8 @@ use crate::device::EmuDev;
 use crate::device::EmuDeviceType;
 use crate::kernel::Vm;
 use crate::kernel::CONFIG_VM_NUM_MAX;
-use crate::kernel::{active_vm, current_cpu};
+use crate::kernel::{active_vm, current_cpu, interrupt_cpu_enable, interrupt_reserve_int};
+use crate::kernel::{iommu_fault_report, IommuFault};
 use crate::util::{bit_extract, device_ref::DeviceRef, FlexBitmap};
 
 const SMMUV2_CBAR_TYPE_S1_S2: usize = 0x3 << 16;
@@ -30,6 +31,8 @@ const SMMUV2_IDR0_NTS_BIT: usize = 1 << 28;
 const SMMUV2_IDR0_SMS_BIT: usize = 1 << 27;
 const SMMUV2_IDR0_CTTW_BIT: usize = 1 << 14;
 const SMMUV2_IDR0_BTM_BIT: usize = 1 << 13;
+const SMMUV2_IDR0_NUMSIDB_OFF: usize = 9;
+const SMMUV2_IDR0_NUMSIDB_LEN: usize = 4;
 
 const SMMUV2_IDR1_PAGESIZE_BIT: usize = 1 << 31;
 const SMMUV2_IDR1_NUMCB_OFF: usize = 0;
@@ -502,6 +505,46 @@ impl SmmuV2 {
         None
     }
 
+    // unbind the streams of a context bank, flush its TLB entries and give it back
+    fn free_ctxbnk(&mut self, context_id: usize, vm_id: usize) {
+        if self.context_alloc_bitmap.get(context_id) == 0 {
+            warn!("smmu_free_ctxbnk: ctx {} is not allocated", context_id);
+            return;
+        }
+        for smr in 0..self.smr_num {
+            if self.smr_alloc_bitmap.get(smr) != 0 && self.smr_get_context(smr) == context_id {
+                self.glb_rs0.SMR[smr].set(0);
+                self.smr_alloc_bitmap.set(smr, false);
+                self.group_alloc_bitmap.set(smr, false);
+            }
+        }
+        let cb = &self.context_bank[context_id];
+        cb.SCTLR.set(0);
+        cb.FSR.set(u32::MAX);
+        self.tlb_invalidate_vmid(vm_id);
+        self.glb_rs1.CBAR[context_id].set(0);
+        self.context_alloc_bitmap.set(context_id, false);
+    }
+
+    // invalidate the TLB entries of the bank of `vm_id` and wait for it, the VMID of a bank is the VM id, see `write_ctxbnk`
+    fn tlb_invalidate_vmid(&self, vm_id: usize) {
+        self.glb_rs0.TLBIVMID.set((vm_id & 0xFF) as u32);
+        self.glb_rs0.TLBGSYNC.set(0);
+        while self.glb_rs0.TLBGSTATUS.get() & 1 != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    // the stream ids the SMMU can match, limited by IDR0.NUMSIDB and the SMR id field
+    fn stream_id_valid(&self, stream_id: usize) -> bool {
+        let sid_bits = bit_extract(
+            self.glb_rs0.IDR0.get() as usize,
+            SMMUV2_IDR0_NUMSIDB_OFF,
+            SMMUV2_IDR0_NUMSIDB_LEN,
+        );
+        stream_id < 1 << usize::min(sid_bits, SMMU_SMR_ID_LEN)
+    }
+
     fn write_ctxbnk(&mut self, context_id: usize, root_pt: usize, vm_id: usize) {
         if self.context_alloc_bitmap.get(context_id) == 0 {
             panic!("smmu ctx {} not allocated", context_id);
@@ -547,9 +590,7 @@ impl SmmuV2 {
 
 static SMMU_V2: Mutex<SmmuV2> = Mutex::new(SmmuV2::new());
 
-#[allow(dead_code)]
-pub fn smmu_global_fault_handler(int_id: usize) {
-    let smmu = SMMU_V2.lock();
+fn smmu_global_fault_handler(smmu: &SmmuV2, int_id: usize) {
     error!("get smmu gloabl fault form irq {int_id}");
     error!(
         "GFSR {:#x} GFSYNR0 {:#x} GFSYNR1 {:#x} GFAR {:#x}",
@@ -564,17 +605,77 @@ pub fn smmu_global_fault_handler(int_id: usize) {
     panic!("smmu_global_fault_handler");
 }
 
+// global and context faults share the irq of the SMMU
+fn smmu_irq_handler() {
+    let int_id = PLAT_DESC.arch_desc.smmu_desc.interrupt_id;
+    let smmu = SMMU_V2.lock();
+    if smmu.glb_rs0.GFSR.get() != 0 {
+        smmu_global_fault_handler(&smmu, int_id);
+    }
+    let mut faults = Vec::new();
+    for (context_id, cb) in smmu.context_bank.iter().enumerate() {
+        if smmu.context_alloc_bitmap.get(context_id) == 0 {
+            continue;
+        }
+        let fsr = cb.FSR.get();
+        if fsr == 0 {
+            continue;
+        }
+        faults.push(IommuFault {
+            iova: cb.FAR.get(),
+            fsr,
+            fsynr0: cb.FSYNR0.get(),
+            stream_id: bit_extract(
+                smmu.glb_rs1.CBFRSYNRA[context_id].get() as usize,
+                SMMU_SMR_ID_OFF,
+                SMMU_SMR_ID_LEN,
+            ) as u32,
+            context_id: context_id as u32,
+        });
+        // write 1 to clear
+        cb.FSR.set(fsr);
+    }
+    drop(smmu);
+
+    for fault in faults {
+        if !iommu_fault_report(&fault) {
+            smmu_fault_irq_enable(fault.context_id as usize, false);
+        }
+    }
+}
+
+// mask or unmask the context fault irq of a context bank, a masked one still records its last fault
+pub fn smmu_fault_irq_enable(context_id: usize, en: bool) {
+    let smmu = SMMU_V2.lock();
+    if let Some(cb) = smmu.context_bank.get(context_id) {
+        let sctlr = cb.SCTLR.get() as usize;
+        let sctlr = if en {
+            sctlr | SMMUV2_SCTLR_CFIE
+        } else {
+            sctlr & !SMMUV2_SCTLR_CFIE
+        };
+        cb.SCTLR.set(sctlr as u32);
+    }
+}
+
 pub fn smmu_init() {
     let mut smmu = SMMU_V2.lock();
     smmu.init(PLAT_DESC.arch_desc.smmu_desc.base);
 }
 
+// the irq can only be enabled after the gic is ready
+pub fn smmu_fault_irq_init() {
+    let int_id = PLAT_DESC.arch_desc.smmu_desc.interrupt_id;
+    interrupt_reserve_int(int_id, smmu_irq_handler);
+    interrupt_cpu_enable(int_id, true);
+}
+
 pub fn smmu_vm_init(vm: &Vm) -> bool {
     let mut smmu_v2 = SMMU_V2.lock();
     match smmu_v2.alloc_ctxbnk() {
         Some(context_id) => {
             smmu_v2.write_ctxbnk(context_id, vm.pt_dir(), vm.id());
-            vm.set_iommu_ctx_id(context_id);
+            vm.set_iommu_ctx_id(Some(context_id));
             info!("alloc context id {} for VM[{}]", context_id, vm.id());
             true
         }
@@ -585,8 +686,31 @@ pub fn smmu_vm_init(vm: &Vm) -> bool {
     }
 }
 
+// unbind the streams of a removed VM and free its context bank for the next VM
+pub fn smmu_vm_detach(vm: &Vm) {
+    if let Some(context_id) = vm.iommu_ctx_id() {
+        let mut smmu_v2 = SMMU_V2.lock();
+        smmu_v2.free_ctxbnk(context_id, vm.id());
+        vm.set_iommu_ctx_id(None);
+        info!("free context id {} of VM[{}]", context_id, vm.id());
+    }
+}
+
+// the stage-2 table of VM `vm_id` changed, drop what the SMMU cached of it
+pub fn smmu_vm_tlb_invalidate(vm_id: usize) {
+    SMMU_V2.lock().tlb_invalidate_vmid(vm_id);
+}
+
+pub fn smmu_stream_id_valid(stream_id: usize) -> bool {
+    SMMU_V2.lock().stream_id_valid(stream_id)
+}
+
 pub fn smmu_add_device(context_id: usize, stream_id: usize) -> bool {
     let mut smmu_v2 = SMMU_V2.lock();
+    if !smmu_v2.stream_id_valid(stream_id) {
+        error!("smmu_add_device: stream id {:#x} is not matched by the smmu", stream_id);
+        return false;
+    }
     let prep_id = (stream_id & bit_mask!(SMMU_SMR_ID_OFF, SMMU_SMR_ID_LEN)) as u16;
 
     if !smmu_v2.compatible_smr_exists(0, prep_id, context_id, false) {
@@ -610,7 +734,13 @@ fn emu_smmu_revise_cbar(emu_ctx: &EmuContext) {
     let vm = active_vm().unwrap();
     let cbar_addr = smmu_v2.glb_rs1.CBAR.as_ptr() as usize;
     let context_id = (emu_ctx.address - cbar_addr) / size_of::<u32>();
-    let vm_context_id = vm.iommu_ctx_id();
+    let vm_context_id = match vm.iommu_ctx_id() {
+        Some(id) => id,
+        None => {
+            error!("emu_smmu_revise_cbar: vm {} has no context bank", vm.id());
+            return;
+        }
+    };
     info!(
         "emu_smmu_revise_cbar: vm {} access context id {}, vm context is {}",
         vm.id(),
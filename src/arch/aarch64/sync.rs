@@ -1,84 +1,262 @@
-use crate::arch::smc_guest_handler;
+use core::mem::size_of;
+
+use crate::arch::{smc_guest_handler, smc_guest_unknown_handler};
 use crate::device::{emu_handler, emu_reg_handler, EmuContext};
-use crate::kernel::{active_vm, current_cpu, hvc_guest_handler};
+use crate::kernel::access::vm_ipa2hva;
+use crate::kernel::{active_vm, current_cpu, hvc_guest_handler, spin_table_write};
 
 use super::exception::{
     exception_data_abort_access_is_sign_ext, exception_data_abort_access_is_write, exception_data_abort_access_reg,
     exception_data_abort_access_reg_width, exception_data_abort_access_width, exception_data_abort_handleable,
-    exception_data_abort_is_permission_fault, exception_data_abort_is_translate_fault, exception_esr,
-    exception_fault_addr, exception_iss, exception_next_instruction_step,
+    exception_data_abort_is_permission_fault, exception_data_abort_is_translate_fault,
+    exception_data_abort_syndrome_valid, exception_esr, exception_fault_addr, exception_guest_va_to_ipa, exception_iss,
+    exception_next_instruction_step,
 };
+use super::insn::decode_mem_insn;
 
 const HVC_RETURN_REG: usize = 0;
 const SMC_RETURN_REG: usize = 0;
 
+/* A guest trapped with something that can not be handled: a GVM is stopped and reported to VM0,
+ * a fault of VM0 or outside of a VM takes the hypervisor down.
+ * The trap context may belong to another vcpu when it returns, the caller must not touch it.
+ */
+pub fn guest_fault_handler(reason: core::fmt::Arguments) {
+    let esr = exception_esr();
+    let ec = esr >> 26;
+    let pc = current_cpu().exception_pc();
+    let vm = match active_vm() {
+        Some(vm) if vm.id() != 0 => vm,
+        _ => panic!(
+            "core {}: {}, EC {:#x} ISS {:#x} @ipa {:#x}, @pc {:#x}",
+            current_cpu().id,
+            reason,
+            ec,
+            exception_iss(),
+            exception_fault_addr(),
+            pc
+        ),
+    };
+    error!(
+        "core {} VM[{}] vcpu {} crashed: {}, EC {:#x} ISS {:#x} @ipa {:#x}, @pc {:#x}",
+        current_cpu().id,
+        vm.id(),
+        current_cpu().active_vcpu.as_ref().map_or(0, |vcpu| vcpu.id()),
+        reason,
+        ec,
+        exception_iss(),
+        exception_fault_addr(),
+        pc
+    );
+    crate::vmm::vmm_crash_vm(&vm);
+}
+
 pub fn data_abort_handler() {
     // let time0 = time_current_us();
-    let emu_ctx = EmuContext {
-        address: exception_fault_addr(),
-        width: exception_data_abort_access_width(),
-        write: exception_data_abort_access_is_write(),
-        sign_ext: exception_data_abort_access_is_sign_ext(),
-        reg: exception_data_abort_access_reg(),
-        reg_width: exception_data_abort_access_reg_width(),
-    };
     let elr = current_cpu().exception_pc();
 
     if !exception_data_abort_handleable() {
-        panic!(
-            "Core {} data abort not handleable {:#x}, esr {:#x}",
-            current_cpu().id,
-            exception_fault_addr(),
-            exception_esr()
-        );
+        guest_fault_handler(format_args!("data abort not handleable"));
+        return;
     }
 
     if !exception_data_abort_is_translate_fault() {
         if exception_data_abort_is_permission_fault() {
-            // println!(
-            //     "write {}, width {}, reg width {}, addr {:x}, iss {:x}, reg idx {}, reg val {:#x}, esr {:#x}",
-            //     exception_data_abort_access_is_write(),
-            //     emu_ctx.width,
-            //     emu_ctx.reg_width,
-            //     emu_ctx.address,
-            //     exception_iss(),
-            //     emu_ctx.reg,
-            //     current_cpu().get_gpr(emu_ctx.reg),
-            //     exception_esr()
-            // );
+            if spin_table_fault(elr) {
+                return;
+            }
+            // a write to a page protected by the dirty log, the guest retries it once it is writable again
+            let logged = exception_data_abort_access_is_write()
+                && active_vm().map_or(false, |vm| vm.dirty_log_fault(exception_fault_addr()));
+            if !logged {
+                guest_fault_handler(format_args!("data abort is permission fault"));
+            }
             // no need to rewrite elr
-
-            // let time1 = time_current_us();
-            // println!("migrate_data_abort_handler: {}us", time1 - time0);
             return;
         } else {
-            panic!(
-                "Core {} data abort is not translate fault {:#x}",
-                current_cpu().id,
-                exception_fault_addr(),
+            guest_fault_handler(format_args!("data abort is not translate fault"));
+            return;
+        }
+    }
+    let address = exception_fault_addr();
+    if stage2_lazy_fault(address) {
+        // no need to rewrite elr, the guest retries the access
+        return;
+    }
+    if exception_data_abort_syndrome_valid() {
+        let emu_ctx = EmuContext {
+            address,
+            width: exception_data_abort_access_width(),
+            write: exception_data_abort_access_is_write(),
+            sign_ext: exception_data_abort_access_is_sign_ext(),
+            reg: exception_data_abort_access_reg(),
+            reg_width: exception_data_abort_access_reg_width(),
+        };
+        if !emu_access(&emu_ctx) {
+            if stage2_fault_raced(address) {
+                return;
+            }
+            active_vm().unwrap().show_pagetable(emu_ctx.address);
+            error!(
+                "write {}, width {}, reg width {}, addr {:x}, iss {:x}, reg idx {}, reg val {:#x}, esr {:#x}",
+                emu_ctx.write,
+                emu_ctx.width,
+                emu_ctx.reg_width,
+                emu_ctx.address,
+                exception_iss(),
+                emu_ctx.reg,
+                current_cpu().get_gpr(emu_ctx.reg),
+                exception_esr()
             );
+            guest_fault_handler(format_args!(
+                "data_abort_handler: Failed to handler emul device request"
+            ));
+            return;
+        }
+    } else if !emu_insn_access(address, elr) {
+        if stage2_fault_raced(address) {
+            return;
+        }
+        active_vm().unwrap().show_pagetable(address);
+        guest_fault_handler(format_args!(
+            "data_abort_handler: Failed to handler emul device request without syndrome"
+        ));
+        return;
+    }
+    let val = elr + exception_next_instruction_step();
+    current_cpu().set_exception_pc(val);
+}
+
+// a store to the read-only release page of the spin-table, done by the hypervisor and skipped
+fn spin_table_fault(elr: usize) -> bool {
+    if !exception_data_abort_access_is_write() || !exception_data_abort_syndrome_valid() {
+        return false;
+    }
+    let vm = match active_vm() {
+        Some(vm) => vm,
+        None => return false,
+    };
+    let val = current_cpu().get_gpr(exception_data_abort_access_reg());
+    if !spin_table_write(&vm, exception_fault_addr(), exception_data_abort_access_width(), val) {
+        return false;
+    }
+    current_cpu().set_exception_pc(elr + exception_next_instruction_step());
+    true
+}
+
+// the translation fault hit a block being split or merged by break-before-make, and the ipa is
+// mapped again once the page table lock is released, so the guest only has to retry the access
+fn stage2_fault_raced(ipa: usize) -> bool {
+    active_vm().map_or(false, |vm| vm.ipa2pa(ipa).is_some())
+}
+
+/* The first access to a 2MB block of guest memory mapped on demand, map it from the colors of the VM.
+ * The descriptors are written by the hypervisor, which is not counted by the pmu, on behalf of the
+ * faulting vcpu, so they are taken from the memory budget of its VM.
+ */
+fn stage2_lazy_fault(ipa: usize) -> bool {
+    let vm = match active_vm() {
+        Some(vm) => vm,
+        None => return false,
+    };
+    match vm.lazy_map_fault(ipa) {
+        Some(pages) => {
+            trace!("VM[{}] ipa {:#x} mapped on demand, {} pages", vm.id(), ipa, pages);
+            #[cfg(feature = "memory-reservation")]
+            if pages != 0 && vm.config().memory.is_limited() {
+                if let Some(vcpu) = current_cpu().active_vcpu.as_ref() {
+                    crate::arch::vcpu_charge_mem_access(vcpu, pages as u32);
+                }
+            }
+            vm.ipa2pa(ipa).is_some()
         }
+        None => false,
+    }
+}
+
+pub fn instruction_abort_handler() {
+    if exception_data_abort_is_translate_fault() && stage2_lazy_fault(exception_fault_addr()) {
+        return;
     }
-    if !emu_handler(&emu_ctx) {
-        active_vm().unwrap().show_pagetable(emu_ctx.address);
+    if exception_data_abort_is_translate_fault() && stage2_fault_raced(exception_fault_addr()) {
+        return;
+    }
+    guest_fault_handler(format_args!("instruction abort"));
+}
+
+// emulate an access, the value read is truncated to the access width,
+// then sign or zero extended to the register width
+fn emu_access(emu_ctx: &EmuContext) -> bool {
+    if !emu_handler(emu_ctx) {
+        return false;
+    }
+    if !emu_ctx.write {
+        let val = current_cpu().get_gpr(emu_ctx.reg);
+        let bits = emu_ctx.width * 8;
+        let val = if bits < usize::BITS as usize {
+            let val = val & ((1 << bits) - 1);
+            if emu_ctx.sign_ext && (val >> (bits - 1)) != 0 {
+                val | !((1 << bits) - 1)
+            } else {
+                val
+            }
+        } else {
+            val
+        };
+        let val = if emu_ctx.reg_width == 4 { val & 0xffff_ffff } else { val };
+        current_cpu().set_gpr(emu_ctx.reg, val);
+    }
+    true
+}
+
+// data abort without a valid syndrome (e.g. LDP/STP), decode the faulting instruction
+// from the guest and emulate the access(es) it makes
+fn emu_insn_access(address: usize, elr: usize) -> bool {
+    let vm = active_vm().unwrap();
+    let insn_hva = match exception_guest_va_to_ipa(elr).map(|ipa| vm_ipa2hva(&vm, ipa, size_of::<u32>())) {
+        Ok(Ok(hva)) => hva,
+        _ => {
+            error!("emu_insn_access: failed to fetch instruction at {:#x}", elr);
+            return false;
+        }
+    };
+    let insn = unsafe { *(insn_hva as *const u32) };
+    let mem_insn = match decode_mem_insn(insn) {
+        Some(mem_insn) => mem_insn,
+        None => {
+            error!("emu_insn_access: unsupported instruction {:#010x} at {:#x}", insn, elr);
+            return false;
+        }
+    };
+    trace!("emu_insn_access: {:#010x} {:?}", insn, mem_insn);
+    if mem_insn.writeback.is_some() && mem_insn.rn == 31 {
         error!(
-            "write {}, width {}, reg width {}, addr {:x}, iss {:x}, reg idx {}, reg val {:#x}, esr {:#x}",
-            exception_data_abort_access_is_write(),
-            emu_ctx.width,
-            emu_ctx.reg_width,
-            emu_ctx.address,
-            exception_iss(),
-            emu_ctx.reg,
-            current_cpu().get_gpr(emu_ctx.reg),
-            exception_esr()
-        );
-        panic!(
-            "data_abort_handler: Failed to handler emul device request, ipa {:#x} elr {:#x}",
-            emu_ctx.address, elr
+            "emu_insn_access: writeback to sp is not supported, instruction {:#010x}",
+            insn
         );
+        return false;
     }
-    let val = elr + exception_next_instruction_step();
-    current_cpu().set_exception_pc(val);
+    // NOTE: the fault address is regarded as the first access of a pair,
+    // a pair crossing the boundary of an emulated device is not supported
+    let regs = core::iter::once(mem_insn.rt).chain(mem_insn.rt2);
+    for (i, reg) in regs.enumerate() {
+        let emu_ctx = EmuContext {
+            address: address + i * mem_insn.width,
+            width: mem_insn.width,
+            write: mem_insn.write,
+            sign_ext: mem_insn.sign_ext,
+            reg,
+            reg_width: mem_insn.reg_width,
+        };
+        if !emu_access(&emu_ctx) {
+            return false;
+        }
+    }
+    if let Some(offset) = mem_insn.writeback {
+        let base = current_cpu().get_gpr(mem_insn.rn);
+        current_cpu().set_gpr(mem_insn.rn, base.wrapping_add_signed(offset));
+    }
+    true
 }
 
 pub fn smc_handler() {
@@ -87,14 +265,15 @@ pub fn smc_handler() {
     let x2 = current_cpu().get_gpr(2);
     let x3 = current_cpu().get_gpr(3);
 
-    if !smc_guest_handler(fid, x1, x2, x3) {
-        warn!("smc_handler: unknown fid {:#x}", fid);
-        current_cpu().set_gpr(SMC_RETURN_REG, usize::MAX);
-    }
-
+    // step over the smc first, the handler may switch the context to another vcpu (e.g. PSCI CPU_OFF)
     let elr = current_cpu().exception_pc();
     let val = elr + exception_next_instruction_step();
     current_cpu().set_exception_pc(val);
+
+    if !smc_guest_handler(fid, x1, x2, x3) && !smc_guest_unknown_handler(fid) {
+        warn!("smc_handler: unknown fid {:#x}", fid);
+        current_cpu().set_gpr(SMC_RETURN_REG, usize::MAX);
+    }
 }
 
 pub fn hvc_handler() {
@@ -115,9 +294,12 @@ pub fn hvc_handler() {
         Ok(val) => {
             current_cpu().set_gpr(HVC_RETURN_REG, val);
         }
-        Err(_) => {
-            warn!("Failed to handle hvc request fid {:#x} event {:#x}", hvc_type, event);
-            current_cpu().set_gpr(HVC_RETURN_REG, usize::MAX);
+        Err(err) => {
+            warn!(
+                "Failed to handle hvc request fid {:#x} event {:#x}: {:?}",
+                hvc_type, event, err
+            );
+            current_cpu().set_gpr(HVC_RETURN_REG, err.code());
         }
     }
     // let time_end = timer_arch_get_counter();
@@ -155,6 +337,11 @@ pub fn wfi_wfe_handler(iss: u32) {
     // see xvisor/arch/arm/cpu/arm64/cpu_vcpu_emulate.c:152
     // cpu_vcpu_emulate_wfi_wfe()
     trace!("trap wfi wfe");
+    // NOTE: step over wfi/wfe first, the context may be switched to another vcpu below
+    let elr = current_cpu().exception_pc();
+    let val = elr + exception_next_instruction_step();
+    current_cpu().set_exception_pc(val);
+
     if condition_check(iss) {
         const ISS_WFI_WFE_TI_MASK: u32 = 1;
         /* If WFE trapped then only yield */
@@ -163,14 +350,49 @@ pub fn wfi_wfe_handler(iss: u32) {
             current_cpu().vcpu_array.resched();
         } else {
             trace!("wfi");
-            /* Wait for irq with default timeout */
-            // vmm_vcpu_irq_wait_timeout(vcpu, 0);
+            let max = active_vm().unwrap().config().halt_poll_ticks();
+            let woken = if max != 0 {
+                let vcpu = current_cpu().active_vcpu.clone().unwrap();
+                let success = halt_poll(vcpu.halt_poll_window(max));
+                vcpu.halt_poll_update(success, max);
+                success
+            } else {
+                wfi_pending()
+            };
+            // nothing to handle, the core goes to other vcpus until an interrupt or the virtual timer wakes it
+            if !woken {
+                current_cpu().vcpu_array.wfi_block_current();
+            }
         }
     }
+}
 
-    let elr = current_cpu().exception_pc();
-    let val = elr + exception_next_instruction_step();
-    current_cpu().set_exception_pc(val);
+// spin for at most `window` counter ticks until the vcpu has something to handle,
+// return true if it is woken up during polling
+#[cfg(feature = "trap-wfi")]
+fn halt_poll(window: usize) -> bool {
+    use super::timer::timer_arch_get_counter;
+
+    let start = timer_arch_get_counter();
+    while timer_arch_get_counter() - start < window {
+        if wfi_pending() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+// a physical irq (including ipi) is pending on this core or a virtual one is pending in LRs
+#[cfg(feature = "trap-wfi")]
+fn wfi_pending() -> bool {
+    use aarch64_cpu::registers::ISR_EL1;
+    use tock_registers::interfaces::Readable;
+
+    use super::gich_lrs_pending;
+    use crate::kernel::ipi_pending;
+
+    ISR_EL1.get() != 0 || gich_lrs_pending() || ipi_pending(current_cpu().id)
 }
 
 #[inline(always)]
@@ -208,10 +430,11 @@ pub fn sysreg_handler(iss: u32) {
 
     let elr = current_cpu().exception_pc();
     if !emu_reg_handler(&emu_ctx) {
-        panic!(
-            "sysreg_handler: Failed to handler emu reg request, ({:#x} at {:#x})",
-            emu_ctx.address, elr
-        );
+        guest_fault_handler(format_args!(
+            "sysreg_handler: Failed to handler emu reg request {:#x}",
+            emu_ctx.address
+        ));
+        return;
     }
 
     let val = elr + exception_next_instruction_step();
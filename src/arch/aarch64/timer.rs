@@ -5,12 +5,12 @@ use tock_registers::interfaces::*;
 use super::regs::CNTHP_CTL_EL2;
 
 static TIMER_FREQ: AtomicUsize = AtomicUsize::new(0);
-static TIMER_MS_TICKS: AtomicUsize = AtomicUsize::new(0); // ms
 static TIMER_TICK_NS: AtomicUsize = AtomicUsize::new(0); // nano second in one timer tick
 
-pub fn timer_arch_set(num: usize) {
-    let slice = TIMER_MS_TICKS.load(Ordering::Relaxed);
-    let val = slice * num;
+// fire the EL2 timer `us` microseconds from now
+pub fn timer_arch_set(us: usize) {
+    let freq = TIMER_FREQ.load(Ordering::Relaxed) as u64;
+    let val = freq * us as u64 / 1_000_000;
     msr!(CNTHP_TVAL_EL2, val);
 }
 
@@ -30,6 +30,19 @@ pub fn timer_arch_get_frequency() -> usize {
     aarch64_cpu::registers::CNTFRQ_EL0.get() as usize
 }
 
+// the physical counter value the virtual timer of the running vcpu fires at, None if it is disabled or masked
+#[cfg(feature = "trap-wfi")]
+pub fn timer_arch_vtimer_deadline() -> Option<u64> {
+    let ctl = mrs!(CNTV_CTL_EL0);
+    let cval = mrs!(CNTV_CVAL_EL0);
+    let off = mrs!(CNTVOFF_EL2);
+    if ctl & GENERIC_TIMER_CTRL_ENABLE != 0 && ctl & GENERIC_TIMER_CTRL_IMASK == 0 {
+        Some(cval.wrapping_add(off))
+    } else {
+        None
+    }
+}
+
 #[allow(dead_code)]
 pub fn gettime_ns() -> usize {
     timer_arch_get_counter() * TIMER_TICK_NS.load(Ordering::Relaxed)
@@ -39,7 +52,6 @@ pub fn timer_arch_init() {
     let freq = timer_arch_get_frequency();
     let ticks_per_ms = freq / 1000; // ms
     TIMER_FREQ.store(freq, Ordering::Relaxed);
-    TIMER_MS_TICKS.store(ticks_per_ms, Ordering::Relaxed);
     TIMER_TICK_NS.store(10usize.pow(9) / freq, Ordering::Relaxed);
 
     CNTHP_CTL_EL2.write(CNTHP_CTL_EL2::ENABLE::SET);
@@ -58,6 +70,8 @@ pub struct GenericTimerContext {
     cntv_ctl_el0: u64,  // Virtual Timer Control register
 }
 
+#[cfg(feature = "trap-wfi")]
+const GENERIC_TIMER_CTRL_ENABLE: u64 = 1 << 0;
 const GENERIC_TIMER_CTRL_IMASK: u64 = 1 << 1;
 
 impl Default for GenericTimerContext {
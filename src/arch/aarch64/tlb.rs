@@ -2,7 +2,10 @@ use core::arch::asm;
 
 use crate::arch::TlbInvalidate;
 
-use super::Aarch64Arch;
+use super::{Aarch64Arch, PAGE_SIZE};
+
+// invalidating more pages one by one is slower than dropping all entries
+const TLBI_RANGE_PAGE_MAX: usize = 512;
 
 impl TlbInvalidate for Aarch64Arch {
     fn invalid_hypervisor_va(va: usize) {
@@ -44,4 +47,59 @@ impl TlbInvalidate for Aarch64Arch {
             asm!("dsb ish", "tlbi vmalls12e1is", "dsb ish", "isb", options(nostack));
         }
     }
+
+    fn invalid_hypervisor_va_range(va: usize, len: usize) {
+        if len / PAGE_SIZE > TLBI_RANGE_PAGE_MAX {
+            Self::invalid_hypervisor_all();
+            return;
+        }
+        unsafe {
+            asm!("dsb ishst", options(nostack));
+            for page in (va..va + len).step_by(PAGE_SIZE) {
+                asm!("tlbi vae2is, {0}", in(reg) page >> 12, options(nostack));
+            }
+            asm!("dsb ish", "isb", options(nostack));
+        }
+    }
+
+    /* TLBI by IPA applies to the VMID in VTTBR_EL2, so switch to `vmid` (only the VMID field is
+     * used by the TLBI, the stage 2 walk is not) with irqs masked, then switch back.
+     * The inner shareable forms reach every core, wherever the vcpus of the VM run.
+     */
+    fn invalid_guest_ipa_range(vmid: usize, ipa: usize, len: usize) {
+        let daif: usize;
+        let vttbr: usize;
+        unsafe {
+            asm!(
+                "mrs {daif}, daif",
+                "msr daifset, #0x3",
+                "mrs {vttbr}, vttbr_el2",
+                "msr vttbr_el2, {target}",
+                "isb",
+                "dsb ishst",
+                daif = out(reg) daif,
+                vttbr = out(reg) vttbr,
+                target = in(reg) vmid << 48,
+                options(nostack)
+            );
+            if len / PAGE_SIZE > TLBI_RANGE_PAGE_MAX {
+                asm!("tlbi vmalls12e1is", options(nostack));
+            } else {
+                for page in (ipa..ipa + len).step_by(PAGE_SIZE) {
+                    asm!("tlbi ipas2e1is, {0}", in(reg) page >> 12, options(nostack));
+                }
+                // combined stage 1 and 2 entries are only tagged by VA, drop all of the VMID
+                asm!("dsb ish", "tlbi vmalle1is", options(nostack));
+            }
+            asm!(
+                "dsb ish",
+                "msr vttbr_el2, {vttbr}",
+                "isb",
+                "msr daif, {daif}",
+                vttbr = in(reg) vttbr,
+                daif = in(reg) daif,
+                options(nostack)
+            );
+        }
+    }
 }
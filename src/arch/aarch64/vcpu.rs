@@ -10,4 +10,13 @@ impl Vcpu {
         let mut inner = self.0.inner_mut.lock();
         inner.vm_ctx.hcr_el2 = hcr;
     }
+
+    pub fn csselr(&self) -> u64 {
+        self.0.inner_mut.lock().vm_ctx.csselr_el1
+    }
+
+    pub fn set_csselr(&self, csselr: u64) {
+        let mut inner = self.0.inner_mut.lock();
+        inner.vm_ctx.csselr_el1 = csselr;
+    }
 }
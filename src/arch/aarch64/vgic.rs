@@ -1,46 +1,48 @@
-use core::cell::{Cell, RefCell};
+use core::cell::RefCell;
 use core::ops::Range;
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 
 use alloc::collections::VecDeque;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
-use spin::Mutex;
-
 use crate::board::{PlatOperation, Platform};
 use crate::config::VmEmulatedDeviceConfig;
 use crate::device::{EmuContext, EmuDev, EmuDeviceType};
 use crate::kernel::{active_vcpu_id, active_vm, current_cpu};
+use crate::kernel::{hw_test_sgi_armed, hw_test_sgi_eoi, interrupt_vm_transfer_eoi, InitcEvent, Vcpu, Vm, VmMutex};
 use crate::kernel::{ipi_intra_broadcast_msg, ipi_send_msg, IpiInitcMessage, IpiInnerMsg, IpiMessage, IpiType};
-use crate::kernel::{InitcEvent, Vcpu, Vm};
+use crate::mm::{HeapTag, HeapTagGuard};
 use crate::util::{bit_extract, bit_get, bit_set, bitmap_find_nth, self_ref_cell::SelfRefCell};
 
 use super::gic::*;
 
+// the interrupts of the hypervisor are at 0x7f, a passthrough interrupt never goes above them in the GICD
+const VGIC_HW_PRIO_MIN: u8 = 0x80;
+// a guest may only choose between level (0b00) and edge (0b10) for its SPIs, bit 0 is reserved
+const VGIC_ICFGR_SPI_MASK: u8 = 0b10;
+
 struct VgicInt {
     inner_const: VgicIntInnerConst,
-    inner: Mutex<VgicIntInnerMut>,
-    lock: Mutex<()>,
+    inner: VmMutex<VgicIntInnerMut>,
+    lock: VmMutex<()>,
 }
 
 struct VgicIntInnerConst {
     id: u16,
-    hw: Cell<bool>,
+    // set when the VM is set up, or when a passthrough SPI moves between VMs
+    hw: AtomicBool,
 }
 
-// SAFETY: VgicIntInnerConst hw is only set when initializing
-unsafe impl Sync for VgicIntInnerConst {}
-
 impl VgicInt {
     fn new(id: usize) -> Self {
         Self {
             inner_const: VgicIntInnerConst {
                 id: (id + GIC_PRIVINT_NUM) as u16,
-                hw: Cell::new(false),
+                hw: AtomicBool::new(false),
             },
-            inner: Mutex::new(VgicIntInnerMut::new()),
-            lock: Mutex::new(()),
+            inner: VmMutex::new(VgicIntInnerMut::new()),
+            lock: VmMutex::new(()),
         }
     }
 
@@ -48,10 +50,10 @@ impl VgicInt {
         Self {
             inner_const: VgicIntInnerConst {
                 id: id as u16,
-                hw: Cell::new(false),
+                hw: AtomicBool::new(false),
             },
-            inner: Mutex::new(VgicIntInnerMut::priv_new(owner, targets, enabled)),
-            lock: Mutex::new(()),
+            inner: VmMutex::new(VgicIntInnerMut::priv_new(owner, targets, enabled)),
+            lock: VmMutex::new(()),
         }
     }
 
@@ -91,7 +93,7 @@ impl VgicInt {
     }
 
     fn set_hw(&self, hw: bool) {
-        self.inner_const.hw.set(hw);
+        self.inner_const.hw.store(hw, Ordering::Release);
     }
 
     fn set_cfg(&self, cfg: u8) {
@@ -131,7 +133,12 @@ impl VgicInt {
 
     #[inline]
     fn hw(&self) -> bool {
-        self.inner_const.hw.get()
+        self.inner_const.hw.load(Ordering::Acquire)
+    }
+
+    fn eoi_trap(&self) -> bool {
+        let vgic_int = self.inner.lock();
+        vgic_int.eoi_trap
     }
 
     pub fn state(&self) -> IrqState {
@@ -170,11 +177,11 @@ impl VgicInt {
         vgic_int.owner_vm()
     }
 
-    fn locked_helper<F>(&self, f: F)
+    fn locked_helper<F, R>(&self, f: F) -> R
     where
-        F: FnOnce(&mut VgicIntInnerMut),
+        F: FnOnce(&mut VgicIntInnerMut) -> R,
     {
-        f(&mut self.inner.lock());
+        f(&mut self.inner.lock())
     }
 }
 
@@ -189,6 +196,8 @@ struct VgicIntInnerMut {
 
     in_pend: bool,
     in_act: bool,
+    // a passthrough SPI moving to another VM, the guest EOI is trapped to deactivate it
+    eoi_trap: bool,
 }
 
 impl VgicIntInnerMut {
@@ -203,6 +212,7 @@ impl VgicIntInnerMut {
             cfg: 0,
             in_pend: false,
             in_act: false,
+            eoi_trap: false,
         }
     }
 
@@ -217,6 +227,7 @@ impl VgicIntInnerMut {
             cfg: 0,
             in_pend: false,
             in_act: false,
+            eoi_trap: false,
         }
     }
 
@@ -276,16 +287,24 @@ impl VgicCpuPrivMut {
             .map(|i| list.remove(i));
     }
 
-    fn pend_list_push(&mut self, interrupt: &VgicInt) {
-        self.pend_list.push_back(SelfRefCell::new(interrupt));
+    // keep the list sorted by priority, FIFO among the same priority
+    #[inline]
+    fn queue_insert(list: &mut VecDeque<SelfRefCell<VgicInt>>, interrupt: &VgicInt, prio: u8) {
+        let idx = queue_insert_pos(list.iter().map(|virt_int| virt_int.prio()), prio);
+        list.insert(idx, SelfRefCell::new(interrupt));
+    }
+
+    // `prio` is passed in as the caller holds the inner lock of `interrupt`
+    fn pend_list_push(&mut self, interrupt: &VgicInt, prio: u8) {
+        Self::queue_insert(&mut self.pend_list, interrupt, prio);
     }
 
     fn pend_list_remove(&mut self, interrupt: &VgicInt) {
         Self::queue_remove(&mut self.pend_list, interrupt);
     }
 
-    fn act_list_push(&mut self, interrupt: &VgicInt) {
-        self.act_list.push_back(SelfRefCell::new(interrupt));
+    fn act_list_push(&mut self, interrupt: &VgicInt, prio: u8) {
+        Self::queue_insert(&mut self.act_list, interrupt, prio);
     }
 
     fn act_list_remove(&mut self, interrupt: &VgicInt) {
@@ -307,10 +326,21 @@ impl Default for VgicCpuPriv {
     }
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct VgicLrStat {
+    // hardware interrupts written to a free LR by `inject_hw_direct`
+    pub direct: usize,
+    // injections that found every LR in use
+    pub overflow: usize,
+}
+
 pub struct Vgic {
     address_range: Range<usize>,
     vgicd: Vgicd,
     cpu_priv: Vec<VgicCpuPriv>,
+    lr_direct: AtomicUsize,
+    lr_overflow: AtomicUsize,
 }
 
 impl Vgic {
@@ -319,6 +349,15 @@ impl Vgic {
             address_range: base..base + length,
             vgicd: Vgicd::new(cpu_num),
             cpu_priv: Vec::new(),
+            lr_direct: AtomicUsize::new(0),
+            lr_overflow: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn lr_stat(&self) -> VgicLrStat {
+        VgicLrStat {
+            direct: self.lr_direct.load(Ordering::Relaxed),
+            overflow: self.lr_overflow.load(Ordering::Relaxed),
         }
     }
 
@@ -328,7 +367,7 @@ impl Vgic {
         interrupt.locked_helper(|int| {
             let state = int.state;
             if state.is_pend() && !int.in_pend {
-                cpu_priv.pend_list_push(interrupt);
+                cpu_priv.pend_list_push(interrupt, int.prio);
                 int.in_pend = true;
             } else if !state.is_pend() && int.in_pend {
                 cpu_priv.pend_list_remove(interrupt);
@@ -336,7 +375,7 @@ impl Vgic {
             }
 
             if state.is_active() && !int.in_act {
-                cpu_priv.act_list_push(interrupt);
+                cpu_priv.act_list_push(interrupt, int.prio);
                 int.in_act = true;
             } else if !state.is_active() && int.in_act {
                 cpu_priv.act_list_remove(interrupt);
@@ -348,7 +387,7 @@ impl Vgic {
                 && cpu_priv.sgis[interrupt.id() as usize].pend != 0
                 && !int.in_pend
             {
-                cpu_priv.pend_list_push(interrupt);
+                cpu_priv.pend_list_push(interrupt, int.prio);
                 int.in_pend = true;
             }
         });
@@ -364,6 +403,39 @@ impl Vgic {
         }
     }
 
+    // the interrupt with the highest priority that waits for a list register
+    fn int_list_first_queued(&self, vcpu: &Vcpu, is_pend: bool) -> Option<&VgicInt> {
+        let cpu_priv = self.cpu_priv[vcpu.id()].inner_mut.borrow();
+        let list = if is_pend {
+            &cpu_priv.pend_list
+        } else {
+            &cpu_priv.act_list
+        };
+        list.iter()
+            .find(|int| {
+                !int.in_lr()
+                    && (!is_pend || int.enabled())
+                    && match int.owner() {
+                        Some(owner) => owner.id() == vcpu.id() && owner.vm_id() == vcpu.vm_id(),
+                        None => true,
+                    }
+            })
+            .cloned()
+            .map(|i| i.as_ref())
+    }
+
+    /* Arm the maintenance interrupt that moves the queued pending interrupts into the list registers:
+     * NPIE while a list register is free, UIE once all of them are in use, as NPIE would fire
+     * over and over while the guest is still handling them.
+     */
+    fn update_lr_maint(&self, vcpu: &Vcpu) {
+        let gic_lrs = gic_lrs();
+        let queued = self.int_list_first_queued(vcpu, true).is_some();
+        let lrs_full = (0..gic_lrs).all(|i| GICH.elrsr(i / 32) & (1 << (i % 32)) == 0);
+        let hcr = GICH.hcr() & !((GICH_HCR_UIE_BIT | GICH_HCR_NPIE_BIT) as u32);
+        GICH.set_hcr(hcr | lr_maint_bits(queued, lrs_full));
+    }
+
     fn set_vgicd_ctlr(&self, ctlr: u32) {
         self.vgicd.ctlr.store(ctlr, Ordering::Relaxed);
     }
@@ -465,8 +537,7 @@ impl Vgic {
 
             if interrupt.state().is_pend() && interrupt.enabled() {
                 // println!("remove_lr: interrupt_state {}", interrupt.state());
-                let hcr = GICH.hcr();
-                GICH.set_hcr(hcr | (1 << 3)); // List registers contain no interrupts in the pending state
+                self.update_lr_maint(vcpu);
             }
             return true;
         }
@@ -489,38 +560,15 @@ impl Vgic {
         }
 
         if lr_ind.is_none() {
-            let mut pend_found = 0;
-            let mut act_found = 0;
-            let mut min_prio_act = 0;
-            let mut min_prio_pend = 0;
-            let mut act_ind = None;
-            let mut pend_ind = None;
-
-            for i in 0..gic_lrs {
-                let lr = GICH.lr(i);
-                let lr_prio = (lr >> 23) & 0b11111;
-                let lr_state = IrqState::from((lr >> 28) & 0b11);
-
-                if lr_state.is_active() {
-                    if lr_prio > min_prio_act {
-                        min_prio_act = lr_prio;
-                        act_ind = Some(i);
-                    }
-                    act_found += 1;
-                } else if lr_state.is_pend() {
-                    if lr_prio > min_prio_pend {
-                        min_prio_pend = lr_prio;
-                        pend_ind = Some(i);
-                    }
-                    pend_found += 1;
-                }
-            }
-
-            if pend_found > 1 {
-                lr_ind = pend_ind;
-            } else if act_found > 1 {
-                lr_ind = act_ind;
-            }
+            self.lr_overflow.fetch_add(1, Ordering::Relaxed);
+            let int_prio = (interrupt.prio() as u32 >> 3) & 0b11111;
+            lr_ind = lr_spill_victim(
+                (0..gic_lrs).map(|i| {
+                    let lr = GICH.lr(i);
+                    (IrqState::from((lr >> 28) & 0b11), (lr >> 23) & 0b11111)
+                }),
+                int_prio,
+            );
 
             if let Some(idx) = lr_ind {
                 let spilled_int = self.get_int(vcpu, GICH.lr(idx) as usize & 0b1111111111).unwrap();
@@ -545,8 +593,7 @@ impl Vgic {
             None => {
                 // turn on maintenance interrupts
                 if vgic_get_state(interrupt).is_pend() {
-                    let hcr = GICH.hcr();
-                    GICH.set_hcr(hcr | (1 << 3)); // List registers contain no interrupts in the pending state
+                    self.update_lr_maint(vcpu);
                 }
             }
         }
@@ -576,8 +623,10 @@ impl Vgic {
 
         let state = vgic_get_state(interrupt);
         let mut lr = (int_id & 0b1111111111) | (((int_prio as usize >> 3) & 0b11111) << 23);
+        // a trapped EOI needs a virtual LR, a hardware one is deactivated without maintenance
+        let hw = vgic_int_is_hw(interrupt) && !interrupt.eoi_trap();
 
-        if vgic_int_is_hw(interrupt) {
+        if hw {
             lr |= 1 << 31;
             lr |= (0b1111111111 & int_id) << 10;
             if state == IrqState::PendActive {
@@ -608,11 +657,12 @@ impl Vgic {
                 }
             }
 
-            if self.cpu_priv_sgis_pend(vcpu_id, int_id) != 0 {
+            // the EOI of the SGI of HVC_SYS_TEST_HW is waited for
+            if self.cpu_priv_sgis_pend(vcpu_id, int_id) != 0 || hw_test_sgi_armed(vcpu, int_id) {
                 lr |= 1 << 19;
             }
         } else {
-            if !gic_is_priv(int_id) && !vgic_int_is_hw(interrupt) {
+            if !gic_is_priv(int_id) && !hw {
                 lr |= 1 << 19;
             }
 
@@ -633,33 +683,42 @@ impl Vgic {
         self.update_int_list(vcpu, interrupt);
     }
 
-    fn route(&self, vcpu: &Vcpu, interrupt: &VgicInt) {
-        let cpu_id = current_cpu().id;
+    /* The targets are vcpu masks, `vcpu` takes the interrupt if it is one of them, or else the first
+     * other target vcpu does on its own core, even one sharing this core with `vcpu`.
+     * The ipi to it is returned, to be sent once `interrupt.lock` is released.
+     */
+    #[must_use]
+    fn route(&self, vcpu: &Vcpu, interrupt: &VgicInt) -> Option<VgicIpi> {
         let int_targets = {
             let int = interrupt.inner.lock();
             if IrqState::Inactive == int.state || !int.enabled {
-                return;
+                return None;
             }
             int.targets
         };
 
-        if (int_targets & (1 << cpu_id)) != 0 {
+        if (int_targets & (1 << vcpu.id())) != 0 {
             // println!("vm{} route addr lr for int {}", vcpu.vm_id(), interrupt.id());
             self.add_lr(vcpu, interrupt);
         }
 
-        if !interrupt.in_lr() && (int_targets & !(1 << cpu_id)) != 0 {
-            let vcpu_vm_id = vcpu.vm_id();
-
+        if !interrupt.in_lr() && (int_targets & !(1 << vcpu.id())) != 0 {
+            let vm = vcpu.vm()?;
+            let trgt = vm
+                .vcpu_list()
+                .iter()
+                .find(|trgt| trgt.id() != vcpu.id() && int_targets & (1 << trgt.id()) != 0)?;
             let ipi_msg = IpiInitcMessage {
                 event: InitcEvent::Route,
-                vm_id: vcpu_vm_id,
+                vm_id: vm.id(),
+                vcpu_id: Some(trgt.id()),
                 int_id: interrupt.id(),
                 val: 0,
             };
             vgic_int_yield_owner(vcpu, interrupt);
-            ipi_intra_broadcast_msg(&active_vm().unwrap(), IpiType::Intc, IpiInnerMsg::Initc(ipi_msg));
+            return Some(VgicIpi::Route(trgt.phys_id(), ipi_msg));
         }
+        None
     }
 
     fn set_enable(&self, vcpu: &Vcpu, int_id: usize, en: bool) {
@@ -669,13 +728,14 @@ impl Vgic {
         match self.get_int(vcpu, int_id) {
             Some(interrupt) => {
                 let interrupt_lock = interrupt.lock.lock();
+                let mut ipi = None;
                 if vgic_int_get_owner(vcpu.clone(), interrupt) {
                     if interrupt.enabled() ^ en {
                         interrupt.set_enabled(en);
                         if !interrupt.enabled() {
                             self.remove_lr(vcpu, interrupt);
                         } else {
-                            self.route(vcpu, interrupt);
+                            ipi = self.route(vcpu, interrupt);
                         }
                         if interrupt.hw() {
                             GICD.set_enable(interrupt.id() as usize, en);
@@ -688,20 +748,17 @@ impl Vgic {
                     let ipi_msg = IpiInitcMessage {
                         event: InitcEvent::SetEn,
                         vm_id: vcpu_vm_id,
+                        vcpu_id: interrupt.owner_id(),
                         int_id: interrupt.id(),
                         val: en as u8,
                     };
-                    if !ipi_send_msg(int_phys_id, IpiType::Intc, IpiInnerMsg::Initc(ipi_msg)) {
-                        error!(
-                            "vgicd_set_enable: Failed to send ipi message, target {} type {}",
-                            int_phys_id, 0
-                        );
-                    }
+                    ipi = Some(VgicIpi::Owner(int_phys_id, ipi_msg));
                 }
                 drop(interrupt_lock);
+                vgic_send_ipi(vcpu, ipi, "vgicd_set_enable");
             }
             None => {
-                println!("vgicd_set_enable: interrupt {} is illegal", int_id);
+                warn!("vgicd_set_enable: interrupt {} is illegal", int_id);
             }
         }
     }
@@ -718,7 +775,7 @@ impl Vgic {
 
         if let Some(interrupt) = self.get_int(vcpu, bit_extract(int_id, 0, 10)) {
             let interrupt_lock = interrupt.lock.lock();
-            if vgic_int_get_owner(vcpu.clone(), interrupt) {
+            let ipi = if vgic_int_get_owner(vcpu.clone(), interrupt) {
                 self.remove_lr(vcpu, interrupt);
 
                 let state = interrupt.state();
@@ -734,30 +791,21 @@ impl Vgic {
                     let vgic_int_id = interrupt.id() as usize;
                     GICD.set_state(vgic_int_id, if state.is_pend() { IrqState::Active } else { state })
                 }
-                self.route(vcpu, interrupt);
+                let ipi = self.route(vcpu, interrupt);
                 vgic_int_yield_owner(vcpu, interrupt);
-                drop(interrupt_lock);
+                ipi
             } else {
                 let vm_id = vcpu.vm_id();
 
                 let m = IpiInitcMessage {
                     event: InitcEvent::SetPend,
                     vm_id,
+                    vcpu_id: interrupt.owner_id(),
                     int_id: interrupt.id(),
                     val: pend as u8,
                 };
                 match interrupt.owner() {
-                    Some(owner) => {
-                        let phys_id = owner.phys_id();
-
-                        drop(interrupt_lock);
-                        if !ipi_send_msg(phys_id, IpiType::Intc, IpiInnerMsg::Initc(m)) {
-                            error!(
-                                "vgicd_set_pend: Failed to send ipi message, target {} type {}",
-                                phys_id, 0
-                            );
-                        }
-                    }
+                    Some(owner) => Some(VgicIpi::Owner(owner.phys_id(), m)),
                     None => {
                         panic!(
                             "set_pend: Core {} int {} has no owner",
@@ -766,14 +814,16 @@ impl Vgic {
                         );
                     }
                 }
-            }
+            };
+            drop(interrupt_lock);
+            vgic_send_ipi(vcpu, ipi, "vgicd_set_pend");
         }
     }
 
     fn set_active(&self, vcpu: &Vcpu, int_id: usize, act: bool) {
         if let Some(interrupt) = self.get_int(vcpu, bit_extract(int_id, 0, 10)) {
             let interrupt_lock = interrupt.lock.lock();
-            if vgic_int_get_owner(vcpu.clone(), interrupt) {
+            let ipi = if vgic_int_get_owner(vcpu.clone(), interrupt) {
                 self.remove_lr(vcpu, interrupt);
                 let state = interrupt.state();
                 if act && !state.is_active() {
@@ -788,54 +838,52 @@ impl Vgic {
                     let vgic_int_id = interrupt.id() as usize;
                     GICD.set_state(vgic_int_id, if state.is_pend() { IrqState::Active } else { state })
                 }
-                self.route(vcpu, interrupt);
+                let ipi = self.route(vcpu, interrupt);
                 vgic_int_yield_owner(vcpu, interrupt);
+                ipi
             } else {
                 let vm_id = vcpu.vm_id();
 
                 let m = IpiInitcMessage {
                     event: InitcEvent::SetPend,
                     vm_id,
+                    vcpu_id: interrupt.owner_id(),
                     int_id: interrupt.id(),
                     val: act as u8,
                 };
-                let phys_id = interrupt.owner_phys_id().unwrap();
-                if !ipi_send_msg(phys_id, IpiType::Intc, IpiInnerMsg::Initc(m)) {
-                    error!(
-                        "vgicd_set_active: Failed to send ipi message, target {} type {}",
-                        phys_id, 0
-                    );
-                }
-            }
+                Some(VgicIpi::Owner(interrupt.owner_phys_id().unwrap(), m))
+            };
             drop(interrupt_lock);
+            vgic_send_ipi(vcpu, ipi, "vgicd_set_active");
         }
     }
 
     fn set_icfgr(&self, vcpu: &Vcpu, int_id: usize, cfg: u8) {
         if let Some(interrupt) = self.get_int(vcpu, int_id) {
             let interrupt_lock = interrupt.lock.lock();
-            if vgic_int_get_owner(vcpu.clone(), interrupt) {
+            let ipi = if vgic_int_get_owner(vcpu.clone(), interrupt) {
                 interrupt.set_cfg(cfg);
                 if interrupt.hw() {
-                    GICD.set_icfgr(interrupt.id() as usize, cfg);
+                    // the trigger of an enabled interrupt must not change under the distributor
+                    let int_id = interrupt.id() as usize;
+                    GICD.set_enable(int_id, false);
+                    GICD.set_icfgr(int_id, cfg);
+                    GICD.set_enable(int_id, interrupt.enabled());
                 }
                 vgic_int_yield_owner(vcpu, interrupt);
+                None
             } else {
                 let m = IpiInitcMessage {
                     event: InitcEvent::SetCfg,
                     vm_id: vcpu.vm_id(),
+                    vcpu_id: interrupt.owner_id(),
                     int_id: interrupt.id(),
                     val: cfg,
                 };
-                if !ipi_send_msg(interrupt.owner_phys_id().unwrap(), IpiType::Intc, IpiInnerMsg::Initc(m)) {
-                    error!(
-                        "set_icfgr: Failed to send ipi message, target {} type {}",
-                        interrupt.owner_phys_id().unwrap(),
-                        0
-                    );
-                }
-            }
+                Some(VgicIpi::Owner(interrupt.owner_phys_id().unwrap(), m))
+            };
             drop(interrupt_lock);
+            vgic_send_ipi(vcpu, ipi, "set_icfgr");
         } else {
             unimplemented!();
         }
@@ -887,7 +935,7 @@ impl Vgic {
             }
             drop(interrupt_lock);
         } else {
-            println!("sgi_set_pend: interrupt {} is None", bit_extract(int_id, 0, 10));
+            warn!("sgi_set_pend: interrupt {} is None", bit_extract(int_id, 0, 10));
         }
     }
 
@@ -896,16 +944,17 @@ impl Vgic {
 
         if let Some(interrupt) = self.get_int(vcpu, int_id) {
             let interrupt_lock = interrupt.lock.lock();
+            let mut ipi = None;
             if vgic_int_get_owner(vcpu.clone(), interrupt) {
                 if interrupt.prio() != prio {
                     self.remove_lr(vcpu, interrupt);
                     let prev_prio = interrupt.prio();
                     interrupt.set_prio(prio);
                     if prio <= prev_prio {
-                        self.route(vcpu, interrupt);
+                        ipi = self.route(vcpu, interrupt);
                     }
                     if interrupt.hw() {
-                        GICD.set_prio(interrupt.id() as usize, prio);
+                        GICD.set_prio(interrupt.id() as usize, prio.max(VGIC_HW_PRIO_MIN));
                     }
                 }
                 vgic_int_yield_owner(vcpu, interrupt);
@@ -915,18 +964,14 @@ impl Vgic {
                 let m = IpiInitcMessage {
                     event: InitcEvent::SetPrio,
                     vm_id,
+                    vcpu_id: interrupt.owner_id(),
                     int_id: interrupt.id(),
                     val: prio,
                 };
-                if !ipi_send_msg(interrupt.owner_phys_id().unwrap(), IpiType::Intc, IpiInnerMsg::Initc(m)) {
-                    error!(
-                        "set_prio: Failed to send ipi message, target {} type {}",
-                        interrupt.owner_phys_id().unwrap(),
-                        0
-                    );
-                }
+                ipi = Some(VgicIpi::Owner(interrupt.owner_phys_id().unwrap(), m));
             }
             drop(interrupt_lock);
+            vgic_send_ipi(vcpu, ipi, "set_prio");
         }
     }
 
@@ -937,20 +982,17 @@ impl Vgic {
     fn set_trgt(&self, vcpu: &Vcpu, int_id: usize, trgt: u8) {
         if let Some(interrupt) = self.get_int(vcpu, int_id) {
             let interrupt_lock = interrupt.lock.lock();
+            let mut ipi = None;
             if vgic_int_get_owner(vcpu.clone(), interrupt) {
                 if interrupt.targets() != trgt {
                     interrupt.set_targets(trgt);
-                    let mut ptrgt = 0;
-                    for cpuid in 0..8 {
-                        if bit_get(trgt as usize, cpuid) != 0 {
-                            ptrgt = bit_set(ptrgt, Platform::cpuid_to_cpuif(cpuid))
-                        }
-                    }
                     if interrupt.hw() {
-                        GICD.set_trgt(interrupt.id() as usize, ptrgt as u8);
+                        if let Some(vm) = vcpu.vm() {
+                            GICD.set_trgt(interrupt.id() as usize, vgic_trgt_to_gicd(&vm, trgt));
+                        }
                     }
                     if vgic_get_state(interrupt) != IrqState::Inactive {
-                        self.route(vcpu, interrupt);
+                        ipi = self.route(vcpu, interrupt);
                     }
                 }
                 vgic_int_yield_owner(vcpu, interrupt);
@@ -959,18 +1001,14 @@ impl Vgic {
                 let m = IpiInitcMessage {
                     event: InitcEvent::SetTrgt,
                     vm_id,
+                    vcpu_id: interrupt.owner_id(),
                     int_id: interrupt.id(),
                     val: trgt,
                 };
-                if !ipi_send_msg(interrupt.owner_phys_id().unwrap(), IpiType::Intc, IpiInnerMsg::Initc(m)) {
-                    error!(
-                        "set_trgt: Failed to send ipi message, target {} type {}",
-                        interrupt.owner_phys_id().unwrap(),
-                        0
-                    );
-                }
+                ipi = Some(VgicIpi::Owner(interrupt.owner_phys_id().unwrap(), m));
             }
             drop(interrupt_lock);
+            vgic_send_ipi(vcpu, ipi, "set_trgt");
         }
     }
 
@@ -978,9 +1016,271 @@ impl Vgic {
         self.get_int(vcpu, int_id).unwrap().targets()
     }
 
+    /* Make the interrupts of a vcpu follow it after it has been moved to another physical cpu.
+     * The targets are vcpu masks and stay, only the passthrough SPIs targeting the vcpu are
+     * retargeted in GICD to its new core.
+     *
+     * @param[in] vcpu: the moved vcpu, its phys_id is already the new one.
+     * @param[in] old_phys_id: the physical cpu the vcpu was running on.
+     */
+    pub fn vcpu_retarget_ints(&self, vcpu: &Vcpu, old_phys_id: usize) {
+        if vcpu.phys_id() == old_phys_id {
+            return;
+        }
+        let vm = match vcpu.vm() {
+            Some(vm) => vm,
+            None => return,
+        };
+        for interrupt in self.vgicd.interrupts.iter() {
+            let _interrupt_lock = interrupt.lock.lock();
+            let targets = interrupt.targets();
+            if targets & (1 << vcpu.id()) == 0 || !interrupt.hw() {
+                continue;
+            }
+            GICD.set_trgt(interrupt.id() as usize, vgic_trgt_to_gicd(&vm, targets));
+            debug!(
+                "VM[{}] retarget int {} to Core {} for vcpu {}",
+                vcpu.vm_id(),
+                interrupt.id(),
+                vcpu.phys_id(),
+                vcpu.id()
+            );
+        }
+    }
+
+    /* The GICD state of PPIs is banked per core, so the passthrough PPIs of a moved vcpu (e.g. the guest timer)
+     * are released on the old core with `bind` false, and set up again on the new core with `bind` true.
+     */
+    pub fn vcpu_bind_hw_ppis(&self, vcpu: &Vcpu, bind: bool) {
+        for interrupt in self.cpu_priv[vcpu.id()].interrupts.iter().skip(GIC_SGIS_NUM) {
+            if !interrupt.hw() {
+                continue;
+            }
+            let _interrupt_lock = interrupt.lock.lock();
+            let int_id = interrupt.id() as usize;
+            if bind {
+                GICD.set_prio(int_id, interrupt.prio().max(VGIC_HW_PRIO_MIN));
+                // deactivated through the HW bit of its saved LR
+                if interrupt.in_lr() {
+                    GICD.set_state(int_id, IrqState::Active);
+                }
+                GICD.set_enable(int_id, interrupt.enabled());
+            } else {
+                GICD.set_enable(int_id, false);
+                GICD.set_act(int_id, false);
+            }
+        }
+    }
+
+    // the physical cpu of the first vcpu of `vm` targeted by a SPI
+    pub fn spi_target(&self, vm: &Vm, int_id: usize) -> Option<usize> {
+        let interrupt = self.vgicd_interrupt(int_id.checked_sub(GIC_PRIVINT_NUM)?)?;
+        match interrupt.targets() {
+            0 => None,
+            targets => vm.vcpuid_to_pcpuid(targets.trailing_zeros() as usize),
+        }
+    }
+
+    // drop the pending and active private interrupts of a vcpu which is going offline
+    pub fn vcpu_drop_priv_ints(&self, vcpu: &Vcpu) {
+        let running = current_cpu().active_vcpu.as_ref() == Some(vcpu);
+        let mut cpu_priv = self.cpu_priv[vcpu.id()].inner_mut.borrow_mut();
+        for interrupt in self.cpu_priv[vcpu.id()].interrupts.iter() {
+            interrupt.locked_helper(|int| {
+                if let Some(lr) = int.lr.take() {
+                    // the list registers only belong to this vcpu when it is running
+                    if running {
+                        GICH.set_lr(lr as usize, 0);
+                    }
+                    cpu_priv.curr_lrs[lr as usize] = 0;
+                }
+                int.state = IrqState::Inactive;
+                int.in_pend = false;
+                int.in_act = false;
+            });
+        }
+        cpu_priv.pend_list.retain(|int| int.id() as usize >= GIC_PRIVINT_NUM);
+        cpu_priv.act_list.retain(|int| int.id() as usize >= GIC_PRIVINT_NUM);
+        cpu_priv.sgis = [Sgis::default(); GIC_SGIS_NUM];
+    }
+
+    /* Drop the pending and active interrupts of a vcpu of a rebooting VM, on the core of the vcpu:
+     * its private interrupts and the SPIs it owns. The SPIs without an owner are cleared as well,
+     * the ones of the vcpus on other cores are left to those cores.
+     */
+    pub fn vcpu_reset_ints(&self, vcpu: &Vcpu) {
+        self.vcpu_drop_priv_ints(vcpu);
+        let running = current_cpu().active_vcpu.as_ref() == Some(vcpu);
+        let mut cpu_priv = self.cpu_priv[vcpu.id()].inner_mut.borrow_mut();
+        for interrupt in self.vgicd.interrupts.iter() {
+            interrupt.locked_helper(|int| {
+                if int.owner.as_ref().is_some_and(|owner| owner != vcpu) {
+                    return;
+                }
+                if let Some(lr) = int.lr.take() {
+                    if running {
+                        GICH.set_lr(lr as usize, 0);
+                    }
+                    cpu_priv.curr_lrs[lr as usize] = 0;
+                }
+                // the physical interrupt forwarded to the old guest may still wait for its deactivation
+                if interrupt.hw() && int.state != IrqState::Inactive {
+                    GICD.set_state(interrupt.id() as usize, IrqState::Inactive);
+                }
+                int.state = IrqState::Inactive;
+                int.in_pend = false;
+                int.in_act = false;
+                int.owner = None;
+            });
+        }
+        // only the SPIs are left after the private interrupts are dropped
+        cpu_priv.pend_list.clear();
+        cpu_priv.act_list.clear();
+    }
+
+    /* Release a passthrough SPI moving to another VM, on the core of the vcpu owning it.
+     * A pending interrupt is dropped and the transfer goes on at once. An active one is put back
+     * into a virtual LR trapping its EOI, it is deactivated in the GICD by `handle_trapped_eoir`.
+     */
+    fn release_hw_int(&self, vcpu: &Vcpu, int_id: usize) {
+        let interrupt = match self.get_int(vcpu, int_id) {
+            Some(interrupt) => interrupt,
+            None => return,
+        };
+        let interrupt_lock = interrupt.lock.lock();
+        let mut active = false;
+        if vgic_owns(vcpu, interrupt) {
+            // a hardware LR already deactivated by the guest is found inactive here
+            self.remove_lr(vcpu, interrupt);
+            active = interrupt.state().is_active();
+            interrupt.locked_helper(|int| {
+                int.state = if active { IrqState::Active } else { IrqState::Inactive };
+                int.eoi_trap = active;
+            });
+            self.update_int_list(vcpu, interrupt);
+            if active {
+                self.add_lr(vcpu, interrupt);
+            } else {
+                vgic_int_yield_owner(vcpu, interrupt);
+            }
+        }
+        if !active {
+            GICD.set_state(int_id, IrqState::Inactive);
+        }
+        drop(interrupt_lock);
+        if !active {
+            interrupt_vm_transfer_eoi(int_id);
+        }
+    }
+
+    /* Release a free LR still recorded by a hardware interrupt, found by the physical id left in the LR.
+     * A hardware LR is deactivated by the guest through the HW bit without any EOI maintenance,
+     * so the interrupt keeps `lr` until someone looks at the LR again.
+     * Return false if the interrupt is locked by someone else, the LR is left as is then.
+     */
+    fn reconcile_hw_lr(&self, vcpu: &Vcpu, lr_idx: usize) -> bool {
+        let lr_val = GICH.lr(lr_idx) as usize;
+        if lr_val & (1 << 31) == 0 || IrqState::from((lr_val >> 28) as u32 & 0b11) != IrqState::Inactive {
+            return true;
+        }
+        if let Some(interrupt) = self.get_int(vcpu, bit_extract(lr_val, 10, 10)) {
+            let interrupt_lock = match interrupt.lock.try_lock() {
+                Some(lock) => lock,
+                None => return false,
+            };
+            if vgic_owns(vcpu, interrupt) && interrupt.lr() == Some(lr_idx as u16) {
+                interrupt.clear_lr();
+                vgic_int_yield_owner(vcpu, interrupt);
+            }
+            drop(interrupt_lock);
+        }
+        GICH.set_lr(lr_idx, 0);
+        true
+    }
+
+    fn reconcile_hw_lrs(&self, vcpu: &Vcpu) {
+        let elrsr = GICH.elrsr(0) as usize | ((GICH.elrsr(1) as usize) << 32);
+        for lr_idx in 0..gic_lrs() {
+            if elrsr & (1 << lr_idx) != 0 {
+                self.reconcile_hw_lr(vcpu, lr_idx);
+            }
+        }
+    }
+
+    /* Fast path of a hardware interrupt for the vcpu running on this core: write it to a free LR
+     * with the HW bit and the physical id, without the interrupt lock and the pend list.
+     * Return false to fall back to the queued path, when no LR is free or the interrupt is
+     * still tracked by the lists or an LR.
+     */
+    fn inject_hw_direct(&self, vcpu: &Vcpu, interrupt: &VgicInt) -> bool {
+        let gic_lrs = gic_lrs();
+        let lr_ind = match bitmap_find_nth(
+            GICH.elrsr(0) as usize | ((GICH.elrsr(1) as usize) << 32),
+            0,
+            gic_lrs,
+            1,
+            true,
+        ) {
+            Some(lr_ind) => lr_ind,
+            None => return false,
+        };
+        if !self.reconcile_hw_lr(vcpu, lr_ind) {
+            return false;
+        }
+
+        let int_id = interrupt.id() as usize;
+        let prio = interrupt.locked_helper(|int| {
+            if !int.enabled || int.in_pend || int.in_act || int.targets & (1 << vcpu.id()) == 0 {
+                return None;
+            }
+            match &int.owner {
+                Some(owner) if owner != vcpu => return None,
+                _ => {}
+            }
+            if let Some(lr) = int.lr {
+                // the interrupt is still live in another LR
+                let lr_val = GICH.lr(lr as usize) as usize;
+                if lr_val & (1 << 31) != 0
+                    && bit_extract(lr_val, 10, 10) == int_id
+                    && IrqState::from((lr_val >> 28) as u32 & 0b11) != IrqState::Inactive
+                {
+                    return None;
+                }
+            }
+            int.owner = Some(vcpu.clone());
+            int.state = IrqState::Inactive;
+            int.lr = Some(lr_ind as u16);
+            Some(int.prio)
+        });
+        let prio = match prio {
+            Some(prio) => prio,
+            None => return false,
+        };
+
+        // the physical interrupt was acknowledged by the hypervisor, so it is active in the GICD already
+        let lr = int_id
+            | (((prio as usize >> 3) & 0b11111) << 23)
+            | (1 << 31)
+            | (int_id << 10)
+            | ((IrqState::Pend as usize) << 28);
+        self.set_cpu_priv_curr_lrs(vcpu.id(), lr_ind, int_id as u16);
+        GICH.set_lr(lr_ind, lr as u32);
+        self.lr_direct.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
     pub fn inject(&self, vcpu: &Vcpu, int_id: usize) {
         // println!("Core {} inject int {} to vm{}", current_cpu().id, int_id, vcpu.vm_id());
+        if current_cpu().active_vcpu.as_ref() != Some(vcpu) {
+            // the LRs of this core belong to the running vcpu, `vcpu` takes the int when it is
+            // restored, the caller kicks it
+            vcpu.push_int(int_id);
+            return;
+        }
         if let Some(interrupt) = self.get_int(vcpu, bit_extract(int_id, 0, 10)) {
+            if vgic_int_is_hw(interrupt) && self.inject_hw_direct(vcpu, interrupt) {
+                return;
+            }
             if interrupt.hw() {
                 let interrupt_lock = interrupt.lock.lock();
                 // interrupt.set_owner(vcpu.clone());
@@ -992,8 +1292,9 @@ impl Vgic {
                     interrupt.lr = None;
                 });
                 self.update_int_list(vcpu, interrupt);
-                self.route(vcpu, interrupt);
+                let ipi = self.route(vcpu, interrupt);
                 drop(interrupt_lock);
+                vgic_send_ipi(vcpu, ipi, "vgic_inject");
             } else {
                 self.set_pend(vcpu, int_id, true);
             }
@@ -1007,20 +1308,20 @@ impl Vgic {
             self.set_vgicd_ctlr(current_cpu().get_gpr(idx) as u32 & 0x1);
             if prev_ctlr ^ self.vgicd_ctlr() != 0 {
                 let enable = self.vgicd_ctlr() != 0;
-                let hcr = GICH.hcr();
-                if enable {
-                    GICH.set_hcr(hcr | 1);
-                } else {
-                    GICH.set_hcr(hcr & !1);
-                }
                 let vm = active_vm().unwrap();
                 let m = IpiInitcMessage {
                     event: InitcEvent::GichEn,
                     vm_id: vm.id(),
+                    vcpu_id: None,
                     int_id: 0,
                     val: enable as u8,
                 };
-                ipi_intra_broadcast_msg(&vm, IpiType::Intc, IpiInnerMsg::Initc(m));
+                ipi_intra_broadcast_msg(&vm, IpiType::Intc, IpiInnerMsg::Initc(m.clone()));
+                // the vcpus of the VM on this core, the active one included
+                vgic_ipi_handler(IpiMessage {
+                    ipi_type: IpiType::Intc,
+                    ipi_message: IpiInnerMsg::Initc(m),
+                });
             }
         } else {
             let idx = emu_ctx.reg;
@@ -1035,7 +1336,7 @@ impl Vgic {
             let val = self.vgicd_typer() as usize;
             current_cpu().set_gpr(idx, val);
         } else {
-            println!("emu_typer_access: can't write to RO reg");
+            warn!("emu_typer_access: can't write to RO reg");
         }
     }
 
@@ -1045,7 +1346,7 @@ impl Vgic {
             let val = self.vgicd_iidr() as usize;
             current_cpu().set_gpr(idx, val);
         } else {
-            println!("emu_iidr_access: can't write to RO reg");
+            warn!("emu_iidr_access: can't write to RO reg");
         }
     }
 
@@ -1071,7 +1372,7 @@ impl Vgic {
             }
         }
         if first_int >= 16 && !vm_has_interrupt_flag {
-            println!(
+            warn!(
                 "emu_isenabler_access: vm[{}] does not have interrupt {}",
                 vm_id, first_int
             );
@@ -1096,7 +1397,7 @@ impl Vgic {
     }
 
     fn emu_pendr_access(&self, emu_ctx: &EmuContext, set: bool) {
-        println!("emu_pendr_access");
+        debug!("emu_pendr_access");
         let reg_idx = (emu_ctx.address & 0b1111111) / 4;
         let idx = emu_ctx.reg;
         let mut val = if emu_ctx.write { current_cpu().get_gpr(idx) } else { 0 };
@@ -1117,7 +1418,7 @@ impl Vgic {
             }
         }
         if first_int >= 16 && !vm_has_interrupt_flag {
-            println!("emu_pendr_access: vm[{}] does not have interrupt {}", vm_id, first_int);
+            warn!("emu_pendr_access: vm[{}] does not have interrupt {}", vm_id, first_int);
             return;
         }
 
@@ -1262,55 +1563,39 @@ impl Vgic {
     }
 
     fn emu_icfgr_access(&self, emu_ctx: &EmuContext) {
-        let first_int = (32 / GIC_CONFIG_BITS) * bit_extract(emu_ctx.address, 0, 9) / 4;
+        let offset = (emu_ctx.address & 0xfff) - VGICD_REG_OFFSET_ICFGR;
+        let ints = vgicd_access_ints(offset, emu_ctx.width, GIC_CONFIG_BITS);
         let vm = match active_vm() {
             Some(vm) => vm,
             None => {
                 panic!("emu_icfgr_access: current vcpu.vm is none");
             }
         };
-        let vm_id = vm.id();
-        let mut vm_has_interrupt_flag = false;
+        let vcpu = current_cpu().active_vcpu.clone().unwrap();
+        let reg = vgicd_reg_read(offset, GIC_CONFIG_BITS, |int_id| self.get_icfgr(&vcpu, int_id));
 
         if emu_ctx.write {
-            for i in 0..emu_ctx.width * 8 {
-                if vm.has_interrupt(first_int + i) {
-                    vm_has_interrupt_flag = true;
-                    break;
-                }
-            }
-            if first_int >= 16 && !vm_has_interrupt_flag {
-                warn!("emu_icfgr_access: vm[{}] does not have interrupt {}", vm_id, first_int);
+            if ints.start >= 16 && !ints.clone().any(|int_id| vm.has_interrupt(int_id)) {
+                warn!(
+                    "emu_icfgr_access: vm[{}] does not have interrupt {}",
+                    vm.id(),
+                    ints.start
+                );
                 return;
             }
-        }
-
-        if emu_ctx.write {
-            let idx = emu_ctx.reg;
-            let cfg = current_cpu().get_gpr(idx);
-            let mut irq = first_int;
-            let mut bit = 0;
-            while bit < emu_ctx.width * 8 {
-                self.set_icfgr(
-                    current_cpu().active_vcpu.as_ref().unwrap(),
-                    irq,
-                    bit_extract(cfg, bit, 2) as u8,
-                );
-                bit += 2;
-                irq += 1;
+            let reg = vgicd_lane_merge(reg, offset, emu_ctx.width, current_cpu().get_gpr(emu_ctx.reg));
+            for int_id in ints {
+                // the config of SGIs and PPIs is fixed, and only the SPIs of this VM are touched
+                if int_id >= GIC_PRIVINT_NUM && vm.has_interrupt(int_id) {
+                    self.set_icfgr(
+                        &vcpu,
+                        int_id,
+                        vgicd_reg_field(reg, offset, int_id, GIC_CONFIG_BITS) as u8 & VGIC_ICFGR_SPI_MASK,
+                    );
+                }
             }
         } else {
-            let mut cfg = 0;
-            let mut irq = first_int;
-            let mut bit = 0;
-            while bit < emu_ctx.width * 8 {
-                cfg |= (self.get_icfgr(current_cpu().active_vcpu.as_ref().unwrap(), irq) as usize) << bit;
-                bit += 2;
-                irq += 1;
-            }
-            let idx = emu_ctx.reg;
-            let val = cfg;
-            current_cpu().set_gpr(idx, val);
+            current_cpu().set_gpr(emu_ctx.reg, vgicd_lane_extract(reg, offset, emu_ctx.width));
         }
     }
 
@@ -1327,38 +1612,34 @@ impl Vgic {
         if bit_extract(emu_ctx.address, 0, 12) == bit_extract(Platform::GICD_BASE + 0x0f00, 0, 12) {
             if emu_ctx.write {
                 let sgir_trglstflt = bit_extract(val, 24, 2);
-                let mut trgtlist = 0;
-                // println!("addr {:x}, sgir trglst flt {}, vtrgt {}", emu_ctx.address, sgir_trglstflt, bit_extract(val, 16, 8));
-                match sgir_trglstflt {
-                    0 => {
-                        trgtlist = vgic_target_translate(&vm, bit_extract(val, 16, 8) as u32, true) as usize;
-                    }
-                    1 => {
-                        trgtlist = active_vm().unwrap().ncpu() & !(1 << current_cpu().id);
-                    }
-                    2 => {
-                        trgtlist = 1 << current_cpu().id;
-                    }
-                    3 => {
-                        return;
-                    }
-                    _ => {}
-                }
+                // the targets are vcpus, several of them may share a core
+                let vtrgt = match sgir_trglstflt {
+                    0 => bit_extract(val, 16, 8),
+                    1 => ((1 << vm.cpu_num()) - 1) & !(1 << active_vcpu_id()),
+                    2 => 1 << active_vcpu_id(),
+                    _ => return,
+                };
 
-                for i in 0..8 {
-                    if trgtlist & (1 << i) != 0 {
-                        let m = IpiInitcMessage {
-                            event: InitcEvent::SetPend,
-                            vm_id: active_vm().unwrap().id(),
-                            int_id: (bit_extract(val, 0, 8) | (active_vcpu_id() << 10)) as u16,
-                            val: true as u8,
-                        };
-                        if !ipi_send_msg(i, IpiType::Intc, IpiInnerMsg::Initc(m)) {
-                            error!(
-                                "emu_sgiregs_access: Failed to send ipi message, target {} type {}",
-                                i, 0
-                            );
-                        }
+                for vcpu in vm.vcpu_list().iter().filter(|vcpu| vtrgt & (1 << vcpu.id()) != 0) {
+                    let m = IpiInitcMessage {
+                        event: InitcEvent::SetPend,
+                        vm_id: vm.id(),
+                        vcpu_id: Some(vcpu.id()),
+                        int_id: (bit_extract(val, 0, 8) | (active_vcpu_id() << 10)) as u16,
+                        val: true as u8,
+                    };
+                    if vcpu.phys_id() == current_cpu().id {
+                        // SGI to this core, a queued ipi would be handled after returning to the guest
+                        vgic_ipi_handler(IpiMessage {
+                            ipi_type: IpiType::Intc,
+                            ipi_message: IpiInnerMsg::Initc(m),
+                        });
+                    } else if ipi_send_msg(vcpu.phys_id(), IpiType::Intc, IpiInnerMsg::Initc(m)).is_err() {
+                        error!(
+                            "emu_sgiregs_access: Failed to send ipi message, target {} type {}",
+                            vcpu.phys_id(),
+                            0
+                        );
                     }
                 }
             }
@@ -1369,78 +1650,57 @@ impl Vgic {
     }
 
     fn emu_ipriorityr_access(&self, emu_ctx: &EmuContext) {
-        let idx = emu_ctx.reg;
-        let mut val = if emu_ctx.write { current_cpu().get_gpr(idx) } else { 0 };
-        let first_int = (8 / GIC_PRIO_BITS) * bit_extract(emu_ctx.address, 0, 9);
+        let offset = (emu_ctx.address & 0xfff) - VGICD_REG_OFFSET_IPRIORITYR;
+        let ints = vgicd_access_ints(offset, emu_ctx.width, GIC_PRIO_BITS);
         let vm = match active_vm() {
             Some(vm) => vm,
             None => {
                 panic!("emu_ipriorityr_access: current vcpu.vm is none");
             }
         };
-        let vm_id = vm.id();
-        let mut vm_has_interrupt_flag = false;
+        let vcpu = current_cpu().active_vcpu.clone().unwrap();
+        let reg = vgicd_reg_read(offset, GIC_PRIO_BITS, |int_id| self.get_prio(&vcpu, int_id));
 
         if emu_ctx.write {
-            for i in 0..emu_ctx.width {
-                if vm.has_interrupt(first_int + i) {
-                    vm_has_interrupt_flag = true;
-                    break;
-                }
-            }
-            if first_int >= 16 && !vm_has_interrupt_flag {
+            if ints.start >= 16 && !ints.clone().any(|int_id| vm.has_interrupt(int_id)) {
                 warn!(
                     "emu_ipriorityr_access: vm[{}] does not have interrupt {}",
-                    vm_id, first_int
+                    vm.id(),
+                    ints.start
                 );
                 return;
             }
-        }
-
-        if emu_ctx.write {
-            for i in 0..emu_ctx.width {
-                self.set_prio(
-                    current_cpu().active_vcpu.as_ref().unwrap(),
-                    first_int + i,
-                    bit_extract(val, GIC_PRIO_BITS * i, GIC_PRIO_BITS) as u8,
-                );
+            let reg = vgicd_lane_merge(reg, offset, emu_ctx.width, current_cpu().get_gpr(emu_ctx.reg));
+            for int_id in ints {
+                // the SPIs of other VMs sharing this register keep their priority
+                if int_id >= GIC_PRIVINT_NUM && !vm.has_interrupt(int_id) {
+                    continue;
+                }
+                self.set_prio(&vcpu, int_id, vgicd_reg_field(reg, offset, int_id, GIC_PRIO_BITS) as u8);
             }
         } else {
-            for i in 0..emu_ctx.width {
-                val |= (self.get_prio(current_cpu().active_vcpu.as_ref().unwrap(), first_int + i) as usize)
-                    << (GIC_PRIO_BITS * i);
-            }
-            let idx = emu_ctx.reg;
-            current_cpu().set_gpr(idx, val);
+            current_cpu().set_gpr(emu_ctx.reg, vgicd_lane_extract(reg, offset, emu_ctx.width));
         }
     }
 
+    // the targets are kept as the vcpu masks the guest sees
     fn emu_itargetr_access(&self, emu_ctx: &EmuContext) {
-        let idx = emu_ctx.reg;
-        let mut val = if emu_ctx.write { current_cpu().get_gpr(idx) } else { 0 };
-        let first_int = (8 / GIC_TARGET_BITS) * bit_extract(emu_ctx.address, 0, 9);
+        let offset = (emu_ctx.address & 0xfff) - VGICD_REG_OFFSET_ITARGETSR;
+        let ints = vgicd_access_ints(offset, emu_ctx.width, GIC_TARGET_BITS);
+        let vcpu = current_cpu().active_vcpu.clone().unwrap();
+        let reg = vgicd_reg_read(offset, GIC_TARGET_BITS, |int_id| self.get_trgt(&vcpu, int_id));
 
         if emu_ctx.write {
-            // println!("write");
-            val = vgic_target_translate(&active_vm().unwrap(), val as u32, true) as usize;
-            for i in 0..emu_ctx.width {
+            let reg = vgicd_lane_merge(reg, offset, emu_ctx.width, current_cpu().get_gpr(emu_ctx.reg));
+            for int_id in ints {
                 self.set_trgt(
-                    current_cpu().active_vcpu.as_ref().unwrap(),
-                    first_int + i,
-                    bit_extract(val, GIC_TARGET_BITS * i, GIC_TARGET_BITS) as u8,
+                    &vcpu,
+                    int_id,
+                    vgicd_reg_field(reg, offset, int_id, GIC_TARGET_BITS) as u8,
                 );
             }
         } else {
-            // println!("read, first_int {}, width {}", first_int, emu_ctx.width);
-            for i in 0..emu_ctx.width {
-                // println!("{}", self.get_trgt(active_vcpu().unwrap(), first_int + i));
-                val |= (self.get_trgt(current_cpu().active_vcpu.as_ref().unwrap(), first_int + i) as usize)
-                    << (GIC_TARGET_BITS * i);
-            }
-            // println!("after read val {}", val);
-            val = vgic_target_translate(&active_vm().unwrap(), val as u32, false) as usize;
-            let idx = emu_ctx.reg;
-            current_cpu().set_gpr(idx, val);
+            current_cpu().set_gpr(emu_ctx.reg, vgicd_lane_extract(reg, offset, emu_ctx.width));
         }
     }
 
@@ -1468,12 +1728,21 @@ impl Vgic {
                 Some(interrupt) => {
                     let interrupt_lock = interrupt.lock.lock();
                     interrupt.clear_lr();
+                    let mut moved = false;
                     if (interrupt.id() as usize) < GIC_SGIS_NUM {
+                        hw_test_sgi_eoi(vcpu, interrupt.id() as usize);
                         self.add_lr(vcpu, interrupt);
                     } else {
+                        moved = interrupt.locked_helper(|int| core::mem::take(&mut int.eoi_trap));
+                        if moved {
+                            GICD.set_state(interrupt.id() as usize, IrqState::Inactive);
+                        }
                         vgic_int_yield_owner(vcpu, interrupt);
                     }
                     drop(interrupt_lock);
+                    if moved {
+                        interrupt_vm_transfer_eoi(interrupt.id() as usize);
+                    }
                     // println!("handle_trapped_eoir: Core {} finish", current_cpu().id);
                 }
                 None => {
@@ -1506,22 +1775,13 @@ impl Vgic {
         ) {
             let mut interrupt_opt = None;
             let mut prev_pend = false;
-            let act_head = self.int_list_head(vcpu, false);
-            let pend_head = self.int_list_head(vcpu, true);
+            // the heads may already sit in a list register, take the first one that does not
             if has_pending {
-                if let Some(act_int) = act_head {
-                    if !act_int.in_lr() {
-                        interrupt_opt = Some(act_int);
-                    }
-                }
+                interrupt_opt = self.int_list_first_queued(vcpu, false);
             }
             if interrupt_opt.is_none() {
-                if let Some(pend_int) = pend_head {
-                    if !pend_int.in_lr() {
-                        interrupt_opt = Some(pend_int);
-                        prev_pend = true;
-                    }
-                }
+                interrupt_opt = self.int_list_first_queued(vcpu, true);
+                prev_pend = interrupt_opt.is_some();
             }
 
             match interrupt_opt {
@@ -1533,12 +1793,11 @@ impl Vgic {
                 }
                 None => {
                     // println!("no int to refill");
-                    let hcr = GICH.hcr();
-                    GICH.set_hcr(hcr & !(1 << 3));
                     break;
                 }
             }
         }
+        self.update_lr_maint(vcpu);
         // println!("end refill lrs");
     }
 
@@ -1560,29 +1819,6 @@ impl Vgic {
     }
 }
 
-fn vgic_target_translate(vm: &Vm, trgt: u32, v2p: bool) -> u32 {
-    let from = trgt.to_le_bytes();
-
-    let mut result = 0;
-    for (idx, val) in from
-        .map(|x| {
-            if v2p {
-                vm.vcpu_to_pcpu_mask(x as usize, 8) as u32
-            } else {
-                vm.pcpu_to_vcpu_mask(x as usize, 8) as u32
-            }
-        })
-        .iter()
-        .enumerate()
-    {
-        result |= *val << (8 * idx);
-        if idx >= 4 {
-            panic!("illegal idx, from len {}", from.len());
-        }
-    }
-    result
-}
-
 fn vgic_owns(vcpu: &Vcpu, interrupt: &VgicInt) -> bool {
     if gic_is_priv(interrupt.id() as usize) {
         return true;
@@ -1659,6 +1895,37 @@ fn vgic_int_yield_owner(vcpu: &Vcpu, interrupt: &VgicInt) {
     }
 }
 
+/* An ipi a vgic operation sends to other cores. It is sent after `interrupt.lock` is released,
+ * the core it goes to may be spinning on the same lock in its own ipi handler.
+ */
+enum VgicIpi {
+    // to the core of the vcpu owning the interrupt
+    Owner(usize, IpiInitcMessage),
+    // to the core of the target vcpu taking the interrupt, handled at once if it is this core
+    Route(usize, IpiInitcMessage),
+}
+
+fn vgic_send_ipi(vcpu: &Vcpu, ipi: Option<VgicIpi>, func: &str) {
+    match ipi {
+        Some(VgicIpi::Owner(phys_id, msg)) => {
+            if ipi_send_msg(phys_id, IpiType::Intc, IpiInnerMsg::Initc(msg)).is_err() {
+                error!("{}: Failed to send ipi message, target {} type {}", func, phys_id, 0);
+            }
+        }
+        Some(VgicIpi::Route(phys_id, msg)) => {
+            if phys_id == current_cpu().id {
+                vgic_ipi_handler(IpiMessage {
+                    ipi_type: IpiType::Intc,
+                    ipi_message: IpiInnerMsg::Initc(msg),
+                });
+            } else if ipi_send_msg(phys_id, IpiType::Intc, IpiInnerMsg::Initc(msg)).is_err() {
+                error!("{}: Failed to send ipi message, target {} type {}", func, phys_id, 0);
+            }
+        }
+        None => {}
+    }
+}
+
 fn vgic_int_is_hw(interrupt: &VgicInt) -> bool {
     interrupt.id() as usize >= GIC_SGIS_NUM && interrupt.hw()
 }
@@ -1706,6 +1973,159 @@ fn vgic_int_get_owner(vcpu: Vcpu, interrupt: &VgicInt) -> bool {
     // return false;
 }
 
+// where an interrupt of `prio` goes in a list sorted by priority, after the ones of the same priority
+fn queue_insert_pos(prios: impl Iterator<Item = u8>, prio: u8) -> usize {
+    prios.take_while(|&p| p <= prio).count()
+}
+
+/* The list register to spill for an interrupt of `int_prio`, out of the (state, priority) of each
+ * of them when all are in use. Only an interrupt of a lower priority is spilled, the others wait in
+ * the pending list.
+ */
+fn lr_spill_victim(lrs: impl Iterator<Item = (IrqState, u32)>, int_prio: u32) -> Option<usize> {
+    let mut pend_found = 0;
+    let mut act_found = 0;
+    let mut min_prio_act = int_prio;
+    let mut min_prio_pend = int_prio;
+    let mut act_ind = None;
+    let mut pend_ind = None;
+
+    for (i, (lr_state, lr_prio)) in lrs.enumerate() {
+        if lr_state.is_active() {
+            if lr_prio > min_prio_act {
+                min_prio_act = lr_prio;
+                act_ind = Some(i);
+            }
+            act_found += 1;
+        } else if lr_state.is_pend() {
+            if lr_prio > min_prio_pend {
+                min_prio_pend = lr_prio;
+                pend_ind = Some(i);
+            }
+            pend_found += 1;
+        }
+    }
+
+    if pend_found > 1 {
+        pend_ind
+    } else if act_found > 1 {
+        act_ind
+    } else {
+        None
+    }
+}
+
+// the GICH_HCR maintenance enables while pending interrupts are `queued` out of the list registers
+fn lr_maint_bits(queued: bool, lrs_full: bool) -> u32 {
+    match (queued, lrs_full) {
+        (false, _) => 0,
+        (true, true) => GICH_HCR_UIE_BIT as u32,
+        (true, false) => GICH_HCR_NPIE_BIT as u32,
+    }
+}
+
+/* Distinct SPIs of (priority, hw) injected into one vcpu with `lrs` list registers, through the
+ * spill, queue and maintenance choices of `Vgic`. The guest takes and completes the highest priority
+ * pending one after every few injections, then all of them that are left.
+ * Returns the SPIs in the order the guest took them, the LR overflows, and the SPIs never taken.
+ */
+#[cfg(feature = "self-test")]
+pub fn vgic_lr_stress_synthetic(ints: &[(u8, bool)], lrs: usize) -> (Vec<usize>, usize, usize) {
+    struct Model {
+        // (spi, priority, hw) in each list register, all of them pending
+        lrs: Vec<Option<(usize, u8, bool)>>,
+        pend_list: VecDeque<(usize, u8, bool)>,
+        hcr: u32,
+        overflow: usize,
+        taken: Vec<usize>,
+    }
+
+    impl Model {
+        fn lr_prio(prio: u8) -> u32 {
+            (prio as u32 >> 3) & 0b11111
+        }
+
+        fn valid(&self) -> usize {
+            self.lrs.iter().filter(|lr| lr.is_some()).count()
+        }
+
+        fn queue(&mut self, int: (usize, u8, bool)) {
+            let idx = queue_insert_pos(self.pend_list.iter().map(|&(_, prio, _)| prio), int.1);
+            self.pend_list.insert(idx, int);
+        }
+
+        fn update_maint(&mut self) {
+            let lrs_full = self.valid() == self.lrs.len();
+            self.hcr = lr_maint_bits(!self.pend_list.is_empty(), lrs_full);
+        }
+
+        fn inject(&mut self, int: (usize, u8, bool)) {
+            let lr_ind = match self.lrs.iter().position(|lr| lr.is_none()) {
+                Some(idx) => Some(idx),
+                None => {
+                    self.overflow += 1;
+                    let lrs = self.lrs.iter().map(|lr| (IrqState::Pend, Self::lr_prio(lr.unwrap().1)));
+                    let victim = lr_spill_victim(lrs, Self::lr_prio(int.1));
+                    if let Some(idx) = victim {
+                        let spilled = self.lrs[idx].take().unwrap();
+                        self.queue(spilled);
+                    }
+                    victim
+                }
+            };
+            match lr_ind {
+                Some(idx) => self.lrs[idx] = Some(int),
+                None => self.queue(int),
+            }
+            self.update_maint();
+        }
+
+        fn refill(&mut self) {
+            for lr in self.lrs.iter_mut().filter(|lr| lr.is_none()) {
+                *lr = self.pend_list.pop_front();
+            }
+            self.update_maint();
+        }
+
+        // the guest acks and EOIs one SPI, false if no list register holds one
+        fn guest_take(&mut self) -> bool {
+            let highest = (0..self.lrs.len())
+                .filter(|&idx| self.lrs[idx].is_some())
+                .min_by_key(|&idx| Self::lr_prio(self.lrs[idx].unwrap().1));
+            let (spi, _, hw) = match highest {
+                Some(idx) => self.lrs[idx].take().unwrap(),
+                None => return false,
+            };
+            self.taken.push(spi);
+            // a virtual SPI asks for the EOI maintenance, a hw one only gets underflow or no pending
+            let valid = self.valid();
+            let underflow = self.hcr & GICH_HCR_UIE_BIT as u32 != 0 && valid <= 1;
+            let no_pend = self.hcr & GICH_HCR_NPIE_BIT as u32 != 0 && valid == 0;
+            if !hw || underflow || no_pend {
+                self.refill();
+            }
+            true
+        }
+    }
+
+    let mut model = Model {
+        lrs: vec![None; lrs],
+        pend_list: VecDeque::new(),
+        hcr: 0,
+        overflow: 0,
+        taken: Vec::new(),
+    };
+    for (spi, &(prio, hw)) in ints.iter().enumerate() {
+        model.inject((spi, prio, hw));
+        if spi % 3 == 2 {
+            model.guest_take();
+        }
+    }
+    while model.guest_take() {}
+    let left = model.pend_list.len() + model.valid();
+    (model.taken, model.overflow, left)
+}
+
 pub fn gic_maintenance_handler() {
     let misr = GICH.misr();
     let vm = match active_vm() {
@@ -1716,18 +2136,16 @@ pub fn gic_maintenance_handler() {
     };
     let vgic = vm.vgic();
 
+    // LRs of hardware interrupts deactivated by the guest
+    vgic.reconcile_hw_lrs(current_cpu().active_vcpu.as_ref().unwrap());
+
     // End Of Interrupt
-    if misr & 1 != 0 {
+    if misr & GICH_MISR_EOI_BIT as u32 != 0 {
         vgic.handle_trapped_eoir(current_cpu().active_vcpu.as_ref().unwrap());
     }
 
-    // No Pending
-    if misr & (1 << 3) != 0 {
-        vgic.refill_lrs(current_cpu().active_vcpu.as_ref().unwrap());
-    }
-
     // List Register Entry Not Present
-    if misr & (1 << 2) != 0 {
+    if misr & GICH_MISR_LRENP_BIT as u32 != 0 {
         // println!("in gic_maintenance_handler eoir_highest_spilled_active");
         let mut hcr = GICH.hcr();
         while hcr & (0b11111 << 27) != 0 {
@@ -1738,6 +2156,11 @@ pub fn gic_maintenance_handler() {
         }
         // println!("end gic_maintenance_handler eoir_highest_spilled_active");
     }
+
+    // No Pending, Underflow, or list registers freed by an EOI: move the queued interrupts in
+    if misr & (GICH_MISR_EOI_BIT | GICH_MISR_U_BIT | GICH_MISR_NP_BIT) as u32 != 0 {
+        vgic.refill_lrs(current_cpu().active_vcpu.as_ref().unwrap());
+    }
 }
 
 const VGICD_REG_OFFSET_PREFIX_CTLR: usize = 0x0;
@@ -1750,6 +2173,49 @@ const VGICD_REG_OFFSET_PREFIX_ISACTIVER: usize = 0x6;
 const VGICD_REG_OFFSET_PREFIX_ICACTIVER: usize = 0x7;
 const VGICD_REG_OFFSET_PREFIX_ICFGR: usize = 0x18;
 const VGICD_REG_OFFSET_PREFIX_SGIR: usize = 0x1e;
+// the register files accessed by byte, halfword or word
+const VGICD_REG_OFFSET_IPRIORITYR: usize = 0x400;
+const VGICD_REG_OFFSET_ITARGETSR: usize = 0x800;
+const VGICD_REG_OFFSET_ICFGR: usize = 0xc00;
+
+/* The interrupts in the lanes of a 1, 2 or 4 byte access at `offset` into a register file of `bits`
+ * per interrupt. The field of the first one is in the low bits of the guest register.
+ */
+pub fn vgicd_access_ints(offset: usize, width: usize, bits: usize) -> Range<usize> {
+    let first = offset * 8 / bits;
+    first..first + width * 8 / bits
+}
+
+// a narrow write at `offset` merged into the 32-bit register holding it
+pub fn vgicd_lane_merge(reg: u32, offset: usize, width: usize, val: usize) -> u32 {
+    let shift = (offset & 0x3) * 8;
+    let mask = (((1_u64 << (width * 8)) - 1) << shift) as u32;
+    (reg & !mask) | (((val as u64) << shift) as u32 & mask)
+}
+
+// the lanes of a narrow read at `offset` out of the 32-bit register holding it
+pub fn vgicd_lane_extract(reg: u32, offset: usize, width: usize) -> usize {
+    let shift = (offset & 0x3) * 8;
+    ((reg as u64 >> shift) & ((1_u64 << (width * 8)) - 1)) as usize
+}
+
+// the 32-bit register holding `offset`, from the field of each of its interrupts
+fn vgicd_reg_read(offset: usize, bits: usize, get: impl Fn(usize) -> u8) -> u32 {
+    vgicd_access_ints(offset & !0x3, 4, bits)
+        .enumerate()
+        .fold(0, |reg, (i, int_id)| reg | (get(int_id) as u32) << (i * bits))
+}
+
+// the field of `int_id` in the 32-bit register holding `offset`
+fn vgicd_reg_field(reg: u32, offset: usize, int_id: usize, bits: usize) -> usize {
+    let first = vgicd_access_ints(offset & !0x3, 4, bits).start;
+    bit_extract(reg as usize, (int_id - first) * bits, bits)
+}
+
+// the priority, target and config registers take naturally aligned 1, 2 and 4 byte accesses
+fn vgicd_access_aligned(emu_ctx: &EmuContext) -> bool {
+    emu_ctx.address & (emu_ctx.width - 1) == 0
+}
 
 pub fn vgicd_emu_access_is_vaild(emu_ctx: &EmuContext) -> bool {
     let offset = emu_ctx.address & 0xfff;
@@ -1761,12 +2227,16 @@ pub fn vgicd_emu_access_is_vaild(emu_ctx: &EmuContext) -> bool {
         | VGICD_REG_OFFSET_PREFIX_ISACTIVER
         | VGICD_REG_OFFSET_PREFIX_ICENABLER
         | VGICD_REG_OFFSET_PREFIX_ICPENDR
-        | VGICD_REG_OFFSET_PREFIX_ICACTIVER
-        | VGICD_REG_OFFSET_PREFIX_ICFGR => {
+        | VGICD_REG_OFFSET_PREFIX_ICACTIVER => {
             if emu_ctx.width != 4 || emu_ctx.address & 0x3 != 0 {
                 return false;
             }
         }
+        VGICD_REG_OFFSET_PREFIX_ICFGR => {
+            if !vgicd_access_aligned(emu_ctx) {
+                return false;
+            }
+        }
         VGICD_REG_OFFSET_PREFIX_SGIR => {
             if (emu_ctx.width == 4 && emu_ctx.address & 0x3 != 0) || (emu_ctx.width == 2 && emu_ctx.address & 0x1 != 0)
             {
@@ -1774,10 +2244,7 @@ pub fn vgicd_emu_access_is_vaild(emu_ctx: &EmuContext) -> bool {
             }
         }
         _ => {
-            // TODO: hard code to rebuild (gicd IPRIORITYR and ITARGETSR)
-            if (0x400..0xc00).contains(&offset)
-                && ((emu_ctx.width == 4 && emu_ctx.address & 0x3 != 0)
-                    || (emu_ctx.width == 2 && emu_ctx.address & 0x1 != 0))
+            if (VGICD_REG_OFFSET_IPRIORITYR..VGICD_REG_OFFSET_ICFGR).contains(&offset) && !vgicd_access_aligned(emu_ctx)
             {
                 return false;
             }
@@ -1837,95 +2304,135 @@ cfg_if::cfg_if! {
 }
 
 pub fn vgic_ipi_handler(msg: IpiMessage) {
+    let _tag = HeapTagGuard::new(HeapTag::Vgic);
     if let IpiInnerMsg::Initc(intc) = msg.ipi_message {
-        let vm_id = intc.vm_id;
-        let int_id = intc.int_id;
-        let val = intc.val;
-        let trgt_vcpu = match current_cpu().vcpu_array.pop_vcpu_through_vmid(vm_id) {
-            None => {
-                error!("Core {} received vgic msg from unknown VM {}", current_cpu().id, vm_id);
-                return;
-            }
-            Some(vcpu) => vcpu,
+        let vcpu_array = &current_cpu().vcpu_array;
+        let trgt_vcpus: Vec<Vcpu> = match (intc.vcpu_id, intc.event) {
+            (Some(vcpu_id), _) => vcpu_array
+                .vcpu_by_id(intc.vm_id, vcpu_id)
+                .cloned()
+                .into_iter()
+                .collect(),
+            // every vcpu of the VM on this core has its own GICH state
+            (None, InitcEvent::GichEn) => vcpu_array
+                .iter()
+                .filter(|vcpu| vcpu.vm_id() == intc.vm_id)
+                .cloned()
+                .collect(),
+            // the other events name the vcpu they are for
+            (None, _) => Vec::new(),
         };
-        // restore_vcpu_gic
-        if let Some(active_vcpu) = &current_cpu().active_vcpu {
-            if trgt_vcpu != active_vcpu {
-                active_vcpu.intc_save_context();
-                trgt_vcpu.intc_restore_context();
-            }
-        } else {
-            trgt_vcpu.intc_restore_context();
+        if trgt_vcpus.is_empty() {
+            error!(
+                "Core {} received vgic msg for unknown VM {} vcpu {:?}",
+                current_cpu().id,
+                intc.vm_id,
+                intc.vcpu_id
+            );
+            return;
+        }
+        for trgt_vcpu in trgt_vcpus.iter() {
+            vgic_ipi_handle_vcpu(&intc, trgt_vcpu);
         }
+    } else {
+        error!("vgic_ipi_handler: illegal ipi");
+    }
+}
 
-        let vm = match trgt_vcpu.vm() {
-            None => {
-                panic!("vgic_ipi_handler: vm is None");
-            }
-            Some(x) => x,
-        };
-        let vgic = vm.vgic();
+fn vgic_ipi_handle_vcpu(intc: &IpiInitcMessage, trgt_vcpu: &Vcpu) {
+    let vm_id = intc.vm_id;
+    let int_id = intc.int_id;
+    let val = intc.val;
+    // restore_vcpu_gic
+    if let Some(active_vcpu) = &current_cpu().active_vcpu {
+        if trgt_vcpu != active_vcpu {
+            active_vcpu.intc_save_context();
+            trgt_vcpu.intc_restore_context();
+        }
+    } else {
+        trgt_vcpu.intc_restore_context();
+    }
 
-        if vm_id != vm.id() {
-            error!("VM {} received vgic msg from another vm {}", vm.id(), vm_id);
-            return;
+    let vm = match trgt_vcpu.vm() {
+        None => {
+            panic!("vgic_ipi_handler: vm is None");
         }
-        // println!(
-        //     "vgic_ipi_handler: core {} receive vgic_ipi, event {:?}, vm_id {}, int_id {}, val {:#x}",
-        //     current_cpu().id,
-        //     intc.event,
-        //     vm_id,
-        //     int_id,
-        //     val
-        // );
-        match intc.event {
-            InitcEvent::GichEn => {
-                let hcr = GICH.hcr();
-                if val != 0 {
-                    GICH.set_hcr(hcr | 0b1);
-                } else {
-                    GICH.set_hcr(hcr & !0b1);
-                }
-            }
-            InitcEvent::SetEn => {
-                vgic.set_enable(trgt_vcpu, int_id as usize, val != 0);
-            }
-            InitcEvent::SetPend => {
-                vgic.set_pend(trgt_vcpu, int_id as usize, val != 0);
-            }
-            InitcEvent::SetPrio => {
-                vgic.set_prio(trgt_vcpu, int_id as usize, val);
-            }
-            InitcEvent::SetTrgt => {
-                vgic.set_trgt(trgt_vcpu, int_id as usize, val);
+        Some(x) => x,
+    };
+    let vgic = vm.vgic();
+
+    if vm_id != vm.id() {
+        error!("VM {} received vgic msg from another vm {}", vm.id(), vm_id);
+        return;
+    }
+    // println!(
+    //     "vgic_ipi_handler: core {} receive vgic_ipi, event {:?}, vm_id {}, int_id {}, val {:#x}",
+    //     current_cpu().id,
+    //     intc.event,
+    //     vm_id,
+    //     int_id,
+    //     val
+    // );
+    match intc.event {
+        InitcEvent::GichEn => {
+            let hcr = GICH.hcr();
+            if val != 0 {
+                GICH.set_hcr(hcr | 0b1);
+            } else {
+                GICH.set_hcr(hcr & !0b1);
             }
-            InitcEvent::Route => {
-                if let Some(interrupt) = vgic.get_int(trgt_vcpu, bit_extract(int_id as usize, 0, 10)) {
-                    let interrupt_lock = interrupt.lock.lock();
-                    if vgic_int_get_owner(trgt_vcpu.clone(), interrupt) {
-                        if (interrupt.targets() & (1 << current_cpu().id)) != 0 {
-                            vgic.add_lr(trgt_vcpu, interrupt);
-                        }
-                        vgic_int_yield_owner(trgt_vcpu, interrupt);
+        }
+        InitcEvent::SetEn => {
+            vgic.set_enable(trgt_vcpu, int_id as usize, val != 0);
+        }
+        InitcEvent::SetPend => {
+            vgic.set_pend(trgt_vcpu, int_id as usize, val != 0);
+        }
+        InitcEvent::SetPrio => {
+            vgic.set_prio(trgt_vcpu, int_id as usize, val);
+        }
+        InitcEvent::SetTrgt => {
+            vgic.set_trgt(trgt_vcpu, int_id as usize, val);
+        }
+        InitcEvent::SetCfg => {
+            vgic.set_icfgr(trgt_vcpu, int_id as usize, val);
+        }
+        InitcEvent::Release => {
+            vgic.release_hw_int(trgt_vcpu, int_id as usize);
+        }
+        InitcEvent::Route => {
+            if let Some(interrupt) = vgic.get_int(trgt_vcpu, bit_extract(int_id as usize, 0, 10)) {
+                let interrupt_lock = interrupt.lock.lock();
+                if vgic_int_get_owner(trgt_vcpu.clone(), interrupt) {
+                    if (interrupt.targets() & (1 << trgt_vcpu.id())) != 0 {
+                        vgic.add_lr(trgt_vcpu, interrupt);
                     }
-                    drop(interrupt_lock);
+                    vgic_int_yield_owner(trgt_vcpu, interrupt);
                 }
-            }
-            _ => {
-                error!("vgic_ipi_handler: core {} received unknown event", current_cpu().id)
+                drop(interrupt_lock);
             }
         }
-        // save_vcpu_gic
-        if let Some(active_vcpu) = &current_cpu().active_vcpu {
-            if trgt_vcpu != active_vcpu {
-                trgt_vcpu.intc_save_context();
-                active_vcpu.intc_restore_context();
-            }
-        } else {
+        _ => {
+            error!("vgic_ipi_handler: core {} received unknown event", current_cpu().id)
+        }
+    }
+    // save_vcpu_gic
+    if let Some(active_vcpu) = &current_cpu().active_vcpu {
+        if trgt_vcpu != active_vcpu {
             trgt_vcpu.intc_save_context();
+            active_vcpu.intc_restore_context();
         }
     } else {
-        error!("vgic_ipi_handler: illegal ipi");
+        trgt_vcpu.intc_save_context();
+    }
+    // an int may be in its LRs now, e.g. a SGI routed here, a vcpu waiting in wfi runs to take it
+    let lr_added = match intc.event {
+        InitcEvent::SetEn | InitcEvent::Route => true,
+        InitcEvent::SetPend => val != 0,
+        _ => false,
+    };
+    if lr_added && trgt_vcpu.wfi_blocked() {
+        current_cpu().vcpu_array.wfi_wakeup(trgt_vcpu);
     }
 }
 
@@ -1938,7 +2445,22 @@ impl EmuDev for Vgic {
         self.address_range.clone()
     }
 
+    // the vcpus on other cores are reset by `psci_vm_reset_percore`
+    fn reset(&self) {
+        self.set_vgicd_ctlr(0);
+        let vm_id = match current_cpu().active_vcpu.as_ref() {
+            Some(vcpu) => vcpu.vm_id(),
+            None => return,
+        };
+        for vcpu in current_cpu().vcpu_array.iter() {
+            if vcpu.vm_id() == vm_id {
+                self.vcpu_reset_ints(vcpu);
+            }
+        }
+    }
+
     fn handler(&self, emu_ctx: &EmuContext) -> bool {
+        let _tag = HeapTagGuard::new(HeapTag::Vgic);
         let offset = emu_ctx.address & 0xfff;
         if emu_ctx.width > 4 {
             return false;
@@ -1993,9 +2515,9 @@ impl EmuDev for Vgic {
                         }
                     }
                 }
-                if (0x400..0x800).contains(&offset) {
+                if (VGICD_REG_OFFSET_IPRIORITYR..VGICD_REG_OFFSET_ITARGETSR).contains(&offset) {
                     self.emu_ipriorityr_access(emu_ctx);
-                } else if (0x800..0xc00).contains(&offset) {
+                } else if (VGICD_REG_OFFSET_ITARGETSR..VGICD_REG_OFFSET_ICFGR).contains(&offset) {
                     self.emu_itargetr_access(emu_ctx);
                 }
             }
@@ -2017,13 +2539,12 @@ pub fn emu_intc_init(emu_cfg: &VmEmulatedDeviceConfig, vcpu_list: &[Vcpu]) -> Re
     }
 
     for vcpu in vcpu_list {
-        let phys_id = vcpu.phys_id();
         let mut cpu_priv = VgicCpuPriv::default();
         for int_idx in 0..GIC_PRIVINT_NUM {
             cpu_priv.interrupts.push(VgicInt::priv_new(
                 int_idx,
                 vcpu.clone(),
-                1 << phys_id,
+                1 << vcpu.id(),
                 int_idx < GIC_SGIS_NUM,
             ));
         }
@@ -2033,6 +2554,22 @@ pub fn emu_intc_init(emu_cfg: &VmEmulatedDeviceConfig, vcpu_list: &[Vcpu]) -> Re
     Ok(Arc::new(vgic))
 }
 
+// the GICD_ITARGETSR of a passthrough SPI targeting the vcpu mask `trgt` of `vm`
+fn vgic_trgt_to_gicd(vm: &Vm, trgt: u8) -> u8 {
+    vgic_trgt_to_cpuif(vm.vcpu_to_pcpu_mask(trgt as usize, 8) as u8)
+}
+
+// translate a physical cpu mask to a GICD_ITARGETSR cpu interface mask
+fn vgic_trgt_to_cpuif(trgt: u8) -> u8 {
+    let mut ptrgt = 0;
+    for cpuid in 0..8 {
+        if bit_get(trgt as usize, cpuid) != 0 {
+            ptrgt = bit_set(ptrgt, Platform::cpuid_to_cpuif(cpuid))
+        }
+    }
+    ptrgt as u8
+}
+
 pub fn vgic_set_hw_int(vm: &Vm, int_id: usize) {
     if int_id < GIC_SGIS_NUM {
         return;
@@ -2053,3 +2590,75 @@ pub fn vgic_set_hw_int(vm: &Vm, int_id: usize) {
         interrupt.set_hw(true);
     }
 }
+
+/* Release the passthrough SPI `int_id` of `vm`, which moves to another VM.
+ * Return true if it is not in flight on any vcpu, or else the core of the vcpu owning it
+ * releases it and calls `interrupt_vm_transfer_eoi` once it is inactive.
+ */
+pub fn vgic_release_hw_int(vm: &Vm, int_id: usize) -> bool {
+    if !vm.has_vgic() || int_id < GIC_PRIVINT_NUM {
+        return true;
+    }
+    let vgic = vm.vgic();
+    let interrupt = match vgic.vgicd_interrupt(int_id - GIC_PRIVINT_NUM) {
+        Some(interrupt) => interrupt,
+        None => return true,
+    };
+    let interrupt_lock = interrupt.lock.lock();
+    let ipi = match interrupt.owner() {
+        Some(owner) => {
+            let m = IpiInitcMessage {
+                event: InitcEvent::Release,
+                vm_id: vm.id(),
+                vcpu_id: Some(owner.id()),
+                int_id: interrupt.id(),
+                val: 0,
+            };
+            Some(VgicIpi::Owner(owner.phys_id(), m))
+        }
+        None => {
+            interrupt.locked_helper(|int| int.state = IrqState::Inactive);
+            GICD.set_state(int_id, IrqState::Inactive);
+            None
+        }
+    };
+    drop(interrupt_lock);
+    match ipi {
+        Some(ipi) => {
+            vgic_send_ipi(vm.vcpu(0).unwrap(), Some(ipi), "vgic_release_hw_int");
+            false
+        }
+        None => true,
+    }
+}
+
+/* Hand the released passthrough SPI `int_id` over from `src` to `dst`: the GICD targets a core of `dst`
+ * and the interrupt is enabled again if the vgic of `dst` has it enabled.
+ */
+pub fn vgic_transfer_hw_int(src: &Vm, dst: &Vm, int_id: usize) {
+    if int_id < GIC_PRIVINT_NUM {
+        return;
+    }
+    if src.has_vgic() {
+        if let Some(interrupt) = src.vgic().vgicd_interrupt(int_id - GIC_PRIVINT_NUM) {
+            let _interrupt_lock = interrupt.lock.lock();
+            interrupt.set_hw(false);
+        }
+    }
+    if !dst.has_vgic() {
+        return;
+    }
+    if let Some(interrupt) = dst.vgic().vgicd_interrupt(int_id - GIC_PRIVINT_NUM) {
+        let _interrupt_lock = interrupt.lock.lock();
+        interrupt.set_hw(true);
+        let mut targets = interrupt.targets();
+        if targets == 0 {
+            // vcpu 0
+            targets = 1;
+            interrupt.set_targets(targets);
+        }
+        GICD.set_trgt(int_id, vgic_trgt_to_gicd(dst, targets));
+        GICD.set_prio(int_id, interrupt.prio().max(VGIC_HW_PRIO_MIN));
+        GICD.set_enable(int_id, interrupt.enabled());
+    }
+}
@@ -39,6 +39,8 @@ pub trait TlbInvalidate {
     fn invalid_hypervisor_all();
     fn invalid_guest_ipa(ipa: usize);
     fn invalid_guest_all();
+    fn invalid_hypervisor_va_range(va: usize, len: usize);
+    fn invalid_guest_ipa_range(vmid: usize, ipa: usize, len: usize);
 }
 
 pub trait CacheInvalidate {
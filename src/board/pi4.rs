@@ -71,6 +71,7 @@ pub static PLAT_DESC: PlatformConfig = PlatformConfig {
             0x100000000..0x100000000 + 0x100000000,
         ],
         base: 0xf0000000,
+        region_affinity: &[],
     },
     arch_desc: ArchDesc {
         gic_desc: GicDesc {
@@ -14,6 +14,8 @@ pub enum SchedRule {
 pub struct PlatMemoryConfig {
     pub base: usize,
     pub regions: &'static [Range<usize>],
+    // the cluster (MPIDR Aff1) each region is close to, empty if the memory is uniform
+    pub region_affinity: &'static [usize],
 }
 
 pub struct PlatCpuCoreConfig {
@@ -55,6 +57,9 @@ pub trait PlatOperation {
     const GICC_BASE: usize;
     const GICH_BASE: usize;
     const GICV_BASE: usize;
+    // GICv3 only, the first redistributor frame
+    #[cfg(feature = "gicv3")]
+    const GICR_BASE: usize;
 
     fn cpu_on(arch_core_id: usize, entry: usize, ctx: usize) {
         crate::arch::power_arch_cpu_on(arch_core_id, entry, ctx);
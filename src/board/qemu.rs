@@ -5,6 +5,10 @@ use super::platform_common::{
     ArchDesc, PlatCpuConfig, PlatCpuCoreConfig, PlatMemoryConfig, PlatOperation, PlatformConfig, SchedRule,
 };
 
+// the guests only have a GICv2 model, its GICV frame is not there with gic-version=3
+#[cfg(feature = "gicv3")]
+compile_error!("feature gicv3: QEMU gic-version=3 has no GICv2 compatible virtual cpu interface for the guests");
+
 pub struct Platform;
 
 impl PlatOperation for Platform {
@@ -21,6 +25,8 @@ impl PlatOperation for Platform {
     const GICC_BASE: usize = 0x08010000;
     const GICH_BASE: usize = 0x08030000;
     const GICV_BASE: usize = 0x08040000;
+    #[cfg(feature = "gicv3")]
+    const GICR_BASE: usize = 0x080A0000;
 
     fn cpuid_to_cpuif(cpuid: usize) -> usize {
         cpuid
@@ -72,6 +78,7 @@ pub static PLAT_DESC: PlatformConfig = PlatformConfig {
             0x50000000..0x50000000 + 0x1f0000000,
         ],
         base: 0x40000000,
+        region_affinity: &[],
     },
     arch_desc: ArchDesc {
         gic_desc: GicDesc {
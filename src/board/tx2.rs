@@ -101,6 +101,11 @@ pub static PLAT_DESC: PlatformConfig = PlatformConfig {
             0xf020_0000..0xf020_0000 + 0x1_8560_0000,
         ],
         base: 0x80000000,
+        /*
+            the two DRAM regions sit behind different memory controllers, the low one is taken
+            as close to the A57 cluster (#1) which runs the hypervisor and the guests
+        */
+        region_affinity: &[1, 0],
     },
     arch_desc: ArchDesc {
         gic_desc: GicDesc {
@@ -1,6 +1,6 @@
 use alloc::string::{String, ToString};
+use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::ffi::CStr;
 use core::ops::Range;
 use core::sync::atomic::{AtomicU32, Ordering};
 use core::time::Duration;
@@ -8,13 +8,24 @@ use core::time::Duration;
 use spin::Mutex;
 
 // use crate::board::*;
-use crate::device::{mediated_blk_free, mediated_blk_request, EmuDeviceType};
-use crate::kernel::access::{copy_between_vm, copy_segment_from_vm};
-use crate::kernel::{active_vm, vm_by_id, Vm, VmType, CONFIG_VM_NUM_MAX};
-use crate::util::{BitAlloc, BitAlloc16};
-use crate::vmm::vmm_init_gvm;
+use crate::arch::{GIC_INTS_MAX, GIC_PRIVINT_NUM, PAGE_SIZE, PTE_S2_DEVICE, PTE_S2_NORMAL, VM_IPA_SIZE};
+use crate::board::{PlatOperation, Platform};
+use crate::device::{emu_virtio_mmio_init, mediated_blk_free, mediated_blk_request, EmuDeviceType, VirtioMmio};
+use crate::kernel::access::{copy_between_vm, copy_cstr_from_vm, copy_segment_from_vm, vm_ipa2hva};
+use crate::kernel::timer::TIMER_SLICE;
+use crate::kernel::{
+    active_vm, async_ipi_req, hvc_send_msg_to_vm, iommu_claim_streams, iommu_release_streams, ivc_ipa_overlap,
+    vm_by_id, vm_if_boot_state, vm_if_upload, vm_if_upload_done, vm_if_upload_fail, vm_if_upload_queue,
+    vm_if_upload_start, AsyncCallback, AsyncTask, HvcGuestMsg, HvcManageMsg, Vm, VmBootState, VmType, ASYNC_UPLOAD_ID,
+    CONFIG_VM_NUM_MAX, EXECUTOR, HVC_CONFIG, HVC_CONFIG_MEMORY_REGION, SCHED_SLICE_MAX_US, SCHED_SLICE_MIN_US,
+    SCHED_WEIGHT_DEFAULT,
+};
+use crate::util::{bit_extract, round_up, BitAlloc, BitAlloc16};
+use crate::vmm::{vmm_add_memory_region, vmm_init_gvm, vmm_load_uploaded_image, vmm_setup_fdt};
 
 const CFG_MAX_NUM: usize = 0x10;
+// where an emulated device added with base_ipa 0 is placed, below the memory of the guests
+const EMU_DEV_AUTO_IPA: Range<usize> = 0x0a00_0000..0x4000_0000;
 // const IRQ_MAX_NUM: usize = 0x40;
 // const PASSTHROUGH_DEV_MAX_NUM: usize = 128;
 // const EMULATED_DEV_MAX_NUM: usize = 16;
@@ -37,6 +48,58 @@ impl From<usize> for DtbDevType {
     }
 }
 
+// what the hypervisor does with an SMC it does not emulate
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum SmcPolicy {
+    #[default]
+    Deny = 0,
+    // record the call in the smc log of the VM and return NOT_SUPPORTED
+    LogDeny = 1,
+    // forward the calls in the allowlist to EL3, log and deny the others
+    Passthrough = 2,
+}
+
+impl From<usize> for SmcPolicy {
+    fn from(value: usize) -> Self {
+        match value {
+            0 => Self::Deny,
+            1 => Self::LogDeny,
+            2 => Self::Passthrough,
+            _ => panic!("Unknown SmcPolicy value: {}", value),
+        }
+    }
+}
+
+const SMC_ALLOWLIST_MAX: usize = 16;
+
+#[derive(Clone, Default)]
+pub struct VmSmcConfig {
+    pub policy: SmcPolicy,
+    // inclusive function id ranges forwarded with SmcPolicy::Passthrough
+    pub allowlist: Vec<(u32, u32)>,
+}
+
+impl VmSmcConfig {
+    pub fn allows(&self, fid: u32) -> bool {
+        self.policy == SmcPolicy::Passthrough
+            && self.allowlist.iter().any(|(start, end)| (*start..=*end).contains(&fid))
+    }
+
+    /* A range must stay inside one service owner (fid bits 31..24), and standard secure services
+     * (PSCI) are never forwarded as they would act on the physical cpus.
+     */
+    pub(super) fn add_range(&mut self, start: usize, end: usize) -> Result<(), ()> {
+        if start > end || end > u32::MAX as usize || start >> 24 != end >> 24 || (start >> 24) & 0x3f == 4 {
+            return Err(());
+        }
+        if self.allowlist.len() >= SMC_ALLOWLIST_MAX {
+            return Err(());
+        }
+        self.allowlist.push((start as u32, end as u32));
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct VmEmulatedDeviceConfig {
     pub name: String,
@@ -48,6 +111,16 @@ pub struct VmEmulatedDeviceConfig {
     pub mediated: bool,
 }
 
+impl VmEmulatedDeviceConfig {
+    // the irq of a pl011 is written to the device tree of the guest as an SPI, it must be one
+    pub fn irq_valid(&self) -> bool {
+        match self.emu_type {
+            EmuDeviceType::EmuDeviceTPl011 => (GIC_PRIVINT_NUM..GIC_INTS_MAX).contains(&self.irq_id),
+            _ => true,
+        }
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct VmEmulatedDeviceConfigList {
     pub emu_dev_list: Vec<VmEmulatedDeviceConfig>,
@@ -61,6 +134,15 @@ pub struct PassthroughRegion {
     pub dev_property: bool,
 }
 
+impl PassthroughRegion {
+    // the physical uarts of the board inside the region
+    pub fn uarts(&self) -> impl Iterator<Item = usize> + '_ {
+        [Platform::UART_0_ADDR, Platform::UART_1_ADDR, Platform::UART_2_ADDR]
+            .into_iter()
+            .filter(move |&uart| uart != usize::MAX && (self.pa..self.pa + self.length).contains(&uart))
+    }
+}
+
 #[derive(Default, Clone)]
 pub struct VmPassthroughDeviceConfig {
     pub regions: Vec<PassthroughRegion>,
@@ -94,6 +176,12 @@ pub struct VmMemoryConfig {
     pub colors: Vec<usize>,
     pub budget: u32,
     pub period: Duration,
+    // fail to init the vm if it shares cache colors with others
+    pub strict_colors: bool,
+    // upper bound of the total memory size when growing a created vm, 0 forbids growing
+    pub max_size: usize,
+    // map all of the memory in stage-2 before boot, instead of each 2MB block on its first access
+    pub eager_map: bool,
 }
 
 impl Default for VmMemoryConfig {
@@ -103,6 +191,9 @@ impl Default for VmMemoryConfig {
             colors: Default::default(),
             budget: DEFAULT_MEMORY_BUDGET,
             period: DEFAULT_MEMORY_REPLENISHMENT_PERIOD,
+            strict_colors: false,
+            max_size: 0,
+            eager_map: false,
         }
     }
 }
@@ -128,8 +219,11 @@ pub struct VmImageConfig {
     pub kernel_entry_point: usize,
     // pub device_tree_filename: Option<&'static str>,
     pub device_tree_load_ipa: usize,
-    // pub ramdisk_filename: Option<&'static str>,
+    // the ramdisk built in with RAMDISK_PATH, see `vmm_init_image`
+    pub ramdisk_filename: Option<&'static str>,
     pub ramdisk_load_ipa: usize,
+    // where a bma guest finds its boot info when device_tree_load_ipa is 0
+    pub boot_info_load_ipa: usize,
 }
 
 impl VmImageConfig {
@@ -140,35 +234,66 @@ impl VmImageConfig {
             kernel_entry_point: kernel_load_ipa,
             // device_tree_filename: None,
             device_tree_load_ipa,
-            // ramdisk_filename: None,
+            ramdisk_filename: None,
             ramdisk_load_ipa,
+            boot_info_load_ipa: 0,
         }
     }
 }
 
+// how the guest brings up its secondary vcpus
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SmpBoot {
+    #[default]
+    Psci,
+    // the guest writes the entry of vcpu n to the n-th u64 of the release page at this ipa
+    SpinTable(usize),
+}
+
+/* `allocate_bitmap` is the set of physical cores the vcpus may run on, and up to `vcpus_per_core` vcpus
+ * share each of them, so `num` can be larger than popcount(allocate_bitmap) for an over-subscribed VM.
+ * The vcpus are spread round-robin over the cores, starting with the master.
+ */
 #[derive(Clone, Default)]
 pub struct VmCpuConfig {
     pub num: usize,
     pub allocate_bitmap: usize,
     pub master: Option<usize>,
+    // vcpus of a real-time VM always run before the best-effort ones on their core
+    pub sched_rt: bool,
+    // time slice of a best-effort vcpu in percent of TIMER_SLICE, 0 means SCHED_WEIGHT_DEFAULT
+    pub sched_weight: usize,
+    // time slice of a vcpu in us, overrides the weight if it is not 0
+    pub sched_slice_us: usize,
+    // 0 is taken as 1, a vcpu per core
+    pub vcpus_per_core: usize,
+    pub smp_boot: SmpBoot,
 }
 
 impl VmCpuConfig {
-    fn new(num: usize, allocate_bitmap: usize, master: usize) -> Self {
-        let num = usize::min(num, allocate_bitmap.count_ones() as usize);
+    pub fn new(num: usize, allocate_bitmap: usize, master: usize) -> Self {
+        Self::with_vcpus_per_core(num, allocate_bitmap, master, 1)
+    }
+
+    pub fn with_vcpus_per_core(num: usize, allocate_bitmap: usize, master: usize, vcpus_per_core: usize) -> Self {
+        let vcpus_per_core = vcpus_per_core.max(1);
+        let num = usize::min(num, allocate_bitmap.count_ones() as usize * vcpus_per_core);
         let allocate_bitmap = {
             // only accept the lower bitmap by given cpu num
-            let mut index = 1 << allocate_bitmap.trailing_zeros();
-            let mut remain = num;
-            while remain > 0 && index <= allocate_bitmap {
-                if allocate_bitmap & index != 0 {
+            let mut bitmap = 0;
+            let mut remain = num.div_ceil(vcpus_per_core);
+            for bit in 0..usize::BITS as usize {
+                if remain == 0 {
+                    break;
+                }
+                if allocate_bitmap & (1 << bit) != 0 {
+                    bitmap |= 1 << bit;
                     remain -= 1;
                 }
-                index <<= 1;
             }
-            allocate_bitmap & (index - 1)
+            bitmap
         };
-        let master = if allocate_bitmap & (1 << master) != 0 {
+        let master = if master < usize::BITS as usize && allocate_bitmap & (1 << master) != 0 {
             Some(master)
         } else {
             None
@@ -177,8 +302,22 @@ impl VmCpuConfig {
             num,
             allocate_bitmap,
             master,
+            vcpus_per_core,
+            ..Default::default()
         }
     }
+
+    // the time slice the scheduler gives a vcpu of this VM, in us
+    pub fn sched_slice_us(&self) -> usize {
+        let slice = if self.sched_slice_us != 0 {
+            self.sched_slice_us
+        } else if self.sched_rt || self.sched_weight == 0 {
+            TIMER_SLICE * 1000 * SCHED_WEIGHT_DEFAULT / 100
+        } else {
+            TIMER_SLICE * 1000 * self.sched_weight / 100
+        };
+        slice.clamp(SCHED_SLICE_MIN_US, SCHED_SLICE_MAX_US)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -209,7 +348,11 @@ pub struct VmConfigEntry {
     pub vm_emu_dev_confg: VmEmulatedDeviceConfigList,
     pub vm_pt_dev_confg: VmPassthroughDeviceConfig,
     pub vm_dtb_devs: VMDtbDevConfigList,
-    pub mediated_block_index: Option<usize>,
+    // the mediated blks of the VM, the n-th mediated virtio blk in the emulated device list uses the n-th
+    pub mediated_block_index: Vec<usize>,
+    // counter ticks to poll before a WFI trapped vcpu yields, 0 means off
+    pub halt_poll_ticks: usize,
+    pub smc: VmSmcConfig,
 }
 
 impl VmConfigEntry {
@@ -232,16 +375,26 @@ impl VmConfigEntry {
             vm_emu_dev_confg: VmEmulatedDeviceConfigList::default(),
             vm_pt_dev_confg: VmPassthroughDeviceConfig::default(),
             vm_dtb_devs: VMDtbDevConfigList::default(),
-            mediated_block_index: None,
+            mediated_block_index: vec![],
+            halt_poll_ticks: 0,
+            smc: VmSmcConfig::default(),
         }
     }
 
-    pub fn mediated_block_index(&self) -> Option<usize> {
-        self.mediated_block_index
+    pub fn mediated_block_index(&self) -> &[usize] {
+        &self.mediated_block_index
     }
 
-    fn set_mediated_block_index(&mut self, med_blk_id: usize) {
-        self.mediated_block_index = Some(med_blk_id);
+    fn add_mediated_block_index(&mut self, med_blk_id: usize) {
+        self.mediated_block_index.push(med_blk_id);
+    }
+
+    pub fn halt_poll_ticks(&self) -> usize {
+        self.halt_poll_ticks
+    }
+
+    pub fn smc_config(&self) -> &VmSmcConfig {
+        &self.smc
     }
 
     pub fn kernel_img_name(&self) -> Option<&'static str> {
@@ -264,6 +417,28 @@ impl VmConfigEntry {
         self.image.ramdisk_load_ipa
     }
 
+    // an image address, if set, is in any of the memory regions, its end is checked as it is loaded
+    pub fn load_ipa_valid(&self, ipa: usize) -> bool {
+        ipa == 0
+            || self
+                .memory_region()
+                .iter()
+                .any(|region| region.as_range().contains(&ipa))
+    }
+
+    pub fn ramdisk_load_ipa_valid(&self) -> bool {
+        self.load_ipa_valid(self.ramdisk_load_ipa())
+    }
+
+    // the ipa of the boot info of a bma guest, 0 for an os guest or if none is set
+    pub fn boot_info_load_ipa(&self) -> usize {
+        match self.os_type {
+            VmType::VmTBma if self.image.device_tree_load_ipa != 0 => self.image.device_tree_load_ipa,
+            VmType::VmTBma => self.image.boot_info_load_ipa,
+            _ => 0,
+        }
+    }
+
     pub fn memory_region(&self) -> &[VmRegion] {
         &self.memory.region
     }
@@ -280,10 +455,45 @@ impl VmConfigEntry {
         }
     }
 
+    pub fn memory_max_size(&self) -> usize {
+        self.memory.max_size
+    }
+
     fn add_memory_cfg(&mut self, ipa_start: usize, length: usize) {
         self.memory.region.push(VmRegion { ipa_start, length });
     }
 
+    /* Check a memory region before it is added to this config: it must be page aligned, not empty,
+     * inside the ipa space of a VM and clear of the regions and devices already configured.
+     */
+    pub fn memory_region_valid(&self, ipa_start: usize, length: usize) -> bool {
+        if length == 0 || ipa_start % PAGE_SIZE != 0 || length % PAGE_SIZE != 0 {
+            return false;
+        }
+        let end = match ipa_start.checked_add(length) {
+            Some(end) if end <= 1 << VM_IPA_SIZE => end,
+            _ => return false,
+        };
+        let range = ipa_start..end;
+        let overlap = |other: Range<usize>| range.start < other.end && other.start < range.end;
+        !(self.memory_region().iter().any(|region| overlap(region.as_range()))
+            || self
+                .emulated_device_list()
+                .iter()
+                .any(|emu_cfg| overlap(emu_cfg.base_ipa..emu_cfg.base_ipa + emu_cfg.length))
+            || self
+                .passthrough_device_regions()
+                .iter()
+                .any(|region| overlap(region.ipa..region.ipa + region.length)))
+    }
+
+    // the release page of the spin-table must be free ipa, 0 is taken for psci
+    pub fn spin_table_valid(&self, release_ipa: usize) -> bool {
+        release_ipa != 0
+            && self.memory_region_valid(release_ipa, PAGE_SIZE)
+            && self.ipa_range_free(&(release_ipa..release_ipa + PAGE_SIZE))
+    }
+
     pub fn cpu_num(&self) -> usize {
         self.cpu.num
     }
@@ -296,8 +506,28 @@ impl VmConfigEntry {
         self.cpu.master
     }
 
-    fn set_cpu_cfg(&mut self, num: usize, allocate_bitmap: usize, master: usize) {
-        self.cpu = VmCpuConfig::new(num, allocate_bitmap, master);
+    pub fn smp_boot(&self) -> SmpBoot {
+        self.cpu.smp_boot
+    }
+
+    pub fn cpu_vcpus_per_core(&self) -> usize {
+        self.cpu.vcpus_per_core.max(1)
+    }
+
+    fn set_cpu_cfg(&mut self, num: usize, allocate_bitmap: usize, master: usize, vcpus_per_core: usize) {
+        let (sched_rt, sched_weight, sched_slice_us, smp_boot) = (
+            self.cpu.sched_rt,
+            self.cpu.sched_weight,
+            self.cpu.sched_slice_us,
+            self.cpu.smp_boot,
+        );
+        self.cpu = VmCpuConfig {
+            sched_rt,
+            sched_weight,
+            sched_slice_us,
+            smp_boot,
+            ..VmCpuConfig::with_vcpus_per_core(num, allocate_bitmap, master, vcpus_per_core)
+        };
     }
 
     pub fn emulated_device_list(&self) -> &[VmEmulatedDeviceConfig] {
@@ -308,6 +538,53 @@ impl VmConfigEntry {
         self.vm_emu_dev_confg.emu_dev_list.push(cfg);
     }
 
+    /* The ipa taken by the memory, the emulated and passthrough devices and the device tree devices.
+     * The gicd of the device tree is the emulated gicd itself, it is left out.
+     */
+    fn ipa_ranges_used(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        self.memory_region()
+            .iter()
+            .map(|region| region.as_range())
+            .chain(
+                self.emulated_device_list()
+                    .iter()
+                    .filter(|emu_cfg| emu_cfg.emu_type.has_mmio())
+                    .map(|emu_cfg| emu_cfg.base_ipa..emu_cfg.base_ipa + emu_cfg.length),
+            )
+            .chain(
+                self.passthrough_device_regions()
+                    .iter()
+                    .map(|region| region.ipa..region.ipa + region.length),
+            )
+            .chain(
+                self.dtb_device_list()
+                    .iter()
+                    .filter(|dev| dev.dev_type != DtbDevType::Gicd)
+                    .map(|dev| dev.addr_region.as_range()),
+            )
+    }
+
+    pub fn ipa_range_free(&self, range: &Range<usize>) -> bool {
+        self.ipa_ranges_used()
+            .all(|used| used.is_empty() || range.end <= used.start || used.end <= range.start)
+    }
+
+    // the lowest page aligned window of `length` bytes in EMU_DEV_AUTO_IPA clear of the ipa taken
+    pub fn emu_dev_free_ipa(&self, length: usize) -> Option<usize> {
+        let mut used: Vec<_> = self.ipa_ranges_used().filter(|used| !used.is_empty()).collect();
+        used.sort_unstable_by_key(|used| used.start);
+        let mut start = EMU_DEV_AUTO_IPA.start;
+        for range in used {
+            if start + length <= range.start {
+                break;
+            }
+            if range.end > start {
+                start = round_up(range.end, PAGE_SIZE);
+            }
+        }
+        (start + length <= EMU_DEV_AUTO_IPA.end).then_some(start)
+    }
+
     pub fn passthrough_device_regions(&self) -> &[PassthroughRegion] {
         &self.vm_pt_dev_confg.regions
     }
@@ -349,13 +626,17 @@ impl VmConfigEntry {
         0
     }
 
+    // the gicd of the device tree, or the emulated one without it
     pub fn gicd_addr(&self) -> usize {
         for dev in &self.vm_dtb_devs.dtb_device_list {
             if dev.dev_type == DtbDevType::Gicd {
                 return dev.addr_region.ipa_start;
             }
         }
-        0
+        self.emulated_device_list()
+            .iter()
+            .find(|emu_cfg| emu_cfg.emu_type == EmuDeviceType::EmuDeviceTGicd)
+            .map_or(0, |emu_cfg| emu_cfg.base_ipa)
     }
 }
 
@@ -382,6 +663,32 @@ impl VmConfigTable {
         Err(())
     }
 
+    // the VM holding a virtio net with `mac`, the config table is the only place all MACs are known before boot
+    fn mac_owner(&self, mac: &[u8; 6]) -> Option<usize> {
+        self.entries
+            .iter()
+            .find(|entry| {
+                entry
+                    .emulated_device_list()
+                    .iter()
+                    .any(|emu_cfg| emu_cfg_mac(emu_cfg).as_ref() == Some(mac))
+            })
+            .map(|entry| entry.id)
+    }
+
+    // the VM that passes through the physical uart at `uart`, a uart has one reader and one writer
+    fn uart_owner(&self, uart: usize) -> Option<usize> {
+        self.entries
+            .iter()
+            .find(|entry| {
+                entry
+                    .passthrough_device_regions()
+                    .iter()
+                    .any(|region| region.uarts().any(|pa| pa == uart))
+            })
+            .map(|entry| entry.id)
+    }
+
     fn remove_vm_id(&mut self, vm_id: usize) {
         if vm_id >= CONFIG_VM_NUM_MAX || self.vm_bitmap.get(vm_id) == 0 {
             error!("illegal vm id {}", vm_id);
@@ -404,6 +711,31 @@ pub fn vm_cfg_entry(vmid: usize) -> Option<VmConfigEntry> {
     None
 }
 
+// the MAC of a virtio net, cfg_list[0..6]
+fn emu_cfg_mac(emu_cfg: &VmEmulatedDeviceConfig) -> Option<[u8; 6]> {
+    if emu_cfg.emu_type != EmuDeviceType::EmuDeviceTVirtioNet || emu_cfg.cfg_list.len() < 6 {
+        return None;
+    }
+    let mut mac = [0; 6];
+    for (byte, item) in mac.iter_mut().zip(emu_cfg.cfg_list.iter()) {
+        *byte = *item as u8;
+    }
+    Some(mac)
+}
+
+// a nic needs a unicast MAC that no other nic uses, frames are switched by the destination MAC
+fn vm_cfg_check_mac(vm_config: &VmConfigTable, mac: &[u8; 6]) -> bool {
+    if *mac == [0; 6] || mac[0] & 1 != 0 {
+        error!("virtio net MAC {:02x?} is not a unicast address", mac);
+        return false;
+    }
+    if let Some(owner) = vm_config.mac_owner(mac) {
+        error!("virtio net MAC {:02x?} is already used by VM[{}]", mac, owner);
+        return false;
+    }
+    true
+}
+
 fn vm_cfg_editor<F>(vmid: usize, f: F) -> Result<usize, ()>
 where
     F: FnOnce(&mut VmConfigEntry) -> Result<usize, ()>,
@@ -421,6 +753,32 @@ where
 /* Add VM config entry to DEF_VM_CONFIG_TABLE */
 pub fn vm_cfg_add_vm_entry(mut vm_cfg_entry: VmConfigEntry) -> Result<usize, ()> {
     let mut vm_config = DEF_VM_CONFIG_TABLE.lock();
+    // the nics of the new VM must not clash with each other or with the other VMs
+    let macs: Vec<[u8; 6]> = vm_cfg_entry
+        .emulated_device_list()
+        .iter()
+        .filter_map(emu_cfg_mac)
+        .collect();
+    for (i, mac) in macs.iter().enumerate() {
+        if !vm_cfg_check_mac(&vm_config, mac) || macs[..i].contains(mac) {
+            error!(
+                "vm_cfg_add_vm_entry: VM {} has a conflicting virtio net MAC",
+                vm_cfg_entry.name
+            );
+            return Err(());
+        }
+    }
+    for region in vm_cfg_entry.passthrough_device_regions() {
+        for uart in region.uarts() {
+            if let Some(owner) = vm_config.uart_owner(uart) {
+                error!(
+                    "vm_cfg_add_vm_entry: VM {} passes through uart {:#x} of VM[{}]",
+                    vm_cfg_entry.name, uart, owner
+                );
+                return Err(());
+            }
+        }
+    }
     match vm_config.generate_vm_id() {
         Ok(vm_id) => {
             if vm_id == 0 && !vm_config.entries.is_empty() {
@@ -444,33 +802,35 @@ pub fn vm_cfg_add_vm_entry(mut vm_cfg_entry: VmConfigEntry) -> Result<usize, ()>
     }
 }
 
+/* Take back an entry just added by `vm_cfg_add_vm_entry`, before anything of it is set up:
+ * no mediated blk or iommu stream is claimed for it yet, unlike `del_vm`.
+ */
+pub fn vm_cfg_remove_vm_entry(vmid: usize) {
+    let mut vm_config = DEF_VM_CONFIG_TABLE.lock();
+    if let Some(idx) = vm_config
+        .entries
+        .iter()
+        .position(|vm_cfg_entry| vm_cfg_entry.id == vmid)
+    {
+        vm_config.remove_vm_id(vmid);
+        vm_config.entries.remove(idx);
+    }
+}
+
 /* Generate a new VM Config Entry, set basic value */
 pub fn add_vm(config_ipa: usize) -> Result<usize, ()> {
     let vm = active_vm().unwrap();
-    let config_pa = vm.ipa2hva(config_ipa);
+    let mut config = [0_usize; 8];
+    copy_segment_from_vm(&vm, &mut config, config_ipa).map_err(|_| ())?;
     let [vm_name_ipa, _vm_name_length, vm_type, cmdline_ipa, _cmdline_length, kernel_load_ipa, device_tree_load_ipa, ramdisk_load_ipa] =
-        unsafe { *(config_pa as *const _) };
+        config;
     info!("\nStart to prepare configuration for new VM");
 
     // Copy VM name from user ipa.
-    let vm_name_pa = vm.ipa2hva(vm_name_ipa);
-    if vm_name_pa == 0 {
-        error!("illegal vm_name_ipa {:x}", vm_name_ipa);
-        return Err(());
-    }
-    let vm_name_str = unsafe { CStr::from_ptr(vm_name_pa as *const _) }
-        .to_string_lossy()
-        .to_string();
+    let vm_name_str = copy_cstr_from_vm(&vm, vm_name_ipa).map_err(|_| ())?;
 
     // Copy VM cmdline from user ipa.
-    let cmdline_pa = vm.ipa2hva(cmdline_ipa);
-    if cmdline_pa == 0 {
-        error!("illegal cmdline_ipa {:x}", cmdline_ipa);
-        return Err(());
-    }
-    let cmdline_str = unsafe { CStr::from_ptr(cmdline_pa as *const _) }
-        .to_string_lossy()
-        .to_string();
+    let cmdline_str = copy_cstr_from_vm(&vm, cmdline_ipa).map_err(|_| ())?;
 
     // Generate a new VM config entry.
     let new_vm_cfg = VmConfigEntry::new(
@@ -493,9 +853,10 @@ pub fn del_vm(vmid: usize) -> Result<usize, ()> {
     let mut vm_config = DEF_VM_CONFIG_TABLE.lock();
     for (idx, vm_cfg_entry) in vm_config.entries.iter().enumerate() {
         if vm_cfg_entry.id == vmid {
-            if let Some(block_idx) = vm_cfg_entry.mediated_block_index() {
+            for &block_idx in vm_cfg_entry.mediated_block_index() {
                 mediated_blk_free(block_idx);
             }
+            iommu_release_streams(vmid);
             vm_config.remove_vm_id(vmid);
             vm_config.entries.remove(idx);
             info!("delete VM[{}] config entry from vm-config-table", vmid);
@@ -505,9 +866,41 @@ pub fn del_vm(vmid: usize) -> Result<usize, ()> {
     Ok(0)
 }
 
-/* Add VM memory region according to VM id */
+/* Give the mediated blks of a shut down VM back, so that `del_vm` does not free them twice.
+ * Returns the freed blk indexes.
+ */
+pub fn vm_cfg_release_mediated_blk(vmid: usize) -> Vec<usize> {
+    let mut vm_config = DEF_VM_CONFIG_TABLE.lock();
+    let vm_cfg_entry = match vm_config
+        .entries
+        .iter_mut()
+        .find(|vm_cfg_entry| vm_cfg_entry.id == vmid)
+    {
+        Some(vm_cfg_entry) => vm_cfg_entry,
+        None => return vec![],
+    };
+    let block_idx = core::mem::take(&mut vm_cfg_entry.mediated_block_index);
+    for &idx in block_idx.iter() {
+        mediated_blk_free(idx);
+    }
+    block_idx
+}
+
+/* Add VM memory region according to VM id.
+ * If the VM is already created, the region is hot-added to it.
+ */
 pub fn add_mem_region(vmid: usize, ipa_start: usize, length: usize) -> Result<usize, ()> {
+    if let Some(vm) = vm_by_id(vmid) {
+        return hotplug_mem_region(vm, ipa_start, length);
+    }
     vm_cfg_editor(vmid, |vm_cfg| {
+        if !vm_cfg.memory_region_valid(ipa_start, length) {
+            error!(
+                "VM[{}] vm_cfg_add_mem_region: illegal region start_ipa {:#x} length {:#x}",
+                vmid, ipa_start, length
+            );
+            return Err(());
+        }
         vm_cfg.add_memory_cfg(ipa_start, length);
         info!(
             "VM[{}] vm_cfg_add_mem_region: add region start_ipa {:x} length {:x}",
@@ -517,24 +910,173 @@ pub fn add_mem_region(vmid: usize, ipa_start: usize, length: usize) -> Result<us
     })
 }
 
-/* Set VM cpu config according to VM id */
+/* Grow the memory of a created VM (running or not) with a new region,
+ * the region is mapped immediately and the guest is notified to online it.
+ * Shrinking is not supported.
+ */
+fn hotplug_mem_region(vm: Arc<Vm>, ipa_start: usize, length: usize) -> Result<usize, ()> {
+    let vmid = vm.id();
+    if length == 0
+        || ipa_start % PAGE_SIZE != 0
+        || length % PAGE_SIZE != 0
+        || !matches!(ipa_start.checked_add(length), Some(end) if end <= 1 << VM_IPA_SIZE)
+    {
+        error!(
+            "VM[{}] hotplug memory: illegal region start_ipa {:#x} length {:#x}",
+            vmid, ipa_start, length
+        );
+        return Err(());
+    }
+    let regions = vm.memory_regions();
+    if let Some(region) = regions.iter().find(|region| region.ipa_start == ipa_start) {
+        if length < region.length {
+            error!(
+                "VM[{}] hotplug memory: shrinking region {:#x} from {:#x} to {:#x} is not supported",
+                vmid, ipa_start, region.length, length
+            );
+            return Err(());
+        }
+    }
+    let range = ipa_start..ipa_start + length;
+    let overlap = |other: Range<usize>| range.start < other.end && other.start < range.end;
+    let config = vm.config();
+    if regions.iter().any(|region| overlap(region.as_range()))
+        || config
+            .emulated_device_list()
+            .iter()
+            .any(|emu_cfg| overlap(emu_cfg.base_ipa..emu_cfg.base_ipa + emu_cfg.length))
+        || vm
+            .passthrough_regions()
+            .iter()
+            .any(|region| overlap(region.ipa..region.ipa + region.length))
+        || vm.emu_dev_overlap(&range)
+        || ivc_ipa_overlap(vmid, range.clone())
+    {
+        error!(
+            "VM[{}] hotplug memory: region {:#x?} overlaps with existing mappings",
+            vmid, range
+        );
+        return Err(());
+    }
+    let total = regions.iter().map(|region| region.length).sum::<usize>() + length;
+    if total > config.memory_max_size() {
+        error!(
+            "VM[{}] hotplug memory: total size {:#x} exceeds max size {:#x}",
+            vmid,
+            total,
+            config.memory_max_size()
+        );
+        return Err(());
+    }
+
+    let region = VmRegion { ipa_start, length };
+    if !vmm_add_memory_region(&vm, region) {
+        return Err(());
+    }
+    // keep the config table in sync, so that the region is listed with the VM
+    vm_cfg_editor(vmid, |vm_cfg| {
+        vm_cfg.add_memory_cfg(ipa_start, length);
+        Ok(0)
+    })?;
+    info!(
+        "VM[{}] hotplug memory: add region start_ipa {:x} length {:x}",
+        vmid, ipa_start, length
+    );
+
+    let msg = HvcManageMsg {
+        fid: HVC_CONFIG,
+        event: HVC_CONFIG_MEMORY_REGION,
+        vm_id: vmid,
+    };
+    if !hvc_send_msg_to_vm(vmid, &HvcGuestMsg::Manage(msg)) {
+        warn!("VM[{}] hotplug memory: failed to notify the guest", vmid);
+    }
+    Ok(0)
+}
+
+pub fn set_memory_max(vmid: usize, max_size: usize) -> Result<usize, ()> {
+    vm_cfg_editor(vmid, |vm_cfg| {
+        vm_cfg.memory.max_size = max_size;
+        info!("VM[{vmid}] memory max size {:#x}", max_size);
+        Ok(0)
+    })
+}
+
+/* Set VM cpu config according to VM id.
+ *
+ * @param num vcpus_per_core ~ (31, 16) ~ [vcpus sharing a core, 0 for one vcpu per core]
+ *            num ~ (15, 0) ~ [number of vcpus]
+ */
 pub fn set_cpu(vmid: usize, num: usize, allocate_bitmap: usize, master: usize) -> Result<usize, ()> {
     vm_cfg_editor(vmid, |vm_cfg| {
-        vm_cfg.set_cpu_cfg(num, allocate_bitmap, master);
+        vm_cfg.set_cpu_cfg(
+            bit_extract(num, 0, 16),
+            allocate_bitmap,
+            master,
+            bit_extract(num, 16, 16),
+        );
 
         info!(
-            "VM[{}] vm_cfg_set_cpu: num {} allocate_bitmap {:#b} master {:?}",
+            "VM[{}] vm_cfg_set_cpu: num {} allocate_bitmap {:#b} master {:?} vcpus per core {}",
             vmid,
             vm_cfg.cpu_num(),
             vm_cfg.cpu_allocated_bitmap(),
-            vm_cfg.cpu_master()
+            vm_cfg.cpu_master(),
+            vm_cfg.cpu_vcpus_per_core()
         );
 
         Ok(0)
     })
 }
 
-/* Add emulated device config for VM */
+/* Set the scheduling class and time slice of the vcpus of VM, applied when the VM boots.
+ * class 0 is best-effort with `weight` (0 for the default), class 1 is real-time.
+ * `slice_us` is the time slice in us, 0 takes the one given by the weight.
+ */
+pub fn set_cpu_sched(vmid: usize, class: usize, weight: usize, slice_us: usize) -> Result<usize, ()> {
+    if class > 1 {
+        warn!("VM[{vmid}] unknown sched class {class}");
+        return Err(());
+    }
+    if slice_us != 0 && !(SCHED_SLICE_MIN_US..=SCHED_SLICE_MAX_US).contains(&slice_us) {
+        warn!("VM[{vmid}] sched slice {slice_us} us out of [{SCHED_SLICE_MIN_US}, {SCHED_SLICE_MAX_US}]");
+        return Err(());
+    }
+    vm_cfg_editor(vmid, |vm_cfg| {
+        vm_cfg.cpu.sched_rt = class == 1;
+        vm_cfg.cpu.sched_weight = weight;
+        vm_cfg.cpu.sched_slice_us = slice_us;
+        info!(
+            "VM[{vmid}] sched class {} weight {weight} slice {} us",
+            if class == 1 { "real-time" } else { "best-effort" },
+            vm_cfg.cpu.sched_slice_us()
+        );
+        Ok(0)
+    })
+}
+
+/* Bring up the secondary vcpus of VM with the spin-table, the release page at `release_ipa` must be
+ * clear of the memory and devices of the VM. 0 goes back to PSCI, the default.
+ */
+pub fn set_cpu_spin_table(vmid: usize, release_ipa: usize) -> Result<usize, ()> {
+    vm_cfg_editor(vmid, |vm_cfg| {
+        if release_ipa == 0 {
+            vm_cfg.cpu.smp_boot = SmpBoot::Psci;
+        } else if vm_cfg.spin_table_valid(release_ipa) {
+            vm_cfg.cpu.smp_boot = SmpBoot::SpinTable(release_ipa);
+        } else {
+            error!("VM[{vmid}] spin-table release page {release_ipa:#x} is not free ipa");
+            return Err(());
+        }
+        info!("VM[{vmid}] secondary vcpus boot by {:x?}", vm_cfg.cpu.smp_boot);
+        Ok(0)
+    })
+}
+
+/* Add emulated device config for VM, return the base ipa of the device.
+ * A device with registers must not overlap with the memory or other devices of the VM,
+ * with `base_ipa` 0 it is placed at a free ipa.
+ */
 pub fn add_emu_dev(
     vmid: usize,
     name_ipa: usize,
@@ -544,21 +1086,47 @@ pub fn add_emu_dev(
     cfg_list_ipa: usize,
     emu_type: usize,
 ) -> Result<usize, ()> {
-    vm_cfg_editor(vmid, |vm_cfg| {
-        // Copy emu device name from user ipa.
-        let name_pa = active_vm().unwrap().ipa2hva(name_ipa);
-        if name_pa == 0 {
-            info!("illegal emulated device name_ipa {:x}", name_ipa);
+    // Copy emu device name from user ipa.
+    let name_str = copy_cstr_from_vm(&active_vm().unwrap(), name_ipa).map_err(|_| ())?;
+    // Copy emu device cfg list from user ipa.
+    let mut cfg_list = vec![0_usize; CFG_MAX_NUM];
+    copy_segment_from_vm(&active_vm().unwrap(), cfg_list.as_mut_slice(), cfg_list_ipa).map_err(|_| ())?;
+
+    if EmuDeviceType::from(emu_type) == EmuDeviceType::EmuDeviceTVirtioNet {
+        let mut mac = [0; 6];
+        for (byte, item) in mac.iter_mut().zip(cfg_list.iter()) {
+            *byte = *item as u8;
+        }
+        if !vm_cfg_check_mac(&DEF_VM_CONFIG_TABLE.lock(), &mac) {
+            error!("VM[{}] virtio net is rejected", vmid);
             return Err(());
         }
-        let name_str = unsafe { CStr::from_ptr(name_pa as *const _) }
-            .to_string_lossy()
-            .to_string();
-        // Copy emu device cfg list from user ipa.
-        let mut cfg_list = vec![0_usize; CFG_MAX_NUM];
-        copy_segment_from_vm(&active_vm().unwrap(), cfg_list.as_mut_slice(), cfg_list_ipa);
+    }
 
+    vm_cfg_editor(vmid, |vm_cfg| {
         let emu_dev_type = EmuDeviceType::from(emu_type);
+        let mut base_ipa = base_ipa;
+        if emu_dev_type.has_mmio() && length != 0 {
+            if base_ipa == 0 {
+                base_ipa = match vm_cfg.emu_dev_free_ipa(round_up(length, PAGE_SIZE)) {
+                    Some(ipa) => ipa,
+                    None => {
+                        error!("VM[{}] emu dev {}: no free ipa of length {:#x}", vmid, name_str, length);
+                        return Err(());
+                    }
+                };
+                info!("VM[{}] emu dev {} is placed at ipa {:#x}", vmid, name_str, base_ipa);
+            } else if !base_ipa
+                .checked_add(length)
+                .map_or(false, |end| vm_cfg.ipa_range_free(&(base_ipa..end)))
+            {
+                error!(
+                    "VM[{}] emu dev {}: ipa {:#x} length {:#x} overlaps with other devices or memory",
+                    vmid, name_str, base_ipa, length
+                );
+                return Err(());
+            }
+        }
         let emu_dev_cfg = VmEmulatedDeviceConfig {
             name: name_str,
             base_ipa,
@@ -574,7 +1142,18 @@ pub fn add_emu_dev(
                 EmuDeviceType::EmuDeviceTVirtioBlkMediated
             ),
         };
+        if !emu_dev_cfg.irq_valid() {
+            error!(
+                "VM[{}] emu dev {}: irq {} is not an SPI",
+                vmid, emu_dev_cfg.name, emu_dev_cfg.irq_id
+            );
+            return Err(());
+        }
         info!("VM[{}] vm_cfg_add_emu_dev: {:?}", vmid, emu_dev_cfg);
+        // cfg_list[5] of a virtio blk is its read-only flag
+        if emu_dev_cfg.emu_type == EmuDeviceType::EmuDeviceTVirtioBlk && emu_dev_cfg.cfg_list[5] != 0 {
+            info!("VM[{}] blk {} is read-only", vmid, emu_dev_cfg.name);
+        }
         vm_cfg.add_emulated_device_cfg(emu_dev_cfg);
 
         // Set GVM Mediated Blk Index Here.
@@ -586,35 +1165,150 @@ pub fn add_emu_dev(
                     return Err(());
                 }
             };
-            vm_cfg.set_mediated_block_index(med_blk_index);
+            vm_cfg.add_mediated_block_index(med_blk_index);
         }
 
-        Ok(0)
+        Ok(base_ipa)
     })
 }
 
-/* Add passthrough device config region for VM */
+/* Add passthrough device config region for VM, a physical uart is passed through to one VM only.
+ * If the VM is already set up, the region is mapped into it immediately.
+ */
 pub fn add_passthrough_device_region(vmid: usize, base_ipa: usize, base_pa: usize, length: usize) -> Result<usize, ()> {
-    // Get VM config entry.
-    vm_cfg_editor(vmid, |vm_cfg| {
-        let pt_region_cfg = PassthroughRegion {
-            ipa: base_ipa,
-            pa: base_pa,
-            length,
-            dev_property: true,
-        };
-        info!("VM[{}] vm_cfg_add_pt_dev: {:x?}", vmid, pt_region_cfg);
+    let pt_region_cfg = PassthroughRegion {
+        ipa: base_ipa,
+        pa: base_pa,
+        length,
+        dev_property: true,
+    };
+    info!("VM[{}] vm_cfg_add_pt_dev: {:x?}", vmid, pt_region_cfg);
 
-        vm_cfg.add_passthrough_device_region(pt_region_cfg);
-        Ok(0)
-    })
+    let mut vm_config = DEF_VM_CONFIG_TABLE.lock();
+    for uart in pt_region_cfg.uarts() {
+        match vm_config.uart_owner(uart) {
+            Some(owner) if owner != vmid => {
+                error!(
+                    "VM[{}] vm_cfg_add_pt_dev: uart {:#x} is passed through to VM[{}]",
+                    vmid, uart, owner
+                );
+                return Err(());
+            }
+            _ => {}
+        }
+    }
+    let vm_cfg = match vm_config.entries.iter_mut().find(|entry| entry.id == vmid) {
+        Some(vm_cfg) => vm_cfg,
+        None => {
+            error!("failed to find VM[{}] in vm cfg entry list", vmid);
+            return Err(());
+        }
+    };
+    if let Some(vm) = vm_by_id(vmid) {
+        hotplug_pt_region(vm, pt_region_cfg.clone())?;
+    }
+    // keep the config table in sync, so that the region is mapped again when the VM is created from it
+    vm_cfg.add_passthrough_device_region(pt_region_cfg);
+    Ok(0)
+}
+
+/* Map a passthrough region into a VM past its setup, e.g. a device re-enumerated by MVM after the guest
+ * booted. The guest is not told, its driver has to probe the device by itself.
+ */
+fn hotplug_pt_region(vm: Arc<Vm>, region: PassthroughRegion) -> Result<(), ()> {
+    let vmid = vm.id();
+    // the stage-2 table of a VM being configured is populated from the config it was created with
+    if vm_if_boot_state(vmid) == VmBootState::Configuring {
+        error!("VM[{vmid}] hotplug passthrough region: the VM is being configured");
+        return Err(());
+    }
+    let range = region.ipa..region.ipa.wrapping_add(region.length);
+    if region.length == 0
+        || region.ipa % PAGE_SIZE != 0
+        || region.pa % PAGE_SIZE != 0
+        || region.length % PAGE_SIZE != 0
+        || !matches!(region.ipa.checked_add(region.length), Some(end) if end <= 1 << VM_IPA_SIZE)
+    {
+        error!("VM[{vmid}] hotplug passthrough region: illegal region {:#x?}", range);
+        return Err(());
+    }
+    let overlap = |other: Range<usize>| range.start < other.end && other.start < range.end;
+    if vm.memory_regions().iter().any(|region| overlap(region.as_range()))
+        || vm
+            .config()
+            .emulated_device_list()
+            .iter()
+            .any(|emu_cfg| overlap(emu_cfg.base_ipa..emu_cfg.base_ipa + emu_cfg.length))
+        || vm
+            .passthrough_regions()
+            .iter()
+            .any(|region| overlap(region.ipa..region.ipa + region.length))
+        || vm.emu_dev_overlap(&range)
+        || ivc_ipa_overlap(vmid, range.clone())
+    {
+        error!(
+            "VM[{vmid}] hotplug passthrough region: region {:#x?} overlaps with existing mappings",
+            range
+        );
+        return Err(());
+    }
+    let pte = if region.dev_property {
+        PTE_S2_DEVICE
+    } else {
+        PTE_S2_NORMAL
+    };
+    vm.hotplug_pt_region(region.clone(), pte);
+    info!(
+        "VM[{vmid}] hotplug passthrough region: ipa {:#x} pa {:#x} length {:#x}",
+        region.ipa, region.pa, region.length
+    );
+    Ok(())
+}
+
+/* HVC_CONFIG_PASSTHROUGH_DEVICE_REGION_REMOVE: remove the passthrough region at `base_ipa` from the VM config.
+ * If the VM is created, the region has to be one added by `add_passthrough_device_region` after its setup,
+ * it is unmapped and the TLBs of all cores are invalidated before the call returns.
+ */
+pub fn remove_passthrough_device_region(vmid: usize, base_ipa: usize) -> Result<usize, ()> {
+    let mut vm_config = DEF_VM_CONFIG_TABLE.lock();
+    let vm_cfg = match vm_config.entries.iter_mut().find(|entry| entry.id == vmid) {
+        Some(vm_cfg) => vm_cfg,
+        None => {
+            error!("failed to find VM[{}] in vm cfg entry list", vmid);
+            return Err(());
+        }
+    };
+    let idx = match vm_cfg
+        .vm_pt_dev_confg
+        .regions
+        .iter()
+        .position(|region| region.ipa == base_ipa)
+    {
+        Some(idx) => idx,
+        None => {
+            error!("VM[{vmid}] remove passthrough region: no region at ipa {:#x}", base_ipa);
+            return Err(());
+        }
+    };
+    if let Some(vm) = vm_by_id(vmid) {
+        if vm.unplug_pt_region(base_ipa).is_none() {
+            error!(
+                "VM[{vmid}] remove passthrough region: the region at ipa {:#x} is not hot-plugged",
+                base_ipa
+            );
+            return Err(());
+        }
+    }
+    let region = vm_cfg.vm_pt_dev_confg.regions.remove(idx);
+    info!("VM[{vmid}] remove passthrough region: {:x?}", region);
+    Ok(0)
 }
 
 /* Add passthrough device config irqs for VM */
 pub fn add_passthrough_device_irqs(vmid: usize, irqs_base_ipa: usize, irqs_length: usize) -> Result<usize, ()> {
     let mut irqs = vec![0_usize; irqs_length];
     if irqs_length > 0 {
-        copy_segment_from_vm(&active_vm().unwrap(), irqs.as_mut_slice(), irqs_base_ipa);
+        copy_segment_from_vm(&active_vm().unwrap(), irqs.as_mut_slice(), irqs_base_ipa).map_err(|_| ())?;
     }
     info!("VM[{}] vm_cfg_add_pt_dev irqs: {:?}", vmid, irqs);
 
@@ -634,10 +1328,15 @@ pub fn add_passthrough_device_streams_ids(
     let mut streams_ids = vec![0_usize; streams_ids_length];
     if streams_ids_length > 0 {
         copy_segment_from_vm(&active_vm().unwrap(), streams_ids.as_mut_slice(), streams_ids_base_ipa)
+            .map_err(|_| ())?;
     }
     info!("VM[{}] vm_cfg_add_pt_dev streams ids {:?}", vmid, streams_ids);
 
     vm_cfg_editor(vmid, |vm_cfg| {
+        // a stream is passed through to one VM only
+        if !iommu_claim_streams(vmid, &streams_ids) {
+            return Err(());
+        }
         vm_cfg.add_passthrough_device_streams_ids(&mut streams_ids);
         Ok(0)
     })
@@ -654,20 +1353,13 @@ pub fn add_dtb_dev(
     addr_region_length: usize,
 ) -> Result<usize, ()> {
     // Copy DTB device name from user ipa.
-    let name_pa = active_vm().unwrap().ipa2hva(name_ipa);
-    if name_pa == 0 {
-        error!("illegal dtb_dev name ipa {:x}", name_ipa);
-        return Err(());
-    }
-    let dtb_dev_name_str = unsafe { CStr::from_ptr(name_pa as *const _) }
-        .to_string_lossy()
-        .to_string();
+    let dtb_dev_name_str = copy_cstr_from_vm(&active_vm().unwrap(), name_ipa).map_err(|_| ())?;
 
     // Copy DTB device irq list from user ipa.
     let mut dtb_irq_list = vec![0_usize; irq_list_length];
 
     if irq_list_length > 0 {
-        copy_segment_from_vm(&active_vm().unwrap(), dtb_irq_list.as_mut_slice(), irq_list_ipa);
+        copy_segment_from_vm(&active_vm().unwrap(), dtb_irq_list.as_mut_slice(), irq_list_ipa).map_err(|_| ())?;
     }
 
     let vm_dtb_dev = VmDtbDevConfig {
@@ -698,6 +1390,15 @@ pub fn set_memory_budget_second(budget: u32) {
     info!("set memory limited budget {budget_per_period}, bandwidth {bandwidth} MB/s");
 }
 
+// the calibrated budget of a VM owning the whole memory bandwidth, and its period
+#[cfg(feature = "memory-reservation")]
+pub fn memory_budget_per_period() -> (u32, Duration) {
+    (
+        MEMORY_BUDGET_PER_PERIOD.load(Ordering::Relaxed),
+        DEFAULT_MEMORY_REPLENISHMENT_PERIOD,
+    )
+}
+
 pub fn set_memory_color_budget(
     vmid: usize,
     color_num: usize,
@@ -705,9 +1406,9 @@ pub fn set_memory_color_budget(
     budget_percent: usize,
 ) -> Result<usize, ()> {
     vm_cfg_editor(vmid, |vm_cfg| {
-        let color_array_hva = active_vm().unwrap().ipa2hva(color_array_addr);
-        let color_array = unsafe { core::slice::from_raw_parts(color_array_hva as *const _, color_num) };
-        vm_cfg.memory.colors.extend_from_slice(color_array);
+        let mut color_array = vec![0_usize; color_num];
+        copy_segment_from_vm(&active_vm().unwrap(), color_array.as_mut_slice(), color_array_addr).map_err(|_| ())?;
+        vm_cfg.memory.colors.extend_from_slice(&color_array);
         info!("VM[{vmid}] memory colors {:?}", vm_cfg.memory.colors);
 
         if cfg!(feature = "memory-reservation") {
@@ -728,14 +1429,230 @@ pub fn set_memory_color_budget(
     })
 }
 
+pub fn set_memory_color_strict(vmid: usize, strict: usize) -> Result<usize, ()> {
+    vm_cfg_editor(vmid, |vm_cfg| {
+        vm_cfg.memory.strict_colors = strict != 0;
+        info!("VM[{vmid}] strict memory colors {}", vm_cfg.memory.strict_colors);
+        Ok(0)
+    })
+}
+
+// a latency-critical guest has its memory mapped before boot, so that it never takes a stage-2 fault on it
+pub fn set_memory_eager_map(vmid: usize, eager: usize) -> Result<usize, ()> {
+    vm_cfg_editor(vmid, |vm_cfg| {
+        vm_cfg.memory.eager_map = eager != 0;
+        info!("VM[{vmid}] eager memory map {}", vm_cfg.memory.eager_map);
+        Ok(0)
+    })
+}
+
+pub fn set_halt_poll(vmid: usize, ticks: usize) -> Result<usize, ()> {
+    vm_cfg_editor(vmid, |vm_cfg| {
+        if cfg!(feature = "trap-wfi") {
+            vm_cfg.halt_poll_ticks = ticks;
+            info!("VM[{vmid}] halt polling {ticks} ticks");
+        } else {
+            warn!("VM[{vmid}] halt polling is not set because feature \"trap-wfi\" is not enabled");
+        }
+        Ok(0)
+    })
+}
+
+/* Set the smc policy of VM (0 deny, 1 log-and-deny, 2 passthrough).
+ * With passthrough, the function ids `fid_start..=fid_end` are added to the allowlist if `fid_end` is not 0,
+ * any other policy clears the allowlist.
+ */
+pub fn set_smc_policy(vmid: usize, policy: usize, fid_start: usize, fid_end: usize) -> Result<usize, ()> {
+    if policy > SmcPolicy::Passthrough as usize {
+        warn!("VM[{vmid}] unknown smc policy {policy}");
+        return Err(());
+    }
+    vm_cfg_editor(vmid, |vm_cfg| {
+        let policy = SmcPolicy::from(policy);
+        vm_cfg.smc.policy = policy;
+        if policy != SmcPolicy::Passthrough {
+            vm_cfg.smc.allowlist.clear();
+        } else if fid_end != 0 && vm_cfg.smc.add_range(fid_start, fid_end).is_err() {
+            warn!("VM[{vmid}] smc range {fid_start:#x}..={fid_end:#x} can not be forwarded");
+            return Err(());
+        }
+        info!(
+            "VM[{vmid}] smc policy {:?}, {} allowed ranges",
+            policy,
+            vm_cfg.smc.allowlist.len()
+        );
+        Ok(0)
+    })
+}
+
+/* Query the MAC of the `idx`th virtio net of VM, or replace it with a random locally administered one
+ * if `regenerate` is set, which is only allowed before the VM is created.
+ * Returns the MAC with the first byte in bits (47, 40).
+ */
+pub fn net_mac(vmid: usize, idx: usize, regenerate: bool) -> Result<usize, ()> {
+    if regenerate && vm_by_id(vmid).is_some() {
+        error!("VM[{vmid}] is created, its MAC can not be changed");
+        return Err(());
+    }
+    let mut vm_config = DEF_VM_CONFIG_TABLE.lock();
+    let mut mac = match vm_config
+        .entries
+        .iter()
+        .find(|entry| entry.id == vmid)
+        .and_then(|entry| entry.emulated_device_list().iter().filter_map(emu_cfg_mac).nth(idx))
+    {
+        Some(mac) => mac,
+        None => {
+            error!("VM[{vmid}] has no virtio net {idx}");
+            return Err(());
+        }
+    };
+    if regenerate {
+        loop {
+            crate::util::rng::rng_fill(&mut mac);
+            // unicast, locally administered
+            mac[0] = (mac[0] & !0x1) | 0x2;
+            if vm_cfg_check_mac(&vm_config, &mac) {
+                break;
+            }
+        }
+        let entry = vm_config.entries.iter_mut().find(|entry| entry.id == vmid).unwrap();
+        let emu_cfg = entry
+            .vm_emu_dev_confg
+            .emu_dev_list
+            .iter_mut()
+            .filter(|emu_cfg| emu_cfg_mac(emu_cfg).is_some())
+            .nth(idx)
+            .unwrap();
+        for (item, byte) in emu_cfg.cfg_list.iter_mut().zip(mac.iter()) {
+            *item = *byte as usize;
+        }
+        info!("VM[{vmid}] virtio net {idx} MAC is set to {:02x?}", mac);
+    }
+    Ok(mac.iter().fold(0, |packed, byte| packed << 8 | *byte as usize))
+}
+
+const HOTPLUG_CONSOLE_LENGTH: usize = 0x1000;
+
+/* Attach a virtio console at `base_ipa` with `irq_id` to a created VM, its port 0 is connected to
+ * the console at `oppo_end_ipa` of VM `oppo_end_vmid`. The device is not in the device tree of the guest,
+ * it is told by a config change interrupt and its driver has to probe the device by itself.
+ */
+pub fn hotplug_console(
+    vmid: usize,
+    base_ipa: usize,
+    irq_id: usize,
+    oppo_end_vmid: usize,
+    oppo_end_ipa: usize,
+) -> Result<usize, ()> {
+    let vm = match vm_by_id(vmid) {
+        Some(vm) => vm,
+        None => {
+            error!("VM[{vmid}] hotplug console: the VM is not created");
+            return Err(());
+        }
+    };
+    let range = base_ipa..base_ipa + HOTPLUG_CONSOLE_LENGTH;
+    let overlap = |other: Range<usize>| range.start < other.end && other.start < range.end;
+    let config = vm.config();
+    if base_ipa == 0
+        || base_ipa % PAGE_SIZE != 0
+        || vm.memory_regions().iter().any(|region| overlap(region.as_range()))
+        || config
+            .emulated_device_list()
+            .iter()
+            .any(|emu_cfg| overlap(emu_cfg.base_ipa..emu_cfg.base_ipa + emu_cfg.length))
+        || vm
+            .passthrough_regions()
+            .iter()
+            .any(|region| overlap(region.ipa..region.ipa + region.length))
+        || ivc_ipa_overlap(vmid, range.clone())
+    {
+        error!("VM[{vmid}] hotplug console: illegal region {:#x?}", range);
+        return Err(());
+    }
+    if !(GIC_PRIVINT_NUM..GIC_INTS_MAX).contains(&irq_id) || vm.has_interrupt(irq_id) {
+        error!("VM[{vmid}] hotplug console: irq {irq_id} is not a free SPI");
+        return Err(());
+    }
+
+    let emu_cfg = VmEmulatedDeviceConfig {
+        name: String::from("virtio_console_hotplug"),
+        base_ipa,
+        length: HOTPLUG_CONSOLE_LENGTH,
+        irq_id,
+        cfg_list: vec![oppo_end_vmid, oppo_end_ipa],
+        emu_type: EmuDeviceType::EmuDeviceTVirtioConsole,
+        mediated: false,
+    };
+    let console = emu_virtio_mmio_init(Arc::downgrade(&vm), &emu_cfg)?;
+    // registers the irq too, the vgic of the guest accepts it from now on
+    if !vm.hotplug_emu_dev(console.clone(), irq_id) {
+        return Err(());
+    }
+    if let Ok(console) = console.into_any_arc().downcast::<VirtioMmio>() {
+        console.notify_config();
+    }
+    info!(
+        "VM[{vmid}] hotplug console at ipa {:#x} irq {}, connected to VM[{}] ipa {:#x}",
+        base_ipa, irq_id, oppo_end_vmid, oppo_end_ipa
+    );
+    Ok(0)
+}
+
+/* Detach a console attached by `hotplug_console`, the descriptors the guest still has in its queues are dropped.
+ * The consoles of the VM config can not be detached.
+ */
+pub fn unplug_console(vmid: usize, base_ipa: usize) -> Result<usize, ()> {
+    let vm = match vm_by_id(vmid) {
+        Some(vm) => vm,
+        None => {
+            error!("VM[{vmid}] unplug console: the VM is not created");
+            return Err(());
+        }
+    };
+    let console = match vm
+        .find_emu_dev(base_ipa)
+        .filter(|dev| dev.emu_type() == EmuDeviceType::EmuDeviceTVirtioConsole && dev.address_range().start == base_ipa)
+        .and_then(|dev| dev.into_any_arc().downcast::<VirtioMmio>().ok())
+    {
+        Some(console) => console,
+        None => {
+            error!("VM[{vmid}] unplug console: no console at ipa {:#x}", base_ipa);
+            return Err(());
+        }
+    };
+    let irq_id = console.dev().int_id();
+    if vm.unplug_emu_dev(base_ipa, irq_id).is_none() {
+        error!(
+            "VM[{vmid}] unplug console: the console at ipa {:#x} is not hot-plugged",
+            base_ipa
+        );
+        return Err(());
+    }
+    // the queues are reset and freed with the last reference to the device
+    console.dev_reset();
+    info!("VM[{vmid}] unplug console at ipa {:#x}", base_ipa);
+    Ok(0)
+}
+
 /**
  * Final Step for GVM configuration.
  * Set up GVM configuration;
  * Set VM kernel image load region;
  */
-fn vm_cfg_finish_configuration(vmid: usize, _img_size: usize) -> alloc::sync::Arc<Vm> {
-    // Set up GVM configuration.
-    vmm_init_gvm(vmid);
+fn vm_cfg_finish_configuration(vmid: usize, _img_size: usize) -> Result<Arc<Vm>, ()> {
+    match vm_cfg_entry(vmid) {
+        Some(config) if config.ramdisk_load_ipa_valid() => {}
+        _ => {
+            error!("VM[{}] ramdisk load ipa is out of its memory regions", vmid);
+            return Err(());
+        }
+    }
+    // Set up GVM configuration, it fails if another request is configuring the VM.
+    if !vmm_init_gvm(vmid) {
+        return Err(());
+    }
 
     // Get VM structure.
 
@@ -743,13 +1660,46 @@ fn vm_cfg_finish_configuration(vmid: usize, _img_size: usize) -> alloc::sync::Ar
         None => {
             panic!("vm_cfg_upload_kernel_image:failed to init VM[{}]", vmid);
         }
-        Some(vm) => vm,
+        Some(vm) => Ok(vm),
+    }
+}
+
+// bytes of the kernel image copied by one executor task
+const UPLOAD_CHUNK_SIZE: usize = 1 << 20;
+
+// a piece of the kernel image in the cache of MVM, copied by the executor
+struct ImageUploadChunk {
+    src_vm: Arc<Vm>,
+    src_ipa: usize,
+    dst_vm: Arc<Vm>,
+    dst_ipa: usize,
+    len: usize,
+}
+
+impl AsyncCallback for ImageUploadChunk {
+    fn preprocess(&self) {
+        // the chunk is smaller than the ones `copy_between_vm` is preempted at
+        match copy_between_vm((&self.dst_vm, self.dst_ipa), (&self.src_vm, self.src_ipa), self.len) {
+            Ok(copied) => vm_if_upload_done(self.dst_vm.id(), copied),
+            Err(_) => {
+                error!(
+                    "VM[{}] upload kernel image: failed to copy {:#x} bytes to ipa {:#x}",
+                    self.dst_vm.id(),
+                    self.len,
+                    self.dst_ipa
+                );
+                vm_if_upload_fail(self.dst_vm.id(), self.len);
+            }
+        }
     }
 }
 
 /**
  * Load kernel image file from MVM user space.
  * It's the last step in GVM configuration.
+ * The chunk is queued to the executor and copied piece by piece on its later runs, a chunk with
+ * load_offset 0 starts a new image. Return `load_offset + load_size`, MVM keeps the cache until
+ * HVC_CONFIG_UPLOAD_STATUS reports that many bytes copied.
  */
 pub fn upload_kernel_image(
     vmid: usize,
@@ -766,7 +1716,12 @@ pub fn upload_kernel_image(
                 vmid
             );
             // This code should only run once.
-            vm_cfg_finish_configuration(vmid, img_size)
+            vm_cfg_finish_configuration(vmid, img_size)?
+        }
+        // another request is still setting it up, its memory is not mapped yet
+        Some(_) if vm_if_boot_state(vmid) == VmBootState::Configuring => {
+            error!("VM[{}] upload kernel image: the VM is being configured", vmid);
+            return Err(());
         }
         Some(vm) => vm,
     };
@@ -776,13 +1731,120 @@ pub fn upload_kernel_image(
         "VM[{}] Upload kernel image. cache_ipa:{:x} load_offset:{:x} load_size:{:x}",
         vmid, cache_ipa, load_offset, load_size
     );
-    if copy_between_vm(
-        (&vm, config.kernel_load_ipa() + load_offset),
-        (&active_vm().unwrap(), cache_ipa),
-        load_size,
-    ) {
-        Ok(0)
+    let mvm = active_vm().unwrap();
+    let load_ipa = config.kernel_load_ipa().wrapping_add(load_offset);
+    if vm_ipa2hva(&vm, load_ipa, load_size).is_err() || vm_ipa2hva(&mvm, cache_ipa, load_size).is_err() {
+        error!(
+            "VM[{}] upload kernel image: illegal load_offset {:#x} or cache_ipa {:#x}",
+            vmid, load_offset, cache_ipa
+        );
+        return Err(());
+    }
+    if load_offset == 0 && !vm_if_upload_start(vmid, img_size) {
+        error!(
+            "VM[{}] upload kernel image: the previous image is still being copied",
+            vmid
+        );
+        return Err(());
+    }
+    for offset in (0..load_size).step_by(UPLOAD_CHUNK_SIZE) {
+        let chunk = ImageUploadChunk {
+            src_vm: mvm.clone(),
+            src_ipa: cache_ipa + offset,
+            dst_vm: vm.clone(),
+            dst_ipa: load_ipa + offset,
+            len: (load_size - offset).min(UPLOAD_CHUNK_SIZE),
+        };
+        vm_if_upload_queue(vmid, chunk.len);
+        EXECUTOR.add_task(
+            AsyncTask::new(chunk, vmid, ASYNC_UPLOAD_ID, async_ipi_req(ASYNC_UPLOAD_ID)),
+            true,
+        );
+    }
+    // the first piece is copied now
+    EXECUTOR.exec();
+    Ok(load_offset + load_size)
+}
+
+/* HVC_CONFIG_UPLOAD_STATUS: copy the next piece of the queued kernel image of VM `vmid`,
+ * load the elf image once it is all copied, and return the progress, percent ~ (7, 0), copied bytes ~ (63, 8).
+ * It fails once a chunk could not be copied, MVM starts the image again at load_offset 0.
+ */
+pub fn upload_status(vmid: usize) -> Result<usize, ()> {
+    let vm = match vm_by_id(vmid) {
+        Some(vm) => vm,
+        None => {
+            error!("VM[{}] upload status: the VM is not created", vmid);
+            return Err(());
+        }
+    };
+    EXECUTOR.exec();
+    let upload = vm_if_upload(vmid);
+    if upload.failed != 0 {
+        error!(
+            "VM[{}] upload status: {:#x} bytes of the kernel image failed to copy, upload it again",
+            vmid, upload.failed
+        );
+        return Err(());
+    }
+    // a malformed elf image fails here, before MVM boots the VM
+    vmm_load_uploaded_image(&vm)?;
+    Ok(upload.percent() | (upload.copied << 8))
+}
+
+/**
+ * Load ramdisk image file from MVM user space into ramdisk_load_ipa.
+ * The image is uploaded in chunks like the kernel image, a chunk with load_offset 0 starts a new image.
+ * The device tree is regenerated so that the chosen node covers the uploaded length.
+ * Return the bytes of the chunk not copied yet when the copy is preempted, MVM uploads them again
+ * at `load_offset + load_size - ret`, 0 means the chunk is done.
+ */
+pub fn upload_ramdisk_image(vmid: usize, cache_ipa: usize, load_offset: usize, load_size: usize) -> Result<usize, ()> {
+    let vm = match vm_by_id(vmid) {
+        None => {
+            info!(
+                "Successfully add configuration file for VM [{}]\n>>> Start to init...",
+                vmid
+            );
+            vm_cfg_finish_configuration(vmid, 0)?
+        }
+        Some(_) if vm_if_boot_state(vmid) == VmBootState::Configuring => {
+            error!("VM[{}] upload ramdisk image: the VM is being configured", vmid);
+            return Err(());
+        }
+        Some(vm) => vm,
+    };
+    let config = vm.config();
+
+    if config.ramdisk_load_ipa() == 0 {
+        error!("VM[{}] upload ramdisk image: ramdisk load ipa is not set", vmid);
+        return Err(());
+    }
+    let load_ipa = match config.ramdisk_load_ipa().checked_add(load_offset) {
+        Some(ipa) if vm.ipa_range_valid(ipa, load_size) => ipa,
+        _ => {
+            error!(
+                "VM[{}] upload ramdisk image: load_offset {:#x} load_size {:#x} out of memory regions",
+                vmid, load_offset, load_size
+            );
+            return Err(());
+        }
+    };
+
+    info!(
+        "VM[{}] Upload ramdisk image. cache_ipa:{:x} load_offset:{:x} load_size:{:x}",
+        vmid, cache_ipa, load_offset, load_size
+    );
+    let copied = copy_between_vm((&vm, load_ipa), (&active_vm().unwrap(), cache_ipa), load_size).map_err(|_| ())?;
+
+    let size = if load_offset == 0 {
+        copied
     } else {
-        Err(())
+        vm.ramdisk_size().max(load_offset + copied)
+    };
+    vm.set_ramdisk_size(size);
+    if config.os_type == VmType::VmTOs && config.device_tree_load_ipa() != 0 && !vmm_setup_fdt(&vm) {
+        return Err(());
     }
+    Ok(load_size - copied)
 }
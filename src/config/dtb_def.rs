@@ -0,0 +1,354 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::arch::PAGE_SIZE;
+use crate::board::PLAT_DESC;
+use crate::config::{vm_cfg_add_vm_entry, vm_cfg_remove_vm_entry};
+use crate::device::{mediated_blk_acquire, mediated_blk_free, mediated_blk_num, EmuDeviceType};
+use crate::dtb::{fdt_parse, FdtNode};
+use crate::kernel::VmType;
+
+use super::{
+    DtbDevType, PassthroughRegion, SmcPolicy, SmpBoot, VMDtbDevConfigList, VmConfigEntry, VmCpuConfig, VmDtbDevConfig,
+    VmEmulatedDeviceConfig, VmEmulatedDeviceConfigList, VmImageConfig, VmMemoryConfig, VmPassthroughDeviceConfig,
+    VmRegion, VmSmcConfig,
+};
+
+/* Guest configs linked into the hypervisor image, see dts/vm_config.dts for the binding.
+ * Addresses and sizes are always 64-bit (two cells), other values are single cells.
+ */
+static VM_CONFIG_DTB: &[u8] = include_bytes!(env!("VM_CONFIG_DTB"));
+
+fn unknown_node(path: &str) -> Result<(), ()> {
+    error!("vm config dtb: unknown node {}", path);
+    Err(())
+}
+
+fn missing_prop(path: &str, prop: &str) {
+    error!("vm config dtb: node {} has no valid property \"{}\"", path, prop);
+}
+
+// the enums of the config are numbered from 0 to `max`
+fn prop_enum<T: From<usize>>(node: &FdtNode, path: &str, prop: &str, max: usize) -> Result<T, ()> {
+    match node.prop_u32(prop) {
+        Some(val) if val <= max => Ok(T::from(val)),
+        _ => {
+            missing_prop(path, prop);
+            Err(())
+        }
+    }
+}
+
+// `reg = <ipa length>`
+fn prop_region(node: &FdtNode, path: &str) -> Result<VmRegion, ()> {
+    match node.prop_u64_list("reg").as_deref() {
+        Some(&[ipa_start, length]) => Ok(VmRegion { ipa_start, length }),
+        _ => {
+            missing_prop(path, "reg");
+            Err(())
+        }
+    }
+}
+
+fn parse_cpu(node: &FdtNode, path: &str) -> Result<VmCpuConfig, ()> {
+    if let Some(child) = node.children.first() {
+        unknown_node(&format!("{}/{}", path, child.name))?;
+    }
+    let num = match node.prop_u32("num") {
+        Some(num) if num != 0 => num,
+        _ => {
+            missing_prop(path, "num");
+            return Err(());
+        }
+    };
+    // a bit for each core the VM may run on, all of them cores of the platform
+    let allocate_bitmap = match node.prop_u32("allocate-bitmap") {
+        Some(bitmap) if bitmap != 0 && bitmap >> PLAT_DESC.cpu_desc.num == 0 => bitmap,
+        _ => {
+            missing_prop(path, "allocate-bitmap");
+            return Err(());
+        }
+    };
+    let smp_boot = match node.prop_u64("spin-table") {
+        Some(release_ipa) if release_ipa != 0 && release_ipa % PAGE_SIZE == 0 => SmpBoot::SpinTable(release_ipa),
+        Some(_) => {
+            missing_prop(path, "spin-table");
+            return Err(());
+        }
+        None => SmpBoot::Psci,
+    };
+    // clamped to the cores of the bitmap as the hvc config does, a master out of it is dropped
+    Ok(VmCpuConfig {
+        sched_rt: node.prop("sched-rt").is_some(),
+        sched_weight: node.prop_u32("sched-weight").unwrap_or(0),
+        sched_slice_us: node.prop_u32("sched-slice-us").unwrap_or(0),
+        smp_boot,
+        ..VmCpuConfig::with_vcpus_per_core(
+            num,
+            allocate_bitmap,
+            node.prop_u32("master").unwrap_or(usize::MAX),
+            node.prop_u32("vcpus-per-core").unwrap_or(1),
+        )
+    })
+}
+
+fn parse_memory(node: &FdtNode, path: &str) -> Result<VmMemoryConfig, ()> {
+    if let Some(child) = node.children.first() {
+        unknown_node(&format!("{}/{}", path, child.name))?;
+    }
+    let region = match node.prop_u64_list("regions") {
+        Some(regions) if !regions.is_empty() && regions.len() % 2 == 0 => regions
+            .chunks_exact(2)
+            .map(|region| VmRegion {
+                ipa_start: region[0],
+                length: region[1],
+            })
+            .collect(),
+        _ => {
+            missing_prop(path, "regions");
+            return Err(());
+        }
+    };
+    Ok(VmMemoryConfig {
+        region,
+        colors: node.prop_u32_list("colors").unwrap_or_default(),
+        strict_colors: node.prop("strict-colors").is_some(),
+        eager_map: node.prop("eager-map").is_some(),
+        max_size: node.prop_u64("max-size").unwrap_or(0),
+        ..Default::default()
+    })
+}
+
+fn parse_image(node: &FdtNode<'static>, path: &str) -> Result<VmImageConfig, ()> {
+    if let Some(child) = node.children.first() {
+        unknown_node(&format!("{}/{}", path, child.name))?;
+    }
+    let kernel_load_ipa = node
+        .prop_u64("kernel-load-ipa")
+        .ok_or_else(|| missing_prop(path, "kernel-load-ipa"))?;
+    Ok(VmImageConfig {
+        kernel_img_name: node.prop_str("kernel-name"),
+        kernel_load_ipa,
+        kernel_entry_point: node.prop_u64("entry-point").unwrap_or(kernel_load_ipa),
+        device_tree_load_ipa: node
+            .prop_u64("dtb-load-ipa")
+            .ok_or_else(|| missing_prop(path, "dtb-load-ipa"))?,
+        ramdisk_filename: node.prop_str("ramdisk-name"),
+        ramdisk_load_ipa: node.prop_u64("ramdisk-load-ipa").unwrap_or(0),
+        boot_info_load_ipa: node.prop_u64("boot-info-load-ipa").unwrap_or(0),
+    })
+}
+
+fn parse_emulated_devices(node: &FdtNode, path: &str) -> Result<Vec<VmEmulatedDeviceConfig>, ()> {
+    let mut emu_dev_list = Vec::new();
+    for dev in node.children.iter() {
+        let dev_path = format!("{}/{}", path, dev.name);
+        if let Some(child) = dev.children.first() {
+            unknown_node(&format!("{}/{}", dev_path, child.name))?;
+        }
+        let region = prop_region(dev, &dev_path)?;
+        let emu_cfg = VmEmulatedDeviceConfig {
+            name: String::from(dev.name),
+            base_ipa: region.ipa_start,
+            length: region.length,
+            irq_id: dev.prop_u32("interrupts").unwrap_or(0),
+            cfg_list: dev.prop_u32_list("cfg-list").unwrap_or_default(),
+            emu_type: prop_enum(dev, &dev_path, "emu-type", EmuDeviceType::EmuDeviceTPl011 as usize)?,
+            mediated: dev.prop("mediated").is_some(),
+        };
+        if !emu_cfg.irq_valid() {
+            error!("vm config dtb: node {} irq {} is not an SPI", dev_path, emu_cfg.irq_id);
+            return Err(());
+        }
+        emu_dev_list.push(emu_cfg);
+    }
+    Ok(emu_dev_list)
+}
+
+fn parse_passthrough(node: &FdtNode, path: &str) -> Result<VmPassthroughDeviceConfig, ()> {
+    let mut regions = Vec::new();
+    for region_node in node.children.iter() {
+        let region_path = format!("{}/{}", path, region_node.name);
+        if region_node.base_name() != "region" {
+            unknown_node(&region_path)?;
+        }
+        if let Some(child) = region_node.children.first() {
+            unknown_node(&format!("{}/{}", region_path, child.name))?;
+        }
+        let region = prop_region(region_node, &region_path)?;
+        regions.push(PassthroughRegion {
+            ipa: region.ipa_start,
+            pa: region_node.prop_u64("pa").unwrap_or(region.ipa_start),
+            length: region.length,
+            dev_property: region_node.prop("device").is_some(),
+        });
+    }
+    Ok(VmPassthroughDeviceConfig {
+        regions,
+        irqs: node.prop_u32_list("irqs").unwrap_or_default(),
+        streams_ids: node.prop_u32_list("streams-ids").unwrap_or_default(),
+    })
+}
+
+fn parse_dtb_devices(node: &FdtNode, path: &str) -> Result<Vec<VmDtbDevConfig>, ()> {
+    let mut dtb_device_list = Vec::new();
+    for dev in node.children.iter() {
+        let dev_path = format!("{}/{}", path, dev.name);
+        if let Some(child) = dev.children.first() {
+            unknown_node(&format!("{}/{}", dev_path, child.name))?;
+        }
+        dtb_device_list.push(VmDtbDevConfig {
+            name: String::from(dev.name),
+            dev_type: prop_enum(dev, &dev_path, "dev-type", DtbDevType::Gicc as usize)?,
+            irqs: dev.prop_u32_list("interrupts").unwrap_or_default(),
+            addr_region: prop_region(dev, &dev_path)?,
+        });
+    }
+    Ok(dtb_device_list)
+}
+
+// `smc-policy = <n>` and `smc-allowlist = <start end ...>`, both optional
+fn parse_smc(node: &FdtNode, path: &str) -> Result<VmSmcConfig, ()> {
+    let mut smc = VmSmcConfig::default();
+    if node.prop_u32_list("smc-policy").is_some() {
+        smc.policy = prop_enum(node, path, "smc-policy", SmcPolicy::Passthrough as usize)?;
+    }
+    if let Some(list) = node.prop_u32_list("smc-allowlist") {
+        if smc.policy != SmcPolicy::Passthrough || list.len() % 2 != 0 {
+            missing_prop(path, "smc-allowlist");
+            return Err(());
+        }
+        for range in list.chunks_exact(2) {
+            if smc.add_range(range[0], range[1]).is_err() {
+                missing_prop(path, "smc-allowlist");
+                return Err(());
+            }
+        }
+    }
+    Ok(smc)
+}
+
+fn parse_vm(node: &FdtNode<'static>, path: &str) -> Result<VmConfigEntry, ()> {
+    let mut config = VmConfigEntry {
+        name: String::from(node.prop_str("name").ok_or_else(|| missing_prop(path, "name"))?),
+        os_type: prop_enum(node, path, "os-type", VmType::VmTBma as usize)?,
+        cmdline: String::from(node.prop_str("cmdline").unwrap_or_default()),
+        mediated_block_index: node.prop_u32_list("mediated-block-index").unwrap_or_default(),
+        halt_poll_ticks: node.prop_u32("halt-poll-ticks").unwrap_or(0),
+        smc: parse_smc(node, path)?,
+        ..Default::default()
+    };
+    let (mut cpu, mut memory, mut image) = (false, false, false);
+    for child in node.children.iter() {
+        let child_path = format!("{}/{}", path, child.name);
+        match child.name {
+            "cpu" => {
+                config.cpu = parse_cpu(child, &child_path)?;
+                cpu = true;
+            }
+            "memory" => {
+                config.memory = parse_memory(child, &child_path)?;
+                memory = true;
+            }
+            "image" => {
+                config.image = parse_image(child, &child_path)?;
+                image = true;
+            }
+            "emulated-devices" => {
+                config.vm_emu_dev_confg = VmEmulatedDeviceConfigList {
+                    emu_dev_list: parse_emulated_devices(child, &child_path)?,
+                };
+            }
+            "passthrough" => config.vm_pt_dev_confg = parse_passthrough(child, &child_path)?,
+            "dtb-devices" => {
+                config.vm_dtb_devs = VMDtbDevConfigList {
+                    dtb_device_list: parse_dtb_devices(child, &child_path)?,
+                };
+            }
+            _ => unknown_node(&child_path)?,
+        }
+    }
+    for (present, name) in [(cpu, "cpu"), (memory, "memory"), (image, "image")] {
+        if !present {
+            error!("vm config dtb: node {} has no {} node", path, name);
+            return Err(());
+        }
+    }
+    // the release page is checked once the memory and devices are known
+    if let SmpBoot::SpinTable(release_ipa) = config.cpu.smp_boot {
+        if !config.spin_table_valid(release_ipa) {
+            error!(
+                "vm config dtb: node {} spin-table release page {:#x} is not free ipa",
+                path, release_ipa
+            );
+            return Err(());
+        }
+    }
+    Ok(config)
+}
+
+/* Add the guest configs in VM_CONFIG_DTB, return their VM ids.
+ * Nothing is added if any node is invalid.
+ */
+/* A mediated blk goes to one guest at most. The blks VM0 has appended already are claimed here,
+ * the others are assigned when VM0 appends them, see `mediated_blk_list_push`.
+ * Returns the blks claimed.
+ */
+fn claim_mediated_blks(entries: &[VmConfigEntry]) -> Result<Vec<usize>, ()> {
+    let mut named: Vec<usize> = Vec::new();
+    let mut claimed = Vec::new();
+    for entry in entries.iter() {
+        for &idx in entry.mediated_block_index() {
+            if named.contains(&idx) || (idx < mediated_blk_num() && !mediated_blk_acquire(idx)) {
+                error!(
+                    "vm config dtb: guest {} mediated blk {} is taken already",
+                    entry.name, idx
+                );
+                for &idx in claimed.iter() {
+                    mediated_blk_free(idx);
+                }
+                return Err(());
+            }
+            named.push(idx);
+            if idx < mediated_blk_num() {
+                claimed.push(idx);
+            }
+        }
+    }
+    Ok(claimed)
+}
+
+pub fn dtb_config_init() -> Result<Vec<usize>, ()> {
+    let root = fdt_parse(VM_CONFIG_DTB)?;
+    if root.prop_str("compatible") != Some("shyper,vm-config") {
+        error!("vm config dtb: root is not compatible with \"shyper,vm-config\"");
+        return Err(());
+    }
+    let mut entries = Vec::new();
+    for node in root.children.iter() {
+        let path = format!("/{}", node.name);
+        if node.base_name() != "vm" {
+            unknown_node(&path)?;
+        }
+        entries.push(parse_vm(node, &path)?);
+    }
+    info!("vm config dtb: {} guest configs", entries.len());
+    let claimed = claim_mediated_blks(&entries)?;
+    let mut vm_ids = Vec::new();
+    for entry in entries {
+        match vm_cfg_add_vm_entry(entry) {
+            Ok(vm_id) => vm_ids.push(vm_id),
+            Err(()) => {
+                // e.g. a MAC or uart clashing with a guest added before, take those out again
+                for vm_id in vm_ids {
+                    vm_cfg_remove_vm_entry(vm_id);
+                }
+                for idx in claimed {
+                    mediated_blk_free(idx);
+                }
+                return Err(());
+            }
+        }
+    }
+    Ok(vm_ids)
+}
@@ -15,6 +15,11 @@ cfg_if::cfg_if! {
     }
 }
 
+#[cfg(feature = "dtb-config")]
+pub use self::dtb_def::dtb_config_init;
+#[cfg(feature = "dtb-config")]
+mod dtb_def;
+
 cfg_if::cfg_if! {
     if #[cfg(feature = "static-config")] {
         pub use self::vm_def::*;
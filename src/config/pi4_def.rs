@@ -9,7 +9,7 @@ use crate::kernel::{VmType, HVC_IRQ, HYPERVISOR_COLORS};
 
 use super::{
     PassthroughRegion, VMDtbDevConfigList, VmConfigEntry, VmCpuConfig, VmEmulatedDeviceConfig,
-    VmEmulatedDeviceConfigList, VmImageConfig, VmMemoryConfig, VmPassthroughDeviceConfig, VmRegion,
+    VmEmulatedDeviceConfigList, VmImageConfig, VmMemoryConfig, VmPassthroughDeviceConfig, VmRegion, VmSmcConfig,
 };
 
 #[rustfmt::skip]
@@ -168,7 +168,9 @@ pub fn mvm_config_init() {
             kernel_load_ipa: 0x280000,
             kernel_entry_point: 0x280000,
             device_tree_load_ipa: 0x10000000,
+            ramdisk_filename: None,
             ramdisk_load_ipa: 0,
+            boot_info_load_ipa: 0,
         },
         memory: VmMemoryConfig {
             region: vm_region,
@@ -179,11 +181,14 @@ pub fn mvm_config_init() {
             num: 1,
             allocate_bitmap: 0b0001,
             master: Some(0),
+            ..Default::default()
         },
         vm_emu_dev_confg: VmEmulatedDeviceConfigList{emu_dev_list: emu_dev_config,},
         vm_pt_dev_confg: pt_dev_config,
         vm_dtb_devs: VMDtbDevConfigList::default(),
-        mediated_block_index: None,
+        mediated_block_index: vec![],
+        halt_poll_ticks: 0,
+        smc: VmSmcConfig::default(),
     };
     vm_cfg_add_vm_entry(mvm_config_entry).unwrap();
 }
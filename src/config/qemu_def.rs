@@ -7,7 +7,7 @@ use crate::kernel::{VmType, HVC_IRQ, HYPERVISOR_COLORS};
 
 use super::{
     vm_cfg_add_vm_entry, PassthroughRegion, VMDtbDevConfigList, VmConfigEntry, VmCpuConfig, VmEmulatedDeviceConfig,
-    VmEmulatedDeviceConfigList, VmImageConfig, VmMemoryConfig, VmPassthroughDeviceConfig, VmRegion,
+    VmEmulatedDeviceConfigList, VmImageConfig, VmMemoryConfig, VmPassthroughDeviceConfig, VmRegion, VmSmcConfig,
 };
 
 #[rustfmt::skip]
@@ -106,14 +106,16 @@ pub fn mvm_config_init() {
             kernel_entry_point: 0x80080000,
             // device_tree_filename: Some("qemu1.bin"),
             device_tree_load_ipa: 0x80000000,
-            // ramdisk_filename: Some("initrd.gz"),
             // ramdisk_load_ipa: 0x53000000,
+            ramdisk_filename: None,
             ramdisk_load_ipa: 0,
+            boot_info_load_ipa: 0,
         },
         cpu: VmCpuConfig {
             num: 4,
             allocate_bitmap: 0b1111,
             master: None,
+            ..Default::default()
         },
         memory: VmMemoryConfig {
             region: vm_region,
@@ -123,7 +125,9 @@ pub fn mvm_config_init() {
         vm_emu_dev_confg: VmEmulatedDeviceConfigList { emu_dev_list: emu_dev_config },
         vm_pt_dev_confg: pt_dev_config,
         vm_dtb_devs: VMDtbDevConfigList::default(),
-        mediated_block_index: None,
+        mediated_block_index: vec![],
+        halt_poll_ticks: 0,
+        smc: VmSmcConfig::default(),
     };
     vm_cfg_add_vm_entry(mvm_config_entry).unwrap();
 }
@@ -9,7 +9,7 @@ use crate::kernel::{VmType, HVC_IRQ};
 
 use super::{
     PassthroughRegion, VMDtbDevConfigList, VmConfigEntry, VmCpuConfig, VmEmulatedDeviceConfig,
-    VmEmulatedDeviceConfigList, VmImageConfig, VmMemoryConfig, VmPassthroughDeviceConfig, VmRegion,
+    VmEmulatedDeviceConfigList, VmImageConfig, VmMemoryConfig, VmPassthroughDeviceConfig, VmRegion, VmSmcConfig,
 };
 
 #[rustfmt::skip]
@@ -78,7 +78,7 @@ pub fn mvm_config_init() {
         //     length: 0x1000,
         //     irq_id: 32 + 0x103,
         //     cfg_list: vec![1 << 20], // 1MB
-        //     emu_type: EmuDeviceType::VirtioBalloon,
+        //     emu_type: EmuDeviceType::EmuDeviceTVirtioBalloon,
         //     mediated: false,
         // },
     ];
@@ -250,7 +250,9 @@ pub fn mvm_config_init() {
             kernel_load_ipa: 0xa0080000,
             kernel_entry_point: 0xa0080000,
             device_tree_load_ipa: 0xa0000000,
+            ramdisk_filename: None,
             ramdisk_load_ipa: 0,
+            boot_info_load_ipa: 0,
         },
         memory: VmMemoryConfig {
             region: vm_region,
@@ -261,11 +263,14 @@ pub fn mvm_config_init() {
             num: 1,
             allocate_bitmap: 0b0001,
             master: Some(0),
+            ..Default::default()
         },
         vm_emu_dev_confg: VmEmulatedDeviceConfigList { emu_dev_list: emu_dev_config },
         vm_pt_dev_confg: pt_dev_config,
         vm_dtb_devs: VMDtbDevConfigList::default(),
-        mediated_block_index: None,
+        mediated_block_index: vec![],
+        halt_poll_ticks: 0,
+        smc: VmSmcConfig::default(),
     };
     vm_cfg_add_vm_entry(mvm_config_entry).unwrap();
 }
@@ -340,7 +345,9 @@ pub fn unishyper_config_init() {
             kernel_load_ipa: 0x40080000,
             kernel_entry_point: 0x40080000,
             device_tree_load_ipa: 0,
+            ramdisk_filename: None,
             ramdisk_load_ipa: 0,
+            boot_info_load_ipa: 0,
         },
         memory: VmMemoryConfig {
             region: vm_region,
@@ -351,13 +358,16 @@ pub fn unishyper_config_init() {
             num: 1,
             allocate_bitmap: 0b0001,
             master: Some(0),
+            ..Default::default()
         },
         vm_emu_dev_confg: VmEmulatedDeviceConfigList {
             emu_dev_list: emu_dev_config,
         },
         vm_pt_dev_confg: pt_dev_config,
         vm_dtb_devs: VMDtbDevConfigList::default(),
-        mediated_block_index: None,
+        mediated_block_index: vec![],
+        halt_poll_ticks: 0,
+        smc: VmSmcConfig::default(),
     };
     vm_cfg_add_vm_entry(mvm_config_entry).unwrap();
 }
@@ -10,9 +10,17 @@ use crate::kernel::VmType;
 use super::{
     DtbDevType, PassthroughRegion, VMDtbDevConfigList, VmConfigEntry, VmCpuConfig, VmDtbDevConfig,
     VmEmulatedDeviceConfig, VmEmulatedDeviceConfigList, VmImageConfig, VmMemoryConfig, VmPassthroughDeviceConfig,
-    VmRegion,
+    VmRegion, VmSmcConfig,
 };
 
+// the guest memory of the bma configs
+pub fn bma_vm_region() -> Vec<VmRegion> {
+    vec![VmRegion {
+        ipa_start: 0x40000000,
+        length: 0x40000000,
+    }]
+}
+
 pub fn init_tmp_config_for_bma1() {
     info!("init_tmp_config_for_bma1");
     // #################### bare metal app emu (vm1) ######################
@@ -55,11 +63,7 @@ pub fn init_tmp_config_for_bma1() {
     pt_dev_config.irqs = vec![Platform::UART_1_INT];
 
     // bma vm_region
-    let mut vm_region: Vec<VmRegion> = Vec::new();
-    vm_region.push(VmRegion {
-        ipa_start: 0x40000000,
-        length: 0x40000000,
-    });
+    let vm_region = bma_vm_region();
 
     // bma config
     let bma_config = VmConfigEntry {
@@ -76,20 +80,26 @@ pub fn init_tmp_config_for_bma1() {
             kernel_load_ipa: 0x40080000,
             kernel_entry_point: 0x40080000,
             device_tree_load_ipa: 0,
+            ramdisk_filename: None,
             ramdisk_load_ipa: 0,
+            // the boot info goes below the image as there is no dtb
+            boot_info_load_ipa: 0x40000000,
         },
         cpu: VmCpuConfig {
             num: 1,
             allocate_bitmap: 0b0010,
             master: Some(1),
+            ..Default::default()
         },
         vm_emu_dev_confg: VmEmulatedDeviceConfigList {
             emu_dev_list: emu_dev_config,
         },
         vm_pt_dev_confg: pt_dev_config,
         vm_dtb_devs: VMDtbDevConfigList::default(),
-        cmdline: String::from(""),
-        mediated_block_index: None,
+        cmdline: String::from("console=uart1 blk=virtio"),
+        mediated_block_index: vec![],
+        halt_poll_ticks: 0,
+        smc: VmSmcConfig::default(),
     };
     let _ = vm_cfg_add_vm_entry(bma_config);
 }
@@ -136,11 +146,7 @@ pub fn init_tmp_config_for_bma2() {
     // pt_dev_config.irqs = vec![UART_1_INT];
 
     // bma vm_region
-    let mut vm_region: Vec<VmRegion> = Vec::new();
-    vm_region.push(VmRegion {
-        ipa_start: 0x40000000,
-        length: 0x40000000,
-    });
+    let vm_region = bma_vm_region();
 
     // bma config
     let bma_config = VmConfigEntry {
@@ -156,21 +162,27 @@ pub fn init_tmp_config_for_bma2() {
             kernel_img_name: None,
             kernel_load_ipa: 0x40080000,
             kernel_entry_point: 0x40080000,
-            device_tree_load_ipa: 0,
+            // a bma guest gets its boot info at the dtb ipa
+            device_tree_load_ipa: 0x40000000,
+            ramdisk_filename: None,
             ramdisk_load_ipa: 0,
+            boot_info_load_ipa: 0,
         },
         cpu: VmCpuConfig {
             num: 1,
             allocate_bitmap: 0b0100,
             master: Some(2),
+            ..Default::default()
         },
         vm_emu_dev_confg: VmEmulatedDeviceConfigList {
             emu_dev_list: emu_dev_config,
         },
         vm_pt_dev_confg: pt_dev_config,
         vm_dtb_devs: VMDtbDevConfigList::default(),
-        cmdline: String::from(""),
-        mediated_block_index: None,
+        cmdline: String::from("console=uart1 blk=virtio"),
+        mediated_block_index: vec![],
+        halt_poll_ticks: 0,
+        smc: VmSmcConfig::default(),
     };
     let _ = vm_cfg_add_vm_entry(bma_config);
 }
@@ -219,6 +231,15 @@ pub fn init_tmp_config_for_vm1() {
         emu_type: EmuDeviceType::EmuDeviceTVirtioConsole,
         mediated: false,
     });
+    emu_dev_config.push(VmEmulatedDeviceConfig {
+        name: String::from("virtio_rng@a005000"),
+        base_ipa: 0xa005000,
+        length: 0x1000,
+        irq_id: 32 + 0x15,
+        cfg_list: Vec::new(),
+        emu_type: EmuDeviceType::EmuDeviceTVirtioRng,
+        mediated: false,
+    });
     // emu_dev_config.push(VmEmulatedDeviceConfig {
     //     name: String::from("vm_service"),
     //     base_ipa: 0,
@@ -297,7 +318,10 @@ pub fn init_tmp_config_for_vm1() {
             kernel_load_ipa: 0x80080000,
             kernel_entry_point: 0x80080000,
             device_tree_load_ipa: 0x80000000,
+            // option_env!("RAMDISK_PATH") to load the built-in ramdisk at ramdisk_load_ipa
+            ramdisk_filename: None,
             ramdisk_load_ipa: 0, //0x83000000,
+            boot_info_load_ipa: 0,
         },
         memory: VmMemoryConfig {
             region: vm_region,
@@ -308,6 +332,7 @@ pub fn init_tmp_config_for_vm1() {
             num: 1,
             allocate_bitmap: 0b0010,
             master: Some(1),
+            ..Default::default()
         },
         vm_emu_dev_confg: VmEmulatedDeviceConfigList {
             emu_dev_list: emu_dev_config,
@@ -316,7 +341,9 @@ pub fn init_tmp_config_for_vm1() {
         vm_dtb_devs: VMDtbDevConfigList {
             dtb_device_list: vm_dtb_devs,
         },
-        mediated_block_index: Some(0),
+        mediated_block_index: vec![0],
+        halt_poll_ticks: 0,
+        smc: VmSmcConfig::default(),
     };
     info!("generate tmp_config for vm1");
     let _ = vm_cfg_add_vm_entry(vm1_config);
@@ -432,7 +459,9 @@ pub fn init_tmp_config_for_vm2() {
             kernel_load_ipa: 0x80080000,
             kernel_entry_point: 0x80080000,
             device_tree_load_ipa: 0x80000000,
+            ramdisk_filename: None,
             ramdisk_load_ipa: 0, //0x83000000,
+            boot_info_load_ipa: 0,
         },
         memory: VmMemoryConfig {
             region: vm_region,
@@ -443,6 +472,7 @@ pub fn init_tmp_config_for_vm2() {
             num: 1,
             allocate_bitmap: 0b0100,
             master: Some(2),
+            ..Default::default()
         },
         vm_emu_dev_confg: VmEmulatedDeviceConfigList {
             emu_dev_list: emu_dev_config,
@@ -451,7 +481,9 @@ pub fn init_tmp_config_for_vm2() {
         vm_dtb_devs: VMDtbDevConfigList {
             dtb_device_list: vm_dtb_devs,
         },
-        mediated_block_index: Some(1),
+        mediated_block_index: vec![1],
+        halt_poll_ticks: 0,
+        smc: VmSmcConfig::default(),
     };
     let _ = vm_cfg_add_vm_entry(vm2_config);
 }
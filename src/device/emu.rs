@@ -1,16 +1,19 @@
 use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use alloc::vec::Vec;
 
 use spin::RwLock;
 
-use crate::kernel::{active_vm, current_cpu};
+use crate::kernel::{active_vm, current_cpu, exit_stat_refine, ExitClass};
 use crate::util::downcast::DowncastSync;
 
 pub trait EmuDev: DowncastSync {
     fn emu_type(&self) -> EmuDeviceType;
     fn address_range(&self) -> Range<usize>;
     fn handler(&self, emu_ctx: &EmuContext) -> bool;
+    // back to the state at creation for a rebooted guest, on the core of its rebooting vcpu
+    fn reset(&self) {}
 }
 
 pub struct EmuContext {
@@ -22,6 +25,107 @@ pub struct EmuContext {
     pub reg_width: usize,
 }
 
+// access widths of 1, 2, 4 and 8 bytes
+const EMU_STAT_WIDTH_NUM: usize = 4;
+// number of distinct ipas tracked per device, others are only counted by width
+const EMU_STAT_IPA_NUM: usize = 8;
+
+/// Trap counters of an emulated device, atomic so that the dispatch path is not serialized across cores
+#[derive(Default)]
+pub struct EmuDevStat {
+    read: [AtomicUsize; EMU_STAT_WIDTH_NUM],
+    write: [AtomicUsize; EMU_STAT_WIDTH_NUM],
+    // (ipa, count), ipa 0 means a free slot
+    ipa: [(AtomicUsize, AtomicUsize); EMU_STAT_IPA_NUM],
+    // ns spent from the dispatch to the end of the handler, in total and at most
+    #[cfg(feature = "emu-latency")]
+    latency_ns: AtomicUsize,
+    #[cfg(feature = "emu-latency")]
+    latency_max_ns: AtomicUsize,
+}
+
+impl EmuDevStat {
+    pub fn record(&self, emu_ctx: &EmuContext) {
+        let counters = if emu_ctx.write { &self.write } else { &self.read };
+        if let Some(counter) = counters.get(emu_ctx.width.trailing_zeros() as usize) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        for (ipa, count) in self.ipa.iter() {
+            match ipa.compare_exchange(0, emu_ctx.address, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => {}
+                Err(cur) if cur == emu_ctx.address => {}
+                Err(_) => continue,
+            }
+            count.fetch_add(1, Ordering::Relaxed);
+            break;
+        }
+    }
+
+    #[cfg(feature = "emu-latency")]
+    pub fn record_latency(&self, ns: usize) {
+        self.latency_ns.fetch_add(ns, Ordering::Relaxed);
+        self.latency_max_ns.fetch_max(ns, Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> usize {
+        self.read
+            .iter()
+            .chain(self.write.iter())
+            .map(|counter| counter.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn reset(&self) {
+        for counter in self.read.iter().chain(self.write.iter()) {
+            counter.store(0, Ordering::Relaxed);
+        }
+        for (ipa, count) in self.ipa.iter() {
+            ipa.store(0, Ordering::Relaxed);
+            count.store(0, Ordering::Relaxed);
+        }
+        #[cfg(feature = "emu-latency")]
+        {
+            self.latency_ns.store(0, Ordering::Relaxed);
+            self.latency_max_ns.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn dump(&self, emu_dev: &dyn EmuDev) {
+        let load = |counters: &[AtomicUsize]| {
+            counters
+                .iter()
+                .map(|counter| counter.load(Ordering::Relaxed))
+                .collect::<Vec<_>>()
+        };
+        let mut top_ipa = self
+            .ipa
+            .iter()
+            .map(|(ipa, count)| (ipa.load(Ordering::Relaxed), count.load(Ordering::Relaxed)))
+            .filter(|(ipa, _)| *ipa != 0)
+            .collect::<Vec<_>>();
+        top_ipa.sort_by(|a, b| b.1.cmp(&a.1));
+        println!(
+            "  {:?} {:#x?}: total {}, read(1/2/4/8B) {:?}, write(1/2/4/8B) {:?}",
+            emu_dev.emu_type(),
+            emu_dev.address_range(),
+            self.total(),
+            load(&self.read),
+            load(&self.write)
+        );
+        #[cfg(feature = "emu-latency")]
+        if self.total() != 0 {
+            println!(
+                "    latency avg {} ns, max {} ns",
+                self.latency_ns.load(Ordering::Relaxed) / self.total(),
+                self.latency_max_ns.load(Ordering::Relaxed)
+            );
+        }
+        for (ipa, count) in top_ipa {
+            println!("    ipa {:#x}: {}", ipa, count);
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum EmuDeviceType {
     EmuDeviceTConsole = 0,
@@ -33,7 +137,21 @@ pub enum EmuDeviceType {
     EmuDeviceTShyper = 6,
     EmuDeviceTVirtioBlkMediated = 7,
     EmuDeviceTIOMMU = 8,
-    VirtioBalloon = 9,
+    EmuDeviceTVirtioBalloon = 9,
+    EmuDeviceTVirtioRng = 10,
+    EmuDeviceTInfoPage = 11,
+    EmuDeviceTPvClock = 12,
+    EmuDeviceTPl011 = 13,
+}
+
+impl EmuDeviceType {
+    // the device takes [base_ipa, base_ipa + length) of the guest, the others have no registers there
+    pub fn has_mmio(&self) -> bool {
+        !matches!(
+            self,
+            EmuDeviceType::EmuDeviceTShyper | EmuDeviceType::EmuDeviceTIOMMU | EmuDeviceType::EmuDeviceTGPPT
+        )
+    }
 }
 
 impl From<usize> for EmuDeviceType {
@@ -48,7 +166,11 @@ impl From<usize> for EmuDeviceType {
             6 => EmuDeviceType::EmuDeviceTShyper,
             7 => EmuDeviceType::EmuDeviceTVirtioBlkMediated,
             8 => EmuDeviceType::EmuDeviceTIOMMU,
-            9 => EmuDeviceType::VirtioBalloon,
+            9 => EmuDeviceType::EmuDeviceTVirtioBalloon,
+            10 => EmuDeviceType::EmuDeviceTVirtioRng,
+            11 => EmuDeviceType::EmuDeviceTInfoPage,
+            12 => EmuDeviceType::EmuDeviceTPvClock,
+            13 => EmuDeviceType::EmuDeviceTPl011,
             _ => panic!("Unknown EmuDeviceType value: {}", value),
         }
     }
@@ -60,8 +182,17 @@ type EmuDevHandler = fn(usize, &EmuContext) -> bool;
 pub fn emu_handler(emu_ctx: &EmuContext) -> bool {
     let ipa = emu_ctx.address;
 
-    if let Some(emu_dev) = active_vm().unwrap().find_emu_dev(ipa) {
-        return emu_dev.handler(emu_ctx);
+    #[cfg(feature = "emu-latency")]
+    let begin = crate::arch::timer::gettime_ns();
+    let vm = active_vm().unwrap();
+    if let Some((emu_dev, _stat)) = vm.find_emu_dev_and_count(emu_ctx) {
+        if let Some(class) = ExitClass::from_emu_type(emu_dev.emu_type()) {
+            exit_stat_refine(class);
+        }
+        let ret = emu_dev.handler(emu_ctx);
+        #[cfg(feature = "emu-latency")]
+        _stat.record_latency(crate::arch::timer::gettime_ns() - begin);
+        return ret;
     }
 
     error!(
@@ -1,5 +1,7 @@
 pub use self::emu::*;
+pub use self::pl011::{emu_pl011_init, pl011_receive, pl011_ring_read};
 pub use self::virtio::*;
 
 mod emu;
+mod pl011;
 mod virtio;
@@ -0,0 +1,327 @@
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use spin::Mutex;
+
+use crate::config::VmEmulatedDeviceConfig;
+use crate::kernel::{current_cpu, interrupt_vm_inject_to, Vm};
+
+use super::{ConsoleRing, EmuContext, EmuDev, EmuDeviceType, CONSOLE_RING_VMID};
+
+const UARTDR: usize = 0x000;
+const UARTRSR: usize = 0x004;
+const UARTFR: usize = 0x018;
+const UARTILPR: usize = 0x020;
+const UARTIBRD: usize = 0x024;
+const UARTFBRD: usize = 0x028;
+const UARTLCR_H: usize = 0x02c;
+const UARTCR: usize = 0x030;
+const UARTIFLS: usize = 0x034;
+const UARTIMSC: usize = 0x038;
+const UARTRIS: usize = 0x03c;
+const UARTMIS: usize = 0x040;
+const UARTICR: usize = 0x044;
+const UARTDMACR: usize = 0x048;
+// UARTPeriphID0-3 and UARTPCellID0-3, probed by the amba bus
+const UARTID_BASE: usize = 0xfe0;
+const UART_ID: [u32; 8] = [0x11, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+const UARTFR_RXFE: u32 = 1 << 4;
+const UARTFR_RXFF: u32 = 1 << 6;
+const UARTFR_TXFE: u32 = 1 << 7;
+
+const UART_INT_RX: u32 = 1 << 4;
+const UART_INT_TX: u32 = 1 << 5;
+const UART_INT_MASK: u32 = 0x7ff;
+
+const PL011_FIFO_SIZE: usize = 32;
+const PL011_LINE_MAX: usize = 128;
+
+// where the guest output goes, cfg_list[0]
+enum Pl011Out {
+    // printed on the hypervisor console, one line at a time with the VM id
+    Console(Vec<u8>),
+    // kept in a ring read by VM0 with HVC_VMM_READ_CONSOLE
+    Ring(Option<ConsoleRing>),
+}
+
+struct Pl011Inner {
+    rx_fifo: VecDeque<u8>,
+    out: Pl011Out,
+    // raw interrupt status and mask
+    ris: u32,
+    imsc: u32,
+    ilpr: u32,
+    ibrd: u32,
+    fbrd: u32,
+    lcr_h: u32,
+    cr: u32,
+    ifls: u32,
+    dmacr: u32,
+}
+
+impl Pl011Inner {
+    fn new(out: Pl011Out) -> Self {
+        Self {
+            rx_fifo: VecDeque::with_capacity(PL011_FIFO_SIZE),
+            out,
+            ris: 0,
+            imsc: 0,
+            ilpr: 0,
+            ibrd: 0,
+            fbrd: 0,
+            lcr_h: 0,
+            // UARTEN off, TXE and RXE on, the reset value
+            cr: 0x300,
+            ifls: 0x12,
+            dmacr: 0,
+        }
+    }
+
+    // the output kept in the ring is still there for VM0 to read
+    fn reset(&mut self) {
+        let mut out = core::mem::replace(&mut self.out, Pl011Out::Ring(None));
+        if let Pl011Out::Console(line) = &mut out {
+            line.clear();
+        }
+        *self = Self::new(out);
+    }
+
+    fn flags(&self) -> u32 {
+        let mut fr = UARTFR_TXFE;
+        if self.rx_fifo.is_empty() {
+            fr |= UARTFR_RXFE;
+        }
+        if self.rx_fifo.len() >= PL011_FIFO_SIZE {
+            fr |= UARTFR_RXFF;
+        }
+        fr
+    }
+
+    fn mis(&self) -> u32 {
+        self.ris & self.imsc
+    }
+
+    fn transmit(&mut self, vm_id: usize, c: u8) {
+        match &mut self.out {
+            Pl011Out::Console(line) => {
+                if c != b'\n' && c != b'\r' {
+                    line.push(c);
+                }
+                if c == b'\n' || line.len() >= PL011_LINE_MAX {
+                    println!(
+                        "VM[{}] pl011: {}",
+                        vm_id,
+                        core::str::from_utf8(line).unwrap_or("<not utf-8>")
+                    );
+                    line.clear();
+                }
+            }
+            Pl011Out::Ring(Some(ring)) => ring.push(&c as *const u8 as usize, 1),
+            Pl011Out::Ring(None) => {}
+        }
+    }
+}
+
+/* An emulated PL011 for guests without a uart of their own. The tx fifo is always empty, every
+ * byte written goes out at once; the rx fifo is filled by VM0 with HVC_VMM_WRITE_CONSOLE.
+ * The level of the interrupt is not tracked by the vgic, so it is injected whenever a source
+ * is raised while unmasked.
+ */
+pub struct EmuPl011 {
+    base: usize,
+    length: usize,
+    irq_id: usize,
+    vm: Weak<Vm>,
+    inner: Mutex<Pl011Inner>,
+}
+
+impl EmuPl011 {
+    fn vm_id(&self) -> usize {
+        self.vm.upgrade().map_or(0, |vm| vm.id())
+    }
+
+    fn inject(&self) {
+        let vm = match self.vm.upgrade() {
+            Some(vm) => vm,
+            None => return,
+        };
+        let target_vcpu = match vm.vcpu(0) {
+            Some(vcpu) => vcpu,
+            None => return,
+        };
+        interrupt_vm_inject_to(&vm, target_vcpu, self.irq_id);
+    }
+
+    // queue input for the guest, return the bytes taken before the rx fifo is full
+    pub fn receive(&self, data: &[u8]) -> usize {
+        let mut inner = self.inner.lock();
+        let len = data.len().min(PL011_FIFO_SIZE - inner.rx_fifo.len());
+        inner.rx_fifo.extend(&data[..len]);
+        if len > 0 {
+            inner.ris |= UART_INT_RX;
+        }
+        let raise = inner.mis() != 0;
+        drop(inner);
+        if raise {
+            self.inject();
+        }
+        len
+    }
+
+    // (bytes overwritten since the last read, bytes copied to `out`), if the output goes to a ring
+    pub fn ring_read(&self, out: &mut [u8]) -> Option<(usize, usize)> {
+        match &mut self.inner.lock().out {
+            Pl011Out::Ring(Some(ring)) => Some(ring.read(out)),
+            _ => None,
+        }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        let mut inner = self.inner.lock();
+        match offset {
+            UARTDR => {
+                let c = inner.rx_fifo.pop_front().unwrap_or(0);
+                if inner.rx_fifo.is_empty() {
+                    inner.ris &= !UART_INT_RX;
+                }
+                c as u32
+            }
+            UARTRSR => 0,
+            UARTFR => inner.flags(),
+            UARTILPR => inner.ilpr,
+            UARTIBRD => inner.ibrd,
+            UARTFBRD => inner.fbrd,
+            UARTLCR_H => inner.lcr_h,
+            UARTCR => inner.cr,
+            UARTIFLS => inner.ifls,
+            UARTIMSC => inner.imsc,
+            UARTRIS => inner.ris,
+            UARTMIS => inner.mis(),
+            UARTDMACR => inner.dmacr,
+            UARTID_BASE..=0xffc => UART_ID[(offset - UARTID_BASE) / 4],
+            _ => 0,
+        }
+    }
+
+    // return whether the interrupt is to be injected
+    fn write(&self, offset: usize, val: u32) -> bool {
+        let mut inner = self.inner.lock();
+        let mis = inner.mis();
+        match offset {
+            UARTDR => {
+                inner.transmit(self.vm_id(), val as u8);
+                inner.ris |= UART_INT_TX;
+            }
+            UARTRSR => {}
+            UARTILPR => inner.ilpr = val & 0xff,
+            UARTIBRD => inner.ibrd = val & 0xffff,
+            UARTFBRD => inner.fbrd = val & 0x3f,
+            UARTLCR_H => inner.lcr_h = val & 0xff,
+            UARTCR => inner.cr = val & 0xffff,
+            UARTIFLS => inner.ifls = val & 0x3f,
+            UARTIMSC => inner.imsc = val & UART_INT_MASK,
+            UARTICR => inner.ris &= !val,
+            UARTDMACR => inner.dmacr = val & 0x7,
+            _ => {
+                warn!("pl011: VM[{}] write to ro or unknown reg {:#x}", self.vm_id(), offset);
+            }
+        }
+        // a newly unmasked source or a byte sent, the tx interrupt stays raised as the fifo is empty
+        let new = inner.mis();
+        new != 0 && (new != mis || offset == UARTDR)
+    }
+}
+
+impl EmuDev for EmuPl011 {
+    fn emu_type(&self) -> EmuDeviceType {
+        EmuDeviceType::EmuDeviceTPl011
+    }
+
+    fn address_range(&self) -> Range<usize> {
+        self.base..self.base + self.length
+    }
+
+    fn reset(&self) {
+        self.inner.lock().reset();
+    }
+
+    fn handler(&self, emu_ctx: &EmuContext) -> bool {
+        let offset = emu_ctx.address - self.base;
+        // the registers are 32 bits wide, a narrower access reaches their low bytes
+        if offset % 4 != 0 || emu_ctx.width > 4 {
+            warn!(
+                "pl011: VM[{}] illegal access at offset {:#x} width {}",
+                self.vm_id(),
+                offset,
+                emu_ctx.width
+            );
+            return false;
+        }
+        let vcpu = current_cpu();
+        if emu_ctx.write {
+            let val = vcpu.get_gpr(emu_ctx.reg) as u32;
+            if self.write(offset, val) {
+                self.inject();
+            }
+        } else {
+            let val = self.read(offset) as usize;
+            let mask = if emu_ctx.width >= 4 {
+                u32::MAX as usize
+            } else {
+                (1 << (8 * emu_ctx.width)) - 1
+            };
+            vcpu.set_gpr(emu_ctx.reg, val & mask);
+        }
+        true
+    }
+}
+
+/* A PL011 of a VM, cfg_list[0] picks where its output goes: 0 for the hypervisor console,
+ * CONSOLE_RING_VMID for a ring read by VM0 with HVC_VMM_READ_CONSOLE.
+ */
+pub fn emu_pl011_init(vm: Weak<Vm>, emu_cfg: &VmEmulatedDeviceConfig) -> Result<Arc<dyn EmuDev>, ()> {
+    if emu_cfg.irq_id == 0 || emu_cfg.length < 0x1000 {
+        error!(
+            "emu_pl011_init: {} needs an irq and a 4KB region, irq {} length {:#x}",
+            emu_cfg.name, emu_cfg.irq_id, emu_cfg.length
+        );
+        return Err(());
+    }
+    let out = match emu_cfg.cfg_list.first().copied().unwrap_or(0) {
+        0 => Pl011Out::Console(Vec::new()),
+        vmid if vmid == CONSOLE_RING_VMID as usize => Pl011Out::Ring(ConsoleRing::new()),
+        vmid => {
+            error!("emu_pl011_init: {} illegal output {:#x}", emu_cfg.name, vmid);
+            return Err(());
+        }
+    };
+    Ok(Arc::new(EmuPl011 {
+        base: emu_cfg.base_ipa,
+        length: emu_cfg.length,
+        irq_id: emu_cfg.irq_id,
+        vm,
+        inner: Mutex::new(Pl011Inner::new(out)),
+    }))
+}
+
+fn vm_pl011(vm: &Vm) -> Option<Arc<EmuPl011>> {
+    vm.config()
+        .emulated_device_list()
+        .iter()
+        .filter(|emu_cfg| emu_cfg.emu_type == EmuDeviceType::EmuDeviceTPl011)
+        .filter_map(|emu_cfg| vm.find_emu_dev(emu_cfg.base_ipa))
+        .find_map(|dev| dev.into_any_arc().downcast::<EmuPl011>().ok())
+}
+
+// drain the ring of the PL011 of `vm`, see `virtio_console_ring_read`
+pub fn pl011_ring_read(vm: &Vm, out: &mut [u8]) -> Option<(usize, usize)> {
+    vm_pl011(vm)?.ring_read(out)
+}
+
+// feed the rx fifo of the PL011 of `vm`, return the bytes taken
+pub fn pl011_receive(vm: &Vm, data: &[u8]) -> Option<usize> {
+    Some(vm_pl011(vm)?.receive(data))
+}
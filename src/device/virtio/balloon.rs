@@ -1,12 +1,14 @@
 // see virtio 1.1 5.5 Traditional Memory Balloon Device
 
 use alloc::sync::Arc;
-use core::{mem::size_of, ops::Deref};
+use core::mem::size_of;
 
-use crate::device::EmuContext;
+use spin::Mutex;
+
+use crate::device::{EmuContext, EmuDeviceType};
 use crate::kernel::Vm;
 
-use super::{iov::VirtioIov, mmio::VIRTIO_F_VERSION_1, VirtioMmio, Virtq};
+use super::{dev::DevDesc, iov::VirtioIov, mmio::VIRTIO_F_VERSION_1, VirtioMmio, Virtq};
 
 // Size of a PFN in the balloon interface.
 const VIRTIO_BALLOON_PFN_SHIFT: usize = 12;
@@ -34,48 +36,103 @@ pub fn balloon_features() -> usize {
 // balloon wants more memory from the guest. If it is less than actual, the balloon doesn’t need it all.
 #[derive(Debug)]
 #[repr(C)]
-pub struct VirtioBallonConfig {
+struct BalloonConfig {
     // Number of pages host wants Guest to give up.
     num_pages: u32,
     // Number of pages we've actually got in balloon.
     actual: u32,
 }
 
+pub struct VirtioBallonConfig {
+    inner: Mutex<BalloonConfig>,
+}
+
 impl VirtioBallonConfig {
     pub fn new(give_up: usize) -> Self {
         Self {
-            num_pages: (give_up >> VIRTIO_BALLOON_PFN_SHIFT) as u32,
-            actual: 0,
+            inner: Mutex::new(BalloonConfig {
+                num_pages: (give_up >> VIRTIO_BALLOON_PFN_SHIFT) as u32,
+                actual: 0,
+            }),
         }
     }
 
-    pub fn read_config(&self, emu_ctx: &EmuContext, offset: usize) -> u64 {
-        if offset < size_of::<Self>() {
-            match emu_ctx.width {
-                1 => unsafe { *((self as *const _ as usize + offset) as *const u8) as u64 },
-                2 => unsafe { *((self as *const _ as usize + offset) as *const u16) as u64 },
-                4 => unsafe { *((self as *const _ as usize + offset) as *const u32) as u64 },
-                8 => unsafe { *((self as *const _ as usize + offset) as *const u64) },
-                _ => 0,
-            }
-        } else {
-            0
-        }
+    // the config space seen by the guest
+    pub fn config<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        let inner = self.inner.lock();
+        let config = &*inner as *const BalloonConfig as *const u8;
+        f(unsafe { core::slice::from_raw_parts(config, size_of::<BalloonConfig>()) })
+    }
+
+    // `actual` is the only field written by the driver
+    pub fn writable(offset: usize, width: usize) -> bool {
+        offset >= size_of::<u32>() && offset + width <= size_of::<BalloonConfig>()
     }
 
     pub fn write_config(&self, emu_ctx: &EmuContext, offset: usize, val: u64) {
-        if offset < size_of::<Self>() {
-            debug!("before: VirtioBallonConfig {:x?}", self);
-            match emu_ctx.width {
-                1 => unsafe { *((self as *const _ as usize + offset) as *mut u8) = val as u8 },
-                2 => unsafe { *((self as *const _ as usize + offset) as *mut u16) = val as u16 },
-                4 => unsafe { *((self as *const _ as usize + offset) as *mut u32) = val as u32 },
-                8 => unsafe { *((self as *const _ as usize + offset) as *mut u64) = val },
-                _ => {}
-            }
-            debug!("after: VirtioBallonConfig {:x?}", self);
+        if !Self::writable(offset, emu_ctx.width) {
+            return;
+        }
+        let mut inner = self.inner.lock();
+        debug!("before: VirtioBallonConfig {:x?}", *inner);
+        let mut actual = inner.actual.to_le_bytes();
+        let start = offset - size_of::<u32>();
+        actual[start..start + emu_ctx.width].copy_from_slice(&val.to_le_bytes()[..emu_ctx.width]);
+        inner.actual = u32::from_le_bytes(actual);
+        debug!("after: VirtioBallonConfig {:x?}", *inner);
+    }
+
+    pub fn set_num_pages(&self, num_pages: u32) {
+        self.inner.lock().num_pages = num_pages;
+    }
+
+    pub fn actual(&self) -> u32 {
+        self.inner.lock().actual
+    }
+}
+
+/* Ask the balloon of `vm` to hold `pages` pages, the driver inflates or deflates to reach it.
+ * Returns the pages the balloon holds now, before the driver has acted on the new target.
+ */
+pub fn virtio_balloon_set_target(vm: &Vm, pages: usize) -> Result<usize, ()> {
+    let balloon = match vm
+        .config()
+        .emulated_device_list()
+        .iter()
+        .find(|emu_cfg| emu_cfg.emu_type == EmuDeviceType::EmuDeviceTVirtioBalloon)
+        .and_then(|emu_cfg| vm.find_emu_dev(emu_cfg.base_ipa))
+        .and_then(|dev| dev.into_any_arc().downcast::<VirtioMmio>().ok())
+    {
+        Some(balloon) => balloon,
+        None => {
+            error!("virtio_balloon_set_target: VM[{}] has no balloon", vm.id());
+            return Err(());
         }
+    };
+    let memory: usize = vm.config().memory_region().iter().map(|region| region.length).sum();
+    if pages >= memory >> VIRTIO_BALLOON_PFN_SHIFT {
+        error!(
+            "virtio_balloon_set_target: VM[{}] balloon of {} pages leaves no memory",
+            vm.id(),
+            pages
+        );
+        return Err(());
     }
+    let actual = match balloon.dev().desc() {
+        DevDesc::Balloon(config) => {
+            config.set_num_pages(pages as u32);
+            config.actual() as usize
+        }
+        _ => return Err(()),
+    };
+    info!(
+        "VM[{}] balloon target {} pages, holds {} pages",
+        vm.id(),
+        pages,
+        vm.balloon_pages()
+    );
+    balloon.notify_config();
+    Ok(actual)
 }
 
 // Virtqueues
@@ -89,21 +146,20 @@ pub fn virtio_balloon_notify_handler(vq: Arc<Virtq>, balloon: Arc<VirtioMmio>, v
     }
 
     while let Some(next_desc_idx) = vq.pop_avail_desc_idx(vq.avail_idx()) {
-        let mut idx = next_desc_idx as usize;
         let mut len = 0;
         let mut iov = VirtioIov::default();
-        loop {
-            let addr = vm.ipa2hva(vq.desc_addr(idx));
-            if addr == 0 {
-                return false;
-            }
-            let desc_len = vq.desc_len(idx) as usize;
-            iov.push_data(addr, desc_len);
-            len += desc_len;
-            if vq.desc_flags(idx) == 0 {
-                break;
+        for desc in vq.desc_chain(next_desc_idx, &vm) {
+            match desc {
+                Ok(desc) => {
+                    iov.push_data(desc.hva, desc.len as usize);
+                    len += desc.len as usize;
+                }
+                Err(_) => {
+                    vq.update_used_ring(0, next_desc_idx as u32);
+                    balloon.notify();
+                    return false;
+                }
             }
-            idx = vq.desc_next(idx) as usize;
         }
         match vq.vq_indx() {
             0 => release_memory_range(&vm, &iov),
@@ -118,29 +174,38 @@ pub fn virtio_balloon_notify_handler(vq: Arc<Virtq>, balloon: Arc<VirtioMmio>, v
     true
 }
 
-fn release_memory_range(vm: &Vm, iov: &VirtioIov) {
+// the guest page of each u32 pfn in the buffers, a pfn split across two buffers is dropped
+fn balloon_pfn_walk(iov: &VirtioIov, mut f: impl FnMut(usize)) {
     for iov_data in iov.iter() {
         debug!("iov data: {:x?}", iov_data);
-        for (i, addr) in (iov_data.buf..iov_data.buf + iov_data.len)
-            .into_iter()
-            .step_by(4)
-            .enumerate()
+        for addr in
+            (iov_data.buf..iov_data.buf + iov_data.len / size_of::<u32>() * size_of::<u32>()).step_by(size_of::<u32>())
         {
-            let pfn = unsafe { *(addr as *const u32) };
-            let range_base = (pfn as usize) << VIRTIO_BALLOON_PFN_SHIFT;
-            let range_len = 1 << VIRTIO_BALLOON_PFN_SHIFT;
-            vm.inflate_balloon(range_base, range_len);
-            debug!(
-                "release_memory_range: VM [{}] {i}: range_base {range_base:#x}, range_len {range_len:#x}",
-                vm.id()
-            );
+            let pfn = unsafe { core::ptr::read_unaligned(addr as *const u32) };
+            f((pfn as usize) << VIRTIO_BALLOON_PFN_SHIFT);
         }
     }
 }
 
-// currently unsupported, VIRTIO_BALLOON_F_DEFLATE_ON_OOM is not set
+fn release_memory_range(vm: &Vm, iov: &VirtioIov) {
+    let mut count = 0;
+    balloon_pfn_walk(iov, |range_base| {
+        if vm.inflate_balloon(range_base, 1 << VIRTIO_BALLOON_PFN_SHIFT) {
+            count += 1;
+        }
+    });
+    debug!("release_memory_range: VM [{}] {} pages released", vm.id(), count);
+}
+
+// the driver only deflates after telling us (VIRTIO_BALLOON_F_MUST_TELL_HOST)
 fn alloc_memory_range(vm: &Vm, iov: &VirtioIov) {
-    info!("alloc_memory_range: VM {} iov {:x?}", vm.id(), iov.deref());
+    let mut count = 0;
+    balloon_pfn_walk(iov, |range_base| {
+        if vm.deflate_balloon(range_base) {
+            count += 1;
+        }
+    });
+    debug!("alloc_memory_range: VM [{}] {} pages restored", vm.id(), count);
 }
 
 // Memory Statistics Tags
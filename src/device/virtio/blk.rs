@@ -1,20 +1,30 @@
 use alloc::ffi::CString;
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
 use spin::Mutex;
 
 use crate::arch::PAGE_SIZE;
-use crate::device::{mediated_blk_list_get, EmuContext, ReadAsyncMsg, UsedInfo, VirtioMmio, Virtq, WriteAsyncMsg};
+use crate::device::{
+    mediated_blk_list_get, mediated_blk_submit, mediated_blk_zero_copy_pages, FlushAsyncMsg, ReadAsyncMsg, UsedInfo,
+    VirtioMmio, Virtq, WriteAsyncMsg,
+};
+use crate::kernel::timer::start_timer_event;
 use crate::kernel::{async_blk_io_req, async_ipi_req, AsyncTask, IpiMediatedMsg, Vm, EXECUTOR};
 use crate::util::memcpy_safe;
+use crate::util::timer_list::{TimerEvent, TimerValue};
 
-use super::mmio::VIRTIO_F_VERSION_1;
+use super::mmio::{VIRTIO_F_VERSION_1, VIRTIO_RING_F_EVENT_IDX};
 
 pub const VIRTQUEUE_BLK_MAX_SIZE: usize = 256;
 
 /* VIRTIO_BLK_FEATURES*/
 const VIRTIO_BLK_F_SIZE_MAX: usize = 1 << 1;
 const VIRTIO_BLK_F_SEG_MAX: usize = 1 << 2;
+const VIRTIO_BLK_F_RO: usize = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: usize = 1 << 9;
 
 /* BLOCK PARAMETERS*/
 pub const SECTOR_BSIZE: usize = 512;
@@ -29,11 +39,29 @@ pub const VIRTIO_BLK_T_GET_ID: usize = 8;
 
 /* BLOCK REQUEST STATUS*/
 pub const VIRTIO_BLK_S_OK: usize = 0;
-// pub const VIRTIO_BLK_S_IOERR: usize = 1;
+pub const VIRTIO_BLK_S_IOERR: usize = 1;
 pub const VIRTIO_BLK_S_UNSUPP: usize = 2;
 
-pub fn blk_features() -> usize {
-    VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX
+/* COMPLETION COALESCING, set by cfg_list[2..5] of the device
+ * [2] completions batched into one guest irq, 0 or 1 injects an irq for each completion
+ * [3] timeout in us to flush an incomplete batch, it is rounded up to the timer tick
+ * [4] non-zero if the VM0 backend reads nreq returned by its completion hypercall,
+ *     so that a request posted during the completion needs no notification of its own
+ * [5] non-zero makes the device read-only, VIRTIO_BLK_F_RO is offered and writes fail with VIRTIO_BLK_S_IOERR
+ */
+pub const BLK_COALESCE_TIMEOUT_US: usize = 100;
+
+pub fn blk_features(read_only: bool) -> usize {
+    let features = VIRTIO_F_VERSION_1
+        | VIRTIO_RING_F_EVENT_IDX
+        | VIRTIO_BLK_F_SIZE_MAX
+        | VIRTIO_BLK_F_SEG_MAX
+        | VIRTIO_BLK_F_FLUSH;
+    if read_only {
+        features | VIRTIO_BLK_F_RO
+    } else {
+        features
+    }
 }
 
 #[repr(C)]
@@ -72,19 +100,10 @@ impl BlkDesc {
         BlkDesc { inner: desc }
     }
 
-    fn start_addr(&self) -> usize {
-        &self.inner.capacity as *const _ as usize
-    }
-
-    pub fn offset_data(&self, emu_ctx: &EmuContext, offset: usize) -> u64 {
-        let start_addr = self.start_addr();
-        match emu_ctx.width {
-            1 => unsafe { *((start_addr + offset) as *const u8) as u64 },
-            2 => unsafe { *((start_addr + offset) as *const u16) as u64 },
-            4 => unsafe { *((start_addr + offset) as *const u32) as u64 },
-            8 => unsafe { *((start_addr + offset) as *const u64) },
-            _ => 0,
-        }
+    // the config space seen by the guest
+    pub fn config<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        let inner = &self.inner as *const BlkDescInner as *const u8;
+        f(unsafe { core::slice::from_raw_parts(inner, size_of::<BlkDescInner>()) })
     }
 }
 
@@ -113,6 +132,8 @@ struct BlkDescInner {
 pub struct BlkIov {
     pub data_bg: usize,
     pub len: u32,
+    // ipa of the buffer, to mark the pages written by a read dirty
+    pub ipa: usize,
 }
 
 #[repr(C)]
@@ -121,10 +142,27 @@ struct BlkReqRegion {
     pub size: usize,
 }
 
+#[derive(Default)]
+struct BlkCoalesce {
+    batch: usize,
+    timeout_us: usize,
+    mvm_poll: bool,
+    // completions in the used ring that the guest has not been interrupted for
+    pending: AtomicUsize,
+    timer_armed: AtomicBool,
+    completions: AtomicUsize,
+    irqs: AtomicUsize,
+    suppressed: AtomicUsize,
+    mvm_notify: AtomicUsize,
+    mvm_coalesced: AtomicUsize,
+}
+
 #[repr(C)]
 pub struct VirtioBlkReq {
     region: BlkReqRegion,
     mediated: bool,
+    read_only: bool,
+    coalesce: BlkCoalesce,
 }
 
 impl VirtioBlkReq {
@@ -132,9 +170,17 @@ impl VirtioBlkReq {
         VirtioBlkReq {
             region: BlkReqRegion { start: 0, size: 0 },
             mediated: false,
+            read_only: false,
+            coalesce: BlkCoalesce::default(),
         }
     }
 
+    pub fn set_coalesce(&mut self, batch: usize, timeout_us: usize, mvm_poll: bool) {
+        self.coalesce.batch = batch;
+        self.coalesce.timeout_us = timeout_us;
+        self.coalesce.mvm_poll = mvm_poll;
+    }
+
     pub fn set_start(&mut self, start: usize) {
         self.region.start = start;
     }
@@ -151,6 +197,14 @@ impl VirtioBlkReq {
         self.mediated
     }
 
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
     pub fn region_start(&self) -> usize {
         self.region.start
     }
@@ -167,6 +221,8 @@ pub struct VirtioBlkReqNode {
     reserved: u32,
     sector: usize,
     desc_chain_head_idx: u32,
+    // hva of the status byte
+    status: usize,
     iov: Vec<BlkIov>,
     // sum up byte for req
     iov_sum_up: usize,
@@ -181,6 +237,7 @@ impl VirtioBlkReqNode {
             reserved: 0,
             sector: 0,
             desc_chain_head_idx: 0,
+            status: 0,
             iov: vec![],
             iov_sum_up: 0,
             iov_total: 0,
@@ -192,6 +249,7 @@ fn generate_blk_req(
     req: &VirtioBlkReq,
     vq: Arc<Virtq>,
     dev: Arc<VirtioMmio>,
+    blk_id: usize,
     cache: usize,
     vm: Arc<Vm>,
     req_node_list: Vec<VirtioBlkReqNode>,
@@ -201,8 +259,11 @@ fn generate_blk_req(
     let mut cache_ptr = cache;
     for req_node in req_node_list {
         let sector = req_node.sector;
-        if sector + req_node.iov_sum_up / SECTOR_BSIZE > region_start + region_size {
-            println!(
+        // the sector of a flush is reserved
+        if req_node.req_type != VIRTIO_BLK_T_FLUSH as u32
+            && sector + req_node.iov_sum_up / SECTOR_BSIZE > region_start + region_size
+        {
+            warn!(
                 "blk_req_handler: {} out of vm range",
                 if req_node.req_type == VIRTIO_BLK_T_IN as u32 {
                     "read"
@@ -216,23 +277,27 @@ fn generate_blk_req(
             VIRTIO_BLK_T_IN => {
                 if req.mediated() {
                     // mediated blk read
+                    let zero_copy = mediated_blk_zero_copy_pages(blk_id, &vm, &req_node.iov);
                     let task = AsyncTask::new(
                         ReadAsyncMsg {
                             src_vm: vm.clone(),
                             vq: vq.clone(),
                             dev: dev.clone(),
-                            blk_id: vm.med_blk_id(),
+                            blk_id,
                             sector: sector + region_start,
                             count: req_node.iov_sum_up / SECTOR_BSIZE,
                             cache,
                             iov_list: Arc::new(req_node.iov),
+                            zero_copy,
                             used_info: UsedInfo {
                                 desc_chain_head_idx: req_node.desc_chain_head_idx,
                                 used_len: req_node.iov_total as u32,
+                                status: req_node.status,
                             },
                         },
                         vm.id(),
-                        async_blk_io_req(),
+                        blk_id,
+                        async_blk_io_req(blk_id),
                     );
                     EXECUTOR.add_task(task, false);
                 } else {
@@ -241,21 +306,26 @@ fn generate_blk_req(
                         let len = iov.len as usize;
 
                         if len < SECTOR_BSIZE {
-                            println!("blk_req_handler: read len < SECTOR_BSIZE");
+                            warn!("blk_req_handler: read len < SECTOR_BSIZE");
                             continue;
                         }
                         memcpy_safe(data_bg as *mut u8, cache_ptr as *mut u8, len);
+                        vm.dirty_log_mark(iov.ipa, len);
                         cache_ptr += len;
                     }
                 }
             }
             VIRTIO_BLK_T_OUT => {
                 if req.mediated() {
+                    let zero_copy = mediated_blk_zero_copy_pages(blk_id, &vm, &req_node.iov);
                     let mut buffer = vec![];
-                    for iov in req_node.iov.iter() {
-                        let data_bg =
-                            unsafe { core::slice::from_raw_parts(iov.data_bg as *const u8, iov.len as usize) };
-                        buffer.extend_from_slice(data_bg);
+                    // a zero-copy write is read by VM0 from the guest pages
+                    if zero_copy.is_empty() {
+                        for iov in req_node.iov.iter() {
+                            let data_bg =
+                                unsafe { core::slice::from_raw_parts(iov.data_bg as *const u8, iov.len as usize) };
+                            buffer.extend_from_slice(data_bg);
+                        }
                     }
                     // mediated blk write
                     let task = AsyncTask::new(
@@ -263,18 +333,21 @@ fn generate_blk_req(
                             src_vm: vm.clone(),
                             vq: vq.clone(),
                             dev: dev.clone(),
-                            blk_id: vm.med_blk_id(),
+                            blk_id,
                             sector: sector + region_start,
                             count: req_node.iov_sum_up / SECTOR_BSIZE,
                             cache,
                             buffer: Arc::new(Mutex::new(buffer)),
+                            zero_copy,
                             used_info: UsedInfo {
                                 desc_chain_head_idx: req_node.desc_chain_head_idx,
                                 used_len: req_node.iov_total as u32,
+                                status: req_node.status,
                             },
                         },
                         vm.id(),
-                        async_blk_io_req(),
+                        blk_id,
+                        async_blk_io_req(blk_id),
                     );
                     EXECUTOR.add_task(task, false);
                 } else {
@@ -282,7 +355,7 @@ fn generate_blk_req(
                         let data_bg = iov.data_bg;
                         let len = iov.len as usize;
                         if len < SECTOR_BSIZE {
-                            println!("blk_req_handler: read len < SECTOR_BSIZE");
+                            warn!("blk_req_handler: read len < SECTOR_BSIZE");
                             continue;
                         }
                         memcpy_safe(cache_ptr as *mut u8, data_bg as *mut u8, len);
@@ -291,7 +364,34 @@ fn generate_blk_req(
                 }
             }
             VIRTIO_BLK_T_FLUSH => {
-                todo!();
+                if req.mediated() {
+                    // completed when VM0 has synced the backing storage
+                    let task = AsyncTask::new(
+                        FlushAsyncMsg {
+                            src_vm: vm.clone(),
+                            vq: vq.clone(),
+                            dev: dev.clone(),
+                            blk_id,
+                            used_info: UsedInfo {
+                                desc_chain_head_idx: req_node.desc_chain_head_idx,
+                                used_len: 0,
+                                status: req_node.status,
+                            },
+                        },
+                        vm.id(),
+                        blk_id,
+                        async_blk_io_req(blk_id),
+                    );
+                    EXECUTOR.add_task(task, false);
+                } else {
+                    // nothing is cached in front of a non-mediated blk
+                    if !vq.update_used_ring(0, req_node.desc_chain_head_idx) {
+                        warn!("blk_req_handler: fail to update used ring");
+                    }
+                    if vq.should_notify() {
+                        dev.notify();
+                    }
+                }
             }
             VIRTIO_BLK_T_GET_ID => {
                 let name = CString::new("virtio-blk").unwrap();
@@ -299,13 +399,16 @@ fn generate_blk_req(
                 let data_bg =
                     unsafe { core::slice::from_raw_parts_mut(req_node.iov[0].data_bg as *mut u8, cstr.len()) };
                 data_bg.copy_from_slice(cstr);
+                vm.dirty_log_mark(req_node.iov[0].ipa, cstr.len());
                 if !vq.update_used_ring(req_node.iov_total as u32, req_node.desc_chain_head_idx) {
-                    println!("blk_req_handler: fail to update used ring");
+                    warn!("blk_req_handler: fail to update used ring");
+                }
+                if vq.should_notify() {
+                    dev.notify();
                 }
-                dev.notify();
             }
             _ => {
-                println!("Wrong block request type {} ", req_node.req_type);
+                warn!("Wrong block request type {} ", req_node.req_type);
                 continue;
             }
         }
@@ -320,19 +423,171 @@ fn generate_blk_req(
     }
 }
 
+// complete an illegal request without touching the backend
+fn blk_req_abort(vq: &Virtq, blk: &VirtioMmio, head_idx: u16, vstatus: Option<&mut u8>) {
+    if let Some(vstatus) = vstatus {
+        *vstatus = VIRTIO_BLK_S_IOERR as u8;
+    }
+    if !vq.update_used_ring(0, head_idx as u32) {
+        warn!("blk_req_abort: fail to update used ring");
+    }
+    if vq.should_notify() {
+        blk.notify();
+    }
+}
+
+fn blk_coalesce(dev: &VirtioMmio) -> Option<&BlkCoalesce> {
+    dev.dev().req().as_ref().map(|req| &req.coalesce)
+}
+
+// inject the irq for the completions of the batch, unless the guest suppressed it to poll the used ring
+fn blk_coalesce_flush(vq: &Virtq, dev: &VirtioMmio) {
+    let coalesce = match blk_coalesce(dev) {
+        Some(coalesce) => coalesce,
+        None => return,
+    };
+    if coalesce.pending.swap(0, Ordering::AcqRel) == 0 {
+        return;
+    }
+    if vq.should_notify() {
+        coalesce.irqs.fetch_add(1, Ordering::Relaxed);
+        dev.notify();
+    } else {
+        coalesce.suppressed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct BlkCoalesceTimer {
+    vq: Weak<Virtq>,
+    dev: Weak<VirtioMmio>,
+}
+
+impl TimerEvent for BlkCoalesceTimer {
+    fn callback(self: Arc<Self>, _now: TimerValue) {
+        if let (Some(vq), Some(dev)) = (self.vq.upgrade(), self.dev.upgrade()) {
+            if let Some(coalesce) = blk_coalesce(&dev) {
+                coalesce.timer_armed.store(false, Ordering::Release);
+            }
+            blk_coalesce_flush(&vq, &dev);
+        }
+    }
+}
+
+/* Complete a mediated request in the used ring.
+ * `more` tells if the VM still has requests in the executor, the batch is flushed if it doesn't,
+ * otherwise the irq waits for the batch to fill up or the timeout.
+ */
+pub fn virtio_blk_complete(vq: &Arc<Virtq>, dev: &Arc<VirtioMmio>, info: &UsedInfo, more: bool) {
+    if !vq.update_used_ring(info.used_len, info.desc_chain_head_idx) {
+        warn!("virtio_blk_complete: fail to update used ring");
+    }
+    let coalesce = match blk_coalesce(dev) {
+        Some(coalesce) => coalesce,
+        None => {
+            dev.notify();
+            return;
+        }
+    };
+    coalesce.completions.fetch_add(1, Ordering::Relaxed);
+    let pending = coalesce.pending.fetch_add(1, Ordering::AcqRel) + 1;
+    if !more || pending >= coalesce.batch {
+        blk_coalesce_flush(vq, dev);
+    } else if !coalesce.timer_armed.swap(true, Ordering::AcqRel) {
+        let timer = BlkCoalesceTimer {
+            vq: Arc::downgrade(vq),
+            dev: Arc::downgrade(dev),
+        };
+        start_timer_event(Duration::from_micros(coalesce.timeout_us as u64), Arc::new(timer));
+    }
+}
+
+// complete a cancelled mediated request with VIRTIO_BLK_S_IOERR
+pub fn virtio_blk_complete_err(vq: &Arc<Virtq>, dev: &Arc<VirtioMmio>, info: &UsedInfo) {
+    let vstatus = match info.status {
+        0 => None,
+        status => Some(unsafe { &mut *(status as *mut u8) }),
+    };
+    blk_req_abort(vq, dev, info.desc_chain_head_idx as u16, vstatus);
+}
+
+// post a mediated request to VM 0, count if it needed a notification
+pub fn virtio_blk_mediated_submit(dev: &VirtioMmio, blk_id: usize, req_type: usize, sector: usize, count: usize) {
+    let coalesce = blk_coalesce(dev);
+    let mvm_poll = coalesce.is_some_and(|coalesce| coalesce.mvm_poll);
+    let notified = mediated_blk_submit(blk_id, req_type, sector, count, mvm_poll);
+    if let Some(coalesce) = coalesce {
+        if notified {
+            coalesce.mvm_notify.fetch_add(1, Ordering::Relaxed);
+        } else {
+            coalesce.mvm_coalesced.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub fn virtio_blk_stat_dump(dev: &VirtioMmio, reset: bool) {
+    let coalesce = match blk_coalesce(dev) {
+        Some(coalesce) => coalesce,
+        None => return,
+    };
+    let counters = [
+        &coalesce.completions,
+        &coalesce.irqs,
+        &coalesce.suppressed,
+        &coalesce.mvm_notify,
+        &coalesce.mvm_coalesced,
+    ];
+    let [completions, irqs, suppressed, mvm_notify, mvm_coalesced] = counters.map(|counter| {
+        if reset {
+            counter.swap(0, Ordering::Relaxed)
+        } else {
+            counter.load(Ordering::Relaxed)
+        }
+    });
+    println!(
+        "    blk batch {}: completions {}, irqs {}, suppressed {}, VM0 notify {}, VM0 coalesced {}",
+        coalesce.batch, completions, irqs, suppressed, mvm_notify, mvm_coalesced
+    );
+}
+
 pub fn virtio_mediated_blk_notify_handler(vq: Arc<Virtq>, blk: Arc<VirtioMmio>, vm: Arc<Vm>) -> bool {
     let src_vmid = vm.id();
-    let task = AsyncTask::new(IpiMediatedMsg { src_vm: vm, vq, blk }, src_vmid, async_ipi_req());
+    let blk_id = match vm.med_blk_id(blk.base()) {
+        Some(blk_id) => blk_id,
+        None => {
+            error!("VM[{}] virtio blk {:#x} has no mediated blk", src_vmid, blk.base());
+            return false;
+        }
+    };
+    let task = AsyncTask::new(
+        IpiMediatedMsg {
+            src_vm: vm,
+            vq,
+            blk,
+            blk_id,
+        },
+        src_vmid,
+        blk_id,
+        async_ipi_req(blk_id),
+    );
     EXECUTOR.add_task(task, true);
     true
 }
 
 pub fn virtio_blk_notify_handler(vq: Arc<Virtq>, blk: Arc<VirtioMmio>, vm: Arc<Vm>) -> bool {
+    blk_req_handler(vq, blk, vm, None)
+}
+
+// handle the requests of a mediated virtio blk on Core 0, they go to mediated blk `blk_id`
+pub fn virtio_mediated_blk_req_handler(vq: Arc<Virtq>, blk: Arc<VirtioMmio>, vm: Arc<Vm>, blk_id: usize) -> bool {
+    blk_req_handler(vq, blk, vm, Some(blk_id))
+}
+
+fn blk_req_handler(vq: Arc<Virtq>, blk: Arc<VirtioMmio>, vm: Arc<Vm>, med_blk_id: Option<usize>) -> bool {
     let avail_idx = vq.avail_idx();
 
     // let begin = time_current_us();
     if vq.ready() == 0 {
-        println!("blk virt_queue is not ready!");
+        warn!("blk virt_queue is not ready!");
         return false;
     }
 
@@ -341,7 +596,7 @@ pub fn virtio_blk_notify_handler(vq: Arc<Virtq>, blk: Arc<VirtioMmio>, vm: Arc<V
     let req = match dev.req() {
         Some(blk_req) => blk_req,
         _ => {
-            panic!("virtio_blk_notify_handler: illegal req");
+            panic!("blk_req_handler: illegal req");
         }
     };
 
@@ -352,94 +607,98 @@ pub fn virtio_blk_notify_handler(vq: Arc<Virtq>, blk: Arc<VirtioMmio>, vm: Arc<V
     // let time0 = time_current_us();
 
     while let Some(head_idx) = vq.pop_avail_desc_idx(avail_idx) {
-        let mut next_desc_idx = head_idx as usize;
         vq.disable_notify();
         if vq.check_avail_idx(avail_idx) {
             vq.enable_notify();
         }
 
-        let mut head = true;
-        // desc_chain_head_idx = next_desc_idx;
-
-        // vq.show_desc_info(4, vm.clone());
-
         let mut req_node = VirtioBlkReqNode::default();
-        req_node.desc_chain_head_idx = next_desc_idx as u32;
-        // println!(
-        //     "avail idx {} desc_chain_head {} avail flag {}",
-        //     vq.last_avail_idx() - 1,
-        //     req_node.desc_chain_head_idx,
-        //     vq.avail_flags()
-        // );
-
-        loop {
-            if vq.desc_has_next(next_desc_idx) {
-                if head {
-                    if vq.desc_is_writable(next_desc_idx) {
-                        println!(
-                            "Failed to get virt blk queue desc header, idx = {}, flag = {:x}",
-                            next_desc_idx,
-                            vq.desc_flags(next_desc_idx)
-                        );
-                        blk.notify();
-                        return false;
-                    }
-                    head = false;
-                    let vreq_addr = vm.ipa2hva(vq.desc_addr(next_desc_idx));
-                    if vreq_addr == 0 {
-                        println!("virtio_blk_notify_handler: failed to get vreq");
-                        return false;
-                    }
-                    let vreq = unsafe { &*(vreq_addr as *const VirtioBlkReqNode) };
-                    req_node.req_type = vreq.req_type;
-                    req_node.sector = vreq.sector;
-                } else {
-                    /*data handler*/
-                    if (vq.desc_flags(next_desc_idx) & 0x2) as u32 >> 1 == req_node.req_type {
-                        println!(
-                            "Failed to get virt blk queue desc data, idx = {}, req.type = {}, desc.flags = {}",
-                            next_desc_idx,
-                            req_node.req_type,
-                            vq.desc_flags(next_desc_idx)
-                        );
-                        blk.notify();
-                        return false;
-                    }
-                    let data_bg = vm.ipa2hva(vq.desc_addr(next_desc_idx));
-                    if data_bg == 0 {
-                        println!("virtio_blk_notify_handler: failed to get iov data begin");
-                        return false;
-                    }
+        req_node.desc_chain_head_idx = head_idx as u32;
+
+        let chain = match vq.desc_chain(head_idx, &vm).collect::<Result<Vec<_>, _>>() {
+            Ok(chain) => chain,
+            Err(_) => {
+                // fail the request if its status byte can be located, the buffers are given back anyway
+                warn!(
+                    "blk_req_handler: vm[{}] drop illegal desc chain, head {}",
+                    vm.id(),
+                    head_idx
+                );
+                let vstatus = vq
+                    .desc_chain_status(head_idx, &vm)
+                    .map(|status| unsafe { &mut *(status as *mut u8) });
+                blk_req_abort(&vq, &blk, head_idx, vstatus);
+                continue;
+            }
+        };
 
-                    let iov = BlkIov {
-                        data_bg,
-                        len: vq.desc_len(next_desc_idx),
-                    };
-                    req_node.iov_sum_up += iov.len as usize;
-                    req_node.iov.push(iov);
-                }
-            } else {
-                /*state handler*/
-                if !vq.desc_is_writable(next_desc_idx) {
-                    println!("Failed to get virt blk queue desc status, idx = {}", next_desc_idx);
-                    blk.notify();
-                    return false;
-                }
-                let vstatus_addr = vm.ipa2hva(vq.desc_addr(next_desc_idx));
-                if vstatus_addr == 0 {
-                    println!("virtio_blk_notify_handler: vm[{}] failed to vstatus", vm.id());
-                    return false;
-                }
-                let vstatus = unsafe { &mut *(vstatus_addr as *mut u8) };
-                if req_node.req_type > 1 && req_node.req_type != VIRTIO_BLK_T_GET_ID as u32 {
-                    *vstatus = VIRTIO_BLK_S_UNSUPP as u8;
-                } else {
-                    *vstatus = VIRTIO_BLK_S_OK as u8;
-                }
+        /*state handler*/
+        let vstatus = match chain.last() {
+            Some(status) if chain.len() >= 2 && status.is_writable() => unsafe { &mut *(status.hva as *mut u8) },
+            _ => {
+                warn!("Failed to get virt blk queue desc status, head = {}", head_idx);
+                blk_req_abort(&vq, &blk, head_idx, None);
+                continue;
+            }
+        };
+
+        /*header handler*/
+        let header = &chain[0];
+        if header.is_writable() {
+            warn!(
+                "Failed to get virt blk queue desc header, idx = {}, flag = {:x}",
+                header.idx, header.flags
+            );
+            blk_req_abort(&vq, &blk, head_idx, Some(vstatus));
+            continue;
+        }
+        let vreq = unsafe { &*(header.hva as *const VirtioBlkReqNode) };
+        req_node.req_type = vreq.req_type;
+        req_node.sector = vreq.sector;
+
+        /*data handler*/
+        let mut data_valid = true;
+        for desc in chain[1..chain.len() - 1].iter() {
+            if desc.is_writable() as u32 == req_node.req_type {
+                warn!(
+                    "Failed to get virt blk queue desc data, idx = {}, req.type = {}, desc.flags = {}",
+                    desc.idx, req_node.req_type, desc.flags
+                );
+                data_valid = false;
                 break;
             }
-            next_desc_idx = vq.desc_next(next_desc_idx) as usize;
+            let iov = BlkIov {
+                data_bg: desc.hva,
+                len: desc.len,
+                ipa: desc.addr,
+            };
+            req_node.iov_sum_up += iov.len as usize;
+            req_node.iov.push(iov);
         }
+        if !data_valid {
+            blk_req_abort(&vq, &blk, head_idx, Some(vstatus));
+            continue;
+        }
+        if req.read_only() && req_node.req_type == VIRTIO_BLK_T_OUT as u32 {
+            warn!(
+                "blk_req_handler: vm[{}] write to read-only blk, head {}",
+                vm.id(),
+                head_idx
+            );
+            blk_req_abort(&vq, &blk, head_idx, Some(vstatus));
+            continue;
+        }
+
+        if req_node.req_type > 1
+            && req_node.req_type != VIRTIO_BLK_T_FLUSH as u32
+            && req_node.req_type != VIRTIO_BLK_T_GET_ID as u32
+        {
+            *vstatus = VIRTIO_BLK_S_UNSUPP as u8;
+        } else {
+            *vstatus = VIRTIO_BLK_S_OK as u8;
+        }
+        req_node.status = vstatus as *mut u8 as usize;
+        vm.dirty_log_mark(chain[chain.len() - 1].addr, 1);
         req_node.iov_total = req_node.iov_sum_up;
         // req.add_req_node(req_node, &vm);
         req_node_list.push(req_node);
@@ -447,19 +706,22 @@ pub fn virtio_blk_notify_handler(vq: Arc<Virtq>, blk: Arc<VirtioMmio>, vm: Arc<V
         process_count += 1;
     }
 
-    if !req.mediated() {
-        // generate_blk_req(&req, &vq, &blk, dev.cache(), &vm);
-        unimplemented!("!req.mediated()");
-    } else {
-        let mediated_blk = mediated_blk_list_get(vm.med_blk_id());
-        let cache = mediated_blk.cache_pa();
-        generate_blk_req(req, vq.clone(), blk.clone(), cache, vm, req_node_list);
+    match med_blk_id {
+        None => {
+            // generate_blk_req(&req, &vq, &blk, dev.cache(), &vm);
+            unimplemented!("!req.mediated()");
+        }
+        Some(blk_id) => {
+            let mediated_blk = mediated_blk_list_get(blk_id);
+            let cache = mediated_blk.cache_pa();
+            generate_blk_req(req, vq.clone(), blk.clone(), blk_id, cache, vm, req_node_list);
+        }
     };
 
     // let time1 = time_current_us();
 
-    if vq.avail_flags() == 0 && process_count > 0 && !req.mediated() {
-        println!("virtio blk notify");
+    if process_count > 0 && !req.mediated() && vq.should_notify() {
+        debug!("virtio blk notify");
         blk.notify();
     }
 
@@ -1,16 +1,19 @@
+use alloc::collections::VecDeque;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
 
 use spin::Mutex;
 
 use crate::arch::PAGE_SIZE;
-use crate::device::{EmuContext, VirtioMmio, Virtq};
-use crate::kernel::vm_by_id;
+use crate::device::{EmuDeviceType, VirtioMmio, Virtq};
 use crate::kernel::Vm;
-use crate::util::round_down;
+use crate::kernel::{mem_pages_alloc, vm_by_id};
+use crate::mm::{PageFrame, PageUsage};
 
 use super::dev::DevDesc;
 use super::iov::VirtioIov;
-use super::mmio::VIRTIO_F_VERSION_1;
+use super::mmio::{VIRTIO_F_VERSION_1, VIRTIO_RING_F_EVENT_IDX};
 
 pub const VIRTQUEUE_CONSOLE_MAX_SIZE: usize = 64;
 
@@ -19,6 +22,10 @@ const VIRTQUEUE_SERIAL_MAX_SIZE: usize = 64;
 const VIRTIO_CONSOLE_F_SIZE: usize = 1 << 0;
 const VIRTIO_CONSOLE_F_MULTIPORT: usize = 1 << 1;
 const VIRTIO_CONSOLE_F_EMERG_WRITE: usize = 1 << 2;
+// offset of `emerg_wr` in the config space
+const CONSOLE_EMERG_WR_OFFSET: usize = 8;
+// an emergency line longer than this is printed in pieces
+const CONSOLE_EMERG_LINE_MAX: usize = 128;
 
 const VIRTIO_CONSOLE_DEVICE_READY: usize = 0;
 const VIRTIO_CONSOLE_DEVICE_ADD: usize = 1;
@@ -29,116 +36,378 @@ const VIRTIO_CONSOLE_RESIZE: usize = 5;
 const VIRTIO_CONSOLE_PORT_OPEN: usize = 6;
 const VIRTIO_CONSOLE_PORT_NAME: usize = 7;
 
+// each port is declared by a pair of (oppo_end_vmid, oppo_end_ipa) in cfg_list
+pub const CONSOLE_PORT_MAX: usize = 4;
+// oppo_end_vmid of a port whose output is kept in a ring of the hypervisor instead of sent to a VM
+pub const CONSOLE_RING_VMID: u16 = 0xffff;
+const CONSOLE_RING_SIZE: usize = 0x10000;
+
+// rx queue of `port`, the queue after it is the tx one, queues 2 and 3 are the control pair
+fn console_port_rx_vq(port: usize) -> usize {
+    if port == 0 {
+        0
+    } else {
+        2 * port + 2
+    }
+}
+
+fn console_port_of_tx_vq(vq_idx: usize) -> usize {
+    if vq_idx == 1 {
+        0
+    } else {
+        (vq_idx - 3) / 2
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+struct VirtioConsoleControl {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+impl VirtioConsoleControl {
+    fn new(id: usize, event: usize, value: u16) -> Self {
+        Self {
+            id: id as u32,
+            event: event as u16,
+            value,
+        }
+    }
+}
+
+/* The output of a guest console kept in the hypervisor, read by VM0 with HVC_VMM_READ_CONSOLE.
+ * The oldest bytes are overwritten when it is full, `head` and `tail` only increase,
+ * the offset in the pages is taken modulo CONSOLE_RING_SIZE. The vcpus writing to it are serialized by its lock.
+ */
+pub struct ConsoleRing {
+    frame: PageFrame,
+    head: usize,
+    tail: usize,
+    // bytes overwritten since the last read
+    dropped: usize,
+}
+
+impl ConsoleRing {
+    pub fn new() -> Option<Self> {
+        match mem_pages_alloc(CONSOLE_RING_SIZE / PAGE_SIZE, PageUsage::Console) {
+            Ok(frame) => Some(Self {
+                frame,
+                head: 0,
+                tail: 0,
+                dropped: 0,
+            }),
+            Err(err) => {
+                warn!("virtio console: alloc ring failed {:?}, its output is dropped", err);
+                None
+            }
+        }
+    }
+
+    pub fn push(&mut self, mut src: usize, mut len: usize) {
+        while len > 0 {
+            let off = self.tail % CONSOLE_RING_SIZE;
+            let n = len.min(CONSOLE_RING_SIZE - off);
+            unsafe { core::ptr::copy_nonoverlapping(src as *const u8, (self.frame.hva + off) as *mut u8, n) };
+            self.tail += n;
+            src += n;
+            len -= n;
+        }
+        if self.tail - self.head > CONSOLE_RING_SIZE {
+            let head = self.tail - CONSOLE_RING_SIZE;
+            self.dropped += head - self.head;
+            self.head = head;
+        }
+    }
+
+    fn push_iov(&mut self, iov: &VirtioIov, len: usize) {
+        let mut remain = len;
+        for data in iov.iter() {
+            let n = data.len.min(remain);
+            self.push(data.buf, n);
+            remain -= n;
+        }
+    }
+
+    // (bytes overwritten since the last read, bytes copied to `out`)
+    pub fn read(&mut self, out: &mut [u8]) -> (usize, usize) {
+        (core::mem::take(&mut self.dropped), self.pop(out))
+    }
+
+    // consume up to `out.len()` bytes, return the number of bytes copied
+    fn pop(&mut self, out: &mut [u8]) -> usize {
+        let len = out.len().min(self.tail - self.head);
+        let mut copied = 0;
+        while copied < len {
+            let off = self.head % CONSOLE_RING_SIZE;
+            let n = (len - copied).min(CONSOLE_RING_SIZE - off);
+            let src = unsafe { core::slice::from_raw_parts((self.frame.hva + off) as *const u8, n) };
+            out[copied..copied + n].copy_from_slice(src);
+            self.head += n;
+            copied += n;
+        }
+        len
+    }
+}
+
+struct ConsolePort {
+    oppo_end_vmid: u16,
+    oppo_end_ipa: u64,
+    guest_open: bool,
+}
+
 pub struct ConsoleDesc {
     inner: Mutex<ConsoleDescInner>,
+    // the ports to CONSOLE_RING_VMID write here
+    ring: Option<Mutex<ConsoleRing>>,
 }
 
 impl ConsoleDesc {
-    pub fn new(oppo_end_vmid: u16, oppo_end_ipa: u64) -> ConsoleDesc {
-        let mut desc = ConsoleDescInner::default();
-        desc.oppo_end_vmid = oppo_end_vmid;
-        desc.oppo_end_ipa = oppo_end_ipa;
-        desc.cols = 80;
-        desc.rows = 25;
+    pub fn new(cfg_list: &[usize]) -> ConsoleDesc {
+        if cfg_list.len() > 2 * CONSOLE_PORT_MAX {
+            warn!("virtio console: only {} ports are supported", CONSOLE_PORT_MAX);
+        }
+        let mut ports: Vec<ConsolePort> = cfg_list
+            .chunks_exact(2)
+            .take(CONSOLE_PORT_MAX)
+            .map(|port| ConsolePort {
+                oppo_end_vmid: port[0] as u16,
+                oppo_end_ipa: port[1] as u64,
+                guest_open: false,
+            })
+            .collect();
+        if ports.is_empty() {
+            ports.push(ConsolePort {
+                oppo_end_vmid: 0,
+                oppo_end_ipa: 0,
+                guest_open: false,
+            });
+        }
+        let config = ConsoleConfig {
+            cols: 80,
+            rows: 25,
+            max_nr_ports: ports.len() as u32,
+            emerg_wr: 0,
+        };
+        let ring = if ports.iter().any(|port| port.oppo_end_vmid == CONSOLE_RING_VMID) {
+            ConsoleRing::new().map(Mutex::new)
+        } else {
+            None
+        };
         ConsoleDesc {
-            inner: Mutex::new(desc),
+            inner: Mutex::new(ConsoleDescInner {
+                ports,
+                ctrl_pending: VecDeque::new(),
+                config,
+                emerg_line: Vec::new(),
+            }),
+            ring,
         }
     }
 
-    fn start_addr(&self) -> usize {
+    // (bytes overwritten since the last read, bytes copied to `out`) of the ring, if the console has one
+    fn ring_pop(&self, out: &mut [u8]) -> Option<(usize, usize)> {
+        Some(self.ring.as_ref()?.lock().read(out))
+    }
+
+    // the config space seen by the guest
+    pub fn config<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
         let inner = self.inner.lock();
-        &inner.cols as *const _ as usize
+        let config = &inner.config as *const ConsoleConfig as *const u8;
+        f(unsafe { core::slice::from_raw_parts(config, size_of::<ConsoleConfig>()) })
     }
 
-    pub fn offset_data(&self, emu_ctx: &EmuContext, offset: usize) -> u64 {
-        let start_addr = self.start_addr();
-        match emu_ctx.width {
-            1 => unsafe { *((start_addr + offset) as *const u8) as u64 },
-            2 => unsafe { *((start_addr + offset) as *const u16) as u64 },
-            4 => unsafe { *((start_addr + offset) as *const u32) as u64 },
-            8 => unsafe { *((start_addr + offset) as *const u64) },
-            _ => 0,
-        }
+    pub fn port_num(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.ports.len()
     }
 
-    pub fn target_console(&self) -> (u16, u64) {
+    pub fn target_console(&self, port: usize) -> Option<(u16, u64)> {
         let inner = self.inner.lock();
-        (inner.oppo_end_vmid, inner.oppo_end_ipa)
+        inner
+            .ports
+            .get(port)
+            .map(|port| (port.oppo_end_vmid, port.oppo_end_ipa))
+    }
+
+    // the port connected to the console at `ipa` of VM `vmid`
+    fn port_of_peer(&self, vmid: usize, ipa: usize) -> Option<usize> {
+        let inner = self.inner.lock();
+        inner
+            .ports
+            .iter()
+            .position(|port| port.oppo_end_vmid as usize == vmid && port.oppo_end_ipa as usize == ipa)
+    }
+
+    fn control(&self, vm_id: usize, msg: VirtioConsoleControl) {
+        let mut inner = self.inner.lock();
+        let id = msg.id as usize;
+        match msg.event as usize {
+            VIRTIO_CONSOLE_DEVICE_READY if msg.value == 1 => {
+                inner.ctrl_pending.clear();
+                for id in 0..inner.ports.len() {
+                    let msg = VirtioConsoleControl::new(id, VIRTIO_CONSOLE_DEVICE_ADD, 0);
+                    inner.ctrl_pending.push_back(msg);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if id < inner.ports.len() && msg.value == 1 => {
+                // every port is a console, the guest gets a hvc for each one
+                let msgs = [
+                    VirtioConsoleControl::new(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1),
+                    VirtioConsoleControl::new(id, VIRTIO_CONSOLE_PORT_OPEN, 1),
+                ];
+                inner.ctrl_pending.extend(msgs);
+            }
+            VIRTIO_CONSOLE_PORT_OPEN if id < inner.ports.len() => {
+                inner.ports[id].guest_open = msg.value != 0;
+            }
+            _ => {
+                warn!("VM {} virtio console: ignore control message {:?}", vm_id, msg);
+            }
+        }
+    }
+
+    // `emerg_wr` is the only field written by the driver
+    pub fn emerg_writable(offset: usize, width: usize) -> bool {
+        offset == CONSOLE_EMERG_WR_OFFSET && width == size_of::<u32>()
+    }
+
+    /* A character written to `emerg_wr`, before the driver is ready or without any queue,
+     * e.g. by an early crash handler of the guest. It goes to the hypervisor console line by line.
+     */
+    pub fn emerg_write(&self, vm_id: usize, val: u32) {
+        let mut inner = self.inner.lock();
+        let c = val as u8;
+        if c != b'\n' {
+            inner.emerg_line.push(c);
+        }
+        if c == b'\n' || inner.emerg_line.len() >= CONSOLE_EMERG_LINE_MAX {
+            let line = core::mem::take(&mut inner.emerg_line);
+            drop(inner);
+            println!(
+                "[VM{}] {}",
+                vm_id,
+                core::str::from_utf8(&line).unwrap_or("<invalid utf-8>")
+            );
+        }
+    }
+
+    fn ctrl_pop(&self) -> Option<VirtioConsoleControl> {
+        let mut inner = self.inner.lock();
+        inner.ctrl_pending.pop_front()
+    }
+
+    fn ctrl_push_front(&self, msg: VirtioConsoleControl) {
+        let mut inner = self.inner.lock();
+        inner.ctrl_pending.push_front(msg);
     }
 }
 
+// the device config space seen by the guest
 #[repr(C)]
 #[derive(Clone, Copy)]
-struct ConsoleDescInner {
-    oppo_end_vmid: u16,
-    oppo_end_ipa: u64,
-    // vm access
+struct ConsoleConfig {
     cols: u16,
     rows: u16,
     max_nr_ports: u32,
     emerg_wr: u32,
 }
 
-impl ConsoleDescInner {
-    fn default() -> ConsoleDescInner {
-        ConsoleDescInner {
-            oppo_end_vmid: 0,
-            oppo_end_ipa: 0,
-            cols: 0,
-            rows: 0,
-            max_nr_ports: 0,
-            emerg_wr: 0,
-        }
-    }
+struct ConsoleDescInner {
+    ports: Vec<ConsolePort>,
+    // control messages waiting for buffers in the control rx queue
+    ctrl_pending: VecDeque<VirtioConsoleControl>,
+    config: ConsoleConfig,
+    // the emergency write not printed yet
+    emerg_line: Vec<u8>,
 }
 
-pub fn console_features() -> usize {
-    VIRTIO_F_VERSION_1 | VIRTIO_CONSOLE_F_SIZE
+// multiport is only offered with more than one port, a single port keeps the plain rx/tx pair
+pub fn console_features(port_num: usize) -> usize {
+    let features = VIRTIO_F_VERSION_1 | VIRTIO_RING_F_EVENT_IDX | VIRTIO_CONSOLE_F_SIZE | VIRTIO_CONSOLE_F_EMERG_WRITE;
+    if port_num > 1 {
+        features | VIRTIO_CONSOLE_F_MULTIPORT
+    } else {
+        features
+    }
 }
 
 pub fn virtio_console_notify_handler(vq: Arc<Virtq>, console: Arc<VirtioMmio>, vm: Arc<Vm>) -> bool {
-    if vq.vq_indx() % 4 != 1 {
+    let vq_idx = vq.vq_indx();
+    if vq_idx % 2 == 0 && vq_idx != 2 {
         // println!("console rx queue notified!");
         return true;
     }
 
     if vq.ready() == 0 {
-        println!("virtio_console_notify_handler: console virt_queue is not ready!");
+        warn!("virtio_console_notify_handler: console virt_queue is not ready!");
         return false;
     }
 
-    let dev = console.dev();
-
-    let (trgt_vmid, trgt_console_ipa) = match dev.desc() {
-        DevDesc::Console(desc) => desc.target_console(),
+    let desc = match console.dev().desc() {
+        DevDesc::Console(desc) => desc,
         _ => {
-            println!("virtio_console_notify_handler: console desc should not be None");
+            warn!("virtio_console_notify_handler: console desc should not be None");
+            return false;
+        }
+    };
+
+    match vq_idx {
+        // the driver added buffers for the control messages
+        2 => {
+            virtio_console_ctrl_flush(&console, desc, &vm);
+            true
+        }
+        3 => virtio_console_ctrl_handler(&vq, &console, desc, &vm),
+        _ => virtio_console_port_tx(&vq, &console, desc, &vm, console_port_of_tx_vq(vq_idx)),
+    }
+}
+
+fn virtio_console_port_tx(vq: &Virtq, console: &VirtioMmio, desc: &ConsoleDesc, vm: &Vm, port: usize) -> bool {
+    let (trgt_vmid, trgt_console_ipa) = match desc.target_console(port) {
+        Some(target) => target,
+        None => {
+            warn!("virtio_console_port_tx: vm[{}] has no console port {}", vm.id(), port);
             return false;
         }
     };
 
     while let Some(head_idx) = vq.pop_avail_desc_idx(vq.avail_idx()) {
-        let mut idx = head_idx as usize;
         let mut len = 0;
         let mut tx_iov = VirtioIov::default();
 
-        loop {
-            let addr = vm.ipa2hva(vq.desc_addr(idx));
-            if addr == 0 {
-                println!("virtio_console_notify_handler: failed to desc addr");
-                return false;
+        let mut chain_valid = true;
+        for desc in vq.desc_chain(head_idx, vm) {
+            match desc {
+                Ok(desc) => {
+                    tx_iov.push_data(desc.hva, desc.len as usize);
+                    len += desc.len as usize;
+                }
+                Err(_) => {
+                    chain_valid = false;
+                    break;
+                }
             }
-            tx_iov.push_data(addr, vq.desc_len(idx) as usize);
-
-            len += vq.desc_len(idx) as usize;
-            if vq.desc_flags(idx) == 0 {
-                break;
+        }
+        if !chain_valid {
+            warn!(
+                "virtio_console_notify_handler: vm[{}] drop illegal desc chain, head {}",
+                vm.id(),
+                head_idx
+            );
+            if !vq.update_used_ring(0, head_idx as u32) {
+                return false;
             }
-            idx = vq.desc_next(idx) as usize;
+            continue;
         }
 
-        if !virtio_console_recv(trgt_vmid, trgt_console_ipa, tx_iov, len) {
-            println!("virtio_console_notify_handler: failed send");
+        if trgt_vmid == CONSOLE_RING_VMID {
+            if let Some(ring) = desc.ring.as_ref() {
+                ring.lock().push_iov(&tx_iov, len);
+            }
+        } else if !virtio_console_recv((vm.id(), console.base()), trgt_vmid, trgt_console_ipa, tx_iov, len) {
+            warn!("virtio_console_notify_handler: failed send");
             // return false;
         }
         if !vq.update_used_ring(len as u32, head_idx as u32) {
@@ -147,19 +416,114 @@ pub fn virtio_console_notify_handler(vq: Arc<Virtq>, console: Arc<VirtioMmio>, v
     }
 
     if !vq.avail_is_avail() {
-        println!("invalid descriptor table index");
+        warn!("invalid descriptor table index");
         return false;
     }
 
-    console.notify();
+    if vq.should_notify() {
+        console.notify();
+    }
 
     true
 }
 
-fn virtio_console_recv(trgt_vmid: u16, trgt_console_ipa: u64, tx_iov: VirtioIov, len: usize) -> bool {
+// handle the control messages from the driver in queue 3
+fn virtio_console_ctrl_handler(vq: &Virtq, console: &VirtioMmio, desc: &ConsoleDesc, vm: &Vm) -> bool {
+    while let Some(head_idx) = vq.pop_avail_desc_idx(vq.avail_idx()) {
+        let mut len = 0;
+        let mut iov = VirtioIov::default();
+        let mut chain_valid = true;
+        for desc in vq.desc_chain(head_idx, vm) {
+            match desc {
+                Ok(desc) => {
+                    iov.push_data(desc.hva, desc.len as usize);
+                    len += desc.len as usize;
+                }
+                Err(_) => {
+                    chain_valid = false;
+                    break;
+                }
+            }
+        }
+        if chain_valid && len >= size_of::<VirtioConsoleControl>() {
+            let msg = VirtioConsoleControl::default();
+            iov.copy_to_buf(&msg as *const _ as usize, size_of::<VirtioConsoleControl>());
+            desc.control(vm.id(), msg);
+        } else {
+            warn!(
+                "virtio_console_ctrl_handler: vm[{}] drop illegal control message, head {}",
+                vm.id(),
+                head_idx
+            );
+        }
+        if !vq.update_used_ring(len as u32, head_idx as u32) {
+            return false;
+        }
+    }
+
+    if vq.should_notify() {
+        console.notify();
+    }
+    virtio_console_ctrl_flush(console, desc, vm);
+    true
+}
+
+// deliver the pending control messages to the driver through queue 2
+fn virtio_console_ctrl_flush(console: &VirtioMmio, desc: &ConsoleDesc, vm: &Vm) {
+    let vq = match console.vq(2) {
+        Ok(vq) if vq.ready() != 0 => vq,
+        _ => return,
+    };
+    let mut delivered = false;
+    while let Some(msg) = desc.ctrl_pop() {
+        let head_idx = match vq.pop_avail_desc_idx(vq.avail_idx()) {
+            Some(head_idx) => head_idx,
+            None => {
+                // wait for the driver to add buffers
+                desc.ctrl_push_front(msg);
+                break;
+            }
+        };
+        let mut len = 0;
+        let mut iov = VirtioIov::default();
+        for desc in vq.desc_chain(head_idx, vm).flatten() {
+            if desc.is_writable() {
+                iov.push_data(desc.hva, desc.len as usize);
+                len += desc.len as usize;
+            }
+        }
+        let used_len = if len >= size_of::<VirtioConsoleControl>() {
+            iov.copy_from_buf(&msg as *const _ as usize, size_of::<VirtioConsoleControl>());
+            size_of::<VirtioConsoleControl>()
+        } else {
+            warn!(
+                "virtio_console_ctrl_flush: vm[{}] drop control message {:?}, buffer too small",
+                vm.id(),
+                msg
+            );
+            0
+        };
+        if !vq.update_used_ring(used_len as u32, head_idx as u32) {
+            return;
+        }
+        delivered = true;
+    }
+    if delivered && vq.should_notify() {
+        console.notify();
+    }
+}
+
+// `src` is the VM id and console ipa of the sender, to find the port connected to it
+fn virtio_console_recv(
+    src: (usize, usize),
+    trgt_vmid: u16,
+    trgt_console_ipa: u64,
+    tx_iov: VirtioIov,
+    len: usize,
+) -> bool {
     let trgt_vm = match vm_by_id(trgt_vmid as usize) {
         None => {
-            println!("target vm [{}] is not ready or not exist", trgt_vmid);
+            warn!("target vm [{}] is not ready or not exist", trgt_vmid);
             return true;
         }
         Some(vm) => vm,
@@ -177,17 +541,30 @@ fn virtio_console_recv(trgt_vmid: u16, trgt_console_ipa: u64, tx_iov: VirtioIov,
     };
 
     if !console.dev().activated() {
-        println!(
+        warn!(
             "virtio_console_recv: trgt_vm[{}] virtio console dev is not ready",
             trgt_vmid
         );
         return false;
     }
 
-    let rx_vq = match console.vq(0) {
+    // peers not configured as ports of the target fall back to port 0
+    let port = match console.dev().desc() {
+        DevDesc::Console(desc) => desc.port_of_peer(src.0, src.1).unwrap_or(0),
+        _ => 0,
+    };
+    if port != 0 && console.driver_features() & VIRTIO_CONSOLE_F_MULTIPORT == 0 {
+        warn!(
+            "virtio_console_recv: trgt_vm[{}] does not use console port {}",
+            trgt_vmid, port
+        );
+        return true;
+    }
+
+    let rx_vq = match console.vq(console_port_rx_vq(port)) {
         Ok(x) => x,
         Err(_) => {
-            println!(
+            warn!(
                 "virtio_console_recv: trgt_vm[{}] failed to get virtio console rx virt queue",
                 trgt_vmid
             );
@@ -197,7 +574,7 @@ fn virtio_console_recv(trgt_vmid: u16, trgt_console_ipa: u64, tx_iov: VirtioIov,
 
     let desc_header_idx_opt = rx_vq.pop_avail_desc_idx(rx_vq.avail_idx());
     if !rx_vq.avail_is_avail() {
-        println!("virtio_console_recv: receive invalid avail desc idx");
+        warn!("virtio_console_recv: receive invalid avail desc idx");
         return false;
     } else if desc_header_idx_opt.is_none() {
         // println!("virtio_console_recv: desc_header_idx_opt.is_none()");
@@ -205,46 +582,40 @@ fn virtio_console_recv(trgt_vmid: u16, trgt_console_ipa: u64, tx_iov: VirtioIov,
     }
 
     let desc_idx_header = desc_header_idx_opt.unwrap();
-    let mut desc_idx = desc_header_idx_opt.unwrap() as usize;
     let mut rx_iov = VirtioIov::default();
     let mut rx_len = 0;
-    loop {
-        let dst = trgt_vm.ipa2hva(rx_vq.desc_addr(desc_idx));
-        if dst == 0 {
-            println!(
-                "virtio_console_recv: failed to get dst, desc_idx {}, avail idx {}",
-                desc_idx,
-                rx_vq.avail_idx()
-            );
-            return false;
-        }
-        let desc_len = rx_vq.desc_len(desc_idx) as usize;
-        // dirty pages
-        if trgt_vmid != 0 {
-            let mut ipa_addr = round_down(rx_vq.desc_addr(desc_idx), PAGE_SIZE);
-            while ipa_addr <= round_down(rx_vq.desc_addr(desc_idx) + desc_len, PAGE_SIZE) {
-                ipa_addr += PAGE_SIZE;
+    for desc in rx_vq.desc_chain(desc_idx_header, &trgt_vm) {
+        let desc = match desc {
+            Ok(desc) => desc,
+            Err(_) => {
+                warn!(
+                    "virtio_console_recv: trgt_vm[{}] illegal rx desc chain, head {}, avail idx {}",
+                    trgt_vmid,
+                    desc_idx_header,
+                    rx_vq.avail_idx()
+                );
+                rx_vq.update_used_ring(0, desc_idx_header as u32);
+                return false;
             }
-        }
-        rx_iov.push_data(dst, desc_len);
+        };
+        let desc_len = desc.len as usize;
+        // the buffer is written by the hypervisor below
+        trgt_vm.dirty_log_mark(desc.addr, desc_len);
+        rx_iov.push_data(desc.hva, desc_len);
         rx_len += desc_len;
         if rx_len >= len {
             break;
         }
-        if rx_vq.desc_flags(desc_idx) & 0x1 == 0 {
-            break;
-        }
-        desc_idx = rx_vq.desc_next(desc_idx) as usize;
     }
 
     if rx_len < len {
         rx_vq.put_back_avail_desc_idx();
-        println!("virtio_console_recv: rx_len smaller than tx_len");
+        warn!("virtio_console_recv: rx_len smaller than tx_len");
         return false;
     }
 
     if tx_iov.write_through_iov(&rx_iov, len) > 0 {
-        println!(
+        warn!(
             "virtio_console_recv: write through iov failed, rx_iov_num {} tx_iov_num {} rx_len {} tx_len {}",
             rx_iov.num(),
             tx_iov.num(),
@@ -255,7 +626,7 @@ fn virtio_console_recv(trgt_vmid: u16, trgt_console_ipa: u64, tx_iov: VirtioIov,
     }
 
     if !rx_vq.update_used_ring(len as u32, desc_idx_header as u32) {
-        println!(
+        warn!(
             "virtio_console_recv: update used ring failed len {} rx_vq num {}",
             len,
             rx_vq.num()
@@ -263,6 +634,24 @@ fn virtio_console_recv(trgt_vmid: u16, trgt_console_ipa: u64, tx_iov: VirtioIov,
         return false;
     }
 
-    console.notify();
+    if rx_vq.should_notify() {
+        console.notify();
+    }
     true
 }
+
+/* Drain the hypervisor ring of the first console of `vm` that has one into `out`.
+ * Return (bytes overwritten since the last read, bytes copied).
+ */
+pub fn virtio_console_ring_read(vm: &Vm, out: &mut [u8]) -> Option<(usize, usize)> {
+    vm.config()
+        .emulated_device_list()
+        .iter()
+        .filter(|emu_cfg| emu_cfg.emu_type == EmuDeviceType::EmuDeviceTVirtioConsole)
+        .filter_map(|emu_cfg| vm.find_emu_dev(emu_cfg.base_ipa))
+        .filter_map(|dev| dev.into_any_arc().downcast::<VirtioMmio>().ok())
+        .find_map(|console| match console.dev().desc() {
+            DevDesc::Console(desc) => desc.ring_pop(out),
+            _ => None,
+        })
+}
@@ -4,9 +4,10 @@ use crate::config::VmEmulatedDeviceConfig;
 
 #[cfg(feature = "balloon")]
 use super::balloon::{balloon_features, VirtioBallonConfig};
-use super::blk::{blk_features, BlkDesc, VirtioBlkReq};
+use super::blk::{blk_features, BlkDesc, VirtioBlkReq, BLK_COALESCE_TIMEOUT_US};
 use super::console::{console_features, ConsoleDesc};
 use super::net::{net_features, NetDesc};
+use super::rng::rng_features;
 
 #[derive(Copy, Clone, Debug)]
 #[allow(dead_code)]
@@ -15,6 +16,7 @@ pub enum VirtioDeviceType {
     Net = 1,
     Block = 2,
     Console = 3,
+    Rng = 4,
     #[cfg(feature = "balloon")]
     Balloon = 5,
 }
@@ -23,6 +25,7 @@ pub enum DevDesc {
     Blk(BlkDesc),
     Net(NetDesc),
     Console(ConsoleDesc),
+    Rng,
     #[cfg(feature = "balloon")]
     Balloon(VirtioBallonConfig),
 }
@@ -42,29 +45,38 @@ impl VirtDev {
         let (desc, features, req) = match dev_type {
             VirtioDeviceType::Block => {
                 let desc = DevDesc::Blk(BlkDesc::new(config.cfg_list[1]));
+                let read_only = config.cfg_list.get(5).is_some_and(|ro| *ro != 0);
 
                 // TODO: blk_features_init & cache init
-                let features = blk_features();
+                let features = blk_features(read_only);
 
                 let mut blk_req = VirtioBlkReq::default();
                 blk_req.set_start(config.cfg_list[0]);
                 blk_req.set_mediated(config.mediated);
+                blk_req.set_read_only(read_only);
                 blk_req.set_size(config.cfg_list[1]);
+                blk_req.set_coalesce(
+                    config.cfg_list.get(2).copied().unwrap_or(1),
+                    config.cfg_list.get(3).copied().unwrap_or(BLK_COALESCE_TIMEOUT_US),
+                    config.cfg_list.get(4).is_some_and(|poll| *poll != 0),
+                );
                 (desc, features, Some(blk_req))
             }
             VirtioDeviceType::Net => {
-                let desc = DevDesc::Net(NetDesc::new(&config.cfg_list));
-
-                let features = net_features();
+                let desc = NetDesc::new(&config.cfg_list);
+                let features = net_features(desc.max_queue_pairs());
+                let desc = DevDesc::Net(desc);
 
                 (desc, features, None)
             }
             VirtioDeviceType::Console => {
-                let desc = DevDesc::Console(ConsoleDesc::new(config.cfg_list[0] as u16, config.cfg_list[1] as u64));
-                let features = console_features();
+                let desc = ConsoleDesc::new(&config.cfg_list);
+                let features = console_features(desc.port_num());
+                let desc = DevDesc::Console(desc);
 
                 (desc, features, None)
             }
+            VirtioDeviceType::Rng => (DevDesc::Rng, rng_features(), None),
             #[cfg(feature = "balloon")]
             VirtioDeviceType::Balloon => {
                 let config = DevDesc::Balloon(VirtioBallonConfig::new(config.cfg_list[0]));
@@ -94,6 +106,19 @@ impl VirtDev {
         inner.generation
     }
 
+    // the config space changed, a driver reading it across the change sees another generation and retries
+    pub fn config_changed(&self) {
+        let mut inner = self.inner.lock();
+        inner.generation = inner.generation.wrapping_add(1);
+    }
+
+    // count a write to a read-only field of the config space, return the count
+    pub fn config_ro_write(&self) -> usize {
+        let mut inner = self.inner.lock();
+        inner.config_ro_writes += 1;
+        inner.config_ro_writes
+    }
+
     pub fn desc(&self) -> &DevDesc {
         &self.desc
     }
@@ -127,6 +152,8 @@ impl VirtDev {
 struct VirtDevInner {
     activated: bool,
     generation: usize,
+    // ignored guest writes to read-only config fields
+    config_ro_writes: usize,
 }
 
 impl VirtDevInner {
@@ -134,6 +161,7 @@ impl VirtDevInner {
         VirtDevInner {
             activated: false,
             generation: 0,
+            config_ro_writes: 0,
         }
     }
 }
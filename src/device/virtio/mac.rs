@@ -18,8 +18,16 @@ impl MacAddress {
     }
 }
 
-pub fn set_mac_info(mac: &[u8], nic: Arc<VirtioMmio>) {
-    MAC2NIC_INFO.lock().insert(MacAddress::new(mac), nic);
+// a MAC of a nic from a live VM is never taken over, the frames for it would go to the new nic,
+// the VM of `nic` is still being created
+pub fn set_mac_info(mac: &[u8], nic: Arc<VirtioMmio>) -> bool {
+    let mut mac2nic = MAC2NIC_INFO.lock();
+    if let Some(owner) = mac2nic.get(&MacAddress::new(mac)).and_then(|nic| nic.upper_vm()) {
+        error!("set_mac_info: MAC {:02x?} is used by VM[{}]", mac, owner.id());
+        return false;
+    }
+    mac2nic.insert(MacAddress::new(mac), nic);
+    true
 }
 
 pub fn mac_to_nic(mac: &[u8]) -> Option<Arc<VirtioMmio>> {
@@ -1,13 +1,17 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use spin::Mutex;
 
-use crate::device::{virtio_blk_notify_handler, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT};
+use crate::arch::{PAGE_SIZE, PTE_S2_NORMAL};
+use crate::device::{virtio_mediated_blk_req_handler, SECTOR_BSIZE, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT};
+use crate::kernel::access::vm_ipa2hva;
 use crate::kernel::IpiMessage;
 use crate::kernel::{
-    active_vm, hvc_send_msg_to_vm, vm_list_walker, AsyncTaskState, HvcDefaultMsg, HvcGuestMsg, IpiInnerMsg, Vm,
-    EXECUTOR, HVC_MEDIATED, HVC_MEDIATED_DEV_NOTIFY, HVC_MEDIATED_DRV_NOTIFY,
+    active_vm, hvc_send_msg_to_vm, vm_by_id, vm_list_walker, AsyncTaskState, HvcGuestMsg, HvcMediatedMsg, IpiInnerMsg,
+    Vm, EXECUTOR, HVC_MEDIATED, HVC_MEDIATED_DEV_NOTIFY, HVC_MEDIATED_DRV_NOTIFY,
 };
 use shyper::MediatedBlkContent;
 
@@ -18,16 +22,16 @@ pub static MEDIATED_BLK_LIST: Mutex<Vec<MediatedBlk>> = Mutex::new(Vec::new());
 pub fn mediated_blk_list_push(mut blk: MediatedBlk) {
     let mut list = MEDIATED_BLK_LIST.lock();
     vm_list_walker(|vm| {
-        if let Some(id) = vm.config().mediated_block_index() {
-            if id == list.len() {
-                info!("Assign blk[{}] to VM {}", list.len(), vm.id());
-                blk.avail = false;
-                #[cfg(feature = "static-config")]
-                {
-                    // NOTE: here, VM0 must monopolize Core 0
-                    use crate::vmm::vmm_boot_vm;
-                    vmm_boot_vm(vm.id());
-                }
+        let block_idx = vm.config().mediated_block_index();
+        if block_idx.contains(&list.len()) {
+            info!("Assign blk[{}] to VM {}", list.len(), vm.id());
+            blk.avail = false;
+            // boot the VM once the last of its blks is there
+            #[cfg(feature = "static-config")]
+            if block_idx.iter().all(|&idx| idx <= list.len()) {
+                // NOTE: here, VM0 must monopolize Core 0
+                use crate::vmm::vmm_boot_vm;
+                let _ = vmm_boot_vm(vm.id());
             }
         }
     });
@@ -46,9 +50,29 @@ pub fn mediated_blk_request() -> Result<usize, ()> {
     Err(())
 }
 
+// take a specific blk if it is not assigned to any VM
+pub fn mediated_blk_acquire(idx: usize) -> bool {
+    let mut list = MEDIATED_BLK_LIST.lock();
+    match list.get_mut(idx) {
+        Some(blk) if blk.avail => {
+            blk.avail = false;
+            true
+        }
+        _ => false,
+    }
+}
+
+// a blk a config names before VM0 has appended it is not in the list yet
 pub fn mediated_blk_free(idx: usize) {
     let mut list = MEDIATED_BLK_LIST.lock();
-    list[idx].avail = true;
+    if let Some(blk) = list.get_mut(idx) {
+        blk.avail = true;
+    }
+}
+
+// the number of mediated blks VM0 has appended
+pub fn mediated_blk_num() -> usize {
+    MEDIATED_BLK_LIST.lock().len()
 }
 
 pub fn mediated_blk_list_get(idx: usize) -> MediatedBlk {
@@ -56,11 +80,12 @@ pub fn mediated_blk_list_get(idx: usize) -> MediatedBlk {
     list[idx].clone()
 }
 
-pub fn mediated_blk_list_get_from_pa(pa: usize) -> Option<MediatedBlk> {
+// the index and the mediated blk at `pa`
+pub fn mediated_blk_list_get_from_pa(pa: usize) -> Option<(usize, MediatedBlk)> {
     let list = MEDIATED_BLK_LIST.lock();
-    for blk in &*list {
+    for (idx, blk) in list.iter().enumerate() {
         if blk.base_addr == pa {
-            return Some(blk.clone());
+            return Some((idx, blk.clone()));
         }
     }
     None
@@ -70,6 +95,12 @@ pub fn mediated_blk_list_get_from_pa(pa: usize) -> Option<MediatedBlk> {
 pub struct MediatedBlk {
     pub base_addr: usize,
     pub avail: bool, // mediated blk will not be removed after append
+    // pa of each page of the cache in VM0, empty if the cache can not be remapped for zero-copy IO
+    cache_pages: Arc<Vec<usize>>,
+    /* VM 0 is completing a request of this blk with HVC_MEDIATED_DEV_NOTIFY_POLL, it sees the requests
+     * posted meanwhile from the returned nreq, shared by the clones of the blk
+     */
+    completing: Arc<AtomicBool>,
 }
 
 impl MediatedBlk {
@@ -120,14 +151,28 @@ impl MediatedBlk {
 // only run in vm0
 pub fn mediated_dev_append(_class_id: usize, mmio_ipa: usize) -> Result<usize, ()> {
     let vm = active_vm().unwrap();
-    let blk_pa = vm.ipa2hva(mmio_ipa);
-    let mediated_blk = MediatedBlk {
+    let blk_pa = vm_ipa2hva(&vm, mmio_ipa, size_of::<MediatedBlkContent>()).map_err(|_| ())?;
+    let mut mediated_blk = MediatedBlk {
         base_addr: blk_pa,
         avail: true,
+        cache_pages: Arc::new(Vec::new()),
+        completing: Arc::new(AtomicBool::new(false)),
     };
     mediated_blk.set_nreq(0);
 
-    let cache_pa = vm.ipa2hva(mediated_blk.cache_ipa());
+    let cache_size = mediated_blk.dma_block_max() * SECTOR_BSIZE;
+    let cache_pa = vm_ipa2hva(&vm, mediated_blk.cache_ipa(), cache_size).map_err(|_| ())?;
+    if cfg!(feature = "mediated-zero-copy") && mediated_blk.cache_ipa() % PAGE_SIZE == 0 {
+        // the cache pages are swapped in stage-2 for zero-copy IO, they must be mapped before
+        vm.lazy_populate(mediated_blk.cache_ipa(), cache_size);
+        let pages: Option<Vec<usize>> = (0..cache_size / PAGE_SIZE)
+            .map(|i| vm.ipa2pa(mediated_blk.cache_ipa() + i * PAGE_SIZE))
+            .collect();
+        match pages {
+            Some(pages) => mediated_blk.cache_pages = Arc::new(pages),
+            None => warn!("mediated_dev_append: cache is not mapped, zero-copy IO is off"),
+        }
+    }
     info!(
         "mediated_dev_append: dev_ipa_reg {:#x}, cache ipa {:#x}, cache_pa {:#x}, dma_block_max {:#x}",
         mmio_ipa,
@@ -140,27 +185,36 @@ pub fn mediated_dev_append(_class_id: usize, mmio_ipa: usize) -> Result<usize, (
     Ok(0)
 }
 
-// service VM finish blk request, and inform the requested VM
-pub fn mediated_blk_notify_handler(dev_ipa_reg: usize) -> Result<usize, ()> {
-    let dev_pa_reg = active_vm().unwrap().ipa2hva(dev_ipa_reg);
+/* Service VM finish blk request, and inform the requested VM.
+ * With `poll` (HVC_MEDIATED_DEV_NOTIFY_POLL) the nreq of the blk is returned, and the requests posted to it
+ * while it completes may be left unnotified, HVC_MEDIATED_DEV_NOTIFY returns 0 as it always did.
+ */
+pub fn mediated_blk_notify_handler(dev_ipa_reg: usize, poll: bool) -> Result<usize, ()> {
+    let dev_pa_reg = vm_ipa2hva(&active_vm().unwrap(), dev_ipa_reg, size_of::<MediatedBlkContent>()).map_err(|_| ())?;
 
     // check weather src vm is still alive
-    let mediated_blk = match mediated_blk_list_get_from_pa(dev_pa_reg) {
-        Some(blk) => blk,
+    let (blk_id, mediated_blk) = match mediated_blk_list_get_from_pa(dev_pa_reg) {
+        Some(res) => res,
         None => {
-            println!("illegal mediated blk pa {:x} ipa {:x}", dev_pa_reg, dev_ipa_reg);
+            warn!("illegal mediated blk pa {:x} ipa {:x}", dev_pa_reg, dev_ipa_reg);
             return Err(());
         }
     };
     if !mediated_blk.avail {
         // finish current IO task
-        EXECUTOR.set_front_io_task_state(AsyncTaskState::Finish);
+        EXECUTOR.set_front_io_task_state(blk_id, AsyncTaskState::Finish);
     } else {
-        println!("Mediated blk not belong to any VM");
+        warn!("Mediated blk not belong to any VM");
     }
     // invoke the excuter to handle finished IO task
+    if !poll {
+        EXECUTOR.exec();
+        return Ok(0);
+    }
+    mediated_blk.completing.store(true, Ordering::Release);
     EXECUTOR.exec();
-    Ok(0)
+    mediated_blk.completing.store(false, Ordering::Release);
+    Ok(mediated_blk.nreq())
 }
 
 #[allow(dead_code)]
@@ -177,53 +231,112 @@ fn check_sum(addr: usize, len: usize) -> usize {
 pub fn mediated_ipi_handler(msg: IpiMessage) {
     // println!("core {} mediated_ipi_handler", current_cpu().id);
     if let IpiInnerMsg::MediatedMsg(mediated_msg) = msg.ipi_message {
-        // generate IO request in `virtio_blk_notify_handler`
-        virtio_blk_notify_handler(mediated_msg.vq, mediated_msg.blk, mediated_msg.src_vm);
+        // generate IO request in `virtio_mediated_blk_req_handler`
+        virtio_mediated_blk_req_handler(
+            mediated_msg.vq,
+            mediated_msg.blk,
+            mediated_msg.src_vm,
+            mediated_msg.blk_id,
+        );
         // invoke the executor to do IO request
         EXECUTOR.exec();
     }
 }
 
-pub fn mediated_blk_read(blk_idx: usize, sector: usize, count: usize) {
+/* Post a request to the shared MediatedBlkContent of blk `blk_idx`, return if VM 0 is notified.
+ * Requests other than reads are notified as HVC_MEDIATED_DRV_NOTIFY, VM 0 tells a write from a flush
+ * (VIRTIO_BLK_T_FLUSH, sync the backing storage) by the request type.
+ * If `mvm_poll` is set and VM 0 is completing the previous request of this blk with
+ * HVC_MEDIATED_DEV_NOTIFY_POLL, the notification is left out, the backend finds the request by comparing
+ * the nreq returned from the completion.
+ */
+pub fn mediated_blk_submit(blk_idx: usize, req_type: usize, sector: usize, count: usize, mvm_poll: bool) -> bool {
     let mediated_blk = mediated_blk_list_get(blk_idx);
     let nreq = mediated_blk.nreq();
     mediated_blk.set_nreq(nreq + 1);
-    mediated_blk.set_type(VIRTIO_BLK_T_IN);
+    mediated_blk.set_type(req_type);
     mediated_blk.set_sector(sector);
     mediated_blk.set_count(count);
 
-    let med_msg = HvcDefaultMsg {
+    if mvm_poll && mediated_blk.completing.load(Ordering::Acquire) {
+        return false;
+    }
+    let med_msg = HvcMediatedMsg {
         fid: HVC_MEDIATED,
-        event: HVC_MEDIATED_DEV_NOTIFY,
+        event: if req_type == VIRTIO_BLK_T_IN {
+            HVC_MEDIATED_DEV_NOTIFY
+        } else {
+            HVC_MEDIATED_DRV_NOTIFY
+        },
+        blk_id: blk_idx,
     };
-
-    if !hvc_send_msg_to_vm(0, &HvcGuestMsg::Default(med_msg)) {
-        println!("mediated_blk_read: failed to notify VM 0");
+    if !hvc_send_msg_to_vm(0, &HvcGuestMsg::Mediated(med_msg)) {
+        warn!("mediated_blk_submit: failed to notify VM 0");
     }
+    true
 }
 
-pub fn mediated_blk_write(blk_idx: usize, sector: usize, count: usize) {
-    let mediated_blk = mediated_blk_list_get(blk_idx);
-    let nreq = mediated_blk.nreq();
-    mediated_blk.set_nreq(nreq + 1);
-    mediated_blk.set_type(VIRTIO_BLK_T_OUT);
-    mediated_blk.set_sector(sector);
-    mediated_blk.set_count(count);
+/* Zero-copy IO: the guest pages of a request are mapped over the cache of the blk in the stage-2 of VM0
+ * while the request is handed to it, so the backend DMAs to and from the guest buffers at the cache ipa
+ * it always uses. The cache pages are mapped back when the request is done or given up, a late DMA then
+ * lands in the cache. MediatedBlkContent is untouched, a backend does not see if a request is zero-copy.
+ */
+
+// the pa of the guest pages behind `iov_list`, empty if the request goes through the cache:
+// a buffer not made of whole pages, more pages than the cache, or the feature is off
+pub fn mediated_blk_zero_copy_pages(blk_idx: usize, vm: &Vm, iov_list: &[BlkIov]) -> Vec<usize> {
+    if !cfg!(feature = "mediated-zero-copy") {
+        return Vec::new();
+    }
+    let window = mediated_blk_list_get(blk_idx).cache_pages.len();
+    let mut pages = Vec::new();
+    for iov in iov_list.iter() {
+        let len = iov.len as usize;
+        if iov.ipa % PAGE_SIZE != 0 || len % PAGE_SIZE != 0 || pages.len() + len / PAGE_SIZE > window {
+            return Vec::new();
+        }
+        for offset in (0..len).step_by(PAGE_SIZE) {
+            match vm.ipa2pa(iov.ipa + offset) {
+                Some(pa) => pages.push(pa),
+                None => return Vec::new(),
+            }
+        }
+    }
+    pages
+}
 
-    let med_msg = HvcDefaultMsg {
-        fid: HVC_MEDIATED,
-        event: HVC_MEDIATED_DRV_NOTIFY,
-    };
+// map `pages` over the cache of blk `blk_idx` in VM0, in order
+pub fn mediated_blk_zero_copy_map(blk_idx: usize, pages: &[usize]) {
+    let blk = mediated_blk_list_get(blk_idx);
+    let vm0 = vm_by_id(0).unwrap();
+    for (i, &pa) in pages.iter().enumerate() {
+        let ipa = blk.cache_ipa() + i * PAGE_SIZE;
+        // break before make, the tlb of the cache page is invalidated by the unmap
+        vm0.pt_unmap_range(ipa, PAGE_SIZE);
+        vm0.pt_map_range(ipa, PAGE_SIZE, pa, PTE_S2_NORMAL, false);
+    }
+}
 
-    // println!("mediated_blk_write send msg to vm0");
-    if !hvc_send_msg_to_vm(0, &HvcGuestMsg::Default(med_msg)) {
-        println!("mediated_blk_write: failed to notify VM 0");
+// map the first `num` pages of the cache of blk `blk_idx` back in VM0
+pub fn mediated_blk_zero_copy_unmap(blk_idx: usize, num: usize) {
+    let blk = mediated_blk_list_get(blk_idx);
+    let vm0 = vm_by_id(0).unwrap();
+    for (i, &pa) in blk.cache_pages.iter().take(num).enumerate() {
+        let ipa = blk.cache_ipa() + i * PAGE_SIZE;
+        vm0.pt_unmap_range(ipa, PAGE_SIZE);
+        vm0.pt_map_range(ipa, PAGE_SIZE, pa, PTE_S2_NORMAL, false);
     }
 }
 
+pub fn mediated_blk_write(blk_idx: usize, sector: usize, count: usize) {
+    mediated_blk_submit(blk_idx, VIRTIO_BLK_T_OUT, sector, count, false);
+}
+
 pub struct UsedInfo {
     pub desc_chain_head_idx: u32,
     pub used_len: u32,
+    // hva of the status byte of the request
+    pub status: usize,
 }
 
 pub struct ReadAsyncMsg {
@@ -235,6 +348,17 @@ pub struct ReadAsyncMsg {
     pub count: usize,
     pub cache: usize,
     pub iov_list: Arc<Vec<BlkIov>>,
+    // the guest pages mapped for VM0, empty if the data goes through the cache
+    pub zero_copy: Vec<usize>,
+    pub used_info: UsedInfo,
+}
+
+// a flush is completed after VM0 notifies that the backing storage is synced
+pub struct FlushAsyncMsg {
+    pub src_vm: Arc<Vm>,
+    pub vq: Arc<Virtq>,
+    pub dev: Arc<VirtioMmio>,
+    pub blk_id: usize,
     pub used_info: UsedInfo,
 }
 
@@ -247,5 +371,7 @@ pub struct WriteAsyncMsg {
     pub count: usize,
     pub cache: usize,
     pub buffer: Arc<Mutex<Vec<u8>>>,
+    // the guest pages mapped for VM0, the buffer is empty then
+    pub zero_copy: Vec<usize>,
     pub used_info: UsedInfo,
 }
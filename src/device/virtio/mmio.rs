@@ -6,17 +6,23 @@ use crate::config::VmEmulatedDeviceConfig;
 use crate::device::EmuContext;
 use crate::device::Virtq;
 use crate::device::{EmuDev, EmuDeviceType};
-use crate::kernel::interrupt_vm_inject;
+use crate::kernel::interrupt_vm_inject_to;
 use crate::kernel::Vm;
-use crate::kernel::{active_vm, current_cpu, ipi_send_msg, IpiInnerMsg, IpiIntInjectMsg, IpiType};
+use crate::kernel::{active_vm, current_cpu};
+use crate::mm::{HeapTag, HeapTagGuard};
 
 use super::blk::{virtio_blk_notify_handler, virtio_mediated_blk_notify_handler, VIRTQUEUE_BLK_MAX_SIZE};
-use super::console::{virtio_console_notify_handler, VIRTQUEUE_CONSOLE_MAX_SIZE};
-use super::dev::{VirtDev, VirtioDeviceType};
+use super::console::{virtio_console_notify_handler, ConsoleDesc, VIRTQUEUE_CONSOLE_MAX_SIZE};
+use super::dev::{DevDesc, VirtDev, VirtioDeviceType};
 use super::net::{virtio_net_handle_ctrl, virtio_net_notify_handler, VIRTQUEUE_NET_MAX_SIZE};
 use super::queue::VIRTQ_READY;
+use super::rng::{virtio_rng_notify_handler, VIRTQUEUE_RNG_MAX_SIZE};
 
 pub const VIRTIO_F_VERSION_1: usize = 1 << 32;
+/* a single queue can be reset through QueueReset */
+pub const VIRTIO_F_RING_RESET: usize = 1 << 40;
+/* used_event and avail_event in the rings replace the notification flags */
+pub const VIRTIO_RING_F_EVENT_IDX: usize = 1 << 29;
 pub const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000;
 pub const VIRTIO_MMIO_VERSION: usize = 0x004;
 pub const VIRTIO_MMIO_DEVICE_ID: usize = 0x008;
@@ -39,6 +45,7 @@ pub const VIRTIO_MMIO_QUEUE_AVAIL_LOW: usize = 0x090;
 pub const VIRTIO_MMIO_QUEUE_AVAIL_HIGH: usize = 0x094;
 pub const VIRTIO_MMIO_QUEUE_USED_LOW: usize = 0x0a0;
 pub const VIRTIO_MMIO_QUEUE_USED_HIGH: usize = 0x0a4;
+pub const VIRTIO_MMIO_QUEUE_RESET: usize = 0x0c0;
 pub const VIRTIO_MMIO_CONFIG_GENERATION: usize = 0x0fc;
 pub const VIRTIO_MMIO_CONFIG: usize = 0x100;
 pub const VIRTIO_MMIO_REGS_END: usize = 0x200;
@@ -46,6 +53,13 @@ pub const VIRTIO_MMIO_REGS_END: usize = 0x200;
 pub const VIRTIO_MMIO_INT_VRING: u32 = 1 << 0;
 pub const VIRTIO_MMIO_INT_CONFIG: u32 = 1 << 1;
 
+// device status bit, the driver is set up and the device is live
+pub const VIRTIO_CONFIG_S_DRIVER_OK: u32 = 0x4;
+// device status bit, the device hit an error and the driver must reset it
+pub const VIRTIO_CONFIG_S_NEEDS_RESET: u32 = 0x40;
+// device status bit, the driver gave up on the device
+pub const VIRTIO_CONFIG_S_FAILED: u32 = 0x80;
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 struct VirtMmioRegs {
@@ -58,7 +72,6 @@ struct VirtMmioRegs {
     drv_feature: u32,
     drv_feature_sel: u32,
     q_sel: u32,
-    q_num_max: u32,
     irt_stat: u32,
     irt_ack: u32,
     dev_stat: u32,
@@ -76,7 +89,6 @@ impl VirtMmioRegs {
             drv_feature: 0,
             drv_feature_sel: 0,
             q_sel: 0,
-            q_num_max: 0,
             irt_stat: 0,
             irt_ack: 0,
             dev_stat: 0,
@@ -128,44 +140,58 @@ impl VirtioMmio {
         inner.regs.init(dev_type);
     }
 
-    pub fn set_q_num_max(&self, q_num_max: u32) {
-        let mut inner = self.inner.lock();
-        inner.regs.q_num_max = q_num_max;
-    }
-
     fn virtio_queue_init(&mut self, weak: &Weak<VirtioMmio>, dev_type: VirtioDeviceType) {
         match dev_type {
             VirtioDeviceType::Block => {
-                self.set_q_num_max(VIRTQUEUE_BLK_MAX_SIZE as u32);
                 let queue = if self.inner_const.dev.mediated() {
-                    Virtq::new(0, weak.clone(), virtio_mediated_blk_notify_handler)
+                    Virtq::new(
+                        0,
+                        VIRTQUEUE_BLK_MAX_SIZE,
+                        weak.clone(),
+                        virtio_mediated_blk_notify_handler,
+                    )
                 } else {
-                    Virtq::new(0, weak.clone(), virtio_blk_notify_handler)
+                    Virtq::new(0, VIRTQUEUE_BLK_MAX_SIZE, weak.clone(), virtio_blk_notify_handler)
                 };
                 self.inner_const.vq.push(queue);
             }
             VirtioDeviceType::Net => {
-                self.set_q_num_max(VIRTQUEUE_NET_MAX_SIZE as u32);
-                // Not support feature VIRTIO_NET_F_CTRL_VQ (no control queue)
-                for i in 0..2 {
-                    let queue = Virtq::new(i, weak.clone(), virtio_net_notify_handler);
+                let pairs = match self.inner_const.dev.desc() {
+                    DevDesc::Net(desc) => desc.max_queue_pairs(),
+                    _ => 1,
+                };
+                // a rx/tx pair for each queue pair, and the control queue after them
+                for i in 0..2 * pairs {
+                    let queue = Virtq::new(i, VIRTQUEUE_NET_MAX_SIZE, weak.clone(), virtio_net_notify_handler);
                     self.inner_const.vq.push(queue);
                 }
-                let queue = Virtq::new(2, weak.clone(), virtio_net_handle_ctrl);
+                let queue = Virtq::new(2 * pairs, VIRTQUEUE_NET_MAX_SIZE, weak.clone(), virtio_net_handle_ctrl);
                 self.inner_const.vq.push(queue);
             }
             VirtioDeviceType::Console => {
-                self.set_q_num_max(VIRTQUEUE_CONSOLE_MAX_SIZE as u32);
-                for i in 0..4 {
-                    let queue = Virtq::new(i, weak.clone(), virtio_console_notify_handler);
+                let port_num = match self.inner_const.dev.desc() {
+                    DevDesc::Console(desc) => desc.port_num(),
+                    _ => 1,
+                };
+                // a rx/tx pair for each port, and the control pair at 2 and 3
+                for i in 0..2 * (port_num + 1) {
+                    let queue = Virtq::new(
+                        i,
+                        VIRTQUEUE_CONSOLE_MAX_SIZE,
+                        weak.clone(),
+                        virtio_console_notify_handler,
+                    );
                     self.inner_const.vq.push(queue);
                 }
             }
+            VirtioDeviceType::Rng => {
+                let queue = Virtq::new(0, VIRTQUEUE_RNG_MAX_SIZE, weak.clone(), virtio_rng_notify_handler);
+                self.inner_const.vq.push(queue);
+            }
             #[cfg(feature = "balloon")]
             VirtioDeviceType::Balloon => {
-                self.set_q_num_max(256_u32);
                 for i in 0..2 {
-                    let queue = Virtq::new(i, weak.clone(), super::balloon::virtio_balloon_notify_handler);
+                    let queue = Virtq::new(i, 256, weak.clone(), super::balloon::virtio_balloon_notify_handler);
                     self.inner_const.vq.push(queue);
                 }
             }
@@ -179,61 +205,47 @@ impl VirtioMmio {
         self.inner_const.vm.upgrade()
     }
 
-    pub fn notify_config(&self) {
+    /* Set `bit` of InterruptStatus, the irq is only injected when the bit was clear. Until the driver
+     * writes it to InterruptACK, it has not handled the last irq yet, and will see the new event with it.
+     */
+    fn raise_irt(&self, bit: u32) {
         let mut inner = self.inner.lock();
-        inner.regs.irt_stat |= VIRTIO_MMIO_INT_CONFIG;
+        let raised = inner.regs.irt_stat & bit == 0;
+        inner.regs.irt_stat |= bit;
         drop(inner);
-        let vm = self.upper_vm().unwrap();
-        let int_id = self.dev().int_id();
-        let target_vcpu = vm.vcpu(0).unwrap();
-        if target_vcpu.phys_id() == current_cpu().id {
-            interrupt_vm_inject(&vm, target_vcpu, int_id);
-        } else {
-            let m = IpiIntInjectMsg { vm_id: vm.id(), int_id };
-            if !ipi_send_msg(target_vcpu.phys_id(), IpiType::IntInject, IpiInnerMsg::IntInjectMsg(m)) {
-                error!("notify_config: failed to send ipi to Core {}", target_vcpu.phys_id());
-            }
+        if raised {
+            let vm = self.upper_vm().unwrap();
+            let int_id = self.dev().int_id();
+            interrupt_vm_inject_to(&vm, vm.vcpu(0).unwrap(), int_id);
         }
     }
 
+    pub fn notify_config(&self) {
+        self.dev().config_changed();
+        self.raise_irt(VIRTIO_MMIO_INT_CONFIG);
+    }
+
     pub fn notify(&self) {
-        let mut inner = self.inner.lock();
-        inner.regs.irt_stat |= VIRTIO_MMIO_INT_VRING;
-        drop(inner);
-        let vm = self.upper_vm().unwrap();
-        let int_id = self.dev().int_id();
-        let target_vcpu = vm.vcpu(0).unwrap();
-        if target_vcpu.phys_id() == current_cpu().id {
-            interrupt_vm_inject(&vm, target_vcpu, int_id);
-        } else {
-            let m = IpiIntInjectMsg { vm_id: vm.id(), int_id };
-            if !ipi_send_msg(target_vcpu.phys_id(), IpiType::IntInject, IpiInnerMsg::IntInjectMsg(m)) {
-                error!("notify_config: failed to send ipi to Core {}", target_vcpu.phys_id());
-            }
-        }
+        self.raise_irt(VIRTIO_MMIO_INT_VRING);
     }
 
-    // virtio_dev_reset
+    // virtio_dev_reset, the queues are unconfigured and the features have to be negotiated again
     pub fn dev_reset(&self) {
         let mut inner = self.inner.lock();
         inner.regs.dev_stat = 0;
         inner.regs.irt_stat = 0;
-        let idx = inner.regs.q_sel as usize;
-        let vq = &self.inner_const.vq;
-        vq[idx].set_ready(0);
-        for virtq in vq.iter() {
+        inner.regs.drv_feature = 0;
+        inner.driver_features = 0;
+        for virtq in self.inner_const.vq.iter() {
             virtq.reset();
         }
         self.dev().set_activated(false);
     }
 
-    pub fn set_irt_stat(&self, irt_stat: u32) {
-        let mut inner = self.inner.lock();
-        inner.regs.irt_stat = irt_stat;
-    }
-
-    pub fn set_irt_ack(&self, irt_ack: u32) {
+    // the bits of InterruptStatus are only cleared by the driver through InterruptACK
+    pub fn ack_irt(&self, irt_ack: u32) {
         let mut inner = self.inner.lock();
+        inner.regs.irt_stat &= !irt_ack;
         inner.regs.irt_ack = irt_ack;
     }
 
@@ -264,7 +276,7 @@ impl VirtioMmio {
 
     pub fn set_drv_feature_sel(&self, drv_feature_sel: u32) {
         let mut inner = self.inner.lock();
-        inner.regs.drv_feature = drv_feature_sel;
+        inner.regs.drv_feature_sel = drv_feature_sel;
     }
 
     pub fn or_driver_feature(&self, driver_features: usize) {
@@ -272,6 +284,11 @@ impl VirtioMmio {
         inner.driver_features |= driver_features;
     }
 
+    pub fn driver_features(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.driver_features
+    }
+
     pub(super) fn dev(&self) -> &VirtDev {
         &self.inner_const.dev
     }
@@ -316,17 +333,12 @@ impl VirtioMmio {
         inner.regs.drv_feature_sel
     }
 
-    pub fn q_num_max(&self) -> u32 {
-        let inner = self.inner.lock();
-        inner.regs.q_num_max
-    }
-
     pub fn irt_stat(&self) -> u32 {
         let inner = self.inner.lock();
         inner.regs.irt_stat
     }
 
-    pub fn vq(&self, idx: usize) -> Result<&Virtq, ()> {
+    pub fn vq(&self, idx: usize) -> Result<&Arc<Virtq>, ()> {
         match self.inner_const.vq.get(idx) {
             Some(vq) => Ok(vq),
             None => Err(()),
@@ -373,9 +385,13 @@ fn virtio_mmio_prologue_access(mmio: &VirtioMmio, emu_ctx: &EmuContext, offset:
                 value
             }
             VIRTIO_MMIO_STATUS => mmio.dev_stat(),
+            // the write-only registers read as 0
             _ => {
-                error!("virtio_be_init_handler wrong reg_read, address={:#x}", emu_ctx.address);
-                return;
+                warn!(
+                    "virtio_mmio_prologue_access: read of write-only reg {:#x}",
+                    emu_ctx.address
+                );
+                0
             }
         };
         let idx = emu_ctx.reg;
@@ -396,6 +412,11 @@ fn virtio_mmio_prologue_access(mmio: &VirtioMmio, emu_ctx: &EmuContext, offset:
             }
             VIRTIO_MMIO_GUEST_FEATURES_SEL => mmio.set_drv_feature_sel(value),
             VIRTIO_MMIO_STATUS => {
+                // NEEDS_RESET sticks until the driver resets the device
+                let value = match value {
+                    0 => 0,
+                    _ => value | (mmio.dev_stat() & VIRTIO_CONFIG_S_NEEDS_RESET),
+                };
                 mmio.set_dev_stat(value);
                 if mmio.dev_stat() == 0 {
                     mmio.dev_reset();
@@ -404,7 +425,10 @@ fn virtio_mmio_prologue_access(mmio: &VirtioMmio, emu_ctx: &EmuContext, offset:
                         active_vm().unwrap().id(),
                         mmio.base()
                     );
-                } else if mmio.dev_stat() == 0xf {
+                } else if mmio.dev_stat() & (VIRTIO_CONFIG_S_DRIVER_OK | VIRTIO_CONFIG_S_FAILED)
+                    == VIRTIO_CONFIG_S_DRIVER_OK
+                    && !mmio.dev().activated()
+                {
                     mmio.dev().set_activated(true);
                     info!(
                         "VM {} virtio device {:x} init ok",
@@ -423,26 +447,18 @@ fn virtio_mmio_prologue_access(mmio: &VirtioMmio, emu_ctx: &EmuContext, offset:
 fn virtio_mmio_queue_access(mmio: &VirtioMmio, emu_ctx: &EmuContext, offset: usize, write: bool) {
     if !write {
         let value = match offset {
-            VIRTIO_MMIO_QUEUE_NUM_MAX => mmio.q_num_max(),
-            VIRTIO_MMIO_QUEUE_READY => {
-                let idx = mmio.q_sel() as usize;
-                match mmio.vq(idx) {
-                    Ok(virtq) => virtq.ready() as u32,
-                    Err(_) => {
-                        error!(
-                            "virtio_mmio_queue_access: wrong q_sel {:x} in read VIRTIO_MMIO_QUEUE_READY",
-                            idx
-                        );
-                        return;
-                    }
-                }
-            }
+            // a queue that does not exist reads as not available
+            VIRTIO_MMIO_QUEUE_NUM_MAX => mmio.vq(mmio.q_sel() as usize).map_or(0, |virtq| virtq.num_max() as u32),
+            VIRTIO_MMIO_QUEUE_READY => mmio.vq(mmio.q_sel() as usize).map_or(0, |virtq| virtq.ready() as u32),
+            // the reset of a queue is done before the write returns
+            VIRTIO_MMIO_QUEUE_RESET => 0,
+            // the write-only registers read as 0
             _ => {
-                error!(
-                    "virtio_mmio_queue_access: wrong reg_read, address {:x}",
+                warn!(
+                    "virtio_mmio_queue_access: read of write-only reg {:#x}",
                     emu_ctx.address
                 );
-                return;
+                0
             }
         };
         let idx = emu_ctx.reg;
@@ -459,52 +475,49 @@ fn virtio_mmio_queue_access(mmio: &VirtioMmio, emu_ctx: &EmuContext, offset: usi
                 match offset {
                     VIRTIO_MMIO_QUEUE_NUM => virtq.set_num(value),
                     VIRTIO_MMIO_QUEUE_READY => {
-                        virtq.set_ready(value);
-                        if value == VIRTQ_READY {
-                            info!(
-                                "VM {} virtio device {:x} queue {} ready",
-                                active_vm().unwrap().id(),
+                        let vm = active_vm().unwrap();
+                        if value == VIRTQ_READY && virtq.setup_rings(&vm).is_err() {
+                            // leave the queue disabled, the driver has to reset the device
+                            warn!(
+                                "VM {} virtio device {:x} queue {} has invalid rings, needs reset",
+                                vm.id(),
                                 mmio.base(),
                                 q_sel
                             );
+                            mmio.set_dev_stat(mmio.dev_stat() | VIRTIO_CONFIG_S_NEEDS_RESET);
+                            mmio.notify_config();
+                            return;
+                        }
+                        virtq.set_event_idx(mmio.driver_features() & VIRTIO_RING_F_EVENT_IDX != 0);
+                        virtq.set_ready(value);
+                        if value == VIRTQ_READY {
+                            info!("VM {} virtio device {:x} queue {} ready", vm.id(), mmio.base(), q_sel);
                         } else {
                             warn!(
                                 "VM {} virtio device {:x} queue {} init failed",
-                                active_vm().unwrap().id(),
+                                vm.id(),
                                 mmio.base(),
                                 q_sel
                             );
                         }
                     }
+                    // the rings are translated and checked when the queue is set ready
                     VIRTIO_MMIO_QUEUE_DESC_LOW => virtq.or_desc_table_addr(value & u32::MAX as usize),
-                    VIRTIO_MMIO_QUEUE_DESC_HIGH => {
-                        virtq.or_desc_table_addr(value << 32);
-                        let desc_table_addr = active_vm().unwrap().ipa2hva(virtq.desc_table_addr());
-                        if desc_table_addr == 0 {
-                            error!("virtio_mmio_queue_access: invalid desc_table_addr");
-                            return;
-                        }
-                        virtq.set_desc_table(desc_table_addr);
-                    }
+                    VIRTIO_MMIO_QUEUE_DESC_HIGH => virtq.or_desc_table_addr(value << 32),
                     VIRTIO_MMIO_QUEUE_AVAIL_LOW => virtq.or_avail_addr(value & u32::MAX as usize),
-                    VIRTIO_MMIO_QUEUE_AVAIL_HIGH => {
-                        virtq.or_avail_addr(value << 32);
-                        let avail_addr = active_vm().unwrap().ipa2hva(virtq.avail_addr());
-                        if avail_addr == 0 {
-                            error!("virtio_mmio_queue_access: invalid avail_addr");
-                            return;
-                        }
-                        virtq.set_avail(avail_addr);
-                    }
+                    VIRTIO_MMIO_QUEUE_AVAIL_HIGH => virtq.or_avail_addr(value << 32),
                     VIRTIO_MMIO_QUEUE_USED_LOW => virtq.or_used_addr(value & u32::MAX as usize),
-                    VIRTIO_MMIO_QUEUE_USED_HIGH => {
-                        virtq.or_used_addr(value << 32);
-                        let used_addr = active_vm().unwrap().ipa2hva(virtq.used_addr());
-                        if used_addr == 0 {
-                            error!("virtio_mmio_queue_access: invalid used_addr");
-                            return;
+                    VIRTIO_MMIO_QUEUE_USED_HIGH => virtq.or_used_addr(value << 32),
+                    VIRTIO_MMIO_QUEUE_RESET => {
+                        if value == 1 {
+                            virtq.reset();
+                            info!(
+                                "VM {} virtio device {:x} queue {} is reset",
+                                active_vm().unwrap().id(),
+                                mmio.base(),
+                                q_sel
+                            );
                         }
-                        virtq.set_used(used_addr);
                     }
                     _ => error!("virtio_mmio_queue_access: wrong reg write {:#x}", emu_ctx.address),
                 }
@@ -515,39 +528,105 @@ fn virtio_mmio_queue_access(mmio: &VirtioMmio, emu_ctx: &EmuContext, offset: usi
     }
 }
 
+// `width` bytes at `offset` of a config space in little-endian, None if they are not all inside it
+fn config_read_le(config: &[u8], offset: usize, width: usize) -> Option<u64> {
+    if !matches!(width, 1 | 2 | 4 | 8) {
+        return None;
+    }
+    let bytes = config.get(offset..offset.checked_add(width)?)?;
+    let mut buf = [0; 8];
+    buf[..width].copy_from_slice(bytes);
+    Some(u64::from_le_bytes(buf))
+}
+
+/* A guest read of the config space of a blk of `capacity` sectors or of a console with one port,
+ * `emu_type` tells which. None is a read out of the config or of another device.
+ */
+#[cfg(feature = "self-test")]
+pub fn virtio_config_read_synthetic(
+    emu_type: EmuDeviceType,
+    capacity: usize,
+    offset: usize,
+    width: usize,
+) -> Option<u64> {
+    match emu_type {
+        EmuDeviceType::EmuDeviceTVirtioBlk => {
+            super::blk::BlkDesc::new(capacity).config(|config| config_read_le(config, offset, width))
+        }
+        EmuDeviceType::EmuDeviceTVirtioConsole => {
+            ConsoleDesc::new(&[]).config(|config| config_read_le(config, offset, width))
+        }
+        _ => None,
+    }
+}
+
+fn virtio_mmio_config_read(mmio: &VirtioMmio, offset: usize, width: usize) -> Option<u64> {
+    match mmio.dev().desc() {
+        DevDesc::Blk(blk_desc) => blk_desc.config(|config| config_read_le(config, offset, width)),
+        DevDesc::Net(net_desc) => net_desc.config(|config| config_read_le(config, offset, width)),
+        DevDesc::Console(console_desc) => console_desc.config(|config| config_read_le(config, offset, width)),
+        #[cfg(feature = "balloon")]
+        DevDesc::Balloon(config) => config.config(|config| config_read_le(config, offset, width)),
+        DevDesc::Rng => None,
+    }
+}
+
 fn virtio_mmio_cfg_access(mmio: &VirtioMmio, emu_ctx: &EmuContext, offset: usize, write: bool) {
     if !write {
         let value = match offset {
             VIRTIO_MMIO_CONFIG_GENERATION => mmio.dev().generation() as u64,
-            VIRTIO_MMIO_CONFIG..=0x1ff => match mmio.dev().desc() {
-                super::dev::DevDesc::Blk(blk_desc) => blk_desc.offset_data(emu_ctx, offset - VIRTIO_MMIO_CONFIG),
-                super::dev::DevDesc::Net(net_desc) => net_desc.offset_data(emu_ctx, offset - VIRTIO_MMIO_CONFIG),
-                #[cfg(feature = "balloon")]
-                super::dev::DevDesc::Balloon(config) => config.read_config(emu_ctx, offset - VIRTIO_MMIO_CONFIG),
-                _ => {
-                    error!("unknow desc type");
-                    return;
+            VIRTIO_MMIO_CONFIG..=0x1ff => {
+                let cfg_offset = offset - VIRTIO_MMIO_CONFIG;
+                match virtio_mmio_config_read(mmio, cfg_offset, emu_ctx.width) {
+                    Some(value) => value,
+                    None => {
+                        // never let the read reach the memory behind the config struct
+                        warn!(
+                            "virtio_mmio_cfg_access: device {:#x} config read out of range, offset {:#x} width {}",
+                            mmio.base(),
+                            cfg_offset,
+                            emu_ctx.width
+                        );
+                        0
+                    }
                 }
-            },
+            }
+            // the reserved regs in the range read as 0
             _ => {
-                error!("virtio_mmio_cfg_access: wrong reg write {:#x}", emu_ctx.address);
-                return;
+                warn!("virtio_mmio_cfg_access: read of reserved reg {:#x}", emu_ctx.address);
+                0
             }
         };
         let idx = emu_ctx.reg;
         let val = value as usize;
         current_cpu().set_gpr(idx, val);
-    } else {
+    } else if (VIRTIO_MMIO_CONFIG..=0x1ff).contains(&offset) {
+        let cfg_offset = offset - VIRTIO_MMIO_CONFIG;
         #[cfg(feature = "balloon")]
-        if (VIRTIO_MMIO_CONFIG..=0x1ff).contains(&offset) {
-            let val = current_cpu().get_gpr(emu_ctx.reg) as u64;
-            match mmio.dev().desc() {
-                super::dev::DevDesc::Balloon(config) => config.write_config(emu_ctx, offset - VIRTIO_MMIO_CONFIG, val),
-                _ => {
-                    error!("unknow desc type");
-                }
+        if let DevDesc::Balloon(config) = mmio.dev().desc() {
+            if super::balloon::VirtioBallonConfig::writable(cfg_offset, emu_ctx.width) {
+                let val = current_cpu().get_gpr(emu_ctx.reg) as u64;
+                config.write_config(emu_ctx, cfg_offset, val);
+                return;
+            }
+        }
+        // taken in any device status, the queues may not be set up yet
+        if let DevDesc::Console(console_desc) = mmio.dev().desc() {
+            if ConsoleDesc::emerg_writable(cfg_offset, emu_ctx.width) {
+                let vm_id = mmio.upper_vm().map_or(usize::MAX, |vm| vm.id());
+                console_desc.emerg_write(vm_id, current_cpu().get_gpr(emu_ctx.reg) as u32);
+                return;
             }
         }
+        let count = mmio.dev().config_ro_write();
+        warn!(
+            "virtio_mmio_cfg_access: device {:#x} ignores write to read-only config offset {:#x} ({} ignored)",
+            mmio.base(),
+            cfg_offset,
+            count
+        );
+    } else {
+        error!("virtio_mmio_cfg_access: wrong reg write {:#x}", emu_ctx.address);
     }
 }
 
@@ -556,8 +635,9 @@ pub fn emu_virtio_mmio_init(vm: Weak<Vm>, emu_cfg: &VmEmulatedDeviceConfig) -> R
         EmuDeviceType::EmuDeviceTVirtioBlk => VirtioDeviceType::Block,
         EmuDeviceType::EmuDeviceTVirtioNet => VirtioDeviceType::Net,
         EmuDeviceType::EmuDeviceTVirtioConsole => VirtioDeviceType::Console,
+        EmuDeviceType::EmuDeviceTVirtioRng => VirtioDeviceType::Rng,
         #[cfg(feature = "balloon")]
-        EmuDeviceType::VirtioBalloon => VirtioDeviceType::Balloon,
+        EmuDeviceType::EmuDeviceTVirtioBalloon => VirtioDeviceType::Balloon,
         _ => {
             error!("emu_virtio_mmio_init: unknown emulated device type");
             return Err(());
@@ -572,7 +652,9 @@ pub fn emu_virtio_mmio_init(vm: Weak<Vm>, emu_cfg: &VmEmulatedDeviceConfig) -> R
     if emu_cfg.emu_type == EmuDeviceType::EmuDeviceTVirtioNet {
         let nic = mmio.clone();
         let mac = emu_cfg.cfg_list.iter().take(6).map(|&x| x as u8).collect::<Vec<_>>();
-        super::mac::set_mac_info(&mac, nic);
+        if !super::mac::set_mac_info(&mac, nic) {
+            return Err(());
+        }
     }
     Ok(mmio)
 }
@@ -586,17 +668,37 @@ impl EmuDev for VirtioMmio {
         self.inner_const.base..self.inner_const.base + self.inner_const.length
     }
 
+    // as a reset by the driver, the selectors are cleared too since no driver has written them yet
+    fn reset(&self) {
+        self.dev_reset();
+        let mut inner = self.inner.lock();
+        inner.regs.irt_ack = 0;
+        inner.regs.q_sel = 0;
+        inner.regs.dev_feature_sel = 0;
+        inner.regs.drv_feature = 0;
+        inner.regs.drv_feature_sel = 0;
+    }
+
     fn handler(&self, emu_ctx: &EmuContext) -> bool {
+        let _tag = HeapTagGuard::new(HeapTag::Virtio);
         let addr = emu_ctx.address;
         let offset = addr - self.base();
         let write = emu_ctx.write;
 
         if offset == VIRTIO_MMIO_QUEUE_NOTIFY && write {
-            self.set_irt_stat(VIRTIO_MMIO_INT_VRING);
             trace!("in VIRTIO_MMIO_QUEUE_NOTIFY");
             let idx = current_cpu().get_gpr(emu_ctx.reg);
-            if !self.inner_const.vq[idx].call_notify_handler() {
-                error!("Failed to handle virtio mmio request!");
+            match self.vq(idx) {
+                Ok(virtq) => {
+                    if !virtq.call_notify_handler() {
+                        error!("Failed to handle virtio mmio request!");
+                    }
+                }
+                Err(_) => error!(
+                    "emu_virtio_mmio_handler: device {:#x} notify wrong queue {}",
+                    self.base(),
+                    idx
+                ),
             }
         } else if offset == VIRTIO_MMIO_INTERRUPT_STATUS && !write {
             trace!("in VIRTIO_MMIO_INTERRUPT_STATUS");
@@ -604,29 +706,32 @@ impl EmuDev for VirtioMmio {
             let val = self.irt_stat() as usize;
             current_cpu().set_gpr(idx, val);
         } else if offset == VIRTIO_MMIO_INTERRUPT_ACK && write {
-            let idx = emu_ctx.reg;
-            let val = self.irt_stat();
-            self.set_irt_stat(val & !(current_cpu().get_gpr(idx) as u32));
-            self.set_irt_ack(current_cpu().get_gpr(idx) as u32);
+            self.ack_irt(current_cpu().get_gpr(emu_ctx.reg) as u32);
         } else if (VIRTIO_MMIO_MAGIC_VALUE..=VIRTIO_MMIO_GUEST_FEATURES_SEL).contains(&offset)
             || offset == VIRTIO_MMIO_STATUS
         {
             trace!("in virtio_mmio_prologue_access");
             virtio_mmio_prologue_access(self, emu_ctx, offset, write);
-        } else if (VIRTIO_MMIO_QUEUE_SEL..=VIRTIO_MMIO_QUEUE_USED_HIGH).contains(&offset) {
+        } else if (VIRTIO_MMIO_QUEUE_SEL..=VIRTIO_MMIO_QUEUE_USED_HIGH).contains(&offset)
+            || offset == VIRTIO_MMIO_QUEUE_RESET
+        {
             trace!("in virtio_mmio_queue_access");
             virtio_mmio_queue_access(self, emu_ctx, offset, write);
         } else if (VIRTIO_MMIO_CONFIG_GENERATION..=VIRTIO_MMIO_REGS_END).contains(&offset) {
             trace!("in virtio_mmio_cfg_access");
             virtio_mmio_cfg_access(self, emu_ctx, offset, write);
         } else {
-            error!(
-                "emu_virtio_mmio_handler: regs wrong {}, address {:#x}, offset {:#x}",
+            // unimplemented or reserved registers read as 0 and ignore writes, the guest is not faulted
+            warn!(
+                "emu_virtio_mmio_handler: device {:#x} ignores {} of unimplemented reg, address {:#x}, offset {:#x}",
+                self.base(),
                 if write { "write" } else { "read" },
                 addr,
                 offset
             );
-            return false;
+            if !write {
+                current_cpu().set_gpr(emu_ctx.reg, 0);
+            }
         }
         true
     }
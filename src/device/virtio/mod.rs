@@ -1,9 +1,19 @@
-pub use blk::{virtio_blk_notify_handler, BlkIov, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT};
+#[cfg(feature = "balloon")]
+pub use balloon::virtio_balloon_set_target;
+pub use blk::{
+    virtio_blk_complete, virtio_blk_complete_err, virtio_blk_mediated_submit, virtio_blk_stat_dump,
+    virtio_mediated_blk_req_handler, BlkIov, SECTOR_BSIZE, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+};
+pub use console::{virtio_console_ring_read, ConsoleRing, CONSOLE_RING_VMID};
 pub use mac::remove_virtio_nic;
 pub use mediated::*;
+#[cfg(feature = "self-test")]
+pub use mmio::virtio_config_read_synthetic;
 pub use mmio::{emu_virtio_mmio_init, VirtioMmio};
-pub use net::{ethernet_ipi_rev_handler, virtio_net_announce};
+pub use net::{ethernet_ipi_rev_handler, virtio_net_announce, virtio_net_stat, NetStat};
 pub use queue::Virtq;
+#[cfg(feature = "self-test")]
+pub use queue::{desc_chain_walk_synthetic, virtq_rings_synthetic, DescChainError, VIRTQ_DESC_F_NEXT};
 
 #[cfg(feature = "balloon")]
 mod balloon;
@@ -18,3 +28,4 @@ mod mmio;
 #[allow(dead_code)]
 mod net;
 mod queue;
+mod rng;
@@ -1,9 +1,10 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::Mutex;
 
-use crate::device::{EmuContext, VirtioMmio, Virtq};
+use crate::device::{VirtioMmio, Virtq};
 use crate::kernel::IpiMessage;
 use crate::kernel::Vm;
 use crate::kernel::{current_cpu, vm_if_get_cpu_id};
@@ -11,8 +12,7 @@ use crate::kernel::{ipi_send_msg, IpiEthernetMsg, IpiInnerMsg, IpiType};
 
 use super::dev::DevDesc;
 use super::iov::VirtioIov;
-use super::mmio::VIRTIO_F_VERSION_1;
-use super::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+use super::mmio::{VIRTIO_F_RING_RESET, VIRTIO_F_VERSION_1, VIRTIO_RING_F_EVENT_IDX};
 
 pub const VIRTQUEUE_NET_MAX_SIZE: usize = 256;
 
@@ -51,6 +51,7 @@ const VIRTIO_NET_F_CTRL_RX: usize = 1 << 18;
 const VIRTIO_NET_F_CTRL_VLAN: usize = 1 << 19;
 // control channel VLAN filtering
 const VIRTIO_NET_F_GUEST_ANNOUNCE: usize = 1 << 21; // guest can send gratuitous pkts
+const VIRTIO_NET_F_MQ: usize = 1 << 22; // device supports multiple rx/tx queue pairs
 
 const VIRTIO_NET_HDR_F_DATA_VALID: usize = 2;
 
@@ -67,19 +68,142 @@ struct VirtioNetHdr {
     pub num_buffers: u16,
 }
 
+const VIRTIO_NET_QUEUE_PAIRS_MAX: usize = 8;
+
+/* The traffic of the nics of a VM, copied to VM0 by HVC_VMM_NET_STAT.
+ * Keep the layout in sync with the VM0 tools.
+ */
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct NetStat {
+    pub tx_pkts: u64,
+    pub tx_bytes: u64,
+    pub rx_pkts: u64,
+    pub rx_bytes: u64,
+    // frames from the guest dropped: broken desc chain, too short, not forwarded
+    // (broadcast other than arp, multicast other than ipv6), unknown destination mac
+    // and destination without a free rx buffer
+    pub drop_desc: u64,
+    pub drop_short: u64,
+    pub drop_filtered: u64,
+    pub drop_no_dest: u64,
+    pub drop_dest_full: u64,
+    // frames for the guest dropped, it had no free rx buffer
+    pub drop_rx_full: u64,
+}
+
+#[derive(Clone, Copy)]
+enum NetDrop {
+    Desc,
+    Short,
+    Filtered,
+    NoDest,
+    DestFull,
+}
+
+// counters of a queue pair, the tx side is only updated by the core handling the tx queue
+#[derive(Default)]
+struct NetQueueStat {
+    tx_pkts: AtomicUsize,
+    tx_bytes: AtomicUsize,
+    rx_pkts: AtomicUsize,
+    rx_bytes: AtomicUsize,
+    drop_desc: AtomicUsize,
+    drop_short: AtomicUsize,
+    drop_filtered: AtomicUsize,
+    drop_no_dest: AtomicUsize,
+    drop_dest_full: AtomicUsize,
+    drop_rx_full: AtomicUsize,
+}
+
+impl NetQueueStat {
+    fn tx(&self, bytes: usize) {
+        self.tx_pkts.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn rx(&self, bytes: usize) {
+        self.rx_pkts.fetch_add(1, Ordering::Relaxed);
+        self.rx_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn drop(&self, reason: NetDrop) {
+        let counter = match reason {
+            NetDrop::Desc => &self.drop_desc,
+            NetDrop::Short => &self.drop_short,
+            NetDrop::Filtered => &self.drop_filtered,
+            NetDrop::NoDest => &self.drop_no_dest,
+            NetDrop::DestFull => &self.drop_dest_full,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn add_to(&self, stat: &mut NetStat) {
+        let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed) as u64;
+        stat.tx_pkts += load(&self.tx_pkts);
+        stat.tx_bytes += load(&self.tx_bytes);
+        stat.rx_pkts += load(&self.rx_pkts);
+        stat.rx_bytes += load(&self.rx_bytes);
+        stat.drop_desc += load(&self.drop_desc);
+        stat.drop_short += load(&self.drop_short);
+        stat.drop_filtered += load(&self.drop_filtered);
+        stat.drop_no_dest += load(&self.drop_no_dest);
+        stat.drop_dest_full += load(&self.drop_dest_full);
+        stat.drop_rx_full += load(&self.drop_rx_full);
+    }
+}
+
 pub struct NetDesc {
     inner: Mutex<NetDescInner>,
+    // queue pairs in use, set by the driver with VIRTIO_NET_CTRL_MQ
+    queue_pairs: AtomicUsize,
+    stats: [NetQueueStat; VIRTIO_NET_QUEUE_PAIRS_MAX],
 }
 
 impl NetDesc {
-    pub fn new(mac: &[usize]) -> NetDesc {
+    // cfg_list: mac[0..6], queue pairs at 6 (1 if absent or 0)
+    pub fn new(cfg_list: &[usize]) -> NetDesc {
         let mut desc = NetDescInner::default();
-        for (i, item) in mac.iter().enumerate().take(6) {
+        for (i, item) in cfg_list.iter().enumerate().take(6) {
             desc.mac[i] = *item as u8;
         }
+        let pairs = match cfg_list.get(6) {
+            None | Some(0) => 1,
+            Some(pairs) => *pairs,
+        };
+        if !(1..=VIRTIO_NET_QUEUE_PAIRS_MAX).contains(&pairs) {
+            warn!(
+                "virtio net: {} queue pairs is not supported, clamped to 1..={}",
+                pairs, VIRTIO_NET_QUEUE_PAIRS_MAX
+            );
+        }
+        desc.max_virtqueue_pairs = pairs.clamp(1, VIRTIO_NET_QUEUE_PAIRS_MAX) as u16;
         NetDesc {
             inner: Mutex::new(desc),
+            queue_pairs: AtomicUsize::new(1),
+            stats: core::array::from_fn(|_| NetQueueStat::default()),
+        }
+    }
+
+    fn stat(&self, pair: usize) -> &NetQueueStat {
+        &self.stats[pair % VIRTIO_NET_QUEUE_PAIRS_MAX]
+    }
+
+    pub fn max_queue_pairs(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.max_virtqueue_pairs as usize
+    }
+
+    pub fn queue_pairs(&self) -> usize {
+        self.queue_pairs.load(Ordering::Relaxed)
+    }
+
+    fn set_queue_pairs(&self, pairs: usize) -> bool {
+        if !(1..=self.max_queue_pairs()).contains(&pairs) {
+            return false;
         }
+        self.queue_pairs.store(pairs, Ordering::Relaxed);
+        true
     }
 
     pub fn set_status(&self, status: u16) {
@@ -92,16 +216,11 @@ impl NetDesc {
         inner.status
     }
 
-    pub fn offset_data(&self, emu_ctx: &EmuContext, offset: usize) -> u64 {
+    // the config space seen by the guest
+    pub fn config<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
         let inner = self.inner.lock();
-        let start_addr = inner.mac.as_ptr() as usize;
-        match emu_ctx.width {
-            1 => unsafe { *((start_addr + offset) as *const u8) as u64 },
-            2 => unsafe { *((start_addr + offset) as *const u16) as u64 },
-            4 => unsafe { *((start_addr + offset) as *const u32) as u64 },
-            8 => unsafe { *((start_addr + offset) as *const u64) },
-            _ => 0,
-        }
+        let config = &*inner as *const NetDescInner as *const u8;
+        f(unsafe { core::slice::from_raw_parts(config, size_of::<NetDescInner>()) })
     }
 }
 
@@ -113,6 +232,7 @@ pub const VIRTIO_NET_S_ANNOUNCE: u16 = 2;
 struct NetDescInner {
     mac: [u8; 6],
     status: u16,
+    max_virtqueue_pairs: u16,
 }
 
 impl NetDescInner {
@@ -120,6 +240,7 @@ impl NetDescInner {
         NetDescInner {
             mac: [0; 6],
             status: VIRTIO_NET_S_LINK_UP,
+            max_virtqueue_pairs: 1,
         }
     }
 }
@@ -131,8 +252,10 @@ struct VirtioNetCtrlHdr {
     command: u8,
 }
 
-pub fn net_features() -> usize {
-    VIRTIO_F_VERSION_1
+pub fn net_features(queue_pairs: usize) -> usize {
+    let features = VIRTIO_F_VERSION_1
+        | VIRTIO_F_RING_RESET
+        | VIRTIO_RING_F_EVENT_IDX
         | VIRTIO_NET_F_GUEST_CSUM
         | VIRTIO_NET_F_MAC
         | VIRTIO_NET_F_CSUM
@@ -145,49 +268,96 @@ pub fn net_features() -> usize {
         | VIRTIO_NET_F_HOST_ECN
         | VIRTIO_NET_F_CTRL_VQ
         | VIRTIO_NET_F_GUEST_ANNOUNCE
-        | VIRTIO_NET_F_STATUS
+        | VIRTIO_NET_F_STATUS;
+    if queue_pairs > 1 {
+        features | VIRTIO_NET_F_MQ
+    } else {
+        features
+    }
 }
 
 const VIRTIO_NET_CTRL_ANNOUNCE: u8 = 3;
 const VIRTIO_NET_CTRL_ANNOUNCE_ACK: u8 = 0;
+const VIRTIO_NET_CTRL_MQ: u8 = 4;
+const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+
+// rx queue of `nic` for a frame sent from tx queue pair `pair`, spread over the pairs in use
+fn net_rx_queue(nic: &VirtioMmio, pair: usize) -> usize {
+    match nic.dev().desc() {
+        DevDesc::Net(desc) => 2 * (pair % desc.queue_pairs()),
+        _ => 0,
+    }
+}
+
+fn net_stat(nic: &VirtioMmio, pair: usize) -> Option<&NetQueueStat> {
+    match nic.dev().desc() {
+        DevDesc::Net(desc) => Some(desc.stat(pair)),
+        _ => None,
+    }
+}
+
+// the counters of all nics of VM `vm_id` summed up, None if it has no nic
+pub fn virtio_net_stat(vm_id: usize) -> Option<NetStat> {
+    let mut stat = None;
+    super::mac::virtio_nic_list_walker(|nic| {
+        if nic.upper_vm().map(|vm| vm.id()) != Some(vm_id) {
+            return;
+        }
+        if let DevDesc::Net(desc) = nic.dev().desc() {
+            let stat = stat.get_or_insert_with(NetStat::default);
+            for queue in desc.stats.iter() {
+                queue.add_to(stat);
+            }
+        }
+    });
+    stat
+}
 
 pub fn virtio_net_handle_ctrl(vq: Arc<Virtq>, nic: Arc<VirtioMmio>, vm: Arc<Vm>) -> bool {
     if vq.ready() == 0 {
-        println!("virtio net control queue is not ready!");
+        warn!("virtio net control queue is not ready!");
         return false;
     }
 
-    while let Some(head_idx) = vq.pop_avail_desc_idx(vq.avail_idx()) {
-        let mut idx = head_idx as usize;
+    'chain: while let Some(head_idx) = vq.pop_avail_desc_idx(vq.avail_idx()) {
         let mut len = 0;
+        let mut out_len = 0;
         let mut out_iov = VirtioIov::default();
         let mut in_iov = VirtioIov::default();
 
-        loop {
-            let addr = vm.ipa2hva(vq.desc_addr(idx));
-            if addr == 0 {
-                println!("virtio_net_handle_ctrl: failed to desc addr");
-                return false;
-            }
-            if vq.desc_flags(idx) & VIRTQ_DESC_F_WRITE != 0 {
-                in_iov.push_data(addr, vq.desc_len(idx) as usize);
+        for desc in vq.desc_chain(head_idx, &vm) {
+            let desc = match desc {
+                Ok(desc) => desc,
+                Err(_) => {
+                    // only this command is dropped, the ones after it are still handled
+                    warn!("virtio_net_handle_ctrl: vm[{}] illegal desc chain", vm.id());
+                    vq.update_used_ring(0, head_idx as u32);
+                    continue 'chain;
+                }
+            };
+            if desc.is_writable() {
+                vm.dirty_log_mark(desc.addr, desc.len as usize);
+                in_iov.push_data(desc.hva, desc.len as usize);
             } else {
-                out_iov.push_data(addr, vq.desc_len(idx) as usize);
-            }
-            len += vq.desc_len(idx) as usize;
-            if vq.desc_flags(idx) != VIRTQ_DESC_F_NEXT {
-                break;
+                out_iov.push_data(desc.hva, desc.len as usize);
+                out_len += desc.len as usize;
             }
-            idx = vq.desc_next(idx) as usize;
+            len += desc.len as usize;
         }
-        let ctrl = VirtioNetCtrlHdr::default();
-        out_iov.copy_to_buf(&ctrl as *const _ as usize, size_of::<VirtioNetCtrlHdr>());
+        // the header and the u16 argument of VIRTIO_NET_CTRL_MQ
+        let mut cmd = [0_u8; size_of::<VirtioNetCtrlHdr>() + size_of::<u16>()];
+        out_iov.copy_to_buf(cmd.as_mut_ptr() as usize, usize::min(out_len, cmd.len()));
+        let ctrl = VirtioNetCtrlHdr {
+            class: cmd[0],
+            command: cmd[1],
+        };
         match ctrl.class {
             VIRTIO_NET_CTRL_ANNOUNCE => {
                 let status: u8 = if ctrl.command == VIRTIO_NET_CTRL_ANNOUNCE_ACK {
                     match nic.dev().desc() {
                         DevDesc::Net(desc) => {
                             desc.set_status(VIRTIO_NET_S_LINK_UP);
+                            nic.dev().config_changed();
                             VIRTIO_NET_OK
                         }
                         _ => {
@@ -199,8 +369,31 @@ pub fn virtio_net_handle_ctrl(vq: Arc<Virtq>, nic: Arc<VirtioMmio>, vm: Arc<Vm>)
                 };
                 in_iov.copy_from_buf(&status as *const _ as usize, size_of::<u8>());
             }
+            VIRTIO_NET_CTRL_MQ => {
+                let pairs = u16::from_le_bytes([cmd[2], cmd[3]]) as usize;
+                let status: u8 = match nic.dev().desc() {
+                    DevDesc::Net(desc)
+                        if ctrl.command == VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET
+                            && out_len >= cmd.len()
+                            && desc.set_queue_pairs(pairs) =>
+                    {
+                        info!("VM {} virtio net {:x} uses {} queue pairs", vm.id(), nic.base(), pairs);
+                        VIRTIO_NET_OK
+                    }
+                    _ => {
+                        warn!(
+                            "VM {} virtio net {:x} rejects {} queue pairs",
+                            vm.id(),
+                            nic.base(),
+                            pairs
+                        );
+                        VIRTIO_NET_ERR
+                    }
+                };
+                in_iov.copy_from_buf(&status as *const _ as usize, size_of::<u8>());
+            }
             _ => {
-                println!("Control queue header class can't match {}", ctrl.class);
+                warn!("Control queue header class can't match {}", ctrl.class);
             }
         }
 
@@ -209,79 +402,106 @@ pub fn virtio_net_handle_ctrl(vq: Arc<Virtq>, nic: Arc<VirtioMmio>, vm: Arc<Vm>)
             return false;
         }
     }
-    nic.notify();
+    if vq.should_notify() {
+        nic.notify();
+    }
     true
 }
 
 pub fn virtio_net_notify_handler(vq: Arc<Virtq>, nic: Arc<VirtioMmio>, vm: alloc::sync::Arc<Vm>) -> bool {
     if vq.ready() == 0 {
-        println!("net virt_queue is not ready!");
+        warn!("net virt_queue is not ready!");
         return false;
     }
 
-    if vq.vq_indx() != 1 {
+    // rx queues are even, the tx queue of pair n is 2n + 1
+    if vq.vq_indx() % 2 == 0 {
         // println!("net rx queue notified!");
         return true;
     }
+    let pair = vq.vq_indx() / 2;
 
     let mut nics_to_notify = vec![];
 
     while let Some(head_idx) = vq.pop_avail_desc_idx(vq.avail_idx()) {
-        let mut idx = head_idx as usize;
         let mut len = 0;
         let mut tx_iov = VirtioIov::default();
 
-        loop {
-            let addr = vm.ipa2hva(vq.desc_addr(idx));
-            if addr == 0 {
-                println!("virtio_net_notify_handler: failed to desc addr");
-                return false;
-            }
-            tx_iov.push_data(addr, vq.desc_len(idx) as usize);
-
-            len += vq.desc_len(idx) as usize;
-            if vq.desc_flags(idx) == 0 {
-                break;
+        let mut chain_valid = true;
+        for desc in vq.desc_chain(head_idx, &vm) {
+            match desc {
+                Ok(desc) => {
+                    tx_iov.push_data(desc.hva, desc.len as usize);
+                    len += desc.len as usize;
+                }
+                Err(_) => {
+                    chain_valid = false;
+                    break;
+                }
             }
-            idx = vq.desc_next(idx) as usize;
         }
 
-        if let Some(list) = ethernet_transmit(tx_iov, len, &vm) {
-            nics_to_notify.extend(list);
+        let stat = net_stat(&nic, pair);
+        if chain_valid {
+            match ethernet_transmit(tx_iov, len, &vm, pair) {
+                Ok(list) => {
+                    if let Some(stat) = stat {
+                        stat.tx(len - size_of::<VirtioNetHdr>());
+                    }
+                    nics_to_notify.extend(list);
+                }
+                Err(reason) => {
+                    if let Some(stat) = stat {
+                        stat.drop(reason);
+                    }
+                }
+            }
+        } else {
+            warn!(
+                "virtio_net_notify_handler: vm[{}] drop illegal desc chain, head {}",
+                vm.id(),
+                head_idx
+            );
+            if let Some(stat) = stat {
+                stat.drop(NetDrop::Desc);
+            }
+            len = size_of::<VirtioNetHdr>();
         }
 
-        if !vq.update_used_ring((len - size_of::<VirtioNetHdr>()) as u32, head_idx as u32) {
+        if !vq.update_used_ring(len.saturating_sub(size_of::<VirtioNetHdr>()) as u32, head_idx as u32) {
             return false;
         }
     }
 
     if !vq.avail_is_avail() {
-        println!("invalid descriptor table index");
+        warn!("invalid descriptor table index");
         return false;
     }
 
-    nic.notify();
-    for nic in nics_to_notify {
+    if vq.should_notify() {
+        nic.notify();
+    }
+    for (nic, rx_idx) in nics_to_notify {
         let trgt_vm = nic.upper_vm().unwrap();
         let vcpu = trgt_vm.vcpu(0).unwrap();
         if vcpu.phys_id() == current_cpu().id {
-            let rx_vq = match nic.vq(0) {
+            let rx_vq = match nic.vq(rx_idx) {
                 Ok(x) => x,
                 Err(_) => {
-                    println!(
+                    warn!(
                         "virtio_net_notify_handler: vm[{}] failed to get virtio net rx virt queue",
                         vm.id()
                     );
                     return false;
                 }
             };
-            if rx_vq.ready() != 0 && rx_vq.avail_flags() == 0 {
+            if rx_vq.ready() != 0 && rx_vq.should_notify() {
                 nic.notify();
             }
         } else {
-            let msg = IpiEthernetMsg { trgt_nic: nic };
+            let msg = IpiEthernetMsg { trgt_nic: nic, rx_idx };
             let cpu_trgt = vm_if_get_cpu_id(trgt_vm.id()).unwrap();
-            if !ipi_send_msg(cpu_trgt, IpiType::EthernetMsg, IpiInnerMsg::EnternetMsg(msg)) {
+            if ipi_send_msg(cpu_trgt, IpiType::EthernetMsg, IpiInnerMsg::EnternetMsg(msg)).is_err() {
                 error!(
                     "virtio_net_notify_handler: failed to send ipi message, target {}",
                     cpu_trgt
@@ -292,15 +512,16 @@ pub fn virtio_net_notify_handler(vq: Arc<Virtq>, nic: Arc<VirtioMmio>, vm: alloc
     true
 }
 
+// the frame is in the rx queue already, copied by the sending core and counted there
 pub fn ethernet_ipi_rev_handler(msg: IpiMessage) {
     match msg.ipi_message {
         IpiInnerMsg::EnternetMsg(ethernet_msg) => {
             let nic = ethernet_msg.trgt_nic;
             let vm = nic.upper_vm().unwrap();
-            let rx_vq = match nic.vq(0) {
+            let rx_vq = match nic.vq(ethernet_msg.rx_idx) {
                 Ok(x) => x,
                 Err(_) => {
-                    println!(
+                    warn!(
                         "ethernet_ipi_rev_handler: vm[{}] failed to get virtio net rx virt queue",
                         vm.id()
                     );
@@ -308,7 +529,7 @@ pub fn ethernet_ipi_rev_handler(msg: IpiMessage) {
                 }
             };
 
-            if rx_vq.ready() != 0 && rx_vq.avail_flags() == 0 {
+            if rx_vq.ready() != 0 && rx_vq.should_notify() {
                 nic.notify();
             }
         }
@@ -318,72 +539,92 @@ pub fn ethernet_ipi_rev_handler(msg: IpiMessage) {
     }
 }
 
-fn ethernet_transmit(tx_iov: VirtioIov, len: usize, vm: &Vm) -> Option<Vec<Arc<VirtioMmio>>> {
+// returns the nics a frame from tx queue pair `pair` is written to, with the rx queue used for each,
+// or why it is dropped
+fn ethernet_transmit(
+    tx_iov: VirtioIov,
+    len: usize,
+    vm: &Vm,
+    pair: usize,
+) -> Result<Vec<(Arc<VirtioMmio>, usize)>, NetDrop> {
     // [ destination MAC - 6 ][ source MAC - 6 ][ EtherType - 2 ][ Payload ]
     if len < size_of::<VirtioNetHdr>() || len - size_of::<VirtioNetHdr>() < 6 + 6 + 2 {
-        println!(
+        warn!(
             "Too short for an ethernet frame, len {}, size of head {}",
             len,
             size_of::<VirtioNetHdr>()
         );
-        return None;
+        return Err(NetDrop::Short);
     }
 
     let frame: &[u8] = tx_iov.get_ptr(size_of::<VirtioNetHdr>());
     if frame[0..6] == [0xff, 0xff, 0xff, 0xff, 0xff, 0xff] {
         if ethernet_is_arp(frame) {
-            return ethernet_broadcast(&tx_iov, len, vm);
+            return Ok(ethernet_broadcast(&tx_iov, len, vm, pair));
         } else {
-            return None;
+            return Err(NetDrop::Filtered);
         }
     }
 
     if frame[0] == 0x33 && frame[1] == 0x33 {
         if !(frame[12] == 0x86 && frame[13] == 0xdd) {
             // Only IPV6 multicast packet is allowed to be broadcast
-            return None;
+            return Err(NetDrop::Filtered);
         }
-        return ethernet_broadcast(&tx_iov, len, vm);
+        return Ok(ethernet_broadcast(&tx_iov, len, vm, pair));
     }
 
     match ethernet_mac_to_nic(frame) {
         Ok(nic) => {
             let vm = nic.upper_vm().unwrap();
-            if ethernet_send_to(&vm, &nic, &tx_iov, len) {
-                Some(vec![nic])
+            let rx_idx = net_rx_queue(&nic, pair);
+            if ethernet_send_to(&vm, &nic, &tx_iov, len, rx_idx) {
+                Ok(vec![(nic, rx_idx)])
             } else {
-                None
+                Err(NetDrop::DestFull)
             }
         }
-        Err(_) => None,
+        Err(_) => Err(NetDrop::NoDest),
     }
 }
 
-fn ethernet_broadcast(tx_iov: &VirtioIov, len: usize, cur_vm: &Vm) -> Option<Vec<Arc<VirtioMmio>>> {
+// a broadcast reaching no other nic is not a drop
+fn ethernet_broadcast(tx_iov: &VirtioIov, len: usize, cur_vm: &Vm, pair: usize) -> Vec<(Arc<VirtioMmio>, usize)> {
     let mut nic_list = vec![];
     super::mac::virtio_nic_list_walker(|nic| {
         let vm = nic.upper_vm().unwrap();
-        if vm.id() != cur_vm.id() && ethernet_send_to(&vm, nic, tx_iov, len) {
-            nic_list.push(nic.clone());
+        let rx_idx = net_rx_queue(nic, pair);
+        if vm.id() != cur_vm.id() && ethernet_send_to(&vm, nic, tx_iov, len, rx_idx) {
+            nic_list.push((nic.clone(), rx_idx));
         }
     });
-    if nic_list.is_empty() {
-        None
-    } else {
-        Some(nic_list)
+    nic_list
+}
+
+fn ethernet_send_to(vm: &Vm, nic: &VirtioMmio, tx_iov: &VirtioIov, len: usize, rx_idx: usize) -> bool {
+    let stat = net_stat(nic, rx_idx / 2);
+    let received = ethernet_receive(vm, nic, tx_iov, len, rx_idx);
+    if let Some(stat) = stat {
+        if received {
+            stat.rx(len - size_of::<VirtioNetHdr>());
+        } else {
+            stat.drop_rx_full.fetch_add(1, Ordering::Relaxed);
+        }
     }
+    received
 }
 
-fn ethernet_send_to(vm: &Vm, nic: &VirtioMmio, tx_iov: &VirtioIov, len: usize) -> bool {
+// copy a frame to an rx buffer of `nic`
+fn ethernet_receive(vm: &Vm, nic: &VirtioMmio, tx_iov: &VirtioIov, len: usize, rx_idx: usize) -> bool {
     if !nic.dev().activated() {
         // println!("ethernet_send_to: vm[{}] nic dev is not activate", vmid);
         return false;
     }
 
-    let rx_vq = match nic.vq(0) {
+    let rx_vq = match nic.vq(rx_idx) {
         Ok(x) => x,
         Err(_) => {
-            println!(
+            warn!(
                 "ethernet_send_to: vm[{}] failed to get virtio net rx virt queue",
                 vm.id()
             );
@@ -393,7 +634,7 @@ fn ethernet_send_to(vm: &Vm, nic: &VirtioMmio, tx_iov: &VirtioIov, len: usize) -
 
     let desc_header_idx_opt = rx_vq.pop_avail_desc_idx(rx_vq.avail_idx());
     if !rx_vq.avail_is_avail() {
-        println!("ethernet_send_to: receive invalid avail desc idx");
+        warn!("ethernet_send_to: receive invalid avail desc idx");
         return false;
     } else if desc_header_idx_opt.is_none() {
         // println!("ethernet_send_to: desc_header_idx_opt is none");
@@ -401,39 +642,38 @@ fn ethernet_send_to(vm: &Vm, nic: &VirtioMmio, tx_iov: &VirtioIov, len: usize) -
     }
 
     let desc_idx_header = desc_header_idx_opt.unwrap();
-    let mut desc_idx = desc_header_idx_opt.unwrap() as usize;
     let mut rx_iov = VirtioIov::default();
     let mut rx_len = 0;
 
-    loop {
-        let dst = vm.ipa2hva(rx_vq.desc_addr(desc_idx));
-        if dst == 0 {
-            println!(
-                "rx_vq desc base table addr {:#x}, idx {}, avail table addr {:#x}, avail last idx {}",
-                rx_vq.desc_table_addr(),
-                desc_idx,
-                rx_vq.avail_addr(),
-                rx_vq.avail_idx()
-            );
-            println!("ethernet_send_to: failed to get dst {}", vm.id());
-            return false;
-        }
-        let desc_len = rx_vq.desc_len(desc_idx) as usize;
-
-        rx_iov.push_data(dst, desc_len);
+    for desc in rx_vq.desc_chain(desc_idx_header, vm) {
+        let desc = match desc {
+            Ok(desc) => desc,
+            Err(_) => {
+                warn!(
+                    "rx_vq desc base table addr {:#x}, head {}, avail table addr {:#x}, avail last idx {}",
+                    rx_vq.desc_table_addr(),
+                    desc_idx_header,
+                    rx_vq.avail_addr(),
+                    rx_vq.avail_idx()
+                );
+                warn!("ethernet_send_to: failed to get dst {}", vm.id());
+                rx_vq.update_used_ring(0, desc_idx_header as u32);
+                return false;
+            }
+        };
+        let desc_len = desc.len as usize;
+        // the buffer is written by the hypervisor below
+        vm.dirty_log_mark(desc.addr, desc_len);
+        rx_iov.push_data(desc.hva, desc_len);
         rx_len += desc_len;
         if rx_len >= len {
             break;
         }
-        if rx_vq.desc_flags(desc_idx) & 0x1 == 0 {
-            break;
-        }
-        desc_idx = rx_vq.desc_next(desc_idx) as usize;
     }
 
     if rx_len < len {
         rx_vq.put_back_avail_desc_idx();
-        println!("ethernet_send_to: rx_len smaller than tx_len");
+        warn!("ethernet_send_to: rx_len smaller than tx_len");
         return false;
     }
     if tx_iov.get_buf(0) < 0x1000 {
@@ -443,7 +683,7 @@ fn ethernet_send_to(vm: &Vm, nic: &VirtioMmio, tx_iov: &VirtioIov, len: usize) -
     header.num_buffers = 1;
 
     if tx_iov.write_through_iov(&rx_iov, len) > 0 {
-        println!(
+        warn!(
             "ethernet_send_to: write through iov failed, rx_iov_num {} tx_iov_num {} rx_len {} tx_len {}",
             rx_iov.num(),
             tx_iov.num(),
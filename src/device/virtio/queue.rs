@@ -1,9 +1,13 @@
 use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::mem::size_of;
 use core::slice;
 
 use spin::Mutex;
 
+use crate::config::VmRegion;
 use crate::device::VirtioMmio;
+use crate::kernel::access::{vm_ipa2hva, vm_ipa2hva_in};
 use crate::kernel::{active_vm, Vm};
 
 pub const VIRTQ_READY: usize = 1;
@@ -16,9 +20,174 @@ pub const VIRTQ_DESC_F_WRITE: u16 = 2;
  * when you add a buffer. It's unreliable, so it's simply an
  * optimization. */
 pub const VRING_USED_F_NO_NOTIFY: usize = 1;
+/* The driver uses this in avail->flags to advise the device: don't interrupt me
+ * when you consume a buffer. */
+pub const VRING_AVAIL_F_NO_INTERRUPT: u16 = 1;
 
 const DESC_QUEUE_SIZE: usize = 512;
 
+#[derive(Debug)]
+pub enum DescChainError {
+    NotReady,
+    IndexOutOfRange(usize),
+    Loop(usize),
+    TooLong(usize),
+    IllegalAddr { idx: usize, addr: usize, len: usize },
+}
+
+/// A single descriptor of a chain, with its guest address already checked
+/// against the VM's memory regions and translated into a hypervisor address.
+#[derive(Clone, Copy, Debug)]
+pub struct VirtqDesc {
+    pub idx: usize,
+    pub addr: usize,
+    pub hva: usize,
+    pub len: u32,
+    pub flags: u16,
+}
+
+impl VirtqDesc {
+    pub fn has_next(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_NEXT != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_WRITE != 0
+    }
+}
+
+/// Bounded walk over a guest supplied descriptor chain.
+/// The walk stops with an error if an index is out of the queue, appears twice
+/// in the chain, the chain is longer than the queue or a buffer is outside the VM memory.
+pub struct DescChain<'a> {
+    vq: &'a Virtq,
+    vm: &'a Vm,
+    // the VM memory regions when the walk started, a region hot-added meanwhile is not taken
+    regions: Vec<VmRegion>,
+    next: Option<usize>,
+    visits: DescVisits,
+}
+
+// the indexes already walked in a chain of a queue with `num` descriptors
+struct DescVisits {
+    num: usize,
+    count: usize,
+    visited: [u64; DESC_QUEUE_SIZE / 64],
+}
+
+impl DescVisits {
+    fn new(num: usize) -> Self {
+        Self {
+            num,
+            count: 0,
+            visited: [0; DESC_QUEUE_SIZE / 64],
+        }
+    }
+
+    fn visit(&mut self, idx: usize) -> Result<(), DescChainError> {
+        if idx >= self.num {
+            return Err(DescChainError::IndexOutOfRange(idx));
+        }
+        if self.count >= self.num {
+            return Err(DescChainError::TooLong(self.count));
+        }
+        if self.visited[idx / 64] & (1 << (idx % 64)) != 0 {
+            return Err(DescChainError::Loop(idx));
+        }
+        self.visited[idx / 64] |= 1 << (idx % 64);
+        self.count += 1;
+        Ok(())
+    }
+}
+
+impl Iterator for DescChain<'_> {
+    type Item = Result<VirtqDesc, DescChainError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let idx = self.next.take()?;
+        let res = self.walk(idx);
+        if let Err(err) = &res {
+            error!(
+                "VM {} virtq {} illegal desc chain: {:?}",
+                self.vm.id(),
+                self.vq.vq_index,
+                err
+            );
+        }
+        Some(res)
+    }
+}
+
+impl DescChain<'_> {
+    fn walk(&mut self, idx: usize) -> Result<VirtqDesc, DescChainError> {
+        self.visits.visit(idx)?;
+
+        let desc = {
+            let inner = self.vq.inner.lock();
+            match inner.desc_table.as_ref() {
+                Some(desc_table) => desc_table[idx],
+                None => return Err(DescChainError::NotReady),
+            }
+        };
+        let addr = desc.addr as usize;
+        let len = desc.len as usize;
+        let hva = match vm_ipa2hva_in(self.vm.id(), &self.regions, addr, len) {
+            Ok(hva) => hva,
+            Err(_) => return Err(DescChainError::IllegalAddr { idx, addr, len }),
+        };
+        if desc.flags & VIRTQ_DESC_F_NEXT != 0 {
+            self.next = Some(desc.next as usize);
+        }
+        Ok(VirtqDesc {
+            idx,
+            addr,
+            hva,
+            len: desc.len,
+            flags: desc.flags,
+        })
+    }
+}
+
+/* Walk a synthetic chain of `(flags, next)` descriptors from `head` with the same checks as
+ * `DescChain`, without the address translation that needs a VM. Returns the walked indexes.
+ */
+#[cfg(feature = "self-test")]
+pub fn desc_chain_walk_synthetic(table: &[(u16, u16)], head: usize) -> Result<Vec<usize>, DescChainError> {
+    if table.len() > DESC_QUEUE_SIZE {
+        return Err(DescChainError::TooLong(table.len()));
+    }
+    let mut visits = DescVisits::new(table.len());
+    let mut walked = Vec::new();
+    let mut next = Some(head);
+    while let Some(idx) = next.take() {
+        visits.visit(idx)?;
+        walked.push(idx);
+        let (flags, desc_next) = table[idx];
+        if flags & VIRTQ_DESC_F_NEXT != 0 {
+            next = Some(desc_next as usize);
+        }
+    }
+    Ok(walked)
+}
+
+/* The ring check of `Virtq::setup_rings` on a queue of `num` entries with the rings at `addrs`
+ * (desc table, avail, used), against `regions` instead of a VM. Returns the hva of the rings.
+ */
+#[cfg(feature = "self-test")]
+pub fn virtq_rings_synthetic(
+    vm_id: usize,
+    regions: &[VmRegion],
+    num: usize,
+    addrs: [usize; 3],
+) -> Result<[usize; 3], ()> {
+    let vq = Virtq::new(0, DESC_QUEUE_SIZE, Weak::new(), |_, _, _| false);
+    vq.set_num(num);
+    vq.or_desc_table_addr(addrs[0]);
+    vq.or_avail_addr(addrs[1]);
+    vq.or_used_addr(addrs[2]);
+    vq.translate_rings(vm_id, regions)
+}
+
 #[repr(C, align(16))]
 #[derive(Copy, Clone)]
 struct VringDesc {
@@ -32,12 +201,11 @@ struct VringDesc {
     next: u16,
 }
 
+// flags and idx at the start of the avail and used rings
 #[repr(C)]
-#[derive(Copy, Clone)]
-struct VringAvail {
+struct VringHdr {
     flags: u16,
     idx: u16,
-    ring: [u16; DESC_QUEUE_SIZE],
 }
 
 #[repr(C)]
@@ -47,16 +215,24 @@ struct VringUsedElem {
     len: u32,
 }
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-struct VringUsed {
-    flags: u16,
-    idx: u16,
-    ring: [VringUsedElem; DESC_QUEUE_SIZE],
+// the avail ring of the negotiated queue size, followed by used_event
+struct VringAvail<'a> {
+    hdr: &'a mut VringHdr,
+    ring: &'a mut [u16],
+    used_event: &'a mut u16,
+}
+
+// the used ring of the negotiated queue size, followed by avail_event
+struct VringUsed<'a> {
+    hdr: &'a mut VringHdr,
+    ring: &'a mut [VringUsedElem],
+    avail_event: &'a mut u16,
 }
 
 pub struct Virtq {
     vq_index: usize,
+    // QueueNumMax of the queue, the guest may choose a smaller size
+    num_max: usize,
     notify_handler: fn(Arc<Self>, Arc<VirtioMmio>, Arc<Vm>) -> bool,
     mmio: Weak<VirtioMmio>,
     inner: Mutex<VirtqInner<'static>>,
@@ -65,11 +241,14 @@ pub struct Virtq {
 impl Virtq {
     pub fn new(
         vq_index: usize,
+        num_max: usize,
         mmio: Weak<VirtioMmio>,
         notify_handler: fn(Arc<Self>, Arc<VirtioMmio>, Arc<Vm>) -> bool,
     ) -> Arc<Self> {
+        assert!(num_max <= DESC_QUEUE_SIZE);
         Arc::new(Self {
             vq_index,
+            num_max,
             notify_handler,
             mmio,
             inner: Mutex::new(VirtqInner::default()),
@@ -85,16 +264,30 @@ impl Virtq {
         let mut inner = self.inner.lock();
         match &inner.avail {
             Some(avail) => {
-                if avail_idx == inner.last_avail_idx {
+                if avail_idx == inner.last_avail_idx || inner.num == 0 {
+                    return None;
+                }
+                // the driver can not make more than the queue size available, the ring is not trusted then
+                if avail_idx.wrapping_sub(inner.last_avail_idx) as usize > inner.num {
+                    warn!(
+                        "pop_avail_desc_idx: avail idx {} is {} ahead of the last one, queue size {}",
+                        avail_idx,
+                        avail_idx.wrapping_sub(inner.last_avail_idx),
+                        inner.num
+                    );
                     return None;
                 }
                 let idx = inner.last_avail_idx as usize % inner.num;
                 let avail_desc_idx = avail.ring[idx];
                 inner.last_avail_idx = inner.last_avail_idx.wrapping_add(1);
+                if inner.event_idx && inner.used_flags & VRING_USED_F_NO_NOTIFY as u16 == 0 {
+                    let last_avail_idx = inner.last_avail_idx;
+                    inner.set_avail_event(last_avail_idx);
+                }
                 Some(avail_desc_idx)
             }
             None => {
-                println!("pop_avail_desc_idx: failed to avail table");
+                warn!("pop_avail_desc_idx: failed to avail table");
                 None
             }
         }
@@ -107,7 +300,7 @@ impl Virtq {
                 inner.last_avail_idx -= 1;
             }
             None => {
-                println!("put_back_avail_desc_idx: failed to avail table");
+                warn!("put_back_avail_desc_idx: failed to avail table");
             }
         }
     }
@@ -117,20 +310,49 @@ impl Virtq {
         inner.avail.is_some()
     }
 
+    // with event idx, kicks are suppressed by leaving avail_event behind the avail idx
     pub fn disable_notify(&self) {
         let mut inner = self.inner.lock();
-        if inner.used_flags & VRING_USED_F_NO_NOTIFY as u16 != 0 {
-            return;
-        }
         inner.used_flags |= VRING_USED_F_NO_NOTIFY as u16;
     }
 
     pub fn enable_notify(&self) {
         let mut inner = self.inner.lock();
-        if inner.used_flags & VRING_USED_F_NO_NOTIFY as u16 == 0 {
-            return;
-        }
         inner.used_flags &= !VRING_USED_F_NO_NOTIFY as u16;
+        if inner.event_idx {
+            // kick when the entry after the ones already popped is made available
+            let last_avail_idx = inner.last_avail_idx;
+            inner.set_avail_event(last_avail_idx);
+        }
+    }
+
+    pub fn set_event_idx(&self, event_idx: bool) {
+        let mut inner = self.inner.lock();
+        inner.event_idx = event_idx;
+    }
+
+    /* Decide if the guest wants an interrupt for the used entries added since the last one,
+     * by used_event with VIRTIO_RING_F_EVENT_IDX or by VRING_AVAIL_F_NO_INTERRUPT otherwise.
+     */
+    pub fn should_notify(&self) -> bool {
+        let mut inner = self.inner.lock();
+        let used_idx = match &inner.used {
+            Some(used) => used.hdr.idx,
+            None => return false,
+        };
+        if inner.event_idx {
+            let old = inner.signalled_used;
+            inner.signalled_used = used_idx;
+            match inner.used_event() {
+                Some(used_event) => vring_need_event(used_event, used_idx, old),
+                None => false,
+            }
+        } else {
+            match &inner.avail {
+                Some(avail) => avail.hdr.flags & VRING_AVAIL_F_NO_INTERRUPT == 0,
+                None => false,
+            }
+        }
     }
 
     pub fn check_avail_idx(&self, avail_idx: u16) -> bool {
@@ -154,21 +376,66 @@ impl Virtq {
         let mut inner = self.inner.lock();
         let num = inner.num;
         let flag = inner.used_flags;
-        match &mut inner.used {
+        let used_addr = inner.used_addr;
+        let updated = match &mut inner.used {
+            Some(_) if num == 0 => {
+                warn!("update_used_ring: virtq num is 0");
+                false
+            }
             Some(used) => {
-                used.flags = flag;
-                used.ring[used.idx as usize % num].id = desc_chain_head_idx;
-                used.ring[used.idx as usize % num].len = len;
-                used.idx = used.idx.wrapping_add(1);
+                used.hdr.flags = flag;
+                let idx = used.hdr.idx as usize % num;
+                used.ring[idx].id = desc_chain_head_idx;
+                used.ring[idx].len = len;
+                used.hdr.idx = used.hdr.idx.wrapping_add(1);
                 true
             }
             None => {
-                println!("update_used_ring: failed to used table");
+                warn!("update_used_ring: failed to used table");
                 false
             }
+        };
+        drop(inner);
+        // the ring is written by the hypervisor, the dirty log of the VM does not see it fault
+        if updated {
+            if let Some(vm) = self.mmio.upgrade().and_then(|mmio| mmio.upper_vm()) {
+                vm.dirty_log_mark(used_addr, self.used_size());
+            }
+        }
+        updated
+    }
+
+    pub fn desc_chain<'a>(&'a self, head_idx: u16, vm: &'a Vm) -> DescChain<'a> {
+        DescChain {
+            vq: self,
+            vm,
+            regions: vm.memory_regions(),
+            next: Some(head_idx as usize),
+            visits: DescVisits::new(self.num()),
         }
     }
 
+    /* The hva of the status byte of a chain `desc_chain` gave up on, its last descriptor if it is
+     * writable and in the VM memory. The buffers before it are not translated, only the indexes
+     * are walked with the same bounds, so that a request with an illegal buffer is failed to the guest.
+     */
+    pub fn desc_chain_status(&self, head_idx: u16, vm: &Vm) -> Option<usize> {
+        let mut visits = DescVisits::new(self.num());
+        let mut idx = head_idx as usize;
+        let last = loop {
+            visits.visit(idx).ok()?;
+            let desc = self.inner.lock().desc_table.as_ref()?[idx];
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                break desc;
+            }
+            idx = desc.next as usize;
+        };
+        if last.flags & VIRTQ_DESC_F_WRITE == 0 || last.len == 0 {
+            return None;
+        }
+        vm_ipa2hva(vm, last.addr as usize, 1).ok()
+    }
+
     pub fn call_notify_handler(self: &Arc<Self>) -> bool {
         if let Some(mmio) = self.mmio.upgrade() {
             (self.notify_handler)(self.clone(), mmio, active_vm().unwrap())
@@ -226,12 +493,26 @@ impl Virtq {
 
     pub fn set_num(&self, num: usize) {
         let mut inner = self.inner.lock();
-        inner.num = num;
+        // the rings are validated for the num at QueueReady
+        if inner.ready != 0 {
+            warn!("virtq {} num can not be changed while ready", self.vq_index);
+            return;
+        }
+        if num > self.num_max {
+            warn!("virtq {} num {} exceeds {}, clamped", self.vq_index, num, self.num_max);
+        }
+        inner.num = usize::min(num, self.num_max);
     }
 
     pub fn set_ready(&self, ready: usize) {
         let mut inner = self.inner.lock();
         inner.ready = ready;
+        if ready != VIRTQ_READY {
+            // set up again by setup_rings when the queue is ready
+            inner.desc_table = None;
+            inner.avail = None;
+            inner.used = None;
+        }
     }
 
     pub fn or_desc_table_addr(&self, addr: usize) {
@@ -249,28 +530,72 @@ impl Virtq {
         inner.used_addr |= addr;
     }
 
-    pub fn set_desc_table(&self, addr: usize) {
-        let mut inner = self.inner.lock();
-        if addr < 0x1000 {
-            panic!("illegal desc ring addr {:x}", addr);
-        }
-        inner.desc_table = Some(unsafe { slice::from_raw_parts_mut(addr as *mut VringDesc, DESC_QUEUE_SIZE) });
-    }
+    /* Translate the rings programmed by the guest, when it writes QueueReady.
+     * Each ring of `num` entries must be aligned as the spec requires and lie in the guest RAM,
+     * otherwise the queue is left without rings and must not be set ready.
+     */
+    pub fn setup_rings(&self, vm: &Vm) -> Result<(), ()> {
+        let hva = self.translate_rings(vm.id(), &vm.memory_regions())?;
 
-    pub fn set_avail(&self, addr: usize) {
-        if addr < 0x1000 {
-            panic!("illegal avail ring addr {:x}", addr);
-        }
+        // the views cover exactly the checked memory of `num` entries
+        let num = self.num();
+        let hdr = size_of::<VringHdr>();
         let mut inner = self.inner.lock();
-        inner.avail = Some(unsafe { &mut *(addr as *mut VringAvail) });
+        inner.desc_table = Some(unsafe { slice::from_raw_parts_mut(hva[0] as *mut VringDesc, num) });
+        inner.avail = Some(unsafe {
+            VringAvail {
+                hdr: &mut *(hva[1] as *mut VringHdr),
+                ring: slice::from_raw_parts_mut((hva[1] + hdr) as *mut u16, num),
+                used_event: &mut *((hva[1] + hdr + num * size_of::<u16>()) as *mut u16),
+            }
+        });
+        inner.used = Some(unsafe {
+            VringUsed {
+                hdr: &mut *(hva[2] as *mut VringHdr),
+                ring: slice::from_raw_parts_mut((hva[2] + hdr) as *mut VringUsedElem, num),
+                avail_event: &mut *((hva[2] + hdr + num * size_of::<VringUsedElem>()) as *mut u16),
+            }
+        });
+        Ok(())
     }
 
-    pub fn set_used(&self, addr: usize) {
-        if addr < 0x1000 {
-            panic!("illegal used ring addr {:x}", addr);
+    // the hva of the desc table, avail and used rings, if all of them are aligned and in `regions`
+    fn translate_rings(&self, vm_id: usize, regions: &[VmRegion]) -> Result<[usize; 3], ()> {
+        let num = self.num();
+        if num == 0 {
+            warn!("VM {} virtq {}: queue num is 0", vm_id, self.vq_index);
+            return Err(());
         }
-        let mut inner = self.inner.lock();
-        inner.used = Some(unsafe { &mut *(addr as *mut VringUsed) });
+        let rings = [
+            ("desc table", self.desc_table_addr(), self.desc_table_size(), 16),
+            ("avail ring", self.avail_addr(), self.avail_size(), 2),
+            ("used ring", self.used_addr(), self.used_size(), 4),
+        ];
+        let mut hva = [0; 3];
+        for (i, (name, ipa, len, align)) in rings.into_iter().enumerate() {
+            if ipa % align != 0 {
+                warn!(
+                    "VM {} virtq {}: {} ipa {:#x} is not aligned to {}",
+                    vm_id, self.vq_index, name, ipa, align
+                );
+                return Err(());
+            }
+            hva[i] = match vm_ipa2hva_in(vm_id, regions, ipa, len) {
+                Ok(hva) => hva,
+                Err(_) => {
+                    warn!(
+                        "VM {} virtq {}: {} [{:#x}, {:#x}) is out of the guest memory",
+                        vm_id,
+                        self.vq_index,
+                        name,
+                        ipa,
+                        ipa + len
+                    );
+                    return Err(());
+                }
+            };
+        }
+        Ok(hva)
     }
 
     // pub fn last_used_idx(&self) -> u16 {
@@ -331,40 +656,28 @@ impl Virtq {
         inner.num
     }
 
-    pub fn desc_addr(&self, idx: usize) -> usize {
-        let inner = self.inner.lock();
-        let desc_table = inner.desc_table.as_ref().unwrap();
-        desc_table[idx].addr as usize
+    pub fn num_max(&self) -> usize {
+        self.num_max
     }
 
-    pub fn desc_flags(&self, idx: usize) -> u16 {
-        let inner = self.inner.lock();
-        let desc_table = inner.desc_table.as_ref().unwrap();
-        desc_table[idx].flags
+    // guest memory used by each ring of `num` entries
+    pub fn desc_table_size(&self) -> usize {
+        self.num() * size_of::<VringDesc>()
     }
 
-    pub fn desc_next(&self, idx: usize) -> u16 {
-        let inner = self.inner.lock();
-        let desc_table = inner.desc_table.as_ref().unwrap();
-        desc_table[idx].next
+    // flags, idx and the ring, then used_event
+    pub fn avail_size(&self) -> usize {
+        3 * size_of::<u16>() + self.num() * size_of::<u16>()
     }
 
-    pub fn desc_len(&self, idx: usize) -> u32 {
-        let inner = self.inner.lock();
-        let desc_table = inner.desc_table.as_ref().unwrap();
-        desc_table[idx].len
-    }
-
-    pub fn avail_flags(&self) -> u16 {
-        let inner = self.inner.lock();
-        let avail = inner.avail.as_ref().unwrap();
-        avail.flags
+    // flags, idx and the ring, then avail_event
+    pub fn used_size(&self) -> usize {
+        3 * size_of::<u16>() + self.num() * size_of::<VringUsedElem>()
     }
 
     pub fn avail_idx(&self) -> u16 {
         let inner = self.inner.lock();
-        let avail = inner.avail.as_ref().unwrap();
-        avail.idx
+        inner.avail.as_ref().map_or(0, |avail| avail.hdr.idx)
     }
 
     // pub fn last_avail_idx(&self) -> u16 {
@@ -384,11 +697,14 @@ struct VirtqInner<'a> {
     ready: usize,
     num: usize,
     desc_table: Option<&'a mut [VringDesc]>,
-    avail: Option<&'a mut VringAvail>,
-    used: Option<&'a mut VringUsed>,
+    avail: Option<VringAvail<'a>>,
+    used: Option<VringUsed<'a>>,
     last_avail_idx: u16,
     last_used_idx: u16,
     used_flags: u16,
+    // VIRTIO_RING_F_EVENT_IDX is negotiated, and the used idx at the last interrupt
+    event_idx: bool,
+    signalled_used: u16,
 
     desc_table_addr: usize,
     avail_addr: usize,
@@ -403,6 +719,8 @@ impl VirtqInner<'_> {
         self.last_avail_idx = 0;
         self.last_used_idx = 0;
         self.used_flags = 0;
+        self.event_idx = false;
+        self.signalled_used = 0;
         self.desc_table_addr = 0;
         self.avail_addr = 0;
         self.used_addr = 0;
@@ -411,4 +729,21 @@ impl VirtqInner<'_> {
         self.avail = None;
         self.used = None;
     }
+
+    // the event fields are written by the other side at any time
+    fn used_event(&self) -> Option<u16> {
+        let avail = self.avail.as_ref()?;
+        Some(unsafe { core::ptr::read_volatile(&*avail.used_event) })
+    }
+
+    fn set_avail_event(&mut self, val: u16) {
+        if let Some(used) = self.used.as_mut() {
+            unsafe { core::ptr::write_volatile(&mut *used.avail_event, val) };
+        }
+    }
+}
+
+// whether `new_idx` has passed `event_idx` since `old_idx`, as vring_need_event of the virtio spec
+fn vring_need_event(event_idx: u16, new_idx: u16, old_idx: u16) -> bool {
+    new_idx.wrapping_sub(event_idx).wrapping_sub(1) < new_idx.wrapping_sub(old_idx)
 }
@@ -0,0 +1,50 @@
+// see virtio 1.1 5.4 Entropy Device
+
+use alloc::sync::Arc;
+
+use crate::kernel::Vm;
+use crate::util::rng::rng_fill;
+
+use super::{mmio::VIRTIO_F_VERSION_1, VirtioMmio, Virtq};
+
+pub const VIRTQUEUE_RNG_MAX_SIZE: usize = 64;
+
+pub fn rng_features() -> usize {
+    VIRTIO_F_VERSION_1
+}
+
+// Virtqueues
+// 0 requestq: the driver posts device-writable buffers for the device to fill with random bytes
+pub fn virtio_rng_notify_handler(vq: Arc<Virtq>, rng: Arc<VirtioMmio>, vm: Arc<Vm>) -> bool {
+    if vq.ready() == 0 {
+        error!("virtio_rng_notify_handler: rng virt_queue is not ready!");
+        return false;
+    }
+
+    while let Some(head_idx) = vq.pop_avail_desc_idx(vq.avail_idx()) {
+        let mut len = 0;
+        for desc in vq.desc_chain(head_idx, &vm) {
+            match desc {
+                Ok(desc) if desc.is_writable() => {
+                    let buf = unsafe { core::slice::from_raw_parts_mut(desc.hva as *mut u8, desc.len as usize) };
+                    rng_fill(buf);
+                    len += desc.len as usize;
+                }
+                _ => {
+                    warn!(
+                        "virtio_rng_notify_handler: vm[{}] drop illegal desc chain, head {}",
+                        vm.id(),
+                        head_idx
+                    );
+                    len = 0;
+                    break;
+                }
+            }
+        }
+        if !vq.update_used_ring(len as u32, head_idx as u32) {
+            return false;
+        }
+    }
+    rng.notify();
+    true
+}
@@ -2,14 +2,21 @@ use alloc::vec::Vec;
 
 use vm_fdt::{Error, FdtWriter, FdtWriterResult};
 
+use crate::arch::PAGE_SIZE;
 use crate::board::{PlatOperation, Platform};
 use crate::config::VmConfigEntry;
-use crate::config::{DtbDevType, VmDtbDevConfig};
+use crate::config::{DtbDevType, SmpBoot, VmDtbDevConfig};
 use crate::device::EmuDeviceType;
-use crate::vmm::CPIO_RAMDISK;
+use crate::kernel::spin_table_release_addr;
 
 pub static SYSTEM_FDT: spin::Once<alloc::vec::Vec<u8>> = spin::Once::new();
 
+// the interrupt-parent of the devices
+const GIC_PHANDLE: u32 = 0x8001;
+const APB_PCLK_PHANDLE: u32 = 0x8002;
+// the reg entries `fdt_set_memory` puts in the memory node of VM0
+const VM0_FDT_MEMORY_REGION_MAX: usize = 4;
+
 pub unsafe fn setup_fdt_vm0(config: &VmConfigEntry, dtb: *mut core::ffi::c_void) -> usize {
     use fdt::*;
     let mut mr = Vec::new();
@@ -19,6 +26,13 @@ pub unsafe fn setup_fdt_vm0(config: &VmConfigEntry, dtb: *mut core::ffi::c_void)
             length: r.length as u64,
         });
     }
+    if mr.len() > VM0_FDT_MEMORY_REGION_MAX {
+        warn!(
+            "setup_fdt_vm0: only the first {} of {} memory regions are in the fdt",
+            VM0_FDT_MEMORY_REGION_MAX,
+            mr.len()
+        );
+    }
     fdt_set_memory(
         dtb,
         mr.len() as u64,
@@ -54,7 +68,8 @@ pub unsafe fn setup_fdt_vm0(config: &VmConfigEntry, dtb: *mut core::ffi::c_void)
             }
             EmuDeviceType::EmuDeviceTVirtioNet
             | EmuDeviceType::EmuDeviceTVirtioConsole
-            | EmuDeviceType::VirtioBalloon => {
+            | EmuDeviceType::EmuDeviceTVirtioRng
+            | EmuDeviceType::EmuDeviceTVirtioBalloon => {
                 #[cfg(any(feature = "tx2", feature = "qemu"))]
                 fdt_add_virtio(
                     dtb,
@@ -76,6 +91,9 @@ pub unsafe fn setup_fdt_vm0(config: &VmConfigEntry, dtb: *mut core::ffi::c_void)
                 #[cfg(feature = "tx2")]
                 trace!("EmuDeviceTIOMMU");
             }
+            EmuDeviceType::EmuDeviceTInfoPage | EmuDeviceType::EmuDeviceTPvClock | EmuDeviceType::EmuDeviceTPl011 => {
+                trace!("{:?} is not advertised to MVM", emu_cfg.emu_type);
+            }
             _ => {
                 todo!();
             }
@@ -236,14 +254,14 @@ pub fn init_vm0_dtb(dtb: *mut core::ffi::c_void) {
 }
 
 // create vm1 fdt demo
-pub fn create_fdt(config: &VmConfigEntry) -> Result<Vec<u8>, Error> {
+pub fn create_fdt(config: &VmConfigEntry, ramdisk_size: usize) -> Result<Vec<u8>, Error> {
     let mut fdt = FdtWriter::new()?;
 
     let root_node = fdt.begin_node("root")?;
     fdt.property_string("compatible", "linux,dummy-virt")?;
     fdt.property_u32("#address-cells", 0x2)?;
     fdt.property_u32("#size-cells", 0x2)?;
-    fdt.property_u32("interrupt-parent", 0x8001)?;
+    fdt.property_u32("interrupt-parent", GIC_PHANDLE)?;
 
     let psci = fdt.begin_node("psci")?;
     fdt.property_string("compatible", "arm,psci-1.0")?;
@@ -253,8 +271,19 @@ pub fn create_fdt(config: &VmConfigEntry) -> Result<Vec<u8>, Error> {
 
     create_memory_node(&mut fdt, config)?;
     create_timer_node(&mut fdt, 0x8)?;
-    // todo: fix create_chosen_node size
-    create_chosen_node(&mut fdt, &config.cmdline, config.ramdisk_load_ipa(), CPIO_RAMDISK.len())?;
+    // an emulated pl011 is the console of the guest
+    let pl011 = config
+        .emulated_device_list()
+        .iter()
+        .find(|emu_cfg| emu_cfg.emu_type == EmuDeviceType::EmuDeviceTPl011);
+    let stdout = pl011.map(|emu_cfg| format!("/pl011@{:x}", emu_cfg.base_ipa));
+    create_chosen_node(
+        &mut fdt,
+        &config.cmdline,
+        config.ramdisk_load_ipa(),
+        ramdisk_size,
+        stdout.as_deref(),
+    )?;
     create_cpu_node(&mut fdt, config)?;
     for dev in config.dtb_device_list().iter() {
         if dev.dev_type == DtbDevType::Serial {
@@ -262,14 +291,26 @@ pub fn create_fdt(config: &VmConfigEntry) -> Result<Vec<u8>, Error> {
         }
     }
     create_gic_node(&mut fdt, config.gicc_addr(), config.gicd_addr())?;
+    if pl011.is_some() {
+        create_apb_pclk_node(&mut fdt)?;
+    }
 
+    // (node name, compatible, ipa) of the pages shared with the hypervisor
+    let mut reserved_pages = Vec::new();
     for emu_cfg in config.emulated_device_list() {
         match emu_cfg.emu_type {
             EmuDeviceType::EmuDeviceTVirtioBlk
             | EmuDeviceType::EmuDeviceTVirtioNet
-            | EmuDeviceType::EmuDeviceTVirtioConsole => {
+            | EmuDeviceType::EmuDeviceTVirtioConsole
+            | EmuDeviceType::EmuDeviceTVirtioRng => {
                 debug!("virtio fdt node init {} {:x}", emu_cfg.name, emu_cfg.base_ipa);
-                create_virtio_node(&mut fdt, &emu_cfg.name, emu_cfg.irq_id, emu_cfg.base_ipa)?;
+                // the unit address follows base_ipa, which may be assigned by the hypervisor
+                let name = format!(
+                    "{}@{:x}",
+                    emu_cfg.name.split('@').next().unwrap_or_default(),
+                    emu_cfg.base_ipa
+                );
+                create_virtio_node(&mut fdt, &name, emu_cfg.irq_id, emu_cfg.base_ipa)?;
             }
             EmuDeviceType::EmuDeviceTShyper => {
                 debug!("shyper fdt node init {:x}", emu_cfg.base_ipa);
@@ -281,32 +322,98 @@ pub fn create_fdt(config: &VmConfigEntry) -> Result<Vec<u8>, Error> {
                     emu_cfg.length,
                 )?;
             }
+            EmuDeviceType::EmuDeviceTPl011 => {
+                debug!("pl011 fdt node init {:x}", emu_cfg.base_ipa);
+                create_pl011_node(&mut fdt, emu_cfg.irq_id, emu_cfg.base_ipa)?;
+            }
+            EmuDeviceType::EmuDeviceTInfoPage => {
+                debug!("info page fdt node init {:x}", emu_cfg.base_ipa);
+                reserved_pages.push(("shyper-info", "shyper,info-page", emu_cfg.base_ipa));
+            }
+            EmuDeviceType::EmuDeviceTPvClock => {
+                debug!("pvclock page fdt node init {:x}", emu_cfg.base_ipa);
+                reserved_pages.push(("shyper-pvclock", "shyper,pvclock", emu_cfg.base_ipa));
+            }
             _ => {}
         }
     }
+    if let SmpBoot::SpinTable(release_ipa) = config.smp_boot() {
+        reserved_pages.push(("spin-table", "shyper,spin-table", release_ipa));
+    }
+    if !reserved_pages.is_empty() {
+        create_reserved_pages_node(&mut fdt, &reserved_pages)?;
+    }
 
     fdt.end_node(root_node)?;
     fdt.finish()
 }
 
-// hard code for tx2 vm1
+// one memory node for each region of the VM
 fn create_memory_node(fdt: &mut FdtWriter, config: &VmConfigEntry) -> FdtWriterResult<()> {
     if config.memory_region().is_empty() {
         panic!("create_memory_node memory region num 0");
     }
-    let memory_name = format!("memory@{:x}", config.memory_region()[0].ipa_start);
-    let memory = fdt.begin_node(&memory_name)?;
-    fdt.property_string("device_type", "memory")?;
-    let mut addr = vec![];
     for region in config.memory_region() {
-        addr.push(region.ipa_start as u64);
-        addr.push(region.length as u64);
+        let memory_name = format!("memory@{:x}", region.ipa_start);
+        let memory = fdt.begin_node(&memory_name)?;
+        fdt.property_string("device_type", "memory")?;
+        fdt.property_array_u64("reg", &[region.ipa_start as u64, region.length as u64])?;
+        fdt.end_node(memory)?;
     }
-    fdt.property_array_u64("reg", addr.as_slice())?;
-    fdt.end_node(memory)?;
     Ok(())
 }
 
+/* Walk a blob made by `create_fdt` again, and check that it has what the config asks for:
+ * a cpu per vcpu, a memory node per region, a virtio node per virtio device and the cmdline.
+ */
+pub fn fdt_check(config: &VmConfigEntry, dtb: &[u8]) -> bool {
+    let root = match super::fdt_parse(dtb) {
+        Ok(root) => root,
+        Err(_) => return false,
+    };
+    let cpu_num = root.child("cpus").map_or(0, |cpus| {
+        cpus.children.iter().filter(|node| node.base_name() == "cpu").count()
+    });
+    let memory_num = root.children.iter().filter(|node| node.base_name() == "memory").count();
+    let virtio_num = root
+        .children
+        .iter()
+        .filter(|node| node.prop_str("compatible") == Some("virtio,mmio"))
+        .count();
+    let virtio_cfg_num = config
+        .emulated_device_list()
+        .iter()
+        .filter(|emu_cfg| {
+            matches!(
+                emu_cfg.emu_type,
+                EmuDeviceType::EmuDeviceTVirtioBlk
+                    | EmuDeviceType::EmuDeviceTVirtioNet
+                    | EmuDeviceType::EmuDeviceTVirtioConsole
+                    | EmuDeviceType::EmuDeviceTVirtioRng
+            )
+        })
+        .count();
+    let bootargs = root.child("chosen").and_then(|chosen| chosen.prop_str("bootargs"));
+    if cpu_num != config.cpu_num()
+        || memory_num != config.memory_region().len()
+        || virtio_num != virtio_cfg_num
+        || bootargs != Some(config.cmdline.as_str())
+    {
+        error!(
+            "fdt_check: VM {} fdt has {} cpus, {} memory nodes, {} virtio nodes, config has {}, {}, {}",
+            config.name,
+            cpu_num,
+            memory_num,
+            virtio_num,
+            config.cpu_num(),
+            config.memory_region().len(),
+            virtio_cfg_num
+        );
+        return false;
+    }
+    true
+}
+
 fn create_timer_node(fdt: &mut FdtWriter, trigger_lvl: u32) -> FdtWriterResult<()> {
     let timer = fdt.begin_node("timer")?;
     fdt.property_string("compatible", "arm,armv8-timer")?;
@@ -336,13 +443,23 @@ fn create_cpu_node(fdt: &mut FdtWriter, config: &VmConfigEntry) -> FdtWriterResu
     fdt.property_u32("#size-cells", 0)?;
     fdt.property_u32("#address-cells", 0x2)?;
 
-    let cpu_num = config.cpu_allocated_bitmap().count_ones();
+    // one cpu node per vcpu, reg is the Aff0 of its vmpidr
+    let cpu_num = config.cpu_num() as u32;
     for cpu_id in 0..cpu_num {
         let cpu_name = format!("cpu@{:x}", cpu_id);
         let cpu_node = fdt.begin_node(&cpu_name)?;
         fdt.property_string("compatible", "arm,cortex-a57")?;
         fdt.property_string("device_type", "cpu")?;
-        fdt.property_string("enable-method", "psci")?;
+        match config.smp_boot() {
+            SmpBoot::Psci => fdt.property_string("enable-method", "psci")?,
+            SmpBoot::SpinTable(release_ipa) => {
+                fdt.property_string("enable-method", "spin-table")?;
+                fdt.property_u64(
+                    "cpu-release-addr",
+                    spin_table_release_addr(release_ipa, cpu_id as usize) as u64,
+                )?;
+            }
+        }
         fdt.property_array_u32("reg", &[0, cpu_id])?;
         fdt.end_node(cpu_node)?;
     }
@@ -367,11 +484,23 @@ fn create_serial_node(fdt: &mut FdtWriter, dev: &VmDtbDevConfig) -> FdtWriterRes
     Ok(())
 }
 
-fn create_chosen_node(fdt: &mut FdtWriter, cmdline: &str, ipa: usize, size: usize) -> FdtWriterResult<()> {
+fn create_chosen_node(
+    fdt: &mut FdtWriter,
+    cmdline: &str,
+    ipa: usize,
+    size: usize,
+    stdout: Option<&str>,
+) -> FdtWriterResult<()> {
     let chosen = fdt.begin_node("chosen")?;
     fdt.property_string("bootargs", cmdline)?;
-    fdt.property_u32("linux,initrd-start", ipa as u32)?;
-    fdt.property_u32("linux,initrd-end", (ipa + size) as u32)?;
+    if let Some(stdout) = stdout {
+        fdt.property_string("stdout-path", stdout)?;
+    }
+    // only advertise the initrd if a ramdisk is actually loaded
+    if ipa != 0 && size != 0 {
+        fdt.property_u64("linux,initrd-start", ipa as u64)?;
+        fdt.property_u64("linux,initrd-end", (ipa + size) as u64)?;
+    }
     fdt.end_node(chosen)?;
     Ok(())
 }
@@ -380,7 +509,7 @@ fn create_gic_node(fdt: &mut FdtWriter, gicc_addr: usize, gicd_addr: usize) -> F
     let gic_name = format!("interrupt-controller@{:x}", gicd_addr);
     let gic = fdt.begin_node(&gic_name)?;
 
-    fdt.property_u32("phandle", 0x8001)?;
+    fdt.property_u32("phandle", GIC_PHANDLE)?;
     fdt.property_array_u64("reg", &[gicd_addr as u64, 0x1000, gicc_addr as u64, 0x2000])?;
     fdt.property_string("compatible", "arm,gic-400")?;
     fdt.property_u32("#interrupt-cells", 0x03)?;
@@ -394,6 +523,7 @@ fn create_virtio_node(fdt: &mut FdtWriter, name: &str, irq: usize, address: usiz
     let virtio = fdt.begin_node(name)?;
     fdt.property_null("dma-coherent")?;
     fdt.property_string("compatible", "virtio,mmio")?;
+    fdt.property_u32("interrupt-parent", GIC_PHANDLE)?;
     fdt.property_array_u32("interrupts", &[0, irq as u32 - 32, 0x1])?;
     fdt.property_array_u64("reg", &[address as u64, 0x400])?;
     fdt.end_node(virtio)?;
@@ -401,9 +531,52 @@ fn create_virtio_node(fdt: &mut FdtWriter, name: &str, irq: usize, address: usiz
     Ok(())
 }
 
+// the clock of the amba bus, a pl011 is not probed without it
+fn create_apb_pclk_node(fdt: &mut FdtWriter) -> FdtWriterResult<()> {
+    let clock = fdt.begin_node("apb-pclk")?;
+    fdt.property_string("compatible", "fixed-clock")?;
+    fdt.property_u32("#clock-cells", 0)?;
+    fdt.property_u32("clock-frequency", 24000000)?;
+    fdt.property_string("clock-output-names", "clk24mhz")?;
+    fdt.property_u32("phandle", APB_PCLK_PHANDLE)?;
+    fdt.end_node(clock)?;
+    Ok(())
+}
+
+fn create_pl011_node(fdt: &mut FdtWriter, irq: usize, address: usize) -> FdtWriterResult<()> {
+    let pl011 = fdt.begin_node(&format!("pl011@{:x}", address))?;
+    fdt.property_string_list("compatible", vec!["arm,pl011".into(), "arm,primecell".into()])?;
+    fdt.property_array_u64("reg", &[address as u64, 0x1000])?;
+    fdt.property_u32("interrupt-parent", GIC_PHANDLE)?;
+    fdt.property_array_u32("interrupts", &[0, irq as u32 - 32, 0x4])?;
+    fdt.property_array_u32("clocks", &[APB_PCLK_PHANDLE, APB_PCLK_PHANDLE])?;
+    fdt.property_string_list("clock-names", vec!["uartclk".into(), "apb_pclk".into()])?;
+    fdt.end_node(pl011)?;
+    Ok(())
+}
+
+// the pages shared with the hypervisor are reserved so that the guest never uses them as RAM
+fn create_reserved_pages_node(fdt: &mut FdtWriter, pages: &[(&str, &str, usize)]) -> FdtWriterResult<()> {
+    let reserved = fdt.begin_node("reserved-memory")?;
+    fdt.property_u32("#address-cells", 0x2)?;
+    fdt.property_u32("#size-cells", 0x2)?;
+    fdt.property_null("ranges")?;
+    for (name, compatible, address) in pages.iter() {
+        let page = fdt.begin_node(&format!("{}@{:x}", name, address))?;
+        fdt.property_string("compatible", compatible)?;
+        fdt.property_array_u64("reg", &[*address as u64, PAGE_SIZE as u64])?;
+        fdt.property_null("no-map")?;
+        fdt.end_node(page)?;
+    }
+    fdt.end_node(reserved)?;
+
+    Ok(())
+}
+
 fn create_shyper_node(fdt: &mut FdtWriter, name: &str, irq: usize, address: usize, len: usize) -> FdtWriterResult<()> {
     let shyper = fdt.begin_node(name)?;
     fdt.property_string("compatible", "shyper")?;
+    fdt.property_u32("interrupt-parent", GIC_PHANDLE)?;
     fdt.property_array_u32("interrupts", &[0, irq as u32 - 32, 0x1])?;
     if address != 0 && len != 0 {
         fdt.property_array_u64("reg", &[address as u64, len as u64])?;
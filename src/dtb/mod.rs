@@ -1,3 +1,5 @@
 pub use self::device_tree::*;
+pub use self::reader::*;
 
 mod device_tree;
+mod reader;
@@ -0,0 +1,180 @@
+use alloc::vec::Vec;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+
+pub struct FdtProp<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+/* A node of a flattened device tree, borrowing names and values from the blob.
+ * Only what the static config needs: no phandles, and the cells of addresses and sizes are given by the caller.
+ */
+pub struct FdtNode<'a> {
+    pub name: &'a str,
+    pub props: Vec<FdtProp<'a>>,
+    pub children: Vec<FdtNode<'a>>,
+}
+
+impl<'a> FdtNode<'a> {
+    // node name without the unit address
+    pub fn base_name(&self) -> &'a str {
+        self.name.split('@').next().unwrap_or(self.name)
+    }
+
+    pub fn prop(&self, name: &str) -> Option<&'a [u8]> {
+        self.props.iter().find(|prop| prop.name == name).map(|prop| prop.value)
+    }
+
+    pub fn prop_str(&self, name: &str) -> Option<&'a str> {
+        let value = self.prop(name)?;
+        let value = value.strip_suffix(&[0u8]).unwrap_or(value);
+        core::str::from_utf8(value).ok()
+    }
+
+    // a list of 32-bit cells
+    pub fn prop_u32_list(&self, name: &str) -> Option<Vec<usize>> {
+        let value = self.prop(name)?;
+        if value.len() % 4 != 0 {
+            return None;
+        }
+        Some(
+            value
+                .chunks_exact(4)
+                .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()) as usize)
+                .collect(),
+        )
+    }
+
+    // a list of 64-bit values, each of two cells
+    pub fn prop_u64_list(&self, name: &str) -> Option<Vec<usize>> {
+        let value = self.prop(name)?;
+        if value.len() % 8 != 0 {
+            return None;
+        }
+        Some(
+            value
+                .chunks_exact(8)
+                .map(|cells| u64::from_be_bytes(cells.try_into().unwrap()) as usize)
+                .collect(),
+        )
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<usize> {
+        match self.prop_u32_list(name)?.as_slice() {
+            [val] => Some(*val),
+            _ => None,
+        }
+    }
+
+    pub fn prop_u64(&self, name: &str) -> Option<usize> {
+        match self.prop_u64_list(name)?.as_slice() {
+            [val] => Some(*val),
+            _ => None,
+        }
+    }
+
+    pub fn child(&self, name: &str) -> Option<&FdtNode<'a>> {
+        self.children.iter().find(|child| child.name == name)
+    }
+}
+
+struct FdtCursor<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> FdtCursor<'a> {
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.structs.get(self.offset..self.offset + 4)?;
+        self.offset += 4;
+        Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.structs.get(self.offset..self.offset + len)?;
+        // tokens are aligned to 4 bytes
+        self.offset += (len + 3) & !3;
+        Some(bytes)
+    }
+
+    fn cstr(&mut self) -> Option<&'a str> {
+        let rest = self.structs.get(self.offset..)?;
+        let len = rest.iter().position(|&c| c == 0)?;
+        let name = self.bytes(len + 1)?;
+        core::str::from_utf8(&name[..len]).ok()
+    }
+
+    fn string(&self, offset: usize) -> Option<&'a str> {
+        let rest = self.strings.get(offset..)?;
+        let len = rest.iter().position(|&c| c == 0)?;
+        core::str::from_utf8(&rest[..len]).ok()
+    }
+
+    // the FDT_BEGIN_NODE token has been consumed
+    fn node(&mut self) -> Option<FdtNode<'a>> {
+        let mut node = FdtNode {
+            name: self.cstr()?,
+            props: Vec::new(),
+            children: Vec::new(),
+        };
+        loop {
+            match self.u32()? {
+                FDT_PROP => {
+                    let len = self.u32()? as usize;
+                    let name = self.string(self.u32()? as usize)?;
+                    let value = self.bytes(len)?;
+                    node.props.push(FdtProp { name, value });
+                }
+                FDT_BEGIN_NODE => node.children.push(self.node()?),
+                FDT_END_NODE => return Some(node),
+                FDT_NOP => {}
+                _ => return None,
+            }
+        }
+    }
+}
+
+fn be32(blob: &[u8], offset: usize) -> Option<usize> {
+    let bytes = blob.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+// parse a whole device tree blob, return the root node
+pub fn fdt_parse(blob: &[u8]) -> Result<FdtNode<'_>, ()> {
+    if be32(blob, 0) != Some(FDT_MAGIC as usize) {
+        error!("fdt_parse: bad magic");
+        return Err(());
+    }
+    let header = || {
+        let total_size = be32(blob, 4)?;
+        let struct_off = be32(blob, 8)?;
+        let strings_off = be32(blob, 12)?;
+        let strings_size = be32(blob, 32)?;
+        let struct_size = be32(blob, 36)?;
+        let blob = blob.get(..total_size)?;
+        Some((
+            blob.get(struct_off..struct_off + struct_size)?,
+            blob.get(strings_off..strings_off + strings_size)?,
+        ))
+    };
+    let (structs, strings) = header().ok_or_else(|| error!("fdt_parse: bad header"))?;
+    let mut cursor = FdtCursor {
+        structs,
+        strings,
+        offset: 0,
+    };
+    let root = loop {
+        match cursor.u32() {
+            Some(FDT_NOP) => {}
+            Some(FDT_BEGIN_NODE) => break cursor.node(),
+            _ => break None,
+        }
+    };
+    root.ok_or_else(|| error!("fdt_parse: bad structure block at offset {:#x}", cursor.offset))
+}
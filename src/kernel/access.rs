@@ -1,19 +1,72 @@
+use alloc::string::{String, ToString};
+use core::ffi::CStr;
 use core::mem::size_of_val;
 use core::slice;
 
-use super::Vm;
+use super::{ipa_range_in_regions, vm_ipa2hva_prefix, Vm};
 use crate::arch::CacheInvalidate;
+use crate::config::VmRegion;
 use crate::util::memcpy_safe;
 
-pub fn copy_segment_to_vm<T: Sized>(vm: &Vm, load_ipa: usize, bin: &[T]) {
-    let bin = unsafe { slice::from_raw_parts(bin.as_ptr() as *const u8, size_of_val(bin)) };
-    let hva = vm.ipa2hva(load_ipa) as *mut u8;
-    if hva.is_null() {
-        error!("illegal ipa {:#x} from VM {}", load_ipa, vm.id());
-        return;
+// the guest memory window [ipa, ipa + len) of VM `vm_id` that failed to translate
+#[derive(Clone, Copy, Debug)]
+pub struct InvalidIpa {
+    pub vm_id: usize,
+    pub ipa: usize,
+    pub len: usize,
+}
+
+/* Translate a guest buffer to hva, the whole [ipa, ipa + len) must be inside
+ * the memory regions of the VM, so that a garbage pointer from guest is never dereferenced.
+ */
+pub fn vm_ipa2hva(vm: &Vm, ipa: usize, len: usize) -> Result<usize, InvalidIpa> {
+    vm_ipa2hva_in(vm.id(), &vm.memory_regions(), ipa, len)
+}
+
+// `vm_ipa2hva` against `regions`, the memory regions of VM `vm_id` taken once for many buffers
+pub fn vm_ipa2hva_in(vm_id: usize, regions: &[VmRegion], ipa: usize, len: usize) -> Result<usize, InvalidIpa> {
+    let hva = vm_ipa2hva_prefix(vm_id, ipa);
+    if hva == 0 || !ipa_range_in_regions(regions, ipa, len.max(1)) {
+        error!(
+            "vm_ipa2hva: VM {} access invalid ipa {:#x} length {:#x}",
+            vm_id, ipa, len
+        );
+        return Err(InvalidIpa { vm_id, ipa, len });
     }
-    memcpy_safe(hva.cast(), bin.as_ptr().cast(), bin.len());
-    crate::arch::Arch::dcache_flush(hva as usize, bin.len());
+    Ok(hva)
+}
+
+// read a NUL terminated string from guest, which must end inside the memory region it starts in
+pub fn copy_cstr_from_vm(vm: &Vm, ipa: usize) -> Result<String, InvalidIpa> {
+    let hva = vm_ipa2hva(vm, ipa, 1)?;
+    let max_len = vm
+        .memory_regions()
+        .iter()
+        .find(|region| region.as_range().contains(&ipa))
+        .map_or(0, |region| region.ipa_start + region.length - ipa);
+    let bin = unsafe { slice::from_raw_parts(hva as *const u8, max_len) };
+    match CStr::from_bytes_until_nul(bin) {
+        Ok(cstr) => Ok(cstr.to_string_lossy().to_string()),
+        Err(_) => {
+            error!(
+                "copy_cstr_from_vm: string at ipa {:#x} of VM {} is not terminated",
+                ipa,
+                vm.id()
+            );
+            Err(InvalidIpa {
+                vm_id: vm.id(),
+                ipa,
+                len: max_len,
+            })
+        }
+    }
+}
+
+pub fn copy_segment_to_vm<T: Sized>(vm: &Vm, load_ipa: usize, bin: &[T]) -> Result<(), InvalidIpa> {
+    let bin = unsafe { slice::from_raw_parts(bin.as_ptr() as *const u8, size_of_val(bin)) };
+    let hva = vm_ipa2hva(vm, load_ipa, bin.len())?;
+    memcpy_safe(hva as *mut u8, bin.as_ptr().cast(), bin.len());
+    crate::arch::Arch::dcache_flush(hva, bin.len());
     // let offset = load_ipa - round_down(load_ipa, PAGE_SIZE);
     // let start = if offset != 0 {
     //     info!(
@@ -40,40 +93,41 @@ pub fn copy_segment_to_vm<T: Sized>(vm: &Vm, load_ipa: usize, bin: &[T]) {
     //     // let dst = unsafe { slice::from_raw_parts_mut(pa, size) };
     //     // dst.copy_from_slice(&bin[i..i + size]);
     // }
+    Ok(())
 }
 
-pub fn copy_between_vm(dest: (&Vm, usize), src: (&Vm, usize), len: usize) -> bool {
-    let (src_vm, src_ipa) = src;
-    let src_hva = src_vm.ipa2hva(src_ipa);
-    if src_hva == 0 {
-        error!("illegal ipa {:#x} from src VM {}", src_ipa, src_vm.id());
-        return false;
-    }
+// bytes copied by `copy_between_vm` before it checks for pending irqs
+const COPY_CHUNK_SIZE: usize = 2 << 20;
 
-    let src_bin = unsafe { core::slice::from_raw_parts(src_hva as *const u8, len) };
+/* Copy between two VMs in chunks of COPY_CHUNK_SIZE, the destination of each chunk is cleaned
+ * to the point of coherency, so that a VM started with caches off sees the data.
+ * The copy stops at a chunk boundary once a physical irq is pending, as irqs are masked here,
+ * and the number of bytes copied is returned, the caller resumes from there.
+ */
+pub fn copy_between_vm(dest: (&Vm, usize), src: (&Vm, usize), len: usize) -> Result<usize, InvalidIpa> {
+    let (src_vm, src_ipa) = src;
+    let src_hva = vm_ipa2hva(src_vm, src_ipa, len)?;
 
     let (dest_vm, dest_ipa) = dest;
-    let dest_hva = dest_vm.ipa2hva(dest_ipa);
-    if dest_hva == 0 {
-        error!("illegal ipa {:#x} from dest VM {}", dest_ipa, dest_vm.id());
-        return false;
-    }
-
-    let dst_bin = unsafe { core::slice::from_raw_parts_mut(dest_hva as *mut u8, len) };
+    let dest_hva = vm_ipa2hva(dest_vm, dest_ipa, len)?;
 
-    dst_bin.copy_from_slice(src_bin);
-    crate::arch::Arch::dcache_flush(dest_hva, len);
-    true
+    let mut copied = 0;
+    while copied < len {
+        let size = (len - copied).min(COPY_CHUNK_SIZE);
+        memcpy_safe((dest_hva + copied) as *mut u8, (src_hva + copied) as *const u8, size);
+        crate::arch::Arch::dcache_clean_flush(dest_hva + copied, size);
+        copied += size;
+        if copied < len && crate::arch::interrupt_arch_irq_pending() {
+            break;
+        }
+    }
+    Ok(copied)
 }
 
-pub fn copy_segment_from_vm<T: Sized>(vm: &Vm, bin: &mut [T], load_ipa: usize) {
+pub fn copy_segment_from_vm<T: Sized>(vm: &Vm, bin: &mut [T], load_ipa: usize) -> Result<(), InvalidIpa> {
     let bin = unsafe { slice::from_raw_parts_mut(bin.as_mut_ptr() as *mut u8, size_of_val(bin)) };
-    let hva = vm.ipa2hva(load_ipa) as *mut u8;
-    if hva.is_null() {
-        error!("illegal ipa {:#x} from VM {}", load_ipa, vm.id());
-        return;
-    }
-    memcpy_safe(bin.as_ptr().cast(), hva.cast(), bin.len());
+    let hva = vm_ipa2hva(vm, load_ipa, bin.len())?;
+    memcpy_safe(bin.as_ptr().cast(), hva as *const u8, bin.len());
     // let offset = load_ipa - round_down(load_ipa, PAGE_SIZE);
     // let start = if offset != 0 {
     //     info!(
@@ -98,14 +152,15 @@ pub fn copy_segment_from_vm<T: Sized>(vm: &Vm, bin: &mut [T], load_ipa: usize) {
     //     // let src = unsafe { slice::from_raw_parts(pa, size) };
     //     // bin[i..i + size].clone_from_slice(src);
     // }
+    Ok(())
 }
 
 #[allow(dead_code)]
-pub fn copy_to_vm<T: Sized>(vm: &Vm, to: *mut u8, from: &T) {
-    copy_segment_to_vm(vm, to as usize, slice::from_ref(from));
+pub fn copy_to_vm<T: Sized>(vm: &Vm, to: *mut u8, from: &T) -> Result<(), InvalidIpa> {
+    copy_segment_to_vm(vm, to as usize, slice::from_ref(from))
 }
 
 #[allow(dead_code)]
-pub fn copy_from_vm<T: Sized>(vm: &Vm, to: &mut T, from: *const u8) {
-    copy_segment_from_vm(vm, slice::from_mut(to), from as usize);
+pub fn copy_from_vm<T: Sized>(vm: &Vm, to: &mut T, from: *const u8) -> Result<(), InvalidIpa> {
+    copy_segment_from_vm(vm, slice::from_mut(to), from as usize)
 }
@@ -1,7 +1,8 @@
 use core::future::Future;
 use core::pin::Pin;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use core::task::Context;
+use core::time::Duration;
 
 use alloc::boxed::Box;
 use alloc::collections::{BTreeMap, LinkedList};
@@ -9,8 +10,17 @@ use alloc::sync::Arc;
 use alloc::task::Wake;
 use spin::mutex::Mutex;
 
-use crate::device::{mediated_blk_read, mediated_blk_write, virtio_blk_notify_handler, ReadAsyncMsg, WriteAsyncMsg};
-use crate::kernel::{active_vm, ipi_send_msg, IpiInnerMsg, IpiMediatedMsg, IpiType};
+use crate::device::{
+    mediated_blk_zero_copy_map, mediated_blk_zero_copy_unmap, virtio_blk_complete, virtio_blk_complete_err,
+    virtio_blk_mediated_submit, virtio_mediated_blk_req_handler, FlushAsyncMsg, ReadAsyncMsg, WriteAsyncMsg,
+    VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+};
+use crate::kernel::access::copy_to_vm;
+use crate::kernel::{
+    active_vm, current_cpu, hvc_send_msg_to_vm, ipi_send_msg_retry, timer, HvcGuestMsg, HvcManageMsg, IpiInnerMsg,
+    IpiMediatedMsg, IpiType, HVC_MEDIATED, HVC_MEDIATED_TASK_TIMEOUT,
+};
+use crate::util::timer_list::{TimerEvent, TimerValue};
 use crate::util::{memcpy_safe, sleep};
 
 #[derive(Clone, Copy, Debug)]
@@ -26,107 +36,195 @@ enum AsyncExeStatus {
     Scheduling,
 }
 
-pub struct Executor {
-    status: Mutex<AsyncExeStatus>,
-    ipi_task_list: Mutex<LinkedList<Arc<AsyncTask>>>,
-    io_task_list: Mutex<FairQueue<AsyncTask>>,
+// the mediated blks share the queues by their index, the tasks of one blk are always in the same queue
+const EXECUTOR_QUEUE_NUM: usize = 8;
+// the kernel image uploads have a queue of their own, after the ones of the blks
+const EXECUTOR_UPLOAD_QUEUE: usize = EXECUTOR_QUEUE_NUM;
+// the blk id the upload tasks are created with
+pub const ASYNC_UPLOAD_ID: usize = usize::MAX;
+
+fn executor_queue(blk_id: usize) -> usize {
+    if blk_id == ASYNC_UPLOAD_ID {
+        EXECUTOR_UPLOAD_QUEUE
+    } else {
+        blk_id % EXECUTOR_QUEUE_NUM
+    }
 }
 
-impl Executor {
+struct TaskQueue {
+    status: AsyncExeStatus,
+    ipi_task_list: LinkedList<Arc<AsyncTask>>,
+    io_task_list: FairQueue<AsyncTask>,
+    // the request that timed out while VM0 still holds it, the next one waits until VM0 completes it
+    late: Option<Arc<AsyncTask>>,
+    // VM0 did not complete the late request either, the IO requests are failed at once
+    failed: bool,
+}
+
+impl TaskQueue {
     const fn new() -> Self {
         Self {
-            status: Mutex::new(AsyncExeStatus::Pending),
-            ipi_task_list: Mutex::new(LinkedList::new()),
-            io_task_list: Mutex::new(FairQueue::new()),
+            status: AsyncExeStatus::Pending,
+            ipi_task_list: LinkedList::new(),
+            io_task_list: FairQueue::new(),
+            late: None,
+            failed: false,
         }
     }
 
-    fn status(&self) -> AsyncExeStatus {
-        *self.status.lock()
+    fn is_empty(&self) -> bool {
+        self.ipi_task_list.is_empty() && self.io_task_list.is_empty()
     }
+}
 
-    fn set_status(&self, status: AsyncExeStatus) {
-        *self.status.lock() = status;
+/* The tasks are queued per mediated blk, each queue has its own lock, so the cores posting
+ * requests to different blks do not contend with each other or with VM0 completing them.
+ */
+pub struct Executor {
+    queues: [Mutex<TaskQueue>; EXECUTOR_QUEUE_NUM + 1],
+}
+
+impl Executor {
+    const fn new() -> Self {
+        Self {
+            queues: [const { Mutex::new(TaskQueue::new()) }; EXECUTOR_QUEUE_NUM + 1],
+        }
     }
 
+    /* Run the queues in VM0. The queues are served round-robin, one task of each queue per round,
+     * so a VM flooding one blk can not hold back the completions of the others.
+     * The upload queue gives one chunk per run, the rest is left to the next runs.
+     */
     pub fn exec(&self) {
-        if active_vm().unwrap().id() == 0 {
-            match self.status() {
-                AsyncExeStatus::Pending => self.set_status(AsyncExeStatus::Scheduling),
-                AsyncExeStatus::Scheduling => return,
+        let mut claimed = [false; EXECUTOR_QUEUE_NUM + 1];
+        for (queue, claimed) in self.queues.iter().zip(claimed.iter_mut()) {
+            let mut queue = queue.lock();
+            // another core of VM0 is scheduling this queue
+            if queue.status == AsyncExeStatus::Pending {
+                queue.status = AsyncExeStatus::Scheduling;
+                *claimed = true;
             }
         }
         loop {
-            let ipi_list = self.ipi_task_list.lock();
-            let io_list = self.io_task_list.lock();
-
-            let (task, ipi) = if io_list.is_empty() && ipi_list.is_empty() {
-                self.set_status(AsyncExeStatus::Pending);
-                return;
-            } else if !io_list.is_empty() {
-                // if io_list is not empty, prioritize IO requests
-                (io_list.front().unwrap().clone(), false)
-            } else {
-                // other VM start an IO which need to be handled by service VM
-                (ipi_list.front().unwrap().clone(), true)
-            };
-            drop(ipi_list);
-            drop(io_list);
-            if task.handle() || ipi {
-                // task finish
-                self.finish_task(ipi);
-            } else {
-                // wait for notify
-                self.set_status(AsyncExeStatus::Pending);
-                return;
+            let mut progress = false;
+            for (idx, claimed) in claimed.iter_mut().enumerate() {
+                if *claimed {
+                    if self.exec_queue(idx) {
+                        progress = true;
+                        if idx == EXECUTOR_UPLOAD_QUEUE {
+                            self.queues[idx].lock().status = AsyncExeStatus::Pending;
+                            *claimed = false;
+                        }
+                    } else {
+                        *claimed = false;
+                    }
+                }
             }
-            // not a service VM, end loop
-            if active_vm().unwrap().id() != 0 {
+            if !progress {
                 return;
             }
         }
     }
 
-    pub fn set_front_io_task_state(&self, state: AsyncTaskState) {
-        if let Some(task) = self.io_task_list.lock().front() {
+    // handle the front task of queue `idx`, return false if the queue is empty or waits for a notify
+    fn exec_queue(&self, idx: usize) -> bool {
+        let mut queue = self.queues[idx].lock();
+        let (task, ipi) = if queue.is_empty() {
+            queue.status = AsyncExeStatus::Pending;
+            return false;
+        } else if !queue.io_task_list.is_empty() {
+            // if io_list is not empty, prioritize IO requests
+            (queue.io_task_list.front().unwrap().clone(), false)
+        } else {
+            // other VM start an IO which need to be handled by service VM
+            (queue.ipi_task_list.front().unwrap().clone(), true)
+        };
+        // VM0 may still use the cache page for the request that timed out
+        if !ipi && !task.started() && queue.late.is_some() {
+            queue.status = AsyncExeStatus::Pending;
+            return false;
+        }
+        drop(queue);
+        // a cancelled task which was not handed to VM0 is just dropped
+        if (task.abandoned() && !task.started()) || task.handle() || ipi {
+            // task finish
+            self.finish_task(idx, ipi);
+            true
+        } else {
+            // wait for notify
+            self.queues[idx].lock().status = AsyncExeStatus::Pending;
+            false
+        }
+    }
+
+    pub fn set_front_io_task_state(&self, blk_id: usize, state: AsyncTaskState) {
+        let mut queue = self.queues[executor_queue(blk_id)].lock();
+        // the front task is not the one VM0 completes, this is the ack of the request that timed out
+        if let Some(task) = queue.late.take() {
+            warn!(
+                "mediated blk {} completes the request of VM[{}] which has timed out",
+                blk_id, task.src_vmid
+            );
+            if queue.failed {
+                info!("mediated blk queue {} is back in service", executor_queue(blk_id));
+                queue.failed = false;
+            }
+            return;
+        }
+        if let Some(task) = queue.io_task_list.front() {
             task.set_state(state)
         }
     }
 
     pub fn add_task(&self, task: AsyncTask, ipi: bool) {
-        while active_vm().unwrap().id() != 0 && self.io_task_list.lock().len() >= 64 {
+        let idx = task.queue;
+        while active_vm().unwrap().id() != 0 && self.queues[idx].lock().io_task_list.len() >= 64 {
             sleep(1);
         }
-        let mut ipi_list = self.ipi_task_list.lock();
-        let mut io_list = self.io_task_list.lock();
-        let need_execute = active_vm().unwrap().id() != 0
-            && ipi_list.is_empty()
-            && io_list.is_empty()
-            && self.status() == AsyncExeStatus::Pending;
+        let mut queue = self.queues[idx].lock();
+        if queue.failed && !ipi {
+            drop(queue);
+            task.callback.cancel(false, true);
+            return;
+        }
+        let need_execute =
+            active_vm().unwrap().id() != 0 && queue.is_empty() && queue.status == AsyncExeStatus::Pending;
         if ipi {
-            ipi_list.push_back(Arc::new(task));
+            queue.ipi_task_list.push_back(Arc::new(task));
         } else {
-            io_list.push_back(Arc::new(task));
+            queue.io_task_list.push_back(Arc::new(task));
         }
-        drop(ipi_list);
-        drop(io_list);
-        // if this is a normal VM and this is the first IO request
+        drop(queue);
+        // if this is a normal VM and this is the first IO request of the queue
         // (which generate a ipi async task in `virtio_mediated_blk_notify_handler`)
-        // invoke the executor to handle it
+        // invoke the executor to handle it, only this queue is touched out of VM0
         if need_execute {
-            self.exec();
+            self.exec_queue(idx);
         }
     }
 
-    fn finish_task(&self, ipi: bool) {
-        if let Some(task) = if ipi {
-            self.ipi_task_list.lock().pop_front()
+    fn finish_task(&self, idx: usize, ipi: bool) {
+        // the queue is unlocked before the callback, it may look at the queued tasks
+        let mut queue = self.queues[idx].lock();
+        let task = if ipi {
+            queue.ipi_task_list.pop_front()
         } else {
-            self.io_task_list.lock().pop_front()
-        } {
-            task.callback.finish();
+            queue.io_task_list.pop_front()
+        };
+        drop(queue);
+        if let Some(task) = task {
+            let abandon = *task.abandon.lock();
+            match abandon {
+                Some(complete) => task.callback.cancel(task.started(), complete),
+                None => task.callback.finish(),
+            }
         }
     }
+
+    // number of IO tasks queued by `vm_id` on blk `blk_id`, including the running one
+    pub fn io_task_num(&self, blk_id: usize, vm_id: usize) -> usize {
+        self.queues[executor_queue(blk_id)].lock().io_task_list.owner_len(vm_id)
+    }
 }
 
 pub static EXECUTOR: Executor = Executor::new();
@@ -191,6 +289,10 @@ impl<T: TaskOwner> FairQueue<T> {
         }
     }
 
+    fn owner_len(&self, owner: usize) -> usize {
+        self.map.get(&owner).map_or(0, |sub_queue| sub_queue.len())
+    }
+
     fn front(&self) -> Option<&Arc<T>> {
         match self.queue.front() {
             Some(owner) => match self.map.get(owner) {
@@ -201,28 +303,52 @@ impl<T: TaskOwner> FairQueue<T> {
         }
     }
 
-    fn remove(&mut self, owner: usize) {
-        if let Some(sub_queue) = self.map.remove(&owner) {
-            self.len -= sub_queue.len();
-            self.queue.extract_if(|x| *x == owner).for_each(drop);
+    fn remove(&mut self, owner: usize) -> LinkedList<Arc<T>> {
+        match self.map.remove(&owner) {
+            Some(sub_queue) => {
+                self.len -= sub_queue.len();
+                self.queue.extract_if(|x| *x == owner).for_each(drop);
+                sub_queue
+            }
+            None => LinkedList::new(),
         }
     }
+
+    fn take_all(&mut self) -> LinkedList<Arc<T>> {
+        let mut tasks = LinkedList::new();
+        while let Some(task) = self.pop_front() {
+            tasks.push_back(task);
+        }
+        tasks
+    }
+
+    fn owner_iter(&self, owner: usize) -> impl Iterator<Item = &Arc<T>> {
+        self.map.get(&owner).into_iter().flat_map(|sub_queue| sub_queue.iter())
+    }
 }
 
 pub trait AsyncCallback {
     fn preprocess(&self);
     #[inline]
     fn finish(&self) {}
+    /* Called instead of `finish` for a task cancelled by `cancel_vm_async_task`,
+     * `started` if it was handed to VM0, `complete` if the request may still be completed to the guest.
+     */
+    #[inline]
+    fn cancel(&self, _started: bool, _complete: bool) {}
 }
 
 impl AsyncCallback for IpiMediatedMsg {
     #[inline]
     fn preprocess(&self) {
-        if active_vm().unwrap().id() == 0 {
-            virtio_blk_notify_handler(self.vq.clone(), self.blk.clone(), self.src_vm.clone());
+        // already in the executor, so call the handler directly rather than queue an ipi to self
+        if active_vm().unwrap().id() == 0 || current_cpu().id == 0 {
+            virtio_mediated_blk_req_handler(self.vq.clone(), self.blk.clone(), self.src_vm.clone(), self.blk_id);
         } else {
             // send IPI to target cpu, and the target will invoke `mediated_ipi_handler`
-            ipi_send_msg(0, IpiType::MediatedDev, IpiInnerMsg::MediatedMsg(self.clone()));
+            if let Err(err) = ipi_send_msg_retry(0, IpiType::MediatedDev, IpiInnerMsg::MediatedMsg(self.clone())) {
+                error!("mediated notify: failed to send ipi to Core 0: {:?}", err);
+            }
         }
     }
 }
@@ -230,38 +356,107 @@ impl AsyncCallback for IpiMediatedMsg {
 impl AsyncCallback for ReadAsyncMsg {
     #[inline]
     fn preprocess(&self) {
-        mediated_blk_read(self.blk_id, self.sector, self.count);
+        if !self.zero_copy.is_empty() {
+            mediated_blk_zero_copy_map(self.blk_id, &self.zero_copy);
+        }
+        virtio_blk_mediated_submit(&self.dev, self.blk_id, VIRTIO_BLK_T_IN, self.sector, self.count);
     }
 
     #[inline]
     fn finish(&self) {
+        if !self.zero_copy.is_empty() {
+            // VM0 has written the guest pages
+            mediated_blk_zero_copy_unmap(self.blk_id, self.zero_copy.len());
+        }
         // let mut sum = 0;
         let mut cache_ptr = self.cache;
         for iov in self.iov_list.iter() {
             let data_bg = iov.data_bg;
             let len = iov.len as usize;
-            memcpy_safe(data_bg as *mut u8, cache_ptr as *mut u8, len);
+            if self.zero_copy.is_empty() {
+                memcpy_safe(data_bg as *mut u8, cache_ptr as *mut u8, len);
+            }
+            self.src_vm.dirty_log_mark(iov.ipa, len);
             // sum |= check_sum(data_bg, len);
             cache_ptr += len;
         }
         // println!("read check_sum is {:x}", sum);
-        let info = &self.used_info;
-        self.vq.update_used_ring(info.used_len, info.desc_chain_head_idx);
-        self.dev.notify();
+        // the task has been popped, any left is a later request of the VM
+        let more = EXECUTOR.io_task_num(self.blk_id, self.src_vm.id()) > 0;
+        virtio_blk_complete(&self.vq, &self.dev, &self.used_info, more);
+    }
+
+    #[inline]
+    fn cancel(&self, started: bool, complete: bool) {
+        if started && !self.zero_copy.is_empty() {
+            mediated_blk_zero_copy_unmap(self.blk_id, self.zero_copy.len());
+        }
+        // the data never reached the guest buffers
+        if complete {
+            virtio_blk_complete_err(&self.vq, &self.dev, &self.used_info);
+        }
     }
 }
 
 impl AsyncCallback for WriteAsyncMsg {
     #[inline]
     fn preprocess(&self) {
+        if !self.zero_copy.is_empty() {
+            // the guest buffers are in use until VM0 completes the write
+            mediated_blk_zero_copy_map(self.blk_id, &self.zero_copy);
+            virtio_blk_mediated_submit(&self.dev, self.blk_id, VIRTIO_BLK_T_OUT, self.sector, self.count);
+            return;
+        }
         // copy buffer to cache
         let mut buffer = self.buffer.lock();
         memcpy_safe(self.cache as *mut u8, buffer.as_ptr(), buffer.len());
-        mediated_blk_write(self.blk_id, self.sector, self.count);
+        virtio_blk_mediated_submit(&self.dev, self.blk_id, VIRTIO_BLK_T_OUT, self.sector, self.count);
         buffer.clear();
-        let info = &self.used_info;
-        self.vq.update_used_ring(info.used_len, info.desc_chain_head_idx);
-        self.dev.notify();
+        // this task is still at the front of the queue
+        let more = EXECUTOR.io_task_num(self.blk_id, self.src_vm.id()) > 1;
+        virtio_blk_complete(&self.vq, &self.dev, &self.used_info, more);
+    }
+
+    #[inline]
+    fn finish(&self) {
+        if !self.zero_copy.is_empty() {
+            mediated_blk_zero_copy_unmap(self.blk_id, self.zero_copy.len());
+            let more = EXECUTOR.io_task_num(self.blk_id, self.src_vm.id()) > 0;
+            virtio_blk_complete(&self.vq, &self.dev, &self.used_info, more);
+        }
+    }
+
+    #[inline]
+    fn cancel(&self, started: bool, complete: bool) {
+        if started && !self.zero_copy.is_empty() {
+            mediated_blk_zero_copy_unmap(self.blk_id, self.zero_copy.len());
+        }
+        // a write through the cache is completed to the guest in `preprocess`
+        if complete && (!started || !self.zero_copy.is_empty()) {
+            virtio_blk_complete_err(&self.vq, &self.dev, &self.used_info);
+        }
+    }
+}
+
+impl AsyncCallback for FlushAsyncMsg {
+    #[inline]
+    fn preprocess(&self) {
+        // the writes before it in the queue are already completed by VM0
+        virtio_blk_mediated_submit(&self.dev, self.blk_id, VIRTIO_BLK_T_FLUSH, 0, 0);
+    }
+
+    #[inline]
+    fn finish(&self) {
+        let more = EXECUTOR.io_task_num(self.blk_id, self.src_vm.id()) > 0;
+        virtio_blk_complete(&self.vq, &self.dev, &self.used_info, more);
+    }
+
+    #[inline]
+    fn cancel(&self, _started: bool, complete: bool) {
+        // the backing storage may not be synced
+        if complete {
+            virtio_blk_complete_err(&self.vq, &self.dev, &self.used_info);
+        }
     }
 }
 
@@ -281,7 +476,14 @@ pub struct AsyncTask {
     id: TaskId,
     callback: Box<dyn AsyncCallback + Send + Sync>,
     src_vmid: usize,
+    // index of the executor queue
+    queue: usize,
+    // when the task was queued and handed to VM0, in us, 0 if it is not yet
+    enqueue_us: u64,
+    start_us: AtomicU64,
     state: Mutex<AsyncTaskState>,
+    // set by `cancel_vm_async_task`, whether the request may still be completed to the guest
+    abandon: Mutex<Option<bool>>,
     task: Mutex<Pin<Box<dyn Future<Output = ()> + 'static + Send + Sync>>>,
 }
 
@@ -301,13 +503,18 @@ impl AsyncTask {
     pub fn new(
         callback: impl AsyncCallback + 'static + Send + Sync,
         src_vmid: usize,
+        blk_id: usize,
         future: impl Future<Output = ()> + 'static + Send + Sync,
     ) -> Self {
         Self {
             id: TaskId::new(),
             callback: Box::new(callback),
             src_vmid,
+            queue: executor_queue(blk_id),
+            enqueue_us: timer::now().as_micros() as u64,
+            start_us: AtomicU64::new(0),
             state: Mutex::new(AsyncTaskState::Pending),
+            abandon: Mutex::new(None),
             task: Mutex::new(Box::pin(future)),
         }
     }
@@ -315,7 +522,10 @@ impl AsyncTask {
     fn handle(self: &Arc<Self>) -> bool {
         let mut state = self.state.lock();
         match *state {
-            AsyncTaskState::Pending => *state = AsyncTaskState::Running,
+            AsyncTaskState::Pending => {
+                *state = AsyncTaskState::Running;
+                self.start_us.store(timer::now().as_micros() as u64, Ordering::Relaxed);
+            }
             AsyncTaskState::Running => {
                 return false;
             }
@@ -334,29 +544,247 @@ impl AsyncTask {
         let mut cur_state = self.state.lock();
         *cur_state = state;
     }
+
+    // the request has been handed to VM0
+    fn started(&self) -> bool {
+        !matches!(*self.state.lock(), AsyncTaskState::Pending)
+    }
+
+    fn abandoned(&self) -> bool {
+        self.abandon.lock().is_some()
+    }
 }
 
-// async req function
-pub async fn async_ipi_req() {
-    let ipi_list = EXECUTOR.ipi_task_list.lock();
-    if let Some(task) = ipi_list.front().cloned() {
-        drop(ipi_list);
+// async req function, `blk_id` must be the one the task is created with
+pub async fn async_ipi_req(blk_id: usize) {
+    let queue = EXECUTOR.queues[executor_queue(blk_id)].lock();
+    if let Some(task) = queue.ipi_task_list.front().cloned() {
+        drop(queue);
         task.callback.preprocess();
     }
 }
 
-pub async fn async_blk_io_req() {
-    let io_list = EXECUTOR.io_task_list.lock();
-    if let Some(task) = io_list.front().cloned() {
-        drop(io_list);
+pub async fn async_blk_io_req(blk_id: usize) {
+    let queue = EXECUTOR.queues[executor_queue(blk_id)].lock();
+    if let Some(task) = queue.io_task_list.front().cloned() {
+        drop(queue);
         task.callback.preprocess();
     }
 }
 // end async req function
 
-pub fn remove_vm_async_task(vm_id: usize) {
-    let mut io_list = EXECUTOR.io_task_list.lock();
-    let mut ipi_list = EXECUTOR.ipi_task_list.lock();
-    io_list.remove(vm_id);
-    ipi_list.extract_if(|x| x.src_vmid == vm_id).for_each(drop);
+/* Cancel the tasks of `vm_id`, return how many are cancelled.
+ * A queued task is dropped at once, unless a request of the VM is in progress in the same queue:
+ * then the tasks of the VM are marked and dropped in order once VM0 completes that request,
+ * so that the completion is not taken for the one of another task.
+ * With `complete`, the requests are completed to the guest with VIRTIO_BLK_S_IOERR,
+ * it must not be set if the virtqueues of the VM are being torn down.
+ */
+pub fn cancel_vm_async_task(vm_id: usize, complete: bool) -> usize {
+    let mut num = 0;
+    for queue in EXECUTOR.queues.iter() {
+        let mut queue = queue.lock();
+        num += queue.ipi_task_list.extract_if(|x| x.src_vmid == vm_id).count();
+        let running = queue
+            .io_task_list
+            .front()
+            .is_some_and(|task| task.src_vmid == vm_id && task.started());
+        if running {
+            for task in queue.io_task_list.owner_iter(vm_id) {
+                *task.abandon.lock() = Some(complete);
+                num += 1;
+            }
+        } else {
+            let tasks = queue.io_task_list.remove(vm_id);
+            drop(queue);
+            num += tasks.len();
+            for task in tasks {
+                task.callback.cancel(false, complete);
+            }
+        }
+    }
+    num
+}
+
+// a request handed to VM0 and not completed within this is failed to the guest, 0 never times out
+static MEDIATED_TASK_TIMEOUT_MS: AtomicUsize = AtomicUsize::new(3000);
+const MEDIATED_TASK_CHECK_PERIOD: Duration = Duration::from_millis(500);
+
+/* Fail the requests VM0 did not complete in time, so that a dead backend does not hold the queues forever.
+ * The front task of a queue is marked abandoned and finished, the executor completes it to the guest with
+ * VIRTIO_BLK_S_IOERR through `AsyncCallback::cancel` and goes on with the next one. A write through the
+ * cache is already completed when it is handed to VM0, only its task is dropped.
+ * VM0 may still be serving the request on the cache page, so the queue keeps it as the late one and the
+ * next request is not handed to VM0 until VM0 acks the timeout by completing it, with any status. The
+ * completion is dropped in `set_front_io_task_state`, it would be taken for the one of the next task otherwise.
+ * If no completion comes within another timeout, the queue is failed: its IO requests are failed to the
+ * guests at once until VM0 completes the late one.
+ */
+fn async_task_timeout_check(now: TimerValue) {
+    let timeout_us = MEDIATED_TASK_TIMEOUT_MS.load(Ordering::Relaxed) as u64 * 1000;
+    if timeout_us == 0 {
+        return;
+    }
+    let now_us = now.as_micros() as u64;
+    let mut expired = false;
+    // the uploads are copied by the hypervisor itself
+    for (idx, queue) in EXECUTOR.queues[..EXECUTOR_QUEUE_NUM].iter().enumerate() {
+        // the queue is in use on this core, the next check will see it
+        let mut queue = match queue.try_lock() {
+            Some(queue) => queue,
+            None => continue,
+        };
+        if let Some(late) = queue.late.clone() {
+            let start_us = late.start_us.load(Ordering::Relaxed);
+            if queue.failed || now_us.saturating_sub(start_us) < 2 * timeout_us {
+                continue;
+            }
+            queue.failed = true;
+            let mut tasks = queue.io_task_list.take_all();
+            // the executor has not dropped the late one yet, it does once it runs the queue
+            if let Some(task) = tasks.extract_if(|task| Arc::ptr_eq(task, &late)).next() {
+                queue.io_task_list.push_back(task);
+            }
+            drop(queue);
+            error!(
+                "mediated blk queue {}: VM0 does not ack the request of VM[{}] which timed out, queue failed",
+                idx, late.src_vmid
+            );
+            for task in tasks {
+                task.callback
+                    .cancel(false, !matches!(*task.abandon.lock(), Some(false)));
+            }
+            continue;
+        }
+        let task = match queue.io_task_list.front() {
+            Some(task) if matches!(*task.state.lock(), AsyncTaskState::Running) => task.clone(),
+            _ => continue,
+        };
+        let start_us = task.start_us.load(Ordering::Relaxed);
+        if now_us.saturating_sub(start_us) < timeout_us {
+            continue;
+        }
+        // a task cancelled with its VM keeps the guest untouched
+        let mut abandon = task.abandon.lock();
+        if abandon.is_none() {
+            *abandon = Some(true);
+        }
+        drop(abandon);
+        task.set_state(AsyncTaskState::Finish);
+        queue.late = Some(task.clone());
+        drop(queue);
+        warn!(
+            "mediated blk queue {}: request of VM[{}] is not completed by VM0 in {} us, failed",
+            idx,
+            task.src_vmid,
+            now_us - start_us
+        );
+        let msg = HvcManageMsg {
+            fid: HVC_MEDIATED,
+            event: HVC_MEDIATED_TASK_TIMEOUT,
+            vm_id: task.src_vmid,
+        };
+        if !hvc_send_msg_to_vm(0, &HvcGuestMsg::Manage(msg)) {
+            warn!("async_task_timeout_check: failed to notify VM0");
+        }
+        expired = true;
+    }
+    if expired {
+        EXECUTOR.exec();
+    }
+}
+
+struct AsyncTaskTimeoutTimer;
+
+impl TimerEvent for AsyncTaskTimeoutTimer {
+    fn callback(self: Arc<Self>, now: TimerValue) {
+        async_task_timeout_check(now);
+        timer::start_timer_event(MEDIATED_TASK_CHECK_PERIOD, self);
+    }
+}
+
+// on core 0, where VM0 completes the requests
+pub fn async_task_timeout_init() {
+    timer::start_timer_event(MEDIATED_TASK_CHECK_PERIOD, Arc::new(AsyncTaskTimeoutTimer));
+}
+
+// HVC_MEDIATED_TASK_TIMEOUT: VM0 only, set the timeout of the requests in ms, return the previous one
+pub fn async_task_set_timeout(timeout_ms: usize) -> Result<usize, ()> {
+    let vm = active_vm().unwrap();
+    if vm.id() != 0 {
+        error!(
+            "async_task_set_timeout: VM[{}] is not allowed to set the timeout",
+            vm.id()
+        );
+        return Err(());
+    }
+    info!(
+        "async_task_set_timeout: mediated requests time out in {} ms",
+        timeout_ms
+    );
+    Ok(MEDIATED_TASK_TIMEOUT_MS.swap(timeout_ms, Ordering::Relaxed))
+}
+
+#[repr(C)]
+struct AsyncTaskStat {
+    queued: u64,
+    running: u64,
+    // age of the oldest queued and running task in us, 0 if there is none
+    queued_age_us: u64,
+    running_age_us: u64,
+}
+
+// HVC_MEDIATED_TASK_STAT: VM0 only, write the IO tasks of `vm_id` to `stat_ipa`, return how many there are
+pub fn async_task_stat(vm_id: usize, stat_ipa: usize) -> Result<usize, ()> {
+    let vm = active_vm().unwrap();
+    if vm.id() != 0 {
+        error!("async_task_stat: VM[{}] is not allowed to read the task stat", vm.id());
+        return Err(());
+    }
+    let now = timer::now().as_micros() as u64;
+    let mut stat = AsyncTaskStat {
+        queued: 0,
+        running: 0,
+        queued_age_us: 0,
+        running_age_us: 0,
+    };
+    for queue in EXECUTOR.queues.iter() {
+        let queue = queue.lock();
+        let tasks = queue
+            .ipi_task_list
+            .iter()
+            .filter(|task| task.src_vmid == vm_id)
+            .chain(queue.io_task_list.owner_iter(vm_id));
+        for task in tasks.filter(|task| !task.abandoned()) {
+            let age = now.saturating_sub(task.enqueue_us);
+            if task.started() {
+                stat.running += 1;
+                stat.running_age_us = stat.running_age_us.max(age);
+            } else {
+                stat.queued += 1;
+                stat.queued_age_us = stat.queued_age_us.max(age);
+            }
+        }
+    }
+    if copy_to_vm(&vm, stat_ipa as *mut u8, &stat).is_err() {
+        error!("async_task_stat: illegal stat ipa {:#x}", stat_ipa);
+        return Err(());
+    }
+    Ok((stat.queued + stat.running) as usize)
+}
+
+// HVC_MEDIATED_TASK_CANCEL: VM0 only, cancel the IO tasks of a guest which stopped consuming its used ring
+pub fn async_task_cancel(vm_id: usize) -> Result<usize, ()> {
+    let vm = active_vm().unwrap();
+    if vm.id() != 0 || vm_id == 0 {
+        error!(
+            "async_task_cancel: VM[{}] can not cancel the tasks of VM[{}]",
+            vm.id(),
+            vm_id
+        );
+        return Err(());
+    }
+    let num = cancel_vm_async_task(vm_id, true);
+    info!("async_task_cancel: cancel {} tasks of VM[{}]", num, vm_id);
+    Ok(num)
 }
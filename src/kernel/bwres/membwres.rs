@@ -183,6 +183,90 @@ impl MemoryBandwidth {
     }
 }
 
+// the budget a VM consumed in one replenishment period, as copied to MVM by HVC_VMM_MEM_BW_STAT
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct MemBwRecord {
+    // the period in us since boot
+    pub start_us: u64,
+    pub end_us: u64,
+    // memory accesses counted by the PMU, summed over the vcpus
+    pub used: u32,
+    pub budget: u32,
+}
+
+pub const MEM_BW_HISTORY_LEN: usize = 16;
+
+/* The consumed budget of a VM in its last periods. Each vcpu has its own replenishment timer,
+ * a period of the VM is closed once every vcpu has reported, or when a vcpu reports twice
+ * because another one missed its replenishment while runnable.
+ */
+pub struct MemBwHistory {
+    vcpu_num: usize,
+    budget: u32,
+    // bitmap of the vcpus reported in the current period
+    reported: usize,
+    used: u32,
+    start: Duration,
+    ring: [MemBwRecord; MEM_BW_HISTORY_LEN],
+    // the next slot to write, and the records kept
+    head: usize,
+    len: usize,
+}
+
+impl MemBwHistory {
+    pub fn new(vcpu_num: usize, budget: u32, start: Duration) -> Self {
+        Self {
+            vcpu_num,
+            budget,
+            reported: 0,
+            used: 0,
+            start,
+            ring: [MemBwRecord::default(); MEM_BW_HISTORY_LEN],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn close(&mut self, now: Duration) {
+        self.ring[self.head] = MemBwRecord {
+            start_us: self.start.as_micros() as u64,
+            end_us: now.as_micros() as u64,
+            used: self.used,
+            budget: self.budget,
+        };
+        self.head = (self.head + 1) % MEM_BW_HISTORY_LEN;
+        self.len = usize::min(self.len + 1, MEM_BW_HISTORY_LEN);
+        self.start = now;
+        self.reported = 0;
+        self.used = 0;
+    }
+
+    // vcpu `vcpu_id` used `used` of its budget in the period ending at `now`
+    pub fn report(&mut self, vcpu_id: usize, used: u32, now: Duration) {
+        let bit = 1 << (vcpu_id % usize::BITS as usize);
+        if self.reported & bit != 0 {
+            self.close(now);
+        }
+        self.reported |= bit;
+        self.used = self.used.saturating_add(used);
+        if self.reported.count_ones() as usize >= self.vcpu_num {
+            self.close(now);
+        }
+    }
+
+    // copy the records to `out`, the oldest first, return the number copied
+    pub fn copy_to(&self, out: &mut [MemBwRecord]) -> usize {
+        let len = usize::min(self.len, out.len());
+        // skip the oldest ones if `out` is too short
+        let first = (self.head + MEM_BW_HISTORY_LEN - len) % MEM_BW_HISTORY_LEN;
+        for (i, record) in out[..len].iter_mut().enumerate() {
+            *record = self.ring[(first + i) % MEM_BW_HISTORY_LEN];
+        }
+        len
+    }
+}
+
 #[repr(align(64))] // CACHE_LINE_SIZE
 struct ListNode<T> {
     data: T,
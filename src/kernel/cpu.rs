@@ -8,7 +8,8 @@ use crate::arch::ContextFrameTrait;
 use crate::arch::PageTable;
 use crate::arch::{pt_map_banked_cpu, TlbInvalidate, PAGE_SIZE, PTE_PER_PAGE};
 use crate::board::{static_config, PLAT_DESC};
-use crate::kernel::{Vcpu, Vm};
+use crate::kernel::{ExitClass, Vcpu, Vm};
+use crate::mm::HeapTag;
 use crate::util::timer_list::TimerList;
 
 use super::sched::get_scheduler;
@@ -62,6 +63,10 @@ pub struct Cpu {
     pub current_irq: usize,
     global_pt: Once<PageTable>,
     pub interrupt_nested: usize,
+    // what the heap allocated on this core is accounted to
+    pub heap_tag: HeapTag,
+    // class of the guest exit being handled, see `exit_stat_enter`
+    pub exit_class: ExitClass,
     pub cpu_pt: CpuPt,
     stack: CpuStack,
 }
@@ -79,6 +84,8 @@ impl Cpu {
             timer_list: TimerList::new(),
             current_irq: 0,
             interrupt_nested: 0,
+            heap_tag: HeapTag::Other,
+            exit_class: ExitClass::DataAbort,
             global_pt: Once::new(),
             cpu_pt: CpuPt {
                 lvl1: [0; PTE_PER_PAGE],
@@ -0,0 +1,92 @@
+use alloc::vec::Vec;
+
+use crate::arch::PAGE_SIZE;
+use crate::config::VmRegion;
+use crate::util::{round_down, round_up, FlexBitmap};
+
+/* The pages of a VM written since the last fetch, one bit for each page of its memory regions,
+ * indexed over the regions in their order. The MVM fetches the bitmap round after round while it
+ * copies the memory of the VM, so a page only goes again if it was written after its last copy.
+ */
+pub struct DirtyLog {
+    regions: Vec<VmRegion>,
+    map: FlexBitmap,
+}
+
+impl DirtyLog {
+    // every page starts dirty, the first round copies the whole memory
+    pub fn new(regions: Vec<VmRegion>) -> Self {
+        let pages: usize = regions.iter().map(|region| region.length / PAGE_SIZE).sum();
+        let mut map = FlexBitmap::new(pages);
+        for page in 0..pages {
+            map.set(page, true);
+        }
+        Self { regions, map }
+    }
+
+    pub fn regions(&self) -> &[VmRegion] {
+        &self.regions
+    }
+
+    // the bytes a fetch copies out
+    pub fn map_size(&self) -> usize {
+        self.map.slice().len() * core::mem::size_of::<usize>()
+    }
+
+    pub fn dirty_pages(&self) -> usize {
+        self.map.sum()
+    }
+
+    fn page_idx(&self, ipa: usize) -> Option<usize> {
+        let mut base = 0;
+        for region in self.regions.iter() {
+            if region.as_range().contains(&ipa) {
+                return Some(base + (ipa - region.ipa_start) / PAGE_SIZE);
+            }
+            base += region.length / PAGE_SIZE;
+        }
+        None
+    }
+
+    pub fn contains(&self, ipa: usize) -> bool {
+        self.page_idx(ipa).is_some()
+    }
+
+    // mark the pages of [ipa, ipa + len) dirty, the ones outside of the logged memory are ignored
+    pub fn mark(&mut self, ipa: usize, len: usize) {
+        let mut page = round_down(ipa, PAGE_SIZE);
+        let end = round_up(ipa + len, PAGE_SIZE);
+        while page < end {
+            if let Some(idx) = self.page_idx(page) {
+                self.map.set(idx, true);
+            }
+            page += PAGE_SIZE;
+        }
+    }
+
+    /* Copy the bitmap to `out` and clear it, return the dirty ranges as (ipa, len) so that the
+     * caller write protects them again. The bits are cleared before anything is protected: a write
+     * after the fetch faults and sets its bit for the next round.
+     */
+    pub fn fetch(&mut self, out: &mut [usize]) -> Vec<(usize, usize)> {
+        out[..self.map.slice().len()].copy_from_slice(self.map.slice());
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        let mut base = 0;
+        for region in self.regions.iter() {
+            let pages = region.length / PAGE_SIZE;
+            for page in 0..pages {
+                if self.map.get(base + page) == 0 {
+                    continue;
+                }
+                let ipa = region.ipa_start + page * PAGE_SIZE;
+                match ranges.last_mut() {
+                    Some((start, len)) if *start + *len == ipa => *len += PAGE_SIZE,
+                    _ => ranges.push((ipa, PAGE_SIZE)),
+                }
+            }
+            base += pages;
+        }
+        self.map.clear();
+        ranges
+    }
+}
@@ -0,0 +1,105 @@
+#[cfg(feature = "event-trace")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(feature = "event-trace")]
+use crate::board::static_config::CORE_NUM;
+#[cfg(feature = "event-trace")]
+use crate::kernel::current_cpu;
+
+// the last events of each core kept for the panic dump
+#[cfg(feature = "event-trace")]
+const EVENT_TRACE_LEN: usize = 64;
+
+// the two arguments of each event are in the comments
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum TraceEvent {
+    // fid, event
+    Hvc,
+    // int_id
+    Irq,
+    // vm_id, int_id
+    Inject,
+    // ipi type, target core
+    IpiSend,
+    // ipi type
+    IpiHandle,
+    // vm_id, vcpu_id of the vcpu switched in
+    VcpuSwitch,
+}
+
+impl TraceEvent {
+    #[cfg(feature = "event-trace")]
+    fn from_usize(kind: usize) -> Option<Self> {
+        match kind {
+            0 => Some(Self::Hvc),
+            1 => Some(Self::Irq),
+            2 => Some(Self::Inject),
+            3 => Some(Self::IpiSend),
+            4 => Some(Self::IpiHandle),
+            5 => Some(Self::VcpuSwitch),
+            _ => None,
+        }
+    }
+}
+
+/* One ring for each core, only written by its own core and read when it panics. An irq taken while
+ * an entry is written gets the next slot, so no lock is needed, a torn entry is only seen in the dump.
+ */
+#[cfg(feature = "event-trace")]
+struct EventRing {
+    head: AtomicUsize,
+    // time in ns, kind, and the two arguments of each entry
+    entries: [[AtomicUsize; 4]; EVENT_TRACE_LEN],
+}
+
+#[cfg(feature = "event-trace")]
+impl EventRing {
+    const fn new() -> Self {
+        Self {
+            head: AtomicUsize::new(0),
+            entries: [const { [const { AtomicUsize::new(0) }; 4] }; EVENT_TRACE_LEN],
+        }
+    }
+}
+
+#[cfg(feature = "event-trace")]
+static EVENT_RING_LIST: [EventRing; CORE_NUM] = [const { EventRing::new() }; CORE_NUM];
+
+// record an event of this core, compiled out without the event-trace feature
+#[inline(always)]
+pub fn event_trace(event: TraceEvent, a: usize, b: usize) {
+    #[cfg(feature = "event-trace")]
+    {
+        let ring = &EVENT_RING_LIST[current_cpu().id];
+        let idx = ring.head.fetch_add(1, Ordering::Relaxed) % EVENT_TRACE_LEN;
+        let entry = &ring.entries[idx];
+        entry[0].store(crate::arch::timer::gettime_ns(), Ordering::Relaxed);
+        entry[1].store(event as usize, Ordering::Relaxed);
+        entry[2].store(a, Ordering::Relaxed);
+        entry[3].store(b, Ordering::Relaxed);
+    }
+    #[cfg(not(feature = "event-trace"))]
+    let _ = (event, a, b);
+}
+
+// print the events of this core, oldest first
+pub fn event_trace_dump() {
+    #[cfg(feature = "event-trace")]
+    {
+        let ring = &EVENT_RING_LIST[current_cpu().id];
+        let head = ring.head.load(Ordering::Relaxed);
+        let num = head.min(EVENT_TRACE_LEN);
+        println!("last {} events of core {}:", num, current_cpu().id);
+        for i in head - num..head {
+            let entry = &ring.entries[i % EVENT_TRACE_LEN];
+            println!(
+                "  [{:>16}ns] {:?} {:#x} {:#x}",
+                entry[0].load(Ordering::Relaxed),
+                TraceEvent::from_usize(entry[1].load(Ordering::Relaxed)),
+                entry[2].load(Ordering::Relaxed),
+                entry[3].load(Ordering::Relaxed)
+            );
+        }
+    }
+}
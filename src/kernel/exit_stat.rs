@@ -0,0 +1,186 @@
+use core::fmt::{Display, Formatter};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::arch::timer::gettime_ns;
+use crate::device::EmuDeviceType;
+use crate::kernel::{current_cpu, Vcpu};
+
+pub const EXIT_CLASS_NUM: usize = 8;
+
+// why a vcpu left the guest, a data abort is refined by the emulated device it hits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ExitClass {
+    // stage-2 faults, and data or instruction aborts not taken by the vgic or a virtio device
+    DataAbort = 0,
+    Hvc = 1,
+    Smc = 2,
+    Sysreg = 3,
+    Wfx = 4,
+    Irq = 5,
+    VgicAccess = 6,
+    VirtioAccess = 7,
+}
+
+impl ExitClass {
+    const ALL: [ExitClass; EXIT_CLASS_NUM] = [
+        ExitClass::DataAbort,
+        ExitClass::Hvc,
+        ExitClass::Smc,
+        ExitClass::Sysreg,
+        ExitClass::Wfx,
+        ExitClass::Irq,
+        ExitClass::VgicAccess,
+        ExitClass::VirtioAccess,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            ExitClass::DataAbort => "abort",
+            ExitClass::Hvc => "hvc",
+            ExitClass::Smc => "smc",
+            ExitClass::Sysreg => "sysreg",
+            ExitClass::Wfx => "wfx",
+            ExitClass::Irq => "irq",
+            ExitClass::VgicAccess => "vgic",
+            ExitClass::VirtioAccess => "virtio",
+        }
+    }
+
+    // the class of an access to an emulated device, None keeps it a plain data abort
+    pub fn from_emu_type(emu_type: EmuDeviceType) -> Option<Self> {
+        match emu_type {
+            EmuDeviceType::EmuDeviceTGicd => Some(ExitClass::VgicAccess),
+            EmuDeviceType::EmuDeviceTVirtioBlk
+            | EmuDeviceType::EmuDeviceTVirtioNet
+            | EmuDeviceType::EmuDeviceTVirtioConsole
+            | EmuDeviceType::EmuDeviceTVirtioBlkMediated
+            | EmuDeviceType::EmuDeviceTVirtioBalloon
+            | EmuDeviceType::EmuDeviceTVirtioRng => Some(ExitClass::VirtioAccess),
+            _ => None,
+        }
+    }
+}
+
+// the exits of a vcpu and the time the hypervisor spent on them, by `ExitClass`
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct ExitStat {
+    pub count: [u64; EXIT_CLASS_NUM],
+    pub time_ns: [u64; EXIT_CLASS_NUM],
+}
+
+impl ExitStat {
+    pub fn add(&mut self, other: &ExitStat) {
+        for class in 0..EXIT_CLASS_NUM {
+            self.count[class] += other.count[class];
+            self.time_ns[class] += other.time_ns[class];
+        }
+    }
+}
+
+// one line, "<class> <count>/<time>us" of each class that has exits
+impl Display for ExitStat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let mut empty = true;
+        for class in ExitClass::ALL {
+            let count = self.count[class as usize];
+            if count == 0 {
+                continue;
+            }
+            if !empty {
+                write!(f, " ")?;
+            }
+            write!(
+                f,
+                "{} {}/{}us",
+                class.name(),
+                count,
+                self.time_ns[class as usize] / 1000
+            )?;
+            empty = false;
+        }
+        if empty {
+            write!(f, "none")?;
+        }
+        Ok(())
+    }
+}
+
+/* The counters of one vcpu. They are only written on the core the vcpu runs on, so a plain load and
+ * store is enough and the exit path takes no lock. A reset from VM0 may lose the exits it races with.
+ */
+pub struct VcpuExitStat {
+    count: [AtomicU64; EXIT_CLASS_NUM],
+    time_ns: [AtomicU64; EXIT_CLASS_NUM],
+}
+
+impl VcpuExitStat {
+    pub const fn new() -> Self {
+        Self {
+            count: [const { AtomicU64::new(0) }; EXIT_CLASS_NUM],
+            time_ns: [const { AtomicU64::new(0) }; EXIT_CLASS_NUM],
+        }
+    }
+
+    pub fn record(&self, class: ExitClass, time_ns: u64) {
+        let count = &self.count[class as usize];
+        count.store(count.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        let time = &self.time_ns[class as usize];
+        time.store(time.load(Ordering::Relaxed) + time_ns, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ExitStat {
+        let mut stat = ExitStat::default();
+        for class in 0..EXIT_CLASS_NUM {
+            stat.count[class] = self.count[class].load(Ordering::Relaxed);
+            stat.time_ns[class] = self.time_ns[class].load(Ordering::Relaxed);
+        }
+        stat
+    }
+
+    pub fn reset(&self) {
+        for class in 0..EXIT_CLASS_NUM {
+            self.count[class].store(0, Ordering::Relaxed);
+            self.time_ns[class].store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for VcpuExitStat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/* An exit being handled on this core. The vcpu is kept, the handler may switch to another one or
+ * move it, and the exit is still charged to the vcpu that trapped.
+ */
+pub struct ExitEntry {
+    vcpu: Vcpu,
+    begin: usize,
+}
+
+// on the entry of an exception from the guest, None if no vcpu trapped or the class is unknown
+pub fn exit_stat_enter(class: Option<ExitClass>) -> Option<ExitEntry> {
+    let cpu = current_cpu();
+    let class = class?;
+    let vcpu = cpu.active_vcpu.clone()?;
+    cpu.exit_class = class;
+    Some(ExitEntry {
+        vcpu,
+        begin: gettime_ns(),
+    })
+}
+
+// the handler found the exit is of a finer class, e.g. a data abort on the vgic distributor
+pub fn exit_stat_refine(class: ExitClass) {
+    current_cpu().exit_class = class;
+}
+
+pub fn exit_stat_leave(entry: Option<ExitEntry>) {
+    if let Some(entry) = entry {
+        let time_ns = gettime_ns().saturating_sub(entry.begin);
+        entry.vcpu.exit_stat().record(current_cpu().exit_class, time_ns as u64);
+    }
+}
@@ -1,20 +1,70 @@
+use alloc::collections::VecDeque;
 use core::mem::size_of;
 
 use crate::arch::PAGE_SIZE;
 use crate::device::{mediated_blk_notify_handler, mediated_dev_append};
+#[cfg(feature = "watchdog")]
+use crate::kernel::watchdog_set;
 use crate::kernel::{
-    active_vm, current_cpu, interrupt_vm_inject, ipi_send_msg, ivc_update_mq, vm_by_id, vm_if_get_cpu_id,
-    vm_if_ivc_arg, vm_if_ivc_arg_ptr, vm_if_set_ivc_arg_ptr, IpiHvcMsg, IpiInnerMsg, IpiMessage, IpiType,
+    active_vm, async_task_cancel, async_task_set_timeout, async_task_stat, current_cpu, event_trace, hw_test_start,
+    interrupt_vm_inject, iommu_fault_read, ipi_send_msg_retry, ipi_stat, ipi_type_stat, ivc_close_share_mem,
+    ivc_list_share_mem, ivc_send_doorbell, ivc_share_mem, ivc_share_mem_ipa, ivc_update_mq, mem_color_free_info,
+    mem_color_info, mem_heap_stat, mem_heap_tag_stat, vm_by_id, vm_if_get_cpu_id, vm_if_ivc_access,
+    vm_if_state_snapshot, vm_list_walker, IpiHvcMsg, IpiInnerMsg, IpiMessage, IpiType, TraceEvent, VmInterface,
 };
+use crate::mm::{HeapTag, HeapTagGuard};
+use crate::util::logger::{log_level_set, LogModule};
 use crate::util::memcpy_safe;
-use crate::vmm::{get_vm_id, vmm_boot_vm, vmm_list_vm, vmm_reboot_vm, vmm_remove_vm};
+use crate::vmm::{
+    get_vm_id, vmm_boot_vm, vmm_dirty_log_fetch, vmm_dirty_log_start, vmm_dirty_log_stop, vmm_dump_vm, vmm_exit_stat,
+    vmm_halt_poll_stat, vmm_int_transfer, vmm_list_vm, vmm_log_console, vmm_lr_stat, vmm_migrate_vcpu, vmm_net_stat,
+    vmm_read_console, vmm_read_log, vmm_reboot_vm, vmm_remove_vm, vmm_shutdown_vm, vmm_write_console,
+};
 
 use shyper::VM_NUM_MAX;
 
 // If succeed, return 0.
 const HVC_FINISH: usize = 0;
 // If failed, return -1.
-// const HVC_ERR: usize = usize::MAX;
+pub const HVC_ERR: usize = usize::MAX;
+// If the hvc type or event is not in this build, return -ENOSYS.
+pub const HVC_ERR_UNSUPPORTED: usize = 38_usize.wrapping_neg();
+
+// version of the hvc interface, packed in the result of HVC_SYS_CAPS
+pub const HVC_VERSION_MAJOR: usize = 1;
+pub const HVC_VERSION_MINOR: usize = 1;
+pub const HVC_VERSION_PATCH: usize = 0;
+
+// feature groups in the result of HVC_SYS_CAPS
+pub const HVC_CAP_CONFIG: usize = 1 << 0;
+pub const HVC_CAP_MEDIATED: usize = 1 << 1;
+pub const HVC_CAP_UNILIB: usize = 1 << 2;
+// dirty log of HVC_VMM_MIGRATE_START/MEMCPY/FINISH
+pub const HVC_CAP_MIGRATION: usize = 1 << 3;
+// HVC_SYS_UPDATE, never set by this hypervisor
+pub const HVC_CAP_LIVE_UPDATE: usize = 1 << 4;
+pub const HVC_CAP_IVC_SHMEM: usize = 1 << 5;
+pub const HVC_CAP_BALLOON: usize = 1 << 6;
+// HVC_VMM_MEM_BW_STAT
+pub const HVC_CAP_MEM_BW: usize = 1 << 7;
+pub const HVC_CAP_WATCHDOG: usize = 1 << 8;
+pub const HVC_CAP_SELF_TEST: usize = 1 << 9;
+
+// the error of an hvc, returned to the guest as HVC_ERR or HVC_ERR_UNSUPPORTED
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HvcError {
+    Failed,
+    Unsupported,
+}
+
+impl HvcError {
+    pub fn code(self) -> usize {
+        match self {
+            HvcError::Failed => HVC_ERR,
+            HvcError::Unsupported => HVC_ERR_UNSUPPORTED,
+        }
+    }
+}
 
 // hvc_fid
 pub const HVC_SYS: usize = 0;
@@ -30,8 +80,30 @@ pub const HVC_SYS_REBOOT: usize = 0;
 pub const HVC_SYS_SHUTDOWN: usize = 1;
 pub const HVC_SYS_UPDATE: usize = 3;
 pub const HVC_SYS_TEST: usize = 4;
+pub const HVC_SYS_EMU_STAT: usize = 5;
+pub const HVC_SYS_MEM_STAT: usize = 6;
+pub const HVC_SYS_IPI_STAT: usize = 7;
+pub const HVC_SYS_IOMMU_FAULT: usize = 8;
+pub const HVC_SYS_SMC_LOG: usize = 9;
+// only sent to VM0, a GVM is stopped by a fault it can not go on from
+pub const HVC_SYS_VM_CRASH: usize = 10;
+pub const HVC_SYS_LOG_LEVEL: usize = 11;
+pub const HVC_SYS_HEAP_TAG_STAT: usize = 12;
+pub const HVC_SYS_WATCHDOG: usize = 13;
+// the packed version of the hvc interface and the HVC_CAP_* groups in this build, see `hvc_caps`
+pub const HVC_SYS_CAPS: usize = 14;
+// the free pages of each color and the last colored allocation that failed, see `ColorFreeInfo`
+pub const HVC_SYS_COLOR_FREE: usize = 15;
+// the queue counts of each `IpiType` on a core, see `IpiTypeStat`
+pub const HVC_SYS_IPI_TYPE_STAT: usize = 16;
+
+// hvc_sys_test sub-commands in x0
+pub const HVC_SYS_TEST_SELF: usize = 1;
+// x1: a `HwTestReport` filled in the background, see `hw_test_start`
+pub const HVC_SYS_TEST_HW: usize = 2;
 
 // hvc_vmm_event
+// x0: ipa of a `VmInfo` array, x1: its length, return the number of VMs written
 pub const HVC_VMM_LIST_VM: usize = 0;
 pub const HVC_VMM_GET_VM_STATE: usize = 1;
 pub const HVC_VMM_BOOT_VM: usize = 2;
@@ -42,16 +114,32 @@ pub const HVC_VMM_GET_VM_CFG: usize = 6;
 pub const HVC_VMM_SET_VM_CFG: usize = 7;
 pub const HVC_VMM_GET_VM_ID: usize = 8;
 pub const HVC_VMM_TRACE_VMEXIT: usize = 9;
-// for src vm: send msg to MVM to ask for migrating
+// for sender: write protect the memory of the src vm and log the dirty pages
 pub const HVC_VMM_MIGRATE_START: usize = 10;
 pub const HVC_VMM_MIGRATE_READY: usize = 11;
-// for sender: copy dirty memory to receiver
+// for sender: fetch and clear the dirty bitmap before copying the dirty memory to receiver
 pub const HVC_VMM_MIGRATE_MEMCPY: usize = 12;
+// for sender: stop logging the dirty pages
 pub const HVC_VMM_MIGRATE_FINISH: usize = 13;
 // for receiver: init new vm but not boot
 pub const HVC_VMM_MIGRATE_INIT_VM: usize = 14;
 pub const HVC_VMM_MIGRATE_VM_BOOT: usize = 15;
 pub const HVC_VMM_VM_REMOVE: usize = 16;
+pub const HVC_VMM_HALT_POLL_STAT: usize = 17;
+pub const HVC_VMM_DUMP_VM: usize = 18;
+pub const HVC_VMM_READ_LOG: usize = 19;
+pub const HVC_VMM_LOG_CONSOLE: usize = 20;
+pub const HVC_VMM_LR_STAT: usize = 21;
+pub const HVC_VMM_MIGRATE_VCPU: usize = 22;
+pub const HVC_VMM_SET_BALLOON: usize = 23;
+pub const HVC_VMM_READ_CONSOLE: usize = 24;
+pub const HVC_VMM_NET_STAT: usize = 25;
+pub const HVC_VMM_WRITE_CONSOLE: usize = 26;
+pub const HVC_VMM_MEM_BW_STAT: usize = 27;
+// from VM0 move a passthrough interrupt between VMs, to the two VMs it has been moved
+pub const HVC_VMM_INT_TRANSFER: usize = 28;
+// the `ExitStat` of each vcpu of a VM, see `vmm_exit_stat`
+pub const HVC_VMM_EXIT_STAT: usize = 29;
 
 // hvc_ivc_event
 pub const HVC_IVC_UPDATE_MQ: usize = 0;
@@ -62,16 +150,27 @@ pub const HVC_IVC_KEEP_ALIVE: usize = 4;
 pub const HVC_IVC_ACK: usize = 5;
 pub const HVC_IVC_GET_TIME: usize = 6;
 pub const HVC_IVC_SHARE_MEM: usize = 7;
+pub const HVC_IVC_SET_TIME: usize = 8;
 pub const HVC_IVC_SEND_SHAREMEM: usize = 0x10;
 //共享内存通信
+// x0: channel id, return the ipa of the channel in the calling VM
 pub const HVC_IVC_GET_SHARED_MEM_IPA: usize = 0x11;
 //用于VM获取共享内存IPA
 pub const HVC_IVC_SEND_SHAREMEM_TEST_SPEED: usize = 0x12; //共享内存通信速度测试
+pub const HVC_IVC_SEND: usize = 0x13; // ring the doorbell of a shared memory channel
+pub const HVC_IVC_CLOSE_SHAREMEM: usize = 0x14;
+pub const HVC_IVC_LIST_SHAREMEM: usize = 0x15;
 
 // hvc_mediated_event
 pub const HVC_MEDIATED_DEV_APPEND: usize = 0x30;
 pub const HVC_MEDIATED_DEV_NOTIFY: usize = 0x31;
 pub const HVC_MEDIATED_DRV_NOTIFY: usize = 0x32;
+pub const HVC_MEDIATED_TASK_STAT: usize = 0x33;
+pub const HVC_MEDIATED_TASK_CANCEL: usize = 0x34;
+// from VM0 the timeout of the requests, to VM0 a request of `vm_id` timed out
+pub const HVC_MEDIATED_TASK_TIMEOUT: usize = 0x35;
+// HVC_MEDIATED_DEV_NOTIFY returning the nreq of the blk, for a backend that polls the requests posted meanwhile
+pub const HVC_MEDIATED_DEV_NOTIFY_POLL: usize = 0x36;
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "unilib")] {
@@ -99,6 +198,20 @@ pub const HVC_CONFIG_PASSTHROUGH_DEVICE_STREAMS_IDS: usize = 7;
 pub const HVC_CONFIG_DTB_DEVICE: usize = 8;
 pub const HVC_CONFIG_UPLOAD_KERNEL_IMAGE: usize = 9;
 pub const HVC_CONFIG_MEMORY_COLOR_BUDGET: usize = 10;
+pub const HVC_CONFIG_HALT_POLL: usize = 11;
+pub const HVC_CONFIG_MEMORY_COLOR_STRICT: usize = 12;
+pub const HVC_CONFIG_CACHE_COLOR_INFO: usize = 13;
+pub const HVC_CONFIG_UPLOAD_RAMDISK_IMAGE: usize = 14;
+pub const HVC_CONFIG_MEMORY_MAX: usize = 15;
+pub const HVC_CONFIG_CPU_SCHED: usize = 16;
+pub const HVC_CONFIG_SMC_POLICY: usize = 17;
+pub const HVC_CONFIG_NET_MAC: usize = 18;
+pub const HVC_CONFIG_HOTPLUG_CONSOLE: usize = 19;
+pub const HVC_CONFIG_UNPLUG_CONSOLE: usize = 20;
+pub const HVC_CONFIG_UPLOAD_STATUS: usize = 21;
+pub const HVC_CONFIG_PASSTHROUGH_DEVICE_REGION_REMOVE: usize = 22;
+pub const HVC_CONFIG_MEMORY_EAGER_MAP: usize = 23;
+pub const HVC_CONFIG_CPU_SPIN_TABLE: usize = 24;
 
 #[cfg(feature = "tx2")]
 pub const HVC_IRQ: usize = 32 + 0x20;
@@ -108,25 +221,32 @@ pub const HVC_IRQ: usize = 32 + 0x10;
 pub const HVC_IRQ: usize = 32 + 0x20;
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub enum HvcGuestMsg {
-    Default(HvcDefaultMsg),
     Manage(HvcManageMsg),
+    Mediated(HvcMediatedMsg),
     Migrate(HvcMigrateMsg),
+    IntTransfer(HvcIntTransferMsg),
+    IvcShare(HvcIvcShareMsg),
     #[cfg(feature = "unilib")]
     UniLib(HvcUniLibMsg),
 }
 
 #[repr(C)]
-pub struct HvcDefaultMsg {
+#[derive(Clone, Copy)]
+pub struct HvcManageMsg {
     pub fid: usize,
     pub event: usize,
+    pub vm_id: usize,
 }
 
+// a request posted to the MediatedBlkContent of mediated blk `blk_id`
 #[repr(C)]
-pub struct HvcManageMsg {
+#[derive(Clone, Copy)]
+pub struct HvcMediatedMsg {
     pub fid: usize,
     pub event: usize,
-    pub vm_id: usize,
+    pub blk_id: usize,
 }
 
 pub const MIGRATE_START: usize = 0;
@@ -134,6 +254,7 @@ pub const MIGRATE_COPY: usize = 1;
 pub const MIGRATE_FINISH: usize = 2;
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct HvcMigrateMsg {
     pub fid: usize,
     pub event: usize,
@@ -142,8 +263,33 @@ pub struct HvcMigrateMsg {
     pub page_num: usize, // bitmap page num
 }
 
+// the passthrough interrupt `int_id` has been moved from `src_vm_id` to `dst_vm_id`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct HvcIntTransferMsg {
+    pub fid: usize,
+    pub event: usize,
+    pub src_vm_id: usize,
+    pub dst_vm_id: usize,
+    pub int_id: usize,
+}
+
+// to the peer of a new shared memory channel, where the `page_num` pages of channel `id` are mapped
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct HvcIvcShareMsg {
+    pub fid: usize,
+    pub event: usize,
+    pub id: usize,
+    pub peer_vm_id: usize,
+    pub ipa: usize,
+    pub page_num: usize,
+    pub irq: usize,
+}
+
 #[cfg(feature = "unilib")]
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct HvcUniLibMsg {
     pub fid: usize,
     pub event: usize,
@@ -163,18 +309,19 @@ pub fn hvc_guest_handler(
     x4: usize,
     x5: usize,
     x6: usize,
-) -> Result<usize, ()> {
+) -> Result<usize, HvcError> {
+    event_trace(TraceEvent::Hvc, hvc_type, event);
     match hvc_type {
-        HVC_SYS => hvc_sys_handler(event, x0),
+        HVC_SYS => hvc_sys_handler(event, x0, x1),
         HVC_VMM => hvc_vmm_handler(event, x0, x1),
-        HVC_IVC => hvc_ivc_handler(event, x0, x1),
+        HVC_IVC => hvc_ivc_handler(event, x0, x1, x2, x3, x4),
         HVC_MEDIATED => hvc_mediated_handler(event, x0, x1),
         HVC_CONFIG => hvc_config_handler(event, x0, x1, x2, x3, x4, x5, x6),
         #[cfg(feature = "unilib")]
         HVC_UNILIB => hvc_unilib_handler(event, x0, x1, x2),
         _ => {
             println!("hvc_guest_handler: unknown hvc type {} event {}", hvc_type, event);
-            Err(())
+            Err(HvcError::Unsupported)
         }
     }
 }
@@ -188,9 +335,10 @@ fn hvc_config_handler(
     x4: usize,
     x5: usize,
     x6: usize,
-) -> Result<usize, ()> {
+) -> Result<usize, HvcError> {
     use crate::config;
-    match event {
+    let _tag = HeapTagGuard::new(HeapTag::Config);
+    let result = match event {
         HVC_CONFIG_ADD_VM => config::add_vm(x0),
         HVC_CONFIG_DELETE_VM => config::del_vm(x0),
         HVC_CONFIG_CPU => config::set_cpu(x0, x1, x2, x3),
@@ -202,72 +350,201 @@ fn hvc_config_handler(
         HVC_CONFIG_DTB_DEVICE => config::add_dtb_dev(x0, x1, x2, x3, x4, x5, x6),
         HVC_CONFIG_UPLOAD_KERNEL_IMAGE => config::upload_kernel_image(x0, x1, x2, x3, x4),
         HVC_CONFIG_MEMORY_COLOR_BUDGET => config::set_memory_color_budget(x0, x1, x2, x3),
+        HVC_CONFIG_HALT_POLL => config::set_halt_poll(x0, x1),
+        HVC_CONFIG_MEMORY_COLOR_STRICT => config::set_memory_color_strict(x0, x1),
+        HVC_CONFIG_CACHE_COLOR_INFO => mem_color_info(x0),
+        HVC_CONFIG_UPLOAD_RAMDISK_IMAGE => config::upload_ramdisk_image(x0, x1, x2, x3),
+        HVC_CONFIG_MEMORY_MAX => config::set_memory_max(x0, x1),
+        HVC_CONFIG_CPU_SCHED => config::set_cpu_sched(x0, x1, x2, x3),
+        HVC_CONFIG_SMC_POLICY => config::set_smc_policy(x0, x1, x2, x3),
+        // the MAC of virtio net x1 of VM x0, a new random one if x2 is not 0
+        HVC_CONFIG_NET_MAC => config::net_mac(x0, x1, x2 != 0),
+        // a console at ipa x1 with irq x2 for VM x0, connected to the console at ipa x4 of VM x3
+        HVC_CONFIG_HOTPLUG_CONSOLE => config::hotplug_console(x0, x1, x2, x3, x4),
+        HVC_CONFIG_UNPLUG_CONSOLE => config::unplug_console(x0, x1),
+        HVC_CONFIG_UPLOAD_STATUS => config::upload_status(x0),
+        HVC_CONFIG_PASSTHROUGH_DEVICE_REGION_REMOVE => config::remove_passthrough_device_region(x0, x1),
+        // map the memory of VM x0 before boot if x1 != 0, by default it is mapped on the first access
+        HVC_CONFIG_MEMORY_EAGER_MAP => config::set_memory_eager_map(x0, x1),
+        // the secondary vcpus of VM x0 boot by the spin-table with the release page at x1, by PSCI if 0
+        HVC_CONFIG_CPU_SPIN_TABLE => config::set_cpu_spin_table(x0, x1),
         _ => {
             println!("hvc_config_handler unknown event {}", event);
-            Err(())
+            return Err(HvcError::Unsupported);
         }
-    }
+    };
+    result.map_err(|_| HvcError::Failed)
 }
 
-fn hvc_sys_handler(event: usize, _x0: usize) -> Result<usize, ()> {
-    match event {
+fn hvc_sys_handler(event: usize, x0: usize, x1: usize) -> Result<usize, HvcError> {
+    let result = match event {
         HVC_SYS_UPDATE => {
-            todo!()
+            // live update is not supported by this hypervisor, refuse it and keep running
+            warn!("hvc_sys_handler: live update is not supported");
+            return Err(HvcError::Unsupported);
         }
-        HVC_SYS_TEST => {
-            let vm = active_vm().unwrap();
-            crate::device::virtio_net_announce(vm);
+        HVC_SYS_CAPS => Ok(hvc_caps()),
+        HVC_SYS_TEST => hvc_sys_test(x0, x1),
+        // dump trap counts of emulated devices, reset them if x0 != 0
+        HVC_SYS_EMU_STAT => {
+            vm_list_walker(|vm| vm.emu_dev_stat_dump(x0 != 0));
             Ok(0)
         }
-        _ => Err(()),
+        // copy the hypervisor heap usage to x0
+        HVC_SYS_MEM_STAT => mem_heap_stat(x0),
+        // copy the pending and dropped ipi counts of each core to x0, return the core number
+        HVC_SYS_IPI_STAT => ipi_stat(x0),
+        // copy the ipi counts of each ipi type on core x1 to x0, return the type number
+        HVC_SYS_IPI_TYPE_STAT => ipi_type_stat(x0, x1),
+        // move the iommu faults of VM x1 to x0, return the number of faults
+        HVC_SYS_IOMMU_FAULT => iommu_fault_read(x0, x1),
+        // move the unknown smc calls logged for VM x1 to x0, return the number of calls
+        HVC_SYS_SMC_LOG => crate::arch::smc_log_read(x0, x1),
+        // copy the free pages of each color and the colored allocation of VM x1 that failed to x0,
+        // return the number of colors
+        HVC_SYS_COLOR_FREE => mem_color_free_info(x0, x1),
+        // copy the heap usage of each `HeapTag` to x0, return the tag number
+        HVC_SYS_HEAP_TAG_STAT => mem_heap_tag_stat(x0),
+        // set the log level of subsystem x0 (`LogModule`) to x1, 0 (off) to 5 (trace), return the old one
+        HVC_SYS_LOG_LEVEL => hvc_log_level(x0, x1),
+        // report a core after x0 missed watchdog periods (0 off), crash its GVM after x1 (0 never)
+        #[cfg(feature = "watchdog")]
+        HVC_SYS_WATCHDOG => watchdog_set(x0, x1),
+        _ => {
+            println!("hvc_sys_handler unknown event {}", event);
+            return Err(HvcError::Unsupported);
+        }
+    };
+    result.map_err(|_| HvcError::Failed)
+}
+
+// x0 == HVC_SYS_TEST_SELF reruns the boot self-test, HVC_SYS_TEST_HW tests the ipi, timer
+// and vgic of the cores, any other x0 announces the virtio nics
+fn hvc_sys_test(x0: usize, x1: usize) -> Result<usize, ()> {
+    if x0 == HVC_SYS_TEST_HW {
+        return hw_test_start(x1);
+    }
+    let vm = active_vm().unwrap();
+    if x0 == HVC_SYS_TEST_SELF {
+        #[cfg(feature = "self-test")]
+        if vm.id() == 0 {
+            return if crate::kernel::self_test() { Ok(0) } else { Err(()) };
+        }
+        error!("hvc_sys_handler: VM {} can not run the self-test", vm.id());
+        return Err(());
     }
+    crate::device::virtio_net_announce(vm);
+    Ok(0)
 }
 
-fn hvc_vmm_handler(event: usize, x0: usize, _x1: usize) -> Result<usize, ()> {
-    match event {
-        HVC_VMM_LIST_VM => vmm_list_vm(x0),
-        HVC_VMM_GET_VM_STATE => {
-            error!("unimplemented");
-            Err(())
+/* HVC_SYS_CAPS: the version of the hvc interface in bits [63:40], major, minor and patch of 8 bits
+ * from the top, and the HVC_CAP_* groups of the features this hypervisor is built with in [31:0].
+ * A tool checks the major version and the groups it needs, an event missing from this build
+ * returns HVC_ERR_UNSUPPORTED instead of HVC_ERR.
+ */
+pub fn hvc_caps() -> usize {
+    let mut caps = HVC_CAP_CONFIG | HVC_CAP_MEDIATED | HVC_CAP_MIGRATION | HVC_CAP_IVC_SHMEM;
+    if cfg!(feature = "unilib") {
+        caps |= HVC_CAP_UNILIB;
+    }
+    if cfg!(feature = "balloon") {
+        caps |= HVC_CAP_BALLOON;
+    }
+    if cfg!(feature = "memory-reservation") {
+        caps |= HVC_CAP_MEM_BW;
+    }
+    if cfg!(feature = "watchdog") {
+        caps |= HVC_CAP_WATCHDOG;
+    }
+    if cfg!(feature = "self-test") {
+        caps |= HVC_CAP_SELF_TEST;
+    }
+    HVC_VERSION_MAJOR << 56 | HVC_VERSION_MINOR << 48 | HVC_VERSION_PATCH << 40 | caps
+}
+
+fn hvc_log_level(module: usize, level: usize) -> Result<usize, ()> {
+    let log_module = match LogModule::from_usize(module) {
+        Some(log_module) => log_module,
+        None => {
+            error!("hvc_log_level: unknown log module {}", module);
+            return Err(());
         }
-        HVC_VMM_BOOT_VM => {
-            vmm_boot_vm(x0);
-            Ok(HVC_FINISH)
+    };
+    match log_level_set(log_module, level) {
+        Some(old) => {
+            info!(
+                "hvc_log_level: {:?} log level {} => {}",
+                log_module,
+                old,
+                log_module.level()
+            );
+            Ok(old as usize)
         }
-        HVC_VMM_SHUTDOWN_VM => {
-            error!("unimplemented");
+        None => {
+            error!("hvc_log_level: illegal log level {}", level);
             Err(())
         }
-        HVC_VMM_REBOOT_VM => {
-            vmm_reboot_vm(x0);
-            Ok(HVC_FINISH)
-        }
+    }
+}
+
+fn hvc_vmm_handler(event: usize, x0: usize, x1: usize) -> Result<usize, HvcError> {
+    let result = match event {
+        HVC_VMM_LIST_VM => vmm_list_vm(x0, x1),
+        // the packed `VmStateSnapshot` of VM x0
+        HVC_VMM_GET_VM_STATE => match vm_if_state_snapshot(x0) {
+            Some(snapshot) => Ok(snapshot.pack()),
+            None => {
+                error!("hvc_vmm_handler: VM[{}] does not exist", x0);
+                Err(())
+            }
+        },
+        HVC_VMM_BOOT_VM => vmm_boot_vm(x0),
+        HVC_VMM_SHUTDOWN_VM => vmm_shutdown_vm(x0),
+        HVC_VMM_REBOOT_VM => vmm_reboot_vm(x0),
         HVC_VMM_GET_VM_ID => {
-            get_vm_id(x0);
-            Ok(HVC_FINISH)
-        }
-        HVC_VMM_MIGRATE_START
-        | HVC_VMM_MIGRATE_READY
-        | HVC_VMM_MIGRATE_MEMCPY
-        | HVC_VMM_MIGRATE_INIT_VM
-        | HVC_VMM_MIGRATE_VM_BOOT
-        | HVC_VMM_MIGRATE_FINISH => {
-            error!("unimplemented");
-            Ok(HVC_FINISH)
+            if get_vm_id(x0) {
+                Ok(HVC_FINISH)
+            } else {
+                Err(())
+            }
         }
-        HVC_VMM_VM_REMOVE => {
-            vmm_remove_vm(x0);
-            Ok(HVC_FINISH)
+        // the dirty log of VM x0, fetched into the buffer at x1 on every memcpy round
+        HVC_VMM_MIGRATE_START => vmm_dirty_log_start(x0),
+        HVC_VMM_MIGRATE_MEMCPY => vmm_dirty_log_fetch(x0, x1),
+        HVC_VMM_MIGRATE_FINISH => vmm_dirty_log_stop(x0),
+        // the rest of a migration is not in this hypervisor, a tool must not take it as done
+        HVC_VMM_MIGRATE_READY | HVC_VMM_MIGRATE_INIT_VM | HVC_VMM_MIGRATE_VM_BOOT => {
+            warn!("hvc_vmm_handler: migration event {} is not supported", event);
+            return Err(HvcError::Unsupported);
         }
+        HVC_VMM_VM_REMOVE => vmm_remove_vm(x0),
+        HVC_VMM_HALT_POLL_STAT => vmm_halt_poll_stat(x0, x1),
+        HVC_VMM_DUMP_VM => vmm_dump_vm(x0, x1),
+        HVC_VMM_READ_LOG => vmm_read_log(x0, x1),
+        HVC_VMM_LOG_CONSOLE => vmm_log_console(x0, x1 != 0),
+        HVC_VMM_LR_STAT => vmm_lr_stat(x0, x1),
+        HVC_VMM_MIGRATE_VCPU => vmm_migrate_vcpu(x0, x1),
+        // the balloon of VM x0 is asked to hold x1 pages
+        #[cfg(feature = "balloon")]
+        HVC_VMM_SET_BALLOON => crate::vmm::vmm_set_balloon(x0, x1),
+        HVC_VMM_READ_CONSOLE => vmm_read_console(x0, x1),
+        HVC_VMM_NET_STAT => vmm_net_stat(x0, x1),
+        HVC_VMM_WRITE_CONSOLE => vmm_write_console(x0, x1),
+        // the memory bandwidth used by a VM in its last periods
+        #[cfg(feature = "memory-reservation")]
+        HVC_VMM_MEM_BW_STAT => crate::vmm::vmm_mem_bw_stat(x0, x1),
+        HVC_VMM_INT_TRANSFER => vmm_int_transfer(x0, x1),
+        HVC_VMM_EXIT_STAT => vmm_exit_stat(x0, x1),
         _ => {
             println!("hvc_vmm unknown event {}", event);
-            Err(())
+            return Err(HvcError::Unsupported);
         }
-    }
+    };
+    result.map_err(|_| HvcError::Failed)
 }
 
-fn hvc_ivc_handler(event: usize, x0: usize, x1: usize) -> Result<usize, ()> {
-    match event {
+fn hvc_ivc_handler(event: usize, x0: usize, x1: usize, x2: usize, x3: usize, x4: usize) -> Result<usize, HvcError> {
+    let result = match event {
         HVC_IVC_UPDATE_MQ => {
             if ivc_update_mq(x0, x1) {
                 Ok(HVC_FINISH)
@@ -275,32 +552,42 @@ fn hvc_ivc_handler(event: usize, x0: usize, x1: usize) -> Result<usize, ()> {
                 Err(())
             }
         }
-        HVC_IVC_SHARE_MEM => {
-            error!("not support vm migration and live update");
-            Ok(HVC_FINISH)
-        }
+        HVC_IVC_ACK => hvc_ivc_ack(x0),
+        HVC_IVC_GET_TIME => super::pvclock::hvc_get_time(),
+        HVC_IVC_SET_TIME => super::pvclock::hvc_set_time(x0),
+        HVC_IVC_SHARE_MEM => ivc_share_mem(x0, x1, x2, x3, x4),
+        HVC_IVC_GET_SHARED_MEM_IPA => ivc_share_mem_ipa(x0),
+        HVC_IVC_SEND => ivc_send_doorbell(x0),
+        HVC_IVC_CLOSE_SHAREMEM => ivc_close_share_mem(x0),
+        HVC_IVC_LIST_SHAREMEM => ivc_list_share_mem(x0, x1),
         _ => {
             error!("hvc_ivc_handler: unknown event {}", event);
-            Err(())
+            return Err(HvcError::Unsupported);
         }
-    }
+    };
+    result.map_err(|_| HvcError::Failed)
 }
 
-fn hvc_mediated_handler(event: usize, x0: usize, x1: usize) -> Result<usize, ()> {
-    match event {
+fn hvc_mediated_handler(event: usize, x0: usize, x1: usize) -> Result<usize, HvcError> {
+    let result = match event {
         HVC_MEDIATED_DEV_APPEND => mediated_dev_append(x0, x1),
-        HVC_MEDIATED_DEV_NOTIFY => mediated_blk_notify_handler(x0),
+        HVC_MEDIATED_DEV_NOTIFY => mediated_blk_notify_handler(x0, false),
+        HVC_MEDIATED_DEV_NOTIFY_POLL => mediated_blk_notify_handler(x0, true),
+        HVC_MEDIATED_TASK_STAT => async_task_stat(x0, x1),
+        HVC_MEDIATED_TASK_CANCEL => async_task_cancel(x0),
+        HVC_MEDIATED_TASK_TIMEOUT => async_task_set_timeout(x0),
         _ => {
             println!("unknown mediated event {}", event);
-            Err(())
+            return Err(HvcError::Unsupported);
         }
-    }
+    };
+    result.map_err(|_| HvcError::Failed)
 }
 
 #[cfg(feature = "unilib")]
-fn hvc_unilib_handler(event: usize, x0: usize, x1: usize, x2: usize) -> Result<usize, ()> {
+fn hvc_unilib_handler(event: usize, x0: usize, x1: usize, x2: usize) -> Result<usize, HvcError> {
     use crate::util::unilib::*;
-    match event {
+    let result = match event {
         HVC_UNILIB_FS_INIT => unilib_fs_init(),
         HVC_UNILIB_FS_OPEN => unilib_fs_open(x0, x1, x2),
         HVC_UNILIB_FS_CLOSE => unilib_fs_close(x0),
@@ -311,31 +598,65 @@ fn hvc_unilib_handler(event: usize, x0: usize, x1: usize, x2: usize) -> Result<u
         HVC_UNILIB_FS_APPEND => unilib_fs_append(x0),
         HVC_UNILIB_FS_FINISHED => unilib_fs_finished(x0),
         _ => {
-            println!("unknown mediated event {}", event);
-            Err(())
+            println!("unknown unilib event {}", event);
+            return Err(HvcError::Unsupported);
+        }
+    };
+    result.map_err(|_| HvcError::Failed)
+}
+
+// Upper bound of the messages waiting for a full mailbox
+const HVC_MSG_QUEUE_MAX: usize = 32;
+// Number of message slots in the mailbox page
+const HVC_MSG_SLOT_NUM: usize = PAGE_SIZE / (PAGE_SIZE / VM_NUM_MAX);
+
+/// Flow control of the ivc mailbox of a VM.
+/// Once the guest acks the messages it has consumed (HVC_IVC_ACK), the mailbox slots which
+/// are not consumed yet are no longer overwritten, new messages wait in `pending` instead.
+pub struct HvcMsgQueue {
+    // messages written to the mailbox
+    sent: usize,
+    // messages consumed by the guest, None if the guest never acks
+    acked: Option<usize>,
+    pending: VecDeque<HvcGuestMsg>,
+}
+
+impl HvcMsgQueue {
+    pub const fn new() -> Self {
+        Self {
+            sent: 0,
+            acked: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn mailbox_full(&self) -> bool {
+        match self.acked {
+            Some(acked) => self.sent - acked >= HVC_MSG_SLOT_NUM,
+            None => false,
         }
     }
 }
 
-pub fn hvc_send_msg_to_vm(vm_id: usize, guest_msg: &HvcGuestMsg) -> bool {
+// copy a message to the next slot of the mailbox, return its (fid, event)
+fn hvc_mailbox_write(vm_if: &mut VmInterface, guest_msg: &HvcGuestMsg) -> Option<(usize, usize)> {
     let mut target_addr = 0;
-    let mut arg_ptr_addr = vm_if_ivc_arg_ptr(vm_id);
-    let arg_addr = vm_if_ivc_arg(vm_id);
+    let mut arg_ptr_addr = vm_if.ivc_arg_ptr;
+    let arg_addr = vm_if.ivc_arg;
 
     if arg_ptr_addr != 0 {
         arg_ptr_addr += PAGE_SIZE / VM_NUM_MAX;
         if arg_ptr_addr - arg_addr >= PAGE_SIZE {
-            vm_if_set_ivc_arg_ptr(vm_id, arg_addr);
+            vm_if.ivc_arg_ptr = arg_addr;
             target_addr = arg_addr;
         } else {
-            vm_if_set_ivc_arg_ptr(vm_id, arg_ptr_addr);
+            vm_if.ivc_arg_ptr = arg_ptr_addr;
             target_addr = arg_ptr_addr;
         }
     }
 
     if target_addr == 0 {
-        println!("hvc_send_msg_to_vm: target VM{} interface is not prepared", vm_id);
-        return false;
+        return None;
     }
 
     if target_addr < 0x1000 || (guest_msg as *const _ as usize) < 0x1000 {
@@ -345,27 +666,43 @@ pub fn hvc_send_msg_to_vm(vm_id: usize, guest_msg: &HvcGuestMsg) -> bool {
         );
     }
     let (fid, event) = match guest_msg {
-        HvcGuestMsg::Default(msg) => {
+        HvcGuestMsg::Migrate(msg) => {
             memcpy_safe(
                 target_addr as *const u8,
                 msg as *const _ as *const u8,
-                size_of::<HvcDefaultMsg>(),
+                size_of::<HvcMigrateMsg>(),
             );
             (msg.fid, msg.event)
         }
-        HvcGuestMsg::Migrate(msg) => {
+        HvcGuestMsg::Manage(msg) => {
             memcpy_safe(
                 target_addr as *const u8,
                 msg as *const _ as *const u8,
-                size_of::<HvcMigrateMsg>(),
+                size_of::<HvcManageMsg>(),
             );
             (msg.fid, msg.event)
         }
-        HvcGuestMsg::Manage(msg) => {
+        HvcGuestMsg::Mediated(msg) => {
             memcpy_safe(
                 target_addr as *const u8,
                 msg as *const _ as *const u8,
-                size_of::<HvcManageMsg>(),
+                size_of::<HvcMediatedMsg>(),
+            );
+            (msg.fid, msg.event)
+        }
+        HvcGuestMsg::IntTransfer(msg) => {
+            memcpy_safe(
+                target_addr as *const u8,
+                msg as *const _ as *const u8,
+                size_of::<HvcIntTransferMsg>(),
+            );
+            (msg.fid, msg.event)
+        }
+        HvcGuestMsg::IvcShare(msg) => {
+            memcpy_safe(
+                target_addr as *const u8,
+                msg as *const _ as *const u8,
+                size_of::<HvcIvcShareMsg>(),
             );
             (msg.fid, msg.event)
         }
@@ -380,6 +717,11 @@ pub fn hvc_send_msg_to_vm(vm_id: usize, guest_msg: &HvcGuestMsg) -> bool {
         }
     };
 
+    vm_if.ivc_msg.sent += 1;
+    Some((fid, event))
+}
+
+fn hvc_notify_vm(vm_id: usize, fid: usize, event: usize) {
     let cpu_trgt = vm_if_get_cpu_id(vm_id).unwrap();
     if cpu_trgt != current_cpu().id {
         // println!("cpu {} send hvc msg to cpu {}", current_cpu().id, cpu_trgt);
@@ -389,18 +731,91 @@ pub fn hvc_send_msg_to_vm(vm_id: usize, guest_msg: &HvcGuestMsg) -> bool {
             fid,
             event,
         };
-        if !ipi_send_msg(cpu_trgt, IpiType::Hvc, IpiInnerMsg::HvcMsg(ipi_msg)) {
+        if let Err(err) = ipi_send_msg_retry(cpu_trgt, IpiType::Hvc, IpiInnerMsg::HvcMsg(ipi_msg)) {
             error!(
-                "hvc_send_msg_to_vm: Failed to send ipi message, target {} type {:#?}",
+                "hvc_send_msg_to_vm: Failed to send ipi message, target {} type {:#?}: {:?}",
                 cpu_trgt,
-                IpiType::Hvc
+                IpiType::Hvc,
+                err
             );
         }
     } else {
         hvc_guest_notify(vm_id);
     }
+}
 
-    true
+/* Send a message to the ivc mailbox of a VM and notify it.
+ * If the VM acks its messages and the mailbox is full, the message is queued and sent on the next ack.
+ *
+ * @param[in] vm_id: target VM id.
+ * @param[in] guest_msg: message to send.
+ * @return false if the mailbox is not prepared or the queue overflows.
+ */
+pub fn hvc_send_msg_to_vm(vm_id: usize, guest_msg: &HvcGuestMsg) -> bool {
+    let sent = vm_if_ivc_access(vm_id, |vm_if| {
+        let queue = &mut vm_if.ivc_msg;
+        if queue.mailbox_full() || !queue.pending.is_empty() {
+            if queue.pending.len() >= HVC_MSG_QUEUE_MAX {
+                error!("hvc_send_msg_to_vm: VM{} message queue overflow", vm_id);
+                return Err(());
+            }
+            queue.pending.push_back(*guest_msg);
+            return Ok(None);
+        }
+        match hvc_mailbox_write(vm_if, guest_msg) {
+            Some(fid_event) => Ok(Some(fid_event)),
+            None => {
+                println!("hvc_send_msg_to_vm: target VM{} interface is not prepared", vm_id);
+                Err(())
+            }
+        }
+    });
+    match sent {
+        Some(Ok(Some((fid, event)))) => {
+            hvc_notify_vm(vm_id, fid, event);
+            true
+        }
+        Some(Ok(None)) => true,
+        _ => false,
+    }
+}
+
+/* The guest has consumed `consumed` messages of its mailbox in total,
+ * move the queued messages to the free slots and notify it again.
+ */
+fn hvc_ivc_ack(consumed: usize) -> Result<usize, ()> {
+    let vm_id = active_vm().unwrap().id();
+    let last = vm_if_ivc_access(vm_id, |vm_if| {
+        let queue = &mut vm_if.ivc_msg;
+        if consumed > queue.sent || consumed < queue.acked.unwrap_or(0) {
+            error!(
+                "hvc_ivc_ack: VM{} illegal consumed index {}, sent {}",
+                vm_id, consumed, queue.sent
+            );
+            return Err(());
+        }
+        queue.acked = Some(consumed);
+        let mut last = None;
+        while !vm_if.ivc_msg.mailbox_full() {
+            match vm_if.ivc_msg.pending.pop_front() {
+                Some(msg) => match hvc_mailbox_write(vm_if, &msg) {
+                    Some(fid_event) => last = Some(fid_event),
+                    None => return Err(()),
+                },
+                None => break,
+            }
+        }
+        Ok(last)
+    });
+    match last {
+        Some(Ok(last)) => {
+            if let Some((fid, event)) = last {
+                hvc_notify_vm(vm_id, fid, event);
+            }
+            Ok(HVC_FINISH)
+        }
+        _ => Err(()),
+    }
 }
 
 // notify current cpu's vcpu
@@ -0,0 +1,308 @@
+use alloc::sync::Arc;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::arch::GIC_SGIS_NUM;
+use crate::board::static_config::CORE_NUM;
+use crate::board::PLAT_DESC;
+use crate::kernel::access::vm_ipa2hva;
+use crate::kernel::{
+    active_vm, current_cpu, interrupt_vm_inject, ipi_send_msg, IpiInnerMsg, IpiMessage, IpiType, Vcpu,
+};
+use crate::util::timer_list::{TimerEvent, TimerValue};
+
+use super::timer::{now, start_timer_event, timer_enable, timer_notify_after};
+
+// the one-shot timer programmed on each core
+const HW_TEST_TIMER_PERIOD: TimerValue = TimerValue::from_millis(1);
+// how late the one-shot timer may fire
+const HW_TEST_TIMER_TOLERANCE: TimerValue = TimerValue::from_micros(200);
+// the longest ipi round trip that passes
+const HW_TEST_IPI_RTT_MAX: TimerValue = TimerValue::from_micros(500);
+// the report is written by then, whatever did not come back has failed
+const HW_TEST_DEADLINE: TimerValue = TimerValue::from_millis(100);
+
+/* The report of HVC_SYS_TEST_HW in the buffer of VM0, followed by a `HwTestCore` for each core.
+ * The hvc only starts the test, the report is written when it is over and `done` is set last.
+ */
+#[repr(C)]
+pub struct HwTestReport {
+    // set by the caller: the SGI injected into the calling vcpu
+    pub sgi_id: u32,
+    pub done: u32,
+    pub core_num: u32,
+    // the core of the calling vcpu, the one the vgic test runs on
+    pub vgic_core: u32,
+    // bit n set: core n passed
+    pub ipi_pass: u64,
+    pub timer_pass: u64,
+    pub vgic_pass: u64,
+    // the worst cases over the cores in ns
+    pub ipi_rtt_max: u64,
+    pub timer_late_max: u64,
+    pub vgic_eoi_ns: u64,
+}
+
+// u64::MAX if it never came back
+#[repr(C)]
+pub struct HwTestCore {
+    pub ipi_rtt_ns: u64,
+    pub timer_late_ns: u64,
+}
+
+#[derive(Copy, Clone)]
+pub enum HwTestEvent {
+    Ping,
+    Pong,
+}
+
+#[derive(Clone)]
+pub struct IpiHwTestMsg {
+    pub event: HwTestEvent,
+    // the core running the test
+    pub src: usize,
+}
+
+struct HwTestRun {
+    report_hva: usize,
+    src: usize,
+    start: TimerValue,
+    ipi_rtt: [Option<TimerValue>; CORE_NUM],
+    timer_late: [Option<TimerValue>; CORE_NUM],
+    vgic_eoi: Option<TimerValue>,
+}
+
+static HW_TEST_RUN: Mutex<Option<HwTestRun>> = Mutex::new(None);
+
+// the SGI waited for: 1 << 32 | vm_id << 16 | vcpu_id << 4 | sgi, 0 if none
+static HW_TEST_SGI: AtomicUsize = AtomicUsize::new(0);
+
+fn hw_test_sgi_key(vcpu: &Vcpu, int_id: usize) -> usize {
+    1 << 32 | vcpu.vm_id() << 16 | vcpu.id() << 4 | int_id
+}
+
+// the test SGI wants an EOI maintenance interrupt when it is written to a list register
+pub fn hw_test_sgi_armed(vcpu: &Vcpu, int_id: usize) -> bool {
+    let armed = HW_TEST_SGI.load(Ordering::Relaxed);
+    armed != 0 && armed == hw_test_sgi_key(vcpu, int_id)
+}
+
+// called on the EOI maintenance interrupt of an SGI
+pub fn hw_test_sgi_eoi(vcpu: &Vcpu, int_id: usize) {
+    let key = hw_test_sgi_key(vcpu, int_id);
+    if HW_TEST_SGI
+        .compare_exchange(key, 0, Ordering::Relaxed, Ordering::Relaxed)
+        .is_err()
+    {
+        return;
+    }
+    if let Some(run) = HW_TEST_RUN.lock().as_mut() {
+        run.vgic_eoi = Some(now() - run.start);
+    }
+}
+
+// the hypervisor timer irq is only on while the vcpus of a core need the tick
+fn hw_test_timer_restore() {
+    if !current_cpu().vcpu_array.timer_on() {
+        timer_enable(false);
+    }
+}
+
+struct HwTestTimer {
+    expected: TimerValue,
+    src: usize,
+}
+
+impl TimerEvent for HwTestTimer {
+    fn callback(self: Arc<Self>, now: TimerValue) {
+        let cpu_id = current_cpu().id;
+        if let Some(run) = HW_TEST_RUN.lock().as_mut() {
+            run.timer_late[cpu_id] = Some(now.saturating_sub(self.expected));
+        }
+        // the deadline of the test is still to come on the core running it
+        if cpu_id != self.src {
+            hw_test_timer_restore();
+        }
+    }
+}
+
+struct HwTestDeadline;
+
+impl TimerEvent for HwTestDeadline {
+    fn callback(self: Arc<Self>, _now: TimerValue) {
+        HW_TEST_SGI.store(0, Ordering::Relaxed);
+        if let Some(run) = HW_TEST_RUN.lock().take() {
+            hw_test_report(&run);
+        }
+        hw_test_timer_restore();
+    }
+}
+
+fn as_ns(val: Option<TimerValue>) -> u64 {
+    val.map_or(u64::MAX, |val| u64::try_from(val.as_nanos()).unwrap_or(u64::MAX))
+}
+
+fn hw_test_report(run: &HwTestRun) {
+    let num = PLAT_DESC.cpu_desc.num;
+    let report = unsafe { &mut *(run.report_hva as *mut HwTestReport) };
+    let cores = unsafe {
+        core::slice::from_raw_parts_mut((run.report_hva + size_of::<HwTestReport>()) as *mut HwTestCore, num)
+    };
+    let mut ipi_pass = 0;
+    let mut timer_pass = 0;
+    let mut ipi_rtt_max = TimerValue::ZERO;
+    let mut timer_late_max = TimerValue::ZERO;
+    for (cpu_id, core) in cores.iter_mut().enumerate() {
+        match run.ipi_rtt[cpu_id] {
+            Some(rtt) if rtt <= HW_TEST_IPI_RTT_MAX => ipi_pass |= 1 << cpu_id,
+            rtt => error!("hw_test: Core {} ipi failed, round trip {:?}", cpu_id, rtt),
+        }
+        match run.timer_late[cpu_id] {
+            Some(late) if late <= HW_TEST_TIMER_TOLERANCE => timer_pass |= 1 << cpu_id,
+            late => error!("hw_test: Core {} timer failed, late {:?}", cpu_id, late),
+        }
+        ipi_rtt_max = ipi_rtt_max.max(run.ipi_rtt[cpu_id].unwrap_or(HW_TEST_DEADLINE));
+        timer_late_max = timer_late_max.max(run.timer_late[cpu_id].unwrap_or(HW_TEST_DEADLINE));
+        core.ipi_rtt_ns = as_ns(run.ipi_rtt[cpu_id]);
+        core.timer_late_ns = as_ns(run.timer_late[cpu_id]);
+    }
+    let vgic_pass = match run.vgic_eoi {
+        Some(_) => 1 << run.src,
+        None => {
+            error!("hw_test: Core {} vgic failed, SGI {} not EOIed", run.src, report.sgi_id);
+            0
+        }
+    };
+    report.core_num = num as u32;
+    report.vgic_core = run.src as u32;
+    report.ipi_pass = ipi_pass;
+    report.timer_pass = timer_pass;
+    report.vgic_pass = vgic_pass;
+    report.ipi_rtt_max = as_ns(Some(ipi_rtt_max));
+    report.timer_late_max = as_ns(Some(timer_late_max));
+    report.vgic_eoi_ns = as_ns(run.vgic_eoi);
+    crate::util::barrier();
+    report.done = 1;
+    info!(
+        "hw_test: ipi pass {:#x} timer pass {:#x} vgic pass {:#x}",
+        ipi_pass, timer_pass, vgic_pass
+    );
+}
+
+// the pong of core `cpu_id` came back to the core running the test
+fn hw_test_pong(cpu_id: usize) {
+    if let Some(run) = HW_TEST_RUN.lock().as_mut() {
+        run.ipi_rtt[cpu_id] = Some(now() - run.start);
+    }
+}
+
+// program the one-shot timer of this core
+fn hw_test_timer_start(src: usize) {
+    timer_enable(true);
+    let event = HwTestTimer {
+        expected: now() + HW_TEST_TIMER_PERIOD,
+        src,
+    };
+    start_timer_event(HW_TEST_TIMER_PERIOD, Arc::new(event));
+    timer_notify_after(HW_TEST_TIMER_PERIOD);
+}
+
+pub fn hw_test_ipi_handler(msg: IpiMessage) {
+    match msg.ipi_message {
+        IpiInnerMsg::HwTestMsg(test_msg) => match test_msg.event {
+            HwTestEvent::Ping => {
+                let cpu_id = current_cpu().id;
+                if test_msg.src == cpu_id {
+                    hw_test_pong(cpu_id);
+                } else {
+                    let pong = IpiHwTestMsg {
+                        event: HwTestEvent::Pong,
+                        src: cpu_id,
+                    };
+                    if let Err(err) = ipi_send_msg(test_msg.src, IpiType::HwTest, IpiInnerMsg::HwTestMsg(pong)) {
+                        error!("hw_test_ipi_handler: Core {} failed to answer: {:?}", cpu_id, err);
+                    }
+                }
+                hw_test_timer_start(test_msg.src);
+            }
+            HwTestEvent::Pong => hw_test_pong(test_msg.src),
+        },
+        _ => {
+            error!("hw_test_ipi_handler: illegal ipi type");
+        }
+    }
+}
+
+/**
+ * HVC_SYS_TEST_HW: VM0 only, test the ipi, timer and vgic paths of the cores. Each core gets a
+ * ping ipi it answers and programs a one-shot timer on; `sgi_id` of the report is injected into
+ * the calling vcpu and waited to be EOIed. The report is written to the buffer in the background.
+ *
+ * @param report_ipa : a `HwTestReport` and a `HwTestCore` for each core.
+ * @return the number of cores.
+ */
+pub fn hw_test_start(report_ipa: usize) -> Result<usize, ()> {
+    let vm = active_vm().unwrap();
+    if vm.id() != 0 {
+        error!("hw_test_start: VM[{}] can not run the test", vm.id());
+        return Err(());
+    }
+    let num = PLAT_DESC.cpu_desc.num;
+    let len = size_of::<HwTestReport>() + num * size_of::<HwTestCore>();
+    if report_ipa % size_of::<u64>() != 0 {
+        error!("hw_test_start: report {:#x} is not aligned", report_ipa);
+        return Err(());
+    }
+    let report_hva = vm_ipa2hva(&vm, report_ipa, len).map_err(|_| ())?;
+    let report = unsafe { &mut *(report_hva as *mut HwTestReport) };
+    let sgi_id = report.sgi_id as usize;
+    if sgi_id >= GIC_SGIS_NUM {
+        error!("hw_test_start: illegal SGI {}", sgi_id);
+        return Err(());
+    }
+
+    let cpu_id = current_cpu().id;
+    let mut run = HW_TEST_RUN.lock();
+    if run.is_some() {
+        error!("hw_test_start: a test is running");
+        return Err(());
+    }
+    report.done = 0;
+    let start = now();
+    *run = Some(HwTestRun {
+        report_hva,
+        src: cpu_id,
+        start,
+        ipi_rtt: [None; CORE_NUM],
+        timer_late: [None; CORE_NUM],
+        vgic_eoi: None,
+    });
+    drop(run);
+
+    // a running tick reaches the deadline by itself, do not put it off
+    if !current_cpu().vcpu_array.timer_on() {
+        timer_enable(true);
+        timer_notify_after(HW_TEST_DEADLINE);
+    }
+    start_timer_event(HW_TEST_DEADLINE, Arc::new(HwTestDeadline));
+
+    for target in (0..num).filter(|target| *target != cpu_id) {
+        let ping = IpiHwTestMsg {
+            event: HwTestEvent::Ping,
+            src: cpu_id,
+        };
+        if let Err(err) = ipi_send_msg(target, IpiType::HwTest, IpiInnerMsg::HwTestMsg(ping)) {
+            error!("hw_test_start: failed to send ipi to Core {}: {:?}", target, err);
+        }
+    }
+    // no ipi to itself, this core answers its own ping right away
+    hw_test_pong(cpu_id);
+    hw_test_timer_start(cpu_id);
+
+    let vcpu = current_cpu().active_vcpu.clone().unwrap();
+    HW_TEST_SGI.store(hw_test_sgi_key(&vcpu, sgi_id), Ordering::Relaxed);
+    interrupt_vm_inject(&vm, &vcpu, sgi_id);
+    Ok(num)
+}
@@ -2,15 +2,26 @@ use alloc::collections::BTreeMap;
 
 use spin::Mutex;
 
+use alloc::vec::Vec;
+
 use crate::arch::{
-    interrupt_arch_ipi_send, interrupt_arch_vm_inject, interrupt_arch_vm_register, GIC_PRIVINT_NUM, GIC_SGIS_NUM,
-    INTERRUPT_NUM_MAX,
+    interrupt_arch_ipi_send, interrupt_arch_vcpu_bind, interrupt_arch_vcpu_retarget, interrupt_arch_vm_inject,
+    interrupt_arch_vm_int_target, interrupt_arch_vm_register, interrupt_arch_vm_release, interrupt_arch_vm_transfer,
+    GIC_PRIVINT_NUM, GIC_SGIS_NUM, INTERRUPT_NUM_MAX,
+};
+use crate::kernel::{
+    current_cpu, event_trace, hvc_send_msg_to_vm, ipi_send_msg, vm_by_id, vm_if_get_state, vm_list_walker, HvcGuestMsg,
+    HvcIntTransferMsg, IpiInnerMsg, IpiIntInjectMsg, IpiType, TraceEvent, Vcpu, VcpuState, Vm, VmState, HVC_VMM,
+    HVC_VMM_INT_TRANSFER,
 };
-use crate::kernel::{current_cpu, Vcpu, VcpuState, Vm};
 use crate::util::{BitAlloc, BitAlloc4K};
 
 static INTERRUPT_GLB_BITMAP: Mutex<BitAlloc4K> = Mutex::new(BitAlloc4K::default());
 static INTERRUPT_HANDLERS: Mutex<BTreeMap<usize, fn()>> = Mutex::new(BTreeMap::new());
+// the passthrough SPIs moving between VMs: int_id -> (src vm_id, dst vm_id)
+static INTERRUPT_TRANSFERS: Mutex<BTreeMap<usize, (usize, usize)>> = Mutex::new(BTreeMap::new());
+// the passthrough SPIs moved at runtime: int_id -> the vm_id owning it now
+static INTERRUPT_MOVED: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
 
 pub fn interrupt_cpu_ipi_send(target_cpu: usize, ipi_id: usize) {
     interrupt_arch_ipi_send(target_cpu, ipi_id);
@@ -47,8 +58,14 @@ pub fn interrupt_vm_register(vm: &Vm, id: usize, hw: bool) -> bool {
     true
 }
 
-pub fn interrupt_vm_remove(_vm: &Vm, id: usize) {
+pub fn interrupt_vm_remove(vm: &Vm, id: usize) {
     if id >= GIC_SGIS_NUM {
+        // a SPI moved away at runtime belongs to another VM now
+        if id >= GIC_PRIVINT_NUM && !vm.has_interrupt(id) {
+            return;
+        }
+        INTERRUPT_MOVED.lock().remove(&id);
+        INTERRUPT_TRANSFERS.lock().remove(&id);
         let mut glb_bitmap_lock = INTERRUPT_GLB_BITMAP.lock();
         // vgic and vm will be removed with struct vm
         glb_bitmap_lock.clear(id);
@@ -59,7 +76,129 @@ pub fn interrupt_vm_remove(_vm: &Vm, id: usize) {
     }
 }
 
+// the passthrough SPIs moved to VM `vm_id` at runtime, not found in its config
+pub fn interrupt_vm_moved_ints(vm_id: usize) -> Vec<usize> {
+    INTERRUPT_MOVED
+        .lock()
+        .iter()
+        .filter(|(_, owner)| **owner == vm_id)
+        .map(|(int_id, _)| *int_id)
+        .collect()
+}
+
+/* Move the passthrough SPI `int_id` from VM `src` to VM `dst` at runtime.
+ * The interrupt is masked in the GICD first. If the guest of `src` still has it active,
+ * the move is finished by `interrupt_vm_transfer_eoi` after the EOI of that guest.
+ *
+ * @param[in] src: the VM owning the interrupt now.
+ * @param[in] dst: the VM to give the interrupt to.
+ * @param[in] int_id: the passthrough SPI.
+ * @return false if `src` does not own `int_id` as a passthrough interrupt, or it is moving already.
+ */
+pub fn interrupt_vm_transfer(src: &Vm, dst: &Vm, int_id: usize) -> bool {
+    if !(GIC_PRIVINT_NUM..INTERRUPT_NUM_MAX).contains(&int_id) || src.id() == dst.id() {
+        error!(
+            "interrupt_vm_transfer: illegal int {} from VM {} to VM {}",
+            int_id,
+            src.id(),
+            dst.id()
+        );
+        return false;
+    }
+    // an emulated interrupt is not in the global bitmap, a reserved one has a handler
+    if !src.has_interrupt(int_id)
+        || dst.has_interrupt(int_id)
+        || INTERRUPT_GLB_BITMAP.lock().get(int_id) == 0
+        || interrupt_is_reserved(int_id).is_some()
+    {
+        error!(
+            "interrupt_vm_transfer: int {} is not a passthrough interrupt of VM {}",
+            int_id,
+            src.id()
+        );
+        return false;
+    }
+    {
+        let mut transfers = INTERRUPT_TRANSFERS.lock();
+        if transfers.contains_key(&int_id) {
+            error!("interrupt_vm_transfer: int {} is moving already", int_id);
+            return false;
+        }
+        transfers.insert(int_id, (src.id(), dst.id()));
+    }
+    interrupt_cpu_enable(int_id, false);
+    if interrupt_arch_vm_release(src, int_id) {
+        interrupt_vm_transfer_eoi(int_id);
+    } else {
+        info!(
+            "interrupt_vm_transfer: int {} is in flight in VM {}, moved after its EOI",
+            int_id,
+            src.id()
+        );
+    }
+    true
+}
+
+/* Finish the move of `int_id` once it is inactive in the source VM, called by
+ * `interrupt_vm_transfer` or on the core taking the EOI of the source guest.
+ */
+pub fn interrupt_vm_transfer_eoi(int_id: usize) {
+    let (src_id, dst_id) = match INTERRUPT_TRANSFERS.lock().remove(&int_id) {
+        Some(transfer) => transfer,
+        None => return,
+    };
+    let (src, dst) = match (vm_by_id(src_id), vm_by_id(dst_id)) {
+        (Some(src), Some(dst)) => (src, dst),
+        (Some(src), None) => {
+            // hand it back to the source, targeted and unmasked again as its vgic has it
+            error!(
+                "interrupt_vm_transfer_eoi: VM {} is gone, int {} stays in VM {}",
+                dst_id, int_id, src_id
+            );
+            interrupt_arch_vm_transfer(&src, &src, int_id);
+            return;
+        }
+        (None, _) => {
+            // nobody owns it any more, keep it masked and free it for a later VM
+            error!(
+                "interrupt_vm_transfer_eoi: VM {} is gone, int {} is released",
+                src_id, int_id
+            );
+            INTERRUPT_MOVED.lock().remove(&int_id);
+            INTERRUPT_GLB_BITMAP.lock().clear(int_id);
+            return;
+        }
+    };
+    src.set_passthrough_int(int_id, false);
+    dst.set_passthrough_int(int_id, true);
+    {
+        // still reserved by a VM, only its owner changes
+        let mut glb_bitmap_lock = INTERRUPT_GLB_BITMAP.lock();
+        glb_bitmap_lock.set(int_id);
+        INTERRUPT_MOVED.lock().insert(int_id, dst_id);
+    }
+    interrupt_arch_vm_transfer(&src, &dst, int_id);
+    info!("VM {} int {} is moved to VM {}", src_id, int_id, dst_id);
+
+    let msg = HvcIntTransferMsg {
+        fid: HVC_VMM,
+        event: HVC_VMM_INT_TRANSFER,
+        src_vm_id: src_id,
+        dst_vm_id: dst_id,
+        int_id,
+    };
+    for vm_id in [src_id, dst_id] {
+        if !hvc_send_msg_to_vm(vm_id, &HvcGuestMsg::IntTransfer(msg)) {
+            warn!(
+                "interrupt_vm_transfer_eoi: failed to notify VM {} of int {}",
+                vm_id, int_id
+            );
+        }
+    }
+}
+
 pub fn interrupt_vm_inject(vm: &Vm, vcpu: &Vcpu, int_id: usize) {
+    event_trace(TraceEvent::Inject, vm.id(), int_id);
     if vcpu.phys_id() != current_cpu().id {
         error!(
             "interrupt_vm_inject: Core {} failed to find target (VCPU {} VM {})",
@@ -69,7 +208,89 @@ pub fn interrupt_vm_inject(vm: &Vm, vcpu: &Vcpu, int_id: usize) {
         );
         return;
     }
+    // the VM is shut down or crashed, its vcpus are gone from the cores
+    if vcpu.state() == VcpuState::Inv && matches!(vm_if_get_state(vm.id()), VmState::Inv | VmState::Crashed) {
+        return;
+    }
     interrupt_arch_vm_inject(vm, vcpu, int_id);
+    if vcpu.wfi_blocked() {
+        current_cpu().vcpu_array.wfi_wakeup(vcpu);
+    } else if vcpu.state() == VcpuState::Runnable {
+        current_cpu().vcpu_array.check_preempt(vcpu);
+    }
+}
+
+/* Inject `int_id` to `vcpu` of `vm` from any core. A vcpu on this core, running or not, gets it in
+ * its vgic state right away, without an IntInject ipi to the core itself, only a vcpu on another
+ * core costs an ipi round trip.
+ */
+pub fn interrupt_vm_inject_to(vm: &Vm, vcpu: &Vcpu, int_id: usize) -> bool {
+    let phys_id = vcpu.phys_id();
+    if phys_id == current_cpu().id {
+        if current_cpu().vcpu_array.vcpu_by_id(vm.id(), vcpu.id()).is_some() {
+            interrupt_vm_inject(vm, vcpu, int_id);
+        } else {
+            // migrating here and not attached yet, the int is injected when it is restored
+            vcpu.push_int(int_id);
+        }
+        return true;
+    }
+    let m = IpiIntInjectMsg {
+        vm_id: vm.id(),
+        vcpu_id: vcpu.id(),
+        int_id,
+    };
+    if ipi_send_msg(phys_id, IpiType::IntInject, IpiInnerMsg::IntInjectMsg(m)).is_err() {
+        error!(
+            "interrupt_vm_inject_to: failed to send int {} of VM {} to Core {}",
+            int_id,
+            vm.id(),
+            phys_id
+        );
+        return false;
+    }
+    true
+}
+
+/* Make the interrupts of a vcpu follow it to its new physical cpu,
+ * it should be called whenever the vcpu-to-pcpu binding changes.
+ *
+ * @param[in] vcpu: the moved vcpu.
+ * @param[in] old_phys_id: the physical cpu the vcpu was bound to.
+ */
+pub fn interrupt_vcpu_retarget(vcpu: &Vcpu, old_phys_id: usize) {
+    interrupt_arch_vcpu_retarget(vcpu, old_phys_id);
+}
+
+/* Release (`bind` false) or set up (`bind` true) the per-core interrupt state of a moved vcpu,
+ * it must be called on the old and on the new core of the vcpu respectively.
+ */
+pub fn interrupt_vcpu_bind(vcpu: &Vcpu, bind: bool) {
+    interrupt_arch_vcpu_bind(vcpu, bind);
+}
+
+// A SPI may still land on the old core while its target vcpu is being moved,
+// forward it to the core the vgic targets now.
+fn interrupt_forward(int_id: usize) -> bool {
+    let mut target = None;
+    vm_list_walker(|vm| {
+        if target.is_none() && vm.has_interrupt(int_id) {
+            if let Some(phys_id) = interrupt_arch_vm_int_target(vm, int_id) {
+                if phys_id != current_cpu().id {
+                    if let Some(vcpu_id) = vm.pcpuid_to_vcpuid(phys_id) {
+                        target = Some((vm.id(), vcpu_id, phys_id));
+                    }
+                }
+            }
+        }
+    });
+    match target {
+        Some((vm_id, vcpu_id, phys_id)) => {
+            let m = IpiIntInjectMsg { vm_id, vcpu_id, int_id };
+            ipi_send_msg(phys_id, IpiType::IntInject, IpiInnerMsg::IntInjectMsg(m)).is_ok()
+        }
+        None => false,
+    }
 }
 
 fn interrupt_is_reserved(int_id: usize) -> Option<fn()> {
@@ -77,6 +298,9 @@ fn interrupt_is_reserved(int_id: usize) -> Option<fn()> {
 }
 
 pub fn interrupt_handler(int_id: usize) -> bool {
+    event_trace(TraceEvent::Irq, int_id, 0);
+    crate::util::rng::rng_add_jitter(int_id);
+
     if let Some(irq_handler) = interrupt_is_reserved(int_id) {
         irq_handler();
         return true;
@@ -95,7 +319,7 @@ pub fn interrupt_handler(int_id: usize) -> bool {
         }
     }
 
-    for vcpu in current_cpu().vcpu_array.iter().flatten() {
+    for vcpu in current_cpu().vcpu_array.iter() {
         if let Some(vm) = vcpu.vm() {
             if vm.has_interrupt(int_id) {
                 if vcpu.state() == VcpuState::Inv {
@@ -107,6 +331,10 @@ pub fn interrupt_handler(int_id: usize) -> bool {
         }
     }
 
+    if int_id >= GIC_PRIVINT_NUM && interrupt_forward(int_id) {
+        return false;
+    }
+
     error!(
         "interrupt_handler: core {} receive unsupported int {}",
         current_cpu().id,
@@ -1,8 +1,13 @@
+use crate::kernel::access::vm_ipa2hva;
+use crate::kernel::{active_vm, CONFIG_VM_NUM_MAX};
 use crate::{config::VmEmulatedDeviceConfig, device::EmuDev, kernel::Vm};
 
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use core::mem::size_of;
 
 use cfg_if::cfg_if;
+use spin::Mutex;
 
 #[allow(dead_code)]
 pub fn iommu_init() {
@@ -32,7 +37,13 @@ pub fn iommmu_vm_init(vm: &Vm) -> bool {
 pub fn iommu_add_device(vm: &Vm, stream_id: usize) -> bool {
     cfg_if! {
         if #[cfg(feature = "smmuv2")] {
-            crate::arch::smmu_add_device(vm.iommu_ctx_id(), stream_id)
+            match vm.iommu_ctx_id() {
+                Some(context_id) => crate::arch::smmu_add_device(context_id, stream_id),
+                None => {
+                    error!("iommu_add_device: VM[{}] has no iommu context bank for stream {:#x}", vm.id(), stream_id);
+                    false
+                }
+            }
         } else {
             warn!("Platform not support IOMMU");
             false
@@ -40,6 +51,71 @@ pub fn iommu_add_device(vm: &Vm, stream_id: usize) -> bool {
     }
 }
 
+// stream id => the VM its device is passed through to
+static IOMMU_STREAM_OWNER: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+#[allow(unused)]
+fn iommu_stream_valid(stream_id: usize) -> bool {
+    cfg_if! {
+        if #[cfg(feature = "smmuv2")] {
+            crate::arch::smmu_stream_id_valid(stream_id)
+        } else {
+            false
+        }
+    }
+}
+
+/* Give the passthrough streams to VM `vm_id`, a stream id 0 ends the list.
+ * Nothing is claimed if the SMMU does not match one of them or another VM owns one of them.
+ */
+pub fn iommu_claim_streams(vm_id: usize, stream_ids: &[usize]) -> bool {
+    let stream_ids = stream_ids.iter().take_while(|&&stream_id| stream_id != 0);
+    let mut owner = IOMMU_STREAM_OWNER.lock();
+    for &stream_id in stream_ids.clone() {
+        if !iommu_stream_valid(stream_id) {
+            error!("VM[{}] stream id {:#x} is not exposed by the iommu", vm_id, stream_id);
+            return false;
+        }
+        match owner.get(&stream_id) {
+            Some(&owner_id) if owner_id != vm_id => {
+                error!(
+                    "VM[{}] stream id {:#x} is passed through to VM[{}] already",
+                    vm_id, stream_id, owner_id
+                );
+                return false;
+            }
+            _ => {}
+        }
+    }
+    for &stream_id in stream_ids {
+        owner.insert(stream_id, vm_id);
+    }
+    true
+}
+
+pub fn iommu_release_streams(vm_id: usize) {
+    IOMMU_STREAM_OWNER.lock().retain(|_, owner_id| *owner_id != vm_id);
+}
+
+/* Take the streams of a removed VM off the iommu and free its context bank,
+ * so that the next VM can have them.
+ */
+pub fn iommu_detach_vm(vm: &Vm) {
+    #[cfg(feature = "iommu")]
+    crate::arch::smmu_vm_detach(vm);
+    iommu_release_streams(vm.id());
+}
+
+/* The iommu walks the stage-2 table of a VM with a context bank and caches its entries,
+ * drop them after a translation or permission of the table changed.
+ */
+pub fn iommu_tlb_invalidate(vm_id: usize) {
+    #[cfg(feature = "iommu")]
+    crate::arch::smmu_vm_tlb_invalidate(vm_id);
+    #[cfg(not(feature = "iommu"))]
+    let _ = vm_id;
+}
+
 #[allow(unused)]
 pub fn emu_iommu_init(emu_cfg: &VmEmulatedDeviceConfig) -> Result<Arc<dyn EmuDev>, ()> {
     cfg_if! {
@@ -50,3 +126,200 @@ pub fn emu_iommu_init(emu_cfg: &VmEmulatedDeviceConfig) -> Result<Arc<dyn EmuDev
         }
     }
 }
+
+/* A fault of a context bank, as read back by the owning VM with HVC_SYS_IOMMU_FAULT.
+ * Keep the layout in sync with the guest drivers.
+ */
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct IommuFault {
+    // the faulting input address (ipa of the VM)
+    pub iova: u64,
+    pub fsr: u32,
+    pub fsynr0: u32,
+    pub stream_id: u32,
+    pub context_id: u32,
+}
+
+const IOMMU_FAULT_RING_LEN: usize = 16;
+// unread faults of a VM before its fault irq is masked, until the ring is read
+const IOMMU_FAULT_STORM: usize = 64;
+
+#[repr(C)]
+pub struct IommuFaultList {
+    // faults since the VM was created, including the lost ones
+    pub total: u64,
+    // faults overwritten or not recorded since the last read
+    pub lost: u64,
+    pub num: u64,
+    pub faults: [IommuFault; IOMMU_FAULT_RING_LEN],
+}
+
+struct IommuFaultRing {
+    faults: [IommuFault; IOMMU_FAULT_RING_LEN],
+    head: usize,
+    num: usize,
+    total: usize,
+    lost: usize,
+    // only one notification until the ring is read
+    notified: bool,
+    // the context bank whose fault irq is masked for a storm
+    muted: Option<usize>,
+}
+
+impl IommuFaultRing {
+    const fn new() -> Self {
+        Self {
+            faults: [IommuFault {
+                iova: 0,
+                fsr: 0,
+                fsynr0: 0,
+                stream_id: 0,
+                context_id: 0,
+            }; IOMMU_FAULT_RING_LEN],
+            head: 0,
+            num: 0,
+            total: 0,
+            lost: 0,
+            notified: false,
+            muted: None,
+        }
+    }
+
+    #[cfg(feature = "smmuv2")]
+    fn push(&mut self, fault: &IommuFault) {
+        if self.num == IOMMU_FAULT_RING_LEN {
+            self.head = (self.head + 1) % IOMMU_FAULT_RING_LEN;
+            self.num -= 1;
+            self.lost += 1;
+        }
+        self.faults[(self.head + self.num) % IOMMU_FAULT_RING_LEN] = *fault;
+        self.num += 1;
+        self.total += 1;
+    }
+
+    #[cfg(feature = "smmuv2")]
+    fn unread(&self) -> usize {
+        self.num + self.lost
+    }
+}
+
+static IOMMU_FAULT_LIST: [Mutex<IommuFaultRing>; CONFIG_VM_NUM_MAX] =
+    [const { Mutex::new(IommuFaultRing::new()) }; CONFIG_VM_NUM_MAX];
+
+#[allow(dead_code)]
+pub fn iommu_fault_irq_init() {
+    cfg_if! {
+        if #[cfg(feature = "smmuv2")] {
+            crate::arch::smmu_fault_irq_init();
+        }
+    }
+}
+
+pub fn iommu_fault_reset(vm_id: usize) {
+    if let Some(ring) = IOMMU_FAULT_LIST.get(vm_id) {
+        *ring.lock() = IommuFaultRing::new();
+    }
+}
+
+/* Record a context bank fault for the VM owning the bank and notify it, or VM0 for a bma guest.
+ * Return false if the fault irq of the bank should be masked, the VM does not read its faults.
+ */
+#[cfg(feature = "smmuv2")]
+pub fn iommu_fault_report(fault: &IommuFault) -> bool {
+    use crate::kernel::{hvc_send_msg_to_vm, vm_list_walker, HvcGuestMsg, HvcManageMsg, VmType};
+    use crate::kernel::{HVC_SYS, HVC_SYS_IOMMU_FAULT};
+
+    let context_id = fault.context_id as usize;
+    let mut owner = None;
+    vm_list_walker(|vm| {
+        if owner.is_none() && vm.owns_iommu_ctx(context_id) {
+            owner = Some((vm.id(), vm.vm_type()));
+        }
+    });
+    let (vm_id, vm_type) = match owner {
+        Some(owner) => owner,
+        None => {
+            warn!(
+                "iommu_fault_report: context bank {} without VM faults at {:#x}, stream {:#x}",
+                context_id, fault.iova, fault.stream_id
+            );
+            return true;
+        }
+    };
+    let mut ring = IOMMU_FAULT_LIST[vm_id].lock();
+    if ring.muted.is_some() {
+        ring.total += 1;
+        ring.lost += 1;
+        return false;
+    }
+    ring.push(fault);
+    if ring.unread() >= IOMMU_FAULT_STORM {
+        warn!(
+            "iommu_fault_report: VM[{}] fault storm on context bank {}, mask it until the faults are read",
+            vm_id, context_id
+        );
+        ring.muted = Some(context_id);
+    }
+    let notify = !ring.notified;
+    ring.notified = true;
+    let muted = ring.muted.is_some();
+    drop(ring);
+
+    debug!(
+        "VM[{}] iommu fault at {:#x}, fsr {:#x} stream {:#x}",
+        vm_id, fault.iova, fault.fsr, fault.stream_id
+    );
+    if notify {
+        let trgt_vm_id = if vm_type == VmType::VmTBma { 0 } else { vm_id };
+        let msg = HvcManageMsg {
+            fid: HVC_SYS,
+            event: HVC_SYS_IOMMU_FAULT,
+            vm_id,
+        };
+        if !hvc_send_msg_to_vm(trgt_vm_id, &HvcGuestMsg::Manage(msg)) {
+            warn!("iommu_fault_report: failed to notify VM[{}]", trgt_vm_id);
+        }
+    }
+    !muted
+}
+
+/* HVC_SYS_IOMMU_FAULT: move the recorded faults of VM `vm_id` to an `IommuFaultList` at `list_ipa`.
+ * A VM reads its own faults, VM0 may read those of any VM.
+ */
+pub fn iommu_fault_read(list_ipa: usize, vm_id: usize) -> Result<usize, ()> {
+    let vm = active_vm().unwrap();
+    if vm.id() != 0 && vm.id() != vm_id {
+        error!("iommu_fault_read: VM[{}] can not read faults of VM[{}]", vm.id(), vm_id);
+        return Err(());
+    }
+    let ring = match IOMMU_FAULT_LIST.get(vm_id) {
+        Some(ring) => ring,
+        None => return Err(()),
+    };
+    let list_hva = vm_ipa2hva(&vm, list_ipa, size_of::<IommuFaultList>()).map_err(|_| ())?;
+    let list = unsafe { &mut *(list_hva as *mut IommuFaultList) };
+
+    let mut ring = ring.lock();
+    list.total = ring.total as u64;
+    list.lost = ring.lost as u64;
+    list.num = ring.num as u64;
+    for i in 0..ring.num {
+        list.faults[i] = ring.faults[(ring.head + i) % IOMMU_FAULT_RING_LEN];
+    }
+    let num = ring.num;
+    ring.head = 0;
+    ring.num = 0;
+    ring.lost = 0;
+    ring.notified = false;
+    let muted = ring.muted.take();
+    drop(ring);
+
+    #[cfg(feature = "smmuv2")]
+    if let Some(context_id) = muted {
+        crate::arch::smmu_fault_irq_enable(context_id, true);
+    }
+    #[cfg(not(feature = "smmuv2"))]
+    let _ = muted;
+    Ok(num)
+}
@@ -1,5 +1,6 @@
-use alloc::collections::LinkedList;
+use alloc::collections::VecDeque;
 use alloc::sync::Arc;
+use core::mem::size_of;
 
 use spin::Mutex;
 
@@ -7,13 +8,46 @@ use crate::arch::INTERRUPT_IRQ_IPI;
 use crate::board::static_config;
 use crate::board::PLAT_DESC;
 use crate::device::{VirtioMmio, Virtq};
-use crate::kernel::{current_cpu, interrupt_cpu_ipi_send};
-use crate::kernel::{interrupt_reserve_int, interrupt_vm_inject};
+use crate::kernel::access::vm_ipa2hva;
+use crate::kernel::{active_vm, current_cpu, interrupt_cpu_ipi_send, vm_by_id};
+use crate::kernel::{event_trace, interrupt_reserve_int, interrupt_vm_inject_to, TraceEvent};
+use crate::mm::{HeapTag, HeapTagGuard};
+use crate::util::sleep;
 use crate::vmm::{VmmEvent, VmmPercoreEvent};
 
 use super::interrupt_cpu_enable;
+use super::IpiHwTestMsg;
 use super::Vm;
 
+// max pending messages of each ipi type on a core, a core that stops handling ipis must not eat up the heap
+const IPI_QUEUE_MAX: usize = 128;
+// attempts of `ipi_send_msg_retry`, the delay between them doubles from IPI_RETRY_BACKOFF_US
+const IPI_RETRY_MAX: usize = 8;
+const IPI_RETRY_BACKOFF_US: usize = 10;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IpiError {
+    InvalidTarget,
+    QueueFull,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct IpiTypeStat {
+    pub pending: usize,
+    pub dropped: usize,
+    pub max_depth: usize,
+    // mediated notifications folded into one already queued for the same vq
+    pub coalesced: usize,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct IpiStat {
+    pub pending: usize,
+    pub dropped: usize,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum InitcEvent {
     GichEn,
@@ -24,6 +58,8 @@ pub enum InitcEvent {
     SetTrgt,
     SetCfg,
     Route,
+    // a passthrough SPI moving to another VM, see `interrupt_vm_transfer`
+    Release,
 }
 
 #[derive(Copy, Clone)]
@@ -37,16 +73,20 @@ pub enum PowerEvent {
 pub struct IpiInitcMessage {
     pub event: InitcEvent,
     pub vm_id: usize,
+    // the vcpu handling it, None for any vcpu of the VM on the receiving core
+    pub vcpu_id: Option<usize>,
     pub int_id: u16,
     pub val: u8,
 }
 
 /*
 * src: src vm id
+* vcpu_id: target vcpu, a Reset is for all vcpus of the VM on the core
 */
 #[derive(Clone)]
 pub struct IpiPowerMessage {
     pub src: usize,
+    pub vcpu_id: usize,
     pub event: PowerEvent,
     pub entry: usize,
     pub context: usize,
@@ -61,6 +101,8 @@ pub struct IpiPowerMessage {
 #[derive(Clone)]
 pub struct IpiEthernetMsg {
     pub trgt_nic: Arc<VirtioMmio>,
+    // the rx queue the frame was written to
+    pub rx_idx: usize,
 }
 
 #[derive(Clone)]
@@ -81,6 +123,8 @@ pub struct IpiMediatedMsg {
     pub src_vm: Arc<Vm>,
     pub vq: Arc<Virtq>,
     pub blk: Arc<VirtioMmio>,
+    // the mediated blk behind `blk`
+    pub blk_id: usize,
 }
 
 #[derive(Clone)]
@@ -99,6 +143,7 @@ pub struct IpiHvcMsg {
 #[derive(Clone)]
 pub struct IpiIntInjectMsg {
     pub vm_id: usize,
+    pub vcpu_id: usize,
     pub int_id: usize,
 }
 
@@ -113,6 +158,7 @@ declare_enum_with_handler! {
         Vmm => crate::vmm::vmm_ipi_handler,
         MediatedDev => crate::device::mediated_ipi_handler,
         IntInject => interrupt_inject_ipi_handler,
+        HwTest => crate::kernel::hw_test_ipi_handler,
     }
 }
 
@@ -136,6 +182,8 @@ pub enum IpiInnerMsg {
     HvcMsg(IpiHvcMsg),
     // IpiTIntInject
     IntInjectMsg(IpiIntInjectMsg),
+    // IpiTHwTest
+    HwTestMsg(IpiHwTestMsg),
 }
 
 pub struct IpiMessage {
@@ -143,23 +191,77 @@ pub struct IpiMessage {
     pub ipi_message: IpiInnerMsg,
 }
 
+// the order the queues of a core are drained in, the control messages go before the bulk io ones
+const IPI_DRAIN_ORDER: [IpiType; IPI_TYPE_NUM] = [
+    IpiType::Power,
+    IpiType::Intc,
+    IpiType::IntInject,
+    IpiType::Vmm,
+    IpiType::Hvc,
+    IpiType::HwTest,
+    IpiType::MediatedDev,
+    IpiType::EthernetMsg,
+];
+const IPI_TYPE_NUM: usize = IpiType::NUM;
+
+struct IpiQueue {
+    msgs: VecDeque<IpiMessage>,
+    // messages given up because the queue was full
+    dropped: usize,
+    max_depth: usize,
+    coalesced: usize,
+}
+
+impl IpiQueue {
+    const fn new() -> Self {
+        Self {
+            msgs: VecDeque::new(),
+            dropped: 0,
+            max_depth: 0,
+            coalesced: 0,
+        }
+    }
+}
+
 struct CpuIf {
-    msg_queue: LinkedList<IpiMessage>,
+    queues: [IpiQueue; IPI_TYPE_NUM],
 }
 
 impl CpuIf {
     const fn new() -> Self {
         Self {
-            msg_queue: LinkedList::new(),
+            queues: [const { IpiQueue::new() }; IPI_TYPE_NUM],
         }
     }
 
-    fn push(&mut self, ipi_msg: IpiMessage) {
-        self.msg_queue.push_back(ipi_msg);
+    fn push(&mut self, ipi_msg: IpiMessage) -> Result<(), IpiMessage> {
+        let queue = &mut self.queues[ipi_msg.ipi_type as usize];
+        // the handler drains the avail ring of the vq, one queued notification covers the others
+        if let IpiInnerMsg::MediatedMsg(med_msg) = &ipi_msg.ipi_message {
+            if queue.msgs.iter().any(|msg| match &msg.ipi_message {
+                IpiInnerMsg::MediatedMsg(queued) => Arc::ptr_eq(&queued.vq, &med_msg.vq),
+                _ => false,
+            }) {
+                queue.coalesced += 1;
+                return Ok(());
+            }
+        }
+        if queue.msgs.len() >= IPI_QUEUE_MAX {
+            return Err(ipi_msg);
+        }
+        queue.msgs.push_back(ipi_msg);
+        queue.max_depth = queue.max_depth.max(queue.msgs.len());
+        Ok(())
     }
 
     fn pop(&mut self) -> Option<IpiMessage> {
-        self.msg_queue.pop_front()
+        IPI_DRAIN_ORDER
+            .iter()
+            .find_map(|ipi_type| self.queues[*ipi_type as usize].msgs.pop_front())
+    }
+
+    fn msgs(&self) -> impl Iterator<Item = &IpiMessage> {
+        self.queues.iter().flat_map(|queue| queue.msgs.iter())
     }
 }
 
@@ -177,13 +279,25 @@ fn interrupt_inject_ipi_handler(msg: IpiMessage) {
         IpiInnerMsg::IntInjectMsg(int_msg) => {
             let vm_id = int_msg.vm_id;
             let int_id = int_msg.int_id;
-            match current_cpu().vcpu_array.pop_vcpu_through_vmid(vm_id) {
+            let vm = match vm_by_id(vm_id) {
+                Some(vm) => vm,
                 None => {
-                    panic!("inject int {} to illegal cpu {}", int_id, current_cpu().id);
+                    warn!(
+                        "interrupt_inject_ipi_handler: VM[{}] is gone, drop int {}",
+                        vm_id, int_id
+                    );
+                    return;
                 }
+            };
+            match vm.vcpu(int_msg.vcpu_id) {
+                // on this core, or migrating here and injected when it is restored, or moved on since
                 Some(vcpu) => {
-                    interrupt_vm_inject(&vcpu.vm().unwrap(), vcpu, int_id);
+                    interrupt_vm_inject_to(&vm, vcpu, int_id);
                 }
+                None => error!(
+                    "interrupt_inject_ipi_handler: VM[{}] has no vcpu {}",
+                    vm_id, int_msg.vcpu_id
+                ),
             }
         }
         _ => {
@@ -203,11 +317,45 @@ fn ipi_pop_message(cpu_id: usize) -> Option<IpiMessage> {
     msg
 }
 
+// whether there are ipi messages not handled yet on this core
+#[cfg(feature = "trap-wfi")]
+pub fn ipi_pending(cpu_id: usize) -> bool {
+    CPU_IF_LIST[cpu_id].lock().msgs().next().is_some()
+}
+
+impl IpiInnerMsg {
+    // the VM a message is about, if any
+    fn vm_id(&self) -> Option<usize> {
+        match self {
+            IpiInnerMsg::Initc(msg) => Some(msg.vm_id),
+            IpiInnerMsg::Power(msg) => Some(msg.src),
+            IpiInnerMsg::EnternetMsg(_) => None,
+            IpiInnerMsg::VmmMsg(msg) => Some(msg.vmid),
+            IpiInnerMsg::VmmPercoreMsg(msg) => Some(msg.vm.id()),
+            IpiInnerMsg::MediatedMsg(msg) => Some(msg.src_vm.id()),
+            IpiInnerMsg::MediatedNotifyMsg(msg) => Some(msg.vm_id),
+            IpiInnerMsg::HvcMsg(msg) => Some(msg.trgt_vmid),
+            IpiInnerMsg::IntInjectMsg(msg) => Some(msg.vm_id),
+            IpiInnerMsg::HwTestMsg(_) => None,
+        }
+    }
+}
+
+// whether messages about VM `vm_id` are still queued on a core, they expect its vcpu to be there
+pub fn ipi_pending_for_vm(cpu_id: usize, vm_id: usize) -> bool {
+    CPU_IF_LIST[cpu_id]
+        .lock()
+        .msgs()
+        .any(|msg| msg.ipi_message.vm_id() == Some(vm_id))
+}
+
 fn ipi_irq_handler() {
+    let _tag = HeapTagGuard::new(HeapTag::Ipi);
     let cpu_id = current_cpu().id;
 
     while let Some(ipi_msg) = ipi_pop_message(cpu_id) {
         let ipi_type = ipi_msg.ipi_type;
+        event_trace(TraceEvent::IpiHandle, ipi_type as usize, 0);
 
         if let Some(handler) = IPI_HANDLER_LIST.get(ipi_type as usize) {
             handler(ipi_msg);
@@ -217,39 +365,122 @@ fn ipi_irq_handler() {
     }
 }
 
-fn ipi_send(target_id: usize, msg: IpiMessage) -> bool {
+// on failure, the message is given back for retrying
+fn ipi_send(target_id: usize, msg: IpiMessage) -> Result<(), (IpiError, IpiMessage)> {
     if target_id >= PLAT_DESC.cpu_desc.num {
         error!("ipi_send: core {} not exist", target_id);
-        return false;
+        return Err((IpiError::InvalidTarget, msg));
     }
-
-    CPU_IF_LIST[target_id].lock().push(msg);
+    // the queue is only drained by the ipi irq, a message to self would be handled after what the caller does next
+    debug_assert_ne!(
+        target_id,
+        current_cpu().id,
+        "ipi_send: {:?} ipi to self, call the handler directly",
+        msg.ipi_type
+    );
+
+    // the target may be waiting for a VM lock held here, see `VmMutex`
+    super::assert_no_vm_lock("sends an ipi");
+
+    let ipi_type = msg.ipi_type;
+    CPU_IF_LIST[target_id]
+        .lock()
+        .push(msg)
+        .map_err(|msg| (IpiError::QueueFull, msg))?;
+    event_trace(TraceEvent::IpiSend, ipi_type as usize, target_id);
     interrupt_cpu_ipi_send(target_id, INTERRUPT_IRQ_IPI);
 
-    true
+    Ok(())
+}
+
+fn ipi_drop(target_id: usize, ipi_type: IpiType) {
+    CPU_IF_LIST[target_id].lock().queues[ipi_type as usize].dropped += 1;
+    warn!("ipi_send: queue of core {} is full, drop {:?} ipi", target_id, ipi_type);
 }
 
-pub fn ipi_send_msg(target_id: usize, ipi_type: IpiType, ipi_message: IpiInnerMsg) -> bool {
+// fire-and-forget, a message to a full queue is dropped and counted
+pub fn ipi_send_msg(target_id: usize, ipi_type: IpiType, ipi_message: IpiInnerMsg) -> Result<(), IpiError> {
     let msg = IpiMessage { ipi_type, ipi_message };
-    ipi_send(target_id, msg)
+    ipi_send(target_id, msg).map_err(|(err, _)| {
+        if err == IpiError::QueueFull {
+            ipi_drop(target_id, ipi_type);
+        }
+        err
+    })
 }
 
-pub fn ipi_intra_broadcast_msg(vm: &Vm, ipi_type: IpiType, msg: IpiInnerMsg) -> bool {
-    let mut i = 0;
-    let mut n = 0;
-    while n < (vm.cpu_num() - 1) {
-        if ((1 << i) & vm.ncpu()) != 0 && i != current_cpu().id {
-            n += 1;
-            if !ipi_send_msg(i, ipi_type, msg.clone()) {
-                error!(
-                    "ipi_intra_broadcast_msg: Failed to send ipi request, cpu {} type {}",
-                    i, ipi_type as usize
-                );
-                return false;
-            }
+/* Like `ipi_send_msg`, but wait with a bounded backoff while the queue of the target is full.
+ * For messages whose loss leaves an operation half-done, e.g. vmm, power and mediated notify.
+ */
+pub fn ipi_send_msg_retry(target_id: usize, ipi_type: IpiType, ipi_message: IpiInnerMsg) -> Result<(), IpiError> {
+    let mut msg = IpiMessage { ipi_type, ipi_message };
+    let mut backoff = IPI_RETRY_BACKOFF_US;
+    for _ in 1..IPI_RETRY_MAX {
+        match ipi_send(target_id, msg) {
+            Ok(()) => return Ok(()),
+            Err((IpiError::QueueFull, m)) => msg = m,
+            Err((err, _)) => return Err(err),
         }
+        sleep(backoff);
+        backoff *= 2;
+    }
+    ipi_send(target_id, msg).map_err(|(err, _)| {
+        if err == IpiError::QueueFull {
+            error!("ipi_send_msg_retry: core {} does not handle its ipis", target_id);
+            ipi_drop(target_id, ipi_type);
+        }
+        err
+    })
+}
+
+// copy the pending and dropped ipi counts of every core to `stat_ipa`
+pub fn ipi_stat(stat_ipa: usize) -> Result<usize, ()> {
+    let num = PLAT_DESC.cpu_desc.num;
+    let stat_hva = vm_ipa2hva(&active_vm().unwrap(), stat_ipa, num * size_of::<IpiStat>()).map_err(|_| ())?;
+    let stat_list = unsafe { core::slice::from_raw_parts_mut(stat_hva as *mut IpiStat, num) };
+    for (cpu_if, stat) in CPU_IF_LIST.iter().zip(stat_list.iter_mut()) {
+        let cpu_if = cpu_if.lock();
+        *stat = IpiStat {
+            pending: cpu_if.queues.iter().map(|queue| queue.msgs.len()).sum(),
+            dropped: cpu_if.queues.iter().map(|queue| queue.dropped).sum(),
+        };
+    }
+    Ok(num)
+}
 
-        i += 1;
+/* Copy the counts of each ipi type of core `cpu_id` to `stat_ipa`, IPI_TYPE_NUM `IpiTypeStat` in
+ * the order of `IpiType`, return IPI_TYPE_NUM.
+ */
+pub fn ipi_type_stat(stat_ipa: usize, cpu_id: usize) -> Result<usize, ()> {
+    let cpu_if = match CPU_IF_LIST.get(cpu_id) {
+        Some(cpu_if) if cpu_id < PLAT_DESC.cpu_desc.num => cpu_if,
+        _ => return Err(()),
+    };
+    let stat_hva =
+        vm_ipa2hva(&active_vm().unwrap(), stat_ipa, IPI_TYPE_NUM * size_of::<IpiTypeStat>()).map_err(|_| ())?;
+    let stat_list = unsafe { core::slice::from_raw_parts_mut(stat_hva as *mut IpiTypeStat, IPI_TYPE_NUM) };
+    let cpu_if = cpu_if.lock();
+    for (queue, stat) in cpu_if.queues.iter().zip(stat_list.iter_mut()) {
+        *stat = IpiTypeStat {
+            pending: queue.msgs.len(),
+            dropped: queue.dropped,
+            max_depth: queue.max_depth,
+            coalesced: queue.coalesced,
+        };
+    }
+    Ok(IPI_TYPE_NUM)
+}
+
+// send `msg` to the other cores holding vcpus of `vm`, once per core
+pub fn ipi_intra_broadcast_msg(vm: &Vm, ipi_type: IpiType, msg: IpiInnerMsg) -> bool {
+    for i in vm.pcpu_list().filter(|&i| i != current_cpu().id) {
+        if let Err(err) = ipi_send_msg_retry(i, ipi_type, msg.clone()) {
+            error!(
+                "ipi_intra_broadcast_msg: Failed to send ipi request, cpu {} type {}: {:?}",
+                i, ipi_type as usize, err
+            );
+            return false;
+        }
     }
     true
 }
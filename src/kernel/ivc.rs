@@ -1,18 +1,36 @@
-use crate::arch::PAGE_SIZE;
-use crate::kernel::{active_vm, current_cpu, vm_if_set_ivc_arg, vm_if_set_ivc_arg_ptr};
+use core::mem::size_of;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::arch::{GIC_INTS_MAX, GIC_PRIVINT_NUM, PAGE_SIZE, PTE_S2_NORMAL};
+use crate::kernel::access::vm_ipa2hva;
+use crate::kernel::{
+    active_vm, current_cpu, hvc_send_msg_to_vm, interrupt_vm_inject_to, mem_pages_alloc, vm_by_id, vm_if_set_ivc_arg,
+    vm_if_set_ivc_arg_ptr, HvcGuestMsg, HvcIvcShareMsg, Vm, HVC_IVC, HVC_IVC_SHARE_MEM,
+};
+use crate::mm::{PageFrame, PageUsage};
 
 use shyper::VM_NUM_MAX;
 
 pub fn ivc_update_mq(receive_ipa: usize, cfg_ipa: usize) -> bool {
     let vm = active_vm().unwrap();
     let vm_id = vm.id();
-    let receive_pa = vm.ipa2hva(receive_ipa);
-    let cfg_pa = vm.ipa2hva(cfg_ipa);
-
-    if receive_pa == 0 {
-        error!("ivc_update_mq: invalid receive_pa");
+    // the message queue is one page, see `hvc_mailbox_write`
+    if vm_ipa2hva(&vm, receive_ipa, 1).is_err() {
+        error!("ivc_update_mq: invalid receive_ipa {:#x}", receive_ipa);
         return false;
     }
+    let cfg_pa = match vm_ipa2hva(&vm, cfg_ipa, PAGE_SIZE) {
+        Ok(hva) => hva,
+        Err(_) => {
+            error!("ivc_update_mq: invalid cfg_ipa {:#x}", cfg_ipa);
+            return false;
+        }
+    };
 
     vm_if_set_ivc_arg(vm_id, cfg_pa);
     vm_if_set_ivc_arg_ptr(vm_id, cfg_pa - PAGE_SIZE / VM_NUM_MAX);
@@ -31,3 +49,250 @@ pub fn shyper_init(vmid: usize, base_ipa: usize, len: usize) -> bool {
     }
     false
 }
+
+// Upper bound of pages in one shared memory channel
+const IVC_SHARE_MEM_PAGE_MAX: usize = 1024;
+
+struct IvcChannel {
+    vm_ids: [usize; 2],
+    ipa: [usize; 2],
+    irq: usize,
+    frame: PageFrame,
+}
+
+impl IvcChannel {
+    fn len(&self) -> usize {
+        self.frame.page_num * PAGE_SIZE
+    }
+
+    // return the side index of this vm in the channel
+    fn side(&self, vm_id: usize) -> Option<usize> {
+        self.vm_ids.iter().position(|id| *id == vm_id)
+    }
+}
+
+#[repr(C)]
+pub struct IvcChannelInfo {
+    pub id: usize,
+    pub vm_ids: [usize; 2],
+    pub ipa: [usize; 2],
+    pub page_num: usize,
+    pub irq: usize,
+}
+
+static IVC_CHANNEL_ID: AtomicUsize = AtomicUsize::new(0);
+static IVC_CHANNEL_LIST: Mutex<BTreeMap<usize, IvcChannel>> = Mutex::new(BTreeMap::new());
+
+fn ivc_ipa_conflict(channels: &BTreeMap<usize, IvcChannel>, vm: &Vm, ipa: usize, len: usize) -> bool {
+    let range = ipa..ipa + len;
+    let overlap = |other: Range<usize>| range.start < other.end && other.start < range.end;
+    vm.memory_regions().iter().any(|region| overlap(region.as_range())) || ivc_channel_overlap(channels, vm.id(), range)
+}
+
+fn ivc_channel_overlap(channels: &BTreeMap<usize, IvcChannel>, vm_id: usize, range: Range<usize>) -> bool {
+    let overlap = |other: Range<usize>| range.start < other.end && other.start < range.end;
+    channels.values().any(|channel| match channel.side(vm_id) {
+        Some(side) => overlap(channel.ipa[side]..channel.ipa[side] + channel.len()),
+        None => false,
+    })
+}
+
+// check if `range` of VM `vm_id` overlaps with any of its shared memory channels
+pub fn ivc_ipa_overlap(vm_id: usize, range: Range<usize>) -> bool {
+    ivc_channel_overlap(&IVC_CHANNEL_LIST.lock(), vm_id, range)
+}
+
+// share `page_num` pages between current VM (at `local_ipa`) and VM `peer_id` (at `peer_ipa`),
+// `irq` is injected into the other side when a doorbell is rung. Return the channel id.
+pub fn ivc_share_mem(
+    peer_id: usize,
+    page_num: usize,
+    local_ipa: usize,
+    peer_ipa: usize,
+    irq: usize,
+) -> Result<usize, ()> {
+    let vm = active_vm().unwrap();
+    let peer = match vm_by_id(peer_id) {
+        Some(peer) if peer_id != vm.id() => peer,
+        _ => {
+            error!("ivc_share_mem: VM {} illegal peer VM {}", vm.id(), peer_id);
+            return Err(());
+        }
+    };
+    if page_num == 0 || page_num > IVC_SHARE_MEM_PAGE_MAX {
+        error!("ivc_share_mem: illegal page num {}", page_num);
+        return Err(());
+    }
+    if local_ipa % PAGE_SIZE != 0 || peer_ipa % PAGE_SIZE != 0 {
+        error!("ivc_share_mem: ipa {local_ipa:#x} or {peer_ipa:#x} is not aligned to page");
+        return Err(());
+    }
+    if !(GIC_PRIVINT_NUM..GIC_INTS_MAX).contains(&irq) || vm.has_interrupt(irq) || peer.has_interrupt(irq) {
+        error!("ivc_share_mem: doorbell irq {} is not a free SPI", irq);
+        return Err(());
+    }
+
+    let len = page_num * PAGE_SIZE;
+    let mut channels = IVC_CHANNEL_LIST.lock();
+    if ivc_ipa_conflict(&channels, &vm, local_ipa, len) || ivc_ipa_conflict(&channels, &peer, peer_ipa, len) {
+        error!(
+            "ivc_share_mem: ipa {:#x} (VM {}) or {:#x} (VM {}) conflicts with existing memory",
+            local_ipa,
+            vm.id(),
+            peer_ipa,
+            peer_id
+        );
+        return Err(());
+    }
+    let frame = match mem_pages_alloc(page_num, PageUsage::Ivc) {
+        Ok(frame) => frame,
+        Err(err) => {
+            error!("ivc_share_mem: alloc {} pages failed {:?}", page_num, err);
+            return Err(());
+        }
+    };
+    vm.pt_map_range(local_ipa, len, frame.pa(), PTE_S2_NORMAL, false);
+    peer.pt_map_range(peer_ipa, len, frame.pa(), PTE_S2_NORMAL, false);
+
+    let id = IVC_CHANNEL_ID.fetch_add(1, Ordering::Relaxed);
+    info!(
+        "ivc channel {}: VM {} ipa {:#x} <-> VM {} ipa {:#x}, {} pages, irq {}",
+        id,
+        vm.id(),
+        local_ipa,
+        peer_id,
+        peer_ipa,
+        page_num,
+        irq
+    );
+    channels.insert(
+        id,
+        IvcChannel {
+            vm_ids: [vm.id(), peer_id],
+            ipa: [local_ipa, peer_ipa],
+            irq,
+            frame,
+        },
+    );
+    drop(channels);
+
+    // the peer learns the channel from its mailbox, or asks for it with HVC_IVC_GET_SHARED_MEM_IPA
+    let msg = HvcIvcShareMsg {
+        fid: HVC_IVC,
+        event: HVC_IVC_SHARE_MEM,
+        id,
+        peer_vm_id: vm.id(),
+        ipa: peer_ipa,
+        page_num,
+        irq,
+    };
+    if !hvc_send_msg_to_vm(peer_id, &HvcGuestMsg::IvcShare(msg)) {
+        warn!("ivc_share_mem: failed to notify VM {} of channel {}", peer_id, id);
+    }
+    Ok(id)
+}
+
+// the ipa channel `id` is mapped at in the current VM
+pub fn ivc_share_mem_ipa(id: usize) -> Result<usize, ()> {
+    let vm_id = active_vm().unwrap().id();
+    let channels = IVC_CHANNEL_LIST.lock();
+    match channels
+        .get(&id)
+        .and_then(|channel| channel.side(vm_id).map(|side| channel.ipa[side]))
+    {
+        Some(ipa) => Ok(ipa),
+        None => {
+            error!("ivc_share_mem_ipa: VM {} is not in channel {}", vm_id, id);
+            Err(())
+        }
+    }
+}
+
+// ring the doorbell of the other side of channel `id`
+pub fn ivc_send_doorbell(id: usize) -> Result<usize, ()> {
+    let vm_id = active_vm().unwrap().id();
+    let (peer_id, irq) = {
+        let channels = IVC_CHANNEL_LIST.lock();
+        match channels
+            .get(&id)
+            .and_then(|channel| Some((channel, channel.side(vm_id)?)))
+        {
+            Some((channel, side)) => (channel.vm_ids[1 - side], channel.irq),
+            None => {
+                error!("ivc_send_doorbell: VM {} is not in channel {}", vm_id, id);
+                return Err(());
+            }
+        }
+    };
+    let peer = vm_by_id(peer_id).ok_or(())?;
+    if !interrupt_vm_inject_to(&peer, peer.vcpu(0).unwrap(), irq) {
+        return Err(());
+    }
+    Ok(0)
+}
+
+fn ivc_channel_teardown(id: usize, channel: IvcChannel) {
+    for (vm_id, ipa) in channel.vm_ids.iter().zip(channel.ipa.iter()) {
+        if let Some(vm) = vm_by_id(*vm_id) {
+            vm.pt_unmap_range(*ipa, channel.len());
+        }
+    }
+    info!("ivc channel {} between VM {:?} closed", id, channel.vm_ids);
+    // the shared pages are freed when the frame drops
+}
+
+// close channel `id`, either side of the channel can close it
+pub fn ivc_close_share_mem(id: usize) -> Result<usize, ()> {
+    let vm_id = active_vm().unwrap().id();
+    let mut channels = IVC_CHANNEL_LIST.lock();
+    match channels.get(&id).and_then(|channel| channel.side(vm_id)) {
+        Some(_) => {
+            let channel = channels.remove(&id).unwrap();
+            drop(channels);
+            ivc_channel_teardown(id, channel);
+            Ok(0)
+        }
+        None => {
+            error!("ivc_close_share_mem: VM {} is not in channel {}", vm_id, id);
+            Err(())
+        }
+    }
+}
+
+// close all the channels of a VM which is being removed
+pub fn ivc_remove_vm_channels(vm_id: usize) {
+    let mut channels = IVC_CHANNEL_LIST.lock();
+    let removed: Vec<_> = channels
+        .extract_if(|_, channel| channel.side(vm_id).is_some())
+        .collect();
+    drop(channels);
+    for (id, channel) in removed {
+        ivc_channel_teardown(id, channel);
+    }
+}
+
+// VM0 only: write at most `max` `IvcChannelInfo` to `info_ipa`, return the number of channels written
+pub fn ivc_list_share_mem(info_ipa: usize, max: usize) -> Result<usize, ()> {
+    let vm = active_vm().unwrap();
+    if vm.id() != 0 {
+        error!("ivc_list_share_mem: VM {} is not allowed to list channels", vm.id());
+        return Err(());
+    }
+    let channels = IVC_CHANNEL_LIST.lock();
+    let num = usize::min(max, channels.len());
+    if num == 0 {
+        return Ok(0);
+    }
+    let info_hva = vm_ipa2hva(&vm, info_ipa, num * size_of::<IvcChannelInfo>()).map_err(|_| ())?;
+    let info_list = unsafe { core::slice::from_raw_parts_mut(info_hva as *mut IvcChannelInfo, num) };
+    for (info, (id, channel)) in info_list.iter_mut().zip(channels.iter()) {
+        *info = IvcChannelInfo {
+            id: *id,
+            vm_ids: channel.vm_ids,
+            ipa: channel.ipa,
+            page_num: channel.frame.page_num,
+            irq: channel.irq,
+        };
+    }
+    Ok(num)
+}
@@ -1,6 +1,7 @@
 use core::mem::size_of;
 use core::ops::RangeInclusive;
 
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use spin::{Mutex, Once};
 
@@ -9,9 +10,13 @@ use crate::arch::{
     PTE_S1_DEVICE, PTE_S1_NORMAL,
 };
 use crate::board::*;
-use crate::kernel::Cpu;
+use crate::kernel::access::vm_ipa2hva;
+use crate::kernel::{active_vm, vm_list_walker, Cpu, Vm, CONFIG_VM_NUM_MAX};
 use crate::mm::vpage_allocator::{vpage_alloc, AllocatedPages, CPU_BANKED_ADDRESS};
-use crate::mm::{PageFrame, _image_end, _image_start, heap_expansion};
+use crate::mm::{
+    heap_stat, heap_tag_stats, HeapStat, HeapTagStat, PageFrame, PageUsage, _image_end, _image_start, heap_expansion,
+    HEAP_TAG_NUM,
+};
 use crate::util::{barrier, reset_barrier, round_up};
 
 use super::{current_cpu, CPU_MASTER};
@@ -28,47 +33,78 @@ pub fn physical_mem_init() {
 pub enum AllocError {
     AllocZeroPage,
     OutOfFrame(usize),
+    // `requested` pages of `color` are needed, only `available` of them are free
+    OutOfColor {
+        color: usize,
+        requested: usize,
+        available: usize,
+    },
 }
 
-pub fn mem_page_alloc() -> Result<PageFrame, AllocError> {
-    PageFrame::alloc_pages(1)
+pub fn mem_page_alloc(usage: PageUsage) -> Result<PageFrame, AllocError> {
+    PageFrame::alloc_pages(1, usage)
 }
 
-#[allow(dead_code)]
-pub fn mem_pages_alloc(page_num: usize) -> Result<PageFrame, AllocError> {
-    PageFrame::alloc_pages(page_num)
+pub fn mem_pages_alloc(page_num: usize, usage: PageUsage) -> Result<PageFrame, AllocError> {
+    PageFrame::alloc_pages(page_num, usage)
 }
 
-#[derive(Clone, Debug)]
+/* Copy the hypervisor heap usage to MVM.
+ *
+ * @param[in] stat_ipa: the ipa of a HeapStat in MVM.
+ */
+pub fn mem_heap_stat(stat_ipa: usize) -> Result<usize, ()> {
+    let stat_hva = vm_ipa2hva(&active_vm().unwrap(), stat_ipa, size_of::<HeapStat>()).map_err(|_| ())?;
+    unsafe { *(stat_hva as *mut HeapStat) = heap_stat() };
+    Ok(0)
+}
+
+/* Copy the heap usage of each HeapTag to MVM, only counted with the heap-tag feature.
+ *
+ * @param[in] stat_ipa: the ipa of an array of HEAP_TAG_NUM HeapTagStat in MVM.
+ */
+pub fn mem_heap_tag_stat(stat_ipa: usize) -> Result<usize, ()> {
+    if !cfg!(feature = "heap-tag") {
+        error!("mem_heap_tag_stat: the heap is not accounted by tag");
+        return Err(());
+    }
+    let stat_hva = vm_ipa2hva(
+        &active_vm().unwrap(),
+        stat_ipa,
+        size_of::<[HeapTagStat; HEAP_TAG_NUM]>(),
+    )
+    .map_err(|_| ())?;
+    unsafe { *(stat_hva as *mut [HeapTagStat; HEAP_TAG_NUM]) = heap_tag_stats() };
+    Ok(HEAP_TAG_NUM)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ColorMemRegion {
     pub color: usize,
     pub base: usize,
     pub count: usize,
     pub step: usize,
-    available: bool,
+    // the cluster this memory is close to, see `PlatMemoryConfig::region_affinity`
+    pub affinity: usize,
 }
 
 impl ColorMemRegion {
-    fn new(color: usize, base: usize, count: usize, step: usize) -> Self {
+    pub(crate) fn new(color: usize, base: usize, count: usize, step: usize, affinity: usize) -> Self {
         Self {
             color,
             base,
             count,
             step,
-            available: true,
+            affinity,
         }
     }
 
     fn left_neighbor(&self, other: &Self) -> bool {
-        self.base + self.count * self.step == other.base
-    }
-
-    fn is_available(&self) -> bool {
-        self.available
+        self.base + self.count * self.step == other.base && self.affinity == other.affinity
     }
 
-    fn mark_available(&mut self, state: bool) {
-        self.available = state;
+    fn end(&self) -> usize {
+        self.base + self.count * self.step
     }
 
     #[allow(dead_code)]
@@ -81,135 +117,317 @@ impl ColorMemRegion {
         (self.base..self.base + self.count * self.step).contains(addr) && (addr - self.base) % self.step == 0
     }
 
+    // give the page at `addr` back to the free memory, and keep the pages left of it in `self`
     #[allow(dead_code)]
     pub fn split(&mut self, addr: usize) -> Option<Self> {
-        let color_region = ColorMemRegion::new(self.color, addr, 1, self.step);
-        MEM_REGION_BY_COLOR
-            .lock()
-            .get_mut(self.color)
-            .unwrap()
-            .push(color_region);
-        if self.contains(&addr) {
-            let left_count = (addr - self.base) / self.step;
-            let right_count = self.count - left_count - 1;
-            let right_base = addr + self.step;
-            if left_count == 0 {
-                self.count = right_count;
-                self.base = right_base;
-                None
-            } else if right_count == 0 {
-                self.count = left_count;
-                None
-            } else {
-                self.count = left_count;
-                Some(Self {
-                    color: self.color,
-                    base: right_base,
-                    count: right_count,
-                    step: self.step,
-                    available: false,
-                })
-            }
-        } else {
+        if !self.contains(&addr) {
+            return None;
+        }
+        color_pool_free(
+            &mut MEM_REGION_BY_COLOR.lock(),
+            ColorMemRegion::new(self.color, addr, 1, self.step, self.affinity),
+        );
+        let left_count = (addr - self.base) / self.step;
+        let right_count = self.count - left_count - 1;
+        let right_base = addr + self.step;
+        if left_count == 0 {
+            self.count = right_count;
+            self.base = right_base;
+            None
+        } else if right_count == 0 {
+            self.count = left_count;
             None
+        } else {
+            self.count = left_count;
+            Some(ColorMemRegion::new(
+                self.color,
+                right_base,
+                right_count,
+                self.step,
+                self.affinity,
+            ))
         }
     }
 }
 
+/* The free memory of each color, sorted by base, the regions next to each other are merged.
+ * The memory allocated to a VM or the hypervisor is not in it until it is freed.
+ */
 static MEM_REGION_BY_COLOR: Mutex<Vec<Vec<ColorMemRegion>>> = Mutex::new(Vec::new());
 
-pub fn mem_region_alloc_colors(size: usize, color_bitmap: usize) -> Result<Vec<ColorMemRegion>, AllocError> {
-    // hold the lock until return
-    let mut mem_region_by_color = MEM_REGION_BY_COLOR.lock();
-    let color_bitmap = color_bitmap & ((1 << mem_region_by_color.len()) - 1);
-    info!("alloc {:#x}B in colors {:#x}", size, color_bitmap);
-    let count = color_bitmap.count_ones() as usize;
-    if count == 0 {
-        error!("no cache color provided");
-        return Err(AllocError::AllocZeroPage);
+/* The color, the pages asked and the pages free of it in the last colored allocation of each VM
+ * that failed, a VM that runs out and is taken down does not hide the failure of another one.
+ */
+static COLOR_ALLOC_FAIL: Mutex<[Option<(usize, usize, usize)>; CONFIG_VM_NUM_MAX]> =
+    Mutex::new([None; CONFIG_VM_NUM_MAX]);
+
+// the cluster of a physical cpu, Aff1 of its MPIDR
+fn cpu_affinity(cpu_id: usize) -> usize {
+    PLAT_DESC
+        .cpu_desc
+        .core_list
+        .get(cpu_id)
+        .map_or(0, |core| (core.mpidr >> 8) & 0xff)
+}
+
+/* The cluster holding most of the cpus in `cpu_bitmap`, None if the platform memory is uniform.
+ * On a tie the lower cluster wins.
+ */
+pub fn mem_affinity_of_cpus(cpu_bitmap: usize) -> Option<usize> {
+    if PLAT_DESC.mem_desc.region_affinity.is_empty() {
+        return None;
     }
-    let page_num = round_up(size, PAGE_SIZE) / PAGE_SIZE;
+    let mut cpu_count = BTreeMap::new();
+    for cpu_id in (0..PLAT_DESC.cpu_desc.num).filter(|cpu_id| cpu_bitmap & (1 << cpu_id) != 0) {
+        *cpu_count.entry(cpu_affinity(cpu_id)).or_insert(0) += 1;
+    }
+    cpu_count
+        .into_iter()
+        .max_by(|(a, a_num), (b, b_num)| a_num.cmp(b_num).then(b.cmp(a)))
+        .map(|(affinity, _)| affinity)
+}
 
-    let color2pages = {
-        // init a vec, contains color -> page_num, init value is the free page num
-        let mut color2pages = vec![];
-        // get the color list, sum free space in these colors
-        let mut free_pages = 0;
-        for (color, region_list) in mem_region_by_color.iter().enumerate() {
-            if color_bitmap & (1 << color) != 0 {
-                let color_free = region_list
-                    .iter()
-                    .filter(|region| region.is_available())
-                    .map(|region| region.count)
-                    .sum();
-                free_pages += color_free;
-                // here, we only use color and free to record a color's free page num
-                color2pages.push(ColorMemRegion::new(color, 0, color_free, 0));
-            } else if color_bitmap < (1 << color) {
-                break;
-            }
+// free pages of each color in `color_bitmap`, counting only the regions accepted by `filter`
+fn color_free_pages(
+    mem_region_by_color: &[Vec<ColorMemRegion>],
+    color_bitmap: usize,
+    filter: &dyn Fn(&ColorMemRegion) -> bool,
+) -> Vec<ColorMemRegion> {
+    let mut color2pages = vec![];
+    for (color, region_list) in mem_region_by_color.iter().enumerate() {
+        if color_bitmap & (1 << color) != 0 {
+            let color_free = region_list
+                .iter()
+                .filter(|region| filter(region))
+                .map(|region| region.count)
+                .sum();
+            // here, we only use color and free to record a color's free page num
+            color2pages.push(ColorMemRegion::new(color, 0, color_free, 0, 0));
+        } else if color_bitmap < (1 << color) {
+            break;
         }
-        // if free pages not satisfy, return error
-        if free_pages < page_num {
-            error!("free pages not satisfy");
-            return Err(AllocError::OutOfFrame(page_num));
+    }
+    color2pages
+}
+
+fn sort_color_list(color2pages: &mut [ColorMemRegion]) {
+    // free pages ascending order (small->large)
+    // if equals, color ascending order
+    color2pages.sort_by(|a, b| {
+        if a.count.ne(&b.count) {
+            a.count.cmp(&b.count)
+        } else {
+            a.color.cmp(&b.color)
         }
+    });
+}
 
-        fn sort_color_list(color2pages: &mut [ColorMemRegion]) {
-            // free pages ascending order (small->large)
-            // if equals, color ascending order
-            color2pages.sort_by(|a, b| {
-                if a.count.ne(&b.count) {
-                    a.count.cmp(&b.count)
+/* Take `size` pages from the free regions of a color accepted by `filter`: the smallest region holding
+ * all of them, or else the largest regions one after the other, so that the large runs last.
+ */
+fn color_take(
+    region_list: &mut Vec<ColorMemRegion>,
+    mut size: usize,
+    filter: &dyn Fn(&ColorMemRegion) -> bool,
+) -> Vec<ColorMemRegion> {
+    let mut taken = vec![];
+    while size > 0 {
+        let idx = match region_list
+            .iter()
+            .enumerate()
+            .filter(|(_, region)| filter(region))
+            .min_by_key(|(_, region)| {
+                if region.count >= size {
+                    (false, region.count)
                 } else {
-                    a.color.cmp(&b.color)
+                    (true, usize::MAX - region.count)
                 }
-            });
+            }) {
+            Some((idx, _)) => idx,
+            None => break,
+        };
+        let region = &mut region_list[idx];
+        let count = usize::min(region.count, size);
+        taken.push(ColorMemRegion::new(
+            region.color,
+            region.base,
+            count,
+            region.step,
+            region.affinity,
+        ));
+        region.base += count * region.step;
+        region.count -= count;
+        if region.is_empty() {
+            region_list.remove(idx);
         }
+        size -= count;
+    }
+    taken
+}
+
+// take `page_num` pages spread over the colors of `color2pages`, from the regions accepted by `filter`
+fn color_region_alloc(
+    mem_region_by_color: &mut [Vec<ColorMemRegion>],
+    mut color2pages: Vec<ColorMemRegion>,
+    page_num: usize,
+    filter: &dyn Fn(&ColorMemRegion) -> bool,
+) -> Vec<ColorMemRegion> {
+    if page_num == 0 {
+        return vec![];
+    }
+
+    let count = color2pages.len();
+    sort_color_list(&mut color2pages);
+    // determine to alloc how many pages in a color
+    // **Greedy**, because color2pages ascending order by free pages
+    let mut remaining_pages = page_num;
+    for (i, region) in color2pages.iter_mut().enumerate() {
+        let color_size = remaining_pages / (count - i);
+        region.count = usize::min(region.count, color_size);
+        remaining_pages -= region.count;
+    }
+    debug_assert_eq!(remaining_pages, 0);
 
-        sort_color_list(&mut color2pages);
-        // determine to alloc how many pages in a color
-        // **Greedy**, because color2pages ascending order by free pages
-        let mut remaining_pages = page_num;
-        for (i, region) in color2pages.iter_mut().enumerate() {
-            let color_size = remaining_pages / (count - i);
-            region.count = usize::min(region.count, color_size);
-            remaining_pages -= region.count;
-        }
-        debug_assert_eq!(remaining_pages, 0);
-        sort_color_list(&mut color2pages);
-        color2pages
-    };
     let mut vm_regions: Vec<ColorMemRegion> = vec![];
+    for region in color2pages.iter().filter(|region| !region.is_empty()) {
+        let color_region_list = mem_region_by_color.get_mut(region.color).unwrap();
+        vm_regions.append(&mut color_take(color_region_list, region.count, filter));
+    }
+    sort_color_list(&mut vm_regions);
+    vm_regions
+}
 
-    for region in color2pages.iter() {
-        let color = region.color;
-        let size = region.count;
-        let color_region_list = mem_region_by_color.get_mut(color).unwrap();
-
-        let mut tmp = vec![];
-        for exist_region in color_region_list.iter_mut() {
-            if exist_region.is_available() && exist_region.count >= size {
-                exist_region.mark_available(false);
-                // if still space remains
-                if exist_region.count > size {
-                    // add to the end of the color region list
-                    tmp.push(ColorMemRegion::new(
-                        color,
-                        exist_region.base + size * exist_region.step,
-                        exist_region.count - size,
-                        exist_region.step,
-                    ));
-                    exist_region.count = size;
-                }
-                vm_regions.push(exist_region.clone());
-                break;
-            }
+/* The color short of pages when `page_num` pages are spread over the colors of `color2pages` with the
+ * free pages of each color in its count, with the pages it gets asked and the pages it has.
+ */
+fn color_shortage(mut color2pages: Vec<ColorMemRegion>, page_num: usize) -> Option<(usize, usize, usize)> {
+    sort_color_list(&mut color2pages);
+    let count = color2pages.len();
+    let mut remaining_pages = page_num;
+    for (i, region) in color2pages.iter().enumerate() {
+        let color_size = remaining_pages / (count - i);
+        if region.count < color_size {
+            return Some((region.color, color_size, region.count));
+        }
+        remaining_pages -= color_size;
+    }
+    None
+}
+
+/* Allocate `page_num` pages in the colors of `color_bitmap` out of the free regions `mem_region_by_color`,
+ * see `mem_region_alloc_colors_near`, the test pools of the self test go through it too.
+ */
+pub(crate) fn color_pool_alloc(
+    mem_region_by_color: &mut [Vec<ColorMemRegion>],
+    page_num: usize,
+    color_bitmap: usize,
+    affinity: Option<usize>,
+) -> Result<(Vec<ColorMemRegion>, Vec<ColorMemRegion>), AllocError> {
+    let local = |region: &ColorMemRegion| affinity.map_or(true, |affinity| region.affinity == affinity);
+    let remote = |region: &ColorMemRegion| !local(region);
+    let local_pages = color_free_pages(mem_region_by_color, color_bitmap, &local);
+    let remote_pages = color_free_pages(mem_region_by_color, color_bitmap, &remote);
+    let local_free: usize = local_pages.iter().map(|region| region.count).sum();
+    let remote_free: usize = remote_pages.iter().map(|region| region.count).sum();
+    // if free pages not satisfy, return the color that runs out
+    if local_free + remote_free < page_num {
+        let all_pages = color_free_pages(mem_region_by_color, color_bitmap, &|_| true);
+        let (color, requested, available) = color_shortage(all_pages, page_num).unwrap_or((0, page_num, 0));
+        return Err(AllocError::OutOfColor {
+            color,
+            requested,
+            available,
+        });
+    }
+    let local_num = usize::min(local_free, page_num);
+    if local_num < page_num {
+        warn!(
+            "alloc {:#x} pages from regions remote to cluster {:?}",
+            page_num - local_num,
+            affinity
+        );
+    }
+    let local_regions = color_region_alloc(mem_region_by_color, local_pages, local_num, &local);
+    let remote_regions = color_region_alloc(mem_region_by_color, remote_pages, page_num - local_num, &remote);
+    Ok((local_regions, remote_regions))
+}
+
+// give `region` back to the free regions of its color, merged with the free regions next to it
+pub(crate) fn color_pool_free(mem_region_by_color: &mut [Vec<ColorMemRegion>], region: ColorMemRegion) {
+    if region.is_empty() {
+        return;
+    }
+    let color_region_list = mem_region_by_color.get_mut(region.color).unwrap();
+    let idx = color_region_list.partition_point(|exist_region| exist_region.base < region.base);
+    let prev_overlap = idx > 0 && color_region_list[idx - 1].end() > region.base;
+    let next_overlap = color_region_list
+        .get(idx)
+        .map_or(false, |next| next.base < region.end());
+    if prev_overlap || next_overlap {
+        error!("color_pool_free: {:x?} is already free", region);
+        return;
+    }
+    let merge_prev = idx > 0 && color_region_list[idx - 1].left_neighbor(&region);
+    let merge_next = color_region_list
+        .get(idx)
+        .map_or(false, |next| region.left_neighbor(next));
+    match (merge_prev, merge_next) {
+        (true, true) => {
+            let next = color_region_list.remove(idx);
+            color_region_list[idx - 1].count += region.count + next.count;
+        }
+        (true, false) => color_region_list[idx - 1].count += region.count,
+        (false, true) => {
+            let next = &mut color_region_list[idx];
+            next.base = region.base;
+            next.count += region.count;
         }
-        color_region_list.append(&mut tmp);
+        (false, false) => color_region_list.insert(idx, region),
     }
+}
 
-    Ok(vm_regions)
+pub fn mem_region_alloc_colors(size: usize, color_bitmap: usize) -> Result<Vec<ColorMemRegion>, AllocError> {
+    mem_region_alloc_colors_near(None, size, color_bitmap, None).map(|(regions, _)| regions)
+}
+
+/* Allocate `size` bytes in the colors of `color_bitmap`, from the regions close to `affinity` first,
+ * the remote regions are only used when the close ones run out of these colors.
+ * Return the close regions and the remote ones, each list is sorted by count and mapped one after the
+ * other, a color short of one large run gets a few smaller regions. Without `affinity` every region is close.
+ * If the colors do not have enough free pages, the one that runs out is returned and kept for
+ * `mem_color_free_info` under `vm_id`, None for the memory of the hypervisor.
+ */
+pub fn mem_region_alloc_colors_near(
+    vm_id: Option<usize>,
+    size: usize,
+    color_bitmap: usize,
+    affinity: Option<usize>,
+) -> Result<(Vec<ColorMemRegion>, Vec<ColorMemRegion>), AllocError> {
+    // hold the lock until return
+    let mut mem_region_by_color = MEM_REGION_BY_COLOR.lock();
+    let color_bitmap = color_bitmap & ((1 << mem_region_by_color.len()) - 1);
+    info!("alloc {:#x}B in colors {:#x}", size, color_bitmap);
+    if color_bitmap.count_ones() == 0 {
+        error!("no cache color provided");
+        return Err(AllocError::AllocZeroPage);
+    }
+    let page_num = round_up(size, PAGE_SIZE) / PAGE_SIZE;
+    let result = color_pool_alloc(&mut mem_region_by_color, page_num, color_bitmap, affinity);
+    if let Err(AllocError::OutOfColor {
+        color,
+        requested,
+        available,
+    }) = &result
+    {
+        error!(
+            "alloc {:#x} pages in colors {:#x}: color {} is short, {:#x} pages requested, {:#x} free",
+            page_num, color_bitmap, color, requested, available
+        );
+        if let Some(vm_id) = vm_id.filter(|vm_id| *vm_id < CONFIG_VM_NUM_MAX) {
+            COLOR_ALLOC_FAIL.lock()[vm_id] = Some((*color, *requested, *available));
+        }
+    }
+    result
 }
 
 pub fn mem_color_region_free(vm_region: &ColorMemRegion) {
@@ -219,41 +437,171 @@ pub fn mem_color_region_free(vm_region: &ColorMemRegion) {
         vm_region.base,
         vm_region.color,
     );
-    let mut mem_region_by_color = MEM_REGION_BY_COLOR.lock();
-    let color_region_list = mem_region_by_color.get_mut(vm_region.color).unwrap();
-    // free mem region
-    let mut free_idx = None;
-    for (idx, exist_region) in color_region_list.iter_mut().enumerate() {
-        if exist_region.base == vm_region.base && !exist_region.is_available() {
-            exist_region.mark_available(true);
-            free_idx = Some(idx);
-            break;
-        }
+    color_pool_free(&mut MEM_REGION_BY_COLOR.lock(), vm_region.clone());
+}
+
+// the free pages of each color and the last colored allocation of a VM that failed, for MVM
+#[repr(C)]
+pub struct ColorFreeInfo {
+    pub num_colors: usize,
+    // usize::MAX if no colored allocation of the VM has failed
+    pub fail_color: usize,
+    pub fail_requested: usize,
+    pub fail_available: usize,
+    pub free_pages: [usize; usize::BITS as usize],
+}
+
+/**
+ * Write the free pages of each color and the last colored allocation of VM `vm_id` that failed to
+ * `info_ipa`. Return the number of colors, at most the 64 of `free_pages`.
+ *
+ * @param[in] info_ipa : ipa of a `ColorFreeInfo`.
+ * @param[in] vm_id : the VM whose failed allocation is reported.
+ */
+pub fn mem_color_free_info(info_ipa: usize, vm_id: usize) -> Result<usize, ()> {
+    if vm_id >= CONFIG_VM_NUM_MAX {
+        return Err(());
     }
-    // merge
-    while let Some(merge_idx) = free_idx {
-        free_idx = None;
-        let tmp = color_region_list.get(merge_idx).unwrap().clone();
-        for (idx, exist_region) in color_region_list.iter_mut().enumerate() {
-            if exist_region.is_available() {
-                if exist_region.left_neighbor(&tmp) {
-                    exist_region.count += tmp.count;
-                    free_idx = Some(if idx < merge_idx { idx } else { idx - 1 });
-                    color_region_list.remove(merge_idx);
-                    break;
-                } else if tmp.left_neighbor(exist_region) {
-                    let count = exist_region.count;
-                    let mut_tmp = color_region_list.get_mut(merge_idx).unwrap();
-                    mut_tmp.count += count;
-                    free_idx = Some(if merge_idx < idx { merge_idx } else { merge_idx - 1 });
-                    color_region_list.remove(idx);
-                    break;
-                }
+    let info_hva = vm_ipa2hva(&active_vm().unwrap(), info_ipa, size_of::<ColorFreeInfo>()).map_err(|_| ())?;
+    let info = unsafe { &mut *(info_hva as *mut ColorFreeInfo) };
+    let mem_region_by_color = MEM_REGION_BY_COLOR.lock();
+    info.num_colors = mem_region_by_color.len().min(usize::BITS as usize);
+    info.free_pages = [0; usize::BITS as usize];
+    for (free, region_list) in info.free_pages.iter_mut().zip(mem_region_by_color.iter()) {
+        *free = region_list.iter().map(|region| region.count).sum();
+    }
+    let (color, requested, available) = COLOR_ALLOC_FAIL.lock()[vm_id].unwrap_or((usize::MAX, 0, 0));
+    info.fail_color = color;
+    info.fail_requested = requested;
+    info.fail_available = available;
+    Ok(info.num_colors)
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct VmColorInfo {
+    pub id: usize,
+    // colors requested in the vm config
+    pub config_colors: usize,
+    // colors of the memory actually allocated to the vm
+    pub alloc_colors: usize,
+    // the cluster the memory is allocated close to, usize::MAX if the platform memory is uniform
+    pub affinity: usize,
+    // pages allocated close to `affinity` out of all colored pages, local / total is the locality ratio
+    pub local_pages: usize,
+    pub total_pages: usize,
+}
+
+#[repr(C)]
+pub struct CacheColorInfo {
+    pub level: usize,
+    pub num_colors: usize,
+    pub way_size: usize,
+    pub line_size: usize,
+    pub vm_num: usize,
+    pub vm_list: [VmColorInfo; CONFIG_VM_NUM_MAX],
+}
+
+fn llc_color_mask() -> usize {
+    let cpu_cache_info = CPU_CACHE.get().unwrap();
+    let num_colors = cpu_cache_info.info_list[cpu_cache_info.min_share_level - 1].num_colors();
+    if num_colors >= usize::BITS as usize {
+        usize::MAX
+    } else {
+        (1 << num_colors) - 1
+    }
+}
+
+/**
+ * Write the LLC geometry and the colors of each VM to `info_ipa`.
+ *
+ * @param[in] info_ipa : ipa of a `CacheColorInfo`.
+ */
+pub fn mem_color_info(info_ipa: usize) -> Result<usize, ()> {
+    let info_hva = vm_ipa2hva(&active_vm().unwrap(), info_ipa, size_of::<CacheColorInfo>()).map_err(|_| ())?;
+    let info = unsafe { &mut *(info_hva as *mut CacheColorInfo) };
+
+    let cpu_cache_info = CPU_CACHE.get().unwrap();
+    let last_level = cpu_cache_info.min_share_level;
+    let llc = &cpu_cache_info.info_list[last_level - 1];
+    info.level = last_level;
+    info.num_colors = llc.num_colors();
+    info.way_size = llc.size() / llc.ways();
+    info.line_size = llc.line_size();
+
+    let color_mask = llc_color_mask();
+    let mut idx = 0;
+    vm_list_walker(|vm| {
+        if let Some(vm_info) = info.vm_list.get_mut(idx) {
+            let (affinity, local_pages, total_pages) = vm.color_locality();
+            *vm_info = VmColorInfo {
+                id: vm.id(),
+                config_colors: vm.config().memory_color_bitmap() & color_mask,
+                alloc_colors: vm.color_bitmap(),
+                affinity: affinity.unwrap_or(usize::MAX),
+                local_pages,
+                total_pages,
+            };
+            idx += 1;
+        }
+    });
+    info.vm_num = idx;
+    Ok(0)
+}
+
+/// Check whether `vm` shares any cache color with other VMs while one of them asks for dedicated colors.
+/// Only warn about it, unless the memory config of either VM is strict.
+pub fn mem_color_check_share(vm: &Vm) -> bool {
+    let color_mask = llc_color_mask();
+    let alloc_colors = vm.color_bitmap();
+    let mut ok = true;
+    vm_list_walker(|other| {
+        if other.id() == vm.id() {
+            return;
+        }
+        let dedicated = !vm.config().memory.colors.is_empty() || !other.config().memory.colors.is_empty();
+        let shared = alloc_colors & other.color_bitmap() & color_mask;
+        if dedicated && shared != 0 {
+            warn!(
+                "VM[{}] (colors {:#x}) shares colors {:#x} with VM[{}] (colors {:#x})",
+                vm.id(),
+                vm.config().memory_color_bitmap() & color_mask,
+                shared,
+                other.id(),
+                other.config().memory_color_bitmap() & color_mask
+            );
+            if vm.config().memory.strict_colors || other.config().memory.strict_colors {
+                ok = false;
             }
         }
+    });
+    ok
+}
+
+/* The number of sets of the LLC a VM with `color_bitmap` sees, scaled by the share of the colors it
+ * owns; None if it owns all of them and gets the hardware value.
+ */
+pub fn llc_scaled_num_sets(num_sets: usize, num_colors: usize, color_bitmap: usize) -> Option<usize> {
+    let num_colors = num_colors.min(usize::BITS as usize);
+    let mask = if num_colors >= usize::BITS as usize {
+        usize::MAX
+    } else {
+        (1 << num_colors) - 1
+    };
+    let colors = (color_bitmap & mask).count_ones() as usize;
+    if colors == 0 || colors == num_colors {
+        None
+    } else {
+        Some((num_sets * colors / num_colors).max(1))
     }
 }
 
+pub fn mem_color_llc_num_sets(color_bitmap: usize) -> Option<usize> {
+    let cpu_cache_info = CPU_CACHE.get().unwrap();
+    let llc = &cpu_cache_info.info_list[cpu_cache_info.min_share_level - 1];
+    llc_scaled_num_sets(llc.num_sets(), llc.num_colors(), color_bitmap)
+}
+
 fn init_hypervisor_colors(colors: Vec<usize>) {
     HYPERVISOR_COLORS.call_once(|| colors);
 }
@@ -307,6 +655,7 @@ fn mem_region_init_by_colors() {
             warn!("PLAT_DESC.mem_desc.regions[{}] is empty.", i);
             continue;
         }
+        let affinity = PLAT_DESC.mem_desc.region_affinity.get(i).copied().unwrap_or(0);
         // NOTE: `plat_mem_region_base` might not align to `step`
         let color_mask = (num_colors - 1) << PAGE_SHIFT;
         let base_color = (plat_mem_region_base & color_mask) >> PAGE_SHIFT;
@@ -319,8 +668,8 @@ fn mem_region_init_by_colors() {
             } | (color << PAGE_SHIFT);
             let count = (plat_mem_region_size - (base - plat_mem_region_base) + step - 1) / step;
             if count > 0 {
-                let region = ColorMemRegion::new(color, base, count, step);
-                mem_region_by_color.get_mut(color).unwrap().push(region);
+                let region = ColorMemRegion::new(color, base, count, step, affinity);
+                color_pool_free(&mut mem_region_by_color, region);
             }
         }
     }
@@ -343,6 +692,65 @@ pub fn count_missing_num(regions: &[ColorMemRegion]) -> Vec<usize> {
     list
 }
 
+/* The pages of color regions sorted by count, in the order `count_missing_num` lays them out:
+ * row j holds the j-th page of each region with more than j pages, so the rows only shrink.
+ * The pa of a page is found without walking the pages before it.
+ */
+pub struct ColorLayout {
+    regions: Vec<ColorMemRegion>,
+    missing: Vec<usize>,
+    pages: usize,
+}
+
+impl ColorLayout {
+    pub fn new(regions: Vec<ColorMemRegion>) -> Self {
+        let missing = if regions.is_empty() {
+            vec![]
+        } else {
+            count_missing_num(&regions)
+        };
+        let pages = regions.iter().map(|region| region.count).sum();
+        Self {
+            regions,
+            missing,
+            pages,
+        }
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    // the regions run out before row j
+    fn row_first_region(&self, row: usize) -> usize {
+        self.regions.partition_point(|region| region.count <= row)
+    }
+
+    // index of the first page of row j
+    fn row_start(&self, row: usize) -> usize {
+        self.row_first_region(row) + row * self.regions.len() - self.missing[row]
+    }
+
+    // pa of page `idx` of the layout
+    pub fn page_pa(&self, idx: usize) -> Option<usize> {
+        if idx >= self.pages {
+            return None;
+        }
+        // the last row starting at or before the page
+        let (mut lo, mut hi) = (0, self.missing.len());
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if self.row_start(mid) <= idx {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        let region = &self.regions[self.row_first_region(lo) + idx - self.row_start(lo)];
+        Some(region.base + lo * region.step)
+    }
+}
+
 fn cpu_map_va2color_regions(cpu: &Cpu, cpu_va_region: RangeInclusive<usize>, color_regions: &[ColorMemRegion]) {
     let missing_list = count_missing_num(color_regions);
     for (i, region) in color_regions.iter().enumerate() {
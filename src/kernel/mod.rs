@@ -1,35 +1,67 @@
 pub use self::async_task::*;
+#[cfg(feature = "memory-reservation")]
+pub use self::bwres::membwres::MemBwRecord;
 pub use self::cpu::*;
+pub use self::dirty_log::DirtyLog;
+pub use self::event_trace::{event_trace, event_trace_dump, TraceEvent};
+pub use self::exit_stat::*;
 pub use self::hvc::*;
+pub use self::hw_test::*;
 pub use self::interrupt::*;
 pub use self::iommu::*;
 pub use self::ipi::*;
 pub use self::ivc::*;
 pub use self::mem::*;
+pub use self::pvclock::pvclock_init;
+pub use self::sched::{SCHED_SLICE_MAX_US, SCHED_SLICE_MIN_US, SCHED_WEIGHT_DEFAULT};
+#[cfg(feature = "self-test")]
+pub use self::self_test::self_test;
+pub use self::spin_table::{spin_table_init, spin_table_release_addr, spin_table_write};
 pub use self::timer::timer_init;
+#[cfg(feature = "tlb-stress")]
+pub use self::tlb_stress::tlb_stress_test;
 pub use self::vcpu::*;
 pub use self::vm::*;
+pub use self::vm_lock::{assert_no_vm_lock, VmMutex};
+#[cfg(feature = "watchdog")]
+pub use self::watchdog::{watchdog_init, watchdog_set};
 
 pub mod access;
 mod async_task;
 #[cfg(feature = "memory-reservation")]
 mod bwres;
 mod cpu;
+mod dirty_log;
+mod event_trace;
+mod exit_stat;
 #[allow(dead_code)]
 mod hvc;
+mod hw_test;
 mod interrupt;
 mod iommu;
 #[allow(dead_code)]
 mod ipi;
 mod ivc;
 mod mem;
+mod pvclock;
 mod sched;
+#[cfg(feature = "self-test")]
+mod self_test;
+mod spin_table;
 pub mod timer;
+#[cfg(feature = "tlb-stress")]
+mod tlb_stress;
 mod vcpu;
 mod vcpu_array;
 mod vm;
+mod vm_lock;
+#[cfg(feature = "watchdog")]
+mod watchdog;
 
 pub fn subinit() {
+    async_task_timeout_init();
     #[cfg(feature = "memory-reservation")]
     bwres::init();
+    #[cfg(feature = "iommu")]
+    iommu::iommu_fault_irq_init();
 }
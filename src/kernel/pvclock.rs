@@ -0,0 +1,140 @@
+use core::mem::size_of;
+use core::sync::atomic::{fence, AtomicBool, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::arch::{Arch, CacheInvalidate, PAGE_SIZE, PTE_S2_RO};
+use crate::device::EmuDeviceType;
+use crate::kernel::{active_vm, mem_page_alloc, timer, vm_list_walker, Vm};
+use crate::mm::PageUsage;
+
+const PVCLOCK_MAGIC: u32 = u32::from_le_bytes(*b"SHYC");
+const PVCLOCK_VERSION: u32 = 1;
+const NSEC_PER_SEC: u128 = 1_000_000_000;
+
+/* The read-only page a guest finds at the ipa of its EmuDeviceTPvClock, the same layout
+ * must be kept by the guest drivers, bump PVCLOCK_VERSION when it changes.
+ *
+ * A reader loads `seq`, retries while it is odd, reads the fields, and retries if `seq` changed.
+ * wallclock_ns = wall_offset_ns + cntvct_el0 * 10^9 / freq
+ */
+#[repr(C)]
+struct PvClockPage {
+    magic: u32,
+    version: u32,
+    // odd while the hypervisor updates the page
+    seq: u32,
+    // 1 once VM0 has set the time, wall_offset_ns is meaningless before
+    valid: u32,
+    // frequency of the virtual counter in Hz
+    freq: u64,
+    // virtual counter value when the VM booted
+    boot_counter: u64,
+    // ns since the epoch at virtual counter 0
+    wall_offset_ns: u64,
+}
+
+const _: () = assert!(size_of::<PvClockPage>() <= PAGE_SIZE);
+
+// ns since the epoch at physical counter 0, set by VM0
+static WALL_OFFSET_NS: AtomicU64 = AtomicU64::new(0);
+static WALL_VALID: AtomicBool = AtomicBool::new(false);
+// serializes the writers of all pages, a VM0 set time and a VM resuming may race
+static PVCLOCK_LOCK: Mutex<()> = Mutex::new(());
+
+fn counter_to_ns(counter: usize) -> u64 {
+    (counter as u128 * NSEC_PER_SEC / crate::arch::timer::timer_arch_get_frequency() as u128) as u64
+}
+
+/* Allocate the clock page of a VM and map it read-only at the ipa of its EmuDeviceTPvClock,
+ * so that guest reads never trap.
+ */
+pub fn pvclock_init(vm: &Vm) -> bool {
+    let emu_cfg = match vm
+        .config()
+        .emulated_device_list()
+        .iter()
+        .find(|emu_cfg| emu_cfg.emu_type == EmuDeviceType::EmuDeviceTPvClock)
+    {
+        Some(emu_cfg) => emu_cfg,
+        None => return true,
+    };
+    if emu_cfg.base_ipa == 0 || emu_cfg.base_ipa % PAGE_SIZE != 0 {
+        error!(
+            "pvclock_init: VM[{}] illegal clock page ipa {:#x}",
+            vm.id(),
+            emu_cfg.base_ipa
+        );
+        return false;
+    }
+    let frame = match mem_page_alloc(PageUsage::PvClock) {
+        Ok(frame) => frame,
+        Err(_) => {
+            error!("pvclock_init: VM[{}] alloc page failed", vm.id());
+            return false;
+        }
+    };
+    let page = unsafe { &mut *(frame.hva as *mut PvClockPage) };
+    *page = PvClockPage {
+        magic: PVCLOCK_MAGIC,
+        version: PVCLOCK_VERSION,
+        seq: 0,
+        valid: 0,
+        freq: crate::arch::timer::timer_arch_get_frequency() as u64,
+        boot_counter: (timer::get_counter() - vm.vtimer_offset()) as u64,
+        wall_offset_ns: 0,
+    };
+    vm.pt_map_range(emu_cfg.base_ipa, PAGE_SIZE, frame.pa, PTE_S2_RO, false);
+    vm.set_pvclock_page(frame);
+    pvclock_update(vm);
+    info!("VM[{}] pvclock page at ipa {:#x}", vm.id(), emu_cfg.base_ipa);
+    true
+}
+
+/* Refill the wall clock offset of a VM, when VM0 sets the time or the virtual counter
+ * of the VM is shifted (it was pending with the vtimer feature).
+ * The virtual counter is the same on every vcpu of a VM, so a vcpu migration changes nothing.
+ */
+pub fn pvclock_update(vm: &Vm) {
+    let hva = match vm.pvclock_page() {
+        Some(hva) => hva,
+        None => return,
+    };
+    let _lock = PVCLOCK_LOCK.lock();
+    let page = unsafe { &mut *(hva as *mut PvClockPage) };
+    let seq = unsafe { core::ptr::read_volatile(&page.seq) };
+    unsafe { core::ptr::write_volatile(&mut page.seq, seq.wrapping_add(1)) };
+    fence(Ordering::SeqCst);
+    // virtual counter 0 is physical counter `vtimer_offset`
+    let offset = WALL_OFFSET_NS.load(Ordering::Acquire) + counter_to_ns(vm.vtimer_offset());
+    unsafe {
+        core::ptr::write_volatile(&mut page.wall_offset_ns, offset);
+        core::ptr::write_volatile(&mut page.valid, WALL_VALID.load(Ordering::Acquire) as u32);
+    }
+    fence(Ordering::SeqCst);
+    unsafe { core::ptr::write_volatile(&mut page.seq, seq.wrapping_add(2)) };
+    Arch::dcache_clean_flush(hva, size_of::<PvClockPage>());
+}
+
+// HVC_IVC_GET_TIME: ns since the epoch, fails until VM0 has set the time
+pub fn hvc_get_time() -> Result<usize, ()> {
+    if !WALL_VALID.load(Ordering::Acquire) {
+        return Err(());
+    }
+    Ok((WALL_OFFSET_NS.load(Ordering::Acquire) + counter_to_ns(timer::get_counter())) as usize)
+}
+
+// HVC_IVC_SET_TIME: VM0 only, `ns` since the epoch, applied to the clock page of every VM
+pub fn hvc_set_time(ns: usize) -> Result<usize, ()> {
+    let vm = active_vm().unwrap();
+    if vm.id() != 0 {
+        error!("hvc_set_time: VM {} is not allowed to set the time", vm.id());
+        return Err(());
+    }
+    let now = counter_to_ns(timer::get_counter());
+    WALL_OFFSET_NS.store((ns as u64).wrapping_sub(now), Ordering::Release);
+    WALL_VALID.store(true, Ordering::Release);
+    info!("pvclock: wall clock set to {} s", ns as u128 / NSEC_PER_SEC);
+    vm_list_walker(|vm| pvclock_update(vm));
+    Ok(0)
+}
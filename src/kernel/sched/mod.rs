@@ -5,9 +5,17 @@ mod sched_rt;
 use alloc::boxed::Box;
 
 use crate::board::SchedRule;
+use crate::util::timer_list::TimerValue;
 
+use super::timer::TIMER_SLICE;
 use super::Vcpu;
 
+// time slice of a best-effort vcpu in percent of TIMER_SLICE
+pub const SCHED_WEIGHT_DEFAULT: usize = 100;
+// bounds of the time slice a VM may ask for, in us
+pub const SCHED_SLICE_MIN_US: usize = 100;
+pub const SCHED_SLICE_MAX_US: usize = 1_000_000;
+
 pub trait Scheduler {
     type SchedItem;
     /* full name for this scheduler */
@@ -20,12 +28,24 @@ pub trait Scheduler {
     fn remove(&mut self, item: &Self::SchedItem);
     /* put a new item into the scheduler */
     fn put(&mut self, item: Self::SchedItem);
+    /* account a timer tick to the running item, return true if it should give up the cpu */
+    fn tick(&mut self, _current: &Self::SchedItem) -> bool {
+        true
+    }
+    /* return true if the queued item should run before the running one right now */
+    fn preempt(&self, _current: &Self::SchedItem, _item: &Self::SchedItem) -> bool {
+        false
+    }
+    /* how long the running item may go on before the next tick is due */
+    fn slice_left(&self, _current: &Self::SchedItem) -> TimerValue {
+        TimerValue::from_millis(TIMER_SLICE as u64)
+    }
 }
 
 // factory mode
 pub fn get_scheduler(rule: SchedRule) -> Box<dyn Scheduler<SchedItem = Vcpu>> {
     match rule {
-        SchedRule::RoundRobin => Box::new(sched_rr::SchedulerRR::new(1)),
+        SchedRule::RoundRobin => Box::new(sched_rr::SchedulerRR::new()),
         #[cfg(feature = "rt-sched")]
         SchedRule::RealTime => Box::new(sched_rt::SchedulerRT::new()),
     }
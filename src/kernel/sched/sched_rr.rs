@@ -1,22 +1,124 @@
+use crate::kernel::timer::{now, TIMER_SLICE};
 use crate::kernel::Vcpu;
+use crate::util::timer_list::TimerValue;
 use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 
-use super::Scheduler;
+use super::{Scheduler, SCHED_SLICE_MIN_US, SCHED_WEIGHT_DEFAULT};
 
+// the real-time class runs for at most SCHED_RT_RUNTIME of every SCHED_RT_PERIOD while
+// best-effort vcpus wait, the rest is the share guaranteed to them
+const SCHED_RT_PERIOD: TimerValue = TimerValue::from_millis(100);
+const SCHED_RT_RUNTIME: TimerValue = TimerValue::from_millis(90);
+
+struct RRUnit {
+    item: Vcpu,
+    rt: bool,
+    // time slice and what is left of it, in us
+    slice: isize,
+    budget: isize,
+}
+
+impl RRUnit {
+    fn new(item: Vcpu) -> Self {
+        let (rt, slice) = match item.vm() {
+            Some(vm) => (vm.config().cpu.sched_rt, vm.config().cpu.sched_slice_us()),
+            None => (false, TIMER_SLICE * 1000 * SCHED_WEIGHT_DEFAULT / 100),
+        };
+        let slice = slice.max(SCHED_SLICE_MIN_US) as isize;
+        Self {
+            item,
+            rt,
+            slice,
+            budget: slice,
+        }
+    }
+}
+
+/* Round robin with two classes: real-time vcpus run before the best-effort ones, each class is
+ * served in turn, and a vcpu runs for the slice of its VM.
+ * The time a vcpu runs is charged when it is switched out or on a tick. A best-effort slice
+ * overdrawn is paid back by skipping turns (deficit round robin), so the cpu share still follows
+ * the slices. The real-time class is throttled once it used up SCHED_RT_RUNTIME of the current
+ * period, so a busy real-time guest can not starve the best-effort ones on its core.
+ */
 #[derive(Default)]
 pub struct SchedulerRR {
+    units: Vec<RRUnit>,
+    rt_queue: VecDeque<Vcpu>,
     queue: VecDeque<Vcpu>,
-    #[allow(unused)]
-    base_slice: usize,
+    // the vcpu handed out by `next` and since when it has not been charged
+    running: Option<Vcpu>,
+    last_charge: TimerValue,
+    // the current period of the real-time class and the time it ran in it
+    rt_period_start: TimerValue,
+    rt_used: TimerValue,
 }
 
 impl SchedulerRR {
-    pub fn new(slice: usize) -> Self {
-        Self {
-            queue: VecDeque::new(),
-            base_slice: slice,
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn unit(&mut self, item: &Vcpu) -> Option<&mut RRUnit> {
+        self.units.iter_mut().find(|unit| &unit.item == item)
+    }
+
+    fn is_rt(&self, item: &Vcpu) -> bool {
+        self.units.iter().any(|unit| &unit.item == item && unit.rt)
+    }
+
+    fn budget(&self, item: &Vcpu) -> isize {
+        self.units
+            .iter()
+            .find(|unit| &unit.item == item)
+            .map_or(0, |unit| unit.budget)
+    }
+
+    // the first best-effort vcpu with budget left, the head if every one is paying back
+    fn next_best_effort(&mut self) -> Option<Vcpu> {
+        let idx = self.queue.iter().position(|item| self.budget(item) > 0).unwrap_or(0);
+        self.queue.remove(idx)
+    }
+
+    // charge the time since the last charge to the running vcpu
+    fn charge(&mut self) {
+        let now = now();
+        let delta = now.saturating_sub(self.last_charge);
+        self.last_charge = now;
+        let running = match self.running.clone() {
+            Some(running) => running,
+            None => return,
+        };
+        let rt = match self.unit(&running) {
+            Some(unit) => {
+                unit.budget -= delta.as_micros() as isize;
+                unit.rt
+            }
+            None => return,
+        };
+        if rt {
+            if now.saturating_sub(self.rt_period_start) >= SCHED_RT_PERIOD {
+                self.rt_period_start = now;
+                self.rt_used = TimerValue::ZERO;
+            }
+            self.rt_used += delta;
         }
     }
+
+    // the time the real-time class ran in the period `now` is in
+    fn rt_used(&self, now: TimerValue) -> TimerValue {
+        if now.saturating_sub(self.rt_period_start) < SCHED_RT_PERIOD {
+            self.rt_used
+        } else {
+            TimerValue::ZERO
+        }
+    }
+
+    // the real-time class used up its runtime of this period
+    fn rt_throttled(&self) -> bool {
+        self.rt_used(now()) >= SCHED_RT_RUNTIME
+    }
 }
 
 impl Scheduler for SchedulerRR {
@@ -29,16 +131,114 @@ impl Scheduler for SchedulerRR {
     fn init(&mut self) {}
 
     fn next(&mut self) -> Option<Self::SchedItem> {
-        self.queue.pop_front()
+        self.charge();
+        let item = if self.rt_throttled() && !self.queue.is_empty() {
+            self.next_best_effort()
+        } else {
+            match self.rt_queue.pop_front() {
+                Some(item) => Some(item),
+                None => self.next_best_effort(),
+            }
+        };
+        // the running vcpu goes on if there is nothing else
+        if item.is_some() {
+            self.running = item.clone();
+        }
+        item
     }
 
     fn remove(&mut self, item: &Self::SchedItem) {
-        if let Some(idx) = self.queue.iter().position(|x| x.eq(item)) {
-            self.queue.remove(idx);
+        if self.running.as_ref() == Some(item) {
+            self.charge();
+            self.running = None;
         }
+        self.rt_queue.retain(|x| x != item);
+        self.queue.retain(|x| x != item);
+        self.units.retain(|unit| &unit.item != item);
     }
 
     fn put(&mut self, item: Self::SchedItem) {
-        self.queue.push_back(item);
+        if self.running.as_ref() == Some(&item) {
+            self.charge();
+            self.running = None;
+        }
+        // a vcpu switched out keeps its budget, a new or woken one starts with a full slice
+        let rt = match self.unit(&item) {
+            Some(unit) => unit.rt,
+            None => {
+                let unit = RRUnit::new(item.clone());
+                let rt = unit.rt;
+                self.units.push(unit);
+                rt
+            }
+        };
+        if rt {
+            self.rt_queue.push_back(item);
+        } else {
+            self.queue.push_back(item);
+        }
+    }
+
+    fn tick(&mut self, current: &Self::SchedItem) -> bool {
+        self.charge();
+        let throttled = self.rt_throttled();
+        let rt_waiting = !self.rt_queue.is_empty();
+        let be_waiting = !self.queue.is_empty();
+        let unit = match self.unit(current) {
+            Some(unit) => unit,
+            None => return true,
+        };
+        if unit.rt {
+            // the best-effort vcpus get their share
+            if throttled && be_waiting {
+                return true;
+            }
+            if unit.budget > 0 {
+                return false;
+            }
+            // real-time vcpus take turns when their slice runs out
+            unit.budget = unit.slice;
+            return rt_waiting;
+        }
+        if rt_waiting && !throttled {
+            return true;
+        }
+        if unit.budget > 0 {
+            return false;
+        }
+        unit.budget += unit.slice;
+        if self.queue.iter().any(|item| self.budget(item) > 0) {
+            return true;
+        }
+        // the queued vcpus skip this turn to pay back, the current one goes on
+        for item in self.queue.iter() {
+            if let Some(unit) = self.units.iter_mut().find(|unit| &unit.item == item) {
+                unit.budget += unit.slice;
+            }
+        }
+        false
+    }
+
+    fn preempt(&self, current: &Self::SchedItem, item: &Self::SchedItem) -> bool {
+        self.is_rt(item) && !self.is_rt(current) && !self.rt_throttled()
+    }
+
+    fn slice_left(&self, current: &Self::SchedItem) -> TimerValue {
+        let now = now();
+        let used = now.saturating_sub(self.last_charge);
+        let budget = TimerValue::from_micros(self.budget(current).max(0) as u64);
+        let left = budget.saturating_sub(used);
+        if self.is_rt(current) {
+            if self.queue.is_empty() {
+                return left;
+            }
+            // stop it when the real-time class used up its runtime and best-effort vcpus wait
+            left.min(SCHED_RT_RUNTIME.saturating_sub(self.rt_used(now) + used))
+        } else if self.rt_throttled() && !self.rt_queue.is_empty() {
+            // give the cpu back to the real-time class when its next period begins
+            left.min((self.rt_period_start + SCHED_RT_PERIOD).saturating_sub(now))
+        } else {
+            left
+        }
     }
 }
@@ -68,9 +68,16 @@ unsafe impl Send for SchedUnit {}
 
 impl SchedUnit {
     fn new(item: SchedItemInner) -> Self {
+        // a VM with its own time slice gets it as the budget of every period
+        let budget = match item.vm() {
+            Some(vm) if vm.config().cpu.sched_slice_us != 0 => {
+                TimerValue::from_micros(vm.config().cpu.sched_slice_us() as u64).min(DEFAULT_PERIOD)
+            }
+            _ => DEFAULT_BUDGET,
+        };
         Self {
             item,
-            budget: DEFAULT_BUDGET,
+            budget,
             period: DEFAULT_PERIOD,
 
             priority: Cell::new(0),
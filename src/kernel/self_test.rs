@@ -0,0 +1,1361 @@
+use alloc::vec::Vec;
+
+use crate::arch::{
+    vgic_lr_stress_synthetic, vgicd_access_ints, vgicd_lane_extract, vgicd_lane_merge, PAGE_SIZE, VM_IPA_SIZE,
+};
+use crate::arch::{PageTable, PTE_S2_FIELD_AP_RO, PTE_S2_FIELD_AP_RW, PTE_S2_NORMAL};
+use crate::arch::{GIC_CONFIG_BITS, GIC_PRIO_BITS};
+use crate::config::{SmpBoot, VmConfigEntry, VmCpuConfig, VmRegion};
+use crate::device::{
+    desc_chain_walk_synthetic, virtio_config_read_synthetic, virtq_rings_synthetic, DescChainError, EmuDeviceType,
+    VIRTQ_DESC_F_NEXT,
+};
+use crate::kernel::access::vm_ipa2hva_in;
+use crate::kernel::timer::{ticks_to_duration, TIMER_SLICE};
+use crate::kernel::{
+    color_pool_alloc, color_pool_free, count_missing_num, hvc_caps, llc_scaled_num_sets, mem_page_alloc,
+    spin_table_release_addr, vm_ipa2hva_prefix, AllocError, ColorLayout, ColorMemRegion, DirtyLog, ExitClass, ExitStat,
+    HvcError, VcpuExitStat, VmBootState, VmImageUpload, VtimerEpoch, CONFIG_VM_NUM_MAX, HVC_CAP_CONFIG,
+    HVC_CAP_LIVE_UPDATE, HVC_VERSION_MAJOR, HVC_VERSION_MINOR, HVC_VERSION_PATCH, SCHED_SLICE_MAX_US,
+    SCHED_SLICE_MIN_US,
+};
+use crate::mm::PageUsage;
+use crate::util::logger::LogModule;
+use crate::util::{BitAlloc, BitAlloc16, BitAlloc4K, FlexBitmap};
+use crate::vmm::elf_parse_synthetic;
+
+// counts the cases of one run, a failing case is printed with the place it is checked
+struct SelfTest {
+    cases: usize,
+    failed: usize,
+}
+
+impl SelfTest {
+    fn check(&mut self, ok: bool, case: core::fmt::Arguments) {
+        self.cases += 1;
+        if !ok {
+            self.failed += 1;
+            error!("self_test: FAILED {}", case);
+        }
+    }
+}
+
+macro_rules! check {
+    ($t:expr, $ok:expr, $($arg:tt)+) => {
+        $t.check($ok, format_args!($($arg)+))
+    };
+}
+
+fn test_bitmap(t: &mut SelfTest) {
+    let mut bits16 = BitAlloc16::default();
+    for idx in [0, BitAlloc16::CAP - 1] {
+        bits16.set(idx);
+        check!(t, bits16.get(idx) == 1, "BitAlloc16 set {}", idx);
+        bits16.clear(idx);
+        check!(t, bits16.get(idx) == 0, "BitAlloc16 clear {}", idx);
+    }
+
+    let mut bits4k = BitAlloc4K::default();
+    for idx in [0, 15, 16, 255, 256, BitAlloc4K::CAP - 1] {
+        bits4k.set(idx);
+        check!(t, bits4k.get(idx) == 1, "BitAlloc4K set {}", idx);
+        // the neighbours on the other side of a sub map boundary are untouched
+        check!(
+            t,
+            idx == 0 || bits4k.get(idx - 1) == 0,
+            "BitAlloc4K set {} leaks to {}",
+            idx,
+            idx - 1
+        );
+        bits4k.clear(idx);
+        check!(t, bits4k.get(idx) == 0, "BitAlloc4K clear {}", idx);
+    }
+
+    for len in [1, 63, 64, 65, 128] {
+        let mut flex = FlexBitmap::new(len);
+        flex.set(len - 1, true);
+        check!(t, flex.get(len - 1) == 1, "FlexBitmap({}) set last bit", len);
+        check!(t, flex.sum() == 1, "FlexBitmap({}) sum after set last bit", len);
+        check!(t, flex.first() == len - 1, "FlexBitmap({}) first", len);
+        flex.set(len - 1, false);
+        check!(t, flex.sum() == 0, "FlexBitmap({}) clear last bit", len);
+    }
+    let mut flex = FlexBitmap::new(128);
+    flex.set_bits(64, 64, true);
+    check!(
+        t,
+        flex.sum() == 64 && flex.get(63) == 0 && flex.get(64) == 1,
+        "FlexBitmap set_bits(64, 64)"
+    );
+}
+
+fn test_cpu_config(t: &mut SelfTest) {
+    // (num, bitmap, master) => (num, bitmap, master)
+    let cases = [
+        ((2, 0b1111, 0), (2, 0b0011, Some(0))),
+        ((2, 0b1010, 3), (2, 0b1010, Some(3))),
+        // master outside the accepted bitmap
+        ((1, 0b0110, 2), (1, 0b0010, None)),
+        ((2, 0b0110, 0), (2, 0b0110, None)),
+        ((1, 0b0001, 64), (1, 0b0001, None)),
+        // num larger than the bitmap
+        ((8, 0b0101, 2), (2, 0b0101, Some(2))),
+        ((1, 0, 0), (0, 0, None)),
+        ((0, 0b1111, 0), (0, 0, None)),
+        ((1, 1 << 63, 63), (1, 1 << 63, Some(63))),
+        ((64, usize::MAX, 63), (64, usize::MAX, Some(63))),
+    ];
+    for ((num, bitmap, master), expect) in cases {
+        let cpu = VmCpuConfig::new(num, bitmap, master);
+        check!(
+            t,
+            (cpu.num, cpu.allocate_bitmap, cpu.master) == expect,
+            "VmCpuConfig::new({}, {:#b}, {}) = ({}, {:#b}, {:?})",
+            num,
+            bitmap,
+            master,
+            cpu.num,
+            cpu.allocate_bitmap,
+            cpu.master
+        );
+        check!(
+            t,
+            cpu.allocate_bitmap.count_ones() as usize == cpu.num,
+            "VmCpuConfig::new({}, {:#b}, {}) num does not match the bitmap",
+            num,
+            bitmap,
+            master
+        );
+    }
+
+    // (num, bitmap, master, vcpus_per_core) => (num, bitmap, master)
+    let cases = [
+        ((4, 0b0011, 0, 2), (4, 0b0011, Some(0))),
+        ((3, 0b1111, 0, 2), (3, 0b0011, Some(0))),
+        ((8, 0b0101, 2, 2), (4, 0b0101, Some(2))),
+        // 0 is one vcpu per core
+        ((2, 0b0110, 1, 0), (2, 0b0110, Some(1))),
+    ];
+    for ((num, bitmap, master, per_core), expect) in cases {
+        let cpu = VmCpuConfig::with_vcpus_per_core(num, bitmap, master, per_core);
+        check!(
+            t,
+            (cpu.num, cpu.allocate_bitmap, cpu.master) == expect,
+            "VmCpuConfig::with_vcpus_per_core({}, {:#b}, {}, {}) = ({}, {:#b}, {:?})",
+            num,
+            bitmap,
+            master,
+            per_core,
+            cpu.num,
+            cpu.allocate_bitmap,
+            cpu.master
+        );
+    }
+
+    // (rt, weight, slice_us) => slice in us
+    let cases = [
+        ((false, 0, 0), TIMER_SLICE * 1000),
+        ((false, 50, 0), TIMER_SLICE * 500),
+        // the weight is for best-effort vcpus only
+        ((true, 50, 0), TIMER_SLICE * 1000),
+        ((true, 50, 2000), 2000),
+        ((false, 300, 500), 500),
+        ((false, 1, 0), SCHED_SLICE_MIN_US),
+        ((false, 0, usize::MAX), SCHED_SLICE_MAX_US),
+    ];
+    for ((sched_rt, sched_weight, sched_slice_us), expect) in cases {
+        let cpu = VmCpuConfig {
+            sched_rt,
+            sched_weight,
+            sched_slice_us,
+            ..Default::default()
+        };
+        check!(
+            t,
+            cpu.sched_slice_us() == expect,
+            "VmCpuConfig sched_slice_us({}, {}, {}) = {}, expect {}",
+            sched_rt,
+            sched_weight,
+            sched_slice_us,
+            cpu.sched_slice_us(),
+            expect
+        );
+    }
+
+    // secondaries boot with PSCI unless a release page is configured
+    check!(
+        t,
+        VmCpuConfig::new(2, 0b0011, 0).smp_boot == SmpBoot::Psci,
+        "VmCpuConfig::new smp_boot is not Psci"
+    );
+    for (vcpu_id, expect) in [(0, 0x9000_0000), (1, 0x9000_0008), (7, 0x9000_0038)] {
+        let addr = spin_table_release_addr(0x9000_0000, vcpu_id);
+        check!(
+            t,
+            addr == expect,
+            "spin_table_release_addr(0x90000000, {}) = {:#x}, expect {:#x}",
+            vcpu_id,
+            addr,
+            expect
+        );
+    }
+}
+
+fn test_ipa2hva(t: &mut SelfTest) {
+    let ipa_max = (1 << VM_IPA_SIZE) - 1;
+    for vm_id in 0..CONFIG_VM_NUM_MAX {
+        check!(t, vm_ipa2hva_prefix(vm_id, 0) == 0, "VM[{}] ipa2hva(0)", vm_id);
+        check!(
+            t,
+            vm_ipa2hva_prefix(vm_id, ipa_max + 1) == 0,
+            "VM[{}] ipa2hva({:#x}) out of the ipa space",
+            vm_id,
+            ipa_max + 1
+        );
+        for ipa in [1, 0x4000_0000, ipa_max] {
+            let hva = vm_ipa2hva_prefix(vm_id, ipa);
+            check!(
+                t,
+                hva != 0 && hva & ipa_max == ipa,
+                "VM[{}] ipa2hva({:#x}) = {:#x}",
+                vm_id,
+                ipa,
+                hva
+            );
+        }
+    }
+    // the windows of two VMs never overlap
+    for ipa in [1, ipa_max] {
+        for a in 0..CONFIG_VM_NUM_MAX {
+            for b in (a + 1)..CONFIG_VM_NUM_MAX {
+                check!(
+                    t,
+                    vm_ipa2hva_prefix(a, ipa) >> VM_IPA_SIZE != vm_ipa2hva_prefix(b, ipa) >> VM_IPA_SIZE,
+                    "VM[{}] and VM[{}] share the hva window of ipa {:#x}",
+                    a,
+                    b,
+                    ipa
+                );
+            }
+        }
+    }
+}
+
+// guest buffers are translated only if all of them is inside one memory region
+fn test_ipa2hva_range(t: &mut SelfTest) {
+    const VM_ID: usize = 1;
+    let region = |ipa_start: usize, length: usize| VmRegion { ipa_start, length };
+    // the second region follows the first one, the third is apart
+    let regions = [
+        region(0x4000_0000, 0x1000_0000),
+        region(0x5000_0000, 0x100_0000),
+        region(0x8000_0000, 0x20_0000),
+    ];
+    // ipa, len, translated
+    let cases = [
+        (0x4000_0000, 0x1000_0000, true),
+        (0x4fff_fff8, 8, true),
+        (0x4fff_fff8, 16, false),
+        (0x4000_0ffc, 8, true),
+        (0x3fff_fff8, 16, false),
+        (0x8000_0000, 0x20_0000, true),
+        (0x8000_0000, 0x20_0001, false),
+        (0x8020_0000, 1, false),
+        (0x4000_0000, 0, true),
+        (0x8020_0000, 0, false),
+        (0, 8, false),
+        ((1 << VM_IPA_SIZE) | 0x4000_0000, 8, false),
+        (0x4000_0000, usize::MAX, false),
+    ];
+    for (ipa, len, ok) in cases {
+        let hva = vm_ipa2hva_in(VM_ID, &regions, ipa, len);
+        check!(
+            t,
+            match hva {
+                Ok(hva) => ok && hva == vm_ipa2hva_prefix(VM_ID, ipa),
+                Err(err) => !ok && err.ipa == ipa && err.len == len,
+            },
+            "vm_ipa2hva({:#x}, {:#x}) = {:x?}",
+            ipa,
+            len,
+            hva
+        );
+    }
+}
+
+fn test_color_bitmap(t: &mut SelfTest) {
+    let mut config = VmConfigEntry::default();
+    check!(
+        t,
+        config.memory_color_bitmap() == usize::MAX,
+        "memory_color_bitmap without colors"
+    );
+    for colors in [vec![0], vec![1, 3, 5], vec![0, 31, 63], (0..64).collect::<Vec<_>>()] {
+        config.memory.colors = colors.clone();
+        let bitmap = config.memory_color_bitmap();
+        let back: Vec<usize> = (0..usize::BITS as usize).filter(|c| bitmap & (1 << c) != 0).collect();
+        check!(t, back == colors, "memory_color_bitmap({:?}) = {:#x}", colors, bitmap);
+    }
+
+    // 2048 sets over 16 colors, the bits above the colors of the LLC are ignored
+    for (bitmap, expect) in [
+        (usize::MAX, None),
+        (0xffff, None),
+        (0, None),
+        (0x1, Some(128)),
+        (0xff, Some(1024)),
+        (0xf0f0, Some(1024)),
+        (0x1_0003, Some(256)),
+    ] {
+        let num_sets = llc_scaled_num_sets(2048, 16, bitmap);
+        check!(
+            t,
+            num_sets == expect,
+            "llc_scaled_num_sets({:#x}) = {:?}",
+            bitmap,
+            num_sets
+        );
+    }
+    check!(
+        t,
+        llc_scaled_num_sets(4, 16, 0x1) == Some(1),
+        "llc_scaled_num_sets keeps a set"
+    );
+}
+
+// the pa of each page of a `ColorLayout` is the one the whole mapping of the regions gives it
+fn test_color_layout(t: &mut SelfTest) {
+    let step = 16 * PAGE_SIZE;
+    for counts in [vec![3], vec![2, 2, 2], vec![1, 3, 4], vec![2, 5, 5, 7]] {
+        let regions: Vec<ColorMemRegion> = counts
+            .iter()
+            .enumerate()
+            .map(|(color, &count)| ColorMemRegion::new(color, 0x1000_0000 + color * PAGE_SIZE, count, step, 0))
+            .collect();
+        let pages: usize = counts.iter().sum();
+        let mut expect = vec![0; pages];
+        let missing_list = count_missing_num(&regions);
+        for (i, region) in regions.iter().enumerate() {
+            for j in 0..region.count {
+                expect[i + j * regions.len() - missing_list[j]] = region.base + j * region.step;
+            }
+        }
+        let layout = ColorLayout::new(regions);
+        let got: Vec<Option<usize>> = (0..pages + 1).map(|idx| layout.page_pa(idx)).collect();
+        check!(
+            t,
+            layout.pages() == pages && got[..pages].iter().zip(expect.iter()).all(|(a, b)| *a == Some(*b)),
+            "ColorLayout {:?} gives {:x?}",
+            counts,
+            got
+        );
+        check!(t, got[pages].is_none(), "ColorLayout {:?} past the end", counts);
+    }
+    check!(
+        t,
+        ColorLayout::new(vec![]).page_pa(0).is_none(),
+        "ColorLayout without regions"
+    );
+}
+
+// a pool of 16 colors, 64 rows close to cluster 0 and 32 rows close to cluster 1
+fn color_test_pool() -> Vec<Vec<ColorMemRegion>> {
+    let step = 16 * PAGE_SIZE;
+    let mut pool = vec![vec![]; 16];
+    for (base, rows, affinity) in [(0x8000_0000, 64, 0), (0x1_0000_0000, 32, 1)] {
+        for color in 0..16 {
+            // in two halves, merged back when freed
+            let half = ColorMemRegion::new(color, base + color * PAGE_SIZE, rows / 2, step, affinity);
+            let second = ColorMemRegion::new(color, half.base + rows / 2 * step, rows / 2, step, affinity);
+            color_pool_free(&mut pool, second);
+            color_pool_free(&mut pool, half);
+        }
+    }
+    pool
+}
+
+fn color_pages(regions: &[ColorMemRegion]) -> usize {
+    regions.iter().map(|region| region.count).sum()
+}
+
+/* VMs of both clusters competing for color 0: each takes the memory close to its cluster first and
+ * only the rest from the other cluster, a VM without affinity takes any.
+ */
+fn test_color_locality(t: &mut SelfTest) {
+    let mut pool = color_test_pool();
+    let initial = pool.clone();
+    let on = |regions: &[ColorMemRegion], affinity: usize| regions.iter().all(|region| region.affinity == affinity);
+    let mut taken = vec![];
+    // affinity, pages, then the local and remote pages it gets, of the 64 pages of cluster 0 and 32 of cluster 1
+    let vm_list = [
+        (Some(1), 20, 20, 0),
+        (Some(0), 50, 50, 0),
+        (Some(1), 20, 12, 8),
+        (None, 4, 4, 0),
+    ];
+    for (affinity, pages, local_pages, remote_pages) in vm_list {
+        match color_pool_alloc(&mut pool, pages, 0x1, affinity) {
+            Ok((local, remote)) => {
+                let placed = match affinity {
+                    Some(affinity) => on(&local, affinity) && on(&remote, 1 - affinity),
+                    None => remote.is_empty(),
+                };
+                check!(
+                    t,
+                    placed && color_pages(&local) == local_pages && color_pages(&remote) == remote_pages,
+                    "color locality: {} pages close to {:?} give {:x?} {:x?}",
+                    pages,
+                    affinity,
+                    local,
+                    remote
+                );
+                taken.extend(local.into_iter().chain(remote));
+            }
+            Err(err) => check!(
+                t,
+                false,
+                "color locality: {} pages close to {:?} {:?}",
+                pages,
+                affinity,
+                err
+            ),
+        }
+    }
+    // 2 pages are left, both close to cluster 0
+    let result = color_pool_alloc(&mut pool, 3, 0x1, Some(1));
+    check!(
+        t,
+        matches!(
+            result,
+            Err(AllocError::OutOfColor {
+                color: 0,
+                available: 2,
+                ..
+            })
+        ),
+        "color locality: alloc past the pool {:?}",
+        result
+    );
+    for region in taken {
+        color_pool_free(&mut pool, region);
+    }
+    check!(t, pool == initial, "color locality: pool after the VMs {:x?}", pool[0]);
+}
+
+// colored VMs created and removed over and over give the pool back as it was, merged
+fn test_color_pool(t: &mut SelfTest) {
+    let mut pool = color_test_pool();
+    check!(
+        t,
+        pool.iter().all(|list| list.len() == 2),
+        "color pool halves merged {:x?}",
+        pool[0]
+    );
+    let initial = pool.clone();
+    // color bitmap, pages, affinity and the pages hot-added later of each VM
+    let vm_list = [
+        (0x000f, 100, Some(0), 20),
+        (0x00f0, 64, Some(1), 0),
+        (0x0f00, 300, Some(0), 33),
+        (0xf000, 17, None, 5),
+        (0x0003, 40, Some(1), 40),
+    ];
+    for round in 0..8 {
+        let mut vm_regions = vec![];
+        for &(bitmap, pages, affinity, hotplug) in vm_list.iter() {
+            let mut regions = vec![];
+            for pages in [pages, hotplug] {
+                match color_pool_alloc(&mut pool, pages, bitmap, affinity) {
+                    Ok((mut local, mut remote)) => {
+                        check!(
+                            t,
+                            color_pages(&local) + color_pages(&remote) == pages
+                                && local.iter().chain(remote.iter()).all(|r| bitmap & (1 << r.color) != 0),
+                            "color_pool_alloc({}, {:#x}) gives {:x?} {:x?}",
+                            pages,
+                            bitmap,
+                            local,
+                            remote
+                        );
+                        regions.append(&mut local);
+                        regions.append(&mut remote);
+                    }
+                    Err(err) => check!(t, false, "color_pool_alloc({}, {:#x}) {:?}", pages, bitmap, err),
+                }
+            }
+            vm_regions.push(regions);
+        }
+        // removed in another order each round
+        vm_regions.rotate_left(round % vm_regions.len());
+        for (i, mut regions) in vm_regions.into_iter().enumerate() {
+            if (round + i) % 2 == 0 {
+                regions.reverse();
+            }
+            for region in regions {
+                color_pool_free(&mut pool, region);
+            }
+        }
+        check!(t, pool == initial, "color pool after round {} is {:x?}", round, pool);
+    }
+
+    // a free in the middle leaves two runs, an allocation larger than each of them still fits
+    let (a, _) = color_pool_alloc(&mut pool, 32, 0x1, Some(0)).unwrap_or_default();
+    let (b, _) = color_pool_alloc(&mut pool, 16, 0x1, Some(0)).unwrap_or_default();
+    for region in a {
+        color_pool_free(&mut pool, region);
+    }
+    let (c, _) = color_pool_alloc(&mut pool, 40, 0x1, Some(0)).unwrap_or_default();
+    check!(
+        t,
+        color_pages(&c) == 40,
+        "color_pool_alloc over fragmented runs {:x?}",
+        c
+    );
+    for region in b.into_iter().chain(c) {
+        color_pool_free(&mut pool, region);
+    }
+    check!(
+        t,
+        pool == initial,
+        "color pool after the fragmented runs {:x?}",
+        pool[0]
+    );
+
+    // the narrow color runs out, the error names it
+    let result = color_pool_alloc(&mut pool, 200, 0x3, Some(0));
+    check!(
+        t,
+        matches!(
+            result,
+            Err(AllocError::OutOfColor {
+                color: 0,
+                requested: 100,
+                available: 96
+            })
+        ),
+        "color_pool_alloc out of colors {:?}",
+        result
+    );
+    check!(t, pool == initial, "color pool after a failed alloc");
+    color_pool_free(&mut pool, initial[0][0].clone());
+    check!(t, pool == initial, "color pool double free");
+}
+
+fn test_memory_region(t: &mut SelfTest) {
+    let mut config = VmConfigEntry::default();
+    config.memory.region.push(VmRegion {
+        ipa_start: 0x8000_0000,
+        length: 0x1000_0000,
+    });
+    let ipa_max = 1 << VM_IPA_SIZE;
+    // (ipa_start, length) => valid
+    let cases = [
+        ((0x9000_0000, 0x1000), true),
+        ((0x7fff_f000, 0x1000), true),
+        ((ipa_max - 0x1000, 0x1000), true),
+        ((0x9000_0000, 0), false),
+        ((0x9000_0800, 0x1000), false),
+        ((0x9000_0000, 0x800), false),
+        // overlaps the region at either end
+        ((0x7fff_f000, 0x2000), false),
+        ((0x8fff_f000, 0x2000), false),
+        ((ipa_max - 0x1000, 0x2000), false),
+        ((usize::MAX & !0xfff, 0x2000), false),
+    ];
+    for ((ipa_start, length), expect) in cases {
+        check!(
+            t,
+            config.memory_region_valid(ipa_start, length) == expect,
+            "memory_region_valid({:#x}, {:#x}) != {}",
+            ipa_start,
+            length,
+            expect
+        );
+    }
+    for (ipa, expect) in [
+        (0, true),
+        (0x8300_0000, true),
+        (0x9000_0000, false),
+        (0x7000_0000, false),
+    ] {
+        config.image.ramdisk_load_ipa = ipa;
+        check!(
+            t,
+            config.ramdisk_load_ipa_valid() == expect,
+            "ramdisk_load_ipa_valid({:#x}) != {}",
+            ipa,
+            expect
+        );
+    }
+}
+
+fn test_boot_state(t: &mut SelfTest) {
+    use VmBootState::*;
+    // the requests as they reach the VM interface, one after the other
+    let runs: [&[(VmBootState, bool)]; 5] = [
+        // two boot requests back to back, the VM is configured once and booted once
+        &[
+            (Configuring, true),
+            (Configuring, false),
+            (Configured, true),
+            (Booted, true),
+            (Booted, false),
+        ],
+        // removal while configured or reset is refused
+        &[
+            (Configuring, true),
+            (Removing, false),
+            (Configured, true),
+            (Booted, true),
+            (Rebooting, true),
+        ],
+        &[
+            (Configuring, true),
+            (Configured, true),
+            (Booted, true),
+            (Removing, true),
+            (Rebooting, false),
+        ],
+        // a crashed VM is stopped once, it is not rebooted but removed
+        &[
+            (Configuring, true),
+            (Configured, true),
+            (Booted, true),
+            (Stopped, true),
+            (Stopped, false),
+            (Rebooting, false),
+            (Removing, true),
+        ],
+        // a removed VM can be configured again
+        &[
+            (Configuring, true),
+            (Configured, true),
+            (Removing, true),
+            (Pending, true),
+            (Configuring, true),
+        ],
+    ];
+    for (i, run) in runs.iter().enumerate() {
+        let mut state = Pending;
+        for &(to, expect) in run.iter() {
+            let ok = state.can_transit(to);
+            check!(
+                t,
+                ok == expect,
+                "boot state run {}: {:?} -> {:?} = {}",
+                i,
+                state,
+                to,
+                ok
+            );
+            if ok {
+                state = to;
+            }
+        }
+    }
+}
+
+fn test_image_upload(t: &mut SelfTest) {
+    // (size, queued, copied, failed) => (pending, busy, percent)
+    let cases = [
+        // nothing uploaded, a VM not loaded by MVM
+        ((0, 0, 0, 0), (false, false, 100)),
+        ((0x400000, 0x100000, 0, 0), (true, true, 0)),
+        ((0x400000, 0x400000, 0x100000, 0), (true, true, 25)),
+        // MVM has not queued the rest yet
+        ((0x400000, 0x200000, 0x200000, 0), (true, false, 50)),
+        ((0x400000, 0x400000, 0x400000, 0), (false, false, 100)),
+        // an image larger than MVM said
+        ((0x100000, 0x200000, 0x100000, 0), (true, true, 50)),
+        // a chunk failed, the VM does not boot but the image may be uploaded again
+        ((0x400000, 0x400000, 0x300000, 0x100000), (true, false, 75)),
+        ((0x400000, 0x400000, 0x100000, 0x100000), (true, true, 25)),
+    ];
+    for ((size, queued, copied, failed), expect) in cases {
+        let upload = VmImageUpload {
+            size,
+            queued,
+            copied,
+            failed,
+            loaded: false,
+        };
+        check!(
+            t,
+            (upload.pending(), upload.busy(), upload.percent()) == expect,
+            "image upload ({:#x}, {:#x}, {:#x}, {:#x}) = ({}, {}, {})",
+            size,
+            queued,
+            copied,
+            failed,
+            upload.pending(),
+            upload.busy(),
+            upload.percent()
+        );
+    }
+}
+
+fn test_vtimer_epoch(t: &mut SelfTest) {
+    let mut epoch = VtimerEpoch::new(100);
+    // vcpu 0 and 1 start together, vcpu 1 is descheduled for a while on a busy core
+    epoch.resume(1000);
+    let vcpu0 = epoch.offset();
+    epoch.resume(1000);
+    epoch.pause(2000);
+    epoch.resume(5000);
+    let vcpu1 = epoch.offset();
+    check!(
+        t,
+        vcpu0 == vcpu1,
+        "vtimer: vcpu descheduled alone drifts by {:#x}",
+        vcpu1.abs_diff(vcpu0)
+    );
+    // the whole VM stops for 3000, the virtual counter goes on from where it stopped
+    epoch.pause(6000);
+    epoch.pause(6000);
+    let virt = 6000 - vcpu0;
+    epoch.resume(9000);
+    check!(
+        t,
+        9000 - epoch.offset() == virt,
+        "vtimer: virtual counter {:#x} after the VM resumed, expect {:#x}",
+        9000 - epoch.offset(),
+        virt
+    );
+    // a vcpu onlined later starts with the counter of its sibling
+    let sibling = epoch.offset();
+    epoch.resume(9500);
+    check!(t, epoch.offset() == sibling, "vtimer: onlined vcpu gets another epoch");
+    // an unbalanced pause does not wrap the running count
+    let mut epoch = VtimerEpoch::new(0);
+    epoch.pause(10);
+    epoch.resume(20);
+    check!(
+        t,
+        epoch.running() == 1,
+        "vtimer: running {} after an unbalanced pause",
+        epoch.running()
+    );
+}
+
+fn test_ticks_to_duration(t: &mut SelfTest) {
+    use core::time::Duration;
+    // (ticks, freq) => duration
+    let cases = [
+        ((0, 62_500_000), Duration::ZERO),
+        ((62_500_000, 62_500_000), Duration::from_secs(1)),
+        ((625, 62_500_000), Duration::from_micros(10)),
+        ((1, 1_000_000_000), Duration::from_nanos(1)),
+        ((100, 0), Duration::ZERO),
+        // a far away deadline does not overflow
+        ((u64::MAX, 1), Duration::from_nanos(u64::MAX)),
+    ];
+    for ((ticks, freq), expect) in cases {
+        let duration = ticks_to_duration(ticks, freq);
+        check!(
+            t,
+            duration == expect,
+            "ticks_to_duration({}, {}) = {:?}, expect {:?}",
+            ticks,
+            freq,
+            duration,
+            expect
+        );
+    }
+}
+
+fn test_vgicd_lanes(t: &mut SelfTest) {
+    const REG: u32 = 0x4433_2211;
+    for width in [1, 2, 4] {
+        for lane in (0..4).step_by(width) {
+            // a register of the second word of the file
+            let offset = 4 + lane;
+            let bytes = &REG.to_le_bytes()[lane..lane + width];
+            let expect = bytes.iter().rev().fold(0, |val, byte| val << 8 | *byte as usize);
+            let val = vgicd_lane_extract(REG, offset, width);
+            check!(
+                t,
+                val == expect,
+                "vgicd_lane_extract(offset {}, width {}) = {:#x}, expect {:#x}",
+                offset,
+                width,
+                val,
+                expect
+            );
+
+            // the bits above the access width in the guest register are not written
+            let merged = vgicd_lane_merge(REG, offset, width, usize::MAX);
+            let mut expect = REG.to_le_bytes();
+            expect[lane..lane + width].fill(0xff);
+            check!(
+                t,
+                merged == u32::from_le_bytes(expect),
+                "vgicd_lane_merge(offset {}, width {}) = {:#x}",
+                offset,
+                width,
+                merged
+            );
+            check!(
+                t,
+                vgicd_lane_extract(merged, offset, width) == vgicd_lane_extract(u32::MAX, offset, width),
+                "vgicd_lane_merge then extract (offset {}, width {})",
+                offset,
+                width
+            );
+
+            let prio = vgicd_access_ints(offset, width, GIC_PRIO_BITS);
+            check!(
+                t,
+                prio == (offset..offset + width),
+                "vgicd_access_ints(offset {}, width {}) of IPRIORITYR = {:?}",
+                offset,
+                width,
+                prio
+            );
+            let cfg = vgicd_access_ints(offset, width, GIC_CONFIG_BITS);
+            check!(
+                t,
+                cfg == (offset * 4..(offset + width) * 4),
+                "vgicd_access_ints(offset {}, width {}) of ICFGR = {:?}",
+                offset,
+                width,
+                cfg
+            );
+        }
+    }
+}
+
+// hundreds of SPIs into one vcpu through a few list registers, none of them is lost or taken twice
+fn test_vgic_lr_stress(t: &mut SelfTest) {
+    const SPI_NUM: usize = 300;
+    const LRS: usize = 4;
+    // mixed priorities; all virtual, some hw without the EOI maintenance, all hw
+    let prio = |spi: usize| (((spi * 37) % 32) << 3) as u8;
+    let runs: [(&str, fn(usize) -> bool); 3] =
+        [("virtual", |_| false), ("mixed", |spi| spi % 4 == 1), ("hw", |_| true)];
+    for (name, hw) in runs {
+        let ints: Vec<(u8, bool)> = (0..SPI_NUM).map(|spi| (prio(spi), hw(spi))).collect();
+        let (mut taken, overflow, left) = vgic_lr_stress_synthetic(&ints, LRS);
+        check!(t, overflow > 0, "{} SPIs: no LR overflow", name);
+        check!(t, left == 0, "{} SPIs: {} never taken", name, left);
+        taken.sort_unstable();
+        check!(
+            t,
+            taken.len() == SPI_NUM && taken.iter().enumerate().all(|(i, &spi)| i == spi),
+            "{} SPIs: {} taken of {}",
+            name,
+            taken.len(),
+            SPI_NUM
+        );
+    }
+}
+
+fn test_hvc_caps(t: &mut SelfTest) {
+    let caps = hvc_caps();
+    let version = (caps >> 56, (caps >> 48) & 0xff, (caps >> 40) & 0xff);
+    check!(
+        t,
+        version == (HVC_VERSION_MAJOR, HVC_VERSION_MINOR, HVC_VERSION_PATCH),
+        "hvc_caps() version {:?}",
+        version
+    );
+    check!(
+        t,
+        caps & HVC_CAP_CONFIG != 0 && caps & HVC_CAP_LIVE_UPDATE == 0 && caps & 0xff_0000_0000 == 0,
+        "hvc_caps() groups {:#x}",
+        caps & u32::MAX as usize
+    );
+    // -ENOSYS, distinct from the -1 of a failed call
+    check!(
+        t,
+        HvcError::Unsupported.code() as isize == -38 && HvcError::Failed.code() as isize == -1,
+        "hvc error codes {:#x} {:#x}",
+        HvcError::Unsupported.code(),
+        HvcError::Failed.code()
+    );
+}
+
+fn test_exit_stat(t: &mut SelfTest) {
+    let vcpu_stat = VcpuExitStat::new();
+    vcpu_stat.record(ExitClass::Hvc, 1500);
+    vcpu_stat.record(ExitClass::Hvc, 500);
+    vcpu_stat.record(ExitClass::VirtioAccess, 3000);
+    let stat = vcpu_stat.snapshot();
+    let hvc = ExitClass::Hvc as usize;
+    let virtio = ExitClass::VirtioAccess as usize;
+    check!(
+        t,
+        stat.count[hvc] == 2 && stat.time_ns[hvc] == 2000 && stat.count[virtio] == 1,
+        "VcpuExitStat snapshot {:?}",
+        stat
+    );
+    let mut sum = stat;
+    sum.add(&stat);
+    check!(
+        t,
+        sum.count[hvc] == 4 && sum.time_ns[virtio] == 6000 && sum.count.iter().sum::<u64>() == 6,
+        "ExitStat::add {:?}",
+        sum
+    );
+    vcpu_stat.reset();
+    check!(
+        t,
+        vcpu_stat.snapshot() == ExitStat::default(),
+        "VcpuExitStat::reset {:?}",
+        vcpu_stat.snapshot()
+    );
+
+    // emu_type => class of the data abort
+    let cases = [
+        (EmuDeviceType::EmuDeviceTGicd, Some(ExitClass::VgicAccess)),
+        (
+            EmuDeviceType::EmuDeviceTVirtioBlkMediated,
+            Some(ExitClass::VirtioAccess),
+        ),
+        (EmuDeviceType::EmuDeviceTVirtioNet, Some(ExitClass::VirtioAccess)),
+        (EmuDeviceType::EmuDeviceTPl011, None),
+    ];
+    for (emu_type, expect) in cases {
+        let class = ExitClass::from_emu_type(emu_type);
+        check!(
+            t,
+            class == expect,
+            "ExitClass::from_emu_type({:?}) = {:?}",
+            emu_type,
+            class
+        );
+    }
+}
+
+fn test_elf_parse(t: &mut SelfTest) {
+    const ENTRY: usize = 0x4008_0000;
+    let put = |bin: &mut Vec<u8>, offset: usize, val: &[u8]| bin[offset..offset + val.len()].copy_from_slice(val);
+    // the header, one PT_LOAD program header at 64 and 0x100 bytes of the segment at 0x100
+    let mut elf = vec![0_u8; 0x200];
+    put(&mut elf, 0, b"\x7fELF\x02\x01");
+    put(&mut elf, 16, &2_u16.to_le_bytes());
+    put(&mut elf, 18, &183_u16.to_le_bytes());
+    put(&mut elf, 24, &(ENTRY as u64).to_le_bytes());
+    put(&mut elf, 32, &64_u64.to_le_bytes());
+    put(&mut elf, 54, &56_u16.to_le_bytes());
+    put(&mut elf, 56, &1_u16.to_le_bytes());
+    put(&mut elf, 64, &1_u32.to_le_bytes());
+    put(&mut elf, 64 + 8, &0x100_u64.to_le_bytes());
+    put(&mut elf, 64 + 24, &(ENTRY as u64).to_le_bytes());
+    put(&mut elf, 64 + 32, &0x100_u64.to_le_bytes());
+    put(&mut elf, 64 + 40, &0x1000_u64.to_le_bytes());
+    let guest = |ipa: usize, len: usize| ipa >= 0x4000_0000 && ipa + len <= 0x4100_0000;
+
+    let entry = elf_parse_synthetic(&elf, guest);
+    check!(t, entry == Ok(ENTRY), "elf: well formed image {:x?}", entry);
+
+    let patched = |offset: usize, val: &[u8]| {
+        let mut bin = elf.clone();
+        put(&mut bin, offset, val);
+        bin
+    };
+    let cases = [
+        ("truncated header", elf[..40].to_vec()),
+        ("program headers out of the image", patched(56, &9_u16.to_le_bytes())),
+        ("program header offset overflow", patched(32, &u64::MAX.to_le_bytes())),
+        ("wrong machine", patched(18, &62_u16.to_le_bytes())),
+        ("segment out of the image", patched(64 + 32, &0x200_u64.to_le_bytes())),
+        (
+            "entry out of the segments",
+            patched(24, &(ENTRY as u64 + 0x1000).to_le_bytes()),
+        ),
+    ];
+    for (name, bin) in cases.iter() {
+        let entry = elf_parse_synthetic(bin, guest);
+        check!(t, entry.is_err(), "elf: {} accepted, entry {:x?}", name, entry);
+    }
+    let entry = elf_parse_synthetic(&elf, |_, _| false);
+    check!(
+        t,
+        entry.is_err(),
+        "elf: segment out of the guest memory accepted, entry {:x?}",
+        entry
+    );
+}
+
+fn test_virtio_config(t: &mut SelfTest) {
+    const BLK: EmuDeviceType = EmuDeviceType::EmuDeviceTVirtioBlk;
+    const CONSOLE: EmuDeviceType = EmuDeviceType::EmuDeviceTVirtioConsole;
+    // the repr(C) blk config ends at 72 with its padding, the console config at 12
+    const BLK_LEN: usize = 72;
+    const CONSOLE_LEN: usize = 12;
+    const CAPACITY: usize = 0x1122_3344_5566_7788;
+    // (device, offset, width, the value read)
+    let cases = [
+        (BLK, 0, 8, Some(CAPACITY as u64)),
+        (BLK, 0, 4, Some(0x5566_7788)),
+        (BLK, 2, 2, Some(0x5566)),
+        (BLK, 7, 1, Some(0x11)),
+        (BLK, 0, 3, None),
+        (BLK, 0, 16, None),
+        (BLK, 0, 0, None),
+        (BLK, BLK_LEN - 8, 8, Some(0)),
+        (BLK, BLK_LEN - 4, 8, None),
+        (BLK, BLK_LEN - 1, 1, Some(0)),
+        (BLK, BLK_LEN, 1, None),
+        (BLK, usize::MAX, 1, None),
+        // cols 80 and rows 25 of the console, one port
+        (CONSOLE, 0, 2, Some(80)),
+        (CONSOLE, 2, 2, Some(25)),
+        (CONSOLE, 0, 4, Some((25 << 16) | 80)),
+        (CONSOLE, 4, 4, Some(1)),
+        (CONSOLE, CONSOLE_LEN - 4, 4, Some(0)),
+        (CONSOLE, CONSOLE_LEN - 4, 8, None),
+        (CONSOLE, CONSOLE_LEN - 1, 1, Some(0)),
+        (CONSOLE, CONSOLE_LEN, 1, None),
+        (EmuDeviceType::EmuDeviceTVirtioRng, 0, 1, None),
+    ];
+    for (emu_type, offset, width, expect) in cases {
+        let val = virtio_config_read_synthetic(emu_type, CAPACITY, offset, width);
+        check!(
+            t,
+            val == expect,
+            "virtio config of {:?} at {:#x} width {} read {:x?}, not {:x?}",
+            emu_type,
+            offset,
+            width,
+            val,
+            expect
+        );
+    }
+}
+
+fn test_desc_chain(t: &mut SelfTest) {
+    const N: u16 = VIRTQ_DESC_F_NEXT;
+    // 0 -> 2 -> 1
+    let table = [(N, 2), (0, 0), (N, 1), (0, 0)];
+    let walked = desc_chain_walk_synthetic(&table, 0);
+    check!(
+        t,
+        matches!(&walked, Ok(walked) if walked[..] == [0, 2, 1]),
+        "desc chain 0 -> 2 -> 1: {:?}",
+        walked
+    );
+    check!(
+        t,
+        matches!(desc_chain_walk_synthetic(&table, 3), Ok(walked) if walked[..] == [3]),
+        "desc chain of a single desc"
+    );
+    // head and next out of the queue
+    check!(
+        t,
+        matches!(
+            desc_chain_walk_synthetic(&table, 4),
+            Err(DescChainError::IndexOutOfRange(4))
+        ),
+        "desc chain head out of range"
+    );
+    let table = [(N, 1), (N, 4), (0, 0), (0, 0)];
+    check!(
+        t,
+        matches!(
+            desc_chain_walk_synthetic(&table, 0),
+            Err(DescChainError::IndexOutOfRange(4))
+        ),
+        "desc chain next out of range"
+    );
+    // 0 -> 1 -> 2 -> 1
+    let table = [(N, 1), (N, 2), (N, 1), (0, 0)];
+    check!(
+        t,
+        matches!(desc_chain_walk_synthetic(&table, 0), Err(DescChainError::Loop(1))),
+        "desc chain loop"
+    );
+    let table = [(N, 0)];
+    check!(
+        t,
+        matches!(desc_chain_walk_synthetic(&table, 0), Err(DescChainError::Loop(0))),
+        "desc chain self loop"
+    );
+    // a chain through every desc of the queue is the longest legal one
+    let table: Vec<(u16, u16)> = (0..256).map(|i| (if i == 255 { 0 } else { N }, i + 1)).collect();
+    check!(
+        t,
+        matches!(desc_chain_walk_synthetic(&table, 0), Ok(walked) if walked.len() == 256),
+        "desc chain over the whole queue"
+    );
+}
+
+fn test_dirty_log(t: &mut SelfTest) {
+    // 16 pages then 3 pages, bits 0..16 and 16..19
+    let regions = alloc::vec![
+        VmRegion {
+            ipa_start: 0x4000_0000,
+            length: 0x10000,
+        },
+        VmRegion {
+            ipa_start: 0x8000_0000,
+            length: 0x3000,
+        },
+    ];
+    let mut log = DirtyLog::new(regions);
+    let mut out = [0_usize; 1];
+    check!(t, log.map_size() == 8, "dirty log map size {}", log.map_size());
+    check!(
+        t,
+        log.dirty_pages() == 19,
+        "dirty log starts all dirty, {} pages",
+        log.dirty_pages()
+    );
+    let ranges = log.fetch(&mut out);
+    check!(t, out[0] == (1 << 19) - 1, "dirty log first fetch {:#x}", out[0]);
+    check!(
+        t,
+        ranges == [(0x4000_0000, 0x10000), (0x8000_0000, 0x3000)],
+        "dirty log first ranges {:x?}",
+        ranges
+    );
+    check!(t, log.dirty_pages() == 0, "dirty log cleared by fetch");
+
+    // a write across a page boundary, one outside of the memory, the last page
+    log.mark(0x4000_0ff0, 0x20);
+    log.mark(0x9000_0000, 0x1000);
+    log.mark(0x8000_2fff, 1);
+    check!(t, log.contains(0x8000_2fff), "dirty log contains the last page");
+    check!(t, !log.contains(0x8000_3000), "dirty log does not contain the end");
+    let ranges = log.fetch(&mut out);
+    check!(t, out[0] == 0b11 | 1 << 18, "dirty log second fetch {:#x}", out[0]);
+    check!(
+        t,
+        ranges == [(0x4000_0000, 0x2000), (0x8000_2000, 0x1000)],
+        "dirty log second ranges {:x?}",
+        ranges
+    );
+    let ranges = log.fetch(&mut out);
+    check!(
+        t,
+        out[0] == 0 && ranges.is_empty(),
+        "dirty log converges when nothing is written"
+    );
+}
+
+fn test_split_block(t: &mut SelfTest) {
+    const BLOCK: usize = 1 << 21;
+    const IPA: usize = 0x4000_0000;
+    // the table is never walked by the mmu, the pa is not touched
+    const PA: usize = 0x8020_0000;
+    let frame = match mem_page_alloc(PageUsage::PageTable) {
+        Ok(frame) => frame,
+        Err(_) => {
+            check!(t, false, "split block: no page for the table");
+            return;
+        }
+    };
+    // a VMID no VM has, for the TLB invalidation
+    let pt = PageTable::new(frame, CONFIG_VM_NUM_MAX);
+    pt.pt_map_range(IPA, BLOCK, PA, PTE_S2_NORMAL, true);
+    let block = pt.get_pte(IPA, 2);
+    check!(
+        t,
+        block.is_some_and(|pte| pte & 0b11 == 0b01) && pt.get_pte(IPA, 3).is_none(),
+        "split block: 2MB block mapped {:x?}",
+        block
+    );
+
+    // write protecting one page splits the block
+    check!(
+        t,
+        pt.pt_set_access_permission(IPA + PAGE_SIZE, PAGE_SIZE, PTE_S2_FIELD_AP_RO),
+        "split block: write protect a page"
+    );
+    let wrong = (0..BLOCK / PAGE_SIZE)
+        .filter(|i| pt.ipa2pa(IPA + i * PAGE_SIZE) != Some(PA + i * PAGE_SIZE))
+        .count();
+    check!(t, wrong == 0, "split block: {} pages translate to a wrong pa", wrong);
+    let ap = |ipa: usize| pt.get_pte(ipa, 3).map(|pte| pte & PTE_S2_FIELD_AP_RW);
+    check!(
+        t,
+        ap(IPA) == Some(PTE_S2_FIELD_AP_RW)
+            && ap(IPA + PAGE_SIZE) == Some(PTE_S2_FIELD_AP_RO)
+            && ap(IPA + 2 * PAGE_SIZE) == Some(PTE_S2_FIELD_AP_RW),
+        "split block: access permissions {:x?} {:x?} {:x?}",
+        ap(IPA),
+        ap(IPA + PAGE_SIZE),
+        ap(IPA + 2 * PAGE_SIZE)
+    );
+
+    // the pages differ, they are not merged
+    check!(
+        t,
+        pt.pt_collapse_range(IPA, BLOCK) == 0,
+        "split block: collapse pages of different permissions"
+    );
+    pt.pt_set_access_permission(IPA + PAGE_SIZE, PAGE_SIZE, PTE_S2_FIELD_AP_RW);
+    check!(t, pt.pt_collapse_range(IPA, BLOCK) == 1, "split block: collapse");
+    check!(
+        t,
+        pt.get_pte(IPA, 2) == block && pt.ipa2pa(IPA + BLOCK - 1) == Some(PA + BLOCK - 1),
+        "split block: collapsed to {:x?}, mapped as {:x?}",
+        pt.get_pte(IPA, 2),
+        block
+    );
+}
+
+fn test_log_module(t: &mut SelfTest) {
+    let cases = [
+        ("rtshyper_rs::arch::aarch64::vgic", LogModule::Vgic),
+        ("rtshyper_rs::device::virtio::blk", LogModule::VirtioBlk),
+        ("rtshyper_rs::device::virtio::mediated", LogModule::VirtioBlk),
+        ("rtshyper_rs::device::virtio::net", LogModule::VirtioNet),
+        ("rtshyper_rs::vmm::manager", LogModule::Vmm),
+        ("rtshyper_rs::mm::page_frame", LogModule::Mem),
+        ("rtshyper_rs::kernel::mem", LogModule::Mem),
+        ("rtshyper_rs::kernel::vm", LogModule::Other),
+        ("rtshyper_rs::device::virtio::console", LogModule::Other),
+        ("rtshyper_rs", LogModule::Other),
+    ];
+    for (target, expect) in cases {
+        let module = LogModule::from_target(target);
+        check!(
+            t,
+            module == expect,
+            "log module of {} is {:?}, expect {:?}",
+            target,
+            module,
+            expect
+        );
+    }
+}
+
+// a queue set ready with rings outside the memory of the static bma config is refused
+#[cfg(feature = "static-config")]
+fn test_virtq_rings(t: &mut SelfTest) {
+    const VM_ID: usize = 1;
+    const NUM: usize = 16;
+    let regions = crate::config::bma_vm_region();
+    let ram_end = regions[0].ipa_start + regions[0].length;
+    // avail ring of 16 entries: flags, idx, 16 entries and used_event
+    let avail_size = 3 * 2 + NUM * 2;
+    let good = [0x4000_0000, 0x4000_1000, 0x4000_2000];
+    // num, (desc table, avail, used), accepted
+    let cases = [
+        (NUM, good, true),
+        (NUM, [good[0], ram_end - avail_size, good[2]], true),
+        (NUM, [0, good[1], good[2]], false),
+        (NUM, [good[0], 0, good[2]], false),
+        (NUM, [ram_end, good[1], good[2]], false),
+        (NUM, [0x3fff_f000, good[1], good[2]], false),
+        (NUM, [good[0], ram_end - avail_size + 2, good[2]], false),
+        (NUM, [good[0] + 8, good[1], good[2]], false),
+        (NUM, [good[0], good[1] + 1, good[2]], false),
+        (NUM, [good[0], good[1], good[2] + 2], false),
+        (NUM, [(1 << VM_IPA_SIZE) | good[0], good[1], good[2]], false),
+        (0, good, false),
+    ];
+    for (num, addrs, ok) in cases {
+        let hva = virtq_rings_synthetic(VM_ID, &regions, num, addrs);
+        check!(
+            t,
+            match hva {
+                Ok(hva) => ok && (0..3).all(|i| hva[i] == vm_ipa2hva_prefix(VM_ID, addrs[i])),
+                Err(()) => !ok,
+            },
+            "virtq num {} rings {:x?}: {:x?}",
+            num,
+            addrs,
+            hva
+        );
+    }
+}
+
+#[cfg(feature = "memory-reservation")]
+fn test_mem_bw_history(t: &mut SelfTest) {
+    use super::bwres::membwres::{MemBwHistory, MemBwRecord, MEM_BW_HISTORY_LEN};
+    use core::time::Duration;
+    let ms = Duration::from_millis;
+    let mut history = MemBwHistory::new(2, 1000, ms(0));
+    let mut out = [MemBwRecord::default(); MEM_BW_HISTORY_LEN + 1];
+    // both vcpus report, the period is closed by the second
+    history.report(0, 100, ms(100));
+    check!(
+        t,
+        history.copy_to(&mut out) == 0,
+        "mem bw period closed by one of two vcpus"
+    );
+    history.report(1, 200, ms(101));
+    check!(
+        t,
+        history.copy_to(&mut out) == 1
+            && out[0]
+                == MemBwRecord {
+                    start_us: 0,
+                    end_us: 101_000,
+                    used: 300,
+                    budget: 1000
+                },
+        "mem bw first period {:?}",
+        out[0]
+    );
+    // vcpu 1 missed its replenishment, vcpu 0 closes the period on its second report
+    history.report(0, 50, ms(200));
+    history.report(0, 60, ms(300));
+    check!(
+        t,
+        history.copy_to(&mut out) == 2 && out[1].used == 50 && out[1].end_us == 300_000,
+        "mem bw period closed by a vcpu reporting twice {:?}",
+        out[1]
+    );
+    // the ring keeps the last periods, oldest first
+    let mut history = MemBwHistory::new(1, 1000, ms(0));
+    for i in 0..=MEM_BW_HISTORY_LEN as u32 {
+        history.report(0, i, ms(100 * (i as u64 + 1)));
+    }
+    let count = history.copy_to(&mut out);
+    check!(
+        t,
+        count == MEM_BW_HISTORY_LEN && out[0].used == 1 && out[count - 1].used == MEM_BW_HISTORY_LEN as u32,
+        "mem bw ring of {} records, last used {}",
+        count,
+        out[count - 1].used
+    );
+    let mut short = [MemBwRecord::default(); 2];
+    check!(
+        t,
+        history.copy_to(&mut short) == 2 && short[1].used == MEM_BW_HISTORY_LEN as u32,
+        "mem bw copy to a short buffer keeps the newest"
+    );
+}
+
+/* Check the helpers that guest input flows through, on core 0 before VM0 is created with the
+ * self-test feature, and again on HVC_SYS_TEST from VM0. Returns false if any case failed.
+ */
+pub fn self_test() -> bool {
+    let mut t = SelfTest { cases: 0, failed: 0 };
+    test_bitmap(&mut t);
+    test_cpu_config(&mut t);
+    test_ipa2hva(&mut t);
+    test_ipa2hva_range(&mut t);
+    test_color_bitmap(&mut t);
+    test_color_layout(&mut t);
+    test_color_pool(&mut t);
+    test_color_locality(&mut t);
+    test_memory_region(&mut t);
+    test_boot_state(&mut t);
+    test_image_upload(&mut t);
+    test_vtimer_epoch(&mut t);
+    test_ticks_to_duration(&mut t);
+    test_vgicd_lanes(&mut t);
+    test_vgic_lr_stress(&mut t);
+    test_hvc_caps(&mut t);
+    test_exit_stat(&mut t);
+    test_elf_parse(&mut t);
+    test_virtio_config(&mut t);
+    test_desc_chain(&mut t);
+    #[cfg(feature = "static-config")]
+    test_virtq_rings(&mut t);
+    test_dirty_log(&mut t);
+    test_split_block(&mut t);
+    test_log_module(&mut t);
+    #[cfg(feature = "memory-reservation")]
+    test_mem_bw_history(&mut t);
+    if t.failed == 0 {
+        info!("self_test: {} cases passed", t.cases);
+    } else {
+        error!("self_test: {} of {} cases failed", t.failed, t.cases);
+    }
+    t.failed == 0
+}
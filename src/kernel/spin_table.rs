@@ -0,0 +1,98 @@
+use core::mem::size_of;
+
+use crate::arch::{vcpu_power_on, PAGE_SIZE, PTE_S2_RO};
+use crate::config::SmpBoot;
+use crate::kernel::{mem_page_alloc, Vm};
+use crate::mm::PageUsage;
+
+/* Allocate the release page of a VM that brings up its secondary vcpus with the spin-table and map it
+ * read-only at its ipa: the guest reads it without a trap, and a write traps to `spin_table_write`.
+ * The page is checked again here, the config may have been edited since it was set, and must not
+ * hit the memory of the VM, hot-added or lazily mapped, nor anything mapped in stage-2.
+ */
+pub fn spin_table_init(vm: &Vm) -> bool {
+    let release_ipa = match vm.config().smp_boot() {
+        SmpBoot::SpinTable(ipa) => ipa,
+        SmpBoot::Psci => return true,
+    };
+    let range = release_ipa..release_ipa + PAGE_SIZE;
+    if !vm.config().spin_table_valid(release_ipa)
+        || vm
+            .memory_regions()
+            .iter()
+            .any(|region| region.ipa_start < range.end && range.start < region.ipa_start + region.length)
+        || vm.ipa2pa(release_ipa).is_some()
+        || vm.cpu_num() * size_of::<u64>() > PAGE_SIZE
+    {
+        error!(
+            "spin_table_init: VM[{}] illegal release page ipa {:#x}",
+            vm.id(),
+            release_ipa
+        );
+        return false;
+    }
+    let frame = match mem_page_alloc(PageUsage::SpinTable) {
+        Ok(frame) => frame,
+        Err(_) => {
+            error!("spin_table_init: VM[{}] alloc page failed", vm.id());
+            return false;
+        }
+    };
+    vm.pt_map_range(release_ipa, PAGE_SIZE, frame.pa, PTE_S2_RO, false);
+    vm.set_spin_table_page(frame);
+    info!("VM[{}] spin-table release page at ipa {:#x}", vm.id(), release_ipa);
+    true
+}
+
+// the cpu-release-addr of vcpu `vcpu_id`, the n-th u64 of the release page
+pub fn spin_table_release_addr(release_ipa: usize, vcpu_id: usize) -> usize {
+    release_ipa + vcpu_id * size_of::<u64>()
+}
+
+/* A store of `width` bytes of `val` to `ipa` trapped on the release page of `vm`, do it for the guest.
+ * A u64 written to the slot of a secondary vcpu starts it at `val` the same way as PSCI CPU_ON.
+ * Return false if `ipa` is not on the release page.
+ */
+pub fn spin_table_write(vm: &Vm, ipa: usize, width: usize, val: usize) -> bool {
+    let release_ipa = match vm.config().smp_boot() {
+        SmpBoot::SpinTable(ipa) => ipa,
+        SmpBoot::Psci => return false,
+    };
+    let hva = match vm.spin_table_page() {
+        Some(hva) => hva,
+        None => return false,
+    };
+    let offset = ipa.wrapping_sub(release_ipa);
+    if offset >= PAGE_SIZE || offset + width > PAGE_SIZE {
+        return false;
+    }
+    unsafe {
+        let ptr = (hva + offset) as *mut u8;
+        match width {
+            1 => core::ptr::write_volatile(ptr, val as u8),
+            2 => core::ptr::write_volatile(ptr as *mut u16, val as u16),
+            4 => core::ptr::write_volatile(ptr as *mut u32, val as u32),
+            _ => core::ptr::write_volatile(ptr as *mut u64, val as u64),
+        }
+    }
+    let vcpu_id = offset / size_of::<u64>();
+    if width != size_of::<u64>() || offset % size_of::<u64>() != 0 || vcpu_id == 0 || val == 0 {
+        return true;
+    }
+    info!(
+        "VM[{}] vcpu {} released by the spin-table, entry {:#x}",
+        vm.id(),
+        vcpu_id,
+        val
+    );
+    let ret = vcpu_power_on(vm, vcpu_id, val, 0);
+    if ret != 0 {
+        warn!(
+            "spin_table_write: VM[{}] vcpu {} not started, psci error {}",
+            vm.id(),
+            vcpu_id,
+            ret as isize
+        );
+    }
+    true
+}
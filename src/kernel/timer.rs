@@ -4,6 +4,9 @@ use crate::arch::INTERRUPT_IRQ_HYPERVISOR_TIMER;
 use crate::kernel::current_cpu;
 use crate::util::timer_list::{TimerEvent, TimerValue};
 
+// ms between two scheduler ticks
+pub const TIMER_SLICE: usize = 10;
+
 pub fn timer_init() {
     crate::arch::timer::timer_arch_init();
     timer_enable(false);
@@ -30,13 +33,13 @@ pub fn get_counter() -> usize {
     crate::arch::timer::timer_arch_get_counter()
 }
 
-fn timer_notify_after(ms: usize) {
+pub(super) fn timer_notify_after(after: TimerValue) {
     use crate::arch::timer::{timer_arch_enable_irq, timer_arch_set};
-    if ms == 0 {
+    if after.is_zero() {
         return;
     }
 
-    timer_arch_set(ms);
+    timer_arch_set(after.as_micros() as usize);
     timer_arch_enable_irq();
 }
 
@@ -53,9 +56,22 @@ pub fn timer_irq_handler() {
 
     check_timer_event(now());
 
-    current_cpu().vcpu_array.resched();
+    #[cfg(feature = "watchdog")]
+    super::watchdog::watchdog_tick();
+
+    current_cpu().vcpu_array.tick();
 
-    timer_notify_after(10);
+    // the tick follows the slice of the vcpu running now
+    timer_notify_after(current_cpu().vcpu_array.next_tick());
+}
+
+// the time `ticks` of a counter running at `freq` Hz take
+pub fn ticks_to_duration(ticks: u64, freq: u64) -> TimerValue {
+    if freq == 0 {
+        return TimerValue::ZERO;
+    }
+    let ns = ticks as u128 * 1_000_000_000 / freq as u128;
+    TimerValue::from_nanos(u64::try_from(ns).unwrap_or(u64::MAX))
 }
 
 #[allow(dead_code)]
@@ -0,0 +1,93 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch::{Arch, ArchTrait, LVL1_SHIFT, PAGE_SIZE, PTE_S1_NORMAL};
+use crate::kernel::current_cpu;
+use crate::mm::vpage_allocator::vpage_alloc;
+use crate::util::barrier;
+
+const TLB_STRESS_ROUNDS: usize = 10000;
+
+#[repr(C, align(4096))]
+struct ScratchPage {
+    round: AtomicUsize,
+}
+
+// the two frames the scratch va is switched between, each holds the round it is mapped for
+static SCRATCH_FRAMES: [ScratchPage; 2] = [
+    ScratchPage {
+        round: AtomicUsize::new(0),
+    },
+    ScratchPage {
+        round: AtomicUsize::new(0),
+    },
+];
+static SCRATCH_VA: AtomicUsize = AtomicUsize::new(0);
+static SCRATCH_PTE: AtomicUsize = AtomicUsize::new(0);
+// the round mapped by core 0 and the round checked by core 1
+static MAPPED: AtomicUsize = AtomicUsize::new(0);
+static CHECKED: AtomicUsize = AtomicUsize::new(0);
+
+/* Core 0 unmaps and remaps a scratch va between two frames in a loop, core 1 reads through the
+ * same va after each remap, so reading the round of the previous frame is a stale translation.
+ * Every core calls it after the self coloring, cores other than 0 and 1 only wait at the barrier.
+ */
+pub fn tlb_stress_test() {
+    match current_cpu().id {
+        0 => tlb_stress_remap(),
+        1 => tlb_stress_check(),
+        _ => {}
+    }
+    barrier();
+}
+
+fn tlb_stress_remap() {
+    // take a whole level 1 entry, so that it can be shared with core 1 as vmm_map_ipa_percore does
+    let va_pages = vpage_alloc(1 << LVL1_SHIFT, Some(1 << LVL1_SHIFT)).expect("tlb_stress: vpage_alloc");
+    let va = *va_pages.as_range_incluesive().start();
+    let pa = |round: usize| Arch::mem_translate(&SCRATCH_FRAMES[round % 2] as *const _ as usize).unwrap();
+    let pt = current_cpu().pt();
+
+    pt.pt_map_range(va, PAGE_SIZE, pa(0), PTE_S1_NORMAL, false);
+    SCRATCH_PTE.store(pt.get_pte(va, 1).unwrap(), Ordering::Relaxed);
+    SCRATCH_VA.store(va, Ordering::Release);
+    for round in 1..=TLB_STRESS_ROUNDS {
+        SCRATCH_FRAMES[round % 2].round.store(round, Ordering::Release);
+        pt.pt_unmap_range(va, PAGE_SIZE);
+        pt.pt_map_range(va, PAGE_SIZE, pa(round), PTE_S1_NORMAL, false);
+        MAPPED.store(round, Ordering::Release);
+        while CHECKED.load(Ordering::Acquire) != round {
+            core::hint::spin_loop();
+        }
+    }
+    pt.pt_unmap_range(va, PAGE_SIZE);
+}
+
+fn tlb_stress_check() {
+    while SCRATCH_VA.load(Ordering::Acquire) == 0 {
+        core::hint::spin_loop();
+    }
+    let va = SCRATCH_VA.load(Ordering::Relaxed);
+    current_cpu().pt().set_pte(va, 1, SCRATCH_PTE.load(Ordering::Relaxed));
+    let scratch = unsafe { &*(va as *const AtomicUsize) };
+
+    let mut stale = 0;
+    for round in 1..=TLB_STRESS_ROUNDS {
+        while MAPPED.load(Ordering::Acquire) != round {
+            core::hint::spin_loop();
+        }
+        // the read of this round caches the translation that the next remap must invalidate
+        let val = scratch.load(Ordering::Acquire);
+        if val != round {
+            if stale == 0 {
+                error!("tlb_stress: round {} reads {} through a stale translation", round, val);
+            }
+            stale += 1;
+        }
+        CHECKED.store(round, Ordering::Release);
+    }
+    if stale == 0 {
+        info!("tlb_stress: {} remaps without stale reads", TLB_STRESS_ROUNDS);
+    } else {
+        error!("tlb_stress: {} stale reads in {} remaps", stale, TLB_STRESS_ROUNDS);
+    }
+}
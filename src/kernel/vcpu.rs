@@ -1,10 +1,11 @@
 use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::{Lazy, Mutex};
 
-use crate::arch::{ContextFrame, ContextFrameTrait, InterruptContext, InterruptContextTriat, VmContext};
+use crate::arch::{ContextFrame, ContextFrameTrait, InterruptContext, InterruptContextTriat, VirtPmu, VmContext};
 use crate::config::VmConfigEntry;
-use crate::kernel::{current_cpu, interrupt_vm_inject};
+use crate::kernel::{current_cpu, interrupt_vm_inject, VcpuExitStat};
 
 #[cfg(feature = "memory-reservation")]
 use super::bwres::membwres::MemoryBandwidth;
@@ -45,6 +46,7 @@ impl WeakVcpu {
 pub struct VcpuInner {
     inner_const: VcpuConst,
     pub inner_mut: Mutex<VcpuInnerMut>,
+    exit_stat: VcpuExitStat,
     #[cfg(feature = "memory-reservation")]
     reservation: MemoryBandwidth,
     #[cfg(feature = "memory-reservation")]
@@ -52,9 +54,9 @@ pub struct VcpuInner {
 }
 
 struct VcpuConst {
-    id: usize,      // vcpu_id
-    vm: Weak<Vm>,   // weak pointer to related Vm
-    phys_id: usize, // related physical CPU id
+    id: usize,            // vcpu_id
+    vm: Weak<Vm>,         // weak pointer to related Vm
+    phys_id: AtomicUsize, // related physical CPU id, only changed by a vcpu migration
 }
 
 impl Vcpu {
@@ -63,7 +65,7 @@ impl Vcpu {
         let inner_const = VcpuConst {
             id: vcpu_id,
             vm,
-            phys_id,
+            phys_id: AtomicUsize::new(phys_id),
         };
         #[cfg(feature = "memory-reservation")]
         let inner = Arc::new_cyclic(|weak| VcpuInner {
@@ -80,37 +82,62 @@ impl Vcpu {
                 None
             },
             inner_mut: Mutex::new(VcpuInnerMut::new()),
+            exit_stat: VcpuExitStat::new(),
         });
         #[cfg(not(feature = "memory-reservation"))]
         let inner = Arc::new(VcpuInner {
             inner_const,
             inner_mut: Mutex::new(VcpuInnerMut::new()),
+            exit_stat: VcpuExitStat::new(),
         });
         Self(inner)
     }
 
+    #[cfg(feature = "trap-wfi")]
+    pub fn downgrade(&self) -> WeakVcpu {
+        WeakVcpu(Arc::downgrade(&self.0))
+    }
+
     #[cfg(feature = "memory-reservation")]
     pub(super) fn pmu_event(&self) -> Option<Arc<PmuTimerEvent>> {
         self.0.pmu_event.clone()
     }
 
-    pub fn init(&self, config: &VmConfigEntry) {
-        self.init_boot_info(config);
+    pub fn init(&self, vm: &Vm) {
+        self.init_boot_info(vm);
         self.reset_context();
     }
 
-    pub fn init_boot_info(&self, config: &VmConfigEntry) {
+    pub fn init_boot_info(&self, vm: &Vm) {
         use crate::kernel::VmType;
+        let config = vm.config();
         let arg = match config.os_type {
             VmType::VmTOs => config.device_tree_load_ipa(),
+            VmType::VmTBma if config.boot_info_load_ipa() != 0 => config.boot_info_load_ipa(),
             _ => {
                 let arg = &config.memory_region()[0];
                 arg.ipa_start + arg.length
             }
         };
+        let entry = vm.entry_point();
+        let mut inner = self.0.inner_mut.lock();
+        inner.vcpu_ctx.set_argument(arg);
+        inner.vcpu_ctx.set_exception_pc(entry);
+    }
+
+    /* Start the vcpu at `entry` with `arg` in x0 from a clean context, as PSCI CPU_ON asks.
+     * A vcpu powered off before must not resume with the registers of its last run,
+     * only what the hypervisor set up for it (vmpidr, hcr, vpmu) is kept.
+     */
+    pub fn reset_power_on(&self, entry: usize, arg: usize) {
         let mut inner = self.0.inner_mut.lock();
+        inner.vcpu_ctx = ContextFrame::default();
+        inner.vcpu_ctx.set_exception_pc(entry);
         inner.vcpu_ctx.set_argument(arg);
-        inner.vcpu_ctx.set_exception_pc(config.kernel_entry_point());
+        let old = core::mem::replace(&mut inner.vm_ctx, VmContext::new());
+        inner.vm_ctx.vmpidr_el2 = old.vmpidr_el2;
+        inner.vm_ctx.hcr_el2 = old.hcr_el2;
+        inner.vm_ctx.vpmu = old.vpmu;
     }
 
     // pub fn shutdown(&self) {
@@ -151,6 +178,7 @@ impl Vcpu {
         self.restore_cpu_ctx();
 
         let mut inner = self.0.inner_mut.lock();
+        // the CNTVOFF_EL2 of this vcpu follows the epoch of the VM, be it descheduled alone or just onlined
         #[cfg(feature = "vtimer")]
         inner.vm_ctx.generic_timer.set_offset(vtimer_offset as u64);
         inner.vm_ctx.ext_regs_restore();
@@ -188,6 +216,12 @@ impl Vcpu {
         }
     }
 
+    // a copy of the saved registers, only up to date while the vcpu is not running
+    pub fn context_snapshot(&self) -> (ContextFrame, VmContext) {
+        let inner = self.0.inner_mut.lock();
+        (inner.vcpu_ctx, inner.vm_ctx)
+    }
+
     pub fn state(&self) -> VcpuState {
         let inner = self.0.inner_mut.lock();
         inner.state
@@ -198,6 +232,17 @@ impl Vcpu {
         inner.state = state;
     }
 
+    // blocked by a trapped wfi, not by a pause
+    pub fn wfi_blocked(&self) -> bool {
+        let inner = self.0.inner_mut.lock();
+        inner.wfi
+    }
+
+    pub(super) fn set_wfi_blocked(&self, wfi: bool) {
+        let mut inner = self.0.inner_mut.lock();
+        inner.wfi = wfi;
+    }
+
     #[inline]
     pub fn id(&self) -> usize {
         self.0.inner_const.id
@@ -210,7 +255,12 @@ impl Vcpu {
 
     #[inline]
     pub fn phys_id(&self) -> usize {
-        self.0.inner_const.phys_id
+        self.0.inner_const.phys_id.load(Ordering::Acquire)
+    }
+
+    // the vcpu must be detached from the vcpu array of its old core
+    pub(crate) fn set_phys_id(&self, phys_id: usize) {
+        self.0.inner_const.phys_id.store(phys_id, Ordering::Release);
     }
 
     pub fn vm_id(&self) -> usize {
@@ -251,6 +301,14 @@ impl Vcpu {
         inner.vcpu_ctx.set_gpr(idx, val);
     }
 
+    pub fn vpmu_access<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut VirtPmu) -> R,
+    {
+        let mut inner = self.0.inner_mut.lock();
+        f(&mut inner.vm_ctx.vpmu)
+    }
+
     pub fn push_int(&self, int: usize) {
         let mut inner = self.0.inner_mut.lock();
         if !inner.int_list.contains(&int) {
@@ -274,6 +332,62 @@ impl Vcpu {
     pub fn bw_info(&self) -> &MemoryBandwidth {
         &self.0.reservation
     }
+
+    // current halt polling window in counter ticks, bounded by `max`
+    #[cfg(feature = "trap-wfi")]
+    pub fn halt_poll_window(&self, max: usize) -> usize {
+        let inner = self.0.inner_mut.lock();
+        match inner.halt_poll.window {
+            0 => max,
+            window => usize::min(window, max),
+        }
+    }
+
+    // grow the window on an early wakeup, shrink it on a wasted poll
+    #[cfg(feature = "trap-wfi")]
+    pub fn halt_poll_update(&self, success: bool, max: usize) {
+        let mut inner = self.0.inner_mut.lock();
+        let halt_poll = &mut inner.halt_poll;
+        let window = match halt_poll.window {
+            0 => max,
+            window => window,
+        };
+        if success {
+            halt_poll.stat.success += 1;
+            halt_poll.window = usize::min(window * HALT_POLL_GROW, max);
+        } else {
+            halt_poll.stat.fail += 1;
+            halt_poll.window = usize::max(window / HALT_POLL_SHRINK, usize::max(max / HALT_POLL_MIN_DIV, 1));
+        }
+    }
+
+    pub fn halt_poll_stat(&self) -> HaltPollStat {
+        let inner = self.0.inner_mut.lock();
+        inner.halt_poll.stat
+    }
+
+    // the exits of this vcpu, counted without a lock on the exit path
+    pub fn exit_stat(&self) -> &VcpuExitStat {
+        &self.0.exit_stat
+    }
+}
+
+const HALT_POLL_GROW: usize = 2;
+const HALT_POLL_SHRINK: usize = 2;
+// the window never shrinks below `max / HALT_POLL_MIN_DIV`
+const HALT_POLL_MIN_DIV: usize = 16;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct HaltPollStat {
+    pub success: usize,
+    pub fail: usize,
+}
+
+#[derive(Default)]
+struct HaltPoll {
+    window: usize,
+    stat: HaltPollStat,
 }
 
 pub struct VcpuInnerMut {
@@ -283,6 +397,8 @@ pub struct VcpuInnerMut {
     vcpu_ctx: ContextFrame,
     pub vm_ctx: VmContext,
     pub intc_ctx: InterruptContext,
+    halt_poll: HaltPoll,
+    wfi: bool,
 }
 
 impl VcpuInnerMut {
@@ -293,6 +409,8 @@ impl VcpuInnerMut {
             vcpu_ctx: ContextFrame::default(),
             vm_ctx: VmContext::new(),
             intc_ctx: InterruptContext::default(),
+            halt_poll: HaltPoll::default(),
+            wfi: false,
         }
     }
 }
@@ -1,21 +1,37 @@
 use crate::{
     arch::ArchTrait,
-    kernel::{current_cpu, CpuState, Vcpu, CONFIG_VM_NUM_MAX},
+    kernel::{current_cpu, event_trace, CpuState, TraceEvent, Vcpu},
+    util::timer_list::TimerValue,
 };
+#[cfg(feature = "trap-wfi")]
+use crate::{kernel::WeakVcpu, util::timer_list::TimerEvent};
+#[cfg(feature = "trap-wfi")]
+use alloc::sync::Arc;
 use alloc::{
     boxed::Box,
     slice::{Iter, IterMut},
+    vec::Vec,
 };
 use spin::Once;
 
-use super::{sched::Scheduler, timer::timer_enable, VcpuState};
+#[cfg(feature = "trap-wfi")]
+use super::timer::{remove_timer_event, start_timer_event, ticks_to_duration};
+use super::{
+    sched::{Scheduler, SCHED_SLICE_MIN_US},
+    timer::{timer_enable, timer_notify_after, TIMER_SLICE},
+    VcpuState,
+};
 
+// the vcpus on a core, several of them may belong to the same VM
 pub struct VcpuArray {
-    array: [Option<Vcpu>; CONFIG_VM_NUM_MAX],
+    array: Vec<Vcpu>,
     pub(super) sched: Once<Box<dyn Scheduler<SchedItem = Vcpu>>>,
-    len: usize,
     active: usize,
     timer_on: bool,
+    // a woken vcpu should preempt the running one when the trap returns
+    need_resched: bool,
+    // vcpus blocked by a trapped wfi, their timer events need the scheduler tick
+    wfi_num: usize,
 }
 
 cfg_if::cfg_if! {
@@ -29,57 +45,67 @@ cfg_if::cfg_if! {
 impl VcpuArray {
     pub const fn new() -> Self {
         Self {
-            array: [const { None }; CONFIG_VM_NUM_MAX],
+            array: Vec::new(),
             sched: Once::new(),
-            len: 0,
             active: 0,
             timer_on: false,
+            need_resched: false,
+            wfi_num: 0,
         }
     }
 
+    // the first vcpu of VM `vm_id` on this core
     #[inline]
     pub fn pop_vcpu_through_vmid(&self, vm_id: usize) -> Option<&Vcpu> {
-        match self.array.get(vm_id) {
-            Some(vcpu) => vcpu.as_ref(),
-            None => None,
-        }
+        self.array.iter().find(|vcpu| vcpu.vm_id() == vm_id)
+    }
+
+    #[inline]
+    pub fn vcpu_by_id(&self, vm_id: usize, vcpu_id: usize) -> Option<&Vcpu> {
+        self.array
+            .iter()
+            .find(|vcpu| vcpu.vm_id() == vm_id && vcpu.id() == vcpu_id)
     }
 
     #[inline]
     pub(super) fn vcpu_num(&self) -> usize {
-        self.len
+        self.array.len()
     }
 
-    pub fn append_vcpu(&mut self, vcpu: Vcpu) {
-        // There is only 1 VCPU from a VM in a PCPU
+    // return false if the vcpu belongs to another core or is here already
+    pub fn append_vcpu(&mut self, vcpu: Vcpu) -> bool {
         let vm_id = vcpu.vm_id();
-        match self.array.get_mut(vm_id) {
-            Some(x) => match x {
-                Some(_) => error!("self.array[{vm_id}].is_some()"),
-                None => {
-                    debug_assert_eq!(current_cpu().id, vcpu.phys_id());
-                    debug!(
-                        "append_vcpu: append VM[{}] vcpu {} on core {}",
-                        vm_id,
-                        vcpu.id(),
-                        current_cpu().id
-                    );
-                    *x = Some(vcpu);
-                    self.len += 1;
-                }
-            },
-            None => error!("vm_id > self.array.len()"),
+        if vcpu.phys_id() != current_cpu().id {
+            error!(
+                "append_vcpu: VM[{}] vcpu {} belongs to core {}, not core {}",
+                vm_id,
+                vcpu.id(),
+                vcpu.phys_id(),
+                current_cpu().id
+            );
+            return false;
         }
+        if self.vcpu_by_id(vm_id, vcpu.id()).is_some() {
+            error!(
+                "append_vcpu: core {} already holds VM[{}] vcpu {}",
+                current_cpu().id,
+                vm_id,
+                vcpu.id()
+            );
+            return false;
+        }
+        debug!(
+            "append_vcpu: append VM[{}] vcpu {} on core {}",
+            vm_id,
+            vcpu.id(),
+            current_cpu().id
+        );
+        self.array.push(vcpu);
+        true
     }
 
     pub fn wakeup_vcpu(&mut self, vcpu: &Vcpu) {
-        if let Some(vcpu) = self
-            .array
-            .iter()
-            .flatten()
-            .find(|&array_vcpu| array_vcpu == vcpu)
-            .cloned()
-        {
+        if let Some(vcpu) = self.array.iter().find(|&array_vcpu| array_vcpu == vcpu).cloned() {
             trace!(
                 "core {} VM {} vcpu {} wakeup",
                 current_cpu().id,
@@ -101,18 +127,57 @@ impl VcpuArray {
             vcpu.set_state(VcpuState::Runnable);
             // determine the timer
             self.active += 1;
-            if !self.timer_on && self.active >= ENABLE_TIMER_ACTIVE_NUM {
-                self.timer_on = true;
-                timer_enable(true);
-            }
+            self.update_timer();
             // do scheduling
-            self.scheduler().put(vcpu);
+            self.scheduler().put(vcpu.clone());
             if current_cpu().active_vcpu.is_none() {
                 self.resched();
+            } else {
+                self.check_preempt(&vcpu);
             }
         }
     }
 
+    // ask for a resched at the trap return if the queued `vcpu` should run before the active one
+    pub fn check_preempt(&mut self, vcpu: &Vcpu) {
+        if let Some(active) = current_cpu().active_vcpu.as_ref() {
+            if active != vcpu && self.scheduler().preempt(active, vcpu) {
+                self.need_resched = true;
+            }
+        }
+    }
+
+    // called before returning to the guest, the context of the trap may belong to another vcpu after it
+    pub fn resched_pending(&mut self) {
+        if self.need_resched {
+            self.resched();
+        }
+    }
+
+    // the scheduler tick, the active vcpu goes on until its slice runs out
+    pub fn tick(&mut self) {
+        if let Some(active) = current_cpu().active_vcpu.clone() {
+            if !self.scheduler().tick(&active) {
+                return;
+            }
+        }
+        self.resched();
+    }
+
+    /* The time to the next scheduler tick, the slice left to the active vcpu.
+     * Timer events are checked on the tick, so it is at most TIMER_SLICE apart.
+     */
+    pub fn next_tick(&mut self) -> TimerValue {
+        let max = TimerValue::from_millis(TIMER_SLICE as u64);
+        match current_cpu().active_vcpu.clone() {
+            Some(active) => self
+                .scheduler()
+                .slice_left(&active)
+                .clamp(TimerValue::from_micros(SCHED_SLICE_MIN_US as u64), max),
+            None => max,
+        }
+    }
+
     fn scheduler(&mut self) -> &mut dyn Scheduler<SchedItem = Vcpu> {
         match self.sched.get_mut() {
             Some(scheduler) => scheduler.as_mut(),
@@ -120,47 +185,88 @@ impl VcpuArray {
         }
     }
 
-    pub fn remove_vcpu(&mut self, vm_id: usize) -> Option<Vcpu> {
-        match self.array.get_mut(vm_id) {
-            Some(x) => x.take().map(|vcpu| {
-                self.len -= 1;
-                if vcpu.state() != VcpuState::Inv {
-                    self.active -= 1;
-                    assert_ne!(self.active, usize::MAX);
-                }
-                vcpu.set_state(VcpuState::Inv);
-                if self.timer_on && self.active < ENABLE_TIMER_ACTIVE_NUM {
-                    self.timer_on = false;
-                    timer_enable(false);
-                }
+    fn take_vcpu(&mut self, vm_id: usize, vcpu_id: usize) -> Option<Vcpu> {
+        let idx = self
+            .array
+            .iter()
+            .position(|vcpu| vcpu.vm_id() == vm_id && vcpu.id() == vcpu_id)?;
+        Some(self.array.remove(idx))
+    }
+
+    pub fn remove_vcpu(&mut self, vm_id: usize, vcpu_id: usize) -> Option<Vcpu> {
+        let vcpu = self.take_vcpu(vm_id, vcpu_id)?;
+        self.wfi_clear(&vcpu);
+        if vcpu.state() != VcpuState::Inv {
+            self.active -= 1;
+            assert_ne!(self.active, usize::MAX);
+        }
+        vcpu.set_state(VcpuState::Inv);
+        self.update_timer();
+        #[cfg(feature = "memory-reservation")]
+        remove_pmu_event(&vcpu);
+        // remove vcpu from scheduler
+        self.scheduler().remove(&vcpu);
+        if current_cpu().active_vcpu.as_ref() == Some(&vcpu) {
+            current_cpu().set_active_vcpu(None);
+            self.resched();
+        }
+        Some(vcpu)
+    }
+
+    // take a vcpu off this core for a migration, its context is saved and its state kept
+    pub fn detach_vcpu(&mut self, vm_id: usize, vcpu_id: usize) -> Option<Vcpu> {
+        let vcpu = self.take_vcpu(vm_id, vcpu_id)?;
+        if current_cpu().active_vcpu.as_ref() == Some(&vcpu) {
+            vcpu.context_vm_store();
+            current_cpu().set_active_vcpu(None);
+            vcpu.set_state(VcpuState::Runnable);
+        }
+        // a vcpu waiting in wfi runs on the new core, waking up from wfi early is allowed
+        if self.wfi_clear(&vcpu) {
+            vcpu.set_state(VcpuState::Runnable);
+        }
+        if vcpu.state() != VcpuState::Inv {
+            self.active -= 1;
+            #[cfg(feature = "memory-reservation")]
+            remove_pmu_event(&vcpu);
+        }
+        self.update_timer();
+        self.scheduler().remove(&vcpu);
+        if current_cpu().active_vcpu.is_none() {
+            self.resched();
+        }
+        Some(vcpu)
+    }
+
+    // put a vcpu detached from another core on this one, a runnable vcpu goes on running here
+    pub fn attach_vcpu(&mut self, vcpu: Vcpu) -> bool {
+        let state = vcpu.state();
+        if state != VcpuState::Inv {
+            // wakeup_vcpu counts it as active again
+            vcpu.set_state(VcpuState::Inv);
+        }
+        if !self.append_vcpu(vcpu.clone()) {
+            vcpu.set_state(state);
+            return false;
+        }
+        match state {
+            VcpuState::Runnable | VcpuState::Running => self.wakeup_vcpu(&vcpu),
+            VcpuState::Blocked => {
                 #[cfg(feature = "memory-reservation")]
-                {
-                    if let Some(vcpu_event) = vcpu.pmu_event() {
-                        use super::timer::remove_timer_event;
-                        use crate::arch::PmuTimerEvent;
-                        remove_timer_event(|event| {
-                            use alloc::sync::Arc;
-                            if let Some(event) = event.as_any().downcast_ref::<PmuTimerEvent>() {
-                                core::ptr::addr_of!(*event) == Arc::as_ptr(&vcpu_event)
-                            } else {
-                                false
-                            }
-                        });
-                    }
-                }
-                // remove vcpu from scheduler
-                self.scheduler().remove(&vcpu);
-                if current_cpu().active_vcpu.as_ref() == Some(&vcpu) {
-                    current_cpu().set_active_vcpu(None);
-                    self.resched();
+                if let Some(event) = vcpu.pmu_event() {
+                    super::timer::start_timer_event(vcpu.bw_info().period(), event);
                 }
-                vcpu
-            }),
-            None => None,
+                self.active += 1;
+                vcpu.set_state(VcpuState::Blocked);
+                self.update_timer();
+            }
+            VcpuState::Inv => {}
         }
+        true
     }
 
     pub fn resched(&mut self) {
+        self.need_resched = false;
         if let Some(next_vcpu) = self.scheduler().next() {
             self.switch_to(next_vcpu);
         } else if current_cpu().active_vcpu.is_none() {
@@ -191,8 +297,84 @@ impl VcpuArray {
         //      and will judge if current active vcpu
         next_vcpu.set_state(VcpuState::Running);
         current_cpu().set_active_vcpu(Some(next_vcpu.clone()));
+        event_trace(TraceEvent::VcpuSwitch, next_vcpu.vm_id(), next_vcpu.id());
         next_vcpu.context_vm_restore();
         crate::arch::Arch::install_vm_page_table(next_vcpu.vm_pt_dir(), next_vcpu.vm_id());
+        // the vcpu switched in off the tick runs for its own slice
+        if self.timer_on {
+            timer_notify_after(self.next_tick());
+        }
+    }
+
+    // power off a vcpu on this core (e.g. PSCI CPU_OFF), it goes back to `Inv`
+    // and can be woken up again by a later CPU_ON
+    pub fn power_off_vcpu(&mut self, vcpu: &Vcpu) -> bool {
+        if !self.array.iter().any(|array_vcpu| array_vcpu == vcpu) || vcpu.state() == VcpuState::Inv {
+            return false;
+        }
+        trace!(
+            "core {} VM {} vcpu {} power off",
+            current_cpu().id,
+            vcpu.vm_id(),
+            vcpu.id()
+        );
+        if current_cpu().active_vcpu.as_ref() == Some(vcpu) {
+            vcpu.context_vm_store();
+            current_cpu().set_active_vcpu(None);
+        }
+        // a blocked vcpu has been removed from scheduler already
+        self.scheduler().remove(vcpu);
+        self.wfi_clear(vcpu);
+        vcpu.set_state(VcpuState::Inv);
+        #[cfg(feature = "memory-reservation")]
+        remove_pmu_event(vcpu);
+        self.active -= 1;
+        self.update_timer();
+        if current_cpu().active_vcpu.is_none() {
+            self.resched();
+        }
+        true
+    }
+
+    // take a vcpu off this core with its context saved, until `unpause_vcpu`
+    pub fn pause_vcpu(&mut self, vm_id: usize, vcpu_id: usize) -> bool {
+        let vcpu = match self.vcpu_by_id(vm_id, vcpu_id) {
+            Some(vcpu) => vcpu.clone(),
+            None => return false,
+        };
+        // a vcpu waiting in wfi is off the scheduler already, it stays blocked as a paused one
+        if self.wfi_clear(&vcpu) {
+            trace!("core {} VM {} vcpu {} pause in wfi", current_cpu().id, vm_id, vcpu_id);
+            return true;
+        }
+        if !matches!(vcpu.state(), VcpuState::Runnable | VcpuState::Running) {
+            return false;
+        }
+        trace!("core {} VM {} vcpu {} pause", current_cpu().id, vm_id, vcpu_id);
+        if current_cpu().active_vcpu.as_ref() == Some(&vcpu) {
+            vcpu.context_vm_store();
+            current_cpu().set_active_vcpu(None);
+        }
+        self.scheduler().remove(&vcpu);
+        vcpu.set_state(VcpuState::Blocked);
+        if current_cpu().active_vcpu.is_none() {
+            self.resched();
+        }
+        true
+    }
+
+    pub fn unpause_vcpu(&mut self, vm_id: usize, vcpu_id: usize) -> bool {
+        let vcpu = match self.vcpu_by_id(vm_id, vcpu_id) {
+            Some(vcpu) if vcpu.state() == VcpuState::Blocked && !vcpu.wfi_blocked() => vcpu.clone(),
+            _ => return false,
+        };
+        trace!("core {} VM {} vcpu {} unpause", current_cpu().id, vm_id, vcpu_id);
+        vcpu.set_state(VcpuState::Runnable);
+        self.scheduler().put(vcpu);
+        if current_cpu().active_vcpu.is_none() {
+            self.resched();
+        }
+        true
     }
 
     #[allow(dead_code)]
@@ -206,12 +388,141 @@ impl VcpuArray {
         }
     }
 
-    pub fn iter(&self) -> Iter<'_, Option<Vcpu>> {
+    /* Block the active vcpu on a trapped wfi, its exception pc must be past the wfi already.
+     * It is woken up by the next interrupt injected for it, or when its virtual timer fires.
+     */
+    #[cfg(feature = "trap-wfi")]
+    pub fn wfi_block_current(&mut self) {
+        use crate::arch::timer::{timer_arch_get_counter, timer_arch_get_frequency, timer_arch_vtimer_deadline};
+
+        let vcpu = match current_cpu().active_vcpu.clone() {
+            Some(vcpu) => vcpu,
+            None => return,
+        };
+        // the guest waits for its own timer, let it run again at the deadline
+        let timeout = match timer_arch_vtimer_deadline() {
+            Some(deadline) => {
+                let now = timer_arch_get_counter() as u64;
+                if deadline <= now {
+                    // the timer interrupt is due, the guest takes it on return
+                    return;
+                }
+                Some(ticks_to_duration(deadline - now, timer_arch_get_frequency() as u64))
+            }
+            None => None,
+        };
+        vcpu.context_vm_store();
+        trace!(
+            "core {} VM {} vcpu {} wfi block",
+            current_cpu().id,
+            vcpu.vm_id(),
+            vcpu.id()
+        );
+        current_cpu().set_active_vcpu(None);
+        self.scheduler().remove(&vcpu);
+        vcpu.set_state(VcpuState::Blocked);
+        vcpu.set_wfi_blocked(true);
+        self.wfi_num += 1;
+        if let Some(timeout) = timeout {
+            start_timer_event(timeout, Arc::new(WfiTimerEvent(vcpu.downgrade())));
+        }
+        self.update_timer();
+        self.resched();
+    }
+
+    // put a vcpu blocked by wfi back to the scheduler, other blocked vcpus are left alone
+    pub fn wfi_wakeup(&mut self, vcpu: &Vcpu) {
+        if !self.array.contains(vcpu) || !self.wfi_clear(vcpu) {
+            return;
+        }
+        trace!(
+            "core {} VM {} vcpu {} wfi wakeup",
+            current_cpu().id,
+            vcpu.vm_id(),
+            vcpu.id()
+        );
+        current_cpu().cpu_state = CpuState::Run;
+        vcpu.set_state(VcpuState::Runnable);
+        self.scheduler().put(vcpu.clone());
+        if current_cpu().active_vcpu.is_none() {
+            self.resched();
+        } else {
+            self.check_preempt(vcpu);
+        }
+    }
+
+    // return true if the vcpu was blocked by wfi, it is not any more
+    fn wfi_clear(&mut self, vcpu: &Vcpu) -> bool {
+        if !vcpu.wfi_blocked() {
+            return false;
+        }
+        vcpu.set_wfi_blocked(false);
+        self.wfi_num -= 1;
+        #[cfg(feature = "trap-wfi")]
+        remove_wfi_event(vcpu);
+        self.update_timer();
+        true
+    }
+
+    // whether the scheduler tick is running on this core
+    pub fn timer_on(&self) -> bool {
+        self.timer_on
+    }
+
+    // the scheduler tick runs while several vcpus share this core or a vcpu waits in wfi,
+    // and all the time with the watchdog, which needs the tick of every core for its heartbeat
+    pub(super) fn update_timer(&mut self) {
+        let need = cfg!(feature = "watchdog") || self.active >= ENABLE_TIMER_ACTIVE_NUM || self.wfi_num > 0;
+        if self.timer_on != need {
+            self.timer_on = need;
+            timer_enable(need);
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, Vcpu> {
         self.array.iter()
     }
 
     #[allow(dead_code)]
-    pub fn iter_mut(&mut self) -> IterMut<'_, Option<Vcpu>> {
+    pub fn iter_mut(&mut self) -> IterMut<'_, Vcpu> {
         self.array.iter_mut()
     }
 }
+
+#[cfg(feature = "trap-wfi")]
+struct WfiTimerEvent(WeakVcpu);
+
+#[cfg(feature = "trap-wfi")]
+impl TimerEvent for WfiTimerEvent {
+    fn callback(self: Arc<Self>, _now: TimerValue) {
+        if let Some(vcpu) = self.0.upgrade() {
+            if vcpu.phys_id() == current_cpu().id {
+                current_cpu().vcpu_array.wfi_wakeup(&vcpu);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "trap-wfi")]
+fn remove_wfi_event(vcpu: &Vcpu) {
+    remove_timer_event(|event| match event.as_any().downcast_ref::<WfiTimerEvent>() {
+        Some(event) => event.0.upgrade().as_ref() == Some(vcpu),
+        None => false,
+    });
+}
+
+#[cfg(feature = "memory-reservation")]
+fn remove_pmu_event(vcpu: &Vcpu) {
+    if let Some(vcpu_event) = vcpu.pmu_event() {
+        use super::timer::remove_timer_event;
+        use crate::arch::PmuTimerEvent;
+        remove_timer_event(|event| {
+            use alloc::sync::Arc;
+            if let Some(event) = event.as_any().downcast_ref::<PmuTimerEvent>() {
+                core::ptr::addr_of!(*event) == Arc::as_ptr(&vcpu_event)
+            } else {
+                false
+            }
+        });
+    }
+}
@@ -1,29 +1,67 @@
 use alloc::boxed::Box;
 use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use spin::{Mutex, Once};
 
 use crate::arch::PageTable;
 use crate::arch::Vgic;
-use crate::arch::{emu_intc_init, HYP_VA_SIZE, VM_IPA_SIZE};
-use crate::config::VmConfigEntry;
-use crate::device::{emu_virtio_mmio_init, EmuDev};
-use crate::kernel::{mem_color_region_free, shyper_init};
+use crate::arch::{emu_intc_init, HYP_VA_SIZE, INTERRUPT_NUM_MAX, PAGE_SIZE, VM_IPA_SIZE};
+use crate::arch::{LVL2_SHIFT, PTE_S2_FIELD_AP_RO, PTE_S2_FIELD_AP_RW, PTE_S2_NORMAL};
+use crate::config::{PassthroughRegion, VmConfigEntry, VmRegion};
+use crate::device::{
+    emu_pl011_init, emu_virtio_mmio_init, virtio_blk_stat_dump, EmuContext, EmuDev, EmuDevStat, EmuDeviceType,
+    VirtioMmio,
+};
+use crate::kernel::{iommu_tlb_invalidate, mem_color_llc_num_sets, mem_color_region_free, shyper_init};
+use crate::mm::{HeapTag, HeapTagGuard, PageFrame, PageUsage};
+use crate::util::logger::LogRing;
 use crate::util::*;
 
+#[cfg(feature = "memory-reservation")]
+use super::bwres::membwres::{MemBwHistory, MemBwRecord};
 use super::vcpu::Vcpu;
-use super::{mem_page_alloc, ColorMemRegion};
+use super::{mem_page_alloc, ColorLayout, ColorMemRegion, DirtyLog, HvcMsgQueue, VmMutex};
 
 // make sure that the CONFIG_VM_NUM_MAX is not greater than (1 << (HYP_VA_SIZE - VM_IPA_SIZE)) - 1
 pub const CONFIG_VM_NUM_MAX: usize = min!(shyper::VM_NUM_MAX, (1 << (HYP_VA_SIZE - VM_IPA_SIZE)) - 1);
 static VM_IF_LIST: [Mutex<VmInterface>; CONFIG_VM_NUM_MAX] =
     [const { Mutex::new(VmInterface::default()) }; CONFIG_VM_NUM_MAX];
 
+// log rings of each VM, lines logged outside of any VM go to GLOBAL_LOG
+static VM_LOG_LIST: [Mutex<LogRing>; CONFIG_VM_NUM_MAX] = [const { Mutex::new(LogRing::new()) }; CONFIG_VM_NUM_MAX];
+static GLOBAL_LOG: Mutex<LogRing> = Mutex::new(LogRing::new());
+
 pub fn vm_if_reset(vm_id: usize) {
     if let Some(vm_if) = VM_IF_LIST.get(vm_id) {
         vm_if.lock().reset();
     }
+    if let Some(log) = VM_LOG_LIST.get(vm_id) {
+        log.lock().reset();
+    }
+}
+
+// access the log ring of a VM, or the global one if `vm_id` is None
+pub fn vm_log_access<F, R>(vm_id: Option<usize>, f: F) -> Option<R>
+where
+    F: FnOnce(&mut LogRing) -> R,
+{
+    let log = match vm_id {
+        Some(vm_id) => VM_LOG_LIST.get(vm_id)?,
+        None => &GLOBAL_LOG,
+    };
+    Some(f(&mut log.lock()))
+}
+
+// append a line to a log ring, return whether it should be printed on the console too
+pub fn vm_log_push(vm_id: Option<usize>, line: &[u8]) -> bool {
+    vm_log_access(vm_id, |log| {
+        log.push(line);
+        log.console()
+    })
+    .unwrap_or(true)
 }
 
 pub fn vm_if_set_state(vm_id: usize, vm_state: VmState) {
@@ -32,6 +70,14 @@ pub fn vm_if_set_state(vm_id: usize, vm_state: VmState) {
     }
 }
 
+// set the state and return the one it replaces
+pub fn vm_if_swap_state(vm_id: usize, vm_state: VmState) -> VmState {
+    match VM_IF_LIST.get(vm_id) {
+        Some(vm_if) => core::mem::replace(&mut vm_if.lock().state, vm_state),
+        None => VmState::default(),
+    }
+}
+
 pub fn vm_if_get_state(vm_id: usize) -> VmState {
     if let Some(vm_if) = VM_IF_LIST.get(vm_id) {
         vm_if.lock().state
@@ -40,6 +86,89 @@ pub fn vm_if_get_state(vm_id: usize) -> VmState {
     }
 }
 
+pub fn vm_if_boot_state(vm_id: usize) -> VmBootState {
+    match VM_IF_LIST.get(vm_id) {
+        Some(vm_if) => vm_if.lock().boot_state,
+        None => VmBootState::default(),
+    }
+}
+
+/* Move the boot state of a VM to `to`, if the transition is allowed from its current state.
+ * It is checked and set under the interface lock, so of two racing requests only one gets through.
+ * Returns the current state on failure.
+ */
+pub fn vm_if_boot_transit(vm_id: usize, to: VmBootState) -> Result<(), VmBootState> {
+    let mut vm_if = match VM_IF_LIST.get(vm_id) {
+        Some(vm_if) => vm_if.lock(),
+        None => return Err(VmBootState::default()),
+    };
+    if vm_if.boot_state.can_transit(to) {
+        vm_if.boot_state = to;
+        Ok(())
+    } else {
+        Err(vm_if.boot_state)
+    }
+}
+
+// a new kernel image is uploaded once the chunks of the previous one are copied or failed
+pub fn vm_if_upload_start(vm_id: usize, size: usize) -> bool {
+    match VM_IF_LIST.get(vm_id) {
+        Some(vm_if) => {
+            let mut vm_if = vm_if.lock();
+            if vm_if.upload.busy() {
+                return false;
+            }
+            vm_if.upload = VmImageUpload {
+                size,
+                queued: 0,
+                copied: 0,
+                failed: 0,
+                loaded: false,
+            };
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn vm_if_upload_queue(vm_id: usize, len: usize) {
+    if let Some(vm_if) = VM_IF_LIST.get(vm_id) {
+        vm_if.lock().upload.queued += len;
+    }
+}
+
+pub fn vm_if_upload_done(vm_id: usize, len: usize) {
+    if let Some(vm_if) = VM_IF_LIST.get(vm_id) {
+        vm_if.lock().upload.copied += len;
+    }
+}
+
+pub fn vm_if_upload_fail(vm_id: usize, len: usize) {
+    if let Some(vm_if) = VM_IF_LIST.get(vm_id) {
+        vm_if.lock().upload.failed += len;
+    }
+}
+
+pub fn vm_if_upload_loaded(vm_id: usize) {
+    if let Some(vm_if) = VM_IF_LIST.get(vm_id) {
+        vm_if.lock().upload.loaded = true;
+    }
+}
+
+pub fn vm_if_upload(vm_id: usize) -> VmImageUpload {
+    match VM_IF_LIST.get(vm_id) {
+        Some(vm_if) => vm_if.lock().upload,
+        None => VmImageUpload::default(),
+    }
+}
+
+// the master core moves with vcpu 0
+pub fn vm_if_update_cpu_id(vm_id: usize, master_cpu_id: usize) {
+    if let Some(vm_if) = VM_IF_LIST.get(vm_id) {
+        vm_if.lock().master_cpu_id = Once::initialized(master_cpu_id);
+    }
+}
+
 fn vm_if_set_cpu_id(vm_id: usize, master_cpu_id: usize) {
     if let Some(vm_if) = VM_IF_LIST.get(vm_id) {
         vm_if.lock().master_cpu_id.call_once(|| master_cpu_id);
@@ -60,31 +189,87 @@ pub fn vm_if_get_cpu_id(vm_id: usize) -> Option<usize> {
 
 pub fn vm_if_set_ivc_arg(vm_id: usize, ivc_arg: usize) {
     if let Some(vm_if) = VM_IF_LIST.get(vm_id) {
-        vm_if.lock().ivc_arg = ivc_arg;
+        let mut vm_if = vm_if.lock();
+        vm_if.ivc_arg = ivc_arg;
+        // a new mailbox starts without pending messages
+        vm_if.ivc_msg = HvcMsgQueue::new();
     }
 }
 
-pub fn vm_if_ivc_arg(vm_id: usize) -> usize {
+pub fn vm_if_set_ivc_arg_ptr(vm_id: usize, ivc_arg_ptr: usize) {
     if let Some(vm_if) = VM_IF_LIST.get(vm_id) {
-        vm_if.lock().ivc_arg
-    } else {
-        0
+        vm_if.lock().ivc_arg_ptr = ivc_arg_ptr;
     }
 }
 
-pub fn vm_if_set_ivc_arg_ptr(vm_id: usize, ivc_arg_ptr: usize) {
-    if let Some(vm_if) = VM_IF_LIST.get(vm_id) {
-        vm_if.lock().ivc_arg_ptr = ivc_arg_ptr;
+// access the ivc mailbox of a VM with its interface locked
+pub fn vm_if_ivc_access<F, R>(vm_id: usize, f: F) -> Option<R>
+where
+    F: FnOnce(&mut VmInterface) -> R,
+{
+    VM_IF_LIST.get(vm_id).map(|vm_if| f(&mut vm_if.lock()))
+}
+
+// the kernel image upload of a VM, in bytes, the chunks are copied by the executor in order
+#[derive(Clone, Copy, Default)]
+pub struct VmImageUpload {
+    // of the whole image, as told by MVM
+    pub size: usize,
+    pub queued: usize,
+    pub copied: usize,
+    // of the chunks the executor could not copy, MVM uploads the image again from offset 0
+    pub failed: usize,
+    // the segments of an elf image are moved to their ipa, see `vmm_load_uploaded_image`
+    pub loaded: bool,
+}
+
+impl VmImageUpload {
+    // the VM is not booted before its image is there
+    pub fn pending(&self) -> bool {
+        self.copied < self.queued.max(self.size)
+    }
+
+    // chunks are queued and not copied or failed yet
+    pub fn busy(&self) -> bool {
+        self.copied + self.failed < self.queued
+    }
+
+    pub fn percent(&self) -> usize {
+        match self.queued.max(self.size) {
+            0 => 100,
+            total => self.copied.min(total) * 100 / total,
+        }
     }
 }
 
-pub fn vm_if_ivc_arg_ptr(vm_id: usize) -> usize {
-    if let Some(vm_if) = VM_IF_LIST.get(vm_id) {
-        vm_if.lock().ivc_arg_ptr
-    } else {
-        0
+#[derive(Clone, Copy)]
+pub struct VmStateSnapshot {
+    pub state: VmState,
+    pub cpu_num: usize,
+    pub master_cpu_id: Option<usize>,
+}
+
+impl VmStateSnapshot {
+    /* state ~ (7, 0), cpu_num ~ (15, 8), master valid ~ (16, 16), master cpu id ~ (47, 32) */
+    pub fn pack(&self) -> usize {
+        let master = match self.master_cpu_id {
+            Some(cpu_id) => (1 << 16) | (cpu_id << 32),
+            None => 0,
+        };
+        (self.state as usize & 0xff) | ((self.cpu_num & 0xff) << 8) | master
     }
 }
+
+// the state of an existing VM, it takes the VM list lock so it can not be called in `vm_list_walker`
+pub fn vm_if_state_snapshot(vm_id: usize) -> Option<VmStateSnapshot> {
+    let vm = vm_by_id(vm_id)?;
+    let vm_if = VM_IF_LIST.get(vm_id)?.lock();
+    Some(VmStateSnapshot {
+        state: vm_if.state,
+        cpu_num: vm.cpu_num(),
+        master_cpu_id: vm_if.master_cpu_id.get().cloned(),
+    })
+}
 // End vm interface func implementation
 
 #[allow(dead_code)]
@@ -94,6 +279,43 @@ pub enum VmState {
     Inv = 0,
     Pending = 1,
     Active = 2,
+    // stopped by a fault of a vcpu, until VM0 removes or reboots it
+    Crashed = 3,
+}
+
+/* Where a VM is in its life, the VMM requests on a VM are only taken in the state they expect:
+ * Pending -> Configuring -> Configured -> Booted <-> Rebooting, Booted/Rebooting -> Stopped once it is shut down
+ * or crashed, and Configured/Booted/Stopped -> Removing -> Pending.
+ */
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum VmBootState {
+    #[default]
+    Pending,
+    Configuring,
+    Configured,
+    Booted,
+    Rebooting,
+    // shut down or crashed, its vcpus are off their cores and it can only be removed
+    Stopped,
+    Removing,
+}
+
+impl VmBootState {
+    pub fn can_transit(self, to: VmBootState) -> bool {
+        use VmBootState::*;
+        matches!(
+            (self, to),
+            (Pending, Configuring)
+                // back to Pending if the VM fails to be created
+                | (Configuring, Configured | Pending)
+                | (Configured, Booted | Removing)
+                // back to Configured if the boot request fails to reach the master core
+                | (Booted, Configured | Rebooting | Stopped | Removing)
+                | (Rebooting, Booted | Stopped)
+                | (Stopped, Removing)
+                | (Removing, Pending)
+        )
+    }
 }
 
 #[derive(Default, Clone, Copy, PartialEq, Eq)]
@@ -116,8 +338,11 @@ impl From<usize> for VmType {
 pub struct VmInterface {
     master_cpu_id: Once<usize>,
     state: VmState,
-    ivc_arg: usize,
-    ivc_arg_ptr: usize,
+    boot_state: VmBootState,
+    upload: VmImageUpload,
+    pub(super) ivc_arg: usize,
+    pub(super) ivc_arg_ptr: usize,
+    pub(super) ivc_msg: HvcMsgQueue,
 }
 
 impl VmInterface {
@@ -125,16 +350,28 @@ impl VmInterface {
         VmInterface {
             master_cpu_id: Once::new(),
             state: VmState::Pending,
+            boot_state: VmBootState::Pending,
+            upload: VmImageUpload {
+                size: 0,
+                queued: 0,
+                copied: 0,
+                failed: 0,
+                loaded: false,
+            },
             ivc_arg: 0,
             ivc_arg_ptr: 0,
+            ivc_msg: HvcMsgQueue::new(),
         }
     }
 
+    // the boot state is left to `vmm_remove_vm`, it is Removing until the VM is gone
     fn reset(&mut self) {
         self.master_cpu_id = Once::new();
         self.state = VmState::Pending;
+        self.upload = VmImageUpload::default();
         self.ivc_arg = 0;
         self.ivc_arg_ptr = 0;
+        self.ivc_msg = HvcMsgQueue::new();
     }
 }
 
@@ -148,7 +385,7 @@ pub enum IntCtrlType {
 
 pub struct Vm {
     inner_const: VmInnerConst,
-    inner_mut: Mutex<VmInnerMut>,
+    inner_mut: VmMutex<VmInnerMut>,
 }
 
 struct VmInnerConst {
@@ -157,13 +394,23 @@ struct VmInnerConst {
     vcpu_list: Box<[Vcpu]>,
     intc_type: IntCtrlType,
     // TODO: create struct ArchVcpu and move intc_dev into it
+    // set once at creation, the injection paths reach the vgic without taking `inner_mut`
     arch_intc_dev: Option<Arc<Vgic>>,
-    int_bitmap: BitAlloc4K,
-    emu_devs: Vec<Arc<dyn EmuDev>>,
+    // the passthrough SPIs may move to another VM at runtime, atomic for the interrupt paths
+    int_bitmap: [AtomicUsize; INTERRUPT_NUM_MAX / usize::BITS as usize],
+    // each device with its trap counters, so that they stay together when the list changes
+    emu_devs: Vec<(Arc<dyn EmuDev>, Arc<EmuDevStat>)>,
+    // irqs of the devices hot-plugged at runtime, atomic for the interrupt paths
+    hotplug_ints: [AtomicUsize; INTERRUPT_NUM_MAX / usize::BITS as usize],
+    // the LLC sets seen by the guest if its colors restrict the LLC
+    llc_num_sets: Option<usize>,
+    // the budget consumed in the last periods, reported by the replenishment timers of the vcpus
+    #[cfg(feature = "memory-reservation")]
+    mem_bw: Mutex<MemBwHistory>,
 }
 
 fn cal_phys_id_list(config: &VmConfigEntry) -> Vec<usize> {
-    // generate the vcpu physical id list
+    // generate the list of the allowed cores, master first
     let mut phys_id_list = vec![];
     let mut cfg_cpu_allocate_bitmap = config.cpu_allocated_bitmap();
     if let Some(master) = config.cpu_master() {
@@ -188,7 +435,13 @@ fn cal_phys_id_list(config: &VmConfigEntry) -> Vec<usize> {
             cfg_cpu_allocate_bitmap >>= 1;
         }
     }
-    phys_id_list
+    if phys_id_list.is_empty() {
+        return phys_id_list;
+    }
+    // the vcpus take the cores in turn, a core holds up to vcpus_per_core of them
+    (0..config.cpu_num())
+        .map(|vcpu_id| phys_id_list[vcpu_id % phys_id_list.len()])
+        .collect()
 }
 
 impl VmInnerConst {
@@ -205,14 +458,21 @@ impl VmInnerConst {
         for (vcpu_id, phys_id) in phys_id_list.into_iter().enumerate() {
             vcpu_list.push(Vcpu::new(vm.clone(), vcpu_id, phys_id, &config));
         }
+        #[cfg(feature = "memory-reservation")]
+        let mem_bw = MemBwHistory::new(config.cpu_num(), config.memory.budget, super::timer::now());
+        let llc_num_sets = mem_color_llc_num_sets(config.memory_color_bitmap());
         let mut this = Self {
             id,
             config,
             vcpu_list: vcpu_list.into_boxed_slice(),
             arch_intc_dev: None,
-            int_bitmap: BitAlloc4K::default(),
+            int_bitmap: [const { AtomicUsize::new(0) }; INTERRUPT_NUM_MAX / usize::BITS as usize],
             emu_devs: vec![],
+            hotplug_ints: [const { AtomicUsize::new(0) }; INTERRUPT_NUM_MAX / usize::BITS as usize],
             intc_type: IntCtrlType::Emulated,
+            llc_num_sets,
+            #[cfg(feature = "memory-reservation")]
+            mem_bw: Mutex::new(mem_bw),
         };
         this.init_devices(vm);
         this
@@ -235,9 +495,12 @@ impl VmInnerConst {
                     self.intc_type = IntCtrlType::Passthrough;
                     crate::arch::partial_passthrough_intc_init(emu_cfg)
                 }
-                EmuDeviceTVirtioBlk | EmuDeviceTVirtioConsole | EmuDeviceTVirtioNet | VirtioBalloon => {
-                    emu_virtio_mmio_init(vm.clone(), emu_cfg)
-                }
+                EmuDeviceTVirtioBlk
+                | EmuDeviceTVirtioConsole
+                | EmuDeviceTVirtioNet
+                | EmuDeviceTVirtioRng
+                | EmuDeviceTVirtioBalloon => emu_virtio_mmio_init(vm.clone(), emu_cfg),
+                EmuDeviceTPl011 => emu_pl011_init(vm.clone(), emu_cfg),
                 #[cfg(feature = "iommu")]
                 EmuDeviceTIOMMU => crate::kernel::emu_iommu_init(emu_cfg), // Do IOMMU init later, after add VM to global list
                 EmuDeviceTShyper => {
@@ -246,6 +509,8 @@ impl VmInnerConst {
                     }
                     Err(())
                 }
+                // mapped read-only in `vmm_init_info_page` and `pvclock_init`, never trap
+                EmuDeviceTInfoPage | EmuDeviceTPvClock => Err(()),
                 _ => {
                     warn!(
                         "vmm_init_emulated_device: unknown emulated device {:?}",
@@ -255,20 +520,22 @@ impl VmInnerConst {
                 }
             };
             if let Ok(emu_dev) = dev {
-                if self.emu_devs.iter().any(|dev| {
+                if self.emu_devs.iter().any(|(dev, _)| {
                     emu_dev.address_range().contains(&dev.address_range().start)
                         || dev.address_range().contains(&emu_dev.address_range().start)
                 }) {
-                    panic!(
-                        "duplicated emul address region: prev address {:x?}",
+                    error!(
+                        "VM[{}] emulated device {:#x?} overlaps with another one",
+                        self.id,
                         emu_dev.address_range(),
                     );
+                    return false;
                 } else {
-                    self.emu_devs.push(emu_dev);
+                    self.emu_devs.push((emu_dev, Arc::new(EmuDevStat::default())));
                 }
             }
             if emu_cfg.irq_id != 0 {
-                self.int_bitmap.set(emu_cfg.irq_id);
+                self.int_bitmap_set(emu_cfg.irq_id);
             }
             info!(
                 "VM {} registers emulated device: id=<{}>, name=\"{:?}\", ipa=<{:#x}>",
@@ -277,49 +544,57 @@ impl VmInnerConst {
         }
         // pass through irqs
         for irq in self.config.passthrough_device_irqs() {
-            self.int_bitmap.set(*irq);
+            self.int_bitmap_set(*irq);
         }
         true
     }
+
+    fn int_bitmap_set(&mut self, int_id: usize) {
+        if let Some(ints) = self.int_bitmap.get_mut(int_id / usize::BITS as usize) {
+            *ints.get_mut() |= 1 << (int_id % usize::BITS as usize);
+        }
+    }
 }
 
 impl Vm {
     pub fn new(id: usize, config: VmConfigEntry) -> Arc<Self> {
         let this = Arc::new_cyclic(|weak| Vm {
             inner_const: VmInnerConst::new(id, config, weak.clone()),
-            inner_mut: Mutex::new(VmInnerMut::new()),
+            inner_mut: VmMutex::new(VmInnerMut::new(id)),
         });
         for vcpu in this.vcpu_list() {
-            vcpu.init(this.config());
+            vcpu.init(&this);
         }
         this.init_intc_mode(this.inner_const.intc_type);
         this
     }
 
     #[cfg(feature = "iommu")]
-    pub fn set_iommu_ctx_id(&self, id: usize) {
+    pub fn set_iommu_ctx_id(&self, id: Option<usize>) {
         let mut vm_inner = self.inner_mut.lock();
-        vm_inner.iommu_ctx_id = Some(id);
+        vm_inner.iommu_ctx_id = id;
     }
 
     #[cfg(feature = "iommu")]
-    pub fn iommu_ctx_id(&self) -> usize {
+    pub fn owns_iommu_ctx(&self, id: usize) -> bool {
+        self.inner_mut.lock().iommu_ctx_id == Some(id)
+    }
+
+    #[cfg(feature = "iommu")]
+    pub fn iommu_ctx_id(&self) -> Option<usize> {
         let vm_inner = self.inner_mut.lock();
-        match vm_inner.iommu_ctx_id {
-            None => {
-                panic!("vm {} do not have iommu context bank", self.id());
-            }
-            Some(id) => id,
-        }
+        vm_inner.iommu_ctx_id
     }
 
-    pub fn med_blk_id(&self) -> usize {
-        match self.config().mediated_block_index() {
-            None => {
-                panic!("vm {} do not have mediated blk", self.id());
-            }
-            Some(idx) => idx,
-        }
+    // the mediated blk behind the mediated virtio blk at `base_ipa`, the n-th of them takes the n-th index
+    pub fn med_blk_id(&self, base_ipa: usize) -> Option<usize> {
+        let config = self.config();
+        let nth = config
+            .emulated_device_list()
+            .iter()
+            .filter(|emu_cfg| emu_cfg.emu_type == EmuDeviceType::EmuDeviceTVirtioBlk && emu_cfg.mediated)
+            .position(|emu_cfg| emu_cfg.base_ipa == base_ipa)?;
+        config.mediated_block_index().get(nth).copied()
     }
 
     #[inline]
@@ -332,23 +607,257 @@ impl Vm {
         &self.inner_const.vcpu_list
     }
 
+    /* The emulated devices of a rebooting VM forget the old guest, the hot-plugged ones included.
+     * The devices stay, so do their trap counters, they are only reset by a dump asking for it.
+     */
+    pub fn reset_emu_devs(&self) {
+        let hotplug_devs = self.inner_mut.lock().hotplug_devs.clone();
+        for (dev, _) in self.inner_const.emu_devs.iter().chain(hotplug_devs.iter()) {
+            dev.reset();
+        }
+    }
+
     pub fn find_emu_dev(&self, ipa: usize) -> Option<Arc<dyn EmuDev>> {
-        self.inner_const
+        match self
+            .inner_const
             .emu_devs
             .iter()
-            .find(|&dev| dev.address_range().contains(&ipa))
-            .cloned()
+            .find(|(dev, _)| dev.address_range().contains(&ipa))
+        {
+            Some((dev, _)) => Some(dev.clone()),
+            None => self
+                .inner_mut
+                .lock()
+                .hotplug_devs
+                .iter()
+                .find(|(dev, _)| dev.address_range().contains(&ipa))
+                .map(|(dev, _)| dev.clone()),
+        }
+    }
+
+    /* Find the emulated device of a trapped access and count the trap.
+     * The devices of the config are never locked, only the hot-plugged ones are.
+     */
+    pub fn find_emu_dev_and_count(&self, emu_ctx: &EmuContext) -> Option<(Arc<dyn EmuDev>, Arc<EmuDevStat>)> {
+        let (dev, stat) = match self
+            .inner_const
+            .emu_devs
+            .iter()
+            .find(|(dev, _)| dev.address_range().contains(&emu_ctx.address))
+        {
+            Some(entry) => entry.clone(),
+            None => self
+                .inner_mut
+                .lock()
+                .hotplug_devs
+                .iter()
+                .find(|(dev, _)| dev.address_range().contains(&emu_ctx.address))
+                .cloned()?,
+        };
+        stat.record(emu_ctx);
+        Some((dev, stat))
+    }
+
+    // whether `range` overlaps an emulated device, including the hot-plugged ones
+    pub fn emu_dev_overlap(&self, range: &Range<usize>) -> bool {
+        let overlap = |dev: &Arc<dyn EmuDev>| {
+            let other = dev.address_range();
+            range.start < other.end && other.start < range.end
+        };
+        self.inner_const.emu_devs.iter().any(|(dev, _)| overlap(dev))
+            || self.inner_mut.lock().hotplug_devs.iter().any(|(dev, _)| overlap(dev))
+    }
+
+    // add an emulated device to the running VM, the accesses to its region trap from now on
+    pub fn hotplug_emu_dev(&self, emu_dev: Arc<dyn EmuDev>, irq_id: usize) -> bool {
+        let range = emu_dev.address_range();
+        let overlap = |dev: &Arc<dyn EmuDev>| {
+            let other = dev.address_range();
+            range.start < other.end && other.start < range.end
+        };
+        let mut inner = self.inner_mut.lock();
+        if self.inner_const.emu_devs.iter().any(|(dev, _)| overlap(dev))
+            || inner.hotplug_devs.iter().any(|(dev, _)| overlap(dev))
+        {
+            error!(
+                "VM[{}] hotplug emu dev: region {:#x?} is already emulated",
+                self.id(),
+                range
+            );
+            return false;
+        }
+        // a new device counts from zero, even at the place of an unplugged one
+        inner.hotplug_devs.push((emu_dev, Arc::new(EmuDevStat::default())));
+        drop(inner);
+        if irq_id != 0 {
+            self.inner_const.hotplug_ints[irq_id / usize::BITS as usize]
+                .fetch_or(1 << (irq_id % usize::BITS as usize), Ordering::Release);
+        }
+        true
+    }
+
+    // remove the hot-plugged device at `ipa`, a trap already dispatched to it keeps its own reference
+    pub fn unplug_emu_dev(&self, ipa: usize, irq_id: usize) -> Option<Arc<dyn EmuDev>> {
+        let mut inner = self.inner_mut.lock();
+        let idx = inner
+            .hotplug_devs
+            .iter()
+            .position(|(dev, _)| dev.address_range().start == ipa)?;
+        let (dev, _) = inner.hotplug_devs.remove(idx);
+        drop(inner);
+        if irq_id != 0 {
+            self.inner_const.hotplug_ints[irq_id / usize::BITS as usize]
+                .fetch_and(!(1 << (irq_id % usize::BITS as usize)), Ordering::Release);
+        }
+        Some(dev)
+    }
+
+    pub fn emu_dev_stat_dump(&self, reset: bool) {
+        println!("VM[{}] emulated device traps:", self.id());
+        let hotplug_devs = self.inner_mut.lock().hotplug_devs.clone();
+        for (emu_dev, stat) in self.inner_const.emu_devs.iter().chain(hotplug_devs.iter()) {
+            stat.dump(emu_dev.as_ref());
+            if emu_dev.emu_type() == EmuDeviceType::EmuDeviceTVirtioBlk {
+                if let Ok(blk) = emu_dev.clone().into_any_arc().downcast::<VirtioMmio>() {
+                    virtio_blk_stat_dump(&blk, reset);
+                }
+            }
+            if reset {
+                stat.reset();
+            }
+        }
     }
 
     pub fn pt_map_range(&self, ipa: usize, len: usize, pa: usize, pte: usize, map_block: bool) {
+        let _tag = HeapTagGuard::new(HeapTag::PageTable);
         let vm_inner = self.inner_mut.lock();
         vm_inner.pt.pt_map_range(ipa, len, pa, pte, map_block);
     }
 
+    // false if a block crossing the range could not be split, it is still mapped
+    pub fn pt_unmap_range(&self, ipa: usize, len: usize) -> bool {
+        let _tag = HeapTagGuard::new(HeapTag::PageTable);
+        let vm_inner = self.inner_mut.lock();
+        let unmapped = vm_inner.pt.pt_unmap_range(ipa, len);
+        let shared = vm_inner.pt_shared_with_iommu();
+        drop(vm_inner);
+        if shared {
+            iommu_tlb_invalidate(self.id());
+        }
+        unmapped
+    }
+
+    // merge the 4K pages of the range mapping whole 2MB frames into blocks
+    pub fn pt_collapse_range(&self, ipa: usize, len: usize) -> usize {
+        let _tag = HeapTagGuard::new(HeapTag::PageTable);
+        let vm_inner = self.inner_mut.lock();
+        vm_inner.pt.pt_collapse_range(ipa, len)
+    }
+
     #[allow(dead_code)]
-    pub fn pt_unmap_range(&self, ipa: usize, len: usize, map_block: bool) {
+    pub fn pt_set_access_permission(&self, ipa: usize, len: usize, ap: usize) {
         let vm_inner = self.inner_mut.lock();
-        vm_inner.pt.pt_unmap_range(ipa, len, map_block);
+        vm_inner.pt.pt_set_access_permission(ipa, len, ap);
+    }
+
+    /* Start logging the writes to the memory of the VM: all of it is write protected and starts
+     * dirty, a permission fault marks the page and makes it writable again.
+     */
+    pub fn dirty_log_start(&self) -> bool {
+        let regions = self.memory_regions();
+        let mut vm_inner = self.inner_mut.lock();
+        if vm_inner.dirty_log.is_some() {
+            return false;
+        }
+        for region in regions.iter() {
+            vm_inner
+                .pt
+                .pt_set_access_permission(region.ipa_start, region.length, PTE_S2_FIELD_AP_RO);
+        }
+        vm_inner.dirty_log = Some(DirtyLog::new(regions));
+        let shared = vm_inner.pt_shared_with_iommu();
+        drop(vm_inner);
+        if shared {
+            iommu_tlb_invalidate(self.id());
+        }
+        true
+    }
+
+    pub fn dirty_log_stop(&self) -> bool {
+        let mut vm_inner = self.inner_mut.lock();
+        let log = match vm_inner.dirty_log.take() {
+            Some(log) => log,
+            None => return false,
+        };
+        // the writes split the blocks into pages, merge them back
+        let mut blocks = 0;
+        for region in log.regions() {
+            vm_inner
+                .pt
+                .pt_set_access_permission(region.ipa_start, region.length, PTE_S2_FIELD_AP_RW);
+            blocks += vm_inner.pt.pt_collapse_range(region.ipa_start, region.length);
+        }
+        let shared = vm_inner.pt_shared_with_iommu();
+        drop(vm_inner);
+        if shared {
+            iommu_tlb_invalidate(self.id());
+        }
+        debug!("VM[{}] dirty log stopped, {} blocks collapsed", self.id(), blocks);
+        true
+    }
+
+    // a write of the guest to `ipa` faulted, return false if it was not write protected by the dirty log
+    pub fn dirty_log_fault(&self, ipa: usize) -> bool {
+        let mut vm_inner = self.inner_mut.lock();
+        let vm_inner = &mut *vm_inner;
+        let log = match vm_inner.dirty_log.as_mut() {
+            Some(log) if log.contains(ipa) => log,
+            _ => return false,
+        };
+        if vm_inner.pt.ipa2pa(ipa).is_none() {
+            return false;
+        }
+        // only the page written is made writable, a block holding it is split
+        let start = round_down(ipa, PAGE_SIZE);
+        log.mark(start, PAGE_SIZE);
+        vm_inner
+            .pt
+            .pt_set_access_permission(start, PAGE_SIZE, PTE_S2_FIELD_AP_RW);
+        true
+    }
+
+    // the hypervisor wrote [ipa, ipa + len) of the guest memory, which does not fault
+    pub fn dirty_log_mark(&self, ipa: usize, len: usize) {
+        if let Some(log) = self.inner_mut.lock().dirty_log.as_mut() {
+            log.mark(ipa, len);
+        }
+    }
+
+    /* Copy the dirty bitmap to `out`, clear it and write protect the pages it held again, in one
+     * go under the lock of the page faults. Return the number of dirty pages fetched.
+     */
+    pub fn dirty_log_fetch(&self, out: &mut [usize]) -> Option<usize> {
+        let mut inner = self.inner_mut.lock();
+        let vm_inner = &mut *inner;
+        let log = vm_inner.dirty_log.as_mut()?;
+        if out.len() * core::mem::size_of::<usize>() < log.map_size() {
+            return None;
+        }
+        let dirty = log.dirty_pages();
+        for (ipa, len) in log.fetch(out) {
+            vm_inner.pt.pt_set_access_permission(ipa, len, PTE_S2_FIELD_AP_RO);
+        }
+        let shared = vm_inner.pt_shared_with_iommu();
+        drop(inner);
+        if shared {
+            iommu_tlb_invalidate(self.id());
+        }
+        Some(dirty)
+    }
+
+    // bytes of the dirty bitmap, 0 if the dirty log is off
+    pub fn dirty_log_size(&self) -> usize {
+        self.inner_mut.lock().dirty_log.as_ref().map_or(0, |log| log.map_size())
     }
 
     pub fn pt_dir(&self) -> usize {
@@ -356,15 +865,174 @@ impl Vm {
         vm_inner.pt.base_pa()
     }
 
+    // pa of `ipa` in stage-2, None for a lazily mapped block the guest has not touched yet
     pub fn ipa2pa(&self, ipa: usize) -> Option<usize> {
+        self.inner_mut.lock().pt.ipa2pa(ipa)
+    }
+
+    /* The memory behind [ipa, ipa + len), mapped in stage-2 or not yet, for the hva of the hypervisor,
+     * as (ipa, pa, len) runs of contiguous pages.
+     */
+    pub fn backing_runs(&self, ipa: usize, len: usize) -> Option<Vec<(usize, usize, usize)>> {
         let vm_inner = self.inner_mut.lock();
-        vm_inner.pt.ipa2pa(ipa)
+        let mut runs: Vec<(usize, usize, usize)> = Vec::new();
+        for page in (ipa..ipa + len).step_by(PAGE_SIZE) {
+            let pa = vm_inner.pt.ipa2pa(page).or_else(|| {
+                vm_inner
+                    .lazy_regions
+                    .iter()
+                    .find(|lazy| lazy.region.as_range().contains(&page))
+                    .and_then(|lazy| lazy.page_pa(page))
+            })?;
+            match runs.last_mut() {
+                Some((_, run_pa, run_len)) if *run_pa + *run_len == pa => *run_len += PAGE_SIZE,
+                _ => runs.push((page, pa, PAGE_SIZE)),
+            }
+        }
+        Some(runs)
+    }
+
+    pub fn add_lazy_region(&self, lazy: LazyRegion) {
+        self.inner_mut.lock().lazy_regions.push(lazy);
+    }
+
+    /* A stage-2 translation fault on `ipa`, map the lazily mapped block holding it.
+     * Return the pages mapped, 0 if another vcpu mapped it first, None if it is not lazily mapped memory.
+     */
+    pub fn lazy_map_fault(&self, ipa: usize) -> Option<usize> {
+        let _tag = HeapTagGuard::new(HeapTag::PageTable);
+        self.inner_mut.lock().lazy_map(ipa)
+    }
+
+    // map the lazily mapped blocks of [ipa, ipa + len) now, return the pages mapped
+    pub fn lazy_populate(&self, ipa: usize, len: usize) -> usize {
+        let _tag = HeapTagGuard::new(HeapTag::PageTable);
+        let mut vm_inner = self.inner_mut.lock();
+        let mut pages = 0;
+        let mut block = round_down(ipa, LAZY_BLOCK_SIZE);
+        while block < ipa.saturating_add(len) {
+            pages += vm_inner.lazy_map(block.max(ipa)).unwrap_or(0);
+            block += LAZY_BLOCK_SIZE;
+        }
+        pages
+    }
+
+    // check if [ipa, ipa + len) is inside one of the VM's memory regions
+    pub fn ipa_range_valid(&self, ipa: usize, len: usize) -> bool {
+        ipa_range_in_regions(&self.memory_regions(), ipa, len)
+    }
+
+    // memory regions of the VM, including the ones hot-added at runtime
+    pub fn memory_regions(&self) -> Vec<VmRegion> {
+        let mut regions = self.config().memory_region().to_vec();
+        regions.extend_from_slice(&self.inner_mut.lock().hotplug_regions);
+        regions
+    }
+
+    pub fn add_memory_region(&self, region: VmRegion) {
+        self.inner_mut.lock().hotplug_regions.push(region);
+    }
+
+    // passthrough regions of the VM, including the ones added at runtime
+    pub fn passthrough_regions(&self) -> Vec<PassthroughRegion> {
+        let mut regions = self.config().passthrough_device_regions().to_vec();
+        regions.extend_from_slice(&self.inner_mut.lock().hotplug_pt_regions);
+        regions
+    }
+
+    /* Map a passthrough region into the running VM and record it. The new ptes fill invalid entries
+     * only, no core has them in its TLB, so no invalidation is needed.
+     */
+    pub fn hotplug_pt_region(&self, region: PassthroughRegion, pte: usize) {
+        let _tag = HeapTagGuard::new(HeapTag::PageTable);
+        let mut vm_inner = self.inner_mut.lock();
+        vm_inner
+            .pt
+            .pt_map_range(region.ipa, region.length, region.pa, pte, true);
+        vm_inner.hotplug_pt_regions.push(region);
+    }
+
+    /* Unmap a passthrough region added by `hotplug_pt_region` at `ipa`, the TLB entries of the
+     * range are invalidated on all cores and in the SMMU before it returns.
+     */
+    pub fn unplug_pt_region(&self, ipa: usize) -> Option<PassthroughRegion> {
+        let _tag = HeapTagGuard::new(HeapTag::PageTable);
+        let mut vm_inner = self.inner_mut.lock();
+        let idx = vm_inner
+            .hotplug_pt_regions
+            .iter()
+            .position(|region| region.ipa == ipa)?;
+        let region = vm_inner.hotplug_pt_regions.remove(idx);
+        vm_inner.pt.pt_unmap_range(region.ipa, region.length);
+        let shared = vm_inner.pt_shared_with_iommu();
+        drop(vm_inner);
+        if shared {
+            iommu_tlb_invalidate(self.id());
+        }
+        Some(region)
+    }
+
+    // hva of the info page, if the VM has one
+    pub fn info_page(&self) -> Option<usize> {
+        self.inner_mut.lock().info_page.as_ref().map(|frame| frame.hva)
+    }
+
+    pub fn set_info_page(&self, frame: PageFrame) {
+        self.inner_mut.lock().info_page = Some(frame);
+    }
+
+    // hva of the pvclock page, if the VM has one
+    pub fn pvclock_page(&self) -> Option<usize> {
+        self.inner_mut.lock().pvclock_page.as_ref().map(|frame| frame.hva)
+    }
+
+    pub fn set_pvclock_page(&self, frame: PageFrame) {
+        self.inner_mut.lock().pvclock_page = Some(frame);
+    }
+
+    // hva of the spin-table release page, if the VM boots its secondaries with it
+    pub fn spin_table_page(&self) -> Option<usize> {
+        self.inner_mut.lock().spin_table_page.as_ref().map(|frame| frame.hva)
+    }
+
+    pub fn set_spin_table_page(&self, frame: PageFrame) {
+        self.inner_mut.lock().spin_table_page = Some(frame);
+    }
+
+    // some vcpus are on no core, the VM can not boot
+    pub fn vcpu_assign_failed(&self) -> bool {
+        self.inner_mut.lock().vcpu_assign_failed
+    }
+
+    pub fn set_vcpu_assign_failed(&self) {
+        self.inner_mut.lock().vcpu_assign_failed = true;
+    }
+
+    // physical counter value at virtual counter 0 of this VM
+    pub fn vtimer_offset(&self) -> usize {
+        #[cfg(feature = "vtimer")]
+        {
+            self.inner_mut.lock().vtimer.offset()
+        }
+        #[cfg(not(feature = "vtimer"))]
+        0
     }
 
     pub fn cpu_num(&self) -> usize {
         self.inner_const.config.cpu_num()
     }
 
+    #[cfg(feature = "memory-reservation")]
+    pub fn mem_bw_report(&self, vcpu_id: usize, used: u32, now: core::time::Duration) {
+        self.inner_const.mem_bw.lock().report(vcpu_id, used, now);
+    }
+
+    // the budget consumed in the last periods, the oldest first
+    #[cfg(feature = "memory-reservation")]
+    pub fn mem_bw_history(&self, out: &mut [MemBwRecord]) -> usize {
+        self.inner_const.mem_bw.lock().copy_to(out)
+    }
+
     #[inline]
     pub fn id(&self) -> usize {
         self.inner_const.id
@@ -381,8 +1049,7 @@ impl Vm {
     }
 
     pub fn reset_mem_regions(&self) {
-        let config = self.config();
-        for region in config.memory_region().iter() {
+        for region in self.memory_regions().iter() {
             let hva = self.ipa2hva(region.ipa_start);
             unsafe { core::slice::from_raw_parts_mut(hva as *mut u8, region.length) }.fill(0);
         }
@@ -393,6 +1060,59 @@ impl Vm {
         vm_inner.color_pa_info.region_list.append(&mut regions);
     }
 
+    pub fn ramdisk_size(&self) -> usize {
+        self.inner_mut.lock().ramdisk_size
+    }
+
+    pub fn set_ramdisk_size(&self, size: usize) {
+        self.inner_mut.lock().ramdisk_size = size;
+    }
+
+    // where vcpu 0 starts
+    pub fn entry_point(&self) -> usize {
+        self.inner_mut
+            .lock()
+            .entry_point
+            .unwrap_or_else(|| self.config().kernel_entry_point())
+    }
+
+    pub fn set_entry_point(&self, entry: Option<usize>) {
+        self.inner_mut.lock().entry_point = entry;
+    }
+
+    pub fn set_color_affinity(&self, affinity: Option<usize>) {
+        self.inner_mut.lock().color_pa_info.affinity = affinity;
+    }
+
+    // the cluster the memory is close to, the pages from that cluster and all pages of the colored memory
+    pub fn color_locality(&self) -> (Option<usize>, usize, usize) {
+        let vm_inner = self.inner_mut.lock();
+        let info = &vm_inner.color_pa_info;
+        let total = info.region_list.iter().map(|region| region.count).sum();
+        let local = info
+            .region_list
+            .iter()
+            .filter(|region| info.affinity.map_or(true, |affinity| region.affinity == affinity))
+            .map(|region| region.count)
+            .sum();
+        (info.affinity, local, total)
+    }
+
+    // the number of sets of the LLC reported to the guest, None for the hardware value
+    pub fn llc_num_sets(&self) -> Option<usize> {
+        self.inner_const.llc_num_sets
+    }
+
+    // colors of the memory allocated to this vm
+    pub fn color_bitmap(&self) -> usize {
+        let vm_inner = self.inner_mut.lock();
+        vm_inner
+            .color_pa_info
+            .region_list
+            .iter()
+            .fold(0, |bitmap, region| bitmap | (1 << region.color))
+    }
+
     pub fn vgic(&self) -> &Vgic {
         if let Some(vgic) = self.inner_const.arch_intc_dev.as_ref() {
             return vgic;
@@ -404,18 +1124,45 @@ impl Vm {
         self.inner_const.arch_intc_dev.is_some()
     }
 
+    // the cores holding the vcpus of this VM, each once
+    pub fn pcpu_list(&self) -> impl Iterator<Item = usize> {
+        let ncpu = self.ncpu();
+        (0..usize::BITS as usize).filter(move |cpu_id| ncpu & (1 << cpu_id) != 0)
+    }
+
+    // the physical cpus the vcpus are on now, the allocated bitmap of the config is only where they start
     pub fn ncpu(&self) -> usize {
-        self.inner_const.config.cpu_allocated_bitmap()
+        self.vcpu_list()
+            .iter()
+            .fold(0, |ncpu, vcpu| ncpu | (1 << vcpu.phys_id()))
     }
 
     pub fn has_interrupt(&self, int_id: usize) -> bool {
-        self.inner_const.int_bitmap.get(int_id) != 0
+        let has = |bitmap: &[AtomicUsize]| {
+            bitmap.get(int_id / usize::BITS as usize).map_or(false, |ints| {
+                ints.load(Ordering::Acquire) & (1 << (int_id % usize::BITS as usize)) != 0
+            })
+        };
+        has(&self.inner_const.int_bitmap) || has(&self.inner_const.hotplug_ints)
+    }
+
+    // a passthrough SPI moved to (`own` true) or away from this VM by `interrupt_vm_transfer`
+    pub fn set_passthrough_int(&self, int_id: usize, own: bool) {
+        if let Some(ints) = self.inner_const.int_bitmap.get(int_id / usize::BITS as usize) {
+            let bit = 1 << (int_id % usize::BITS as usize);
+            if own {
+                ints.fetch_or(bit, Ordering::Release);
+            } else {
+                ints.fetch_and(!bit, Ordering::Release);
+            }
+        }
     }
 
     pub fn vcpuid_to_pcpuid(&self, vcpuid: usize) -> Option<usize> {
         self.vcpu_list().get(vcpuid).map(|vcpu| vcpu.phys_id())
     }
 
+    // the first vcpu on core `pcpuid`, more of them may share it
     pub fn pcpuid_to_vcpuid(&self, pcpuid: usize) -> Option<usize> {
         for vcpu in self.vcpu_list() {
             if vcpu.phys_id() == pcpuid {
@@ -437,74 +1184,94 @@ impl Vm {
         pmask
     }
 
-    pub fn pcpu_to_vcpu_mask(&self, mask: usize, len: usize) -> usize {
-        let mut pmask = 0;
-        for i in 0..len {
-            if let Some(shift) = self.pcpuid_to_vcpuid(i) {
-                if mask & (1 << i) != 0 {
-                    pmask |= 1 << shift;
-                }
-            }
-        }
-        pmask
-    }
-
     pub fn show_pagetable(&self, ipa: usize) {
         let vm_inner = self.inner_mut.lock();
         vm_inner.pt.show_pt(ipa);
     }
 
-    // Formula: Virtual Count = Physical Count - <offset>
-    //          (from ARM: Learn the architecture - Generic Timer)
-    // So, <offset> = Physical Count - Virtual Count
-    // in this case, Physical Count is `timer::get_counter()`;
-    // virtual count is recorded when the VM is pending (runnning vcpu = 0)
     // Only used in Vcpu::context_vm_store
     #[cfg(feature = "vtimer")]
     pub(super) fn update_vtimer(&self) {
         let mut inner = self.inner_mut.lock();
-        trace!(">>> update_vtimer: VM[{}] running {}", self.id(), inner.running);
-        inner.running -= 1;
-        if inner.running == 0 {
-            inner.vtimer = super::timer::get_counter() - inner.vtimer_offset;
-            trace!("VM[{}] set vtimer {:#x}", self.id(), inner.vtimer);
-        }
+        inner.vtimer.pause(super::timer::get_counter());
+        trace!("VM[{}] pause vtimer, running {}", self.id(), inner.vtimer.running());
     }
 
-    // Only used in Vcpu::context_vm_restore
+    /* Only used in Vcpu::context_vm_restore, return the epoch offset the vcpu takes:
+     * the one its siblings run with, or a new one if no vcpu of the VM was running.
+     */
     #[cfg(feature = "vtimer")]
     pub(super) fn update_vtimer_offset(&self) -> usize {
         let mut inner = self.inner_mut.lock();
-        trace!(">>> update_vtimer_offset: VM[{}] running {}", self.id(), inner.running);
-        if inner.running == 0 {
-            inner.vtimer_offset = super::timer::get_counter() - inner.vtimer;
-            trace!("VM[{}] set offset {:#x}", self.id(), inner.vtimer_offset);
+        let resumed = inner.vtimer.resume(super::timer::get_counter());
+        let vtimer_offset = inner.vtimer.offset();
+        trace!("VM[{}] resume vtimer, offset {:#x}", self.id(), vtimer_offset);
+        drop(inner);
+        // the virtual counter stood still while the VM was pending, the wall clock did not
+        if resumed {
+            super::pvclock::pvclock_update(self);
         }
-        inner.running += 1;
-        inner.vtimer_offset
+        vtimer_offset
     }
 
+    // raw translation without checking the memory regions,
+    // addresses from guest should go through `access::vm_ipa2hva`
     pub fn ipa2hva(&self, ipa: usize) -> usize {
-        let mask = (1 << (HYP_VA_SIZE - VM_IPA_SIZE)) - 1;
-        let prefix = mask << VM_IPA_SIZE;
-        if ipa == 0 || ipa & prefix != 0 {
+        let hva = vm_ipa2hva_prefix(self.id(), ipa);
+        if hva == 0 {
             error!("ipa2hva: VM {} access invalid ipa {:x}", self.id(), ipa);
-            return 0;
         }
-        let prefix = prefix - ((self.id() & mask) << VM_IPA_SIZE);
-        prefix | ipa
+        hva
     }
 
+    /* Take the page at `guest_addr` from the VM and give it back to the free colored memory,
+     * returns false if the page is not a mapped page of the VM memory.
+     */
     #[cfg(feature = "balloon")]
-    pub fn inflate_balloon(&self, guest_addr: usize, len: usize) {
-        use crate::arch::PAGE_SIZE;
-        if len != PAGE_SIZE {
-            error!("len {:#x} not handable", len);
-            return;
+    pub fn inflate_balloon(&self, guest_addr: usize, len: usize) -> bool {
+        if len != PAGE_SIZE || guest_addr % PAGE_SIZE != 0 || !self.ipa_range_valid(guest_addr, len) {
+            error!(
+                "inflate_balloon: VM[{}] illegal page {guest_addr:#x} len {len:#x}",
+                self.id()
+            );
+            return false;
         }
-        let pa = self.ipa2pa(guest_addr).unwrap();
+        // the balloon takes the page from stage-2, a lazily mapped block the guest never touched is mapped first
+        self.lazy_populate(guest_addr, len);
+        // a page already in the balloon is not mapped
+        let pa = match self.ipa2pa(guest_addr) {
+            Some(pa) => pa,
+            None => {
+                warn!("inflate_balloon: VM[{}] page {guest_addr:#x} is not mapped", self.id());
+                return false;
+            }
+        };
         debug!("inflate_balloon: remove guest_addr {guest_addr:#x} -> pa {pa:#x}");
         let mut inner = self.inner_mut.lock();
+        if !inner
+            .color_pa_info
+            .region_list
+            .iter()
+            .any(|region| region.contains(&pa))
+        {
+            error!(
+                "inflate_balloon: VM[{}] page {guest_addr:#x} is not colored memory",
+                self.id()
+            );
+            return false;
+        }
+        // the guest must not reach the page once it is free
+        let unmapped = {
+            let _tag = HeapTagGuard::new(HeapTag::PageTable);
+            inner.pt.pt_unmap_range(guest_addr, len)
+        };
+        if !unmapped {
+            error!(
+                "inflate_balloon: VM[{}] page {guest_addr:#x} can not be unmapped",
+                self.id()
+            );
+            return false;
+        }
         let mut tmp = vec![];
         for region in inner.color_pa_info.region_list.iter_mut() {
             if region.contains(&pa) {
@@ -517,14 +1284,67 @@ impl Vm {
         inner.color_pa_info.region_list.retain(|region| !region.is_empty());
         inner.color_pa_info.region_list.append(&mut tmp);
         inner.balloon.push(guest_addr);
+        let shared = inner.pt_shared_with_iommu();
+        drop(inner);
+        if shared {
+            iommu_tlb_invalidate(self.id());
+        }
+        true
+    }
+
+    /* Give a page in the balloon back to the VM, backed by a new zeroed page of the VM colors. */
+    #[cfg(feature = "balloon")]
+    pub fn deflate_balloon(&self, guest_addr: usize) -> bool {
+        let mut inner = self.inner_mut.lock();
+        let idx = match inner.balloon.iter().position(|&addr| addr == guest_addr) {
+            Some(idx) => idx,
+            None => {
+                warn!(
+                    "deflate_balloon: VM[{}] page {guest_addr:#x} is not in the balloon",
+                    self.id()
+                );
+                return false;
+            }
+        };
+        let affinity = inner.color_pa_info.affinity;
+        let mut regions = match super::mem_region_alloc_colors_near(
+            Some(self.id()),
+            PAGE_SIZE,
+            self.config().memory_color_bitmap(),
+            affinity,
+        ) {
+            Ok((mut local, mut remote)) => {
+                local.append(&mut remote);
+                local
+            }
+            Err(_) => {
+                error!("deflate_balloon: VM[{}] out of memory", self.id());
+                return false;
+            }
+        };
+        let pa = regions[0].base;
+        inner.color_pa_info.region_list.append(&mut regions);
+        inner.balloon.swap_remove(idx);
         drop(inner);
-        self.pt_unmap_range(guest_addr, len, false);
+        self.pt_map_range(guest_addr, PAGE_SIZE, pa, PTE_S2_NORMAL, false);
+        // the page may have belonged to another VM
+        unsafe { core::slice::from_raw_parts_mut(self.ipa2hva(guest_addr) as *mut u8, PAGE_SIZE) }.fill(0);
+        debug!("deflate_balloon: add guest_addr {guest_addr:#x} -> pa {pa:#x}");
+        true
+    }
+
+    // pages taken from the VM by its balloon
+    #[cfg(feature = "balloon")]
+    pub fn balloon_pages(&self) -> usize {
+        self.inner_mut.lock().balloon.len()
     }
 }
 
 #[derive(Default, Debug, raii::RAII)]
 struct VmColorPaInfo {
     region_list: Vec<ColorMemRegion>,
+    // the cluster the memory is allocated close to, None if the platform memory is uniform
+    affinity: Option<usize>,
 }
 
 impl Drop for VmColorPaInfo {
@@ -545,20 +1365,184 @@ struct VmInnerMut {
     #[cfg(feature = "balloon")]
     balloon: Vec<usize>,
 
+    // length of the ramdisk loaded at ramdisk_load_ipa
+    ramdisk_size: usize,
+    // the entry of an elf kernel image, kernel_entry_point of the config is taken without it
+    entry_point: Option<usize>,
+    // memory regions added after the VM is created
+    hotplug_regions: Vec<VmRegion>,
+    // memory regions mapped in stage-2 a 2MB block at a time, on the first access to it
+    lazy_regions: Vec<LazyRegion>,
+    // passthrough regions added after the VM is set up
+    hotplug_pt_regions: Vec<PassthroughRegion>,
+    // emulated devices added after the VM is created and their trap counters
+    hotplug_devs: Vec<(Arc<dyn EmuDev>, Arc<EmuDevStat>)>,
+    // backing page of the EmuDeviceTInfoPage
+    info_page: Option<PageFrame>,
+    // backing page of the EmuDeviceTPvClock
+    pvclock_page: Option<PageFrame>,
+    // release page of the spin-table, see `spin_table_init`
+    spin_table_page: Option<PageFrame>,
+    // pages written since the last fetch of the MVM, while the memory of the VM is copied out
+    dirty_log: Option<DirtyLog>,
+    // a core could not take its vcpus, see `vmm_assign_vcpu_percore`
+    vcpu_assign_failed: bool,
+
     // VM timer
     #[cfg(feature = "vtimer")]
+    vtimer: VtimerEpoch,
+}
+
+// Formula: Virtual Count = Physical Count - <offset>
+//          (from ARM: Learn the architecture - Generic Timer)
+// So, <offset> = Physical Count - Virtual Count
+/* The virtual counter epoch of a VM. The vcpus keep their own CNTVOFF_EL2 in their context, and
+ * take the epoch offset when they are restored. The offset only moves while no vcpu of the VM
+ * runs, so a vcpu descheduled alone does not drift from the others, and a vcpu onlined later
+ * starts with the counter of its siblings.
+ * The physical counter is passed in, the self test drives it with made up values.
+ */
+#[derive(Clone, Copy)]
+pub struct VtimerEpoch {
+    // vcpus of the VM in guest context
     running: usize,
-    #[cfg(feature = "vtimer")]
-    vtimer_offset: usize,
-    #[cfg(feature = "vtimer")]
+    offset: usize,
+    // virtual count when the last running vcpu stopped
     vtimer: usize,
 }
 
+impl VtimerEpoch {
+    pub const fn new(now: usize) -> Self {
+        Self {
+            running: 0,
+            offset: now,
+            vtimer: 0,
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn running(&self) -> usize {
+        self.running
+    }
+
+    // a vcpu stops at physical count `now`
+    pub fn pause(&mut self, now: usize) {
+        match self.running {
+            // a vcpu stored without having been restored, e.g. reset while it was off
+            0 => {}
+            1 => {
+                self.running = 0;
+                self.vtimer = now - self.offset;
+            }
+            _ => self.running -= 1,
+        }
+    }
+
+    // a vcpu starts at physical count `now`, return whether the virtual counter was shifted
+    pub fn resume(&mut self, now: usize) -> bool {
+        let resumed = self.running == 0;
+        if resumed {
+            self.offset = now - self.vtimer;
+        }
+        self.running += 1;
+        resumed
+    }
+}
+
+const LAZY_BLOCK_SIZE: usize = 1 << LVL2_SHIFT;
+
+/* A memory region of a VM whose colored memory is allocated, but only mapped in stage-2 a 2MB block
+ * at a time, when the guest first accesses it. The pages keep the ipa they get when all mapped at once.
+ */
+pub struct LazyRegion {
+    region: VmRegion,
+    // the close memory first, then the remote one
+    local: ColorLayout,
+    remote: ColorLayout,
+    // the 2MB blocks mapped, from the one holding the start of the region
+    mapped: FlexBitmap,
+}
+
+impl LazyRegion {
+    pub fn new(region: VmRegion, local: ColorLayout, remote: ColorLayout) -> Self {
+        let start = round_down(region.ipa_start, LAZY_BLOCK_SIZE);
+        let end = round_up(region.ipa_start + region.length, LAZY_BLOCK_SIZE);
+        Self {
+            region,
+            local,
+            remote,
+            mapped: FlexBitmap::new((end - start) / LAZY_BLOCK_SIZE),
+        }
+    }
+
+    fn block(&self, ipa: usize) -> usize {
+        (round_down(ipa, LAZY_BLOCK_SIZE) - round_down(self.region.ipa_start, LAZY_BLOCK_SIZE)) / LAZY_BLOCK_SIZE
+    }
+
+    // the block holding `ipa`, without the part out of the region
+    fn block_range(&self, ipa: usize) -> Range<usize> {
+        let start = round_down(ipa, LAZY_BLOCK_SIZE).max(self.region.ipa_start);
+        let end = (round_down(ipa, LAZY_BLOCK_SIZE) + LAZY_BLOCK_SIZE).min(self.region.ipa_start + self.region.length);
+        start..end
+    }
+
+    fn page_pa(&self, ipa: usize) -> Option<usize> {
+        let idx = (ipa - self.region.ipa_start) / PAGE_SIZE;
+        let pa = match idx.checked_sub(self.local.pages()) {
+            None => self.local.page_pa(idx),
+            Some(idx) => self.remote.page_pa(idx),
+        };
+        pa.map(|pa| pa + ipa % PAGE_SIZE)
+    }
+}
+
 impl VmInnerMut {
-    fn new() -> Self {
+    // the iommu walks the stage-2 table of a VM with a context bank, the SMMU lock is taken after this one is dropped
+    fn pt_shared_with_iommu(&self) -> bool {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "iommu")] {
+                self.iommu_ctx_id.is_some()
+            } else {
+                false
+            }
+        }
+    }
+
+    /* Map the block of a lazy region holding `ipa` if it is not mapped yet, return the pages mapped.
+     * Its pages come from the colors already allocated to the VM, the memory is accounted to it as before.
+     * A block mapped before gives 0 if `ipa` is mapped, None if its page has been taken away, e.g. by the balloon.
+     */
+    fn lazy_map(&mut self, ipa: usize) -> Option<usize> {
+        let lazy = self
+            .lazy_regions
+            .iter_mut()
+            .find(|lazy| lazy.region.as_range().contains(&ipa))?;
+        let block = lazy.block(ipa);
+        if lazy.mapped.get(block) != 0 {
+            return self.pt.ipa2pa(ipa).map(|_| 0);
+        }
+        let range = lazy.block_range(ipa);
+        for page in range.clone().step_by(PAGE_SIZE) {
+            let pa = lazy.page_pa(page)?;
+            self.pt.pt_map_range(page, PAGE_SIZE, pa, PTE_S2_NORMAL, false);
+        }
+        // the pages of a block are contiguous with all colors
+        self.pt.pt_collapse_range(range.start, range.len());
+        lazy.mapped.set(block, true);
+        // the block is writable, fetched as dirty it is write protected again
+        if let Some(log) = self.dirty_log.as_mut() {
+            log.mark(range.start, range.len());
+        }
+        Some(range.len() / PAGE_SIZE)
+    }
+
+    fn new(id: usize) -> Self {
         Self {
-            pt: if let Ok(pt_dir_frame) = mem_page_alloc() {
-                PageTable::new(pt_dir_frame, true)
+            pt: if let Ok(pt_dir_frame) = mem_page_alloc(PageUsage::PageTable) {
+                PageTable::new(pt_dir_frame, id)
             } else {
                 panic!("vmm_init_memory: page alloc failed");
             },
@@ -567,12 +1551,19 @@ impl VmInnerMut {
             iommu_ctx_id: None,
             #[cfg(feature = "balloon")]
             balloon: vec![],
+            ramdisk_size: 0,
+            entry_point: None,
+            hotplug_regions: Vec::new(),
+            lazy_regions: Vec::new(),
+            hotplug_pt_regions: Vec::new(),
+            hotplug_devs: Vec::new(),
+            info_page: None,
+            pvclock_page: None,
+            spin_table_page: None,
+            dirty_log: None,
+            vcpu_assign_failed: false,
             #[cfg(feature = "vtimer")]
-            running: 0,
-            #[cfg(feature = "vtimer")]
-            vtimer_offset: super::timer::get_counter(),
-            #[cfg(feature = "vtimer")]
-            vtimer: 0,
+            vtimer: VtimerEpoch::new(super::timer::get_counter()),
         }
     }
 }
@@ -616,3 +1607,27 @@ pub fn vm_by_id(id: usize) -> Option<Arc<Vm>> {
     let vm_list = VM_LIST.lock();
     vm_list.iter().find(|&x| x.id() == id).cloned()
 }
+
+// check if [ipa, ipa + len) is inside one of `regions`, a range across two regions is not
+pub fn ipa_range_in_regions(regions: &[VmRegion], ipa: usize, len: usize) -> bool {
+    let end = match ipa.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    regions
+        .iter()
+        .any(|region| region.ipa_start <= ipa && end <= region.ipa_start + region.length)
+}
+
+/* The hypervisor va of `ipa` in the linear map of VM `vm_id`, 0 if the ipa is out of the VM ipa space.
+ * Every VM gets its own window of 1 << VM_IPA_SIZE bytes below the top of the hypervisor va space.
+ */
+pub fn vm_ipa2hva_prefix(vm_id: usize, ipa: usize) -> usize {
+    let mask = (1 << (HYP_VA_SIZE - VM_IPA_SIZE)) - 1;
+    let prefix = mask << VM_IPA_SIZE;
+    if ipa == 0 || ipa & prefix != 0 {
+        return 0;
+    }
+    let prefix = prefix - ((vm_id & mask) << VM_IPA_SIZE);
+    prefix | ipa
+}
@@ -0,0 +1,95 @@
+/* The locks of the state of a VM and its vgic. They are taken on the interrupt injection paths,
+ * including the ipi handlers, so a core must never wait for another core while holding one:
+ * the other core may be spinning on the same lock with the ipi it is waited for still queued.
+ * With the `lock-check` feature, each core counts the VM locks it holds and `assert_no_vm_lock`
+ * panics where such a wait starts, e.g. sending an ipi.
+ */
+
+#[cfg(not(feature = "lock-check"))]
+pub type VmMutex<T> = spin::Mutex<T>;
+
+#[cfg(not(feature = "lock-check"))]
+#[inline(always)]
+pub fn assert_no_vm_lock(_what: &str) {}
+
+#[cfg(feature = "lock-check")]
+pub use self::check::*;
+
+#[cfg(feature = "lock-check")]
+mod check {
+    use core::ops::{Deref, DerefMut};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use spin::{Mutex, MutexGuard};
+
+    use crate::board::static_config::CORE_NUM;
+    use crate::kernel::current_cpu;
+
+    // VM locks held by each core
+    static VM_LOCK_HELD: [AtomicUsize; CORE_NUM] = [const { AtomicUsize::new(0) }; CORE_NUM];
+
+    pub struct VmMutex<T> {
+        inner: Mutex<T>,
+    }
+
+    pub struct VmMutexGuard<'a, T> {
+        guard: MutexGuard<'a, T>,
+        cpu_id: usize,
+    }
+
+    impl<T> VmMutex<T> {
+        pub const fn new(data: T) -> Self {
+            Self {
+                inner: Mutex::new(data),
+            }
+        }
+
+        pub fn lock(&self) -> VmMutexGuard<'_, T> {
+            VmMutexGuard::new(self.inner.lock())
+        }
+
+        pub fn try_lock(&self) -> Option<VmMutexGuard<'_, T>> {
+            self.inner.try_lock().map(VmMutexGuard::new)
+        }
+    }
+
+    impl<T: Default> Default for VmMutex<T> {
+        fn default() -> Self {
+            Self::new(T::default())
+        }
+    }
+
+    impl<'a, T> VmMutexGuard<'a, T> {
+        fn new(guard: MutexGuard<'a, T>) -> Self {
+            let cpu_id = current_cpu().id;
+            VM_LOCK_HELD[cpu_id].fetch_add(1, Ordering::Relaxed);
+            Self { guard, cpu_id }
+        }
+    }
+
+    impl<T> Deref for VmMutexGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.guard
+        }
+    }
+
+    impl<T> DerefMut for VmMutexGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            &mut self.guard
+        }
+    }
+
+    impl<T> Drop for VmMutexGuard<'_, T> {
+        fn drop(&mut self) {
+            VM_LOCK_HELD[self.cpu_id].fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn assert_no_vm_lock(what: &str) {
+        let cpu_id = current_cpu().id;
+        let held = VM_LOCK_HELD[cpu_id].load(Ordering::Relaxed);
+        assert!(held == 0, "Core {} {} while holding {} VM locks", cpu_id, what, held);
+    }
+}
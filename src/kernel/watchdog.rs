@@ -0,0 +1,202 @@
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::arch::INTERRUPT_IRQ_WATCHDOG;
+use crate::board::static_config::CORE_NUM;
+use crate::board::PLAT_DESC;
+use crate::kernel::{current_cpu, interrupt_cpu_enable, interrupt_cpu_ipi_send, interrupt_reserve_int, CPU_MASTER};
+use crate::util::timer_list::{TimerEvent, TimerValue};
+use crate::vmm::vmm_force_crash_vm;
+
+use super::timer::{start_timer_event, timer_notify_after, TIMER_SLICE};
+
+// how often core 0 checks the heartbeats of the other cores, and its buddy the one of core 0
+const WATCHDOG_PERIOD: TimerValue = TimerValue::from_secs(1);
+// the periods a core may miss before it is reported, 0 turns the watchdog off
+const WATCHDOG_REPORT_DEFAULT: usize = 3;
+// vm_id and vcpu_id of a core not running a vcpu
+const WATCHDOG_NONE: usize = usize::MAX;
+
+/* Touched by each core on its timer tick, along with what the core was doing then.
+ * A core that stops ticking leaves here the last context the watchdog knows of.
+ */
+struct Heartbeat {
+    count: AtomicUsize,
+    ctx: AtomicUsize,
+    vm_id: AtomicUsize,
+    vcpu_id: AtomicUsize,
+    current_irq: AtomicUsize,
+}
+
+impl Heartbeat {
+    const fn new() -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            ctx: AtomicUsize::new(0),
+            vm_id: AtomicUsize::new(WATCHDOG_NONE),
+            vcpu_id: AtomicUsize::new(WATCHDOG_NONE),
+            current_irq: AtomicUsize::new(0),
+        }
+    }
+}
+
+static HEARTBEAT_LIST: [Heartbeat; CORE_NUM] = [const { Heartbeat::new() }; CORE_NUM];
+
+static WATCHDOG_REPORT: AtomicUsize = AtomicUsize::new(WATCHDOG_REPORT_DEFAULT);
+// the periods a core may miss before its active GVM is forced into `VmState::Crashed`, 0 never
+static WATCHDOG_CRASH: AtomicUsize = AtomicUsize::new(0);
+
+// the count seen on the last check and the periods missed since, of the cores checked by core 0 and its buddy
+struct WatchdogState {
+    seen: [usize; CORE_NUM],
+    missed: [usize; CORE_NUM],
+}
+
+static WATCHDOG_STATE: Mutex<WatchdogState> = Mutex::new(WatchdogState {
+    seen: [0; CORE_NUM],
+    missed: [0; CORE_NUM],
+});
+
+// on the timer tick of each core
+pub fn watchdog_tick() {
+    let cpu = current_cpu();
+    let heartbeat = &HEARTBEAT_LIST[cpu.id];
+    let (vm_id, vcpu_id) = match cpu.active_vcpu.as_ref() {
+        Some(vcpu) => (vcpu.vm_id(), vcpu.id()),
+        None => (WATCHDOG_NONE, WATCHDOG_NONE),
+    };
+    heartbeat.ctx.store(cpu.current_ctx() as usize, Ordering::Relaxed);
+    heartbeat.vm_id.store(vm_id, Ordering::Relaxed);
+    heartbeat.vcpu_id.store(vcpu_id, Ordering::Relaxed);
+    heartbeat.current_irq.store(cpu.current_irq, Ordering::Relaxed);
+    heartbeat.count.fetch_add(1, Ordering::Release);
+}
+
+// the diagnostic SGI, dumps the context the stuck core was interrupted in
+fn watchdog_sgi_handler() {
+    let cpu = current_cpu();
+    warn!(
+        "watchdog: core {} is alive, current_irq {} interrupt_nested {}",
+        cpu.id, cpu.current_irq, cpu.interrupt_nested
+    );
+    match unsafe { cpu.current_ctx().as_ref() } {
+        Some(ctx) => println!("{}", ctx),
+        None => warn!("watchdog: core {} has no context", cpu.id),
+    }
+}
+
+fn watchdog_report(cpu_id: usize, missed: usize) {
+    let heartbeat = &HEARTBEAT_LIST[cpu_id];
+    let vm_id = heartbeat.vm_id.load(Ordering::Relaxed);
+    let vcpu_id = heartbeat.vcpu_id.load(Ordering::Relaxed);
+    error!(
+        "watchdog: core {} missed {} heartbeats, last ctx {:#x} current_irq {}",
+        cpu_id,
+        missed,
+        heartbeat.ctx.load(Ordering::Relaxed),
+        heartbeat.current_irq.load(Ordering::Relaxed)
+    );
+    if vm_id == WATCHDOG_NONE {
+        error!("watchdog: core {} was idle", cpu_id);
+    } else {
+        error!("watchdog: core {} was running VM[{}] vcpu {}", cpu_id, vm_id, vcpu_id);
+    }
+    interrupt_cpu_ipi_send(cpu_id, INTERRUPT_IRQ_WATCHDOG);
+}
+
+fn watchdog_crash(cpu_id: usize) {
+    let vm_id = HEARTBEAT_LIST[cpu_id].vm_id.load(Ordering::Relaxed);
+    match vm_id {
+        WATCHDOG_NONE => {}
+        0 => error!("watchdog: core {} stuck in VM0, it can not be crashed", cpu_id),
+        _ => {
+            if vmm_force_crash_vm(vm_id) {
+                error!("watchdog: VM[{}] on stuck core {} is crashed", vm_id, cpu_id);
+            }
+        }
+    }
+}
+
+// the core that checks core 0, None with a single core
+fn watchdog_buddy() -> Option<usize> {
+    let buddy = (CPU_MASTER + 1) % PLAT_DESC.cpu_desc.num;
+    (buddy != CPU_MASTER).then_some(buddy)
+}
+
+fn watchdog_check(watched: impl Iterator<Item = usize>) {
+    let report = WATCHDOG_REPORT.load(Ordering::Relaxed);
+    let crash = WATCHDOG_CRASH.load(Ordering::Relaxed);
+    let mut state = WATCHDOG_STATE.lock();
+    for cpu_id in watched {
+        let count = HEARTBEAT_LIST[cpu_id].count.load(Ordering::Acquire);
+        // a core that has not ticked yet is not up
+        if count == 0 || count != state.seen[cpu_id] {
+            state.seen[cpu_id] = count;
+            if state.missed[cpu_id] >= report && report != 0 {
+                warn!(
+                    "watchdog: core {} is back after {} periods",
+                    cpu_id, state.missed[cpu_id]
+                );
+            }
+            state.missed[cpu_id] = 0;
+            continue;
+        }
+        state.missed[cpu_id] += 1;
+        let missed = state.missed[cpu_id];
+        if report != 0 && missed == report {
+            watchdog_report(cpu_id, missed);
+        }
+        if report != 0 && crash != 0 && missed == crash {
+            watchdog_crash(cpu_id);
+        }
+    }
+}
+
+struct WatchdogTimer;
+
+impl TimerEvent for WatchdogTimer {
+    fn callback(self: Arc<Self>, _now: TimerValue) {
+        if current_cpu().id == CPU_MASTER {
+            watchdog_check((0..PLAT_DESC.cpu_desc.num).filter(|&id| id != CPU_MASTER));
+        } else {
+            watchdog_check(core::iter::once(CPU_MASTER));
+        }
+        start_timer_event(WATCHDOG_PERIOD, self);
+    }
+}
+
+/* Keep the tick of every core running so that it touches its heartbeat, even with a single vcpu,
+ * and let core 0 check the other cores. Core 0 itself is checked by the next core, its buddy.
+ */
+pub fn watchdog_init() {
+    let cpu = current_cpu();
+    if cpu.id == CPU_MASTER {
+        interrupt_reserve_int(INTERRUPT_IRQ_WATCHDOG, watchdog_sgi_handler);
+        start_timer_event(WATCHDOG_PERIOD, Arc::new(WatchdogTimer));
+        info!("Watchdog init ok, period {:?}", WATCHDOG_PERIOD);
+    } else if watchdog_buddy() == Some(cpu.id) {
+        start_timer_event(WATCHDOG_PERIOD, Arc::new(WatchdogTimer));
+    }
+    interrupt_cpu_enable(INTERRUPT_IRQ_WATCHDOG, true);
+    cpu.vcpu_array.update_timer();
+    timer_notify_after(TimerValue::from_millis(TIMER_SLICE as u64));
+}
+
+/* HVC_SYS_WATCHDOG: report a core after `report` missed periods, 0 turns the watchdog off,
+ * and force its active GVM into `VmState::Crashed` after `crash`, 0 never. Return the old `report`.
+ */
+pub fn watchdog_set(report: usize, crash: usize) -> Result<usize, ()> {
+    if crash != 0 && crash < report {
+        error!(
+            "watchdog_set: crash after {} periods is before the report after {}",
+            crash, report
+        );
+        return Err(());
+    }
+    WATCHDOG_CRASH.store(crash, Ordering::Relaxed);
+    let old = WATCHDOG_REPORT.swap(report, Ordering::Relaxed);
+    info!("watchdog_set: report after {} periods, crash after {}", report, crash);
+    Ok(old)
+}
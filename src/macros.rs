@@ -24,6 +24,10 @@ macro_rules! declare_enum_with_handler {
         $array_vis static $array: &[$handler_type] = &[
             $($handler, )*
         ];
+        impl $enum_name {
+            // the number of variants, the length of the handler list
+            $enum_vis const NUM: usize = [$(stringify!($variant), )*].len();
+        }
     }
 }
 
@@ -78,16 +78,26 @@ pub fn init(cpu_id: usize, dtb: *mut core::ffi::c_void) -> ! {
     kernel::timer_init();
     util::barrier();
     kernel::hypervisor_self_coloring();
+    #[cfg(feature = "tlb-stress")]
+    kernel::tlb_stress_test();
     if cpu_id == 0 {
+        #[cfg(feature = "self-test")]
+        if !kernel::self_test() {
+            panic!("self-test failed, VM0 is not started");
+        }
         kernel::subinit();
         vmm::vm_init();
         info!(
             "{} Hypervisor init ok\n\nStart booting Monitor VM ...",
             env!("CARGO_PKG_NAME")
         );
-        vmm::vmm_boot_vm(0);
+        if vmm::vmm_boot_vm(0).is_err() {
+            panic!("failed to boot VM0");
+        }
     }
 
+    #[cfg(feature = "watchdog")]
+    kernel::watchdog_init();
     use kernel::current_cpu;
     current_cpu().vcpu_array.resched();
     extern "C" {
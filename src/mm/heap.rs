@@ -1,5 +1,7 @@
 use buddy_system_allocator::Heap;
+use core::alloc::Layout;
 use core::ops::RangeInclusive;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::Mutex;
 
 struct LockedHeap<const ORDER: usize>(Mutex<Heap<ORDER>>);
@@ -20,22 +22,201 @@ impl<const ORDER: usize> core::ops::Deref for LockedHeap<ORDER> {
     }
 }
 
-unsafe impl<const ORDER: usize> alloc::alloc::GlobalAlloc for LockedHeap<ORDER> {
-    unsafe fn alloc(&self, layout: alloc::alloc::Layout) -> *mut u8 {
-        self.0
-            .lock()
+impl<const ORDER: usize> LockedHeap<ORDER> {
+    unsafe fn alloc_raw(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.0.lock();
+        let ptr = heap
             .alloc(layout)
-            .map_or(core::ptr::null_mut(), |allocation| allocation.as_ptr())
+            .map_or(core::ptr::null_mut(), |allocation| allocation.as_ptr());
+        HEAP_PEAK.fetch_max(heap.stats_alloc_actual(), Ordering::Relaxed);
+        ptr
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: alloc::alloc::Layout) {
+    unsafe fn dealloc_raw(&self, ptr: *mut u8, layout: Layout) {
         self.0.lock().dealloc(core::ptr::NonNull::new_unchecked(ptr), layout)
     }
 }
 
+#[cfg(not(feature = "heap-tag"))]
+unsafe impl<const ORDER: usize> alloc::alloc::GlobalAlloc for LockedHeap<ORDER> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_raw(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.dealloc_raw(ptr, layout)
+    }
+}
+
+/* With heap-tag, an allocation of small alignment carries the tag of the core at allocation time
+ * in HEAP_TAG_HEADER bytes before it, so it is freed from the same tag on any core.
+ * The page frames and other allocations of larger alignment are not tagged, the page frames are
+ * counted by their PageUsage.
+ */
+#[cfg(feature = "heap-tag")]
+const HEAP_TAG_HEADER: usize = 16;
+
+#[cfg(feature = "heap-tag")]
+unsafe impl<const ORDER: usize> alloc::alloc::GlobalAlloc for LockedHeap<ORDER> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.align() > HEAP_TAG_HEADER {
+            return self.alloc_raw(layout);
+        }
+        let tag = crate::kernel::current_cpu().heap_tag;
+        let counter = &HEAP_TAG_COUNTERS[tag as usize];
+        let ptr = match Layout::from_size_align(layout.size() + HEAP_TAG_HEADER, HEAP_TAG_HEADER) {
+            Ok(tagged) => self.alloc_raw(tagged),
+            Err(_) => core::ptr::null_mut(),
+        };
+        if ptr.is_null() {
+            counter.fails.fetch_add(1, Ordering::Relaxed);
+            return ptr;
+        }
+        *(ptr as *mut usize) = tag as usize;
+        counter.allocs.fetch_add(1, Ordering::Relaxed);
+        let current = counter.current.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        counter.peak.fetch_max(current, Ordering::Relaxed);
+        ptr.add(HEAP_TAG_HEADER)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if layout.align() > HEAP_TAG_HEADER {
+            return self.dealloc_raw(ptr, layout);
+        }
+        let ptr = ptr.sub(HEAP_TAG_HEADER);
+        let tag = *(ptr as *const usize);
+        if let Some(counter) = HEAP_TAG_COUNTERS.get(tag) {
+            counter.current.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        self.dealloc_raw(
+            ptr,
+            Layout::from_size_align_unchecked(layout.size() + HEAP_TAG_HEADER, HEAP_TAG_HEADER),
+        )
+    }
+}
+
 #[global_allocator]
 static HEAP_ALLOCATOR: LockedHeap<{ usize::BITS as usize }> = LockedHeap::empty();
 
+// the peak of the actually allocated bytes
+static HEAP_PEAK: AtomicUsize = AtomicUsize::new(0);
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapStat {
+    pub total: usize,
+    pub free: usize,
+    pub peak: usize,
+}
+
+pub fn heap_stat() -> HeapStat {
+    let heap = HEAP_ALLOCATOR.lock();
+    let total = heap.stats_total_bytes();
+    HeapStat {
+        total,
+        free: total - heap.stats_alloc_actual(),
+        peak: HEAP_PEAK.load(Ordering::Relaxed),
+    }
+}
+
+/// The subsystem the heap is used by, set on a core with `HeapTagGuard`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeapTag {
+    Other = 0,
+    Vgic = 1,
+    Virtio = 2,
+    PageTable = 3,
+    Config = 4,
+    Ipi = 5,
+}
+
+pub const HEAP_TAG_NUM: usize = 6;
+
+impl HeapTag {
+    const ALL: [HeapTag; HEAP_TAG_NUM] = [
+        HeapTag::Other,
+        HeapTag::Vgic,
+        HeapTag::Virtio,
+        HeapTag::PageTable,
+        HeapTag::Config,
+        HeapTag::Ipi,
+    ];
+}
+
+/* The heap allocated on this core is accounted to `tag` until the guard is dropped,
+ * it is taken at the entry points of the subsystems, the previous tag comes back on drop.
+ */
+pub struct HeapTagGuard(HeapTag);
+
+impl HeapTagGuard {
+    pub fn new(tag: HeapTag) -> Self {
+        Self(core::mem::replace(&mut crate::kernel::current_cpu().heap_tag, tag))
+    }
+}
+
+impl Drop for HeapTagGuard {
+    fn drop(&mut self) {
+        crate::kernel::current_cpu().heap_tag = self.0;
+    }
+}
+
+struct HeapTagCounter {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+    allocs: AtomicUsize,
+    fails: AtomicUsize,
+}
+
+impl HeapTagCounter {
+    const fn new() -> Self {
+        Self {
+            current: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            allocs: AtomicUsize::new(0),
+            fails: AtomicUsize::new(0),
+        }
+    }
+}
+
+// only counted with heap-tag
+static HEAP_TAG_COUNTERS: [HeapTagCounter; HEAP_TAG_NUM] = [const { HeapTagCounter::new() }; HEAP_TAG_NUM];
+
+// bytes asked by the allocations of a HeapTag, without the tag headers
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapTagStat {
+    pub current: usize,
+    pub peak: usize,
+    pub allocs: usize,
+    pub fails: usize,
+}
+
+pub fn heap_tag_stats() -> [HeapTagStat; HEAP_TAG_NUM] {
+    let mut stats = [HeapTagStat::default(); HEAP_TAG_NUM];
+    for (stat, counter) in stats.iter_mut().zip(HEAP_TAG_COUNTERS.iter()) {
+        *stat = HeapTagStat {
+            current: counter.current.load(Ordering::Relaxed),
+            peak: counter.peak.load(Ordering::Relaxed),
+            allocs: counter.allocs.load(Ordering::Relaxed),
+            fails: counter.fails.load(Ordering::Relaxed),
+        };
+    }
+    stats
+}
+
+pub fn heap_tag_dump() {
+    if !cfg!(feature = "heap-tag") {
+        return;
+    }
+    println!("heap by tag:");
+    for (tag, stat) in HeapTag::ALL.iter().zip(heap_tag_stats().iter()) {
+        println!(
+            "  {:?}: current {:#x}, peak {:#x}, allocs {}, fails {}",
+            tag, stat.current, stat.peak, stat.allocs, stat.fails
+        );
+    }
+}
+
 pub fn heap_init() {
     #[repr(align(4096))]
     struct HeapRegion([u8; HEAP_SIZE]);
@@ -58,5 +239,10 @@ pub fn heap_expansion(region: RangeInclusive<usize>) {
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: core::alloc::Layout) -> ! {
-    panic!("Out Of Memory: Heap allocation error, layout = {:x?}", layout);
+    super::mem_usage_dump();
+    panic!(
+        "Out Of Memory: Heap allocation error, layout = {:x?}, tag {:?}",
+        layout,
+        crate::kernel::current_cpu().heap_tag
+    );
 }
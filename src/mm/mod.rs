@@ -1,4 +1,6 @@
-pub use self::heap::heap_expansion;
+pub use self::heap::{
+    heap_expansion, heap_stat, heap_tag_stats, HeapStat, HeapTag, HeapTagGuard, HeapTagStat, HEAP_TAG_NUM,
+};
 pub use self::page_frame::*;
 
 mod heap;
@@ -17,3 +19,15 @@ pub fn init() {
     heap::heap_init();
     vpage_allocator::init();
 }
+
+// print the hypervisor memory usage, e.g. when running out of memory
+pub fn mem_usage_dump() {
+    let stat = heap_stat();
+    println!(
+        "hypervisor heap: total {:#x}, free {:#x}, peak used {:#x}",
+        stat.total, stat.free, stat.peak
+    );
+    heap::heap_tag_dump();
+    println!("page frames:");
+    page_usage_dump();
+}
@@ -1,40 +1,86 @@
 use alloc::alloc;
 use core::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::arch::PAGE_SIZE;
 use crate::kernel::{current_cpu, AllocError};
 
+/// The subsystem a page frame is allocated for, used for memory accounting
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageUsage {
+    PageTable = 0,
+    Ivc = 1,
+    VmInfo = 2,
+    Console = 3,
+    PvClock = 4,
+    SpinTable = 5,
+}
+
+pub const PAGE_USAGE_NUM: usize = 6;
+
+impl PageUsage {
+    const ALL: [PageUsage; PAGE_USAGE_NUM] = [
+        PageUsage::PageTable,
+        PageUsage::Ivc,
+        PageUsage::VmInfo,
+        PageUsage::Console,
+        PageUsage::PvClock,
+        PageUsage::SpinTable,
+    ];
+}
+
+// allocated pages of each PageUsage
+static PAGE_USAGE_COUNT: [AtomicUsize; PAGE_USAGE_NUM] = [const { AtomicUsize::new(0) }; PAGE_USAGE_NUM];
+
+pub fn page_usage_count(usage: PageUsage) -> usize {
+    PAGE_USAGE_COUNT[usage as usize].load(Ordering::Relaxed)
+}
+
+pub fn page_usage_dump() {
+    for usage in PageUsage::ALL {
+        println!("  {:?}: {} pages", usage, page_usage_count(usage));
+    }
+}
+
 #[derive(Debug, raii::RAII)]
 pub struct PageFrame {
     pub hva: usize,
     pub page_num: usize,
     pub pa: usize,
     layout: Layout,
+    usage: PageUsage,
 }
 
 #[allow(dead_code)]
 impl PageFrame {
-    fn new(hva: usize, page_num: usize, layout: Layout) -> Self {
+    fn new(hva: usize, page_num: usize, layout: Layout, usage: PageUsage) -> Self {
+        PAGE_USAGE_COUNT[usage as usize].fetch_add(page_num, Ordering::Relaxed);
         Self {
             hva,
             page_num,
             pa: current_cpu().pt().ipa2pa(hva).unwrap(),
             layout,
+            usage,
         }
     }
 
-    pub fn alloc_pages(page_num: usize) -> Result<Self, AllocError> {
+    pub fn alloc_pages(page_num: usize, usage: PageUsage) -> Result<Self, AllocError> {
         if page_num == 0 {
             return Err(AllocError::AllocZeroPage);
         }
         match Layout::from_size_align(page_num * PAGE_SIZE, PAGE_SIZE) {
             Ok(layout) => {
                 let hva = unsafe { alloc::alloc_zeroed(layout) };
-                if hva.is_null() || hva as usize & (PAGE_SIZE - 1) != 0 {
+                if hva.is_null() {
+                    error!("alloc_pages: failed to alloc {} pages for {:?}", page_num, usage);
+                    super::mem_usage_dump();
+                    return Err(AllocError::OutOfFrame(page_num));
+                }
+                if hva as usize & (PAGE_SIZE - 1) != 0 {
                     panic!("alloc_pages: get wrong ptr {hva:#p}, layout = {:?}", layout);
                 }
                 let hva = hva as usize;
-                Ok(Self::new(hva, page_num, layout))
+                Ok(Self::new(hva, page_num, layout, usage))
             }
             Err(err) => {
                 error!("alloc_pages: Layout error {}", err);
@@ -55,6 +101,7 @@ impl PageFrame {
 impl Drop for PageFrame {
     fn drop(&mut self) {
         trace!("<<< free page frame {:#x}, {}", self.pa, self.page_num);
+        PAGE_USAGE_COUNT[self.usage as usize].fetch_sub(self.page_num, Ordering::Relaxed);
         unsafe { alloc::dealloc(self.hva as *mut _, self.layout) }
     }
 }
@@ -1,4 +1,5 @@
-use crate::kernel::current_cpu;
+use crate::arch::exception_syndrome_regs;
+use crate::kernel::{current_cpu, event_trace_dump};
 use core::panic::PanicInfo;
 
 #[cfg_attr(target_os = "none", panic_handler)]
@@ -8,9 +9,15 @@ fn panic(info: &PanicInfo) -> ! {
         current_cpu().id,
         info
     );
+    if let Some(vcpu) = current_cpu().active_vcpu.as_ref() {
+        println!("active VM {:?} vcpu {}", vcpu.vm().map(|vm| vm.id()), vcpu.id());
+    }
+    let (esr, far, hpfar) = exception_syndrome_regs();
+    println!("ESR_EL2 {:#x} FAR_EL2 {:#x} HPFAR_EL2 {:#x}", esr, far, hpfar);
     if let Some(ctx) = unsafe { current_cpu().current_ctx().as_ref() } {
         println!("{}", ctx);
     }
+    event_trace_dump();
     loop {
         core::hint::spin_loop();
     }
@@ -135,7 +135,7 @@ impl FlexBitmap {
     }
 
     pub fn get(&self, idx: usize) -> usize {
-        if idx > self.len {
+        if idx >= self.len {
             panic!("too large idx {} for get bitmap", idx);
         }
         let val = self.map[idx / 64];
@@ -143,7 +143,7 @@ impl FlexBitmap {
     }
 
     pub fn set(&mut self, bit: usize, val: bool) {
-        if bit > self.len {
+        if bit >= self.len {
             panic!("too large idx {} for set bitmap", bit);
         }
         if val {
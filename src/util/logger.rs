@@ -1,5 +1,201 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
 
+pub const LOG_RING_SIZE: usize = 0x1000;
+const LOG_LINE_MAX: usize = 256;
+
+/* A ring of log lines kept in memory, the oldest lines are dropped to make room for new ones.
+ * `head` and `tail` only increase, the offset in `buf` is taken modulo LOG_RING_SIZE.
+ */
+pub struct LogRing {
+    buf: [u8; LOG_RING_SIZE],
+    head: usize,
+    tail: usize,
+    // lines dropped since the last read
+    dropped: usize,
+    // print lines of this ring on the console as well
+    console: bool,
+}
+
+impl LogRing {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; LOG_RING_SIZE],
+            head: 0,
+            tail: 0,
+            dropped: 0,
+            console: true,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.head = 0;
+        self.tail = 0;
+        self.dropped = 0;
+        self.console = true;
+    }
+
+    pub fn console(&self) -> bool {
+        self.console
+    }
+
+    pub fn set_console(&mut self, console: bool) {
+        self.console = console;
+    }
+
+    fn drop_line(&mut self) {
+        while self.head < self.tail {
+            let c = self.buf[self.head % LOG_RING_SIZE];
+            self.head += 1;
+            if c == b'\n' {
+                break;
+            }
+        }
+        self.dropped += 1;
+    }
+
+    pub fn push(&mut self, line: &[u8]) {
+        let line = &line[..line.len().min(LOG_RING_SIZE)];
+        while LOG_RING_SIZE - (self.tail - self.head) < line.len() {
+            self.drop_line();
+        }
+        for &c in line {
+            self.buf[self.tail % LOG_RING_SIZE] = c;
+            self.tail += 1;
+        }
+    }
+
+    // consume up to `out.len()` bytes, return the number of bytes copied
+    pub fn pop(&mut self, out: &mut [u8]) -> usize {
+        let len = out.len().min(self.tail - self.head);
+        for c in out[..len].iter_mut() {
+            *c = self.buf[self.head % LOG_RING_SIZE];
+            self.head += 1;
+        }
+        len
+    }
+
+    pub fn take_dropped(&mut self) -> usize {
+        core::mem::take(&mut self.dropped)
+    }
+}
+
+// a log line for the rings, without color and truncated to LOG_LINE_MAX
+struct LogLine {
+    buf: [u8; LOG_LINE_MAX],
+    len: usize,
+}
+
+impl LogLine {
+    const fn new() -> Self {
+        Self {
+            buf: [0; LOG_LINE_MAX],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&mut self) -> &[u8] {
+        self.buf[self.len] = b'\n';
+        &self.buf[..=self.len]
+    }
+}
+
+impl Write for LogLine {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // keep the last byte for '\n'
+        let len = s.len().min(LOG_LINE_MAX - 1 - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/* Subsystems with their own log level, picked by the module path of a line. The levels can be
+ * changed at runtime, a line is only formatted if it passes the level of its subsystem.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogModule {
+    Vgic = 0,
+    VirtioBlk = 1,
+    VirtioNet = 2,
+    Vmm = 3,
+    Mem = 4,
+    // the rest of the hypervisor
+    Other = 5,
+}
+
+const LOG_MODULE_NUM: usize = 6;
+const LOG_LEVEL_DEFAULT: LevelFilter = LevelFilter::Info;
+
+static LOG_LEVELS: [AtomicUsize; LOG_MODULE_NUM] =
+    [const { AtomicUsize::new(LOG_LEVEL_DEFAULT as usize) }; LOG_MODULE_NUM];
+
+impl LogModule {
+    pub fn from_usize(module: usize) -> Option<Self> {
+        match module {
+            0 => Some(Self::Vgic),
+            1 => Some(Self::VirtioBlk),
+            2 => Some(Self::VirtioNet),
+            3 => Some(Self::Vmm),
+            4 => Some(Self::Mem),
+            5 => Some(Self::Other),
+            _ => None,
+        }
+    }
+
+    // `target` is the module path of the line, e.g. rtshyper_rs::device::virtio::blk
+    pub fn from_target(target: &str) -> Self {
+        let path = target.split_once("::").map_or("", |(_, path)| path);
+        if path.starts_with("arch::aarch64::vgic") {
+            Self::Vgic
+        } else if path.starts_with("device::virtio::blk") || path.starts_with("device::virtio::mediated") {
+            Self::VirtioBlk
+        } else if path.starts_with("device::virtio::net") {
+            Self::VirtioNet
+        } else if path.starts_with("vmm") {
+            Self::Vmm
+        } else if path.starts_with("mm::") || path.starts_with("kernel::mem") {
+            Self::Mem
+        } else {
+            Self::Other
+        }
+    }
+
+    pub fn level(self) -> LevelFilter {
+        level_from_usize(LOG_LEVELS[self as usize].load(Ordering::Relaxed)).unwrap_or(LOG_LEVEL_DEFAULT)
+    }
+}
+
+fn level_from_usize(level: usize) -> Option<LevelFilter> {
+    match level {
+        0 => Some(LevelFilter::Off),
+        1 => Some(LevelFilter::Error),
+        2 => Some(LevelFilter::Warn),
+        3 => Some(LevelFilter::Info),
+        4 => Some(LevelFilter::Debug),
+        5 => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+/* Set the level of a subsystem, 0 (off) to 5 (trace), return the old one.
+ * The global max level of the log crate follows the most verbose subsystem, so the lines no
+ * subsystem wants are dropped before anything is looked at.
+ */
+pub fn log_level_set(module: LogModule, level: usize) -> Option<LevelFilter> {
+    let level = level_from_usize(level)?;
+    let old = LOG_LEVELS[module as usize].swap(level as usize, Ordering::Relaxed);
+    let max = LOG_LEVELS
+        .iter()
+        .map(|level| level.load(Ordering::Relaxed))
+        .max()
+        .unwrap_or(0);
+    log::set_max_level(level_from_usize(max).unwrap_or(LOG_LEVEL_DEFAULT));
+    level_from_usize(old)
+}
+
 struct SimpleLogger;
 
 fn level2color(level: Level) -> u8 {
@@ -20,8 +216,8 @@ macro_rules! with_color {
 }
 
 impl log::Log for SimpleLogger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= LogModule::from_target(metadata.target()).level()
     }
 
     fn log(&self, record: &Record) {
@@ -37,13 +233,33 @@ impl log::Log for SimpleLogger {
                 Level::Debug => "D>",
                 Level::Trace => "T>",
             };
+            let cpu = crate::kernel::current_cpu();
+            // lines logged when handling a VM go to the ring of that VM, others to the global ring
+            let vm_id = cpu.active_vcpu.as_ref().map(|vcpu| vcpu.vm_id());
+            // core id and vm id, `-` outside of a VM
+            let vm = VmTag(vm_id);
+            let mut line = LogLine::new();
+            let _ = write!(
+                line,
+                "[{sec:04}.{ms:03}]{}{}:{}[{}] {}",
+                level,
+                cpu.id,
+                vm,
+                record.target(),
+                record.args()
+            );
+            // the ring may be read only, which keeps the uart lock out of the way
+            if !crate::kernel::vm_log_push(vm_id, line.as_bytes()) {
+                return;
+            }
             println!(
                 "{}",
                 with_color!(
                     level2color(record.level()),
-                    "[{sec:04}.{ms:03}]{}{}[{}] {}",
+                    "[{sec:04}.{ms:03}]{}{}:{}[{}] {}",
                     level,
-                    crate::kernel::current_cpu().id,
+                    cpu.id,
+                    vm,
                     record.target(),
                     record.args()
                 )
@@ -54,8 +270,19 @@ impl log::Log for SimpleLogger {
     fn flush(&self) {}
 }
 
+struct VmTag(Option<usize>);
+
+impl core::fmt::Display for VmTag {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            Some(vm_id) => write!(f, "{}", vm_id),
+            None => write!(f, "-"),
+        }
+    }
+}
+
 static LOGGER: SimpleLogger = SimpleLogger;
 
 pub fn logger_init() -> Result<(), SetLoggerError> {
-    log::set_logger(&LOGGER).map(|()| log::set_max_level(LevelFilter::Trace))
+    log::set_logger(&LOGGER).map(|()| log::set_max_level(LOG_LEVEL_DEFAULT))
 }
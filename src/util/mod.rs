@@ -10,6 +10,7 @@ pub mod device_ref;
 pub mod downcast;
 pub mod logger;
 mod print;
+pub mod rng;
 pub mod self_ref_cell;
 mod time;
 pub mod timer_list;
@@ -0,0 +1,85 @@
+//! Entropy pool for the virtio-rng device.
+//!
+//! NOTE: this is NOT a cryptographically secure generator. The pool is seeded from the
+//! cycle counter and mixed with interrupt arrival jitter, and the output is produced by
+//! xoshiro256**. It is only meant to keep guests from blocking on entropy at boot, a
+//! platform with a TRNG should feed it through `rng_add_entropy`.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::arch::timer::timer_arch_get_counter;
+
+// jitter collected from interrupt arrivals since the last fill
+static JITTER: AtomicU64 = AtomicU64::new(0);
+static RNG: Mutex<Option<Xoshiro256>> = Mutex::new(None);
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+struct Xoshiro256 {
+    s: [u64; 4],
+}
+
+impl Xoshiro256 {
+    fn new(seed: u64) -> Self {
+        let mut rng = Self { s: [0; 4] };
+        rng.mix(seed);
+        rng
+    }
+
+    fn mix(&mut self, val: u64) {
+        let mut state = val;
+        for s in self.s.iter_mut() {
+            *s ^= splitmix64(&mut state);
+        }
+        // the all-zero state is a fixed point
+        if self.s == [0; 4] {
+            self.s[0] = 1;
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let result = self.s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.s[1] << 17;
+        self.s[2] ^= self.s[0];
+        self.s[3] ^= self.s[1];
+        self.s[1] ^= self.s[2];
+        self.s[0] ^= self.s[3];
+        self.s[2] ^= t;
+        self.s[3] = self.s[3].rotate_left(45);
+        result
+    }
+}
+
+/// Mix the arrival time of an interrupt into the pool, cheap enough for the irq path.
+pub fn rng_add_jitter(int_id: usize) {
+    let counter = timer_arch_get_counter() as u64;
+    JITTER.fetch_xor(counter.rotate_left(int_id as u32 % u64::BITS), Ordering::Relaxed);
+}
+
+/// Mix external entropy (e.g. from a platform TRNG) into the pool.
+#[allow(dead_code)]
+pub fn rng_add_entropy(val: u64) {
+    let mut rng = RNG.lock();
+    match rng.as_mut() {
+        Some(rng) => rng.mix(val),
+        None => *rng = Some(Xoshiro256::new(val ^ timer_arch_get_counter() as u64)),
+    }
+}
+
+pub fn rng_fill(buf: &mut [u8]) {
+    let mut rng = RNG.lock();
+    let rng = rng.get_or_insert_with(|| Xoshiro256::new(timer_arch_get_counter() as u64));
+    rng.mix(JITTER.swap(0, Ordering::Relaxed) ^ timer_arch_get_counter() as u64);
+    for chunk in buf.chunks_mut(8) {
+        let bytes = rng.next_u64().to_ne_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
@@ -7,9 +7,11 @@
  *  We still need some notify mechanism to improve the CPU usage.
  */
 use alloc::collections::BTreeMap;
+use core::mem::size_of;
 
 use spin::Mutex;
 
+use crate::kernel::access::vm_ipa2hva;
 use crate::kernel::HVC_UNILIB;
 use crate::kernel::{active_vm, HVC_UNILIB_FS_INIT, HVC_UNILIB_FS_LSEEK};
 use crate::kernel::{hvc_send_msg_to_vm, HvcGuestMsg, HvcUniLibMsg};
@@ -171,9 +173,9 @@ pub fn unilib_fs_init() -> Result<usize, ()> {
 /// * `mmio_ipa`        - The intermediated physical address of target GVM's `UnilibFS` struct provided ny MVM.
 pub fn unilib_fs_append(mmio_ipa: usize) -> Result<usize, ()> {
     let vm = active_vm().unwrap();
-    let mmio_pa = vm.ipa2hva(mmio_ipa);
+    let mmio_pa = vm_ipa2hva(&vm, mmio_ipa, size_of::<UnilibFSContent>()).map_err(|_| ())?;
     let unilib_fs = UnilibFS { base_addr: mmio_pa };
-    let buf_pa = vm.ipa2hva(unilib_fs.buf_ipa());
+    let buf_pa = vm_ipa2hva(&vm, unilib_fs.buf_ipa(), 1).map_err(|_| ())?;
     println!(
         "unilib_fs_append: VM[{}] fs_mmio_ipa {:#x}, buf ipa {:#x}, buf_pa {:#x}",
         unilib_fs.vm_id(),
@@ -226,7 +228,7 @@ pub fn unilib_fs_open(path_start_ipa: usize, path_length: usize, flags: usize) -
     };
 
     // Copy path to unilib_fs buf, see UnilibFSCfg.
-    let path_pa = active_vm().unwrap().ipa2hva(path_start_ipa);
+    let path_pa = vm_ipa2hva(&vm, path_start_ipa, path_length).map_err(|_| ())?;
     memcpy_safe(fs_cfg.get_buf(), path_pa as *mut u8, path_length);
     // Add end '\0' for path buf.
     unsafe {
@@ -341,7 +343,7 @@ pub fn unilib_fs_read(fd: usize, buf_ipa: usize, len: usize) -> Result<usize, ()
     if res < 0 {
         return Ok(res as usize);
     }
-    let buf_pa = vm.ipa2hva(buf_ipa);
+    let buf_pa = vm_ipa2hva(&vm, buf_ipa, fs_cfg.value()).map_err(|_| ())?;
     memcpy_safe(buf_pa as *mut u8, fs_cfg.get_buf(), fs_cfg.value());
     Ok(fs_cfg.value())
 }
@@ -373,7 +375,7 @@ pub fn unilib_fs_write(fd: usize, buf_ipa: usize, len: usize) -> Result<usize, (
             return Err(());
         }
     };
-    let buf_pa = vm.ipa2hva(buf_ipa);
+    let buf_pa = vm_ipa2hva(&vm, buf_ipa, len).map_err(|_| ())?;
     memcpy_safe(fs_cfg.get_buf(), buf_pa as *mut u8, len);
 
     fs_cfg.prepare_for_request();
@@ -4,9 +4,10 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 use spin::RwLock;
 
-use crate::arch::{LVL1_SHIFT, PAGE_SIZE, PTE_S1_NORMAL};
+use crate::arch::{LVL1_SHIFT, PTE_S1_NORMAL};
 use crate::board::PLAT_DESC;
-use crate::kernel::{current_cpu, ipi_send_msg, IpiInnerMsg, IpiType, IpiVmmPercoreMsg, Vm};
+use crate::config::VmRegion;
+use crate::kernel::{current_cpu, ipi_send_msg_retry, IpiInnerMsg, IpiType, IpiVmmPercoreMsg, Vm};
 use crate::util::barrier;
 
 use super::VmmPercoreEvent;
@@ -14,8 +15,8 @@ use super::VmmPercoreEvent;
 // Here, we regrad IPA as part of HVA (Hypervisor VA)
 // using the higher bits as VMID to distinguish
 
-// convert ipa to pa and mapping the hva(from ipa) on current cpu()
-pub fn vmm_setup_ipa2hva(vm: Arc<Vm>) {
+// convert ipa to pa and mapping the hva(from ipa) of `regions` on every cpu
+pub fn vmm_setup_ipa2hva(vm: Arc<Vm>, regions: &[VmRegion]) -> bool {
     let mut flag = false;
     for target_cpu_id in 0..PLAT_DESC.cpu_desc.num {
         if target_cpu_id != current_cpu().id {
@@ -23,8 +24,12 @@ pub fn vmm_setup_ipa2hva(vm: Arc<Vm>) {
                 vm: vm.clone(),
                 event: VmmPercoreEvent::MapIPA,
             };
-            if !ipi_send_msg(target_cpu_id, IpiType::Vmm, IpiInnerMsg::VmmPercoreMsg(msg)) {
-                error!("vmm_setup_ipa2hva: failed to send ipi to Core {}", target_cpu_id);
+            if let Err(err) = ipi_send_msg_retry(target_cpu_id, IpiType::Vmm, IpiInnerMsg::VmmPercoreMsg(msg)) {
+                error!(
+                    "vmm_setup_ipa2hva: failed to send ipi to Core {}: {:?}",
+                    target_cpu_id, err
+                );
+                return false;
             }
         } else {
             flag = true;
@@ -32,9 +37,10 @@ pub fn vmm_setup_ipa2hva(vm: Arc<Vm>) {
     }
     // execute after notify all other cores
     if flag {
-        vmm_map_ipa_percore(&vm, true);
+        vmm_map_ipa_percore(&vm, regions, true);
     }
     info!("vmm_setup_ipa2hva: VM[{}] is ok", vm.id());
+    true
 }
 
 pub fn vmm_unmap_ipa2hva(vm: Arc<Vm>) {
@@ -46,8 +52,11 @@ pub fn vmm_unmap_ipa2hva(vm: Arc<Vm>) {
                 vm: vm.clone(),
                 event: VmmPercoreEvent::UnmapIPA,
             };
-            if !ipi_send_msg(target_cpu_id, IpiType::Vmm, IpiInnerMsg::VmmPercoreMsg(msg)) {
-                error!("vmm_unmap_ipa2hva: failed to send ipi to Core {}", target_cpu_id);
+            if let Err(err) = ipi_send_msg_retry(target_cpu_id, IpiType::Vmm, IpiInnerMsg::VmmPercoreMsg(msg)) {
+                error!(
+                    "vmm_unmap_ipa2hva: failed to send ipi to Core {}: {:?}",
+                    target_cpu_id, err
+                );
             }
         } else {
             flag = true;
@@ -60,30 +69,32 @@ pub fn vmm_unmap_ipa2hva(vm: Arc<Vm>) {
     info!("vmm_unmap_ipa2hva: VM[{}] is ok", vm.id());
 }
 
-fn vm_flush_ipa(vm: &Vm) {
-    for region in vm.config().memory_region().iter() {
+fn vm_flush_ipa(vm: &Vm, regions: &[VmRegion]) {
+    for region in regions.iter() {
         let hva = vm.ipa2hva(region.ipa_start);
         use crate::arch::{Arch, CacheInvalidate};
         Arch::dcache_clean_flush(hva, region.length);
     }
 }
 
-pub fn vmm_map_ipa_percore(vm: &Vm, is_master: bool) {
+/* The master maps `regions` and shares the level 1 ptes, other cores just copy them.
+ * A region is mapped by runs of contiguous memory, looked up under one lock of the VM, rather than
+ * by a stage-2 walk and a map call per page, which held up the boot of a VM with a large memory.
+ */
+pub fn vmm_map_ipa_percore(vm: &Vm, regions: &[VmRegion], is_master: bool) {
     static SHARED_PTE: RwLock<Vec<(usize, usize)>> = RwLock::new(Vec::new());
     static FINISH: AtomicBool = AtomicBool::new(false);
 
     trace!("vmm_map_ipa_percore: on core {}, for VM[{}]", current_cpu().id, vm.id());
-    let config = vm.config();
     if is_master {
         let mut shared_pte_list = SHARED_PTE.write();
         shared_pte_list.clear();
-        for region in config.memory_region().iter() {
-            for ipa in region.as_range().step_by(PAGE_SIZE) {
-                let hva = vm.ipa2hva(ipa);
-                let pa = vm.ipa2pa(ipa).unwrap();
+        for region in regions.iter() {
+            let runs = vm.backing_runs(region.ipa_start, region.length).unwrap();
+            for (ipa, pa, len) in runs {
                 current_cpu()
                     .pt()
-                    .pt_map_range(hva, PAGE_SIZE, pa, PTE_S1_NORMAL, false);
+                    .pt_map_range(vm.ipa2hva(ipa), len, pa, PTE_S1_NORMAL, false);
             }
 
             for ipa in region.as_range().step_by(1 << LVL1_SHIFT) {
@@ -102,8 +113,9 @@ pub fn vmm_map_ipa_percore(vm: &Vm, is_master: bool) {
         }
     }
     barrier();
-    vm_flush_ipa(vm);
     if is_master {
+        // cache maintenance by VA is broadcast, flushing on the master is enough
+        vm_flush_ipa(vm, regions);
         FINISH.store(false, Ordering::Relaxed);
     }
 }
@@ -114,10 +126,9 @@ pub fn vmm_unmap_ipa_percore(vm: &Vm) {
         current_cpu().id,
         vm.id()
     );
-    let config = vm.config();
-    for region in config.memory_region().iter() {
+    for region in vm.memory_regions().iter() {
         let hva = vm.ipa2hva(region.ipa_start);
-        current_cpu().pt().pt_unmap_range(hva, region.length, false);
+        current_cpu().pt().pt_unmap_range(hva, region.length);
     }
     barrier();
 }
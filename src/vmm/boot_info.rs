@@ -0,0 +1,111 @@
+use core::mem::size_of;
+
+use crate::kernel::access::copy_to_vm;
+use crate::kernel::Vm;
+
+const BMA_BOOT_MAGIC: u32 = u32::from_le_bytes(*b"SHYB");
+const BMA_BOOT_VERSION: u32 = 1;
+const BMA_BOOT_REGION_MAX: usize = 16;
+const BMA_BOOT_DEV_MAX: usize = 32;
+const BMA_BOOT_CMDLINE_MAX: usize = 256;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct BmaBootRegion {
+    ipa_start: u64,
+    length: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct BmaBootDev {
+    emu_type: u32,
+    irq: u32,
+    base_ipa: u64,
+    length: u64,
+}
+
+/* The boot info a bma guest finds at x0 when it enters, instead of the device tree of an os guest.
+ * BMA projects keep a copy of this layout, bump BMA_BOOT_VERSION when it changes.
+ * NOTE: it belongs to the shyper crate together with the hvc definitions, it is kept here
+ * until the crate carries it.
+ */
+#[repr(C)]
+struct BmaBootInfo {
+    magic: u32,
+    version: u32,
+    // size of this struct, a guest built against an older version reads only what it knows
+    size: u32,
+    cpu_num: u32,
+    region_num: u32,
+    dev_num: u32,
+    // without the terminating NUL
+    cmdline_len: u32,
+    _reserved: u32,
+    regions: [BmaBootRegion; BMA_BOOT_REGION_MAX],
+    devs: [BmaBootDev; BMA_BOOT_DEV_MAX],
+    cmdline: [u8; BMA_BOOT_CMDLINE_MAX],
+}
+
+/* Write the boot info of a bma guest at its boot_info_load_ipa, it is passed in x0 by `init_boot_info`. */
+pub(super) fn vmm_init_boot_info(vm: &Vm) -> bool {
+    let config = vm.config();
+    let ipa = config.boot_info_load_ipa();
+    if ipa == 0 {
+        warn!("VM[{}] is a bma guest without boot info load ipa", vm.id());
+        return true;
+    }
+    let mut info = BmaBootInfo {
+        magic: BMA_BOOT_MAGIC,
+        version: BMA_BOOT_VERSION,
+        size: size_of::<BmaBootInfo>() as u32,
+        cpu_num: config.cpu_num() as u32,
+        region_num: 0,
+        dev_num: 0,
+        cmdline_len: 0,
+        _reserved: 0,
+        regions: [BmaBootRegion::default(); BMA_BOOT_REGION_MAX],
+        devs: [BmaBootDev::default(); BMA_BOOT_DEV_MAX],
+        cmdline: [0; BMA_BOOT_CMDLINE_MAX],
+    };
+    if config.memory_region().len() > BMA_BOOT_REGION_MAX || config.emulated_device_list().len() > BMA_BOOT_DEV_MAX {
+        warn!(
+            "VM[{}] boot info: too many memory regions or devices, the rest is dropped",
+            vm.id()
+        );
+    }
+    for (boot, region) in info.regions.iter_mut().zip(config.memory_region().iter()) {
+        *boot = BmaBootRegion {
+            ipa_start: region.ipa_start as u64,
+            length: region.length as u64,
+        };
+        info.region_num += 1;
+    }
+    for (boot, emu_cfg) in info.devs.iter_mut().zip(config.emulated_device_list().iter()) {
+        *boot = BmaBootDev {
+            emu_type: emu_cfg.emu_type as u32,
+            irq: emu_cfg.irq_id as u32,
+            base_ipa: emu_cfg.base_ipa as u64,
+            length: emu_cfg.length as u64,
+        };
+        info.dev_num += 1;
+    }
+    let cmdline = config.cmdline.as_bytes();
+    if cmdline.len() >= BMA_BOOT_CMDLINE_MAX {
+        warn!(
+            "VM[{}] boot info: cmdline is truncated to {} bytes",
+            vm.id(),
+            BMA_BOOT_CMDLINE_MAX - 1
+        );
+    }
+    let len = cmdline.len().min(BMA_BOOT_CMDLINE_MAX - 1);
+    info.cmdline[..len].copy_from_slice(&cmdline[..len]);
+    info.cmdline_len = len as u32;
+
+    if copy_to_vm(vm, ipa as *mut u8, &info).is_err() {
+        error!("vmm_init_boot_info: VM[{}] illegal boot info ipa {:#x}", vm.id(), ipa);
+        return false;
+    }
+    info!("VM[{}] boot info at ipa {:#x}", vm.id(), ipa);
+    true
+}
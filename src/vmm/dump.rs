@@ -0,0 +1,358 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::arch::timer::gettime_ns;
+use crate::arch::{ContextFrame, VmContext};
+use crate::device::{mediated_blk_acquire, mediated_blk_free, mediated_blk_list_get, mediated_blk_write, SECTOR_BSIZE};
+use crate::kernel::{
+    async_blk_io_req, current_cpu, hvc_send_msg_to_vm, ipi_send_msg_retry, vm_by_id, AsyncCallback, AsyncTask,
+    HvcGuestMsg, HvcManageMsg, IpiInnerMsg, IpiType, IpiVmmPercoreMsg, Vm, EXECUTOR, HVC_VMM, HVC_VMM_DUMP_VM,
+};
+use crate::util::{bit_extract, memcpy_safe, round_up};
+
+use super::VmmPercoreEvent;
+
+const VM_DUMP_MAGIC: u64 = u64::from_le_bytes(*b"SHYPDUMP");
+const VM_DUMP_VERSION: u32 = 1;
+const VM_DUMP_NAME_LEN: usize = 32;
+
+/* Layout of a dump on the block, starting from sector 0:
+ * VmDumpHeader, VmDumpRegion * region_num, VmDumpVcpu * cpu_num,
+ * then the memory regions in order, the first one starts at `mem_offset`.
+ */
+#[repr(C)]
+struct VmDumpHeader {
+    magic: u64,
+    version: u32,
+    vm_id: u32,
+    vm_type: u32,
+    cpu_num: u32,
+    region_num: u32,
+    vcpu_size: u32,
+    mem_offset: u64,
+    mem_size: u64,
+    name: [u8; VM_DUMP_NAME_LEN],
+}
+
+#[repr(C)]
+struct VmDumpRegion {
+    ipa_start: u64,
+    length: u64,
+}
+
+#[repr(C)]
+struct VmDumpVcpu {
+    vcpu_id: u64,
+    phys_id: u64,
+    state: u64,
+    ctx: ContextFrame,
+    vm_ctx: VmContext,
+}
+
+// only one dump is in flight, its block is owned until the last chunk is written
+static DUMP_BUSY: AtomicBool = AtomicBool::new(false);
+// the cores that acked the pause of the VM being dumped, and the vcpus the pause took
+static DUMP_PAUSED_CORES: AtomicUsize = AtomicUsize::new(0);
+static DUMP_PAUSED_VCPUS: AtomicUsize = AtomicUsize::new(0);
+// how long a pause waits for the cores of the VM
+const DUMP_PAUSE_TIMEOUT_US: usize = 100_000;
+
+fn push_bytes<T>(buf: &mut Vec<u8>, val: &T) {
+    let bytes = unsafe { core::slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) };
+    buf.extend_from_slice(bytes);
+}
+
+// size of everything before the memory, padded to a whole sector
+fn vm_dump_meta_size(vm: &Vm, region_num: usize) -> usize {
+    let size =
+        size_of::<VmDumpHeader>() + region_num * size_of::<VmDumpRegion>() + vm.cpu_num() * size_of::<VmDumpVcpu>();
+    round_up(size, SECTOR_BSIZE)
+}
+
+fn vm_dump_header(vm: &Vm) -> Vec<u8> {
+    let config = vm.config();
+    let regions = vm.memory_regions();
+    let mut name = [0; VM_DUMP_NAME_LEN];
+    let len = config.name.len().min(VM_DUMP_NAME_LEN - 1);
+    name[..len].copy_from_slice(&config.name.as_bytes()[..len]);
+    let header = VmDumpHeader {
+        magic: VM_DUMP_MAGIC,
+        version: VM_DUMP_VERSION,
+        vm_id: vm.id() as u32,
+        vm_type: vm.vm_type() as u32,
+        cpu_num: vm.cpu_num() as u32,
+        region_num: regions.len() as u32,
+        vcpu_size: size_of::<VmDumpVcpu>() as u32,
+        mem_offset: vm_dump_meta_size(vm, regions.len()) as u64,
+        mem_size: regions.iter().map(|region| region.length).sum::<usize>() as u64,
+        name,
+    };
+
+    let mut buf = Vec::with_capacity(header.mem_offset as usize);
+    push_bytes(&mut buf, &header);
+    for region in regions.iter() {
+        let region = VmDumpRegion {
+            ipa_start: region.ipa_start as u64,
+            length: region.length as u64,
+        };
+        push_bytes(&mut buf, &region);
+    }
+    for vcpu in vm.vcpu_list() {
+        let (ctx, vm_ctx) = vcpu.context_snapshot();
+        let vcpu = VmDumpVcpu {
+            vcpu_id: vcpu.id() as u64,
+            phys_id: vcpu.phys_id() as u64,
+            state: vcpu.state() as u64,
+            ctx,
+            vm_ctx,
+        };
+        push_bytes(&mut buf, &vcpu);
+    }
+    buf.resize(header.mem_offset as usize, 0);
+    buf
+}
+
+// where the data of a chunk comes from
+enum VmDumpSrc {
+    Header(Arc<Vec<u8>>, usize),
+    Memory(usize),
+}
+
+struct VmDumpChunk {
+    vm: Arc<Vm>,
+    blk_id: usize,
+    cache: usize,
+    src: VmDumpSrc,
+    sector: usize,
+    len: usize,
+    last: bool,
+}
+
+impl AsyncCallback for VmDumpChunk {
+    fn preprocess(&self) {
+        let src = match &self.src {
+            VmDumpSrc::Header(buf, offset) => buf[*offset..].as_ptr(),
+            VmDumpSrc::Memory(ipa) => self.vm.ipa2hva(*ipa) as *const u8,
+        };
+        memcpy_safe(self.cache as *mut u8, src, self.len);
+        mediated_blk_write(self.blk_id, self.sector, self.len / SECTOR_BSIZE);
+    }
+
+    fn finish(&self) {
+        if self.last {
+            vm_dump_finish(&self.vm, self.blk_id);
+        }
+    }
+
+    // the chunks of a VM are cancelled in order, the blk is released with the last one
+    fn cancel(&self, _started: bool, complete: bool) {
+        if !self.last {
+            return;
+        }
+        if complete {
+            vm_dump_finish(&self.vm, self.blk_id);
+        } else {
+            // the VM is being removed
+            mediated_blk_free(self.blk_id);
+            DUMP_BUSY.store(false, Ordering::Release);
+        }
+        warn!("vm_dump: VM[{}] dump to blk {} is cancelled", self.vm.id(), self.blk_id);
+    }
+}
+
+fn vm_dump_finish(vm: &Arc<Vm>, blk_id: usize) {
+    vmm_vcpu_resume(vm);
+    mediated_blk_free(blk_id);
+    DUMP_BUSY.store(false, Ordering::Release);
+    info!("vm_dump: VM[{}] dump to blk {} finished", vm.id(), blk_id);
+
+    let msg = HvcManageMsg {
+        fid: HVC_VMM,
+        event: HVC_VMM_DUMP_VM,
+        vm_id: vm.id(),
+    };
+    if !hvc_send_msg_to_vm(0, &HvcGuestMsg::Manage(msg)) {
+        error!("vm_dump_finish: failed to notify VM 0");
+    }
+}
+
+/* Pause all vcpus of `vm` on their cores, returns after every core has acked it. If a core can not
+ * be reached or does not ack in time, the vcpus paused so far are resumed and false is returned.
+ */
+fn vmm_vcpu_pause(vm: &Arc<Vm>) -> bool {
+    DUMP_PAUSED_CORES.store(0, Ordering::Release);
+    DUMP_PAUSED_VCPUS.store(0, Ordering::Release);
+    let cores = vm.ncpu();
+    let mut sent = true;
+    for phys_id in vm.pcpu_list().filter(|&phys_id| phys_id != current_cpu().id) {
+        let msg = IpiVmmPercoreMsg {
+            vm: vm.clone(),
+            event: VmmPercoreEvent::PauseVcpu,
+        };
+        if let Err(err) = ipi_send_msg_retry(phys_id, IpiType::Vmm, IpiInnerMsg::VmmPercoreMsg(msg)) {
+            error!("vmm_vcpu_pause: failed to send ipi to Core {}: {:?}", phys_id, err);
+            sent = false;
+        }
+    }
+    if cores & (1 << current_cpu().id) != 0 {
+        vmm_vcpu_pause_percore(vm, true);
+    }
+    let begin = gettime_ns();
+    let mut acked = DUMP_PAUSED_CORES.load(Ordering::Acquire);
+    while sent && acked != cores {
+        if gettime_ns() - begin > DUMP_PAUSE_TIMEOUT_US * 1000 {
+            error!(
+                "vmm_vcpu_pause: VM[{}] cores {:#x} of {:#x} acked in {}us",
+                vm.id(),
+                acked,
+                cores,
+                DUMP_PAUSE_TIMEOUT_US
+            );
+            sent = false;
+            break;
+        }
+        core::hint::spin_loop();
+        acked = DUMP_PAUSED_CORES.load(Ordering::Acquire);
+    }
+    if !sent {
+        // a core acking late is resumed too, its ipis are handled in order
+        vmm_vcpu_resume(vm);
+    }
+    sent
+}
+
+// resume the vcpus of `vm` the last pause took, without waiting for the cores as the executor is busy
+fn vmm_vcpu_resume(vm: &Arc<Vm>) {
+    for phys_id in vm.pcpu_list().filter(|&phys_id| phys_id != current_cpu().id) {
+        let msg = IpiVmmPercoreMsg {
+            vm: vm.clone(),
+            event: VmmPercoreEvent::ResumeVcpu,
+        };
+        if let Err(err) = ipi_send_msg_retry(phys_id, IpiType::Vmm, IpiInnerMsg::VmmPercoreMsg(msg)) {
+            error!("vmm_vcpu_resume: failed to send ipi to Core {}: {:?}", phys_id, err);
+        }
+    }
+    if vm.ncpu() & (1 << current_cpu().id) != 0 {
+        vmm_vcpu_pause_percore(vm, false);
+    }
+}
+
+/* Pause the vcpus of `vm` on this core, or resume the ones the pause took. A vcpu blocked for
+ * another reason is left alone, so that resuming does not wake it up.
+ */
+pub fn vmm_vcpu_pause_percore(vm: &Vm, pause: bool) {
+    for vcpu in vm.vcpu_list().iter().filter(|vcpu| vcpu.phys_id() == current_cpu().id) {
+        let bit = 1 << vcpu.id();
+        if pause {
+            if current_cpu().vcpu_array.pause_vcpu(vm.id(), vcpu.id()) {
+                DUMP_PAUSED_VCPUS.fetch_or(bit, Ordering::AcqRel);
+            }
+        } else if DUMP_PAUSED_VCPUS.fetch_and(!bit, Ordering::AcqRel) & bit != 0 {
+            current_cpu().vcpu_array.unpause_vcpu(vm.id(), vcpu.id());
+        }
+    }
+    if pause {
+        DUMP_PAUSED_CORES.fetch_or(1 << current_cpu().id, Ordering::AcqRel);
+    }
+}
+
+/**
+ * Dump the vcpu registers and memory of a VM to a mediated blk, the VM continues after the dump.
+ * VM 0 is notified with HVC_VMM_DUMP_VM when all the data is written.
+ *
+ * @param arg blk_id ~ (31, 16) ~ [mediated blk to write the dump]
+ *            vmid ~ (15, 0) ~ [target vm id]
+ * @param blk_sectors : capacity of the blk in sectors.
+ */
+pub fn vmm_dump_vm(arg: usize, blk_sectors: usize) -> Result<usize, ()> {
+    let vm_id = bit_extract(arg, 0, 16);
+    let blk_id = bit_extract(arg, 16, 16);
+    let vm = match vm_by_id(vm_id) {
+        Some(vm) if vm_id != 0 => vm,
+        _ => {
+            error!("vmm_dump_vm: VM[{}] can not be dumped", vm_id);
+            return Err(());
+        }
+    };
+
+    let regions = vm.memory_regions();
+    let mem_size = regions.iter().map(|region| region.length).sum::<usize>();
+    let dump_size = vm_dump_meta_size(&vm, regions.len()) + mem_size;
+    if dump_size > blk_sectors * SECTOR_BSIZE {
+        error!(
+            "vmm_dump_vm: VM[{}] needs {:#x} bytes, but blk {} only has {:#x}",
+            vm_id,
+            dump_size,
+            blk_id,
+            blk_sectors * SECTOR_BSIZE
+        );
+        return Err(());
+    }
+    if DUMP_BUSY
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        error!("vmm_dump_vm: another dump is in progress");
+        return Err(());
+    }
+    if !mediated_blk_acquire(blk_id) {
+        error!("vmm_dump_vm: blk {} is not available", blk_id);
+        DUMP_BUSY.store(false, Ordering::Release);
+        return Err(());
+    }
+    let blk = mediated_blk_list_get(blk_id);
+    let chunk_size = blk.dma_block_max() * SECTOR_BSIZE;
+    if chunk_size == 0 {
+        error!("vmm_dump_vm: blk {} has no dma buffer", blk_id);
+        mediated_blk_free(blk_id);
+        DUMP_BUSY.store(false, Ordering::Release);
+        return Err(());
+    }
+
+    if !vmm_vcpu_pause(&vm) {
+        error!("vmm_dump_vm: VM[{}] can not be paused", vm_id);
+        mediated_blk_free(blk_id);
+        DUMP_BUSY.store(false, Ordering::Release);
+        return Err(());
+    }
+    info!(
+        "vmm_dump_vm: dump VM[{}] ({:#x} bytes) to blk {}",
+        vm_id, dump_size, blk_id
+    );
+
+    let header = Arc::new(vm_dump_header(&vm));
+    let mut chunks = Vec::new();
+    for offset in (0..header.len()).step_by(chunk_size) {
+        chunks.push((
+            VmDumpSrc::Header(header.clone(), offset),
+            (header.len() - offset).min(chunk_size),
+        ));
+    }
+    for region in regions.iter() {
+        for ipa in region.as_range().step_by(chunk_size) {
+            chunks.push((
+                VmDumpSrc::Memory(ipa),
+                (region.ipa_start + region.length - ipa).min(chunk_size),
+            ));
+        }
+    }
+    let chunk_num = chunks.len();
+    let mut sector = 0;
+    for (i, (src, len)) in chunks.into_iter().enumerate() {
+        let chunk = VmDumpChunk {
+            vm: vm.clone(),
+            blk_id,
+            cache: blk.cache_pa(),
+            src,
+            sector,
+            len,
+            last: i + 1 == chunk_num,
+        };
+        sector += len / SECTOR_BSIZE;
+        EXECUTOR.add_task(AsyncTask::new(chunk, vm_id, blk_id, async_blk_io_req(blk_id)), false);
+    }
+    EXECUTOR.exec();
+    Ok(0)
+}
@@ -0,0 +1,154 @@
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::arch::{Arch, CacheInvalidate};
+use crate::kernel::access::{copy_segment_to_vm, vm_ipa2hva};
+use crate::kernel::Vm;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LSB: u8 = 1;
+const ELF_TYPE_EXEC: u16 = 2;
+const ELF_MACHINE_AARCH64: u16 = 183;
+const ELF_HEADER_SIZE: usize = 64;
+const ELF_PHDR_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+
+// a PT_LOAD segment, `file` in the image goes to the start of `ipa`, the rest of it is zeroed
+struct ElfSegment {
+    file: Range<usize>,
+    ipa: Range<usize>,
+}
+
+fn read_u16(bin: &[u8], offset: usize) -> usize {
+    u16::from_le_bytes([bin[offset], bin[offset + 1]]) as usize
+}
+
+fn read_u32(bin: &[u8], offset: usize) -> usize {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&bin[offset..offset + 4]);
+    u32::from_le_bytes(bytes) as usize
+}
+
+fn read_u64(bin: &[u8], offset: usize) -> usize {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&bin[offset..offset + 8]);
+    u64::from_le_bytes(bytes) as usize
+}
+
+pub(super) fn is_elf(bin: &[u8]) -> bool {
+    bin.starts_with(ELF_MAGIC)
+}
+
+/* The PT_LOAD segments and the entry of an aarch64 executable, the segments are sorted by ipa.
+ * `ipa_valid(ipa, len)` tells if a segment fits the guest memory. Nothing is written to the VM
+ * here, so a malformed image leaves its memory as it is.
+ */
+fn elf_parse(bin: &[u8], ipa_valid: impl Fn(usize, usize) -> bool) -> Result<(Vec<ElfSegment>, usize), &'static str> {
+    if bin.len() < ELF_HEADER_SIZE || !is_elf(bin) {
+        return Err("not an elf image");
+    }
+    if bin[4] != ELF_CLASS_64 || bin[5] != ELF_DATA_LSB {
+        return Err("not a 64-bit little endian elf");
+    }
+    if read_u16(bin, 16) != ELF_TYPE_EXEC as usize {
+        return Err("not an executable");
+    }
+    if read_u16(bin, 18) != ELF_MACHINE_AARCH64 as usize {
+        return Err("wrong machine type");
+    }
+    let entry = read_u64(bin, 24);
+    let phoff = read_u64(bin, 32);
+    let phentsize = read_u16(bin, 54);
+    let phnum = read_u16(bin, 56);
+    if phentsize < ELF_PHDR_SIZE {
+        return Err("program header too small");
+    }
+    match phentsize.checked_mul(phnum).and_then(|size| phoff.checked_add(size)) {
+        Some(end) if end <= bin.len() => {}
+        _ => return Err("program headers out of the image"),
+    }
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let phdr = &bin[phoff + i * phentsize..];
+        if read_u32(phdr, 0) != PT_LOAD as usize {
+            continue;
+        }
+        let offset = read_u64(phdr, 8);
+        let paddr = read_u64(phdr, 24);
+        let filesz = read_u64(phdr, 32);
+        let memsz = read_u64(phdr, 40);
+        if memsz == 0 {
+            continue;
+        }
+        if filesz > memsz {
+            return Err("segment file size larger than its memory size");
+        }
+        let file = match offset.checked_add(filesz) {
+            Some(end) if end <= bin.len() => offset..end,
+            _ => return Err("segment out of the image"),
+        };
+        if !ipa_valid(paddr, memsz) {
+            return Err("segment out of the guest memory");
+        }
+        segments.push(ElfSegment {
+            file,
+            ipa: paddr..paddr + memsz,
+        });
+    }
+    if segments.is_empty() {
+        return Err("no loadable segment");
+    }
+    segments.sort_unstable_by_key(|segment| segment.ipa.start);
+    if segments.windows(2).any(|pair| pair[0].ipa.end > pair[1].ipa.start) {
+        return Err("overlapping segments");
+    }
+    if !segments.iter().any(|segment| segment.ipa.contains(&entry)) {
+        return Err("entry out of the loaded segments");
+    }
+    Ok((segments, entry))
+}
+
+// parse an elf image without a VM, the entry if it is accepted
+#[cfg(feature = "self-test")]
+pub fn elf_parse_synthetic(bin: &[u8], ipa_valid: impl Fn(usize, usize) -> bool) -> Result<usize, &'static str> {
+    elf_parse(bin, ipa_valid).map(|(_, entry)| entry)
+}
+
+/* Load the PT_LOAD segments of the elf `bin` to their physical addresses in `vm`, and start its
+ * vcpu 0 at the elf entry instead of kernel_entry_point.
+ */
+pub(super) fn vmm_load_elf(vm: &Vm, bin: &[u8]) -> Result<usize, ()> {
+    let (segments, entry) = match elf_parse(bin, |ipa, len| vm.ipa_range_valid(ipa, len)) {
+        Ok(elf) => elf,
+        Err(reason) => {
+            error!("vmm_load_elf: VM[{}] malformed elf: {}", vm.id(), reason);
+            return Err(());
+        }
+    };
+    for segment in segments.iter() {
+        if !segment.file.is_empty() {
+            copy_segment_to_vm(vm, segment.ipa.start, &bin[segment.file.clone()]).map_err(|_| ())?;
+        }
+        let bss = segment.ipa.start + segment.file.len();
+        let bss_len = segment.ipa.end - bss;
+        if bss_len != 0 {
+            let hva = vm_ipa2hva(vm, bss, bss_len).map_err(|_| ())?;
+            unsafe { core::slice::from_raw_parts_mut(hva as *mut u8, bss_len) }.fill(0);
+            Arch::dcache_clean_flush(hva, bss_len);
+        }
+        debug!(
+            "VM[{}] elf segment {:#x?} loaded from offset {:#x}",
+            vm.id(),
+            segment.ipa,
+            segment.file.start
+        );
+    }
+    vm.set_entry_point(Some(entry));
+    if let Some(vcpu) = vm.vcpu(0) {
+        vcpu.init_boot_info(vm);
+    }
+    info!("VM[{}] elf image loaded, entry {:#x}", vm.id(), entry);
+    Ok(entry)
+}
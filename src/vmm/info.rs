@@ -0,0 +1,137 @@
+use core::mem::size_of;
+
+use crate::arch::{Arch, CacheInvalidate, PAGE_SIZE, PTE_S2_RO};
+use crate::device::EmuDeviceType;
+use crate::kernel::{mem_page_alloc, Vm};
+use crate::mm::PageUsage;
+
+const VM_INFO_MAGIC: u32 = u32::from_le_bytes(*b"SHYI");
+const VM_INFO_VERSION: u32 = 1;
+const VM_INFO_STR_LEN: usize = 32;
+const VM_INFO_REGION_MAX: usize = 16;
+const VM_INFO_DEV_MAX: usize = 32;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VmInfoRegion {
+    ipa_start: u64,
+    length: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VmInfoDev {
+    emu_type: u32,
+    irq: u32,
+    base_ipa: u64,
+    length: u64,
+}
+
+/* The read-only page a guest finds at the ipa of its EmuDeviceTInfoPage,
+ * bump VM_INFO_VERSION when the layout changes.
+ */
+#[repr(C)]
+struct VmInfoPage {
+    magic: u32,
+    version: u32,
+    hv_version: [u8; VM_INFO_STR_LEN],
+    build_time: [u8; VM_INFO_STR_LEN],
+    vm_id: u32,
+    cpu_num: u32,
+    // irq injected for hvc messages, 0 if the VM has no shyper device
+    hvc_irq: u32,
+    region_num: u32,
+    dev_num: u32,
+    _reserved: u32,
+    regions: [VmInfoRegion; VM_INFO_REGION_MAX],
+    devs: [VmInfoDev; VM_INFO_DEV_MAX],
+}
+
+const _: () = assert!(size_of::<VmInfoPage>() <= PAGE_SIZE);
+
+fn info_str(s: &str) -> [u8; VM_INFO_STR_LEN] {
+    let mut buf = [0; VM_INFO_STR_LEN];
+    let len = s.len().min(VM_INFO_STR_LEN - 1);
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+    buf
+}
+
+/* Allocate the info page of a VM and map it read-only at the ipa of its EmuDeviceTInfoPage,
+ * so that guest reads never trap.
+ */
+pub(super) fn vmm_init_info_page(vm: &Vm) -> bool {
+    let emu_cfg = match vm
+        .config()
+        .emulated_device_list()
+        .iter()
+        .find(|emu_cfg| emu_cfg.emu_type == EmuDeviceType::EmuDeviceTInfoPage)
+    {
+        Some(emu_cfg) => emu_cfg,
+        None => return true,
+    };
+    if emu_cfg.base_ipa == 0 || emu_cfg.base_ipa % PAGE_SIZE != 0 {
+        error!(
+            "vmm_init_info_page: VM[{}] illegal info page ipa {:#x}",
+            vm.id(),
+            emu_cfg.base_ipa
+        );
+        return false;
+    }
+    let frame = match mem_page_alloc(PageUsage::VmInfo) {
+        Ok(frame) => frame,
+        Err(_) => {
+            error!("vmm_init_info_page: VM[{}] alloc page failed", vm.id());
+            return false;
+        }
+    };
+    vm.pt_map_range(emu_cfg.base_ipa, PAGE_SIZE, frame.pa, PTE_S2_RO, false);
+    vm.set_info_page(frame);
+    vmm_update_info_page(vm);
+    info!("VM[{}] info page at ipa {:#x}", vm.id(), emu_cfg.base_ipa);
+    true
+}
+
+// refill the info page, e.g. after the memory regions change
+pub fn vmm_update_info_page(vm: &Vm) {
+    let hva = match vm.info_page() {
+        Some(hva) => hva,
+        None => return,
+    };
+    let config = vm.config();
+    let page = unsafe { &mut *(hva as *mut VmInfoPage) };
+    *page = VmInfoPage {
+        magic: VM_INFO_MAGIC,
+        version: VM_INFO_VERSION,
+        hv_version: info_str(env!("CARGO_PKG_VERSION")),
+        build_time: info_str(env!("BUILD_TIME")),
+        vm_id: vm.id() as u32,
+        cpu_num: vm.cpu_num() as u32,
+        hvc_irq: config
+            .emulated_device_list()
+            .iter()
+            .find(|emu_cfg| emu_cfg.emu_type == EmuDeviceType::EmuDeviceTShyper)
+            .map_or(0, |emu_cfg| emu_cfg.irq_id as u32),
+        region_num: 0,
+        dev_num: 0,
+        _reserved: 0,
+        regions: [VmInfoRegion::default(); VM_INFO_REGION_MAX],
+        devs: [VmInfoDev::default(); VM_INFO_DEV_MAX],
+    };
+    for (info, region) in page.regions.iter_mut().zip(vm.memory_regions().iter()) {
+        *info = VmInfoRegion {
+            ipa_start: region.ipa_start as u64,
+            length: region.length as u64,
+        };
+        page.region_num += 1;
+    }
+    for (info, emu_cfg) in page.devs.iter_mut().zip(config.emulated_device_list().iter()) {
+        *info = VmInfoDev {
+            emu_type: emu_cfg.emu_type as u32,
+            irq: emu_cfg.irq_id as u32,
+            base_ipa: emu_cfg.base_ipa as u64,
+            length: emu_cfg.length as u64,
+        };
+        page.dev_num += 1;
+    }
+    Arch::dcache_clean_flush(hva, size_of::<VmInfoPage>());
+}
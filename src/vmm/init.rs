@@ -1,41 +1,102 @@
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use crate::arch::PAGE_SIZE;
 use crate::arch::{PTE_S2_DEVICE, PTE_S2_NORMAL};
 use crate::config::VmRegion;
 use crate::device::EmuDeviceType::*;
-use crate::dtb::{create_fdt, setup_fdt_vm0};
-use crate::kernel::access::copy_segment_to_vm;
+use crate::dtb::{create_fdt, fdt_check, setup_fdt_vm0};
+use crate::kernel::access::{copy_segment_from_vm, copy_segment_to_vm};
 use crate::kernel::interrupt_vm_register;
 use crate::kernel::{
-    count_missing_num, current_cpu, iommmu_vm_init, iommu_add_device, ipi_send_msg, mem_region_alloc_colors,
-    ColorMemRegion, IpiInnerMsg, IpiType, IpiVmmPercoreMsg, Vm,
+    count_missing_num, current_cpu, iommmu_vm_init, iommu_add_device, iommu_claim_streams, ipi_send_msg_retry,
+    mem_affinity_of_cpus, mem_color_check_share, mem_region_alloc_colors_near, pvclock_init, spin_table_init,
+    vm_if_upload, vm_if_upload_loaded, AllocError, ColorLayout, ColorMemRegion, IpiInnerMsg, IpiType, IpiVmmPercoreMsg,
+    LazyRegion, Vm, VmType,
 };
 use crate::vmm::address::vmm_setup_ipa2hva;
+use crate::vmm::boot_info::vmm_init_boot_info;
+use crate::vmm::elf::{is_elf, vmm_load_elf};
+use crate::vmm::info::{vmm_init_info_page, vmm_update_info_page};
 use crate::vmm::VmmPercoreEvent;
 
-cfg_if::cfg_if! {
-    if #[cfg(feature = "ramdisk")] {
-        pub static CPIO_RAMDISK: & [u8] = include_bytes!("../../image/net_rootfs.cpio");
-    } else {
-        pub static CPIO_RAMDISK: &[u8] = &[];
-    }
-}
+#[cfg(feature = "ramdisk")]
+pub static CPIO_RAMDISK: &[u8] = include_bytes!(env!("RAMDISK_PATH"));
 
-fn vm_map_ipa2color_regions(vm: &Vm, vm_region: &VmRegion, color_regions: &[ColorMemRegion]) {
+fn vm_map_ipa2color_regions(vm: &Vm, ipa_start: usize, color_regions: &[ColorMemRegion]) {
     // NOTE: continuous ipa should across colors, and the color_regions must be sorted by count
+    if color_regions.is_empty() {
+        return;
+    }
     let missing_list = count_missing_num(color_regions);
     for (i, region) in color_regions.iter().enumerate() {
         for j in 0..region.count {
             let missing_num = missing_list.get(j).unwrap();
             let page_idx = i + j * color_regions.len() - missing_num;
-            let ipa = vm_region.ipa_start + page_idx * PAGE_SIZE;
+            let ipa = ipa_start + page_idx * PAGE_SIZE;
             let pa = region.base + j * region.step;
             vm.pt_map_range(ipa, PAGE_SIZE, pa, PTE_S2_NORMAL, false);
         }
     }
 }
 
+// the windows of the images loaded at boot, mapped in stage-2 before the VM runs even if it maps lazily
+const LAZY_EAGER_IMAGE_SIZE: usize = 32 * 1024 * 1024;
+const LAZY_EAGER_DTB_SIZE: usize = 2 * 1024 * 1024;
+
+/* Map the memory of the VM in stage-2 on the first access to each 2MB block, unless it asks for
+ * `eager-map`. VM0 and a VM with devices behind the SMMU, which share the stage-2 table for dma
+ * that never faults, are always mapped at once.
+ */
+fn vmm_lazy_map(vm: &Vm) -> bool {
+    let config = vm.config();
+    !config.memory.eager_map && vm.id() != 0 && config.passthrough_device_stread_ids().is_empty()
+}
+
+// allocate the memory of `vm_region` close to the cpus of the VM, and map it now or on demand
+fn vmm_alloc_region(vm: &Vm, vm_region: &VmRegion) -> Result<(), AllocError> {
+    let config = vm.config();
+    let affinity = mem_affinity_of_cpus(config.cpu_allocated_bitmap());
+    match mem_region_alloc_colors_near(Some(vm.id()), vm_region.length, config.memory_color_bitmap(), affinity) {
+        Ok((local_regions, remote_regions)) if vmm_lazy_map(vm) => {
+            debug!("{:x?} {:x?}", local_regions, remote_regions);
+            vm.append_color_regions(local_regions.clone());
+            vm.append_color_regions(remote_regions.clone());
+            vm.add_lazy_region(LazyRegion::new(
+                vm_region.clone(),
+                ColorLayout::new(local_regions),
+                ColorLayout::new(remote_regions),
+            ));
+            debug!(
+                "VM {} region {:#x} size {:#x} mapped on demand",
+                vm.id(),
+                vm_region.ipa_start,
+                vm_region.length
+            );
+            Ok(())
+        }
+        Ok((local_regions, remote_regions)) => {
+            debug!("{:x?} {:x?}", local_regions, remote_regions);
+            // the remote memory follows the close one in the ipa space
+            let local_size = local_regions.iter().map(|region| region.count).sum::<usize>() * PAGE_SIZE;
+            vm_map_ipa2color_regions(vm, vm_region.ipa_start, &local_regions);
+            vm_map_ipa2color_regions(vm, vm_region.ipa_start + local_size, &remote_regions);
+            // the colors mapped page by page may still give whole 2MB frames, e.g. with all colors
+            let blocks = vm.pt_collapse_range(vm_region.ipa_start, vm_region.length);
+            debug!(
+                "VM {} region {:#x} collapsed {} 2MB blocks",
+                vm.id(),
+                vm_region.ipa_start,
+                blocks
+            );
+            vm.append_color_regions(local_regions);
+            vm.append_color_regions(remote_regions);
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
+
 fn vmm_init_memory(vm: Arc<Vm>) -> bool {
     let config = vm.config();
     // passthrough regions
@@ -56,31 +117,106 @@ fn vmm_init_memory(vm: Arc<Vm>) -> bool {
     }
     // normal memory regions
     let vm_memory_regions = config.memory_region();
+    vm.set_color_affinity(mem_affinity_of_cpus(config.cpu_allocated_bitmap()));
     for vm_region in vm_memory_regions.iter() {
-        match mem_region_alloc_colors(vm_region.length, config.memory_color_bitmap()) {
-            Ok(vm_color_regions) => {
-                assert!(!vm_color_regions.is_empty());
-                debug!("{:x?}", vm_color_regions);
-                vm_map_ipa2color_regions(&vm, vm_region, &vm_color_regions);
-                vm.append_color_regions(vm_color_regions);
-            }
-            Err(_) => {
-                error!(
-                    "vmm_init_memory: mem_vm_region_alloc_by_colors failed, length {}, color bitmap {:#x}",
-                    vm_region.length,
-                    config.memory_color_bitmap()
-                );
-                return false;
-            }
+        if let Err(err) = vmm_alloc_region(&vm, vm_region) {
+            error!(
+                "vmm_init_memory: mem_vm_region_alloc_by_colors failed, length {}, color bitmap {:#x}: {:?}",
+                vm_region.length,
+                config.memory_color_bitmap(),
+                err
+            );
+            return false;
         }
     }
-    vmm_setup_ipa2hva(vm);
+    if !mem_color_check_share(&vm) {
+        error!("vmm_init_memory: VM[{}] shares cache colors with others", vm.id());
+        return false;
+    }
+    if vmm_lazy_map(&vm) {
+        // the guest starts on the images, map them before it runs
+        let mut pages = vm.lazy_populate(config.kernel_load_ipa(), LAZY_EAGER_IMAGE_SIZE);
+        pages += vm.lazy_populate(config.device_tree_load_ipa(), LAZY_EAGER_DTB_SIZE);
+        if config.ramdisk_load_ipa() != 0 {
+            pages += vm.lazy_populate(config.ramdisk_load_ipa(), LAZY_EAGER_IMAGE_SIZE);
+        }
+        info!(
+            "VM[{}] maps its memory on demand, {} pages of the images mapped",
+            vm.id(),
+            pages
+        );
+    }
+    vmm_setup_ipa2hva(vm.clone(), vm_memory_regions)
+}
 
+// map a new memory region into a created VM, both stage-2 and the hypervisor hva on each core
+pub fn vmm_add_memory_region(vm: &Arc<Vm>, vm_region: VmRegion) -> bool {
+    if let Err(err) = vmm_alloc_region(vm, &vm_region) {
+        error!(
+            "vmm_add_memory_region: mem_vm_region_alloc_by_colors failed, length {}, color bitmap {:#x}: {:?}",
+            vm_region.length,
+            vm.config().memory_color_bitmap(),
+            err
+        );
+        return false;
+    }
+    if !vmm_setup_ipa2hva(vm.clone(), core::slice::from_ref(&vm_region)) {
+        return false;
+    }
+    vm.add_memory_region(vm_region);
+    vmm_update_info_page(vm);
     true
 }
 
-fn vmm_load_image(vm: &Vm, bin: &[u8]) {
-    copy_segment_to_vm(vm, vm.config().kernel_load_ipa(), bin);
+// an elf image of a bma guest is loaded by its program headers, others at kernel_load_ipa as they are
+fn vmm_load_image(vm: &Vm, bin: &[u8]) -> bool {
+    if vm.vm_type() == VmType::VmTBma && is_elf(bin) {
+        return vmm_load_elf(vm, bin).is_ok();
+    }
+    vm.set_entry_point(None);
+    copy_segment_to_vm(vm, vm.config().kernel_load_ipa(), bin).is_ok()
+}
+
+/* The kernel image uploaded by MVM is copied to kernel_load_ipa as it is. Once all of it is there,
+ * the segments of an elf image of a bma guest are moved to their ipa, from a copy of the image as
+ * they may overlap where it was uploaded. It is done once for each upload.
+ */
+pub fn vmm_load_uploaded_image(vm: &Vm) -> Result<(), ()> {
+    let upload = vm_if_upload(vm.id());
+    if vm.vm_type() != VmType::VmTBma || upload.size == 0 || upload.pending() || upload.failed != 0 || upload.loaded {
+        return Ok(());
+    }
+    let load_ipa = vm.config().kernel_load_ipa();
+    let mut magic = [0_u8; 4];
+    copy_segment_from_vm(vm, &mut magic, load_ipa).map_err(|_| ())?;
+    if is_elf(&magic) {
+        let mut bin = vec![0_u8; upload.size];
+        copy_segment_from_vm(vm, &mut bin, load_ipa).map_err(|_| ())?;
+        vmm_load_elf(vm, &bin)?;
+    } else {
+        // the entry of an elf uploaded before is stale, the raw image starts at kernel_entry_point
+        vm.set_entry_point(None);
+        if let Some(vcpu) = vm.vcpu(0) {
+            vcpu.init_boot_info(vm);
+        }
+    }
+    vm_if_upload_loaded(vm.id());
+    Ok(())
+}
+
+// the image of a guest with several memory regions may be loaded into any of them
+fn vmm_load_ipa_valid(vm: &Vm, name: &str, ipa: usize) -> bool {
+    if vm.config().load_ipa_valid(ipa) {
+        return true;
+    }
+    error!(
+        "vmm_init_image: VM[{}] {} ipa {:#x} is out of its memory regions {:#x?}",
+        vm.id(),
+        name,
+        ipa,
+        vm.config().memory_region()
+    );
+    false
 }
 
 pub(super) fn vmm_init_image(vm: &Vm) -> bool {
@@ -91,6 +227,14 @@ pub(super) fn vmm_init_image(vm: &Vm) -> bool {
         error!("vmm_init_image: kernel load ipa is null");
         return false;
     }
+    if !vmm_load_ipa_valid(vm, "kernel load", config.kernel_load_ipa())
+        || !vmm_load_ipa_valid(vm, "kernel entry", config.kernel_entry_point())
+        || !vmm_load_ipa_valid(vm, "device tree load", config.device_tree_load_ipa())
+        || !vmm_load_ipa_valid(vm, "ramdisk load", config.ramdisk_load_ipa())
+        || !vmm_load_ipa_valid(vm, "boot info load", config.boot_info_load_ipa())
+    {
+        return false;
+    }
 
     // Only load MVM kernel image "L4T" from binding.
     // Load GVM kernel image from shyper-cli, you may check it for more information.
@@ -98,19 +242,25 @@ pub(super) fn vmm_init_image(vm: &Vm) -> bool {
         Some(name) => {
             if name == env!("VM0_IMAGE_PATH") {
                 trace!("MVM {} loading Image", vm.id());
-                vmm_load_image(vm, include_bytes!(env!("VM0_IMAGE_PATH")));
+                if !vmm_load_image(vm, include_bytes!(env!("VM0_IMAGE_PATH"))) {
+                    return false;
+                }
             } else {
                 cfg_if::cfg_if! {
                     if #[cfg(feature = "static-config")] {
                         if name == "Image_vanilla" {
                             trace!("VM {} loading default Linux Image", vm.id());
-                            vmm_load_image(vm, include_bytes!("../../image/Image_vanilla"));
+                            if !vmm_load_image(vm, include_bytes!("../../image/Image_vanilla")) {
+                                return false;
+                            }
                         } else {
                             warn!("Image {} is not supported", name);
                         }
                     } else if #[cfg(feature = "unishyper")] {
                         if name == "Image_Unishyper" {
-                            vmm_load_image(vm, include_bytes!("../../image/Image_Unishyper"));
+                            if !vmm_load_image(vm, include_bytes!("../../image/Image_Unishyper")) {
+                                return false;
+                            }
                         } else {
                             warn!("Image {} is not supported", name);
                         }
@@ -125,6 +275,42 @@ pub(super) fn vmm_init_image(vm: &Vm) -> bool {
         }
     }
 
+    // The ramdisk of a GVM configured by shyper-cli is uploaded later, see `upload_ramdisk_image`.
+    // A config naming no ramdisk gets the built-in one at its ramdisk load ipa, as before.
+    #[cfg(feature = "ramdisk")]
+    let ramdisk_filename = config
+        .image
+        .ramdisk_filename
+        .or((config.ramdisk_load_ipa() != 0).then_some(env!("RAMDISK_PATH")));
+    #[cfg(not(feature = "ramdisk"))]
+    let ramdisk_filename = config.image.ramdisk_filename;
+    if let Some(name) = ramdisk_filename {
+        if config.ramdisk_load_ipa() == 0 {
+            error!(
+                "vmm_init_image: VM[{}] has ramdisk {} but no ramdisk load ipa",
+                vm_id, name
+            );
+            return false;
+        }
+        #[cfg(feature = "ramdisk")]
+        if name == env!("RAMDISK_PATH") {
+            info!("VM {} use ramdisk {}", vm_id, name);
+            if copy_segment_to_vm(vm, config.ramdisk_load_ipa(), CPIO_RAMDISK).is_err() {
+                error!("vmm_init_image: VM[{}] ramdisk does not fit in its memory", vm_id);
+                return false;
+            }
+            vm.set_ramdisk_size(CPIO_RAMDISK.len());
+        }
+        if vm.ramdisk_size() == 0 {
+            warn!("Ramdisk {} is not supported", name);
+        }
+    }
+
+    if config.os_type == VmType::VmTBma {
+        // A bma guest has no device tree, it gets the boot info instead.
+        return vmm_init_boot_info(vm);
+    }
+
     if config.device_tree_load_ipa() != 0 {
         // Init dtb for Linux.
         if vm_id == 0 {
@@ -137,34 +323,38 @@ pub(super) fn vmm_init_image(vm: &Vm) -> bool {
                 panic!("unsafe dtb editing!!");
             }
             dtb.resize(size, 0);
-            copy_segment_to_vm(vm, config.device_tree_load_ipa(), dtb.as_slice());
-        } else {
-            // Init dtb for GVM.
-            match create_fdt(config) {
-                Ok(dtb) => {
-                    copy_segment_to_vm(vm, config.device_tree_load_ipa(), dtb.as_slice());
-                }
-                _ => {
-                    panic!("vmm_setup_config: create fdt for vm{} fail", vm.id());
-                }
+            if copy_segment_to_vm(vm, config.device_tree_load_ipa(), dtb.as_slice()).is_err() {
+                return false;
             }
+        } else if !vmm_setup_fdt(vm) {
+            panic!("vmm_setup_config: create fdt for vm{} fail", vm.id());
         }
     } else {
         warn!("VM {} id {} device tree load ipa is not set", vm_id, vm.config().name);
     }
 
-    // ...
-    // Todo: support loading ramdisk from MVM shyper-cli.
-    // ...
-    #[cfg(feature = "ramdisk")]
-    if config.ramdisk_load_ipa() != 0 {
-        info!("VM {} use ramdisk CPIO_RAMDISK", vm_id);
-        copy_segment_to_vm(vm, config.ramdisk_load_ipa(), CPIO_RAMDISK);
-    }
-
     true
 }
 
+/* Generate the device tree of a GVM and copy it to device_tree_load_ipa.
+ * It is called again whenever the ramdisk changes, so that the chosen node
+ * reflects the actual ramdisk length.
+ *
+ * @param[in] vm: target GVM.
+ */
+pub fn vmm_setup_fdt(vm: &Vm) -> bool {
+    let config = vm.config();
+    match create_fdt(config, vm.ramdisk_size()) {
+        Ok(dtb) if fdt_check(config, &dtb) => {
+            copy_segment_to_vm(vm, config.device_tree_load_ipa(), dtb.as_slice()).is_ok()
+        }
+        _ => {
+            error!("vmm_setup_fdt: create fdt for VM[{}] fail", vm.id());
+            false
+        }
+    }
+}
+
 fn vmm_init_hardware(vm: &Vm) -> bool {
     // init passthrough irqs
     for irq in vm.config().passthrough_device_irqs() {
@@ -182,6 +372,10 @@ fn vmm_init_hardware(vm: &Vm) -> bool {
             }
         }
     }
+    // streams of a static config are claimed here
+    if !iommu_claim_streams(vm.id(), vm.config().passthrough_device_stread_ids()) {
+        return false;
+    }
     for stream_id in vm.config().passthrough_device_stread_ids() {
         if *stream_id == 0 {
             break;
@@ -196,10 +390,12 @@ fn vmm_init_hardware(vm: &Vm) -> bool {
 /* Setup VM Configuration before boot.
  * Only VM0 will call this function.
  * This func should run 1 time for each vm.
+ * Returns false if the memory of the VM can not be allocated or its spin-table release page is not
+ * free, the caller takes the VM down.
  *
  * @param[in] vm_id: target VM id to set up config.
  */
-pub fn vmm_setup_config(vm: Arc<Vm>) {
+pub fn vmm_setup_config(vm: Arc<Vm>) -> bool {
     trace!(
         "vmm_setup_config VM[{}] name {:?} current core {}",
         vm.id(),
@@ -207,10 +403,14 @@ pub fn vmm_setup_config(vm: Arc<Vm>) {
         current_cpu().id
     );
     // need ipi, must after push to global list
-    vmm_init_cpu(vm.clone());
+    if !vmm_init_cpu(vm.clone()) {
+        error!("vmm_setup_config: vmm_init_cpu failed");
+        return false;
+    }
     // need ipi, must after push to global list
     if !vmm_init_memory(vm.clone()) {
-        panic!("vmm_setup_config: vmm_init_memory failed");
+        error!("vmm_setup_config: vmm_init_memory failed");
+        return false;
     }
     // need memory, must after init memory
     if !vmm_init_image(&vm) {
@@ -219,11 +419,22 @@ pub fn vmm_setup_config(vm: Arc<Vm>) {
     if !vmm_init_hardware(&vm) {
         panic!("vmm_setup_config: vmm_init_hardware failed");
     }
+    if !vmm_init_info_page(&vm) {
+        panic!("vmm_setup_config: vmm_init_info_page failed");
+    }
+    if !pvclock_init(&vm) {
+        panic!("vmm_setup_config: pvclock_init failed");
+    }
+    if !spin_table_init(&vm) {
+        error!("vmm_setup_config: spin_table_init failed");
+        return false;
+    }
 
     info!("VM {} id {} init ok", vm.id(), vm.config().name);
+    true
 }
 
-fn vmm_init_cpu(vm: Arc<Vm>) {
+fn vmm_init_cpu(vm: Arc<Vm>) -> bool {
     let vm_id = vm.id();
     trace!("vmm_init_cpu: set up vm {} on cpu {}", vm_id, current_cpu().id);
     info!(
@@ -233,29 +444,35 @@ fn vmm_init_cpu(vm: Arc<Vm>) {
         vm.config().cpu_allocated_bitmap()
     );
 
-    for vcpu in vm.vcpu_list() {
-        let target_cpu_id = vcpu.phys_id();
+    for target_cpu_id in vm.pcpu_list() {
         if target_cpu_id != current_cpu().id {
             let m = IpiVmmPercoreMsg {
                 vm: vm.clone(),
                 event: VmmPercoreEvent::AssignCpu,
             };
-            if !ipi_send_msg(target_cpu_id, IpiType::Vmm, IpiInnerMsg::VmmPercoreMsg(m)) {
-                error!("vmm_init_cpu: failed to send ipi to Core {}", target_cpu_id);
+            if let Err(err) = ipi_send_msg_retry(target_cpu_id, IpiType::Vmm, IpiInnerMsg::VmmPercoreMsg(m)) {
+                error!("vmm_init_cpu: failed to send ipi to Core {}: {:?}", target_cpu_id, err);
+                return false;
             }
-        } else {
-            vmm_assign_vcpu_percore(&vm);
+        } else if !vmm_assign_vcpu_percore(&vm) {
+            return false;
         }
     }
     info!("vmm_init_cpu: VM [{}] is ready", vm_id);
+    true
 }
 
-pub fn vmm_assign_vcpu_percore(vm: &Vm) {
+/* Put the vcpus of `vm` that belong to this core on it. If one is rejected, the ones put here are
+ * taken off again and the VM is marked, so that it is not booted with vcpus missing.
+ * Return false on the failure, for the local caller; a core asked by ipi only leaves the mark.
+ */
+pub fn vmm_assign_vcpu_percore(vm: &Vm) -> bool {
     let cpu_id = current_cpu().id;
     if current_cpu().assigned() {
         trace!("vmm_cpu_assign_vcpu vm[{}] cpu {} is assigned", vm.id(), cpu_id);
     }
 
+    let mut appended = Vec::new();
     for vcpu in vm.vcpu_list() {
         if vcpu.phys_id() == current_cpu().id {
             if vcpu.id() == 0 {
@@ -263,10 +480,18 @@ pub fn vmm_assign_vcpu_percore(vm: &Vm) {
             } else {
                 info!("Core {} is assigned => vm {}, vcpu {}", cpu_id, vm.id(), vcpu.id());
             }
-            current_cpu().vcpu_array.append_vcpu(vcpu.clone());
-            break;
+            if !current_cpu().vcpu_array.append_vcpu(vcpu.clone()) {
+                error!("Core {} failed to assign vm {}, vcpu {}", cpu_id, vm.id(), vcpu.id());
+                for assigned in appended {
+                    current_cpu().vcpu_array.remove_vcpu(vm.id(), assigned);
+                }
+                vm.set_vcpu_assign_failed();
+                return false;
+            }
+            appended.push(vcpu.id());
         }
     }
+    true
 }
 
 pub fn vm_init() {
@@ -280,12 +505,30 @@ pub fn vm_init() {
         }
         // Add VM 0
         super::vmm_init_gvm(0);
-        #[cfg(feature = "static-config")]
-        {
-            crate::config::init_tmp_config_for_vm1();
-            crate::config::init_tmp_config_for_vm2();
-            super::vmm_init_gvm(1);
-            super::vmm_init_gvm(2);
+        for vm_id in static_gvm_config_init() {
+            super::vmm_init_gvm(vm_id);
         }
     }
 }
+
+// add the guests configured at build time, the config dtb is preferred over the Rust-coded configs
+fn static_gvm_config_init() -> Vec<usize> {
+    #[cfg(feature = "dtb-config")]
+    match crate::config::dtb_config_init() {
+        Ok(vm_ids) => return vm_ids,
+        Err(()) => error!("static_gvm_config_init: invalid vm config dtb"),
+    }
+    rust_gvm_config_init()
+}
+
+#[cfg(feature = "static-config")]
+fn rust_gvm_config_init() -> Vec<usize> {
+    crate::config::init_tmp_config_for_vm1();
+    crate::config::init_tmp_config_for_vm2();
+    vec![1, 2]
+}
+
+#[cfg(not(feature = "static-config"))]
+fn rust_gvm_config_init() -> Vec<usize> {
+    Vec::new()
+}
@@ -1,22 +1,31 @@
-use alloc::ffi::CString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
 
 use crate::arch::interrupt_arch_deactive_irq;
 use crate::arch::power_arch_vm_shutdown_secondary_cores;
-use crate::config::vm_cfg_entry;
+use crate::arch::VgicLrStat;
+use crate::config::{vm_cfg_entry, vm_cfg_release_mediated_blk};
+use crate::device::{pl011_receive, pl011_ring_read, virtio_console_ring_read, virtio_net_stat, NetStat};
+use crate::kernel::access::vm_ipa2hva;
 use crate::kernel::HVC_CONFIG;
 use crate::kernel::HVC_CONFIG_UPLOAD_KERNEL_IMAGE;
 use crate::kernel::HVC_VMM;
 use crate::kernel::HVC_VMM_REBOOT_VM;
 use crate::kernel::{
-    active_vcpu_id, active_vm, current_cpu, push_vm, vm_if_get_state, vm_if_set_ivc_arg, vm_if_set_ivc_arg_ptr,
-    vm_list_walker, Vm,
+    active_vcpu_id, active_vm, cancel_vm_async_task, current_cpu, push_vm, remove_vm, vm_by_id, vm_if_boot_state,
+    vm_if_boot_transit, vm_if_get_state, vm_if_reset, vm_if_set_ivc_arg, vm_if_set_ivc_arg_ptr, vm_if_set_state,
+    vm_if_swap_state, vm_if_upload, vm_list_walker, vm_log_access, ExitStat, HaltPollStat, Vm, VmBootState, VmState,
 };
-use crate::kernel::{hvc_send_msg_to_vm, HvcGuestMsg, HvcManageMsg};
-use crate::kernel::{ipi_send_msg, vm_if_get_cpu_id, IpiInnerMsg, IpiMessage, IpiType, IpiVmmMsg};
+use crate::kernel::{hvc_send_msg_to_vm, interrupt_vm_transfer, HvcGuestMsg, HvcManageMsg};
+use crate::kernel::{ipi_send_msg_retry, vm_if_get_cpu_id, IpiInnerMsg, IpiMessage, IpiType, IpiVmmMsg};
+use crate::kernel::{HVC_SYS, HVC_SYS_VM_CRASH};
 use crate::util::bit_extract;
-use crate::vmm::{vmm_assign_vcpu_percore, vmm_init_image, vmm_remove_vcpu_percore, vmm_setup_config};
+use crate::vmm::{
+    vmm_assign_vcpu_percore, vmm_init_image, vmm_load_uploaded_image, vmm_remove_vcpu_percore, vmm_setup_config,
+};
 
-use shyper::{VMInfo, VM_NUM_MAX};
+use super::remove::{vmm_remove_passthrough_device, vmm_remove_vcpu};
 
 #[derive(Copy, Clone)]
 pub enum VmmEvent {
@@ -32,6 +41,12 @@ pub enum VmmPercoreEvent {
     RemoveCpu,
     MapIPA,
     UnmapIPA,
+    PauseVcpu,
+    ResumeVcpu,
+    // vcpu migration, on the old core and then on the new one
+    DetachVcpu { vcpu_id: usize, target: usize },
+    // `source` is None when the vcpu is moved back after the new core failed to take it
+    AttachVcpu { vcpu_id: usize, source: Option<usize> },
 }
 
 fn vmm_shutdown_secondary_vm() {
@@ -56,16 +71,36 @@ fn vmm_push_vm(vm_id: usize) -> Result<alloc::sync::Arc<Vm>, ()> {
 
 /* Init VM before boot.
  * Only VM0 will call this function.
+ * Returns false if the VM is not created, a second request for a VM being configured is rejected.
  *
  * @param[in] vm_id: target VM id to boot.
  */
-pub fn vmm_init_gvm(vm_id: usize) {
+pub fn vmm_init_gvm(vm_id: usize) -> bool {
     // Before boot, we need to set up the VM config.
     if current_cpu().id == 0 || (active_vm().unwrap().id() == 0 && active_vm().unwrap().id() != vm_id) {
+        if let Err(state) = vm_if_boot_transit(vm_id, VmBootState::Configuring) {
+            error!("VM[{}] can not be configured in state {:?}", vm_id, state);
+            return false;
+        }
         if let Ok(vm) = vmm_push_vm(vm_id) {
-            vmm_setup_config(vm);
+            if !vmm_setup_config(vm.clone()) {
+                if vm_id == 0 {
+                    panic!("vmm_init_gvm: VM0 setup failed");
+                }
+                // take the VM down again, the colored memory it got is freed with it
+                vmm_remove_vcpu(&vm);
+                vm_if_reset(vm_id);
+                remove_vm(vm_id);
+                let _ = vm_if_boot_transit(vm_id, VmBootState::Pending);
+                error!("VM[{}] setup failed, for its memory see HVC_SYS_COLOR_FREE", vm_id);
+                return false;
+            }
+            let _ = vm_if_boot_transit(vm_id, VmBootState::Configured);
+            true
         } else {
             error!("VM[{}] alloc failed", vm_id);
+            let _ = vm_if_boot_transit(vm_id, VmBootState::Pending);
+            false
         }
     } else {
         error!(
@@ -74,46 +109,176 @@ pub fn vmm_init_gvm(vm_id: usize) {
             current_cpu().id,
             vm_id
         );
+        false
     }
 }
 
 /* Boot Guest VM.
+ * Only a configured VM is booted, and only once, a request racing with another one is rejected.
  *
  * @param[in] vm_id: target VM id to boot.
  */
-pub fn vmm_boot_vm(vm_id: usize) {
-    if let Some(phys_id) = vm_if_get_cpu_id(vm_id) {
-        trace!("vmm_boot_vm: target vm {} get phys_id {}", vm_id, phys_id);
-        if phys_id != current_cpu().id {
-            let m = IpiVmmMsg {
-                vmid: vm_id,
-                event: VmmEvent::Boot,
-            };
-            if !ipi_send_msg(phys_id, IpiType::Vmm, IpiInnerMsg::VmmMsg(m)) {
-                error!("vmm_boot_vm: failed to send ipi to Core {}", phys_id);
-            }
-        } else {
-            match current_cpu().vcpu_array.pop_vcpu_through_vmid(vm_id) {
-                None => {
-                    panic!(
-                        "vmm_boot_vm: VM[{}] does not have vcpu on Core {}",
-                        vm_id,
-                        current_cpu().id
-                    );
-                }
-                Some(vcpu) => {
-                    use crate::kernel::{vm_if_set_state, VmState};
-                    vm_if_set_state(vm_id, VmState::Active);
-                    interrupt_arch_deactive_irq(true);
-                    current_cpu().vcpu_array.wakeup_vcpu(vcpu);
-                    if current_cpu().assigned() && active_vcpu_id() == 0 {
-                        info!("Core {} start running", current_cpu().id);
-                    }
-                }
-            };
+pub fn vmm_boot_vm(vm_id: usize) -> Result<usize, ()> {
+    let phys_id = match vm_if_get_cpu_id(vm_id) {
+        Some(phys_id) => phys_id,
+        None => {
+            error!("VM [{vm_id}] is not configured");
+            return Err(());
+        }
+    };
+    if vm_if_upload(vm_id).pending() {
+        error!("vmm_boot_vm: VM[{}] kernel image upload is in progress", vm_id);
+        return Err(());
+    }
+    // in case MVM did not ask for the upload status after the last piece
+    match vm_by_id(vm_id) {
+        Some(vm) if vm.vcpu_assign_failed() => {
+            error!("vmm_boot_vm: VM[{}] has vcpus no core took, remove it", vm_id);
+            return Err(());
+        }
+        Some(vm) => vmm_load_uploaded_image(&vm)?,
+        None => return Err(()),
+    }
+    if let Err(state) = vm_if_boot_transit(vm_id, VmBootState::Booted) {
+        error!("vmm_boot_vm: VM[{}] is busy or already booted ({:?})", vm_id, state);
+        return Err(());
+    }
+    trace!("vmm_boot_vm: target vm {} get phys_id {}", vm_id, phys_id);
+    if phys_id != current_cpu().id {
+        let m = IpiVmmMsg {
+            vmid: vm_id,
+            event: VmmEvent::Boot,
+        };
+        if let Err(err) = ipi_send_msg_retry(phys_id, IpiType::Vmm, IpiInnerMsg::VmmMsg(m)) {
+            error!("vmm_boot_vm: failed to send ipi to Core {}: {:?}", phys_id, err);
+            let _ = vm_if_boot_transit(vm_id, VmBootState::Configured);
+            return Err(());
         }
     } else {
-        error!("VM [{vm_id}] is not configured");
+        vmm_boot_vm_percore(vm_id);
+    }
+    Ok(0)
+}
+
+// on the master core of the VM: start running its vcpu 0
+fn vmm_boot_vm_percore(vm_id: usize) {
+    match current_cpu().vcpu_array.vcpu_by_id(vm_id, 0) {
+        None => {
+            panic!(
+                "vmm_boot_vm: VM[{}] does not have vcpu 0 on Core {}",
+                vm_id,
+                current_cpu().id
+            );
+        }
+        Some(vcpu) => {
+            vm_if_set_state(vm_id, VmState::Active);
+            interrupt_arch_deactive_irq(true);
+            current_cpu().vcpu_array.wakeup_vcpu(vcpu);
+            if current_cpu().assigned() && active_vcpu_id() == 0 {
+                info!("Core {} start running", current_cpu().id);
+            }
+        }
+    };
+}
+
+/* Stop a running guest VM without rebooting the board.
+ * Its vcpus are taken off their cores, its passthrough interrupts are disabled and its mediated blks are
+ * given back, the memory and emulated devices stay until `vmm_remove_vm`.
+ *
+ * @param[in] vm_id: target VM id, VM0 can not be shut down.
+ */
+pub fn vmm_shutdown_vm(vm_id: usize) -> Result<usize, ()> {
+    if vm_id == 0 {
+        error!("vmm_shutdown_vm: VM0 can not be shut down");
+        return Err(());
+    }
+    let vm = match vm_by_id(vm_id) {
+        Some(vm) => vm,
+        None => {
+            error!("vmm_shutdown_vm: VM[{}] does not exist", vm_id);
+            return Err(());
+        }
+    };
+    if !matches!(vm_if_get_state(vm_id), VmState::Active) {
+        error!("vmm_shutdown_vm: VM[{}] is not running", vm_id);
+        return Err(());
+    }
+    info!("vmm_shutdown_vm: VM[{}] shutting down", vm_id);
+    // nothing is injected into the VM from now on
+    vm_if_set_state(vm_id, VmState::Inv);
+    if !vmm_teardown_vm(&vm) {
+        return Err(());
+    }
+    Ok(0)
+}
+
+/* Stop a VM where it is: its passthrough irqs are released, its vcpus leave their cores
+ * and its IO in flight is dropped. The VM itself stays until it is removed.
+ * No lock is held across the ipis, so it is safe in exception context on any core.
+ * Only the first of a shutdown and the crashes of its vcpus gets the VM to `VmBootState::Stopped`
+ * and takes it down, return false for the others.
+ */
+fn vmm_teardown_vm(vm: &Arc<Vm>) -> bool {
+    if let Err(state) = vm_if_boot_transit(vm.id(), VmBootState::Stopped) {
+        warn!("vmm_teardown_vm: VM[{}] is not running ({:?})", vm.id(), state);
+        return false;
+    }
+    info!("VM[{}] exits: {}", vm.id(), vmm_exit_stat_sum(vm));
+    vmm_remove_passthrough_device(vm);
+    vmm_remove_vcpu(vm);
+    // the IO in flight has no one to complete to
+    cancel_vm_async_task(vm.id(), false);
+    let block_idx = vm_cfg_release_mediated_blk(vm.id());
+    if !block_idx.is_empty() {
+        info!("VM[{}] release mediated blk {:?}", vm.id(), block_idx);
+    }
+    true
+}
+
+/* A vcpu of GVM `vm` hit a fault the hypervisor can not handle, called from the exception of that vcpu.
+ * The VM is stopped as `VmState::Crashed` and VM0 is told, it decides to dump or remove it.
+ * The context of the trap belongs to another vcpu or the idle thread when it returns.
+ */
+pub fn vmm_crash_vm(vm: &Arc<Vm>) {
+    let vm_id = vm.id();
+    if matches!(vm_if_swap_state(vm_id, VmState::Crashed), VmState::Crashed) {
+        // another vcpu crashed first, only this one is left to take off
+        vmm_remove_vcpu_percore(vm);
+        return;
+    }
+    if !vmm_teardown_vm(vm) {
+        // shut down meanwhile, this vcpu is the one left to take off
+        vmm_remove_vcpu_percore(vm);
+        return;
+    }
+    vmm_notify_crash(vm_id);
+}
+
+/* The watchdog found a core stuck in a vcpu of GVM `vm_id`, that core can not take the vcpu off itself.
+ * Only the states are set and VM0 is told, so that the vcpus on the other cores can be cleaned up.
+ * As `VmBootState::Stopped` the VM is not booted or rebooted again, it can only be removed.
+ * Return false if the VM was crashed already.
+ */
+#[cfg(feature = "watchdog")]
+pub fn vmm_force_crash_vm(vm_id: usize) -> bool {
+    if matches!(vm_if_swap_state(vm_id, VmState::Crashed), VmState::Crashed) {
+        return false;
+    }
+    if let Err(state) = vm_if_boot_transit(vm_id, VmBootState::Stopped) {
+        warn!("vmm_force_crash_vm: VM[{}] is not running ({:?})", vm_id, state);
+    }
+    vmm_notify_crash(vm_id);
+    true
+}
+
+fn vmm_notify_crash(vm_id: usize) {
+    let msg = HvcManageMsg {
+        fid: HVC_SYS,
+        event: HVC_SYS_VM_CRASH,
+        vm_id,
+    };
+    if !hvc_send_msg_to_vm(0, &HvcGuestMsg::Manage(msg)) {
+        error!("vmm_notify_crash: failed to notify VM0 of VM[{}]", vm_id);
     }
 }
 
@@ -123,7 +288,7 @@ pub fn vmm_boot_vm(vm_id: usize) {
  * @param arg force ~ (31, 16) ~ [soft shutdown or hard shutdown]
  *            vmid ~ (15, 0) ~ [target vm id]
  */
-pub fn vmm_reboot_vm(arg: usize) {
+pub fn vmm_reboot_vm(arg: usize) -> Result<usize, ()> {
     let vm_id = bit_extract(arg, 0, 16);
     let force = bit_extract(arg, 16, 16) != 0;
     let cur_vm = active_vm().unwrap();
@@ -134,16 +299,23 @@ pub fn vmm_reboot_vm(arg: usize) {
         if cur_vm.id() == vm_id {
             vmm_reboot();
         } else {
+            // `vmm_reboot` takes the state on the core of the VM, a removal may still win meanwhile
+            let state = vm_if_boot_state(vm_id);
+            if state != VmBootState::Booted {
+                error!("vmm_reboot_vm: VM[{}] is busy or not booted ({:?})", vm_id, state);
+                return Err(());
+            }
             let cpu_trgt = vm_if_get_cpu_id(vm_id).unwrap();
             let m = IpiVmmMsg {
                 vmid: vm_id,
                 event: VmmEvent::Reboot,
             };
-            if !ipi_send_msg(cpu_trgt, IpiType::Vmm, IpiInnerMsg::VmmMsg(m)) {
-                error!("vmm_reboot_vm: failed to send ipi to Core {}", cpu_trgt);
+            if let Err(err) = ipi_send_msg_retry(cpu_trgt, IpiType::Vmm, IpiInnerMsg::VmmMsg(m)) {
+                error!("vmm_reboot_vm: failed to send ipi to Core {}: {:?}", cpu_trgt, err);
+                return Err(());
             }
         }
-        return;
+        return Ok(0);
     }
 
     let msg = HvcManageMsg {
@@ -153,7 +325,9 @@ pub fn vmm_reboot_vm(arg: usize) {
     };
     if !hvc_send_msg_to_vm(vm_id, &HvcGuestMsg::Manage(msg)) {
         error!("vmm_reboot_vm: failed to notify VM 0");
+        return Err(());
     }
+    Ok(0)
 }
 
 /* Reset vm os at current core.
@@ -169,6 +343,12 @@ pub fn vmm_reboot() {
         Platform::sys_reboot();
     }
 
+    // a VM being removed is not brought back
+    if let Err(state) = vm_if_boot_transit(vm.id(), VmBootState::Rebooting) {
+        warn!("VM [{}] can not be reset in state {:?}", vm.id(), state);
+        return;
+    }
+
     // Reset GVM.
     let vcpu = current_cpu().active_vcpu.as_ref().unwrap();
     info!("VM [{}] reset...", vm.id());
@@ -180,6 +360,11 @@ pub fn vmm_reboot() {
         active_vcpu_id()
     );
 
+    // The IO in flight completes to the old guest, drop it before the devices are reset.
+    cancel_vm_async_task(vm.id(), false);
+    // Reset emulated devices, the virtqueues point into the memory of the old guest.
+    vm.reset_emu_devs();
+
     // Clear memory region.
     info!(
         "Core {} (VM [{}] vcpu {}) reset mem region",
@@ -188,6 +373,8 @@ pub fn vmm_reboot() {
         active_vcpu_id(),
     );
     vm.reset_mem_regions();
+    // the uploaded ramdisk is gone with the memory, MVM has to upload it again
+    vm.set_ramdisk_size(0);
 
     // Reset image.
     if !vmm_init_image(&vm) {
@@ -199,7 +386,8 @@ pub fn vmm_reboot() {
     vm_if_set_ivc_arg_ptr(vm.id(), 0);
 
     crate::arch::interrupt_arch_clear();
-    vcpu.init(vm.config());
+    vcpu.init(&vm);
+    let _ = vm_if_boot_transit(vm.id(), VmBootState::Booted);
 
     vmm_load_image_from_mvm(&vm);
 }
@@ -223,67 +411,409 @@ fn vmm_load_image_from_mvm(vm: &Vm) {
  */
 pub fn get_vm_id(id_ipa: usize) -> bool {
     let vm = active_vm().unwrap();
-    let id_pa = vm.ipa2hva(id_ipa);
-    if id_pa == 0 {
-        error!("illegal id_pa {:x}", id_pa);
-        return false;
-    }
+    let id_pa = match vm_ipa2hva(&vm, id_ipa, size_of::<usize>()) {
+        Ok(hva) => hva,
+        Err(_) => return false,
+    };
     unsafe {
         *(id_pa as *mut usize) = vm.id();
     }
     true
 }
 
+// bytes of the name in a `VmInfo`, including the terminating nul
+pub const VM_INFO_NAME_LEN: usize = 32;
+
 #[repr(C)]
-struct VMInfoList {
-    pub vm_num: usize,
-    pub info_list: [VMInfo; VM_NUM_MAX],
+#[derive(Clone, Copy)]
+pub struct VmInfo {
+    pub id: u32,
+    // `VmType`
+    pub vm_type: u32,
+    // `VmState`
+    pub vm_state: u32,
+    pub cpu_num: u32,
+    pub cpu_allocated_bitmap: u64,
+    // bytes of all memory regions, the hot-added ones included
+    pub mem_size: u64,
+    pub emu_dev_num: u32,
+    // the first mediated blk of the VM, u32::MAX if it has none
+    pub med_blk_idx: u32,
+    // nul terminated, truncated if longer
+    pub name: [u8; VM_INFO_NAME_LEN],
+}
+
+impl VmInfo {
+    fn new(vm: &Vm) -> Self {
+        let config = vm.config();
+        let mut name = [0; VM_INFO_NAME_LEN];
+        let len = config.name.len().min(VM_INFO_NAME_LEN - 1);
+        name[..len].copy_from_slice(&config.name.as_bytes()[..len]);
+        Self {
+            id: vm.id() as u32,
+            vm_type: vm.vm_type() as u32,
+            vm_state: vm_if_get_state(vm.id()) as u32,
+            cpu_num: vm.cpu_num() as u32,
+            cpu_allocated_bitmap: config.cpu_allocated_bitmap() as u64,
+            mem_size: vm.memory_regions().iter().map(|region| region.length as u64).sum(),
+            emu_dev_num: config.emulated_device_list().len() as u32,
+            med_blk_idx: config
+                .mediated_block_index()
+                .first()
+                .map_or(u32::MAX, |idx| *idx as u32),
+            name,
+        }
+    }
 }
 
 /* List VM info in hypervisor.
  *
- * @param[in] vm_info_ipa : vm info list ipa.
+ * @param[in] vm_info_ipa : ipa of an array of `VmInfo`.
+ * @param[in] max : the number of entries of the array.
+ * @return the number of entries written.
  */
-pub fn vmm_list_vm(vm_info_ipa: usize) -> Result<usize, ()> {
-    let vm_info_pa = active_vm().unwrap().ipa2hva(vm_info_ipa);
-    if vm_info_pa == 0 {
-        error!("illegal vm_info_ipa {:x}", vm_info_ipa);
-        return Err(());
+pub fn vmm_list_vm(vm_info_ipa: usize, max: usize) -> Result<usize, ()> {
+    // a snapshot, the VM list is not held while writing to the guest
+    let mut info_list = Vec::new();
+    vm_list_walker(|vm| {
+        if info_list.len() < max {
+            info_list.push(VmInfo::new(vm));
+        }
+    });
+    if info_list.is_empty() {
+        return Ok(0);
     }
 
-    let vm_info = unsafe { &mut *(vm_info_pa as *mut VMInfoList) };
+    let size = info_list.len() * size_of::<VmInfo>();
+    let vm_info_pa = vm_ipa2hva(&active_vm().unwrap(), vm_info_ipa, size).map_err(|_| ())?;
+    let out = unsafe { core::slice::from_raw_parts_mut(vm_info_pa as *mut VmInfo, info_list.len()) };
+    out.copy_from_slice(&info_list);
+    Ok(info_list.len())
+}
 
-    let mut idx = 0;
-    vm_list_walker(|vm| {
-        let vm_cfg = vm.config();
-        // Get VM type.
-        let vm_type = vm.vm_type();
-        // Get VM State.
-        let vm_state = vm_if_get_state(vm.id());
-
-        vm_info.info_list[idx].id = vm.id() as u32;
-        vm_info.info_list[idx].vm_type = vm_type as u32;
-        vm_info.info_list[idx].vm_state = vm_state as u32;
-
-        // From Rust to C: CString represents an owned, C-friendly string
-        let vm_name_cstring = CString::new(vm_cfg.name.clone()).unwrap();
-        let vm_name_with_null = vm_name_cstring.to_bytes_with_nul();
-        // ensure that the slice length is equal
-        vm_info.info_list[idx].vm_name[..vm_name_with_null.len()].copy_from_slice(vm_name_with_null);
-        idx += 1;
-    });
-    vm_info.vm_num = idx;
+/**
+ * Write the halt polling statistics of all vcpus of a VM.
+ *
+ * @param[in] vm_id : target VM id.
+ * @param[in] stat_ipa : ipa of a `HaltPollStat` to store the sum.
+ */
+pub fn vmm_halt_poll_stat(vm_id: usize, stat_ipa: usize) -> Result<usize, ()> {
+    let vm = match vm_by_id(vm_id) {
+        Some(vm) => vm,
+        None => {
+            error!("vmm_halt_poll_stat: VM[{vm_id}] does not exist");
+            return Err(());
+        }
+    };
+    let stat_pa = vm_ipa2hva(&active_vm().unwrap(), stat_ipa, size_of::<HaltPollStat>()).map_err(|_| ())?;
+    let stat = unsafe { &mut *(stat_pa as *mut HaltPollStat) };
+    *stat = HaltPollStat::default();
+    for vcpu in vm.vcpu_list() {
+        let vcpu_stat = vcpu.halt_poll_stat();
+        stat.success += vcpu_stat.success;
+        stat.fail += vcpu_stat.fail;
+    }
+    Ok(0)
+}
+
+/**
+ * Write the list register statistics of a VM, the number of hardware interrupts injected
+ * directly and the number of injections that found no free LR.
+ *
+ * @param[in] vm_id : target VM id.
+ * @param[in] stat_ipa : ipa of a `VgicLrStat` to store the counters.
+ */
+pub fn vmm_lr_stat(vm_id: usize, stat_ipa: usize) -> Result<usize, ()> {
+    let vm = match vm_by_id(vm_id) {
+        Some(vm) if vm.has_vgic() => vm,
+        _ => {
+            error!("vmm_lr_stat: VM[{vm_id}] does not exist or has no vgic");
+            return Err(());
+        }
+    };
+    let stat_pa = vm_ipa2hva(&active_vm().unwrap(), stat_ipa, size_of::<VgicLrStat>()).map_err(|_| ())?;
+    let stat = unsafe { &mut *(stat_pa as *mut VgicLrStat) };
+    *stat = vm.vgic().lr_stat();
+    Ok(0)
+}
+
+// the exits of all vcpus of `vm`
+fn vmm_exit_stat_sum(vm: &Vm) -> ExitStat {
+    let mut sum = ExitStat::default();
+    for vcpu in vm.vcpu_list() {
+        sum.add(&vcpu.exit_stat().snapshot());
+    }
+    sum
+}
+
+/**
+ * Write the exit statistics of each vcpu of a VM, in the order of the vcpu ids.
+ *
+ * @param arg reset ~ (16) ~ [clear the counters once they are read]
+ *            vm_id ~ (15, 0) ~ [target VM]
+ * @param stat_ipa : ipa of an array of `ExitStat`, one for each vcpu of the VM.
+ * @return the number of `ExitStat` written.
+ */
+pub fn vmm_exit_stat(arg: usize, stat_ipa: usize) -> Result<usize, ()> {
+    let vm_id = bit_extract(arg, 0, 16);
+    let reset = bit_extract(arg, 16, 1) != 0;
+    let vm = match vm_by_id(vm_id) {
+        Some(vm) => vm,
+        None => {
+            error!("vmm_exit_stat: VM[{vm_id}] does not exist");
+            return Err(());
+        }
+    };
+    let vcpu_list = vm.vcpu_list();
+    let size = vcpu_list.len() * size_of::<ExitStat>();
+    let stat_hva = vm_ipa2hva(&active_vm().unwrap(), stat_ipa, size).map_err(|_| ())?;
+    let out = unsafe { core::slice::from_raw_parts_mut(stat_hva as *mut ExitStat, vcpu_list.len()) };
+    for (stat, vcpu) in out.iter_mut().zip(vcpu_list) {
+        *stat = vcpu.exit_stat().snapshot();
+        if reset {
+            vcpu.exit_stat().reset();
+        }
+    }
+    Ok(vcpu_list.len())
+}
+
+/* Copy a snapshot of the virtio-net counters of VM `vm_id` to a `NetStat` at `stat_ipa`. */
+pub fn vmm_net_stat(vm_id: usize, stat_ipa: usize) -> Result<usize, ()> {
+    let net_stat = match virtio_net_stat(vm_id) {
+        Some(stat) => stat,
+        None => {
+            error!("vmm_net_stat: VM[{vm_id}] does not exist or has no virtio net");
+            return Err(());
+        }
+    };
+    let stat_hva = vm_ipa2hva(&active_vm().unwrap(), stat_ipa, size_of::<NetStat>()).map_err(|_| ())?;
+    let stat = unsafe { &mut *(stat_hva as *mut NetStat) };
+    *stat = net_stat;
     Ok(0)
 }
 
+// vm id of the global log ring in HVC_VMM_READ_LOG and HVC_VMM_LOG_CONSOLE
+const VM_LOG_GLOBAL_ID: usize = 0xffff;
+
+fn vm_log_id(vm_id: usize) -> Option<usize> {
+    if vm_id == VM_LOG_GLOBAL_ID {
+        None
+    } else {
+        Some(vm_id)
+    }
+}
+
+/**
+ * Consume the log ring of a VM.
+ *
+ * @param arg len ~ (47, 16) ~ [max bytes to copy]
+ *            vmid ~ (15, 0) ~ [target vm id, 0xffff for the global ring]
+ * @param buf_ipa : ipa of a `usize` to store the lines dropped since the last read, followed by the text buffer.
+ * @return the number of bytes copied to the text buffer.
+ */
+pub fn vmm_read_log(arg: usize, buf_ipa: usize) -> Result<usize, ()> {
+    let vm_id = bit_extract(arg, 0, 16);
+    let len = bit_extract(arg, 16, 32);
+    let buf_hva = vm_ipa2hva(&active_vm().unwrap(), buf_ipa, size_of::<usize>() + len).map_err(|_| ())?;
+    let text = unsafe { core::slice::from_raw_parts_mut((buf_hva + size_of::<usize>()) as *mut u8, len) };
+    match vm_log_access(vm_log_id(vm_id), |log| (log.take_dropped(), log.pop(text))) {
+        Some((dropped, len)) => {
+            unsafe { *(buf_hva as *mut usize) = dropped };
+            Ok(len)
+        }
+        None => {
+            error!("vmm_read_log: VM[{vm_id}] has no log ring");
+            Err(())
+        }
+    }
+}
+
+/**
+ * Consume the console ring of a VM, kept by its virtio console ports to vmid 0xffff or its pl011.
+ *
+ * @param arg len ~ (47, 16) ~ [max bytes to copy]
+ *            vmid ~ (15, 0) ~ [target vm id]
+ * @param buf_ipa : ipa of a `usize` to store the bytes overwritten since the last read, followed by the text buffer.
+ * @return the number of bytes copied to the text buffer.
+ */
+pub fn vmm_read_console(arg: usize, buf_ipa: usize) -> Result<usize, ()> {
+    let vm_id = bit_extract(arg, 0, 16);
+    let len = bit_extract(arg, 16, 32);
+    let vm = match vm_by_id(vm_id) {
+        Some(vm) => vm,
+        None => {
+            error!("vmm_read_console: VM[{vm_id}] does not exist");
+            return Err(());
+        }
+    };
+    let buf_hva = vm_ipa2hva(&active_vm().unwrap(), buf_ipa, size_of::<usize>() + len).map_err(|_| ())?;
+    let text = unsafe { core::slice::from_raw_parts_mut((buf_hva + size_of::<usize>()) as *mut u8, len) };
+    match virtio_console_ring_read(&vm, text).or_else(|| pl011_ring_read(&vm, text)) {
+        Some((dropped, len)) => {
+            unsafe { *(buf_hva as *mut usize) = dropped };
+            Ok(len)
+        }
+        None => {
+            error!("vmm_read_console: VM[{vm_id}] has no console ring");
+            Err(())
+        }
+    }
+}
+
+/**
+ * Send input to the emulated pl011 of a VM, as if typed on its uart.
+ *
+ * @param arg len ~ (47, 16) ~ [bytes of the text]
+ *            vmid ~ (15, 0) ~ [target vm id]
+ * @param buf_ipa : ipa of the text buffer.
+ * @return the number of bytes taken, less than len when the rx fifo of the pl011 is full.
+ */
+pub fn vmm_write_console(arg: usize, buf_ipa: usize) -> Result<usize, ()> {
+    let vm_id = bit_extract(arg, 0, 16);
+    let len = bit_extract(arg, 16, 32);
+    let vm = match vm_by_id(vm_id) {
+        Some(vm) => vm,
+        None => {
+            error!("vmm_write_console: VM[{vm_id}] does not exist");
+            return Err(());
+        }
+    };
+    let buf_hva = vm_ipa2hva(&active_vm().unwrap(), buf_ipa, len).map_err(|_| ())?;
+    let text = unsafe { core::slice::from_raw_parts(buf_hva as *const u8, len) };
+    match pl011_receive(&vm, text) {
+        Some(len) => Ok(len),
+        None => {
+            error!("vmm_write_console: VM[{vm_id}] has no pl011");
+            Err(())
+        }
+    }
+}
+
+/**
+ * The header of HVC_VMM_MEM_BW_STAT, followed by the `MemBwRecord`s of the last periods, the oldest first.
+ * A budget converts to MB/s with `budget2bandwidth(budget, period)`.
+ */
+#[cfg(feature = "memory-reservation")]
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct MemBwStat {
+    // measured at boot, the accesses of the whole memory bandwidth in `calibrated_period_us`
+    pub calibrated_budget: u32,
+    // the budget of the VM in one period, all vcpus together
+    pub budget: u32,
+    pub calibrated_period_us: u64,
+    pub period_us: u64,
+    pub records: u64,
+}
+
+/**
+ * Copy the memory bandwidth history of a VM to MVM.
+ *
+ * @param arg len ~ (47, 16) ~ [bytes of the buffer]
+ *            vmid ~ (15, 0) ~ [target vm id]
+ * @param buf_ipa : ipa of a `MemBwStat`, followed by the records.
+ * @return the number of records copied.
+ */
+#[cfg(feature = "memory-reservation")]
+pub fn vmm_mem_bw_stat(arg: usize, buf_ipa: usize) -> Result<usize, ()> {
+    use crate::kernel::MemBwRecord;
+    let vm_id = bit_extract(arg, 0, 16);
+    let len = bit_extract(arg, 16, 32);
+    let vm = match vm_by_id(vm_id) {
+        Some(vm) => vm,
+        None => {
+            error!("vmm_mem_bw_stat: VM[{vm_id}] does not exist");
+            return Err(());
+        }
+    };
+    if len < size_of::<MemBwStat>() || buf_ipa % size_of::<u64>() != 0 {
+        error!("vmm_mem_bw_stat: illegal buffer {buf_ipa:#x} len {len:#x}");
+        return Err(());
+    }
+    let buf_hva = vm_ipa2hva(&active_vm().unwrap(), buf_ipa, len).map_err(|_| ())?;
+    let records = unsafe {
+        core::slice::from_raw_parts_mut(
+            (buf_hva + size_of::<MemBwStat>()) as *mut MemBwRecord,
+            (len - size_of::<MemBwStat>()) / size_of::<MemBwRecord>(),
+        )
+    };
+    let count = vm.mem_bw_history(records);
+    let (calibrated_budget, calibrated_period) = crate::config::memory_budget_per_period();
+    let stat = MemBwStat {
+        calibrated_budget,
+        budget: vm.config().memory.budget,
+        calibrated_period_us: calibrated_period.as_micros() as u64,
+        period_us: vm.config().memory.period.as_micros() as u64,
+        records: count as u64,
+    };
+    unsafe { *(buf_hva as *mut MemBwStat) = stat };
+    Ok(count)
+}
+
+/**
+ * Move a passthrough interrupt from one VM to another at runtime, both VMs are told once it is moved.
+ * An interrupt still active in the source guest is moved after its EOI.
+ *
+ * @param arg dst_vmid ~ (31, 16) ~ [vm to give the interrupt to]
+ *            src_vmid ~ (15, 0) ~ [vm owning the interrupt now]
+ * @param int_id : the passthrough SPI.
+ */
+pub fn vmm_int_transfer(arg: usize, int_id: usize) -> Result<usize, ()> {
+    let src_id = bit_extract(arg, 0, 16);
+    let dst_id = bit_extract(arg, 16, 16);
+    let (src, dst) = match (vm_by_id(src_id), vm_by_id(dst_id)) {
+        (Some(src), Some(dst)) => (src, dst),
+        _ => {
+            error!("vmm_int_transfer: VM[{}] or VM[{}] does not exist", src_id, dst_id);
+            return Err(());
+        }
+    };
+    if interrupt_vm_transfer(&src, &dst, int_id) {
+        Ok(0)
+    } else {
+        Err(())
+    }
+}
+
+// set the balloon target of a guest VM to `pages` pages, returns the pages its driver reports holding
+#[cfg(feature = "balloon")]
+pub fn vmm_set_balloon(vm_id: usize, pages: usize) -> Result<usize, ()> {
+    match vm_by_id(vm_id) {
+        Some(vm) if vm_id != 0 => crate::device::virtio_balloon_set_target(&vm, pages),
+        _ => {
+            error!("vmm_set_balloon: VM[{}] has no balloon to set", vm_id);
+            Err(())
+        }
+    }
+}
+
+// whether the log lines of a VM are also printed on the console, only kept in its ring otherwise
+pub fn vmm_log_console(vm_id: usize, console: bool) -> Result<usize, ()> {
+    match vm_log_access(vm_log_id(vm_id), |log| log.set_console(console)) {
+        Some(()) => Ok(0),
+        None => {
+            error!("vmm_log_console: VM[{vm_id}] has no log ring");
+            Err(())
+        }
+    }
+}
+
 pub fn vmm_ipi_handler(msg: IpiMessage) {
     match msg.ipi_message {
         IpiInnerMsg::VmmMsg(vmm) => match vmm.event {
             VmmEvent::Boot => {
-                vmm_boot_vm(vmm.vmid);
+                vmm_boot_vm_percore(vmm.vmid);
             }
             VmmEvent::Reboot => {
-                vmm_reboot();
+                // the VM may have crashed and left this core since the request was sent
+                match active_vm() {
+                    Some(vm) if vm.id() == vmm.vmid => vmm_reboot(),
+                    _ => warn!(
+                        "vmm_ipi_handler: VM[{}] is not running on Core {}, not rebooted",
+                        vmm.vmid,
+                        current_cpu().id
+                    ),
+                }
             }
             VmmEvent::Shutdown => {
                 todo!();
@@ -296,7 +826,7 @@ pub fn vmm_ipi_handler(msg: IpiMessage) {
                     current_cpu().id,
                     msg.vm.id()
                 );
-                super::address::vmm_map_ipa_percore(&msg.vm, false);
+                super::address::vmm_map_ipa_percore(&msg.vm, &[], false);
             }
             VmmPercoreEvent::UnmapIPA => {
                 debug!(
@@ -306,12 +836,29 @@ pub fn vmm_ipi_handler(msg: IpiMessage) {
                 );
                 super::address::vmm_unmap_ipa_percore(&msg.vm);
             }
+            VmmPercoreEvent::PauseVcpu => {
+                debug!(
+                    "vmm_ipi_handler: core {} pause vcpu of vm[{}]",
+                    current_cpu().id,
+                    msg.vm.id()
+                );
+                super::dump::vmm_vcpu_pause_percore(&msg.vm, true);
+            }
+            VmmPercoreEvent::ResumeVcpu => {
+                debug!(
+                    "vmm_ipi_handler: core {} resume vcpu of vm[{}]",
+                    current_cpu().id,
+                    msg.vm.id()
+                );
+                super::dump::vmm_vcpu_pause_percore(&msg.vm, false);
+            }
             VmmPercoreEvent::AssignCpu => {
                 debug!(
                     "vmm_ipi_handler: core {} receive assign vcpu request for vm[{}]",
                     current_cpu().id,
                     msg.vm.id()
                 );
+                // a failure marks the VM, vmm_boot_vm refuses it
                 vmm_assign_vcpu_percore(&msg.vm);
             }
             VmmPercoreEvent::RemoveCpu => {
@@ -322,6 +869,14 @@ pub fn vmm_ipi_handler(msg: IpiMessage) {
                 );
                 vmm_remove_vcpu_percore(&msg.vm);
             }
+            VmmPercoreEvent::DetachVcpu { .. } | VmmPercoreEvent::AttachVcpu { .. } => {
+                debug!(
+                    "vmm_ipi_handler: core {} migrate vcpu of vm[{}]",
+                    current_cpu().id,
+                    msg.vm.id()
+                );
+                super::vcpu::vmm_ipi_migrate_handler(&msg.vm, msg.event);
+            }
         },
         _ => {
             error!("vmm_ipi_handler: illegal ipi type");
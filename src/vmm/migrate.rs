@@ -0,0 +1,70 @@
+use alloc::sync::Arc;
+
+use crate::kernel::access::vm_ipa2hva;
+use crate::kernel::{active_vm, vm_by_id, Vm};
+use crate::util::bit_extract;
+
+fn migrate_vm(vm_id: usize, func: &str) -> Result<Arc<Vm>, ()> {
+    match vm_by_id(vm_id) {
+        Some(vm) if vm_id != 0 => Ok(vm),
+        _ => {
+            error!("{}: VM[{}] can not be migrated", func, vm_id);
+            Err(())
+        }
+    }
+}
+
+// start logging the writes to the memory of a VM, return the bytes of its dirty bitmap
+pub fn vmm_dirty_log_start(vm_id: usize) -> Result<usize, ()> {
+    let vm = migrate_vm(vm_id, "vmm_dirty_log_start")?;
+    if !vm.dirty_log_start() {
+        error!("vmm_dirty_log_start: VM[{}] is already logging dirty pages", vm_id);
+        return Err(());
+    }
+    info!("vmm_dirty_log_start: VM[{}] memory is write protected", vm_id);
+    Ok(vm.dirty_log_size())
+}
+
+/**
+ * Fetch and clear the dirty bitmap of a VM into a buffer of the MVM, one bit for each page of
+ * the memory regions of the VM in their order. The pages fetched are write protected again.
+ *
+ * @param arg len ~ (47, 16) ~ [bytes of the buffer]
+ *            vmid ~ (15, 0) ~ [target vm id]
+ * @param buf_ipa : the buffer in the MVM.
+ * @return the number of dirty pages fetched.
+ */
+pub fn vmm_dirty_log_fetch(arg: usize, buf_ipa: usize) -> Result<usize, ()> {
+    let vm_id = bit_extract(arg, 0, 16);
+    let len = bit_extract(arg, 16, 32);
+    let vm = migrate_vm(vm_id, "vmm_dirty_log_fetch")?;
+    if buf_ipa % core::mem::size_of::<usize>() != 0 {
+        error!("vmm_dirty_log_fetch: buffer {:#x} is not aligned", buf_ipa);
+        return Err(());
+    }
+    let buf_hva = vm_ipa2hva(&active_vm().unwrap(), buf_ipa, len).map_err(|_| ())?;
+    let buf = unsafe { core::slice::from_raw_parts_mut(buf_hva as *mut usize, len / core::mem::size_of::<usize>()) };
+    match vm.dirty_log_fetch(buf) {
+        Some(dirty) => Ok(dirty),
+        None => {
+            error!(
+                "vmm_dirty_log_fetch: VM[{}] is not logging dirty pages or buffer len {:#x} < {:#x}",
+                vm_id,
+                len,
+                vm.dirty_log_size()
+            );
+            Err(())
+        }
+    }
+}
+
+// stop logging and give the VM its memory writable back
+pub fn vmm_dirty_log_stop(vm_id: usize) -> Result<usize, ()> {
+    let vm = migrate_vm(vm_id, "vmm_dirty_log_stop")?;
+    if !vm.dirty_log_stop() {
+        error!("vmm_dirty_log_stop: VM[{}] is not logging dirty pages", vm_id);
+        return Err(());
+    }
+    info!("vmm_dirty_log_stop: VM[{}] memory is writable", vm_id);
+    Ok(0)
+}
@@ -1,8 +1,19 @@
+pub use self::dump::vmm_dump_vm;
+#[cfg(feature = "self-test")]
+pub use self::elf::elf_parse_synthetic;
 pub use self::init::*;
 pub use self::manager::*;
+pub use self::migrate::*;
 pub use self::remove::*;
+pub use self::vcpu::vmm_migrate_vcpu;
 
 mod address;
+mod boot_info;
+mod dump;
+mod elf;
+mod info;
 mod init;
 mod manager;
+mod migrate;
 mod remove;
+mod vcpu;
@@ -1,30 +1,44 @@
 use alloc::sync::Arc;
 
-use crate::arch::{interrupt_arch_deactive_irq, INTERRUPT_IRQ_GUEST_TIMER};
-use crate::kernel::vm_if_reset;
+use crate::arch::{interrupt_arch_deactive_irq, smc_log_reset, INTERRUPT_IRQ_GUEST_TIMER};
 use crate::kernel::{
-    current_cpu, interrupt_cpu_enable, interrupt_vm_remove, ipi_send_msg, remove_vm, remove_vm_async_task, vm_by_id,
-    IpiInnerMsg, IpiType, IpiVmmPercoreMsg, Vm,
+    cancel_vm_async_task, current_cpu, interrupt_cpu_enable, interrupt_vm_moved_ints, interrupt_vm_remove,
+    iommu_detach_vm, iommu_fault_reset, ipi_send_msg_retry, ivc_remove_vm_channels, remove_vm, vm_by_id, IpiInnerMsg,
+    IpiType, IpiVmmPercoreMsg, Vm,
 };
+use crate::kernel::{vm_if_boot_transit, vm_if_reset, VmBootState};
 use crate::vmm::address::vmm_unmap_ipa2hva;
 use crate::vmm::VmmPercoreEvent;
 
-pub fn vmm_remove_vm(vm_id: usize) {
+pub fn vmm_remove_vm(vm_id: usize) -> Result<usize, ()> {
     if vm_id == 0 {
         warn!("{} do not support remove vm0", env!("CARGO_PKG_NAME"));
-        return;
+        return Err(());
     }
 
     // remove vm: page table / mmio / vgic will be removed when vm drop
     if let Some(vm) = vm_by_id(vm_id) {
+        // not in the middle of being configured or reset
+        if let Err(state) = vm_if_boot_transit(vm_id, VmBootState::Removing) {
+            error!("VM[{}] is busy ({:?}), can not be removed", vm_id, state);
+            return Err(());
+        }
         // vcpu
         vmm_remove_vcpu(&vm);
         // reset vm interface
         vm_if_reset(vm_id);
         // passthrough dev
         vmm_remove_passthrough_device(&vm);
-        // clear async task list
-        remove_vm_async_task(vm_id);
+        // shared memory channels with other vms
+        ivc_remove_vm_channels(vm_id);
+        // streams and context bank of the iommu
+        iommu_detach_vm(&vm);
+        // recorded iommu faults
+        iommu_fault_reset(vm_id);
+        // logged unknown smc calls
+        smc_log_reset(vm_id);
+        // cancel the mediated IO in flight, the virtqueues are going away
+        cancel_vm_async_task(vm_id, false);
         crate::device::remove_virtio_nic(vm_id);
         // remove vm cfg
         let _ = crate::config::del_vm(vm_id);
@@ -34,14 +48,21 @@ pub fn vmm_remove_vm(vm_id: usize) {
         // unmap ipa(hva) percore at last
         vmm_unmap_ipa2hva(vm);
         remove_vm(vm_id);
+        let _ = vm_if_boot_transit(vm_id, VmBootState::Pending);
         info!("remove vm[{}] successfully", vm_id);
+        Ok(0)
     } else {
         error!("VM[{vm_id}] does not exist!");
+        Err(())
     }
 }
 
 pub fn vmm_remove_vcpu_percore(vm: &Vm) {
-    current_cpu().vcpu_array.remove_vcpu(vm.id());
+    for vcpu in vm.vcpu_list() {
+        if vcpu.phys_id() == current_cpu().id {
+            current_cpu().vcpu_array.remove_vcpu(vm.id(), vcpu.id());
+        }
+    }
     if !current_cpu().assigned() {
         // hard code: remove el1 timer interrupt 27
         interrupt_cpu_enable(INTERRUPT_IRQ_GUEST_TIMER, false);
@@ -49,25 +70,29 @@ pub fn vmm_remove_vcpu_percore(vm: &Vm) {
     }
 }
 
-fn vmm_remove_vcpu(vm: &Arc<Vm>) {
-    for vcpu in vm.vcpu_list() {
-        if vcpu.phys_id() == current_cpu().id {
+pub(super) fn vmm_remove_vcpu(vm: &Arc<Vm>) {
+    for phys_id in vm.pcpu_list() {
+        if phys_id == current_cpu().id {
             vmm_remove_vcpu_percore(vm);
         } else {
             let m = IpiVmmPercoreMsg {
                 vm: vm.clone(),
                 event: VmmPercoreEvent::RemoveCpu,
             };
-            if !ipi_send_msg(vcpu.phys_id(), IpiType::Vmm, IpiInnerMsg::VmmPercoreMsg(m)) {
-                warn!("vmm_remove_vcpu: failed to send ipi to Core {}", vcpu.phys_id());
+            if let Err(err) = ipi_send_msg_retry(phys_id, IpiType::Vmm, IpiInnerMsg::VmmPercoreMsg(m)) {
+                warn!("vmm_remove_vcpu: failed to send ipi to Core {}: {:?}", phys_id, err);
             }
         }
     }
 }
 
-fn vmm_remove_passthrough_device(vm: &Vm) {
+pub(super) fn vmm_remove_passthrough_device(vm: &Vm) {
     for irq in vm.config().passthrough_device_irqs() {
         interrupt_vm_remove(vm, *irq);
         debug!("VM[{}] remove irq {}", vm.id(), irq);
     }
+    for irq in interrupt_vm_moved_ints(vm.id()) {
+        interrupt_vm_remove(vm, irq);
+        debug!("VM[{}] remove moved irq {}", vm.id(), irq);
+    }
 }
@@ -0,0 +1,207 @@
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::arch::INTERRUPT_IRQ_GUEST_TIMER;
+use crate::board::PLAT_DESC;
+use crate::kernel::{
+    current_cpu, interrupt_cpu_enable, interrupt_vcpu_bind, interrupt_vcpu_retarget, ipi_pending_for_vm,
+    ipi_send_msg_retry, timer, vm_by_id, vm_if_update_cpu_id, IpiInnerMsg, IpiType, IpiVmmPercoreMsg, Vm,
+};
+use crate::util::bit_extract;
+
+use super::VmmPercoreEvent;
+
+// only one vcpu is moved at a time
+static MIGRATE_BUSY: AtomicBool = AtomicBool::new(false);
+// when the running migration was asked for, in us
+static MIGRATE_START_US: AtomicU64 = AtomicU64::new(0);
+
+fn vmm_migrate_finish() {
+    MIGRATE_BUSY.store(false, Ordering::Release);
+}
+
+fn vmm_send_percore(vm: &Arc<Vm>, target: usize, event: VmmPercoreEvent) -> bool {
+    if target == current_cpu().id {
+        vmm_ipi_migrate_handler(vm, event);
+        return true;
+    }
+    let msg = IpiVmmPercoreMsg { vm: vm.clone(), event };
+    if let Err(err) = ipi_send_msg_retry(target, IpiType::Vmm, IpiInnerMsg::VmmPercoreMsg(msg)) {
+        error!("vmm_migrate_vcpu: failed to send ipi to Core {}: {:?}", target, err);
+        return false;
+    }
+    true
+}
+
+/**
+ * Move a vcpu of a VM to another physical cpu, the vcpu is paused while it is moved.
+ * The move is done by the cores asynchronously, the HVC returns once it is started.
+ *
+ * @param arg vcpu_id ~ (31, 16) ~ [vcpu to move]
+ *            vmid ~ (15, 0) ~ [target vm id]
+ * @param target : the physical cpu to move the vcpu to.
+ */
+pub fn vmm_migrate_vcpu(arg: usize, target: usize) -> Result<usize, ()> {
+    let vm_id = bit_extract(arg, 0, 16);
+    let vcpu_id = bit_extract(arg, 16, 16);
+    let vm = match vm_by_id(vm_id) {
+        Some(vm) if vm_id != 0 => vm,
+        _ => {
+            error!("vmm_migrate_vcpu: VM[{}] can not be migrated", vm_id);
+            return Err(());
+        }
+    };
+    let vcpu = match vm.vcpu(vcpu_id) {
+        Some(vcpu) => vcpu.clone(),
+        None => {
+            error!("vmm_migrate_vcpu: VM[{}] has no vcpu {}", vm_id, vcpu_id);
+            return Err(());
+        }
+    };
+    if target >= PLAT_DESC.cpu_desc.num || target == vcpu.phys_id() {
+        error!(
+            "vmm_migrate_vcpu: VM[{}] vcpu {} can not move from Core {} to Core {}",
+            vm_id,
+            vcpu_id,
+            vcpu.phys_id(),
+            target
+        );
+        return Err(());
+    }
+    // a core holds no more vcpus of a VM than its config allows
+    let shared = vm.vcpu_list().iter().filter(|v| v.phys_id() == target).count();
+    if shared >= vm.config().cpu_vcpus_per_core() {
+        error!(
+            "vmm_migrate_vcpu: Core {} already holds {} vcpus of VM[{}]",
+            target, shared, vm_id
+        );
+        return Err(());
+    }
+    if MIGRATE_BUSY
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        error!("vmm_migrate_vcpu: another migration is in progress");
+        return Err(());
+    }
+    MIGRATE_START_US.store(timer::now().as_micros() as u64, Ordering::Release);
+    info!(
+        "vmm_migrate_vcpu: VM[{}] vcpu {} Core {} => Core {}",
+        vm_id,
+        vcpu_id,
+        vcpu.phys_id(),
+        target
+    );
+    if !vmm_send_percore(&vm, vcpu.phys_id(), VmmPercoreEvent::DetachVcpu { vcpu_id, target }) {
+        vmm_migrate_finish();
+        return Err(());
+    }
+    Ok(0)
+}
+
+// on the old core: take the vcpu off, make its interrupts follow it and hand it over
+fn vmm_detach_vcpu_percore(vm: &Arc<Vm>, vcpu_id: usize, target: usize) {
+    let cpu_id = current_cpu().id;
+    // the messages queued here still expect the vcpu on this core
+    if ipi_pending_for_vm(cpu_id, vm.id()) {
+        warn!(
+            "vmm_migrate_vcpu: Core {} is handling ipi messages for VM[{}], reject migration",
+            cpu_id,
+            vm.id()
+        );
+        vmm_migrate_finish();
+        return;
+    }
+    let vcpu = match current_cpu().vcpu_array.detach_vcpu(vm.id(), vcpu_id) {
+        Some(vcpu) => vcpu,
+        None => {
+            error!(
+                "vmm_migrate_vcpu: Core {} does not hold VM[{}] vcpu {}",
+                cpu_id,
+                vm.id(),
+                vcpu_id
+            );
+            vmm_migrate_finish();
+            return;
+        }
+    };
+
+    interrupt_vcpu_bind(&vcpu, false);
+    if !current_cpu().assigned() {
+        interrupt_cpu_enable(INTERRUPT_IRQ_GUEST_TIMER, false);
+    }
+    vcpu.set_phys_id(target);
+    interrupt_vcpu_retarget(&vcpu, cpu_id);
+    if vcpu_id == 0 {
+        vm_if_update_cpu_id(vm.id(), target);
+    }
+
+    let event = VmmPercoreEvent::AttachVcpu {
+        vcpu_id,
+        source: Some(cpu_id),
+    };
+    if !vmm_send_percore(vm, target, event) {
+        // put it back, the vcpu must not get lost
+        vcpu.set_phys_id(cpu_id);
+        interrupt_vcpu_retarget(&vcpu, target);
+        if vcpu_id == 0 {
+            vm_if_update_cpu_id(vm.id(), cpu_id);
+        }
+        interrupt_vcpu_bind(&vcpu, true);
+        current_cpu().vcpu_array.attach_vcpu(vcpu);
+        vmm_migrate_finish();
+    }
+}
+
+// on the new core: resume the vcpu with the context saved by the old core, or send it back there
+fn vmm_attach_vcpu_percore(vm: &Arc<Vm>, vcpu_id: usize, source: Option<usize>) {
+    let cpu_id = current_cpu().id;
+    let vcpu = match vm.vcpu(vcpu_id) {
+        Some(vcpu) => vcpu.clone(),
+        None => {
+            vmm_migrate_finish();
+            return;
+        }
+    };
+    interrupt_vcpu_bind(&vcpu, true);
+    if current_cpu().vcpu_array.attach_vcpu(vcpu.clone()) {
+        let paused = timer::now().as_micros() as u64 - MIGRATE_START_US.load(Ordering::Acquire);
+        info!(
+            "vmm_migrate_vcpu: VM[{}] vcpu {} is running on Core {}, moved in {} us",
+            vm.id(),
+            vcpu_id,
+            cpu_id,
+            paused
+        );
+        vmm_migrate_finish();
+        return;
+    }
+    error!(
+        "vmm_migrate_vcpu: Core {} failed to attach VM[{}] vcpu {}",
+        cpu_id,
+        vm.id(),
+        vcpu_id
+    );
+    interrupt_vcpu_bind(&vcpu, false);
+    if let Some(source) = source {
+        // the old core still has room for it, the migration finishes there
+        vcpu.set_phys_id(source);
+        interrupt_vcpu_retarget(&vcpu, cpu_id);
+        if vcpu_id == 0 {
+            vm_if_update_cpu_id(vm.id(), source);
+        }
+        if vmm_send_percore(vm, source, VmmPercoreEvent::AttachVcpu { vcpu_id, source: None }) {
+            return;
+        }
+    }
+    error!("vmm_migrate_vcpu: VM[{}] vcpu {} is lost", vm.id(), vcpu_id);
+    vmm_migrate_finish();
+}
+
+pub fn vmm_ipi_migrate_handler(vm: &Arc<Vm>, event: VmmPercoreEvent) {
+    match event {
+        VmmPercoreEvent::DetachVcpu { vcpu_id, target } => vmm_detach_vcpu_percore(vm, vcpu_id, target),
+        VmmPercoreEvent::AttachVcpu { vcpu_id, source } => vmm_attach_vcpu_percore(vm, vcpu_id, source),
+        _ => {}
+    }
+}